@@ -57,11 +57,13 @@ fn main() {
     gen_linker_script(&linker_script_path);
 
     let rustflags = format!(
-        "-C link-arg=-T{} -C relocation-model=static -C code-model=kernel",
+        "-C link-arg=-T{} -C relocation-model=static -C code-model=kernel \
+         -C force-frame-pointers=yes",
         linker_script_path.display()
     );
 
-    let status = Command::new("cargo")
+    let mut cargo = Command::new("cargo");
+    cargo
         .env("RUSTFLAGS", rustflags)
         .env_remove("RUSTC_WORKSPACE_WRAPPER")
         .env_remove("CARGO_ENCODED_RUSTFLAGS")
@@ -73,7 +75,14 @@ fn main() {
             "--target-dir",
             out_dir.join("kernel-target").to_str().unwrap(),
         ])
-        .current_dir(&kernel_dir)
+        .current_dir(&kernel_dir);
+    if env::var_os("CARGO_FEATURE_KERNEL_ALLOC_DEBUG").is_some() {
+        cargo.args(["--features", "alloc-debug"]);
+    }
+    if env::var_os("CARGO_FEATURE_KERNEL_REDZONE").is_some() {
+        cargo.args(["--features", "redzone"]);
+    }
+    let status = cargo
         .status()
         .expect("Failed to run cargo build for kernel");
 
@@ -1,16 +1,128 @@
+use alloc::vec::Vec;
+use core::ffi::{CStr, c_char};
+
 #[cfg(target_os = "none")]
 unsafe extern "C" {
-    fn kt_spawn(entry: usize) -> usize;
+    fn kt_spawn(name: *const u8, name_len: usize, entry: usize, arg: usize) -> i64;
+    fn kt_spawn_forked(name: *const u8, name_len: usize, entry: usize, arg: usize) -> i64;
+    fn kt_process_at(index: usize, pid: *mut u64, state: *mut u8, name: *mut u8) -> i64;
+    fn kt_sched_trace(records: *mut [u64; 4], len: usize) -> usize;
     fn kt_has_pid(pid: usize) -> bool;
+    fn kt_exit_status(pid: usize) -> i64;
+    fn kt_getppid() -> i64;
+    fn kt_initial_stack() -> usize;
+    fn kt_kill(pid: i64, signal: u64) -> i64;
+    fn kt_wait4(pid: i64, status: *mut i32, options: u64) -> i64;
+    fn kt_getrusage(who: i64, user_us: *mut u64, system_us: *mut u64) -> i64;
+    fn kt_times(buf: *mut Tms) -> i64;
     fn kt_yield_now();
+    fn kt_set_segment_bases(fs: u64, gs: u64);
+    fn kt_segment_bases(fs: *mut u64, gs: *mut u64);
+    fn kt_wait_sleep(timeout_ms: i64);
+    fn kt_wait_wake();
     fn kt_mmap_anonymous(len: usize) -> i64;
+    fn kt_mmap(addr: usize, len: usize, prot: u64, flags: u64) -> i64;
+    fn kt_brk(addr: usize) -> i64;
+    fn kt_setrlimit(resource: usize, cur: u64, max: u64) -> i64;
+    fn kt_getpriority(which: u64, who: usize) -> i64;
+    fn kt_setpriority(which: u64, who: usize, nice: i32) -> i64;
+    fn kt_sched_setaffinity(pid: usize, mask: *const u64, words: usize) -> i64;
+    fn kt_sched_getaffinity(pid: usize, mask: *mut u64, words: usize) -> i64;
+    fn kt_memory_usage(pid: usize, mapped: *mut u64, resident: *mut u64) -> i64;
+    fn kt_boot_memory(ram_pages: *mut u64, allocatable_pages: *mut u64);
+    fn kt_hypervisor_vendor(vendor: *mut [u8; 12]) -> bool;
+    fn kt_kmalloc_stats(live_objects: *mut u64, bytes_in_use: *mut u64);
+    fn kt_translate(vaddr: usize, paddr: *mut u64, writable: *mut bool, user: *mut bool) -> bool;
+    fn kt_mapped_bytes(start: usize, end: usize) -> u64;
+    fn kt_mkdir(path: *const c_char, mode: u32) -> i64;
+    fn kt_rmdir(path: *const c_char) -> i64;
+    fn kt_chdir(path: *const c_char) -> i64;
+    fn kt_getcwd(buf: *mut u8, len: usize) -> i64;
+    fn kt_umask(mask: u32) -> i64;
+    fn kt_access(path: *const c_char, mode: u64) -> i64;
+    fn kt_readlink(path: *const c_char, buf: *mut u8, len: usize) -> i64;
+    fn kt_openat(dirfd: i64, path: *const c_char, flags: u64, mode: u32) -> i64;
+    fn kt_close(fd: u64) -> i64;
+    fn kt_read(fd: u64, buf: *mut u8, len: usize) -> i64;
+    fn kt_write(fd: u64, buf: *const u8, len: usize) -> i64;
+    fn kt_lseek(fd: u64, offset: i64, whence: u64) -> i64;
+    fn kt_statfs(path: *const c_char, f_type: *mut i64, bfree: *mut u64, ffree: *mut u64) -> i64;
+    fn kt_fstatfs(fd: u64, f_type: *mut i64, bfree: *mut u64, ffree: *mut u64) -> i64;
+    fn kt_sendfile(out_fd: u64, in_fd: u64, offset: *mut i64, count: usize) -> i64;
+    fn kt_ftruncate(fd: u64, len: i64) -> i64;
+    fn kt_unlink(path: *const c_char) -> i64;
+    fn kt_shrink_ramfs(pages: usize) -> usize;
+    fn kt_poll(fd: i32, events: i16, timeout_ms: i32) -> i64;
+    fn kt_epoll_create1(flags: u64) -> i64;
+    fn kt_epoll_ctl(epfd: u64, op: u64, fd: u64, events: u32, data: u64) -> i64;
+    fn kt_epoll_wait(epfd: u64, timeout_ms: i32, events: *mut u32, data: *mut u64) -> i64;
+    fn kt_eventfd(initval: u32, flags: u64) -> i64;
+    fn kt_timerfd_create(clockid: u64, flags: u64) -> i64;
+    fn kt_timerfd_settime(fd: u64, value_ns: u64, interval_ns: u64) -> i64;
+    fn kt_socket(ty: u64) -> i64;
+    fn kt_tcp_socket(flags: u64) -> i64;
+    fn kt_bind_loopback(fd: u64, port: u16) -> i64;
+    fn kt_connect_loopback(fd: u64, port: u16) -> i64;
+    fn kt_bind(fd: u64, name: *const u8, len: usize) -> i64;
+    fn kt_listen(fd: u64, backlog: i32) -> i64;
+    fn kt_connect(fd: u64, name: *const u8, len: usize) -> i64;
+    fn kt_accept4(fd: u64, flags: u64) -> i64;
+    fn kt_sendmsg(
+        fd: u64,
+        buf: *const u8,
+        len: usize,
+        to: *const u8,
+        to_len: usize,
+        flags: u64,
+    ) -> i64;
+    fn kt_recvmsg(
+        fd: u64,
+        buf: *mut u8,
+        len: usize,
+        flags: u64,
+        from: *mut u8,
+        from_len: *mut usize,
+        truncated: *mut bool,
+    ) -> i64;
+    fn kt_seccomp_strict() -> i64;
+    fn kt_seccomp_filter(filter: *const SockFilter, len: u16) -> i64;
     fn kt_exit(status: i32) -> !;
     fn kt_signal_success() -> !;
     fn kt_signal_failure() -> !;
 }
 
 #[cfg(not(target_os = "none"))]
-unsafe extern "C" fn kt_spawn(_entry: usize) -> usize {
+unsafe extern "C" fn kt_spawn(
+    _name: *const u8,
+    _name_len: usize,
+    _entry: usize,
+    _arg: usize,
+) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_process_at(
+    _index: usize,
+    _pid: *mut u64,
+    _state: *mut u8,
+    _name: *mut u8,
+) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_spawn_forked(
+    _name: *const u8,
+    _name_len: usize,
+    _entry: usize,
+    _arg: usize,
+) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_sched_trace(_records: *mut [u64; 4], _len: usize) -> usize {
     panic!("kernel test API is unavailable outside kernel target");
 }
 
@@ -19,16 +131,361 @@ unsafe extern "C" fn kt_has_pid(_pid: usize) -> bool {
     panic!("kernel test API is unavailable outside kernel target");
 }
 
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_exit_status(_pid: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_getppid() -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_initial_stack() -> usize {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_kill(_pid: i64, _signal: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_wait4(_pid: i64, _status: *mut i32, _options: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_getrusage(_who: i64, _user_us: *mut u64, _system_us: *mut u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_times(_buf: *mut Tms) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
 #[cfg(not(target_os = "none"))]
 unsafe extern "C" fn kt_yield_now() {
     panic!("kernel test API is unavailable outside kernel target");
 }
 
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_set_segment_bases(_fs: u64, _gs: u64) {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_segment_bases(_fs: *mut u64, _gs: *mut u64) {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_wait_sleep(_timeout_ms: i64) {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_wait_wake() {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
 #[cfg(not(target_os = "none"))]
 unsafe extern "C" fn kt_mmap_anonymous(_len: usize) -> i64 {
     panic!("kernel test API is unavailable outside kernel target");
 }
 
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_mmap(_addr: usize, _len: usize, _prot: u64, _flags: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_brk(_addr: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_setrlimit(_resource: usize, _cur: u64, _max: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_getpriority(_which: u64, _who: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_setpriority(_which: u64, _who: usize, _nice: i32) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_sched_setaffinity(_pid: usize, _mask: *const u64, _words: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_sched_getaffinity(_pid: usize, _mask: *mut u64, _words: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_memory_usage(_pid: usize, _mapped: *mut u64, _resident: *mut u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_boot_memory(_ram_pages: *mut u64, _allocatable_pages: *mut u64) {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_hypervisor_vendor(_vendor: *mut [u8; 12]) -> bool {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_kmalloc_stats(_live_objects: *mut u64, _bytes_in_use: *mut u64) {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_translate(
+    _vaddr: usize,
+    _paddr: *mut u64,
+    _writable: *mut bool,
+    _user: *mut bool,
+) -> bool {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_mapped_bytes(_start: usize, _end: usize) -> u64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_mkdir(_path: *const c_char, _mode: u32) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_rmdir(_path: *const c_char) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_chdir(_path: *const c_char) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_getcwd(_buf: *mut u8, _len: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_umask(_mask: u32) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_access(_path: *const c_char, _mode: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_readlink(_path: *const c_char, _buf: *mut u8, _len: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_openat(_dirfd: i64, _path: *const c_char, _flags: u64, _mode: u32) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_close(_fd: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_read(_fd: u64, _buf: *mut u8, _len: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_write(_fd: u64, _buf: *const u8, _len: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_lseek(_fd: u64, _offset: i64, _whence: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_statfs(
+    _path: *const c_char,
+    _f_type: *mut i64,
+    _bfree: *mut u64,
+    _ffree: *mut u64,
+) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_fstatfs(
+    _fd: u64,
+    _f_type: *mut i64,
+    _bfree: *mut u64,
+    _ffree: *mut u64,
+) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_sendfile(
+    _out_fd: u64,
+    _in_fd: u64,
+    _offset: *mut i64,
+    _count: usize,
+) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_ftruncate(_fd: u64, _len: i64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_unlink(_path: *const c_char) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_shrink_ramfs(_pages: usize) -> usize {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_poll(_fd: i32, _events: i16, _timeout_ms: i32) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_epoll_create1(_flags: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_epoll_ctl(_epfd: u64, _op: u64, _fd: u64, _events: u32, _data: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_epoll_wait(
+    _epfd: u64,
+    _timeout_ms: i32,
+    _events: *mut u32,
+    _data: *mut u64,
+) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_eventfd(_initval: u32, _flags: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_timerfd_create(_clockid: u64, _flags: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_timerfd_settime(_fd: u64, _value_ns: u64, _interval_ns: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_socket(_ty: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_tcp_socket(_flags: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_bind_loopback(_fd: u64, _port: u16) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_connect_loopback(_fd: u64, _port: u16) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_bind(_fd: u64, _name: *const u8, _len: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_listen(_fd: u64, _backlog: i32) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_connect(_fd: u64, _name: *const u8, _len: usize) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_accept4(_fd: u64, _flags: u64) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_sendmsg(
+    _fd: u64,
+    _buf: *const u8,
+    _len: usize,
+    _to: *const u8,
+    _to_len: usize,
+    _flags: u64,
+) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_recvmsg(
+    _fd: u64,
+    _buf: *mut u8,
+    _len: usize,
+    _flags: u64,
+    _from: *mut u8,
+    _from_len: *mut usize,
+    _truncated: *mut bool,
+) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_seccomp_strict() -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
+#[cfg(not(target_os = "none"))]
+unsafe extern "C" fn kt_seccomp_filter(_filter: *const SockFilter, _len: u16) -> i64 {
+    panic!("kernel test API is unavailable outside kernel target");
+}
+
 #[cfg(not(target_os = "none"))]
 unsafe extern "C" fn kt_exit(_status: i32) -> ! {
     panic!("kernel test API is unavailable outside kernel target");
@@ -45,21 +502,545 @@ unsafe extern "C" fn kt_signal_failure() -> ! {
 }
 
 pub fn spawn(entry: fn()) -> usize {
-    unsafe { kt_spawn(entry as usize) }
+    spawn_with_arg(run_entry, entry as usize)
+}
+
+// Name of processes spawned without one.
+const DEFAULT_PROCESS_NAME: &str = "kernel-tests";
+
+/// Pid of a new process called `name` running `entry(arg)`, or a negated
+/// errno.
+pub fn try_spawn_named(name: &str, entry: fn(usize), arg: usize) -> i64 {
+    unsafe { kt_spawn(name.as_ptr(), name.len(), entry as usize, arg) }
+}
+
+pub fn spawn_named(name: &str, entry: fn(usize), arg: usize) -> usize {
+    let pid = try_spawn_named(name, entry, arg);
+    assert!(pid > 0, "spawn failed with return value {}", pid);
+    pid as usize
+}
+
+pub fn spawn_with_arg(entry: fn(usize), arg: usize) -> usize {
+    spawn_named(DEFAULT_PROCESS_NAME, entry, arg)
+}
+
+/// Pid of a new process running `entry` in a copy of the calling process's
+/// address space.
+pub fn spawn_forked(entry: fn()) -> usize {
+    let name = DEFAULT_PROCESS_NAME;
+    let run: fn(usize) = run_entry;
+    let pid = unsafe { kt_spawn_forked(name.as_ptr(), name.len(), run as usize, entry as usize) };
+    assert!(pid > 0, "spawn failed with return value {}", pid);
+    pid as usize
+}
+
+// Entries without an argument get passed as one.
+fn run_entry(entry: usize) {
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    entry();
 }
 
 pub fn has_pid(pid: usize) -> bool {
     unsafe { kt_has_pid(pid) }
 }
 
+/// The wait4(2) status word process `pid` exited with, for as long as the
+/// kernel remembers it.
+pub fn exit_status(pid: usize) -> Option<i32> {
+    let status = unsafe { kt_exit_status(pid) };
+    i32::try_from(status).ok().filter(|&status| status >= 0)
+}
+
+/// Pid of the calling process's parent, 0 for the kernel.
+pub fn getppid() -> usize {
+    unsafe { kt_getppid() as usize }
+}
+
+/// Address of argc on the calling process's System V initial stack.
+pub fn initial_stack() -> usize {
+    unsafe { kt_initial_stack() }
+}
+
+/// Raw kill(2): 0, or a negated errno.
+pub fn kill(pid: i64, signal: u64) -> i64 {
+    unsafe { kt_kill(pid, signal) }
+}
+
+/// Raw wait4(2) without resource usage: the reaped pid, 0 under `WNOHANG`
+/// while no child has exited, or a negated errno.
+pub fn wait4(pid: i64, status: &mut i32, options: u64) -> i64 {
+    unsafe { kt_wait4(pid, status, options) }
+}
+
+/// CPU time in microseconds, as getrusage(2) reports it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuUsage {
+    pub user_us: u64,
+    pub system_us: u64,
+}
+
+/// Raw getrusage(2) for the caller (`RUSAGE_SELF`) or the children it
+/// reaped (`RUSAGE_CHILDREN`): 0 or a negated errno.
+pub fn getrusage(who: i64, usage: &mut CpuUsage) -> i64 {
+    unsafe { kt_getrusage(who, &mut usage.user_us, &mut usage.system_us) }
+}
+
+/// CPU time in clock ticks, as times(2) reports it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tms {
+    pub utime: i64,
+    pub stime: i64,
+    pub cutime: i64,
+    pub cstime: i64,
+}
+
+/// Raw times(2): clock ticks since boot, or a negated errno.
+pub fn times(buf: &mut Tms) -> i64 {
+    unsafe { kt_times(buf) }
+}
+
+// Longest process name the kernel keeps.
+const PROCESS_NAME_MAX_LEN: usize = 15;
+
+/// What a live process is doing, as the kernel's `TaskState` numbers it.
+pub const TASK_RUNNING: u8 = 0;
+pub const TASK_READY: u8 = 1;
+pub const TASK_BLOCKED: u8 = 2;
+
+/// A live process as the kernel's process listing reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProcessEntry {
+    pub pid: usize,
+    pub state: u8,
+    name: [u8; PROCESS_NAME_MAX_LEN],
+    name_len: usize,
+}
+
+impl ProcessEntry {
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+/// Entry `index` of the process listing, which is in process table order.
+pub fn process_at(index: usize) -> Option<ProcessEntry> {
+    let mut entry = ProcessEntry {
+        pid: 0,
+        state: 0,
+        name: [0; PROCESS_NAME_MAX_LEN],
+        name_len: 0,
+    };
+    let mut pid = 0;
+    let name_len =
+        unsafe { kt_process_at(index, &mut pid, &mut entry.state, entry.name.as_mut_ptr()) };
+    entry.pid = pid as usize;
+    entry.name_len = usize::try_from(name_len).ok()?;
+    Some(entry)
+}
+
+/// The listing entry of live process `pid`.
+pub fn find_process(pid: usize) -> Option<ProcessEntry> {
+    (0..).map_while(process_at).find(|entry| entry.pid == pid)
+}
+
+// As many records as the kernel keeps.
+const SCHED_TRACE_LEN: usize = 128;
+
+/// Something the scheduler did. Pid 0 is the kernel or its idle task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedEvent {
+    Switch {
+        from: usize,
+        to: usize,
+    },
+    Wakeup {
+        pid: usize,
+    },
+    Block {
+        pid: usize,
+        deadline_ns: Option<u64>,
+    },
+    Exit {
+        pid: usize,
+        status: i32,
+    },
+}
+
+/// A scheduler trace event and when it happened on the monotonic clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchedRecord {
+    pub at_ns: u64,
+    pub event: SchedEvent,
+}
+
+/// The scheduler trace, oldest record first.
+pub fn sched_trace() -> Vec<SchedRecord> {
+    let mut raw = [[0u64; 4]; SCHED_TRACE_LEN];
+    let len = unsafe { kt_sched_trace(raw.as_mut_ptr(), raw.len()) };
+    raw[..len]
+        .iter()
+        .map(|&[at_ns, kind, pid, arg]| {
+            let pid = pid as usize;
+            let event = match kind {
+                0 => SchedEvent::Switch {
+                    from: pid,
+                    to: arg as usize,
+                },
+                1 => SchedEvent::Wakeup { pid },
+                2 => SchedEvent::Block {
+                    pid,
+                    deadline_ns: (arg != u64::MAX).then_some(arg),
+                },
+                3 => SchedEvent::Exit {
+                    pid,
+                    status: arg as i32,
+                },
+                _ => panic!("unknown scheduler trace event kind {kind}"),
+            };
+            SchedRecord { at_ns, event }
+        })
+        .collect()
+}
+
 pub fn yield_now() {
     unsafe { kt_yield_now() }
 }
 
+/// Point FS and GS of the calling context at `fs` and `gs`.
+pub fn set_segment_bases(fs: u64, gs: u64) {
+    unsafe { kt_set_segment_bases(fs, gs) }
+}
+
+/// The FS and GS bases of the calling context.
+pub fn segment_bases() -> (u64, u64) {
+    let mut fs = 0;
+    let mut gs = 0;
+    unsafe { kt_segment_bases(&mut fs, &mut gs) };
+    (fs, gs)
+}
+
+/// Sleep on a wait queue kept for the tests until [`wait_wake`] or until
+/// `timeout_ms` passes.
+pub fn wait_sleep(timeout_ms: Option<u64>) {
+    unsafe { kt_wait_sleep(timeout_ms.map_or(-1, |ms| ms as i64)) }
+}
+
+pub fn wait_wake() {
+    unsafe { kt_wait_wake() }
+}
+
 pub fn mmap_anonymous(len: usize) -> i64 {
     unsafe { kt_mmap_anonymous(len) }
 }
 
+/// Anonymous mapping with no file behind it.
+pub fn mmap(addr: usize, len: usize, prot: u64, flags: u64) -> i64 {
+    unsafe { kt_mmap(addr, len, prot, flags) }
+}
+
+pub fn brk(addr: usize) -> i64 {
+    unsafe { kt_brk(addr) }
+}
+
+pub fn setrlimit(resource: usize, cur: u64, max: u64) -> i64 {
+    unsafe { kt_setrlimit(resource, cur, max) }
+}
+
+/// Raw getpriority(2) result: `20 - nice`, or a negated errno.
+pub fn getpriority(which: u64, who: usize) -> i64 {
+    unsafe { kt_getpriority(which, who) }
+}
+
+pub fn setpriority(which: u64, who: usize, nice: i32) -> i64 {
+    unsafe { kt_setpriority(which, who, nice) }
+}
+
+/// Raw sched_setaffinity(2) with CPU `n` in bit `n % 64` of `mask[n / 64]`.
+pub fn sched_setaffinity(pid: usize, mask: &[u64]) -> i64 {
+    unsafe { kt_sched_setaffinity(pid, mask.as_ptr(), mask.len()) }
+}
+
+/// Raw sched_getaffinity(2): the number of bytes of `mask` filled in, or a
+/// negated errno.
+pub fn sched_getaffinity(pid: usize, mask: &mut [u64]) -> i64 {
+    unsafe { kt_sched_getaffinity(pid, mask.as_mut_ptr(), mask.len()) }
+}
+
+/// Reserved and resident bytes of a process's address space.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub mapped: u64,
+    pub resident: u64,
+}
+
+/// Usage of process `pid`, or of the caller for pid 0.
+pub fn memory_usage(pid: usize, usage: &mut MemoryUsage) -> i64 {
+    unsafe { kt_memory_usage(pid, &mut usage.mapped, &mut usage.resident) }
+}
+
+/// RAM pages the VM reported past the kernel image, and pages the page
+/// allocator was given to manage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BootMemory {
+    pub ram_pages: u64,
+    pub allocatable_pages: u64,
+}
+
+pub fn boot_memory() -> BootMemory {
+    let mut memory = BootMemory::default();
+    unsafe { kt_boot_memory(&mut memory.ram_pages, &mut memory.allocatable_pages) };
+    memory
+}
+
+/// The hypervisor vendor the kernel finds in CPUID, if it finds one.
+pub fn hypervisor_vendor() -> Option<[u8; 12]> {
+    let mut vendor = [0; 12];
+    unsafe { kt_hypervisor_vendor(&mut vendor) }.then_some(vendor)
+}
+
+/// Live kernel heap objects and the bytes they take at their rounded sizes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapUsage {
+    pub live_objects: u64,
+    pub bytes_in_use: u64,
+}
+
+/// Kernel heap usage; the kernel also prints its per-class report.
+pub fn kmalloc_stats() -> HeapUsage {
+    let mut usage = HeapUsage::default();
+    unsafe { kt_kmalloc_stats(&mut usage.live_objects, &mut usage.bytes_in_use) };
+    usage
+}
+
+/// Where a virtual address points in the running page tables.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Translation {
+    pub paddr: u64,
+    pub writable: bool,
+    pub user: bool,
+}
+
+pub fn translate(vaddr: usize) -> Option<Translation> {
+    let mut translation = Translation::default();
+    let mapped = unsafe {
+        kt_translate(
+            vaddr,
+            &mut translation.paddr,
+            &mut translation.writable,
+            &mut translation.user,
+        )
+    };
+    mapped.then_some(translation)
+}
+
+/// Bytes of `[start, end)` that the running page tables map, counted in
+/// whole pages.
+pub fn mapped_bytes(start: usize, end: usize) -> u64 {
+    unsafe { kt_mapped_bytes(start, end) }
+}
+
+pub fn mkdir(path: &CStr, mode: u32) -> i64 {
+    unsafe { kt_mkdir(path.as_ptr(), mode) }
+}
+
+pub fn rmdir(path: &CStr) -> i64 {
+    unsafe { kt_rmdir(path.as_ptr()) }
+}
+
+pub fn chdir(path: &CStr) -> i64 {
+    unsafe { kt_chdir(path.as_ptr()) }
+}
+
+pub fn getcwd(buf: &mut [u8]) -> i64 {
+    unsafe { kt_getcwd(buf.as_mut_ptr(), buf.len()) }
+}
+
+pub fn umask(mask: u32) -> i64 {
+    unsafe { kt_umask(mask) }
+}
+
+pub fn access(path: &CStr, mode: u64) -> i64 {
+    unsafe { kt_access(path.as_ptr(), mode) }
+}
+
+pub fn readlink(path: &CStr, buf: &mut [u8]) -> i64 {
+    unsafe { kt_readlink(path.as_ptr(), buf.as_mut_ptr(), buf.len()) }
+}
+
+pub fn openat(dirfd: i64, path: &CStr, flags: u64, mode: u32) -> i64 {
+    unsafe { kt_openat(dirfd, path.as_ptr(), flags, mode) }
+}
+
+pub fn close(fd: u64) -> i64 {
+    unsafe { kt_close(fd) }
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> i64 {
+    unsafe { kt_read(fd, buf.as_mut_ptr(), buf.len()) }
+}
+
+pub fn write(fd: u64, buf: &[u8]) -> i64 {
+    unsafe { kt_write(fd, buf.as_ptr(), buf.len()) }
+}
+
+pub fn lseek(fd: u64, offset: i64, whence: u64) -> i64 {
+    unsafe { kt_lseek(fd, offset, whence) }
+}
+
+/// Filesystem type, free blocks and free inodes, as reported by statfs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FsInfo {
+    pub f_type: i64,
+    pub bfree: u64,
+    pub ffree: u64,
+}
+
+pub fn statfs(path: &CStr, info: &mut FsInfo) -> i64 {
+    unsafe {
+        kt_statfs(
+            path.as_ptr(),
+            &mut info.f_type,
+            &mut info.bfree,
+            &mut info.ffree,
+        )
+    }
+}
+
+pub fn fstatfs(fd: u64, info: &mut FsInfo) -> i64 {
+    unsafe { kt_fstatfs(fd, &mut info.f_type, &mut info.bfree, &mut info.ffree) }
+}
+
+pub fn sendfile(out_fd: u64, in_fd: u64, offset: Option<&mut i64>, count: usize) -> i64 {
+    let offset = offset.map_or(core::ptr::null_mut(), |offset| offset as *mut i64);
+    unsafe { kt_sendfile(out_fd, in_fd, offset, count) }
+}
+
+pub fn ftruncate(fd: u64, len: i64) -> i64 {
+    unsafe { kt_ftruncate(fd, len) }
+}
+
+pub fn unlink(path: &CStr) -> i64 {
+    unsafe { kt_unlink(path.as_ptr()) }
+}
+
+/// Run the ramfs memory-pressure shrinker, returning the pages it freed.
+pub fn shrink_ramfs(pages: usize) -> usize {
+    unsafe { kt_shrink_ramfs(pages) }
+}
+
+pub fn poll(fd: i32, events: i16, timeout_ms: i32) -> i64 {
+    unsafe { kt_poll(fd, events, timeout_ms) }
+}
+
+pub fn epoll_create1(flags: u64) -> i64 {
+    unsafe { kt_epoll_create1(flags) }
+}
+
+pub fn epoll_ctl(epfd: u64, op: u64, fd: u64, events: u32, data: u64) -> i64 {
+    unsafe { kt_epoll_ctl(epfd, op, fd, events, data) }
+}
+
+pub fn epoll_wait(epfd: u64, timeout_ms: i32, events: &mut u32, data: &mut u64) -> i64 {
+    unsafe { kt_epoll_wait(epfd, timeout_ms, events, data) }
+}
+
+pub fn eventfd(initval: u32, flags: u64) -> i64 {
+    unsafe { kt_eventfd(initval, flags) }
+}
+
+pub fn timerfd_create(clockid: u64, flags: u64) -> i64 {
+    unsafe { kt_timerfd_create(clockid, flags) }
+}
+
+pub fn timerfd_settime(fd: u64, value_ns: u64, interval_ns: u64) -> i64 {
+    unsafe { kt_timerfd_settime(fd, value_ns, interval_ns) }
+}
+
+pub fn socket(ty: u64) -> i64 {
+    unsafe { kt_socket(ty) }
+}
+
+pub fn tcp_socket(flags: u64) -> i64 {
+    unsafe { kt_tcp_socket(flags) }
+}
+
+pub fn bind_loopback(fd: u64, port: u16) -> i64 {
+    unsafe { kt_bind_loopback(fd, port) }
+}
+
+pub fn connect_loopback(fd: u64, port: u16) -> i64 {
+    unsafe { kt_connect_loopback(fd, port) }
+}
+
+pub fn bind(fd: u64, name: &[u8]) -> i64 {
+    unsafe { kt_bind(fd, name.as_ptr(), name.len()) }
+}
+
+pub fn listen(fd: u64, backlog: i32) -> i64 {
+    unsafe { kt_listen(fd, backlog) }
+}
+
+pub fn connect(fd: u64, name: &[u8]) -> i64 {
+    unsafe { kt_connect(fd, name.as_ptr(), name.len()) }
+}
+
+pub fn accept4(fd: u64, flags: u64) -> i64 {
+    unsafe { kt_accept4(fd, flags) }
+}
+
+pub fn sendmsg(fd: u64, data: &[u8], to: Option<&[u8]>, flags: u64) -> i64 {
+    unsafe {
+        kt_sendmsg(
+            fd,
+            data.as_ptr(),
+            data.len(),
+            to.map_or(core::ptr::null(), <[u8]>::as_ptr),
+            to.map_or(0, <[u8]>::len),
+            flags,
+        )
+    }
+}
+
+pub fn recvmsg(
+    fd: u64,
+    buf: &mut [u8],
+    flags: u64,
+    from: &mut [u8; 108],
+    from_len: &mut usize,
+    truncated: &mut bool,
+) -> i64 {
+    unsafe {
+        kt_recvmsg(
+            fd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            flags,
+            from.as_mut_ptr(),
+            from_len,
+            truncated,
+        )
+    }
+}
+
+/// A classic BPF instruction, laid out as `struct sock_filter`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+pub fn seccomp_strict() -> i64 {
+    unsafe { kt_seccomp_strict() }
+}
+
+pub fn seccomp_filter(filter: &[SockFilter]) -> i64 {
+    unsafe { kt_seccomp_filter(filter.as_ptr(), filter.len() as u16) }
+}
+
 pub fn exit(status: i32) -> ! {
     unsafe { kt_exit(status) }
 }
@@ -1,8 +1,13 @@
 #![no_std]
 
+extern crate alloc;
 extern crate self as kernel_tests;
 
 mod api;
+mod test_alloc;
+mod test_events;
+mod test_fs;
+mod test_net;
 mod test_process;
 
 pub use kernel_tests_macros::KernelTest;
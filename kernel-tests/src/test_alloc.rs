@@ -0,0 +1,70 @@
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+
+use crate::api;
+use kernel_tests_macros::kernel_test;
+
+#[kernel_test]
+fn kernel_heap_backs_box_vec_and_btreemap() {
+    let boxed = Box::new(0xdead_beef_u64);
+    assert_eq!(*boxed, 0xdead_beef);
+
+    let mut values = Vec::new();
+    for i in 0..100_000u64 {
+        values.push(i);
+    }
+    assert_eq!(values.len(), 100_000);
+    assert_eq!(values.iter().sum::<u64>(), 99_999 * 100_000 / 2);
+
+    let mut map = BTreeMap::new();
+    for i in (0..1000u64).rev() {
+        map.insert(i, i * i);
+    }
+    assert_eq!(map.get(&31), Some(&961));
+    assert_eq!(map.keys().next(), Some(&0));
+
+    drop(values);
+    drop(map);
+    drop(boxed);
+}
+
+#[kernel_test]
+fn kernel_heap_honours_alignment() {
+    #[repr(align(4096))]
+    struct PageAligned([u8; 16]);
+
+    let pages: Vec<Box<PageAligned>> = (0..8).map(|_| Box::new(PageAligned([0; 16]))).collect();
+    for page in &pages {
+        assert_eq!(&**page as *const PageAligned as usize % 4096, 0);
+        assert_eq!(page.0, [0; 16]);
+    }
+}
+
+#[kernel_test]
+fn kmalloc_stats_track_live_heap_objects() {
+    let before = api::kmalloc_stats();
+    // Ten 4 KiB blocks and a 1 KiB block for the vector itself. Nothing
+    // reads them, so the compiler may drop them unless they are kept opaque.
+    let mut blocks = Vec::with_capacity(10);
+    for _ in 0..10 {
+        blocks.push(Box::new([0u8; 3000]));
+    }
+    let blocks = core::hint::black_box(blocks);
+    let during = api::kmalloc_stats();
+    assert_eq!(during.live_objects, before.live_objects + 11);
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 10 * 4096 + 1024);
+
+    drop(blocks);
+    assert_eq!(api::kmalloc_stats(), before);
+}
+
+#[kernel_test]
+fn page_allocator_manages_the_ram_reported_by_the_vm() {
+    let memory = api::boot_memory();
+    assert!(memory.ram_pages > 0, "VM reported no RAM");
+    assert_eq!(memory.allocatable_pages, memory.ram_pages);
+}
+
+#[kernel_test]
+fn kernel_finds_itself_running_under_hostel() {
+    assert_eq!(api::hypervisor_vendor(), Some(*b"hostelhostel"));
+}
@@ -0,0 +1,175 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::api;
+use kernel_tests_macros::kernel_test;
+
+const EPERM: i64 = 1;
+const ENOENT: i64 = 2;
+const EAGAIN: i64 = 11;
+const EEXIST: i64 = 17;
+const EINVAL: i64 = 22;
+const EPOLL_CTL_ADD: u64 = 1;
+const EPOLL_CTL_DEL: u64 = 2;
+const EPOLL_CTL_MOD: u64 = 3;
+const EPOLLIN: u32 = 0x001;
+const EPOLLOUT: u32 = 0x004;
+const EPOLLONESHOT: u32 = 1 << 30;
+const EFD_SEMAPHORE: u64 = 0o1;
+const EFD_NONBLOCK: u64 = 0o4000;
+const CLOCK_MONOTONIC: u64 = 1;
+const TFD_NONBLOCK: u64 = 0o4000;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const AT_FDCWD: i64 = -100;
+
+static EPOLL_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+static EVENTFD_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+static TIMERFD_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn epoll_reports_console_readiness() {
+    EPOLL_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(epoll_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "epoll process must exit");
+    assert!(
+        EPOLL_PROCESS_DONE.load(Ordering::SeqCst),
+        "epoll process did not reach completion point"
+    );
+}
+
+fn epoll_process_entry() {
+    let epfd = api::epoll_create1(0);
+    assert!(epfd >= 3, "epoll_create1 failed with {}", epfd);
+    let epfd = epfd as u64;
+
+    assert_eq!(api::epoll_ctl(epfd, EPOLL_CTL_ADD, 1, EPOLLOUT, 42), 0);
+    assert_eq!(
+        api::epoll_ctl(epfd, EPOLL_CTL_ADD, 1, EPOLLOUT, 42),
+        -EEXIST
+    );
+
+    let (mut events, mut data) = (0, 0);
+    assert_eq!(api::epoll_wait(epfd, 0, &mut events, &mut data), 1);
+    assert_eq!((events, data), (EPOLLOUT, 42));
+
+    // A one-shot watch fires once and stays quiet until re-armed.
+    assert_eq!(
+        api::epoll_ctl(epfd, EPOLL_CTL_MOD, 1, EPOLLOUT | EPOLLONESHOT, 7),
+        0
+    );
+    assert_eq!(api::epoll_wait(epfd, -1, &mut events, &mut data), 1);
+    assert_eq!(data, 7);
+    assert_eq!(api::epoll_wait(epfd, 1, &mut events, &mut data), 0);
+
+    assert_eq!(api::epoll_ctl(epfd, EPOLL_CTL_DEL, 1, 0, 0), 0);
+    assert_eq!(api::epoll_ctl(epfd, EPOLL_CTL_DEL, 1, 0, 0), -ENOENT);
+
+    let file = api::openat(AT_FDCWD, c"/epoll-file", O_RDWR | O_CREAT, 0o644);
+    assert!(file >= 0, "creating file failed with {}", file);
+    assert_eq!(
+        api::epoll_ctl(epfd, EPOLL_CTL_ADD, file as u64, EPOLLIN, 0),
+        -EPERM
+    );
+
+    assert_eq!(api::close(file as u64), 0);
+    assert_eq!(api::unlink(c"/epoll-file"), 0);
+    assert_eq!(api::close(epfd), 0);
+    EPOLL_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+#[kernel_test]
+fn eventfd_counts_and_wakes_epoll() {
+    EVENTFD_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(eventfd_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "eventfd process must exit");
+    assert!(
+        EVENTFD_PROCESS_DONE.load(Ordering::SeqCst),
+        "eventfd process did not reach completion point"
+    );
+}
+
+fn eventfd_process_entry() {
+    let efd = api::eventfd(0, EFD_NONBLOCK);
+    assert!(efd >= 3, "eventfd failed with {}", efd);
+    let efd = efd as u64;
+    let mut value = [0u8; 8];
+    assert_eq!(api::read(efd, &mut value), -EAGAIN);
+    assert_eq!(api::read(efd, &mut value[..4]), -EINVAL);
+
+    let epfd = api::epoll_create1(0) as u64;
+    assert_eq!(api::epoll_ctl(epfd, EPOLL_CTL_ADD, efd, EPOLLIN, 5), 0);
+    let (mut events, mut data) = (0, 0);
+    assert_eq!(api::epoll_wait(epfd, 0, &mut events, &mut data), 0);
+
+    assert_eq!(api::write(efd, &3u64.to_ne_bytes()), 8);
+    assert_eq!(api::write(efd, &4u64.to_ne_bytes()), 8);
+    assert_eq!(api::epoll_wait(epfd, 0, &mut events, &mut data), 1);
+    assert_eq!((events, data), (EPOLLIN, 5));
+
+    assert_eq!(api::read(efd, &mut value), 8);
+    assert_eq!(u64::from_ne_bytes(value), 7);
+    assert_eq!(api::epoll_wait(epfd, 0, &mut events, &mut data), 0);
+    assert_eq!(api::close(efd), 0);
+    assert_eq!(api::close(epfd), 0);
+
+    // A semaphore counter hands out one unit per read.
+    let sem = api::eventfd(2, EFD_SEMAPHORE) as u64;
+    for _ in 0..2 {
+        assert_eq!(api::read(sem, &mut value), 8);
+        assert_eq!(u64::from_ne_bytes(value), 1);
+    }
+    assert_eq!(api::close(sem), 0);
+    EVENTFD_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+#[kernel_test]
+fn timerfd_expiry_wakes_epoll() {
+    TIMERFD_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(timerfd_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "timerfd process must exit");
+    assert!(
+        TIMERFD_PROCESS_DONE.load(Ordering::SeqCst),
+        "timerfd process did not reach completion point"
+    );
+}
+
+fn timerfd_process_entry() {
+    let tfd = api::timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK);
+    assert!(tfd >= 3, "timerfd_create failed with {}", tfd);
+    let tfd = tfd as u64;
+    let mut expirations = [0u8; 8];
+    assert_eq!(api::read(tfd, &mut expirations), -EAGAIN);
+
+    let epfd = api::epoll_create1(0) as u64;
+    assert_eq!(api::epoll_ctl(epfd, EPOLL_CTL_ADD, tfd, EPOLLIN, 9), 0);
+    assert_eq!(api::timerfd_settime(tfd, 1_000_000, 0), 0);
+
+    let (mut events, mut data) = (0, 0);
+    assert_eq!(api::epoll_wait(epfd, -1, &mut events, &mut data), 1);
+    assert_eq!((events, data), (EPOLLIN, 9));
+    assert_eq!(api::read(tfd, &mut expirations), 8);
+    assert_eq!(u64::from_ne_bytes(expirations), 1);
+
+    // Disarmed timers never fire.
+    assert_eq!(api::timerfd_settime(tfd, 0, 0), 0);
+    assert_eq!(api::epoll_wait(epfd, 2, &mut events, &mut data), 0);
+
+    assert_eq!(api::close(tfd), 0);
+    assert_eq!(api::close(epfd), 0);
+    TIMERFD_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
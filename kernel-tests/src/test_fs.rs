@@ -0,0 +1,389 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::api;
+use kernel_tests_macros::kernel_test;
+
+const ENOENT: i64 = 2;
+const EINVAL: i64 = 22;
+const R_OK: u64 = 4;
+const ENOTEMPTY: i64 = 39;
+const EACCES: i64 = 13;
+const X_OK: u64 = 1;
+
+static FS_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn process_directory_syscalls() {
+    FS_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(fs_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "filesystem process must exit");
+    assert!(
+        FS_PROCESS_DONE.load(Ordering::SeqCst),
+        "filesystem process did not reach completion point"
+    );
+}
+
+fn fs_process_entry() {
+    assert_eq!(api::mkdir(c"/work", 0o755), 0);
+    assert_eq!(api::mkdir(c"/work/sub", 0o755), 0);
+    assert_eq!(api::chdir(c"/missing"), -ENOENT);
+
+    assert_eq!(api::chdir(c"/work"), 0);
+    assert_eq!(api::chdir(c"sub/.."), 0);
+    let mut cwd = [0u8; 16];
+    let len = api::getcwd(&mut cwd);
+    assert_eq!(&cwd[..len as usize], b"/work\0");
+
+    assert_eq!(api::access(c"sub", R_OK), 0);
+    assert_eq!(api::access(c"missing", R_OK), -ENOENT);
+    let mut link = [0u8; 16];
+    let len = api::readlink(c"/proc/self/exe", &mut link);
+    assert!(len > 0, "readlink failed with return value {}", len);
+    assert_eq!(link[0], b'/');
+    assert_eq!(api::readlink(c"sub", &mut link), -EINVAL);
+
+    assert_eq!(api::rmdir(c"/work"), -ENOTEMPTY);
+    assert_eq!(api::rmdir(c"sub"), 0);
+    assert_eq!(api::chdir(c"/"), 0);
+    assert_eq!(api::rmdir(c"work"), 0);
+    FS_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+const O_RDONLY: u64 = 0o0;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_DIRECTORY: u64 = 0o200000;
+const AT_FDCWD: i64 = -100;
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const EEXIST: i64 = 17;
+const POLLOUT: i16 = 0x004;
+const ENOTDIR: i64 = 20;
+const ESPIPE: i64 = 29;
+const RAMFS_MAGIC: i64 = 0x8584_58f6;
+const SOCK_STREAM: u64 = 1;
+
+static FILE_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+static SENDFILE_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+static STATFS_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+static UMASK_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+static SHRINK_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+
+const RAMFS_BLOCK_SIZE: i64 = 4096;
+
+#[kernel_test]
+fn process_file_io_through_dirfd() {
+    FILE_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(file_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "file process must exit");
+    assert!(
+        FILE_PROCESS_DONE.load(Ordering::SeqCst),
+        "file process did not reach completion point"
+    );
+}
+
+fn file_process_entry() {
+    assert_eq!(api::mkdir(c"/data", 0o755), 0);
+    let dir = api::openat(AT_FDCWD, c"/data", O_RDONLY | O_DIRECTORY, 0);
+    assert!(dir >= 3, "opening directory failed with {}", dir);
+    let dir = dir as u64;
+
+    let fd = api::openat(dir as i64, c"log", O_RDWR | O_CREAT | O_EXCL, 0o644);
+    assert!(fd >= 3, "creating file failed with {}", fd);
+    let fd = fd as u64;
+    assert_eq!(
+        api::openat(dir as i64, c"log", O_RDWR | O_CREAT | O_EXCL, 0o644),
+        -EEXIST
+    );
+    assert_eq!(api::openat(fd as i64, c"x", O_RDONLY, 0), -ENOTDIR);
+
+    assert_eq!(api::write(fd, b"hello world"), 11);
+    assert_eq!(api::ftruncate(fd, 5), 0);
+    assert_eq!(api::lseek(fd, 0, SEEK_SET), 0);
+    let mut buf = [0u8; 16];
+    assert_eq!(api::read(fd, &mut buf), 5);
+    assert_eq!(&buf[..5], b"hello");
+
+    // The data stays reachable through the descriptor after unlink.
+    assert_eq!(api::unlink(c"/data/log"), 0);
+    assert_eq!(api::lseek(fd, 1, SEEK_SET), 1);
+    assert_eq!(api::read(fd, &mut buf), 4);
+    assert_eq!(&buf[..4], b"ello");
+
+    assert_eq!(api::poll(fd as i32, POLLOUT, -1), 1);
+    assert_eq!(api::close(fd), 0);
+    assert_eq!(api::close(dir), 0);
+    // With nothing to wait for, poll yields until its timeout runs out.
+    assert_eq!(api::poll(-1, POLLOUT, 1), 0);
+    assert_eq!(api::rmdir(c"/data"), 0);
+    FILE_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+#[kernel_test]
+fn sendfile_copies_between_files_and_sockets() {
+    SENDFILE_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(sendfile_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "sendfile process must exit");
+    assert!(
+        SENDFILE_PROCESS_DONE.load(Ordering::SeqCst),
+        "sendfile process did not reach completion point"
+    );
+}
+
+fn sendfile_process_entry() {
+    const NAME: &[u8] = b"\0hostel-sendfile";
+
+    let src = api::openat(AT_FDCWD, c"/sendfile-src", O_RDWR | O_CREAT | O_EXCL, 0o644) as u64;
+    let dst = api::openat(AT_FDCWD, c"/sendfile-dst", O_RDWR | O_CREAT | O_EXCL, 0o644) as u64;
+    assert_eq!(api::write(src, b"hello world"), 11);
+
+    // An explicit offset leaves the file offset alone.
+    let mut offset = 6;
+    assert_eq!(api::sendfile(dst, src, Some(&mut offset), 64), 5);
+    assert_eq!(offset, 11);
+    assert_eq!(api::lseek(src, 0, SEEK_SET), 0);
+    assert_eq!(api::sendfile(dst, src, None, 5), 5);
+    assert_eq!(api::lseek(src, 0, SEEK_CUR), 5);
+
+    let mut buf = [0u8; 16];
+    assert_eq!(api::lseek(dst, 0, SEEK_SET), 0);
+    assert_eq!(api::read(dst, &mut buf), 10);
+    assert_eq!(&buf[..10], b"worldhello");
+
+    let listener = api::socket(SOCK_STREAM) as u64;
+    assert_eq!(api::bind(listener, NAME), 0);
+    assert_eq!(api::listen(listener, 1), 0);
+    let client = api::socket(SOCK_STREAM) as u64;
+    assert_eq!(api::connect(client, NAME), 0);
+    let server = api::accept4(listener, 0) as u64;
+
+    assert_eq!(api::sendfile(client, src, None, 64), 6);
+    assert_eq!(api::read(server, &mut buf), 6);
+    assert_eq!(&buf[..6], b" world");
+    assert_eq!(api::sendfile(dst, server, Some(&mut offset), 1), -ESPIPE);
+
+    for fd in [server, client, listener, dst, src] {
+        assert_eq!(api::close(fd), 0);
+    }
+    assert_eq!(api::unlink(c"/sendfile-src"), 0);
+    assert_eq!(api::unlink(c"/sendfile-dst"), 0);
+    SENDFILE_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+#[kernel_test]
+fn statfs_tracks_ramfs_usage() {
+    STATFS_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(statfs_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "statfs process must exit");
+    assert!(
+        STATFS_PROCESS_DONE.load(Ordering::SeqCst),
+        "statfs process did not reach completion point"
+    );
+}
+
+fn statfs_process_entry() {
+    let mut before = api::FsInfo::default();
+    assert_eq!(api::statfs(c"/", &mut before), 0);
+    assert_eq!(before.f_type, RAMFS_MAGIC);
+    let mut after = api::FsInfo::default();
+    assert_eq!(api::statfs(c"/missing", &mut after), -ENOENT);
+
+    // A new file takes an inode; its first byte takes a block, which may
+    // fit in an allocator page that is already in use.
+    let fd = api::openat(AT_FDCWD, c"/statfs", O_RDWR | O_CREAT | O_EXCL, 0o644) as u64;
+    assert_eq!(api::write(fd, b"x"), 1);
+    assert_eq!(api::fstatfs(fd, &mut after), 0);
+    assert_eq!(after.f_type, RAMFS_MAGIC);
+    assert_eq!(after.ffree, before.ffree - 1);
+    assert!(after.bfree <= before.bfree);
+
+    assert_eq!(api::close(fd), 0);
+    assert_eq!(api::unlink(c"/statfs"), 0);
+    assert_eq!(api::statfs(c"/", &mut after), 0);
+    assert_eq!(after, before);
+    STATFS_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+#[kernel_test]
+fn ramfs_shrinker_drops_only_zero_pages() {
+    SHRINK_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(shrink_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "shrink process must exit");
+    assert!(
+        SHRINK_PROCESS_DONE.load(Ordering::SeqCst),
+        "shrink process did not reach completion point"
+    );
+}
+
+fn shrink_process_entry() {
+    let free_blocks = || {
+        let mut info = api::FsInfo::default();
+        assert_eq!(api::statfs(c"/", &mut info), 0);
+        info.bfree
+    };
+
+    // The first block only ever held zeros; the second holds data.
+    let fd = api::openat(AT_FDCWD, c"/shrink", O_RDWR | O_CREAT | O_EXCL, 0o644) as u64;
+    assert_eq!(api::write(fd, &[0; 16]), 16);
+    assert_eq!(api::lseek(fd, RAMFS_BLOCK_SIZE, SEEK_SET), RAMFS_BLOCK_SIZE);
+    assert_eq!(api::write(fd, b"data"), 4);
+    let before = free_blocks();
+    let heap = api::kmalloc_stats().bytes_in_use;
+
+    // Blocks share allocator pages, so dropping one need not free a page.
+    let freed = api::shrink_ramfs(usize::MAX);
+    assert_eq!(free_blocks(), before + freed as u64);
+    assert_eq!(
+        api::kmalloc_stats().bytes_in_use,
+        heap - RAMFS_BLOCK_SIZE as u64,
+        "zero block was not freed"
+    );
+    assert_eq!(api::shrink_ramfs(usize::MAX), 0);
+
+    let mut buf = [0xff; 16];
+    assert_eq!(api::lseek(fd, 0, SEEK_SET), 0);
+    assert_eq!(api::read(fd, &mut buf), 16);
+    assert_eq!(buf, [0; 16]);
+    assert_eq!(api::lseek(fd, RAMFS_BLOCK_SIZE, SEEK_SET), RAMFS_BLOCK_SIZE);
+    assert_eq!(api::read(fd, &mut buf[..4]), 4);
+    assert_eq!(&buf[..4], b"data");
+
+    assert_eq!(api::close(fd), 0);
+    assert_eq!(api::unlink(c"/shrink"), 0);
+    SHRINK_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+#[kernel_test]
+fn umask_clears_mode_bits_of_new_inodes() {
+    UMASK_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(umask_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "umask process must exit");
+    assert!(
+        UMASK_PROCESS_DONE.load(Ordering::SeqCst),
+        "umask process did not reach completion point"
+    );
+}
+
+// Tests run as root, which may only execute files that have some execute
+// bit set, so X_OK shows whether the umask took them away.
+fn umask_process_entry() {
+    assert_eq!(api::umask(0o111), 0o022);
+    let fd = api::openat(AT_FDCWD, c"/masked", O_RDWR | O_CREAT | O_EXCL, 0o755);
+    assert!(fd >= 3, "creating file failed with {}", fd);
+    assert_eq!(api::close(fd as u64), 0);
+    assert_eq!(api::access(c"/masked", X_OK), -EACCES);
+
+    assert_eq!(api::umask(0), 0o111);
+    let fd = api::openat(AT_FDCWD, c"/unmasked", O_RDWR | O_CREAT | O_EXCL, 0o755);
+    assert_eq!(api::close(fd as u64), 0);
+    assert_eq!(api::access(c"/unmasked", X_OK), 0);
+    assert_eq!(api::umask(0o1777), 0);
+    assert_eq!(api::umask(0o022), 0o777);
+
+    assert_eq!(api::unlink(c"/masked"), 0);
+    assert_eq!(api::unlink(c"/unmasked"), 0);
+    UMASK_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+const V9FS_MAGIC: i64 = 0x0102_1997;
+const SEEK_END: u64 = 2;
+
+static HOST_SHARE_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn host_share_files_syscalls() {
+    HOST_SHARE_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(host_share_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "host share process must exit");
+    assert!(
+        HOST_SHARE_PROCESS_DONE.load(Ordering::SeqCst),
+        "host share process did not reach completion point"
+    );
+}
+
+// Only runs when the VMM shares a directory holding `input.txt`; the host
+// side checks what is left behind in `out`.
+fn host_share_process_entry() {
+    if api::access(c"/host", R_OK) == -ENOENT {
+        HOST_SHARE_PROCESS_DONE.store(true, Ordering::SeqCst);
+        api::exit(0);
+    }
+
+    let fd = api::openat(AT_FDCWD, c"/host/input.txt", O_RDONLY, 0);
+    assert!(fd >= 3, "opening shared file failed with {}", fd);
+    let fd = fd as u64;
+    let mut buf = [0u8; 32];
+    assert_eq!(api::read(fd, &mut buf), 20);
+    assert_eq!(&buf[..20], b"hello from the host\n");
+    assert_eq!(api::lseek(fd, 0, SEEK_END), 20);
+    let mut info = api::FsInfo::default();
+    assert_eq!(api::fstatfs(fd, &mut info), 0);
+    assert_eq!(info.f_type, V9FS_MAGIC);
+    assert_eq!(api::close(fd), 0);
+
+    let fd = api::openat(
+        AT_FDCWD,
+        c"/host/input.txt",
+        O_RDWR | O_CREAT | O_EXCL,
+        0o644,
+    );
+    assert_eq!(fd, -EEXIST);
+    assert_eq!(api::access(c"/host/missing", R_OK), -ENOENT);
+
+    assert_eq!(api::mkdir(c"/host/out", 0o755), 0);
+    let fd = api::openat(
+        AT_FDCWD,
+        c"/host/out/result.txt",
+        O_RDWR | O_CREAT | O_EXCL,
+        0o644,
+    );
+    assert!(fd >= 3, "creating shared file failed with {}", fd);
+    let fd = fd as u64;
+    assert_eq!(api::write(fd, b"hello from the guest, twice"), 27);
+    assert_eq!(api::ftruncate(fd, 20), 0);
+    assert_eq!(api::lseek(fd, 0, SEEK_SET), 0);
+    assert_eq!(api::read(fd, &mut buf), 20);
+    assert_eq!(&buf[..20], b"hello from the guest");
+    assert_eq!(api::close(fd), 0);
+    assert_eq!(api::rmdir(c"/host/out"), -ENOTEMPTY);
+
+    assert_eq!(api::statfs(c"/host/out", &mut info), 0);
+    assert_eq!(info.f_type, V9FS_MAGIC);
+    HOST_SHARE_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
@@ -0,0 +1,219 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::api;
+use kernel_tests_macros::kernel_test;
+
+const EAGAIN: i64 = 11;
+const EINVAL: i64 = 22;
+const EPIPE: i64 = 32;
+const EADDRINUSE: i64 = 98;
+const EISCONN: i64 = 106;
+const EINPROGRESS: i64 = 115;
+const SOCK_STREAM: u64 = 1;
+const SOCK_DGRAM: u64 = 2;
+const SOCK_NONBLOCK: u64 = 0o4000;
+const MSG_DONTWAIT: u64 = 0x40;
+
+static STREAM_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+static DATAGRAM_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+static TCP_PROCESS_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn unix_stream_sockets_connect_and_exchange() {
+    STREAM_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(stream_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "stream socket process must exit");
+    assert!(
+        STREAM_PROCESS_DONE.load(Ordering::SeqCst),
+        "stream socket process did not reach completion point"
+    );
+}
+
+fn stream_process_entry() {
+    const NAME: &[u8] = b"\0hostel-stream";
+
+    let listener = api::socket(SOCK_STREAM | SOCK_NONBLOCK);
+    assert!(listener >= 3, "socket failed with {}", listener);
+    let listener = listener as u64;
+    assert_eq!(api::bind(listener, NAME), 0);
+    assert_eq!(api::listen(listener, 1), 0);
+    assert_eq!(api::accept4(listener, 0), -EAGAIN);
+
+    let client = api::socket(SOCK_STREAM) as u64;
+    assert_eq!(api::connect(client, NAME), 0);
+    let server = api::accept4(listener, 0);
+    assert!(server >= 3, "accept4 failed with {}", server);
+    let server = server as u64;
+
+    let (mut from, mut from_len, mut truncated) = ([0; 108], 0, false);
+    let mut buf = [0u8; 16];
+    assert_eq!(api::sendmsg(client, b"ping", None, 0), 4);
+    assert_eq!(
+        api::recvmsg(
+            server,
+            &mut buf,
+            0,
+            &mut from,
+            &mut from_len,
+            &mut truncated
+        ),
+        4
+    );
+    assert_eq!(&buf[..4], b"ping");
+    assert_eq!(
+        api::recvmsg(
+            server,
+            &mut buf,
+            MSG_DONTWAIT,
+            &mut from,
+            &mut from_len,
+            &mut truncated
+        ),
+        -EAGAIN
+    );
+
+    // Closing one end leaves the other at end of file.
+    assert_eq!(api::close(client), 0);
+    assert_eq!(
+        api::recvmsg(
+            server,
+            &mut buf,
+            0,
+            &mut from,
+            &mut from_len,
+            &mut truncated
+        ),
+        0
+    );
+    assert_eq!(api::sendmsg(server, b"pong", None, 0), -EPIPE);
+
+    assert_eq!(api::close(server), 0);
+    assert_eq!(api::close(listener), 0);
+    STREAM_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+#[kernel_test]
+fn unix_datagram_sockets_keep_message_boundaries() {
+    DATAGRAM_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(datagram_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "datagram socket process must exit");
+    assert!(
+        DATAGRAM_PROCESS_DONE.load(Ordering::SeqCst),
+        "datagram socket process did not reach completion point"
+    );
+}
+
+fn datagram_process_entry() {
+    const SENDER: &[u8] = b"\0hostel-dgram-a";
+    const PATH: &[u8] = b"/dgram-sock";
+
+    let sender = api::socket(SOCK_DGRAM) as u64;
+    let receiver = api::socket(SOCK_DGRAM) as u64;
+    assert_eq!(api::bind(sender, SENDER), 0);
+    assert_eq!(api::bind(receiver, PATH), 0);
+    assert_eq!(api::bind(receiver, b"\0other"), -EINVAL);
+    let other = api::socket(SOCK_DGRAM) as u64;
+    assert_eq!(api::bind(other, SENDER), -EADDRINUSE);
+
+    assert_eq!(api::sendmsg(sender, b"hello world", Some(PATH), 0), 11);
+    assert_eq!(api::sendmsg(sender, b"bye", Some(PATH), 0), 3);
+
+    let (mut from, mut from_len, mut truncated) = ([0; 108], 0, false);
+    let mut buf = [0u8; 5];
+    assert_eq!(
+        api::recvmsg(
+            receiver,
+            &mut buf,
+            0,
+            &mut from,
+            &mut from_len,
+            &mut truncated
+        ),
+        5
+    );
+    assert_eq!(&buf, b"hello");
+    assert!(truncated, "short read of a datagram must be flagged");
+    assert_eq!(&from[..from_len], SENDER);
+
+    // The rest of a truncated datagram is dropped.
+    assert_eq!(
+        api::recvmsg(
+            receiver,
+            &mut buf,
+            0,
+            &mut from,
+            &mut from_len,
+            &mut truncated
+        ),
+        3
+    );
+    assert_eq!(&buf[..3], b"bye");
+    assert!(!truncated);
+
+    assert_eq!(api::close(other), 0);
+    assert_eq!(api::close(receiver), 0);
+    assert_eq!(api::close(sender), 0);
+    DATAGRAM_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+#[kernel_test]
+fn tcp_loopback_connection_exchanges_data() {
+    TCP_PROCESS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(tcp_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "tcp socket process must exit");
+    assert!(
+        TCP_PROCESS_DONE.load(Ordering::SeqCst),
+        "tcp socket process did not reach completion point"
+    );
+}
+
+fn tcp_process_entry() {
+    const PORT: u16 = 7000;
+
+    let listener = api::tcp_socket(0);
+    assert!(listener >= 3, "socket failed with {}", listener);
+    let listener = listener as u64;
+    assert_eq!(api::bind_loopback(listener, PORT), 0);
+    assert_eq!(api::listen(listener, 1), 0);
+    let other = api::tcp_socket(0) as u64;
+    assert_eq!(api::bind_loopback(other, PORT), -EADDRINUSE);
+
+    let client = api::tcp_socket(SOCK_NONBLOCK) as u64;
+    assert_eq!(api::connect_loopback(client, PORT), -EINPROGRESS);
+    // A blocking accept drives the handshake to completion.
+    let server = api::accept4(listener, 0);
+    assert!(server >= 3, "accept4 failed with {}", server);
+    let server = server as u64;
+    assert_eq!(api::connect_loopback(client, PORT), 0);
+    assert_eq!(api::connect_loopback(client, PORT), -EISCONN);
+
+    let mut buf = [0u8; 16];
+    assert_eq!(api::write(client, b"ping"), 4);
+    assert_eq!(api::read(server, &mut buf), 4);
+    assert_eq!(&buf[..4], b"ping");
+    assert_eq!(api::read(client, &mut buf), -EAGAIN);
+
+    // Closing one end leaves the other at end of file.
+    assert_eq!(api::close(client), 0);
+    assert_eq!(api::read(server, &mut buf), 0);
+
+    assert_eq!(api::close(server), 0);
+    assert_eq!(api::close(other), 0);
+    assert_eq!(api::close(listener), 0);
+    TCP_PROCESS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
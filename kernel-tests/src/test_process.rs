@@ -1,9 +1,14 @@
-use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
 
 use crate::api;
 use kernel_tests_macros::kernel_test;
 
-const PAGE_SIZE: usize = 2 << 20;
+const PAGE_SIZE: usize = 4096;
+// Size of an allocator page, which user pages are carved out of.
+const ALLOCATOR_PAGE_SIZE: usize = 2 << 20;
 const MAGIC_VALUE: u64 = 0xfeed_face_cafe_beef;
 
 static PROCESS_DONE: AtomicBool = AtomicBool::new(false);
@@ -48,3 +53,1489 @@ fn process_entry() {
 
     api::exit(0);
 }
+
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+static INITIAL_STACK_CHECKED: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn processes_start_with_a_sysv_initial_stack() {
+    INITIAL_STACK_CHECKED.store(false, Ordering::SeqCst);
+
+    let entry: fn(usize) = initial_stack_entry;
+    api::spawn_named("argv-check", entry, entry as usize);
+    api::yield_now();
+
+    assert!(
+        INITIAL_STACK_CHECKED.load(Ordering::SeqCst),
+        "process did not get through its initial stack"
+    );
+}
+
+fn initial_stack_entry(entry: usize) {
+    let rsp = api::initial_stack();
+    assert_eq!(rsp % 16, 0);
+    let word = |index: usize| unsafe { ((rsp + 8 * index) as *const u64).read() };
+
+    // argc, argv holding the name alone, then an empty envp.
+    assert_eq!(word(0), 1);
+    let argv0 = unsafe { CStr::from_ptr(word(1) as *const core::ffi::c_char) };
+    assert_eq!(argv0.to_bytes(), b"argv-check");
+    assert_eq!(word(2), 0);
+    assert_eq!(word(3), 0);
+
+    let aux = |key| {
+        let mut index = 4;
+        while word(index) != AT_NULL {
+            if word(index) == key {
+                return Some(word(index + 1));
+            }
+            index += 2;
+        }
+        None
+    };
+    assert_eq!(aux(AT_PAGESZ), Some(PAGE_SIZE as u64));
+    assert_eq!(aux(AT_ENTRY), Some(entry as u64));
+    let random = aux(AT_RANDOM).expect("AT_RANDOM") as usize;
+    assert!(random > rsp, "AT_RANDOM must point above the vector");
+
+    INITIAL_STACK_CHECKED.store(true, Ordering::SeqCst);
+    api::exit(0);
+}
+
+// Twice what the process table used to hold before it could grow.
+const IDLE_PROCESSES: usize = 16;
+
+static IDLE_EXITS: AtomicU64 = AtomicU64::new(0);
+
+#[kernel_test]
+fn process_table_grows_past_its_old_size() {
+    IDLE_EXITS.store(0, Ordering::SeqCst);
+
+    let mut pids = [0; IDLE_PROCESSES];
+    for pid in &mut pids {
+        *pid = api::spawn(idle_process_entry);
+    }
+    api::yield_now();
+
+    assert!(pids.iter().all(|&pid| !api::has_pid(pid)));
+    assert_eq!(IDLE_EXITS.load(Ordering::SeqCst), IDLE_PROCESSES as u64);
+
+    // Exited slots are handed out again, under fresh pids.
+    let pid = api::spawn(idle_process_entry);
+    api::yield_now();
+    assert!(!api::has_pid(pid));
+    assert!(pids.iter().all(|&old| old < pid));
+}
+
+fn idle_process_entry() {
+    IDLE_EXITS.fetch_add(1, Ordering::SeqCst);
+    api::exit(0);
+}
+
+// Stack the kernel gives every process for its syscalls.
+const KERNEL_STACK_SIZE: u64 = 64 * 1024;
+
+#[kernel_test]
+fn exited_processes_give_back_their_kernel_stacks() {
+    // The first process may grow the process table, which stays allocated.
+    api::spawn(idle_process_entry);
+    api::yield_now();
+
+    let before = api::kmalloc_stats();
+    let pid = api::spawn(idle_process_entry);
+    let during = api::kmalloc_stats();
+    // It exits through a syscall, i.e. while running on its kernel stack.
+    api::yield_now();
+
+    assert!(!api::has_pid(pid));
+    assert!(during.bytes_in_use >= before.bytes_in_use + KERNEL_STACK_SIZE);
+    assert_eq!(api::kmalloc_stats(), before);
+}
+
+const ECHILD: i64 = 10;
+const WNOHANG: u64 = 1;
+const SIGILL: i32 = 4;
+const SIGSEGV: i32 = 11;
+const SIGSYS: i32 = 31;
+const CHILD_EXIT_CODE: i32 = 42;
+
+// wait4 status words pack the exit code into the second byte.
+fn exited_with(code: i32) -> i32 {
+    code << 8
+}
+
+fn exit_code_entry(code: usize) {
+    api::exit(code as i32);
+}
+
+#[kernel_test]
+fn exit_status_outlives_the_process() {
+    let pid = api::spawn_with_arg(exit_code_entry, 7);
+    assert_eq!(api::exit_status(pid), None, "a live process has no status");
+    api::yield_now();
+
+    assert!(!api::has_pid(pid));
+    assert_eq!(api::exit_status(pid), Some(exited_with(7)));
+}
+
+static WAIT_CHILD: AtomicU64 = AtomicU64::new(0);
+static WAIT_NOHANG: AtomicI64 = AtomicI64::new(0);
+static WAIT_REAPED: AtomicI64 = AtomicI64::new(0);
+static WAIT_STATUS: AtomicI64 = AtomicI64::new(0);
+static WAIT_AGAIN: AtomicI64 = AtomicI64::new(0);
+static WAIT_BAD_OPTIONS: AtomicI64 = AtomicI64::new(0);
+
+#[kernel_test]
+fn parents_reap_their_children_with_wait4() {
+    WAIT_CHILD.store(0, Ordering::SeqCst);
+    WAIT_NOHANG.store(-1, Ordering::SeqCst);
+    WAIT_REAPED.store(0, Ordering::SeqCst);
+    WAIT_STATUS.store(0, Ordering::SeqCst);
+    WAIT_AGAIN.store(0, Ordering::SeqCst);
+    WAIT_BAD_OPTIONS.store(0, Ordering::SeqCst);
+
+    let parent = api::spawn(waiting_parent_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(parent));
+    let child = WAIT_CHILD.load(Ordering::SeqCst);
+    assert_ne!(child, 0, "parent did not spawn its child");
+    assert_eq!(WAIT_NOHANG.load(Ordering::SeqCst), 0);
+    assert_eq!(WAIT_REAPED.load(Ordering::SeqCst), child as i64);
+    assert_eq!(
+        WAIT_STATUS.load(Ordering::SeqCst),
+        exited_with(CHILD_EXIT_CODE) as i64
+    );
+    assert_eq!(WAIT_AGAIN.load(Ordering::SeqCst), -ECHILD);
+    assert_eq!(WAIT_BAD_OPTIONS.load(Ordering::SeqCst), -EINVAL);
+}
+
+fn waiting_parent_entry() {
+    let child = api::spawn(waiting_child_entry);
+    WAIT_CHILD.store(child as u64, Ordering::SeqCst);
+
+    // The child sleeps until woken, so it cannot have exited yet.
+    let mut status = 0;
+    WAIT_NOHANG.store(
+        api::wait4(child as i64, &mut status, WNOHANG),
+        Ordering::SeqCst,
+    );
+    WAIT_BAD_OPTIONS.store(api::wait4(-1, &mut status, 0x80), Ordering::SeqCst);
+
+    // A wake before the child sleeps would be lost. Without a timer the
+    // child never blocks, and exits after a yield instead.
+    while api::find_process(child).is_some_and(|entry| entry.state != api::TASK_BLOCKED) {
+        api::yield_now();
+    }
+    api::wait_wake();
+
+    WAIT_REAPED.store(api::wait4(-1, &mut status, 0), Ordering::SeqCst);
+    WAIT_STATUS.store(status as i64, Ordering::SeqCst);
+    WAIT_AGAIN.store(api::wait4(-1, &mut status, 0), Ordering::SeqCst);
+    api::exit(0);
+}
+
+fn waiting_child_entry() {
+    api::wait_sleep(None);
+    api::exit(CHILD_EXIT_CODE);
+}
+
+const SIGKILL: u64 = 9;
+const SIGTERM: u64 = 15;
+
+static KILLER_PID: AtomicU64 = AtomicU64::new(0);
+static KILLED_CHECKED: AtomicBool = AtomicBool::new(false);
+static ENDLESS_STARTED: AtomicU64 = AtomicU64::new(0);
+
+#[kernel_test]
+fn kill_ends_other_processes_and_the_caller_with_sigkill() {
+    KILLED_CHECKED.store(false, Ordering::SeqCst);
+    ENDLESS_STARTED.store(0, Ordering::SeqCst);
+    // The first sleep may grow the wait queue, which stays allocated.
+    api::spawn(brief_sleeper_entry);
+    api::yield_now();
+
+    let before = api::kmalloc_stats();
+    let killer = api::spawn(killer_entry);
+    KILLER_PID.store(killer as u64, Ordering::SeqCst);
+    api::yield_now();
+
+    assert!(KILLED_CHECKED.load(Ordering::SeqCst));
+    assert!(!api::has_pid(killer));
+    assert_eq!(api::exit_status(killer), Some(SIGKILL as i32));
+    // Stacks of everything killed were given back.
+    assert_eq!(api::kmalloc_stats(), before);
+}
+
+fn killer_entry() {
+    let sleeper = api::spawn(endless_sleeper_entry);
+    let yielder = api::spawn(endless_yielder_entry);
+    while ENDLESS_STARTED.load(Ordering::SeqCst) < 2 {
+        api::yield_now();
+    }
+
+    assert_eq!(api::kill(sleeper as i64, 0), 0);
+    assert_eq!(api::kill(yielder as i64, SIGTERM), -EINVAL);
+    assert_eq!(api::kill(0, SIGKILL), -EINVAL);
+    assert_eq!(api::kill(-1, SIGKILL), -EINVAL);
+    for pid in [sleeper, yielder] {
+        assert_eq!(api::kill(pid as i64, SIGKILL), 0);
+        assert!(!api::has_pid(pid), "{pid} outlived SIGKILL");
+        let mut status = 0;
+        assert_eq!(api::wait4(pid as i64, &mut status, 0), pid as i64);
+        assert_eq!(status, SIGKILL as i32);
+        assert_eq!(api::kill(pid as i64, SIGKILL), -ESRCH);
+        assert_eq!(api::kill(pid as i64, 0), -ESRCH);
+    }
+
+    KILLED_CHECKED.store(true, Ordering::SeqCst);
+    api::kill(KILLER_PID.load(Ordering::SeqCst) as i64, SIGKILL);
+    panic!("survived killing itself");
+}
+
+fn brief_sleeper_entry() {
+    api::wait_sleep(Some(0));
+    api::exit(0);
+}
+
+// Runs until killed, asleep whenever the timer can wake it.
+fn endless_sleeper_entry() {
+    ENDLESS_STARTED.fetch_add(1, Ordering::SeqCst);
+    loop {
+        api::wait_sleep(None);
+    }
+}
+
+fn endless_yielder_entry() {
+    ENDLESS_STARTED.fetch_add(1, Ordering::SeqCst);
+    loop {
+        api::yield_now();
+    }
+}
+
+static SEGMENT_BASES_CHECKED: AtomicU64 = AtomicU64::new(0);
+
+#[kernel_test]
+fn fs_and_gs_bases_survive_context_switches() {
+    SEGMENT_BASES_CHECKED.store(0, Ordering::SeqCst);
+    let (kernel_fs, kernel_gs) = api::segment_bases();
+    api::set_segment_bases(0x1000, 0x2000);
+
+    for arg in 1..=2 {
+        api::spawn_with_arg(segment_bases_entry, arg);
+    }
+    api::yield_now();
+
+    assert_eq!(SEGMENT_BASES_CHECKED.load(Ordering::SeqCst), 2);
+    assert_eq!(api::segment_bases(), (0x1000, 0x2000));
+    api::set_segment_bases(kernel_fs, kernel_gs);
+}
+
+// Sets bases of its own and checks the other process never clobbers them.
+fn segment_bases_entry(arg: usize) {
+    assert_eq!(
+        api::segment_bases(),
+        (0, 0),
+        "new processes start without bases"
+    );
+    let bases = (arg as u64 * 0x10_0000, arg as u64 * 0x10_0000 + 0x8000);
+    api::set_segment_bases(bases.0, bases.1);
+    for _ in 0..8 {
+        api::yield_now();
+        assert_eq!(api::segment_bases(), bases);
+    }
+    SEGMENT_BASES_CHECKED.fetch_add(1, Ordering::SeqCst);
+    api::exit(0);
+}
+
+static ORPHAN_STARTED: AtomicBool = AtomicBool::new(false);
+static ORPHAN_FIRST_PPID: AtomicU64 = AtomicU64::new(0);
+static ORPHAN_LAST_PPID: AtomicU64 = AtomicU64::new(0);
+
+#[kernel_test]
+fn orphans_are_handed_to_the_kernel_without_init() {
+    ORPHAN_STARTED.store(false, Ordering::SeqCst);
+    ORPHAN_FIRST_PPID.store(u64::MAX, Ordering::SeqCst);
+    ORPHAN_LAST_PPID.store(u64::MAX, Ordering::SeqCst);
+
+    let parent = api::spawn(short_lived_parent_entry);
+    api::yield_now();
+
+    assert!(ORPHAN_STARTED.load(Ordering::SeqCst));
+    assert_eq!(ORPHAN_FIRST_PPID.load(Ordering::SeqCst), parent as u64);
+    // Pid 1 belongs to whichever test spawned first and is long gone, so
+    // only the kernel is left to adopt the orphan.
+    assert!(!api::has_pid(1));
+    assert_eq!(ORPHAN_LAST_PPID.load(Ordering::SeqCst), 0);
+}
+
+fn short_lived_parent_entry() {
+    api::spawn(orphan_entry);
+    while !ORPHAN_STARTED.load(Ordering::SeqCst) {
+        api::yield_now();
+    }
+    api::exit(0);
+}
+
+fn orphan_entry() {
+    let parent = api::getppid();
+    ORPHAN_FIRST_PPID.store(parent as u64, Ordering::SeqCst);
+    ORPHAN_STARTED.store(true, Ordering::SeqCst);
+    while api::has_pid(parent) {
+        api::yield_now();
+    }
+    ORPHAN_LAST_PPID.store(api::getppid() as u64, Ordering::SeqCst);
+    api::exit(0);
+}
+
+const RUSAGE_SELF: i64 = 0;
+const RUSAGE_CHILDREN: i64 = -1;
+// Enough work to take a measurable share of a microsecond-grained clock.
+const SPIN_ITERATIONS: u64 = 1_000_000;
+const SYSCALL_ITERATIONS: usize = 10_000;
+
+static CPU_PID: AtomicU64 = AtomicU64::new(0);
+static CPU_CHECKED: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn cpu_time_is_accounted_per_process() {
+    CPU_CHECKED.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn_named("cpu-hog", cpu_hog_entry, 0);
+    CPU_PID.store(pid as u64, Ordering::SeqCst);
+    api::yield_now();
+
+    assert!(CPU_CHECKED.load(Ordering::SeqCst), "cpu-hog never finished");
+}
+
+fn cpu_hog_entry(_: usize) {
+    spin();
+    for _ in 0..SYSCALL_ITERATIONS {
+        api::getppid();
+    }
+    let mut usage = api::CpuUsage::default();
+    assert_eq!(api::getrusage(RUSAGE_SELF, &mut usage), 0);
+    assert!(usage.user_us > 0, "no user time in {usage:?}");
+    assert!(usage.system_us > 0, "no system time in {usage:?}");
+    assert_eq!(api::getrusage(7, &mut usage), -EINVAL);
+
+    let mut children = api::CpuUsage::default();
+    assert_eq!(api::getrusage(RUSAGE_CHILDREN, &mut children), 0);
+    assert_eq!(children, api::CpuUsage::default());
+    let child = api::spawn(spinning_child_entry);
+    let mut status = 0;
+    assert_eq!(api::wait4(child as i64, &mut status, 0), child as i64);
+    assert_eq!(api::getrusage(RUSAGE_CHILDREN, &mut children), 0);
+    assert!(children.user_us > 0, "no child user time in {children:?}");
+
+    let mut tms = api::Tms::default();
+    assert!(api::times(&mut tms) >= 0);
+    assert!(tms.utime >= (usage.user_us / 10_000) as i64);
+    assert_eq!(tms.cutime, (children.user_us / 10_000) as i64);
+
+    let mut buf = [0u8; 128];
+    let stat =
+        core::str::from_utf8(read_proc(c"/proc/self/stat", &mut buf)).expect("stat is ASCII");
+    let mut fields = stat.split_whitespace();
+    let pid = fields.next().and_then(|field| field.parse::<u64>().ok());
+    assert_eq!(pid, Some(CPU_PID.load(Ordering::SeqCst)));
+    assert_eq!(fields.next(), Some("(cpu-hog)"));
+    assert_eq!(fields.next(), Some("R"));
+    assert_eq!(fields.next(), Some("0"), "the kernel spawned cpu-hog");
+    let utime = fields.nth(9).and_then(|field| field.parse::<i64>().ok());
+    assert!(utime >= Some(tms.utime), "stat {stat:?} after {tms:?}");
+    assert_eq!(fields.count(), 3, "stat {stat:?} has the wrong field count");
+    CPU_CHECKED.store(true, Ordering::SeqCst);
+    api::exit(0);
+}
+
+static IDLE_SLEPT_TICKS: AtomicI64 = AtomicI64::new(0);
+static IDLE_USED_US: AtomicU64 = AtomicU64::new(u64::MAX);
+
+#[kernel_test]
+fn the_idle_task_holds_the_cpu_while_everything_sleeps() {
+    IDLE_SLEPT_TICKS.store(0, Ordering::SeqCst);
+    IDLE_USED_US.store(u64::MAX, Ordering::SeqCst);
+
+    api::spawn(lone_sleeper_entry);
+    api::yield_now();
+
+    // Only the timer can have ended the sleep, and the time spent halted
+    // in between belongs to no process.
+    assert!(IDLE_SLEPT_TICKS.load(Ordering::SeqCst) >= 9);
+    let used = IDLE_USED_US.load(Ordering::SeqCst);
+    assert!(used < 50_000, "charged {used} us for sleeping");
+}
+
+fn lone_sleeper_entry() {
+    // The first sleep may have to grow the wait queue, and setting up a
+    // fresh slab for that is slow enough to show up below.
+    api::wait_sleep(Some(0));
+    let mut before = api::CpuUsage::default();
+    let mut after = api::CpuUsage::default();
+    let mut tms = api::Tms::default();
+    assert_eq!(api::getrusage(RUSAGE_SELF, &mut before), 0);
+    let start = api::times(&mut tms);
+    api::wait_sleep(Some(100));
+    IDLE_SLEPT_TICKS.store(api::times(&mut tms) - start, Ordering::SeqCst);
+    assert_eq!(api::getrusage(RUSAGE_SELF, &mut after), 0);
+    let used = after.user_us + after.system_us - before.user_us - before.system_us;
+    IDLE_USED_US.store(used, Ordering::SeqCst);
+    api::exit(0);
+}
+
+fn spinning_child_entry() {
+    spin();
+    api::exit(0);
+}
+
+fn spin() {
+    for i in 0..SPIN_ITERATIONS {
+        core::hint::black_box(i);
+    }
+}
+
+const RLIMIT_AS: usize = 9;
+const ENOMEM: i64 = 12;
+
+static LIMITED_MMAP_RESULT: AtomicU64 = AtomicU64::new(0);
+
+#[kernel_test]
+fn process_address_space_limit_rejects_mmap() {
+    LIMITED_MMAP_RESULT.store(0, Ordering::SeqCst);
+
+    let pid = api::spawn(limited_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "limited process must exit");
+    assert_eq!(
+        LIMITED_MMAP_RESULT.load(Ordering::SeqCst) as i64,
+        -ENOMEM,
+        "mmap past RLIMIT_AS must fail with ENOMEM"
+    );
+}
+
+fn limited_process_entry() {
+    let limit = PAGE_SIZE as u64;
+    let ret = api::setrlimit(RLIMIT_AS, limit, limit);
+    assert_eq!(ret, 0, "setrlimit failed with return value {}", ret);
+
+    let mapped = api::mmap_anonymous(2 * PAGE_SIZE);
+    LIMITED_MMAP_RESULT.store(mapped as u64, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+static BRK_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn brk_shrink_returns_pages_to_the_allocator() {
+    BRK_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(brk_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "brk process must exit");
+    assert!(
+        BRK_DONE.load(Ordering::SeqCst),
+        "brk process did not reach completion point"
+    );
+}
+
+// statfs on the ramfs reports free allocator pages as free blocks.
+fn brk_process_entry() {
+    let free_pages = || {
+        let mut info = api::FsInfo::default();
+        assert_eq!(api::statfs(c"/", &mut info), 0);
+        info.bfree
+    };
+
+    // Any mapped page keeps the page of share counts allocated, so it does
+    // not show up in the counts below.
+    let anchor = api::mmap_anonymous(PAGE_SIZE);
+    assert!(anchor > 0, "mmap failed with return value {}", anchor);
+    unsafe { (anchor as *mut u64).write_volatile(MAGIC_VALUE) };
+    let base = api::brk(0) as usize;
+    let top = base + ALLOCATOR_PAGE_SIZE + 1;
+    let before = free_pages();
+    assert_eq!(api::brk(top), top as i64);
+    assert!(free_pages() < before);
+
+    // The heap's page tables stay behind, but the pages freed by shrinking
+    // are enough to grow back without taking more from the allocator.
+    assert_eq!(api::brk(base), base as i64);
+    let before = free_pages();
+    assert_eq!(api::brk(top), top as i64);
+    assert_eq!(free_pages(), before);
+
+    assert_eq!(api::brk(base + 1), (base + 1) as i64);
+    unsafe {
+        (base as *mut u64).write_volatile(MAGIC_VALUE);
+        assert_eq!((base as *const u64).read_volatile(), MAGIC_VALUE);
+    }
+
+    assert_eq!(api::brk(base), base as i64);
+    assert_eq!(free_pages(), before);
+    BRK_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+const MAP_SHARED: u64 = 0x01;
+const FORK_CHILD_VALUE: u64 = 0x5eed;
+
+// Each process records what it finds in the shared and then the private
+// page: the child before writing to them, the parent after the child exits.
+static FORK_PAGES: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+static FORK_CHILD_SAW: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+static FORK_PARENT_SAW: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+#[kernel_test]
+fn shared_mappings_stay_shared_with_forked_children() {
+    for saw in FORK_CHILD_SAW.iter().chain(&FORK_PARENT_SAW) {
+        saw.store(0, Ordering::SeqCst);
+    }
+
+    let pid = api::spawn(forking_parent_entry);
+    while api::has_pid(pid) {
+        api::yield_now();
+    }
+
+    let saw = |pages: &[AtomicU64; 2]| pages.each_ref().map(|page| page.load(Ordering::SeqCst));
+    assert_eq!(
+        saw(&FORK_CHILD_SAW),
+        [MAGIC_VALUE, MAGIC_VALUE],
+        "the child starts with the parent's contents in both pages"
+    );
+    assert_eq!(
+        saw(&FORK_PARENT_SAW),
+        [FORK_CHILD_VALUE, MAGIC_VALUE],
+        "only the child's write to the shared page reaches the parent"
+    );
+}
+
+fn forking_parent_entry() {
+    for (page, flags) in FORK_PAGES.iter().zip([MAP_SHARED, MAP_PRIVATE]) {
+        let mapped = api::mmap(0, PAGE_SIZE, RW, flags | MAP_ANONYMOUS);
+        assert!(mapped > 0, "mmap failed with return value {}", mapped);
+        unsafe { (mapped as *mut u64).write_volatile(MAGIC_VALUE) };
+        page.store(mapped as u64, Ordering::SeqCst);
+    }
+
+    let child = api::spawn_forked(forked_child_entry);
+    while api::has_pid(child) {
+        api::yield_now();
+    }
+
+    for (saw, page) in FORK_PARENT_SAW.iter().zip(&FORK_PAGES) {
+        let page = page.load(Ordering::SeqCst) as *const u64;
+        saw.store(unsafe { page.read_volatile() }, Ordering::SeqCst);
+    }
+    api::exit(0);
+}
+
+fn forked_child_entry() {
+    for (saw, page) in FORK_CHILD_SAW.iter().zip(&FORK_PAGES) {
+        let page = page.load(Ordering::SeqCst) as *mut u64;
+        unsafe {
+            saw.store(page.read_volatile(), Ordering::SeqCst);
+            page.write_volatile(FORK_CHILD_VALUE);
+        }
+    }
+    api::exit(0);
+}
+
+const EINVAL: i64 = 22;
+const EEXIST: i64 = 17;
+const EOPNOTSUPP: i64 = 95;
+const MAP_SHARED_VALIDATE: u64 = 0x03;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_GROWSDOWN: u64 = 0x100;
+const MAP_POPULATE: u64 = 0x8000;
+const MAP_STACK: u64 = 0x20000;
+const MAP_HUGETLB: u64 = 0x40000;
+const MAP_FIXED_NOREPLACE: u64 = 0x100000;
+const PROT_NONE: u64 = 0x0;
+const PROT_READ: u64 = 0x1;
+const PROT_WRITE: u64 = 0x2;
+const RW: u64 = PROT_READ | PROT_WRITE;
+
+static MMAP_FLAGS_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn mmap_honours_fixed_and_hint_flags() {
+    MMAP_FLAGS_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(mmap_flags_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "mmap flags process must exit");
+    assert!(
+        MMAP_FLAGS_DONE.load(Ordering::SeqCst),
+        "mmap flags process did not reach completion point"
+    );
+}
+
+fn mmap_flags_process_entry() {
+    let free_pages = || {
+        let mut info = api::FsInfo::default();
+        assert_eq!(api::statfs(c"/", &mut info), 0);
+        info.bfree
+    };
+    let anon = MAP_PRIVATE | MAP_ANONYMOUS;
+
+    let addr = api::mmap(0, PAGE_SIZE, RW, anon | MAP_POPULATE | MAP_STACK);
+    assert!(addr > 0, "mmap failed with return value {}", addr);
+    let addr = addr as usize;
+    unsafe { (addr as *mut u64).write_volatile(MAGIC_VALUE) };
+
+    assert_eq!(
+        api::mmap(addr, PAGE_SIZE, RW, anon | MAP_FIXED_NOREPLACE),
+        -EEXIST
+    );
+    assert_eq!(unsafe { (addr as *const u64).read_volatile() }, MAGIC_VALUE);
+    assert_eq!(
+        api::mmap(addr + 1, PAGE_SIZE, RW, anon | MAP_FIXED_NOREPLACE),
+        -EINVAL
+    );
+
+    // Replacing the page frees the old one, and touching the new one
+    // takes its place.
+    let before = free_pages();
+    assert_eq!(
+        api::mmap(addr, PAGE_SIZE, RW, anon | MAP_FIXED),
+        addr as i64
+    );
+    assert_eq!(unsafe { (addr as *const u64).read_volatile() }, 0);
+    assert_eq!(free_pages(), before);
+
+    // Mappings are made of 4 KiB pages, so the neighbouring page is free.
+    let next = addr + PAGE_SIZE;
+    assert_eq!(
+        api::mmap(next, PAGE_SIZE, RW, anon | MAP_FIXED_NOREPLACE),
+        next as i64
+    );
+    unsafe { (next as *mut u64).write_volatile(MAGIC_VALUE) };
+    assert_eq!(unsafe { (addr as *const u64).read_volatile() }, 0);
+
+    // Replacing the middle of a mapping leaves both ends in place.
+    let span = api::mmap(0, 3 * PAGE_SIZE, RW, anon);
+    assert!(span > 0, "mmap failed with return value {}", span);
+    let span = span as usize;
+    unsafe { (span as *mut u64).write_volatile(MAGIC_VALUE) };
+    let middle = span + PAGE_SIZE;
+    assert_eq!(
+        api::mmap(middle, PAGE_SIZE, RW, anon | MAP_FIXED),
+        middle as i64
+    );
+    for page in [span, span + 2 * PAGE_SIZE] {
+        assert_eq!(
+            api::mmap(page, PAGE_SIZE, RW, anon | MAP_FIXED_NOREPLACE),
+            -EEXIST
+        );
+    }
+    assert_eq!(unsafe { (span as *const u64).read_volatile() }, MAGIC_VALUE);
+
+    assert!(api::mmap(0, PAGE_SIZE, RW, anon | MAP_GROWSDOWN) > 0);
+    let validate = MAP_SHARED_VALIDATE | MAP_ANONYMOUS;
+    assert!(api::mmap(0, PAGE_SIZE, RW, validate | MAP_POPULATE) > 0);
+    assert_eq!(
+        api::mmap(0, PAGE_SIZE, RW, validate | MAP_HUGETLB),
+        -EOPNOTSUPP
+    );
+    MMAP_FLAGS_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+static MMAP_PROT_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn mmap_honours_protection() {
+    MMAP_PROT_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(mmap_prot_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "mmap prot process must exit");
+    assert!(
+        MMAP_PROT_DONE.load(Ordering::SeqCst),
+        "mmap prot process did not reach completion point"
+    );
+}
+
+fn mmap_prot_process_entry() {
+    let anon = MAP_PRIVATE | MAP_ANONYMOUS;
+
+    let read_only = api::mmap(0, PAGE_SIZE, PROT_READ, anon);
+    assert!(read_only > 0, "mmap failed with return value {}", read_only);
+    assert_eq!(unsafe { (read_only as *const u64).read_volatile() }, 0);
+
+    // A PROT_NONE guard still reserves its range.
+    let guard = api::mmap(0, PAGE_SIZE, PROT_NONE, anon);
+    assert!(guard > 0, "mmap failed with return value {}", guard);
+    let guard = guard as usize;
+    assert_eq!(
+        api::mmap(guard, PAGE_SIZE, RW, anon | MAP_FIXED_NOREPLACE),
+        -EEXIST
+    );
+    assert_eq!(
+        api::mmap(guard, PAGE_SIZE, RW, anon | MAP_FIXED),
+        guard as i64
+    );
+    unsafe { (guard as *mut u64).write_volatile(MAGIC_VALUE) };
+    assert_eq!(
+        unsafe { (guard as *const u64).read_volatile() },
+        MAGIC_VALUE
+    );
+
+    assert_eq!(api::mmap(0, PAGE_SIZE, 0x8, anon), -EINVAL);
+    MMAP_PROT_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+static SPARSE_MMAP_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn anonymous_mmap_is_paged_in_on_touch() {
+    SPARSE_MMAP_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(sparse_mmap_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "sparse mmap process must exit");
+    assert!(
+        SPARSE_MMAP_DONE.load(Ordering::SeqCst),
+        "sparse mmap process did not reach completion point"
+    );
+}
+
+fn sparse_mmap_process_entry() {
+    let free_pages = || {
+        let mut info = api::FsInfo::default();
+        assert_eq!(api::statfs(c"/", &mut info), 0);
+        info.bfree
+    };
+    let anon = MAP_PRIVATE | MAP_ANONYMOUS;
+    let len = 64 * ALLOCATOR_PAGE_SIZE;
+
+    // Bookkeeping may take a slab page or two, but nowhere near the 64 pages
+    // an eager mapping would.
+    let before = free_pages();
+    let arena = api::mmap(0, len, RW, anon);
+    assert!(arena > 0, "mmap failed with return value {}", arena);
+    assert!(before - free_pages() < 4, "mmap must not allocate up front");
+
+    // Touch a few pages far apart; each comes in zeroed on first access.
+    let arena = arena as usize;
+    for offset in [0, len / 2, len - PAGE_SIZE] {
+        let ptr = (arena + offset) as *mut u64;
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(MAGIC_VALUE);
+            assert_eq!(ptr.read_volatile(), MAGIC_VALUE);
+        }
+    }
+    assert!(before - free_pages() < 4, "only touched pages are backed");
+
+    let populated = api::mmap(0, 8 * ALLOCATOR_PAGE_SIZE, RW, anon | MAP_POPULATE);
+    assert!(populated > 0, "mmap failed with return value {}", populated);
+    assert!(
+        before - free_pages() >= 8,
+        "MAP_POPULATE must back pages up front"
+    );
+    SPARSE_MMAP_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+static FAULT_REACHED: AtomicBool = AtomicBool::new(false);
+static FAULT_SURVIVED: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn bad_access_terminates_faulting_process() {
+    FAULT_REACHED.store(false, Ordering::SeqCst);
+    FAULT_SURVIVED.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(faulting_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "faulting process must be terminated");
+    assert!(
+        FAULT_REACHED.load(Ordering::SeqCst),
+        "faulting process did not reach the bad access"
+    );
+    assert!(
+        !FAULT_SURVIVED.load(Ordering::SeqCst),
+        "faulting process survived a write to read-only memory"
+    );
+    assert_eq!(api::exit_status(pid), Some(SIGSEGV));
+}
+
+fn faulting_process_entry() {
+    let read_only = api::mmap(0, PAGE_SIZE, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS);
+    assert!(read_only > 0, "mmap failed with return value {}", read_only);
+    FAULT_REACHED.store(true, Ordering::SeqCst);
+
+    unsafe { (read_only as *mut u64).write_volatile(MAGIC_VALUE) };
+    FAULT_SURVIVED.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+#[kernel_test]
+fn cpu_exceptions_terminate_the_faulting_process() {
+    let invalid_opcode = api::spawn(invalid_opcode_entry);
+    let non_canonical = api::spawn(non_canonical_access_entry);
+    api::yield_now();
+
+    assert_eq!(api::exit_status(invalid_opcode), Some(SIGILL));
+    assert_eq!(api::exit_status(non_canonical), Some(SIGSEGV));
+}
+
+fn invalid_opcode_entry() {
+    unsafe { asm!("ud2") };
+    panic!("survived an invalid opcode");
+}
+
+// Addresses outside the canonical range raise #GP rather than a page fault.
+fn non_canonical_access_entry() {
+    unsafe { (0x8000_0000_0000_0000 as *const u64).read_volatile() };
+    panic!("survived a non-canonical access");
+}
+
+static STALE_REACHED: AtomicBool = AtomicBool::new(false);
+static STALE_SURVIVED: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn access_after_unmap_faults_despite_cached_translation() {
+    STALE_REACHED.store(false, Ordering::SeqCst);
+    STALE_SURVIVED.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(stale_translation_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "process must be terminated");
+    assert!(
+        STALE_REACHED.load(Ordering::SeqCst),
+        "process did not reach the unmapped access"
+    );
+    assert!(
+        !STALE_SURVIVED.load(Ordering::SeqCst),
+        "write through a stale TLB entry succeeded"
+    );
+}
+
+fn stale_translation_process_entry() {
+    let base = api::brk(0) as usize;
+    let top = base + PAGE_SIZE;
+    assert_eq!(api::brk(top), top as i64);
+
+    // Reading the page back caches its translation before it goes away.
+    let ptr = base as *mut u64;
+    unsafe {
+        ptr.write_volatile(MAGIC_VALUE);
+        assert_eq!(ptr.read_volatile(), MAGIC_VALUE);
+    }
+    assert_eq!(api::brk(base), base as i64);
+    STALE_REACHED.store(true, Ordering::SeqCst);
+
+    unsafe { ptr.write_volatile(MAGIC_VALUE) };
+    STALE_SURVIVED.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+static TRANSLATE_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn page_table_walk_reports_touched_user_pages() {
+    TRANSLATE_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(translate_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "translate process must exit");
+    assert!(
+        TRANSLATE_DONE.load(Ordering::SeqCst),
+        "translate process did not reach completion point"
+    );
+}
+
+fn translate_process_entry() {
+    let len = 4 * PAGE_SIZE;
+    let arena = api::mmap(0, len, RW, MAP_PRIVATE | MAP_ANONYMOUS);
+    assert!(arena > 0, "mmap failed with return value {}", arena);
+    let arena = arena as usize;
+    assert_eq!(api::mapped_bytes(arena, arena + len), 0);
+
+    for page in [0, 1, 3] {
+        unsafe { ((arena + page * PAGE_SIZE) as *mut u64).write_volatile(MAGIC_VALUE) };
+    }
+    assert_eq!(api::mapped_bytes(arena, arena + len), 3 * PAGE_SIZE as u64);
+
+    let translation = api::translate(arena + 8).expect("touched page is mapped");
+    assert!(translation.writable && translation.user);
+    assert_eq!(translation.paddr % PAGE_SIZE as u64, 8);
+    assert_eq!(api::translate(arena + 2 * PAGE_SIZE), None);
+
+    let read_only = api::mmap(0, PAGE_SIZE, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS);
+    assert!(read_only > 0, "mmap failed with return value {}", read_only);
+    let read_only = read_only as usize;
+    assert_eq!(unsafe { (read_only as *const u64).read_volatile() }, 0);
+    let translation = api::translate(read_only).expect("read page is mapped");
+    assert!(!translation.writable && translation.user);
+
+    // The kernel image is shared into every address space, but not with
+    // user permissions.
+    let code = api::translate(translate_process_entry as *const () as usize)
+        .expect("kernel code is mapped");
+    assert!(!code.user);
+    TRANSLATE_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+const ESRCH: i64 = 3;
+const EACCES: i64 = 13;
+const O_RDONLY: u64 = 0o0;
+const O_RDWR: u64 = 0o2;
+const AT_FDCWD: i64 = -100;
+
+static ACCOUNTING_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn memory_usage_tracks_mapped_and_resident_bytes() {
+    ACCOUNTING_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(accounting_process_entry);
+    let mut usage = api::MemoryUsage::default();
+    assert_eq!(api::memory_usage(pid, &mut usage), 0);
+    assert_eq!(usage, api::MemoryUsage::default());
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "accounting process must exit");
+    assert!(
+        ACCOUNTING_DONE.load(Ordering::SeqCst),
+        "accounting process did not reach completion point"
+    );
+    assert_eq!(api::memory_usage(pid, &mut usage), -ESRCH);
+}
+
+fn accounting_process_entry() {
+    let usage = || {
+        let mut usage = api::MemoryUsage::default();
+        assert_eq!(api::memory_usage(0, &mut usage), 0);
+        usage
+    };
+    let before = usage();
+
+    let len = 4 * PAGE_SIZE;
+    let arena = api::mmap(0, len, RW, MAP_PRIVATE | MAP_ANONYMOUS);
+    assert!(arena > 0, "mmap failed with return value {}", arena);
+    let mapped = usage();
+    assert_eq!(mapped.mapped, before.mapped + len as u64);
+    assert_eq!(
+        mapped.resident, before.resident,
+        "untouched pages are not resident"
+    );
+
+    for page in [0, 2] {
+        unsafe { ((arena as usize + page * PAGE_SIZE) as *mut u64).write_volatile(MAGIC_VALUE) };
+    }
+    let touched = usage();
+    assert_eq!(touched.mapped, mapped.mapped);
+    assert_eq!(touched.resident, before.resident + 2 * PAGE_SIZE as u64);
+
+    assert_eq!(
+        api::openat(AT_FDCWD, c"/proc/self/statm", O_RDWR, 0),
+        -EACCES
+    );
+    let fd = api::openat(AT_FDCWD, c"/proc/self/statm", O_RDONLY, 0);
+    assert!(fd >= 0, "open statm failed with return value {}", fd);
+    let mut buf = [0u8; 64];
+    let read = api::read(fd as u64, &mut buf);
+    assert!(read > 0, "read statm failed with return value {}", read);
+    assert_eq!(api::close(fd as u64), 0);
+
+    let text = core::str::from_utf8(&buf[..read as usize]).expect("statm is ASCII");
+    let mut fields = text
+        .split_ascii_whitespace()
+        .map(|field| field.parse::<u64>());
+    assert_eq!(fields.next(), Some(Ok(touched.mapped / PAGE_SIZE as u64)));
+    assert_eq!(fields.next(), Some(Ok(touched.resident / PAGE_SIZE as u64)));
+
+    ACCOUNTING_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+const EPERM: i64 = 1;
+const ENOENT: i64 = 2;
+const F_OK: u64 = 0;
+const SYS_MKDIR: u32 = 83;
+const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+static FILTERED_DONE: AtomicBool = AtomicBool::new(false);
+static STRICT_REACHED: AtomicBool = AtomicBool::new(false);
+static STRICT_SURVIVED: AtomicBool = AtomicBool::new(false);
+
+const fn bpf(code: u16, k: u32, jt: u8, jf: u8) -> api::SockFilter {
+    api::SockFilter { code, jt, jf, k }
+}
+
+#[kernel_test]
+fn seccomp_filter_fails_denied_syscalls() {
+    FILTERED_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(filtered_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "filtered process must exit");
+    assert!(
+        FILTERED_DONE.load(Ordering::SeqCst),
+        "filtered process did not reach completion point"
+    );
+}
+
+fn filtered_process_entry() {
+    // Load seccomp_data.arch, then seccomp_data.nr.
+    let filter = [
+        bpf(BPF_LD_W_ABS, 4, 0, 0),
+        bpf(BPF_JMP_JEQ_K, AUDIT_ARCH_X86_64, 1, 0),
+        bpf(BPF_RET_K, SECCOMP_RET_KILL_PROCESS, 0, 0),
+        bpf(BPF_LD_W_ABS, 0, 0, 0),
+        bpf(BPF_JMP_JEQ_K, SYS_MKDIR, 0, 1),
+        bpf(BPF_RET_K, SECCOMP_RET_ERRNO | EPERM as u32, 0, 0),
+        bpf(BPF_RET_K, SECCOMP_RET_ALLOW, 0, 0),
+    ];
+    assert_eq!(api::seccomp_filter(&filter), 0);
+    assert_eq!(api::seccomp_strict(), -22, "mode cannot change to strict");
+
+    assert_eq!(api::mkdir(c"/denied", 0o755), -EPERM);
+    assert_eq!(api::access(c"/denied", F_OK), -ENOENT);
+    FILTERED_DONE.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+#[kernel_test]
+fn seccomp_strict_mode_kills_on_disallowed_syscall() {
+    STRICT_REACHED.store(false, Ordering::SeqCst);
+    STRICT_SURVIVED.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(strict_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid), "strict process must be killed");
+    assert!(
+        STRICT_REACHED.load(Ordering::SeqCst),
+        "strict process did not enter strict mode"
+    );
+    assert!(
+        !STRICT_SURVIVED.load(Ordering::SeqCst),
+        "strict process survived a disallowed syscall"
+    );
+    assert_eq!(api::exit_status(pid), Some(SIGSYS));
+}
+
+fn strict_process_entry() {
+    assert_eq!(api::seccomp_strict(), 0);
+    assert_eq!(api::write(1, b""), 0);
+    STRICT_REACHED.store(true, Ordering::SeqCst);
+
+    api::umask(0o022);
+    STRICT_SURVIVED.store(true, Ordering::SeqCst);
+
+    api::exit(0);
+}
+
+// Far longer than a timer tick, and long enough that a spinner not being
+// preempted shows up as a failure rather than a lucky interleaving.
+const SPIN_TSC_TICKS: u64 = 4_000_000_000;
+
+static SPINNER_SAW_OTHER: AtomicBool = AtomicBool::new(false);
+static OTHER_RAN: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn timer_preempts_a_process_that_never_yields() {
+    SPINNER_SAW_OTHER.store(false, Ordering::SeqCst);
+    OTHER_RAN.store(false, Ordering::SeqCst);
+
+    let spinner = api::spawn(spinning_process_entry);
+    let other = api::spawn(other_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(spinner) && !api::has_pid(other));
+    assert!(
+        SPINNER_SAW_OTHER.load(Ordering::SeqCst),
+        "second process only ran after the spinning one gave up the CPU"
+    );
+}
+
+fn spinning_process_entry() {
+    let deadline = unsafe { core::arch::x86_64::_rdtsc() } + SPIN_TSC_TICKS;
+    while !OTHER_RAN.load(Ordering::SeqCst) && unsafe { core::arch::x86_64::_rdtsc() } < deadline {
+        core::hint::spin_loop();
+    }
+    SPINNER_SAW_OTHER.store(OTHER_RAN.load(Ordering::SeqCst), Ordering::SeqCst);
+    api::exit(0);
+}
+
+fn other_process_entry() {
+    OTHER_RAN.store(true, Ordering::SeqCst);
+    api::exit(0);
+}
+
+#[kernel_test]
+fn ticks_held_off_by_the_process_table_lock_still_preempt() {
+    SPINNER_SAW_OTHER.store(false, Ordering::SeqCst);
+    OTHER_RAN.store(false, Ordering::SeqCst);
+
+    let spinner = api::spawn(listing_spinner_entry);
+    let other = api::spawn(other_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(spinner) && !api::has_pid(other));
+    assert!(
+        SPINNER_SAW_OTHER.load(Ordering::SeqCst),
+        "a process busy with the process table was never preempted"
+    );
+}
+
+// Takes the process table lock over and over, so ticks keep arriving while
+// it is held and only land once it is released.
+fn listing_spinner_entry() {
+    let deadline = unsafe { core::arch::x86_64::_rdtsc() } + SPIN_TSC_TICKS;
+    while !OTHER_RAN.load(Ordering::SeqCst) && unsafe { core::arch::x86_64::_rdtsc() } < deadline {
+        core::hint::black_box(api::has_pid(1));
+    }
+    SPINNER_SAW_OTHER.store(OTHER_RAN.load(Ordering::SeqCst), Ordering::SeqCst);
+    api::exit(0);
+}
+
+const PRIO_PROCESS: u64 = 0;
+const PRIO_USER: u64 = 2;
+
+static PRIORITY_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn setpriority_moves_a_process_between_levels() {
+    PRIORITY_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(priority_process_entry);
+    // The raw syscall reports 20 - nice.
+    assert_eq!(api::getpriority(PRIO_PROCESS, pid), 20);
+    assert_eq!(api::setpriority(PRIO_PROCESS, pid, 3), 0);
+    assert_eq!(api::getpriority(PRIO_PROCESS, pid), 17);
+    assert_eq!(api::getpriority(PRIO_USER, pid), -EINVAL);
+    api::yield_now();
+
+    assert!(PRIORITY_DONE.load(Ordering::SeqCst));
+    assert_eq!(api::getpriority(PRIO_PROCESS, pid), -ESRCH);
+    // The kernel's own context is not a process.
+    assert_eq!(api::getpriority(PRIO_PROCESS, 0), -ESRCH);
+}
+
+fn priority_process_entry() {
+    assert_eq!(api::getpriority(PRIO_PROCESS, 0), 17);
+    assert_eq!(api::setpriority(PRIO_PROCESS, 0, 100), 0);
+    assert_eq!(
+        api::getpriority(PRIO_PROCESS, 0),
+        1,
+        "nice is clamped to 19"
+    );
+    assert_eq!(api::setpriority(PRIO_PROCESS, 0, -20), 0);
+    assert_eq!(api::getpriority(PRIO_PROCESS, 0), 40);
+    PRIORITY_DONE.store(true, Ordering::SeqCst);
+    api::exit(0);
+}
+
+static AFFINITY_DONE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn affinity_masks_are_limited_to_online_cpus() {
+    AFFINITY_DONE.store(false, Ordering::SeqCst);
+
+    let pid = api::spawn(affinity_process_entry);
+    let mut mask = [0; 2];
+    // Only one word's worth is filled in, and the single CPU is allowed.
+    assert_eq!(api::sched_getaffinity(pid, &mut mask), 8);
+    assert_eq!(mask, [1, 0]);
+    assert_eq!(api::sched_getaffinity(pid, &mut []), -EINVAL);
+
+    // Nothing runs on CPUs that do not exist.
+    assert_eq!(api::sched_setaffinity(pid, &[0b10]), -EINVAL);
+    assert_eq!(api::sched_setaffinity(pid, &[]), -EINVAL);
+    assert_eq!(api::sched_setaffinity(pid, &[u64::MAX, u64::MAX]), 0);
+    assert_eq!(api::sched_getaffinity(pid, &mut mask[..1]), 8);
+    assert_eq!(mask[0], 1, "the mask only keeps online CPUs");
+    api::yield_now();
+
+    assert!(AFFINITY_DONE.load(Ordering::SeqCst));
+    assert_eq!(api::sched_getaffinity(pid, &mut mask), -ESRCH);
+    assert_eq!(api::sched_setaffinity(0, &[1]), -ESRCH);
+}
+
+fn affinity_process_entry() {
+    assert_eq!(api::sched_setaffinity(0, &[1]), 0);
+    let mut mask = [0];
+    assert_eq!(api::sched_getaffinity(0, &mut mask), 8);
+    assert_eq!(mask, [1]);
+    AFFINITY_DONE.store(true, Ordering::SeqCst);
+    api::exit(0);
+}
+
+static SLEEPER_ASLEEP: AtomicBool = AtomicBool::new(false);
+static SLEEPER_WOKE: AtomicBool = AtomicBool::new(false);
+static SLEEPER_SKIPPED: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn wait_queue_sleepers_only_run_once_woken() {
+    SLEEPER_ASLEEP.store(false, Ordering::SeqCst);
+    SLEEPER_WOKE.store(false, Ordering::SeqCst);
+    SLEEPER_SKIPPED.store(false, Ordering::SeqCst);
+
+    let sleeper = api::spawn(sleeper_process_entry);
+    let waker = api::spawn(waker_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(sleeper) && !api::has_pid(waker));
+    assert!(
+        SLEEPER_SKIPPED.load(Ordering::SeqCst),
+        "sleeping process ran before it was woken"
+    );
+    assert!(SLEEPER_WOKE.load(Ordering::SeqCst));
+}
+
+fn sleeper_process_entry() {
+    SLEEPER_ASLEEP.store(true, Ordering::SeqCst);
+    api::wait_sleep(None);
+    SLEEPER_WOKE.store(true, Ordering::SeqCst);
+    api::exit(0);
+}
+
+fn waker_process_entry() {
+    while !SLEEPER_ASLEEP.load(Ordering::SeqCst) {
+        api::yield_now();
+    }
+    // However often others yield, a blocked process is passed over.
+    for _ in 0..10 {
+        api::yield_now();
+    }
+    SLEEPER_SKIPPED.store(!SLEEPER_WOKE.load(Ordering::SeqCst), Ordering::SeqCst);
+    api::wait_wake();
+    while !SLEEPER_WOKE.load(Ordering::SeqCst) {
+        api::yield_now();
+    }
+    api::exit(0);
+}
+
+static TIMED_SLEEPER_WOKE: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn wait_queue_sleep_ends_at_its_deadline() {
+    TIMED_SLEEPER_WOKE.store(false, Ordering::SeqCst);
+
+    // Nothing else runs, so only the timer can end the sleep.
+    let pid = api::spawn(timed_sleeper_process_entry);
+    api::yield_now();
+
+    assert!(!api::has_pid(pid));
+    assert!(TIMED_SLEEPER_WOKE.load(Ordering::SeqCst));
+}
+
+fn timed_sleeper_process_entry() {
+    api::wait_sleep(Some(20));
+    TIMED_SLEEPER_WOKE.store(true, Ordering::SeqCst);
+    api::exit(0);
+}
+
+#[kernel_test]
+fn scheduler_trace_follows_a_sleep_from_block_to_exit() {
+    let pid = api::spawn(traced_sleeper_process_entry);
+    api::yield_now();
+
+    let trace = api::sched_trace();
+    assert!(trace.windows(2).all(|pair| pair[0].at_ns <= pair[1].at_ns));
+    let events: Vec<_> = trace
+        .iter()
+        .filter(|record| match record.event {
+            api::SchedEvent::Switch { from, to } => from == pid || to == pid,
+            api::SchedEvent::Wakeup { pid: other }
+            | api::SchedEvent::Block { pid: other, .. }
+            | api::SchedEvent::Exit { pid: other, .. } => other == pid,
+        })
+        .collect();
+    let [_, block, _, wakeup, ..] = events[..] else {
+        panic!("trace of {pid} is too short: {events:?}");
+    };
+    let api::SchedEvent::Block {
+        deadline_ns: Some(deadline_ns),
+        ..
+    } = block.event
+    else {
+        panic!("{pid} did not block with a deadline: {events:?}");
+    };
+    assert!(wakeup.at_ns >= deadline_ns, "woken early: {events:?}");
+    // Nothing else was left to run, so the idle task held the CPU.
+    let kinds = events.iter().map(|record| record.event).collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            api::SchedEvent::Switch { from: 0, to: pid },
+            block.event,
+            api::SchedEvent::Switch { from: pid, to: 0 },
+            api::SchedEvent::Wakeup { pid },
+            api::SchedEvent::Switch { from: 0, to: pid },
+            api::SchedEvent::Exit {
+                pid,
+                status: CHILD_EXIT_CODE << 8,
+            },
+            api::SchedEvent::Switch { from: pid, to: 0 },
+        ]
+    );
+}
+
+fn traced_sleeper_process_entry() {
+    api::wait_sleep(Some(20));
+    api::exit(CHILD_EXIT_CODE);
+}
+
+static ARG_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+#[kernel_test]
+fn spawn_passes_its_argument_to_the_entry() {
+    ARG_TOTAL.store(0, Ordering::SeqCst);
+
+    let first = api::spawn_with_arg(arg_process_entry, 1);
+    let second = api::spawn_with_arg(arg_process_entry, 40);
+    api::yield_now();
+
+    assert!(!api::has_pid(first) && !api::has_pid(second));
+    assert_eq!(ARG_TOTAL.load(Ordering::SeqCst), 41);
+}
+
+fn arg_process_entry(arg: usize) {
+    ARG_TOTAL.fetch_add(arg as u64, Ordering::SeqCst);
+    api::exit(0);
+}
+
+static LISTED_ASLEEP: AtomicBool = AtomicBool::new(false);
+static LISTING_CHECKED: AtomicBool = AtomicBool::new(false);
+
+#[kernel_test]
+fn process_listing_reports_names_and_states() {
+    LISTED_ASLEEP.store(false, Ordering::SeqCst);
+    LISTING_CHECKED.store(false, Ordering::SeqCst);
+
+    let sleeper = api::spawn_named("sleeper", listed_sleeper_entry, 0);
+    let inspector = api::spawn_named("inspector", inspector_entry, sleeper);
+    let listed = api::find_process(inspector).expect("new process is listed");
+    assert_eq!(listed.state, api::TASK_READY);
+    assert_eq!(listed.name(), b"inspector");
+
+    api::yield_now();
+    assert!(!api::has_pid(sleeper) && !api::has_pid(inspector));
+    assert!(api::find_process(inspector).is_none());
+    assert!(LISTING_CHECKED.load(Ordering::SeqCst));
+}
+
+fn listed_sleeper_entry(_: usize) {
+    LISTED_ASLEEP.store(true, Ordering::SeqCst);
+    api::wait_sleep(None);
+    api::exit(0);
+}
+
+fn inspector_entry(sleeper: usize) {
+    while !LISTED_ASLEEP.load(Ordering::SeqCst) {
+        api::yield_now();
+    }
+    // The sleeper may still be between announcing and starting its sleep.
+    let mut blocked = false;
+    for _ in 0..100 {
+        blocked = api::find_process(sleeper).map(|entry| entry.state) == Some(api::TASK_BLOCKED);
+        if blocked {
+            break;
+        }
+        api::yield_now();
+    }
+    assert!(blocked, "sleeper is never listed as blocked");
+
+    let me = (0..)
+        .map_while(api::process_at)
+        .find(|entry| entry.name() == b"inspector")
+        .expect("running process is listed");
+    assert_eq!(me.state, api::TASK_RUNNING);
+    let mut buf = [0u8; 32];
+    assert_eq!(read_proc(c"/proc/self/comm", &mut buf), b"inspector\n");
+    assert_eq!(
+        read_proc(comm_path(sleeper, &mut [0; 32]), &mut buf),
+        b"sleeper\n"
+    );
+    assert_eq!(
+        api::openat(
+            AT_FDCWD,
+            comm_path(me.pid + 1000, &mut [0; 32]),
+            O_RDONLY,
+            0
+        ),
+        -ENOENT
+    );
+    assert_eq!(
+        api::openat(AT_FDCWD, c"/proc/self/comm", O_RDWR, 0),
+        -EACCES
+    );
+
+    api::wait_wake();
+    LISTING_CHECKED.store(true, Ordering::SeqCst);
+    api::exit(0);
+}
+
+fn comm_path(pid: usize, buf: &mut [u8; 32]) -> &CStr {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    let mut rest = pid;
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    let parts: [&[u8]; 3] = [b"/proc/", &digits[start..], b"/comm\0"];
+    let mut len = 0;
+    for part in parts {
+        buf[len..len + part.len()].copy_from_slice(part);
+        len += part.len();
+    }
+    CStr::from_bytes_with_nul(&buf[..len]).expect("path has a single NUL")
+}
+
+fn read_proc<'b>(path: &CStr, buf: &'b mut [u8]) -> &'b [u8] {
+    let fd = api::openat(AT_FDCWD, path, O_RDONLY, 0);
+    assert!(fd >= 0, "open {:?} failed with return value {}", path, fd);
+    let read = api::read(fd as u64, buf);
+    assert!(
+        read > 0,
+        "read {:?} failed with return value {}",
+        path,
+        read
+    );
+    assert_eq!(api::close(fd as u64), 0);
+    &buf[..read as usize]
+}
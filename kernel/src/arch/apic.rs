@@ -0,0 +1,131 @@
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::{
+    cpu::{self, Features},
+    rdmsr, wrmsr,
+};
+use crate::time;
+
+/// Vector the local APIC timer fires on.
+pub const TIMER_VECTOR: u8 = 0x20;
+/// Vector the local APIC reports when an interrupt vanished before delivery.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// Timer interrupts per second, i.e. how often a running process can be
+/// preempted.
+pub const TICK_HZ: u64 = 100;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// x2APIC registers live at MSR 0x800 plus the xAPIC offset divided by 16.
+const X2APIC_EOI: u32 = 0x80b;
+const X2APIC_SPURIOUS: u32 = 0x80f;
+const X2APIC_LVT_TIMER: u32 = 0x832;
+const X2APIC_LVT_LINT0: u32 = 0x835;
+const X2APIC_TIMER_INITIAL_COUNT: u32 = 0x838;
+const X2APIC_TIMER_CURRENT_COUNT: u32 = 0x839;
+const X2APIC_TIMER_DIVIDE: u32 = 0x83e;
+
+const SPURIOUS_APIC_ENABLE: u64 = 1 << 8;
+const LVT_MASKED: u64 = 1 << 16;
+// The interrupting controller supplies the vector, as the legacy PIC does.
+const LVT_DELIVERY_EXTINT: u64 = 0b111 << 8;
+const LVT_TIMER_PERIODIC: u64 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u64 = 0b0011;
+const TIMER_DIVISOR: u64 = 16;
+// What KVM clocks the local APIC timer at, assumed if calibration sees the
+// timer stand still.
+const APIC_BUS_HZ: u64 = 1_000_000_000;
+// How long calibration counts the timer down against the TSC.
+const CALIBRATION_MS: u64 = 10;
+
+static TIMER_RUNNING: AtomicBool = AtomicBool::new(false);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Switch the local APIC to x2APIC mode and start a periodic timer firing
+/// [`TICK_HZ`] times a second, as measured by the TSC, on [`TIMER_VECTOR`].
+/// Returns `false` without
+/// touching anything when the CPU has no x2APIC.
+pub fn start_timer() -> bool {
+    if !cpu::features().contains(Features::X2APIC) {
+        return false;
+    }
+    // The APIC comes out of reset enabled in xAPIC mode, which may move
+    // straight to x2APIC mode.
+    let base = rdmsr(IA32_APIC_BASE);
+    wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+    wrmsr(
+        X2APIC_SPURIOUS,
+        SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u64,
+    );
+    wrmsr(X2APIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    let count = calibrate().unwrap_or(APIC_BUS_HZ / TIMER_DIVISOR / TICK_HZ);
+    wrmsr(X2APIC_LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u64);
+    wrmsr(X2APIC_TIMER_INITIAL_COUNT, count);
+    TIMER_RUNNING.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Whether `start_timer` succeeded, i.e. whether anything interrupts a CPU
+/// that is waiting.
+pub fn timer_running() -> bool {
+    TIMER_RUNNING.load(Ordering::Relaxed)
+}
+
+/// Deliver the legacy PIC's interrupts, which come in on LINT0. Only once
+/// [`start_timer`] has switched the APIC to x2APIC mode.
+pub fn accept_pic_interrupts() {
+    wrmsr(X2APIC_LVT_LINT0, LVT_DELIVERY_EXTINT);
+}
+
+/// Timer interrupts taken since the timer started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Count a timer interrupt towards [`ticks`].
+pub(super) fn record_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Let a masked one-shot timer count down for `CALIBRATION_MS` of TSC time and
+// return the initial count that makes it fire `TICK_HZ` times a second, or
+// `None` if it did not move.
+fn calibrate() -> Option<u64> {
+    let window = time::tsc_hz() * CALIBRATION_MS / 1000;
+    wrmsr(X2APIC_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u64);
+    wrmsr(X2APIC_TIMER_INITIAL_COUNT, u32::MAX as u64);
+    let start = time::rdtsc();
+    while time::rdtsc() - start < window {
+        spin_loop();
+    }
+    let remaining = rdmsr(X2APIC_TIMER_CURRENT_COUNT);
+    wrmsr(X2APIC_TIMER_INITIAL_COUNT, 0);
+    count_per_tick(u32::MAX as u64 - remaining, CALIBRATION_MS)
+}
+
+fn count_per_tick(elapsed: u64, window_ms: u64) -> Option<u64> {
+    let count = elapsed * 1000 / window_ms / TICK_HZ;
+    (count != 0).then_some(count)
+}
+
+/// Tell the local APIC the interrupt being handled is done, so it can
+/// deliver the next one.
+pub fn end_of_interrupt() {
+    wrmsr(X2APIC_EOI, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_per_tick_scales_the_window_to_a_tick() {
+        // A 62.5 MHz timer counts 625_000 in 10 ms and 625_000 per 10 ms tick.
+        assert_eq!(count_per_tick(625_000, 10), Some(625_000));
+        assert_eq!(count_per_tick(625_000, 20), Some(312_500));
+        assert_eq!(count_per_tick(0, 10), None);
+    }
+}
@@ -0,0 +1,119 @@
+use core::arch::x86_64::__cpuid;
+
+bitflags::bitflags! {
+    /// Optional capabilities of the CPU the kernel runs on. Hosts pass
+    /// through different subsets, so the kernel checks rather than assumes
+    /// them.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Features: u32 {
+        /// The no-execute page table bit.
+        const NX = 1 << 0;
+        /// 1 GiB leaf entries in the PDPT.
+        const GIGAPAGES = 1 << 1;
+        const RDRAND = 1 << 2;
+        /// RDFSBASE and friends, once CR4 enables them.
+        const FSGSBASE = 1 << 3;
+        const XSAVE = 1 << 4;
+        /// A TSC that ticks at a constant rate through power states.
+        const INVARIANT_TSC = 1 << 5;
+        const X2APIC = 1 << 6;
+    }
+}
+
+const LEAF1_ECX_X2APIC: u32 = 1 << 21;
+const LEAF1_ECX_XSAVE: u32 = 1 << 26;
+const LEAF1_ECX_RDRAND: u32 = 1 << 30;
+const LEAF1_ECX_HYPERVISOR: u32 = 1 << 31;
+// Hypervisors put their vendor at the start of the leaves reserved for them.
+const HYPERVISOR_LEAF: u32 = 0x4000_0000;
+const LEAF7_EBX_FSGSBASE: u32 = 1 << 0;
+const EXT1_EDX_NX: u32 = 1 << 20;
+const EXT1_EDX_GIGAPAGES: u32 = 1 << 26;
+const EXT7_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+static FEATURES: spin::Once<Features> = spin::Once::new();
+
+/// What this CPU supports, read from CPUID on first use.
+pub fn features() -> Features {
+    *FEATURES.call_once(detect)
+}
+
+/// The vendor the hypervisor reports, when the CPU says there is one;
+/// [`HYPERVISOR_VENDOR`](crate::boot::HYPERVISOR_VENDOR) under hostel.
+pub fn hypervisor_vendor() -> Option<[u8; 12]> {
+    if __cpuid(1).ecx & LEAF1_ECX_HYPERVISOR == 0 {
+        return None;
+    }
+    let leaf = __cpuid(HYPERVISOR_LEAF);
+    Some(vendor(leaf.ebx, leaf.ecx, leaf.edx))
+}
+
+fn vendor(ebx: u32, ecx: u32, edx: u32) -> [u8; 12] {
+    let mut vendor = [0; 12];
+    vendor[..4].copy_from_slice(&ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&ecx.to_le_bytes());
+    vendor[8..].copy_from_slice(&edx.to_le_bytes());
+    vendor
+}
+
+fn detect() -> Features {
+    let leaf = |leaf: u32, max: u32| (leaf <= max).then(|| __cpuid(leaf));
+    let max_basic = __cpuid(0).eax;
+    let max_extended = __cpuid(0x8000_0000).eax;
+    let leaf1 = leaf(1, max_basic).map_or(0, |regs| regs.ecx);
+    // Subleaf 0, which `__cpuid` passes in ECX.
+    let leaf7 = leaf(7, max_basic).map_or(0, |regs| regs.ebx);
+    let ext1 = leaf(0x8000_0001, max_extended).map_or(0, |regs| regs.edx);
+    let ext7 = leaf(0x8000_0007, max_extended).map_or(0, |regs| regs.edx);
+    decode(leaf1, leaf7, ext1, ext7)
+}
+
+fn decode(leaf1_ecx: u32, leaf7_ebx: u32, ext1_edx: u32, ext7_edx: u32) -> Features {
+    let mut features = Features::empty();
+    for (present, feature) in [
+        (leaf1_ecx & LEAF1_ECX_X2APIC, Features::X2APIC),
+        (leaf1_ecx & LEAF1_ECX_XSAVE, Features::XSAVE),
+        (leaf1_ecx & LEAF1_ECX_RDRAND, Features::RDRAND),
+        (leaf7_ebx & LEAF7_EBX_FSGSBASE, Features::FSGSBASE),
+        (ext1_edx & EXT1_EDX_NX, Features::NX),
+        (ext1_edx & EXT1_EDX_GIGAPAGES, Features::GIGAPAGES),
+        (ext7_edx & EXT7_EDX_INVARIANT_TSC, Features::INVARIANT_TSC),
+    ] {
+        features.set(feature, present != 0);
+    }
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vendor_is_ebx_ecx_edx_in_order() {
+        // "KVMKVMKVM\0\0\0", as KVM reports itself.
+        assert_eq!(vendor(0x4b4d564b, 0x564b4d56, 0x4d), *b"KVMKVMKVM\0\0\0");
+    }
+
+    #[test]
+    fn decode_maps_each_cpuid_bit_to_its_feature() {
+        assert_eq!(decode(0, 0, 0, 0), Features::empty());
+        assert_eq!(
+            decode(LEAF1_ECX_RDRAND | LEAF1_ECX_X2APIC, 0, EXT1_EDX_NX, 0),
+            Features::RDRAND | Features::X2APIC | Features::NX
+        );
+        assert_eq!(
+            decode(
+                !0,
+                LEAF7_EBX_FSGSBASE,
+                EXT1_EDX_GIGAPAGES,
+                EXT7_EDX_INVARIANT_TSC
+            ),
+            Features::X2APIC
+                | Features::XSAVE
+                | Features::RDRAND
+                | Features::FSGSBASE
+                | Features::GIGAPAGES
+                | Features::INVARIANT_TSC
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use core::arch::asm;
+
+// The VMM enters long mode with the kernel selectors cached but no GDT behind
+// them, so the kernel segments keep those slots. The user segments follow in
+// the order SYSRET expects: data 8 bytes above the STAR base, code 16.
+pub const KERNEL_CS: u16 = 0x8;
+pub const KERNEL_SS: u16 = 0x10;
+pub const USER_SS: u16 = 0x18 | 3;
+pub const USER_CS: u16 = 0x20 | 3;
+const TSS_SELECTOR: u16 = 0x28;
+
+// Interrupt stack table slots of exceptions that must not run on the stack
+// they interrupted: a double fault usually means that stack is gone, and an
+// NMI or machine check can arrive anywhere.
+pub(super) const DOUBLE_FAULT_IST: u8 = 1;
+pub(super) const NMI_IST: u8 = 2;
+pub(super) const MACHINE_CHECK_IST: u8 = 3;
+const IST_STACKS: usize = 3;
+const IST_STACK_SIZE: usize = 16 * 1024;
+
+const GDT_ENTRIES: usize = 7;
+// The accessed bits are preset so the CPU never has to write them.
+const KERNEL_CODE: u64 = 0x00af_9b00_0000_ffff; // 64-bit code, DPL 0
+const KERNEL_DATA: u64 = 0x00cf_9300_0000_ffff; // data, DPL 0
+const USER_DATA: u64 = 0x00cf_f300_0000_ffff; // data, DPL 3
+const USER_CODE: u64 = 0x00af_fb00_0000_ffff; // 64-bit code, DPL 3
+// Present, DPL 0, available 64-bit TSS.
+const TSS_AVAILABLE: u64 = 0x89;
+
+// The CPU reads this on every interrupt taken from ring 3 and on every
+// exception with an IST slot.
+#[repr(C, packed(4))]
+struct TaskStateSegment {
+    reserved0: u32,
+    rsp0: u64,
+    rsp1: u64,
+    rsp2: u64,
+    reserved1: u64,
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+// Only the CPU and `set_kernel_stack`, which runs with interrupts masked,
+// touch these.
+static mut TSS: TaskStateSegment = TaskStateSegment {
+    reserved0: 0,
+    rsp0: 0,
+    rsp1: 0,
+    rsp2: 0,
+    reserved1: 0,
+    ist: [0; 7],
+    reserved2: 0,
+    reserved3: 0,
+    // Past the limit: there is no I/O permission bitmap.
+    iomap_base: size_of::<TaskStateSegment>() as u16,
+};
+static mut IST_STACK: [IstStack; IST_STACKS] =
+    [const { IstStack([0; IST_STACK_SIZE]) }; IST_STACKS];
+
+// Loading the task register marks the TSS descriptor busy, so the table has
+// to be writable.
+static GDT: spin::Once<[u64; GDT_ENTRIES]> = spin::Once::new();
+
+#[repr(C, packed)]
+pub(super) struct DescriptorTablePointer {
+    pub limit: u16,
+    pub base: u64,
+}
+
+// A TSS descriptor takes two slots: the usual segment layout, then the upper
+// half of the base.
+fn tss_descriptor(base: u64, limit: u64) -> [u64; 2] {
+    let low = (limit & 0xffff)
+        | (base & 0xff_ffff) << 16
+        | TSS_AVAILABLE << 40
+        | ((limit >> 16) & 0xf) << 48
+        | ((base >> 24) & 0xff) << 56;
+    [low, base >> 32]
+}
+
+pub(super) fn load() {
+    let gdt = GDT.call_once(|| {
+        // Slot n gets the top of stack n - 1.
+        let stacks = &raw const IST_STACK as u64;
+        let mut ist = [0; 7];
+        for (i, top) in ist[..IST_STACKS].iter_mut().enumerate() {
+            *top = stacks + ((i + 1) * IST_STACK_SIZE) as u64;
+        }
+        unsafe {
+            TSS.ist = ist;
+        }
+        let tss = tss_descriptor(
+            &raw const TSS as u64,
+            size_of::<TaskStateSegment>() as u64 - 1,
+        );
+        [
+            0,
+            KERNEL_CODE,
+            KERNEL_DATA,
+            USER_DATA,
+            USER_CODE,
+            tss[0],
+            tss[1],
+        ]
+    });
+    let ptr = DescriptorTablePointer {
+        limit: (size_of_val(gdt) - 1) as u16,
+        base: gdt.as_ptr() as u64,
+    };
+    unsafe {
+        asm!("lgdt [{}]", in(reg) &ptr, options(readonly, nostack, preserves_flags));
+    }
+
+    // Swap the segment state the VMM set up for the table's own descriptors.
+    // FS and GS keep their bases.
+    unsafe {
+        asm!(
+            "push {cs}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "retfq",
+            "2:",
+            "mov ss, {ss:x}",
+            "mov ds, {ss:x}",
+            "mov es, {ss:x}",
+            "ltr {tss:x}",
+            cs = in(reg) u64::from(KERNEL_CS),
+            ss = in(reg) KERNEL_SS,
+            tss = in(reg) TSS_SELECTOR,
+            tmp = out(reg) _,
+            options(preserves_flags),
+        );
+    }
+}
+
+/// Make interrupts taken in ring 3 switch to the kernel stack ending at
+/// `top`. Called on every context switch, with interrupts masked.
+pub fn set_kernel_stack(top: u64) {
+    unsafe {
+        TSS.rsp0 = top;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tss_descriptor_spreads_base_and_limit_over_both_slots() {
+        assert_eq!(size_of::<TaskStateSegment>(), 104);
+        assert_eq!(
+            tss_descriptor(0xffff_8000_1234_5678, 0x6_0067),
+            [0x1206_8934_5678_0067, 0xffff_8000]
+        );
+    }
+}
@@ -0,0 +1,637 @@
+use core::arch::{asm, global_asm};
+use core::fmt;
+
+use super::{
+    apic,
+    gdt::{self, DescriptorTablePointer, KERNEL_CS},
+    percpu, pic,
+};
+use crate::memory::{
+    address::{KernelDirectMap, VirtualAddr},
+    errors::MemoryError,
+    pagetable,
+};
+use crate::{
+    backtrace, boot, console, println,
+    process::{self, ExitStatus},
+    syscall,
+};
+
+const IDT_ENTRIES: usize = 256;
+// Vectors the CPU reserves for exceptions; each gets a stub that saves the
+// full register state.
+const EXCEPTION_VECTORS: usize = 32;
+const EXCEPTION_STUB_SIZE: usize = 16;
+// Exceptions that push an error code: #DF, #TS, #NP, #SS, #GP, #PF, #AC,
+// #CP, #VC and #SX.
+const ERROR_CODE_VECTORS: u32 = 1 << 8
+    | 1 << 10
+    | 1 << 11
+    | 1 << 12
+    | 1 << 13
+    | 1 << 14
+    | 1 << 17
+    | 1 << 21
+    | 1 << 29
+    | 1 << 30;
+const NMI_VECTOR: usize = 2;
+const DOUBLE_FAULT_VECTOR: usize = 8;
+const PAGE_FAULT_VECTOR: u64 = 14;
+const MACHINE_CHECK_VECTOR: usize = 18;
+// Present, DPL 0, 64-bit interrupt gate.
+const INTERRUPT_GATE: u8 = 0x8e;
+
+// Page fault error code bits.
+const PF_PRESENT: u64 = 1 << 0;
+const PF_WRITE: u64 = 1 << 1;
+const PF_USER: u64 = 1 << 2;
+const PF_INSTRUCTION_FETCH: u64 = 1 << 4;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Gate {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    type_attr: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl Gate {
+    const fn missing() -> Self {
+        Self {
+            offset_low: 0,
+            selector: 0,
+            ist: 0,
+            type_attr: 0,
+            offset_mid: 0,
+            offset_high: 0,
+            reserved: 0,
+        }
+    }
+
+    fn interrupt(handler: unsafe extern "C" fn()) -> Self {
+        Self::interrupt_on_stack(handler as *const () as usize, 0)
+    }
+
+    // `ist` picks a stack from the TSS's interrupt stack table, 0 keeps the
+    // interrupted one.
+    fn interrupt_on_stack(addr: usize, ist: u8) -> Self {
+        Self {
+            offset_low: addr as u16,
+            selector: KERNEL_CS,
+            ist,
+            type_attr: INTERRUPT_GATE,
+            offset_mid: (addr >> 16) as u16,
+            offset_high: (addr >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+static IDT: spin::Once<[Gate; IDT_ENTRIES]> = spin::Once::new();
+
+global_asm!(
+    r#"
+    // Stubs for exception vectors 0 to 31, a fixed size apart. Those whose
+    // exception pushes no error code push a zero in its place, so every
+    // exception reaches the common path with the same frame.
+    // Interrupts taken from ring 3 run `swapgs` on the way in and out, so the
+    // kernel always finds its per-CPU area in IA32_KERNEL_GS_BASE's place.
+    // Processes run in ring 0 for now and keep their GS base throughout.
+    // `cs_offset` is where the interrupted CS sits above RSP.
+    .macro swapgs_if_from_user cs_offset
+    test byte ptr [rsp + \cs_offset], 3
+    jz 1f
+    swapgs
+1:
+    .endm
+
+    .balign {stub_size}
+    .global __exception_stubs
+__exception_stubs:
+    .irp vector, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    .balign {stub_size}
+    .if (({error_code_vectors} >> \vector) & 1) == 0
+    push 0
+    .endif
+    push \vector
+    jmp __exception_common
+    .endr
+
+__exception_common:
+    // The CPU pushed SS, RSP, RFLAGS, CS, RIP and the stub the error code
+    // and vector, which leaves RSP 16-byte aligned after fifteen registers.
+    swapgs_if_from_user 24
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    cld
+    call __exception_dispatch
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    // Drop the vector and error code.
+    add rsp, 16
+    swapgs_if_from_user 8
+    iretq
+
+    // Entry for a device interrupt, which pushes no error code. Saving the
+    // caller-saved registers is what brings RSP back to 16-byte alignment.
+    .macro interrupt_entry name, dispatch
+    .global \name
+\name:
+    swapgs_if_from_user 8
+    push rax
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+
+    call \dispatch
+
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rax
+    swapgs_if_from_user 8
+    iretq
+    .endm
+
+    interrupt_entry __timer_entry, __timer_dispatch
+    interrupt_entry __serial_entry, __serial_dispatch
+
+    .global __spurious_entry
+__spurious_entry:
+    // Spurious interrupts are not acknowledged with an EOI.
+    iretq
+
+    .global __pic_slave_spurious_entry
+__pic_slave_spurious_entry:
+    // The master did see its cascade line go up, so it still wants one.
+    push rax
+    mov al, {pic_eoi}
+    out {pic_master_command}, al
+    pop rax
+    iretq
+"#,
+    stub_size = const EXCEPTION_STUB_SIZE,
+    error_code_vectors = const ERROR_CODE_VECTORS,
+    pic_eoi = const pic::EOI,
+    pic_master_command = const pic::MASTER_COMMAND,
+);
+
+unsafe extern "C" {
+    fn __exception_stubs();
+    fn __timer_entry();
+    fn __serial_entry();
+    fn __spurious_entry();
+    fn __pic_slave_spurious_entry();
+}
+
+pub(super) fn load() {
+    let idt = IDT.call_once(|| {
+        let mut idt = [Gate::missing(); IDT_ENTRIES];
+        let stubs = __exception_stubs as *const () as usize;
+        for (vector, gate) in idt[..EXCEPTION_VECTORS].iter_mut().enumerate() {
+            let ist = match vector {
+                NMI_VECTOR => gdt::NMI_IST,
+                DOUBLE_FAULT_VECTOR => gdt::DOUBLE_FAULT_IST,
+                MACHINE_CHECK_VECTOR => gdt::MACHINE_CHECK_IST,
+                _ => 0,
+            };
+            *gate = Gate::interrupt_on_stack(stubs + vector * EXCEPTION_STUB_SIZE, ist);
+        }
+        idt[apic::TIMER_VECTOR as usize] = Gate::interrupt(__timer_entry);
+        idt[apic::SPURIOUS_VECTOR as usize] = Gate::interrupt(__spurious_entry);
+        idt[(pic::MASTER_VECTOR + console::COM1_IRQ) as usize] = Gate::interrupt(__serial_entry);
+        idt[pic::MASTER_SPURIOUS_VECTOR as usize] = Gate::interrupt(__spurious_entry);
+        idt[pic::SLAVE_SPURIOUS_VECTOR as usize] = Gate::interrupt(__pic_slave_spurious_entry);
+        idt
+    });
+    let ptr = DescriptorTablePointer {
+        limit: (size_of_val(idt) - 1) as u16,
+        base: idt.as_ptr() as u64,
+    };
+    unsafe {
+        asm!("lidt [{}]", in(reg) &ptr, options(readonly, nostack, preserves_flags));
+    }
+}
+
+/// The registers an exception interrupted, as the exception stubs save
+/// them, lowest address first.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for exceptions that push none.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    /// The exception's name and mnemonic as the SDM gives them.
+    pub fn name(&self) -> &'static str {
+        match self.vector {
+            0 => "divide error (#DE)",
+            1 => "debug exception (#DB)",
+            2 => "non-maskable interrupt (NMI)",
+            3 => "breakpoint (#BP)",
+            4 => "overflow (#OF)",
+            5 => "bound range exceeded (#BR)",
+            6 => "invalid opcode (#UD)",
+            7 => "device not available (#NM)",
+            8 => "double fault (#DF)",
+            9 => "coprocessor segment overrun",
+            10 => "invalid TSS (#TS)",
+            11 => "segment not present (#NP)",
+            12 => "stack-segment fault (#SS)",
+            13 => "general protection fault (#GP)",
+            14 => "page fault (#PF)",
+            16 => "x87 floating-point error (#MF)",
+            17 => "alignment check (#AC)",
+            18 => "machine check (#MC)",
+            19 => "SIMD floating-point exception (#XM)",
+            20 => "virtualization exception (#VE)",
+            21 => "control protection exception (#CP)",
+            28 => "hypervisor injection exception (#HV)",
+            29 => "VMM communication exception (#VC)",
+            30 => "security exception (#SX)",
+            _ => "reserved exception",
+        }
+    }
+
+    /// The signal Linux would kill a process with for this exception, or
+    /// `None` for those that mean the machine itself is broken.
+    pub fn signal(&self) -> Option<u8> {
+        match self.vector {
+            0 | 16 | 19 => Some(process::SIGFPE),
+            1 | 3 => Some(process::SIGTRAP),
+            6 => Some(process::SIGILL),
+            17 => Some(process::SIGBUS),
+            4 | 5 | 10 | 11 | 12 | 13 | 14 | 21 => Some(process::SIGSEGV),
+            _ => None,
+        }
+    }
+
+    /// Every saved register, three to a line.
+    pub fn registers(&self) -> Registers<'_> {
+        Registers(self)
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at rip {:#x}, error code {:#x}",
+            self.name(),
+            self.rip,
+            self.error_code
+        )
+    }
+}
+
+/// Register dump of an [`ExceptionFrame`].
+pub struct Registers<'a>(&'a ExceptionFrame);
+
+impl fmt::Display for Registers<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.0;
+        let registers = [
+            ("rip", frame.rip),
+            ("rsp", frame.rsp),
+            ("rflags", frame.rflags),
+            ("rax", frame.rax),
+            ("rbx", frame.rbx),
+            ("rcx", frame.rcx),
+            ("rdx", frame.rdx),
+            ("rsi", frame.rsi),
+            ("rdi", frame.rdi),
+            ("rbp", frame.rbp),
+            ("r8", frame.r8),
+            ("r9", frame.r9),
+            ("r10", frame.r10),
+            ("r11", frame.r11),
+            ("r12", frame.r12),
+            ("r13", frame.r13),
+            ("r14", frame.r14),
+            ("r15", frame.r15),
+            ("cs", frame.cs),
+            ("ss", frame.ss),
+        ];
+        for (i, line) in registers.chunks(3).enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            for (j, (name, value)) in line.iter().enumerate() {
+                if j > 0 {
+                    f.write_str("  ")?;
+                }
+                write!(f, "{name:>6} {value:016x}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A page fault as reported by the CPU: the faulting address from CR2, the
+/// error code pushed with the exception and the instruction that faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    pub addr: usize,
+    pub error_code: u64,
+    pub rip: u64,
+}
+
+impl PageFault {
+    /// The page was present, so this is a protection violation rather than a
+    /// missing translation.
+    pub fn present(&self) -> bool {
+        self.error_code & PF_PRESENT != 0
+    }
+
+    pub fn write(&self) -> bool {
+        self.error_code & PF_WRITE != 0
+    }
+
+    pub fn user(&self) -> bool {
+        self.error_code & PF_USER != 0
+    }
+
+    pub fn instruction_fetch(&self) -> bool {
+        self.error_code & PF_INSTRUCTION_FETCH != 0
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self.instruction_fetch() {
+            "fetch"
+        } else if self.write() {
+            "write"
+        } else {
+            "read"
+        };
+        write!(
+            f,
+            "{} {} {access} at {:#x}, rip {:#x}, error code {:#x}",
+            if self.user() { "user" } else { "kernel" },
+            if self.present() {
+                "protection"
+            } else {
+                "not-present"
+            },
+            self.addr,
+            self.rip,
+            self.error_code,
+        )
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn __exception_dispatch(frame: &ExceptionFrame) {
+    if frame.vector == NMI_VECTOR as u64 {
+        nmi(frame);
+        return;
+    }
+    if frame.vector == PAGE_FAULT_VECTOR {
+        page_fault(frame);
+        return;
+    }
+
+    if let Some(signal) = frame.signal()
+        && let Some(kernel) = crate::try_active_kernel()
+        && let Some(pid) = process::try_current_pid(kernel)
+    {
+        println!("{}", frame.registers());
+        println!("{}, pid {}; terminating process", frame, pid);
+        process::terminate_current(kernel, ExitStatus::Killed(signal))
+    }
+    println!("{}", frame.registers());
+    println!("kernel exception: {}", frame);
+    halt()
+}
+
+// The host injects NMIs to find out what a wedged guest is doing, so report
+// where it was and let it carry on. Everything here has to cope with the
+// interrupted code holding any lock.
+fn nmi(frame: &ExceptionFrame) {
+    console::break_lock();
+    println!(
+        "nmi: interrupted rip {:#x}, pid {}",
+        frame.rip,
+        percpu::this_cpu().current_pid()
+    );
+    println!("{}", frame.registers());
+    if let Some(kernel) = crate::try_active_kernel()
+        && !process::try_dump_trace(kernel)
+    {
+        println!("nmi: process state locked, no scheduler trace");
+    }
+    backtrace::print_from(frame.rbp);
+}
+
+fn page_fault(frame: &ExceptionFrame) {
+    let addr: usize;
+    unsafe {
+        asm!("mov {}, cr2", out(reg) addr, options(nomem, nostack, preserves_flags));
+    }
+    let fault = PageFault {
+        addr,
+        error_code: frame.error_code,
+        rip: frame.rip,
+    };
+
+    let Some(kernel) = crate::try_active_kernel() else {
+        fatal_page_fault(frame, &fault);
+    };
+
+    // User memory is never executable, so only data accesses can be paged in.
+    if !fault.instruction_fetch() {
+        match process::handle_page_fault(kernel, fault.addr, fault.write()) {
+            Ok(()) => return,
+            // Either this process is killed or another one made room and the
+            // access is retried.
+            Err(MemoryError::OutOfMemory) => {
+                process::oom_kill(kernel);
+                return;
+            }
+            Err(_) => {}
+        }
+    }
+
+    match process::try_current_pid(kernel) {
+        Some(pid) => {
+            println!("{}", frame.registers());
+            println!("page fault: {}, pid {}; terminating process", fault, pid);
+            process::terminate_current(kernel, ExitStatus::Killed(process::SIGSEGV))
+        }
+        None => fatal_page_fault(frame, &fault),
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn __timer_dispatch() {
+    // Acknowledge first: a preempted process may not come back for a while.
+    apic::end_of_interrupt();
+    apic::record_tick();
+    if let Some(kernel) = crate::try_active_kernel() {
+        syscall::poll_io(kernel);
+        process::preempt(kernel);
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn __serial_dispatch() {
+    console::receive();
+    if let Some(kernel) = crate::try_active_kernel() {
+        syscall::wake_io_waiters(kernel);
+    }
+    pic::end_of_interrupt(console::COM1_IRQ);
+}
+
+// The register dump goes before the exception line, so the failure the VMM
+// records stays the same from run to run.
+fn fatal_page_fault(frame: &ExceptionFrame, fault: &PageFault) -> ! {
+    println!("{}", frame.registers());
+    println!("kernel exception: page fault: {}", fault);
+    let addr = VirtualAddr::new(fault.addr);
+    match pagetable::translate(super::current_page_table(), addr, &KernelDirectMap) {
+        Some(mapping) => println!("  mapped by {}", mapping),
+        None => println!("  {} is not mapped", addr),
+    }
+    halt()
+}
+
+fn halt() -> ! {
+    if boot::read_run_flags(&KernelDirectMap).run_tests() {
+        boot::signal_kernel_tests_failure();
+    }
+    boot::halt_forever()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_fault_decodes_error_code_bits() {
+        let fault = PageFault {
+            addr: 0x4000_1000,
+            error_code: PF_PRESENT | PF_WRITE,
+            rip: 0x20_1234,
+        };
+        assert!(fault.present() && fault.write());
+        assert!(!fault.user() && !fault.instruction_fetch());
+        assert_eq!(
+            fault.to_string(),
+            "kernel protection write at 0x40001000, rip 0x201234, error code 0x3"
+        );
+
+        let fault = PageFault {
+            addr: 0x10,
+            error_code: PF_USER | PF_INSTRUCTION_FETCH,
+            rip: 0x10,
+        };
+        assert_eq!(
+            fault.to_string(),
+            "user not-present fetch at 0x10, rip 0x10, error code 0x14"
+        );
+    }
+
+    #[test]
+    fn exception_frames_name_the_exception_and_dump_registers() {
+        let frame = ExceptionFrame {
+            vector: 13,
+            error_code: 0x18,
+            rip: 0x20_1234,
+            rsp: 0x7fff_f000,
+            rflags: 0x202,
+            rax: 1,
+            r15: 0xffff_8000_0000_0000,
+            cs: 0x8,
+            ss: 0x10,
+            ..ExceptionFrame::default()
+        };
+        assert_eq!(
+            frame.to_string(),
+            "general protection fault (#GP) at rip 0x201234, error code 0x18"
+        );
+        assert_eq!(frame.signal(), Some(process::SIGSEGV));
+
+        let dump = frame.registers().to_string();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(
+            lines[0],
+            "   rip 0000000000201234     rsp 000000007ffff000  rflags 0000000000000202"
+        );
+        assert_eq!(
+            lines[5],
+            "   r13 0000000000000000     r14 0000000000000000     r15 ffff800000000000"
+        );
+        assert_eq!(lines[6], "    cs 0000000000000008      ss 0000000000000010");
+
+        let double_fault = ExceptionFrame { vector: 8, ..frame };
+        assert_eq!(double_fault.signal(), None);
+    }
+}
@@ -0,0 +1,116 @@
+use core::hint::spin_loop;
+use core::ptr::{addr_of, read_volatile};
+use core::sync::atomic::{Ordering, fence};
+
+use crate::boot::PvClock;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A kvmclock as it stood between two of KVM's rewrites.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+}
+
+/// Copy `clock` once KVM is not midway through rewriting it. `None` if KVM
+/// never wrote it, as when the VM left kvmclock off.
+///
+/// # Safety
+///
+/// `clock` must point at a [`PvClock`] that stays mapped.
+pub unsafe fn snapshot(clock: *const PvClock) -> Option<Snapshot> {
+    loop {
+        // SAFETY: the caller vouches for `clock`. The loads are volatile as
+        // KVM writes it from outside the guest.
+        let (version, snapshot, after) = unsafe {
+            let version = read_volatile(addr_of!((*clock).version));
+            fence(Ordering::Acquire);
+            let snapshot = Snapshot {
+                tsc_timestamp: read_volatile(addr_of!((*clock).tsc_timestamp)),
+                system_time: read_volatile(addr_of!((*clock).system_time)),
+                tsc_to_system_mul: read_volatile(addr_of!((*clock).tsc_to_system_mul)),
+                tsc_shift: read_volatile(addr_of!((*clock).tsc_shift)),
+            };
+            fence(Ordering::Acquire);
+            (version, snapshot, read_volatile(addr_of!((*clock).version)))
+        };
+        if version == 0 {
+            return None;
+        }
+        if version % 2 == 0 && after == version {
+            return Some(snapshot);
+        }
+        spin_loop();
+    }
+}
+
+impl Snapshot {
+    /// Nanoseconds on the clock when the TSC read `tsc`.
+    pub fn nanos_at(&self, tsc: u64) -> u64 {
+        let delta = tsc.wrapping_sub(self.tsc_timestamp);
+        let delta = if self.tsc_shift >= 0 {
+            delta << self.tsc_shift
+        } else {
+            delta >> -self.tsc_shift
+        };
+        let scaled = (delta as u128 * self.tsc_to_system_mul as u128) >> 32;
+        self.system_time.wrapping_add(scaled as u64)
+    }
+
+    /// The TSC frequency the clock is scaled by: KVM's own measurement, so
+    /// nothing is left for the guest to calibrate.
+    pub fn tsc_hz(&self) -> u64 {
+        // A tick is `mul / 2^32` nanoseconds, shifted by `shift`.
+        let hz = (NANOS_PER_SEC << 32) / self.tsc_to_system_mul.max(1) as u128;
+        let hz = if self.tsc_shift >= 0 {
+            hz >> self.tsc_shift
+        } else {
+            hz << -self.tsc_shift
+        };
+        hz as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What KVM writes for a 3 GHz TSC: a third of a nanosecond a tick, as
+    // 2/3 * 2^32 shifted right once.
+    const THREE_GHZ: Snapshot = Snapshot {
+        tsc_timestamp: 1_000,
+        system_time: 5_000,
+        tsc_to_system_mul: 0xaaaa_aaab,
+        tsc_shift: -1,
+    };
+
+    #[test]
+    fn nanos_scale_the_ticks_since_the_timestamp() {
+        assert_eq!(THREE_GHZ.nanos_at(1_000), 5_000);
+        assert_eq!(THREE_GHZ.nanos_at(1_000 + 3_000_000_000), 1_000_005_000);
+        let doubled = Snapshot {
+            tsc_shift: 1,
+            ..THREE_GHZ
+        };
+        assert_eq!(doubled.nanos_at(1_000 + 3), 5_004);
+    }
+
+    #[test]
+    fn tsc_hz_inverts_the_scale() {
+        assert_eq!(THREE_GHZ.tsc_hz(), 2_999_999_998);
+    }
+
+    #[test]
+    fn snapshots_copy_a_written_clock_and_refuse_an_unused_one() {
+        let mut clock = PvClock::default();
+        assert_eq!(unsafe { snapshot(&clock) }, None);
+        clock.version = 2;
+        clock.system_time = 7;
+        clock.tsc_to_system_mul = 1 << 31;
+        let snapshot = unsafe { snapshot(&clock) }.unwrap();
+        assert_eq!(snapshot.nanos_at(clock.tsc_timestamp + 10), 12);
+    }
+}
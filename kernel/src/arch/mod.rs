@@ -0,0 +1,157 @@
+use core::arch::asm;
+
+use crate::memory::address::PhysicalAddr;
+
+pub mod apic;
+pub mod cpu;
+pub mod gdt;
+pub mod idt;
+pub mod kvmclock;
+pub mod percpu;
+pub mod pic;
+pub mod pit;
+
+/// Interrupt enable flag in RFLAGS.
+pub const RFLAGS_IF: u64 = 1 << 9;
+
+/// MSRs holding the FS and GS segment bases.
+pub const IA32_FS_BASE: u32 = 0xC000_0100;
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+
+/// Load the kernel's descriptor tables, silence the legacy PICs and set up
+/// the per-CPU area. Must run before anything can fault.
+pub fn init() {
+    gdt::load();
+    idt::load();
+    pic::init();
+    percpu::init();
+}
+
+/// Root of the page tables the CPU is running on.
+pub fn current_page_table() -> PhysicalAddr {
+    let cr3: usize;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    PhysicalAddr::new(cr3 & !0xFFF)
+}
+
+/// Run `f` with maskable interrupts disabled, then restore whatever state
+/// the caller had.
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", "cli", out(reg) rflags, options(nomem));
+    }
+    let result = f();
+    if rflags & RFLAGS_IF != 0 {
+        unsafe {
+            asm!("sti", options(nomem, nostack));
+        }
+    }
+    result
+}
+
+/// Sleep until an interrupt has been handled. Interrupts are masked again on
+/// return.
+pub fn wait_for_interrupt() {
+    // `sti` takes effect after the next instruction, so nothing can be
+    // delivered between enabling interrupts and halting.
+    unsafe {
+        asm!("sti", "hlt", "cli", options(nomem, nostack));
+    }
+}
+
+/// Whether maskable interrupts are enabled on this CPU.
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    rflags & RFLAGS_IF != 0
+}
+
+/// Mask interrupts until [`enable_interrupts`]; paths that switch away and
+/// never return use it to mask them for good.
+pub fn disable_interrupts() {
+    // Without `nomem` this is a compiler barrier too, so nothing a lock
+    // protects moves out from under the masked section.
+    unsafe {
+        asm!("cli", options(nostack));
+    }
+}
+
+pub fn enable_interrupts() {
+    unsafe {
+        asm!("sti", options(nostack));
+    }
+}
+
+/// Switch to the page tables rooted at `root`.
+///
+/// # Safety
+///
+/// `root` must map the running code, its stack and everything it is about to
+/// touch.
+pub unsafe fn load_page_table(root: PhysicalAddr) {
+    unsafe {
+        asm!("mov cr3, {}", in(reg) root.as_u64(), options(nostack, preserves_flags));
+    }
+}
+
+#[inline]
+pub fn wrmsr(msr: u32, value: u64) {
+    let lo = value as u32;
+    let hi = (value >> 32) as u32;
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") lo,
+            in("edx") hi,
+            options(nostack, preserves_flags),
+        );
+    }
+}
+
+#[inline]
+pub fn rdmsr(msr: u32) -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!(
+            "rdmsr",
+            in("ecx") msr,
+            out("eax") lo,
+            out("edx") hi,
+            options(nostack, preserves_flags),
+        );
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+#[inline]
+pub fn outb(port: u16, value: u8) {
+    unsafe {
+        asm!(
+            "out dx, al",
+            in("dx") port,
+            in("al") value,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+}
+
+#[inline]
+pub fn inb(port: u16) -> u8 {
+    let value: u8;
+    unsafe {
+        asm!(
+            "in al, dx",
+            in("dx") port,
+            out("al") value,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    value
+}
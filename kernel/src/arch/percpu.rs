@@ -0,0 +1,72 @@
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::wrmsr;
+
+/// MSR `swapgs` exchanges with the GS base.
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+/// Offsets entry stubs use to reach fields through GS once they have run
+/// `swapgs`.
+pub(crate) const KERNEL_STACK_OFFSET: usize = offset_of!(PerCpu, kernel_stack);
+pub(crate) const SCRATCH_RSP_OFFSET: usize = offset_of!(PerCpu, scratch_rsp);
+
+/// State private to one CPU. Outside entry stubs the GS base belongs to
+/// whatever runs, and IA32_KERNEL_GS_BASE holds this area's address for
+/// `swapgs` to bring in.
+#[repr(C)]
+pub struct PerCpu {
+    // Lets code that reached the area through GS turn it into a pointer.
+    this: AtomicU64,
+    // Top of the running process's kernel stack, kept up to date by context
+    // switches. 0 while the kernel's own context runs; its syscalls stay on
+    // the stack they were made on.
+    kernel_stack: AtomicU64,
+    // The caller's stack pointer while the syscall entry stub moves off its
+    // stack.
+    scratch_rsp: AtomicU64,
+    current_pid: AtomicUsize,
+    id: usize,
+}
+
+// The VMM creates a single vCPU.
+static BOOT_CPU: PerCpu = PerCpu {
+    this: AtomicU64::new(0),
+    kernel_stack: AtomicU64::new(0),
+    scratch_rsp: AtomicU64::new(0),
+    current_pid: AtomicUsize::new(0),
+    id: 0,
+};
+
+/// Point IA32_KERNEL_GS_BASE at this CPU's area.
+pub(super) fn init() {
+    let area = &BOOT_CPU as *const PerCpu as u64;
+    BOOT_CPU.this.store(area, Ordering::Relaxed);
+    wrmsr(IA32_KERNEL_GS_BASE, area);
+}
+
+/// The running CPU's area.
+pub fn this_cpu() -> &'static PerCpu {
+    &BOOT_CPU
+}
+
+impl PerCpu {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Pid of the process running on this CPU, 0 for the kernel.
+    pub fn current_pid(&self) -> usize {
+        self.current_pid.load(Ordering::Relaxed)
+    }
+
+    pub fn set_current_pid(&self, pid: usize) {
+        self.current_pid.store(pid, Ordering::Relaxed);
+    }
+
+    /// Make syscalls switch to the kernel stack ending at `top` from now on,
+    /// or stay on the caller's stack for 0. Called on every context switch.
+    pub fn set_kernel_stack(&self, top: u64) {
+        self.kernel_stack.store(top, Ordering::Relaxed);
+    }
+}
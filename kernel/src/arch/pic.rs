@@ -0,0 +1,63 @@
+use super::{inb, outb};
+
+/// First vector of the master PIC's lines, IRQ 0 to 7. The PICs power up
+/// raising vectors 8 to 15, where an IRQ would pass for a double fault or a
+/// general protection fault.
+pub const MASTER_VECTOR: u8 = 0x30;
+/// First vector of the slave PIC's lines, IRQ 8 to 15.
+pub const SLAVE_VECTOR: u8 = MASTER_VECTOR + 8;
+/// Vectors a PIC raises for an interrupt that went away before the CPU
+/// acknowledged it, even with every line masked.
+pub const MASTER_SPURIOUS_VECTOR: u8 = MASTER_VECTOR + 7;
+pub const SLAVE_SPURIOUS_VECTOR: u8 = SLAVE_VECTOR + 7;
+
+pub(super) const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+
+// ICW1: edge triggered, cascaded, ICW4 follows.
+const ICW1_INIT: u8 = 0x11;
+// ICW3: the slave hangs off the master's IRQ 2.
+const ICW3_MASTER_CASCADE: u8 = 1 << 2;
+const ICW3_SLAVE_ID: u8 = 2;
+// ICW4: 8086 mode, explicit end of interrupt.
+const ICW4_8086: u8 = 0x01;
+const OCW_ALL_MASKED: u8 = 0xff;
+// OCW2: non-specific end of interrupt.
+pub(super) const EOI: u8 = 0x20;
+
+/// Move both PICs off the exception vectors and mask every line until a
+/// driver unmasks its own; the timer is the local APIC's.
+pub fn init() {
+    outb(MASTER_COMMAND, ICW1_INIT);
+    outb(SLAVE_COMMAND, ICW1_INIT);
+    outb(MASTER_DATA, MASTER_VECTOR);
+    outb(SLAVE_DATA, SLAVE_VECTOR);
+    outb(MASTER_DATA, ICW3_MASTER_CASCADE);
+    outb(SLAVE_DATA, ICW3_SLAVE_ID);
+    outb(MASTER_DATA, ICW4_8086);
+    outb(SLAVE_DATA, ICW4_8086);
+    outb(MASTER_DATA, OCW_ALL_MASKED);
+    outb(SLAVE_DATA, OCW_ALL_MASKED);
+}
+
+/// Let legacy IRQ line `irq` through. Its handler must call
+/// [`end_of_interrupt`].
+pub fn unmask(irq: u8) {
+    if irq >= 8 {
+        outb(SLAVE_DATA, inb(SLAVE_DATA) & !(1 << (irq - 8)));
+        // Slave lines reach the CPU through the master's cascade line.
+        outb(MASTER_DATA, inb(MASTER_DATA) & !ICW3_MASTER_CASCADE);
+    } else {
+        outb(MASTER_DATA, inb(MASTER_DATA) & !(1 << irq));
+    }
+}
+
+/// Tell the PICs the interrupt on line `irq` has been handled.
+pub fn end_of_interrupt(irq: u8) {
+    if irq >= 8 {
+        outb(SLAVE_COMMAND, EOI);
+    }
+    outb(MASTER_COMMAND, EOI);
+}
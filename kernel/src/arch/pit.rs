@@ -0,0 +1,49 @@
+use core::hint::spin_loop;
+
+use super::{inb, outb};
+use crate::time::rdtsc;
+
+// The PIT counts down at this rate whatever the CPU runs at.
+const PIT_HZ: u64 = 1_193_182;
+const CALIBRATION_MS: u64 = 10;
+
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// Port B of the old keyboard controller wires up channel 2: its gate, the
+// speaker and the channel's output.
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT2: u8 = 1 << 5;
+// Channel 2, low then high count byte, mode 0 (interrupt on terminal
+// count), binary.
+const CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+// Fewer polls than this before the output rises means nothing counted;
+// more means it never will.
+const MIN_POLLS: u64 = 100;
+const MAX_POLLS: u64 = 100_000_000;
+
+/// Count TSC ticks across a `CALIBRATION_MS` countdown on PIT channel 2 and
+/// scale them to a second. `None` if the countdown ended before it could
+/// have started or never ended, as with no PIT behind the ports.
+pub fn measure_tsc_hz() -> Option<u64> {
+    let count = PIT_HZ * CALIBRATION_MS / 1000;
+    // Raise the gate with the speaker off, then load the count, which starts
+    // the countdown with the output low.
+    outb(PORT_B, (inb(PORT_B) & !PORT_B_SPEAKER) | PORT_B_GATE2);
+    outb(COMMAND, CHANNEL2_ONE_SHOT);
+    outb(CHANNEL2_DATA, count as u8);
+    outb(CHANNEL2_DATA, (count >> 8) as u8);
+
+    let start = rdtsc();
+    let mut polls = 0;
+    while inb(PORT_B) & PORT_B_OUT2 == 0 {
+        polls += 1;
+        if polls == MAX_POLLS {
+            return None;
+        }
+        spin_loop();
+    }
+    let elapsed = rdtsc() - start;
+    (polls >= MIN_POLLS).then(|| elapsed * 1000 / CALIBRATION_MS)
+}
@@ -0,0 +1,104 @@
+use core::arch::asm;
+
+use crate::arch;
+use crate::memory::{
+    address::{KernelDirectMap, VirtualAddr},
+    constants::{KERNEL_CODE_SIZE, KERNEL_CODE_VIRT},
+    pagetable,
+};
+use crate::println;
+
+// Deep enough for any kernel call chain; a corrupt chain that loops back on
+// itself stops here too.
+const MAX_FRAMES: usize = 32;
+
+/// Print the return addresses of the calling function's callers, innermost
+/// first. The kernel is built with frame pointers, so each frame starts with
+/// the caller's RBP followed by the return address. Resolve the addresses
+/// with `addr2line -e` on the kernel ELF.
+pub fn print() {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    print_from(rbp);
+}
+
+/// Like [`print`], but for the chain starting at frame pointer `rbp`, such
+/// as the one saved by an interrupt.
+pub fn print_from(rbp: u64) {
+    println!("backtrace:");
+    let mut depth = 0;
+    walk(rbp, is_mapped, |ret| {
+        println!("  #{:<2} {:#018x}", depth, ret);
+        depth += 1;
+    });
+}
+
+// A panic may come from a smashed stack, so only follow a frame pointer
+// into memory the page tables map.
+fn is_mapped(addr: u64) -> bool {
+    pagetable::translate(
+        arch::current_page_table(),
+        VirtualAddr::new(addr as usize),
+        &KernelDirectMap,
+    )
+    .is_some()
+}
+
+fn in_kernel_image(addr: u64) -> bool {
+    let start = KERNEL_CODE_VIRT.as_u64();
+    addr >= start && addr - start < KERNEL_CODE_SIZE as u64
+}
+
+// Follow the RBP chain from `rbp`, handing each return address to `visit`.
+// Stops at a null, misaligned or unreadable frame pointer, or at a return
+// address outside the kernel, which is where the first frame of a process
+// or of boot ends up.
+fn walk(mut rbp: u64, readable: impl Fn(u64) -> bool, mut visit: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !readable(rbp) || !readable(rbp + 8) {
+            return;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if !in_kernel_image(ret) {
+            return;
+        }
+        visit(ret);
+        rbp = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walk_follows_the_frame_chain_until_it_leaves_the_kernel() {
+        let text = KERNEL_CODE_VIRT.as_u64();
+        let mut stack = [0u64; 6];
+        let base = stack.as_ptr() as u64;
+        // Three frames, the outermost returning into something that is not
+        // kernel code.
+        stack[0] = base + 16;
+        stack[1] = text + 0x100;
+        stack[2] = base + 32;
+        stack[3] = text + 0x200;
+        stack[4] = 0;
+        stack[5] = 0x40_1000;
+        let readable = |addr| addr >= base && addr < base + size_of_val(&stack) as u64;
+
+        let mut seen = [0; 4];
+        let mut count = 0;
+        walk(base, readable, |ret| {
+            seen[count] = ret;
+            count += 1;
+        });
+        assert_eq!(&seen[..count], &[text + 0x100, text + 0x200]);
+
+        count = 0;
+        walk(base + 4, readable, |_| count += 1);
+        walk(0, readable, |_| count += 1);
+        assert_eq!(count, 0);
+    }
+}
@@ -1,10 +1,24 @@
 use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
 
-use crate::memory::{address::DirectMap, constants::RUN_FLAGS_PHYS};
+use crate::memory::{
+    address::{DirectMap, PhysicalAddr},
+    constants::{BOOT_INFO_PHYS, PVCLOCK_PHYS, STARTUP_MAILBOX_PHYS},
+};
+use crate::process::ExitStatus;
 
 pub const KERNEL_TEST_EXIT_PORT: u16 = 0xF4;
 pub const KERNEL_TEST_EXIT_SUCCESS: u32 = 0x10;
 pub const KERNEL_TEST_EXIT_FAILURE: u32 = 0x11;
+/// What hostel reports as the hypervisor's vendor, in EBX, ECX and EDX of
+/// CPUID leaf 0x40000000.
+pub const HYPERVISOR_VENDOR: [u8; 12] = *b"hostelhostel";
+
+/// Written once the kernel halts for good. With the local APIC emulated by
+/// KVM, `hlt` alone no longer hands control back to the VMM. A word written
+/// is the VM's exit status, as `ExitStatus::wait_status` encodes it; a byte
+/// written is an exit code alone.
+pub const POWER_OFF_PORT: u16 = 0xF5;
 
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
@@ -14,6 +28,21 @@ pub struct RunFlags {
 
 impl RunFlags {
     const RUN_TESTS_BIT: u64 = 1 << 0;
+    // Zero pages as they are freed instead of leaving their contents behind.
+    const SCRUB_ON_FREE_BIT: u64 = 1 << 1;
+    // Guest credentials reported by the getuid/getgid family.
+    const UID_SHIFT: u32 = 16;
+    const GID_SHIFT: u32 = 32;
+    const ID_MASK: u64 = 0xFFFF;
+    // Timer ticks a process runs before others of its priority get a turn,
+    // with 0 leaving the scheduler's default.
+    const QUANTUM_SHIFT: u32 = 48;
+    const QUANTUM_MASK: u64 = 0xFF;
+    const VALID_BITS: u64 = Self::RUN_TESTS_BIT
+        | Self::SCRUB_ON_FREE_BIT
+        | (Self::ID_MASK << Self::UID_SHIFT)
+        | (Self::ID_MASK << Self::GID_SHIFT)
+        | (Self::QUANTUM_MASK << Self::QUANTUM_SHIFT);
 
     pub const fn empty() -> Self {
         Self { bits: 0 }
@@ -21,7 +50,7 @@ impl RunFlags {
 
     pub const fn from_bits(bits: u64) -> Self {
         Self {
-            bits: bits & Self::RUN_TESTS_BIT,
+            bits: bits & Self::VALID_BITS,
         }
     }
 
@@ -41,12 +70,355 @@ impl RunFlags {
     pub const fn run_tests(self) -> bool {
         (self.bits & Self::RUN_TESTS_BIT) != 0
     }
+
+    pub const fn with_scrub_on_free(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.bits |= Self::SCRUB_ON_FREE_BIT;
+        } else {
+            self.bits &= !Self::SCRUB_ON_FREE_BIT;
+        }
+        self
+    }
+
+    pub const fn scrub_on_free(self) -> bool {
+        (self.bits & Self::SCRUB_ON_FREE_BIT) != 0
+    }
+
+    pub const fn with_uid(mut self, uid: u16) -> Self {
+        self.bits &= !(Self::ID_MASK << Self::UID_SHIFT);
+        self.bits |= (uid as u64) << Self::UID_SHIFT;
+        self
+    }
+
+    pub const fn uid(self) -> u32 {
+        ((self.bits >> Self::UID_SHIFT) & Self::ID_MASK) as u32
+    }
+
+    pub const fn with_gid(mut self, gid: u16) -> Self {
+        self.bits &= !(Self::ID_MASK << Self::GID_SHIFT);
+        self.bits |= (gid as u64) << Self::GID_SHIFT;
+        self
+    }
+
+    pub const fn gid(self) -> u32 {
+        ((self.bits >> Self::GID_SHIFT) & Self::ID_MASK) as u32
+    }
+
+    pub const fn with_quantum_ticks(mut self, ticks: u8) -> Self {
+        self.bits &= !(Self::QUANTUM_MASK << Self::QUANTUM_SHIFT);
+        self.bits |= (ticks as u64) << Self::QUANTUM_SHIFT;
+        self
+    }
+
+    pub const fn quantum_ticks(self) -> u32 {
+        ((self.bits >> Self::QUANTUM_SHIFT) & Self::QUANTUM_MASK) as u32
+    }
 }
 
+/// The run flags of the boot info, or none if the VM wrote no boot info
+/// this kernel can read.
 pub fn read_run_flags(map: &impl DirectMap) -> RunFlags {
-    let flags_addr = RUN_FLAGS_PHYS.to_virtual(map);
-    let raw = unsafe { core::ptr::read_volatile(flags_addr.as_ptr::<u64>() as *const u64) };
-    RunFlags::from_bits(raw)
+    read_boot_info(map).map_or(RunFlags::empty(), |info| info.flags)
+}
+
+// e820 region types; everything that is not RAM is left alone.
+pub const E820_RAM: u32 = 1;
+pub const E820_RESERVED: u32 = 2;
+
+pub const MEMORY_MAP_MAX_ENTRIES: usize = 16;
+
+/// One e820-style entry of the guest physical memory map.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub base: u64,
+    pub len: u64,
+    pub kind: u32,
+    // Keeps the layout free of padding so the map can be copied as bytes.
+    _reserved: u32,
+}
+
+impl MemoryRegion {
+    pub const fn new(base: u64, len: u64, kind: u32) -> Self {
+        Self {
+            base,
+            len,
+            kind,
+            _reserved: 0,
+        }
+    }
+
+    pub const fn end(self) -> u64 {
+        self.base.saturating_add(self.len)
+    }
+
+    pub const fn is_ram(self) -> bool {
+        self.kind == E820_RAM
+    }
+}
+
+/// Physical memory map, part of the boot info. The kernel hands the RAM
+/// regions to the page allocator at boot, so the amount of guest memory is
+/// decided by the VM rather than compiled in.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryMap {
+    len: u64,
+    entries: [MemoryRegion; MEMORY_MAP_MAX_ENTRIES],
+}
+
+impl MemoryMap {
+    pub const fn empty() -> Self {
+        Self {
+            len: 0,
+            entries: [MemoryRegion::new(0, 0, 0); MEMORY_MAP_MAX_ENTRIES],
+        }
+    }
+
+    /// Append `region`, handing it back if the map is already full.
+    pub fn push(&mut self, region: MemoryRegion) -> Result<(), MemoryRegion> {
+        let len = self.regions().len();
+        let slot = self.entries.get_mut(len).ok_or(region)?;
+        *slot = region;
+        self.len = len as u64 + 1;
+        Ok(())
+    }
+
+    /// Entries in the order they were written. A corrupt length is clamped
+    /// to the table size.
+    pub fn regions(&self) -> &[MemoryRegion] {
+        let len = (self.len as usize).min(MEMORY_MAP_MAX_ENTRIES);
+        &self.entries[..len]
+    }
+
+    pub fn ram(&self) -> impl Iterator<Item = MemoryRegion> + '_ {
+        self.regions()
+            .iter()
+            .copied()
+            .filter(|region| region.is_ram())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: the map is `repr(C)` and made of integers with no padding.
+        unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>(),
+                core::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+/// Zeroed by the VM next to the boot info. Every vCPU enters the kernel at
+/// `_start` with its index in RDI and checks in here; only the boot vCPU,
+/// index 0, goes on to run the kernel.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct StartupMailbox {
+    online: AtomicU32,
+    // Set by the boot vCPU once its IDT is loaded. Also keeps the mailbox a
+    // whole number of words, so what follows it stays aligned.
+    idt_loaded: AtomicU32,
+}
+
+impl StartupMailbox {
+    pub const fn new() -> Self {
+        Self {
+            online: AtomicU32::new(0),
+            idt_loaded: AtomicU32::new(0),
+        }
+    }
+
+    /// vCPUs that have reached the kernel so far.
+    pub fn online(&self) -> u32 {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub fn check_in(&self) {
+        self.online.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Whether the boot vCPU takes exceptions and NMIs with the kernel's
+    /// own handlers yet.
+    pub fn idt_loaded(&self) -> bool {
+        self.idt_loaded.load(Ordering::Acquire) != 0
+    }
+
+    pub fn set_idt_loaded(&self) {
+        self.idt_loaded.store(1, Ordering::Release);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: the mailbox is `repr(C)` and made of integers with no padding.
+        unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>(),
+                core::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+pub fn startup_mailbox(map: &impl DirectMap) -> &'static StartupMailbox {
+    unsafe {
+        &*STARTUP_MAILBOX_PHYS
+            .to_virtual(map)
+            .as_ptr::<StartupMailbox>()
+    }
+}
+
+/// Where in guest memory the VM put the initramfs, a newc cpio archive the
+/// kernel unpacks into its root filesystem. The memory map reserves the pages it sits in, which the
+/// kernel hands to the page allocator once it is done with them. `len` is 0
+/// without one.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Initramfs {
+    pub base: u64,
+    pub len: u64,
+}
+
+impl Initramfs {
+    pub const fn none() -> Self {
+        Self { base: 0, len: 0 }
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.len == 0
+    }
+}
+
+/// "HSTLBOOT", which starts every boot info hostel writes.
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"HSTLBOOT");
+/// Bumped whenever [`BootInfo`] changes layout, so a kernel never reads a
+/// block written for another.
+pub const BOOT_INFO_VERSION: u32 = 1;
+
+/// Everything the VM tells the kernel before it starts, written in one
+/// block at `BOOT_INFO_PHYS`. A new boot parameter is a new field here and
+/// a bump of [`BOOT_INFO_VERSION`], not an address of its own.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootInfo {
+    magic: u64,
+    version: u32,
+    /// vCPUs the VM created, the boot one included.
+    pub cpu_count: u32,
+    pub flags: RunFlags,
+    /// Host wall-clock time as the guest powers on, in nanoseconds since
+    /// the Unix epoch; the kernel's realtime clock counts on from it.
+    pub wall_clock_nanos: u64,
+    // Physical address and length of the command line, which is not
+    // NUL-terminated. Empty without one.
+    command_line: u64,
+    command_line_len: u64,
+    pub initramfs: Initramfs,
+    pub memory_map: MemoryMap,
+}
+
+impl BootInfo {
+    pub const fn new(cpu_count: u32) -> Self {
+        Self {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_INFO_VERSION,
+            cpu_count,
+            flags: RunFlags::empty(),
+            wall_clock_nanos: 0,
+            command_line: 0,
+            command_line_len: 0,
+            initramfs: Initramfs::none(),
+            memory_map: MemoryMap::empty(),
+        }
+    }
+
+    /// Whether this is a block of the layout this kernel was built with.
+    pub fn is_valid(&self) -> bool {
+        self.magic == BOOT_INFO_MAGIC && self.version == BOOT_INFO_VERSION
+    }
+
+    /// Point the kernel at `len` bytes of command line at `addr`.
+    pub fn set_command_line(&mut self, addr: PhysicalAddr, len: usize) {
+        self.command_line = addr.as_u64();
+        self.command_line_len = len as u64;
+    }
+
+    /// The command line, read through `map`.
+    pub fn command_line(&self, map: &impl DirectMap) -> &'static [u8] {
+        if self.command_line_len == 0 {
+            return &[];
+        }
+        let addr = PhysicalAddr::new(self.command_line as usize).to_virtual(map);
+        // SAFETY: the VM wrote the command line into memory it reserved for
+        // it, which nothing else writes.
+        unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), self.command_line_len as usize) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: the block is `repr(C)` and made of integers with no padding.
+        unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>(),
+                core::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+/// The boot info the VM wrote, or `None` if it is not of the layout this
+/// kernel was built with.
+pub fn read_boot_info(map: &impl DirectMap) -> Option<BootInfo> {
+    let addr = BOOT_INFO_PHYS.to_virtual(map);
+    let info = unsafe { core::ptr::read_volatile(addr.as_ptr::<BootInfo>() as *const BootInfo) };
+    info.is_valid().then_some(info)
+}
+
+/// KVM's kvmclock MSR. The VM writes each vCPU's the guest physical address
+/// of its [`PvClock`], or'd with [`PVCLOCK_ENABLE`].
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+pub const PVCLOCK_ENABLE: u64 = 1;
+
+/// A vCPU's kvmclock, KVM's `pvclock_vcpu_time_info`: how to turn the
+/// vCPU's TSC into nanoseconds on a clock the host keeps in step with its
+/// own. KVM rewrites it as the vCPU enters the guest whenever the two drift
+/// apart. All zeroes if the VM left kvmclock off.
+#[repr(C, align(32))]
+#[derive(Debug, Default)]
+pub struct PvClock {
+    /// Odd while KVM rewrites the rest, and bumped again once it is done.
+    pub(crate) version: u32,
+    _pad0: u32,
+    pub(crate) tsc_timestamp: u64,
+    /// Nanoseconds at `tsc_timestamp`.
+    pub(crate) system_time: u64,
+    pub(crate) tsc_to_system_mul: u32,
+    pub(crate) tsc_shift: i8,
+    pub(crate) flags: u8,
+    _pad1: [u8; 2],
+}
+
+/// vCPU `cpu`'s kvmclock. Only ever read through volatile loads, since KVM
+/// writes it behind the guest's back.
+pub fn pvclock(map: &impl DirectMap, cpu: usize) -> *const PvClock {
+    PVCLOCK_PHYS
+        .to_virtual(map)
+        .as_ptr::<PvClock>()
+        .wrapping_add(cpu)
+}
+
+/// Stop this vCPU for good without powering the VM off, for vCPUs the
+/// kernel has no use for.
+pub fn park() -> ! {
+    loop {
+        unsafe {
+            asm!("cli", "hlt", options(nomem, nostack));
+        }
+    }
 }
 
 pub fn signal_kernel_tests_success() -> ! {
@@ -60,6 +432,19 @@ pub fn signal_kernel_tests_failure() -> ! {
 }
 
 pub fn halt_forever() -> ! {
+    power_off(ExitStatus::Exited(0))
+}
+
+/// Power off the VM, which the VMM reports as ending with `status`.
+pub fn power_off(status: ExitStatus) -> ! {
+    unsafe {
+        asm!(
+            "out dx, ax",
+            in("dx") POWER_OFF_PORT,
+            in("ax") status.wait_status() as u16,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
     loop {
         unsafe {
             asm!("hlt", options(nomem, nostack, preserves_flags));
@@ -78,3 +463,47 @@ fn write_test_exit_code(code: u32) {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_map_keeps_ram_regions_in_order() {
+        let mut map = MemoryMap::empty();
+        map.push(MemoryRegion::new(0, 0x1000, E820_RESERVED))
+            .unwrap();
+        for index in 1..MEMORY_MAP_MAX_ENTRIES as u64 {
+            map.push(MemoryRegion::new(index << 21, 1 << 21, E820_RAM))
+                .unwrap();
+        }
+        let extra = MemoryRegion::new(0, 1, E820_RAM);
+        assert_eq!(map.push(extra), Err(extra));
+
+        assert_eq!(map.regions().len(), MEMORY_MAP_MAX_ENTRIES);
+        assert!(
+            map.ram()
+                .map(|region| region.base)
+                .eq((1..16).map(|i| i << 21))
+        );
+        assert_eq!(map.as_bytes().len(), size_of::<MemoryMap>());
+    }
+
+    #[test]
+    fn boot_info_is_only_valid_at_this_version() {
+        let mut info = BootInfo::new(2);
+        assert!(info.is_valid());
+        assert_eq!(info.as_bytes().len(), size_of::<BootInfo>());
+        assert_eq!(&info.as_bytes()[..8], b"HSTLBOOT");
+
+        info.version += 1;
+        assert!(!info.is_valid());
+        assert!(
+            !BootInfo {
+                magic: 0,
+                ..BootInfo::new(1)
+            }
+            .is_valid()
+        );
+    }
+}
@@ -1,23 +1,122 @@
 use core::fmt::{self, Write};
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use spin::Mutex;
 
+use crate::arch::{self, apic, inb, outb, pic};
+use crate::fs::errors::Result;
+use crate::memory::{address::DirectMap, alloc::palloc::PageAllocator};
+use crate::sync::IrqMutex;
+use crate::virtio::console::VirtioConsole;
+
 const COM1_PORT: u16 = 0x3f8;
+/// Legacy IRQ line COM1 raises.
+pub const COM1_IRQ: u8 = 4;
+const LSR_DATA_READY: u8 = 1 << 0;
 const LSR_THR_EMPTY: u8 = 1 << 5;
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+// RTS and DSR, plus OUT2, which gates the UART's interrupt line.
+const MCR_RTS_DSR: u8 = 0x03;
+const MCR_OUT2: u8 = 1 << 3;
+// Bytes received but not yet read. More arriving while it is full are lost,
+// as with a real UART overrun.
+const INPUT_BUFFER_SIZE: usize = 256;
+
+// The serial line has no way to report the far end's size, so assume a
+// classic terminal.
+pub const ROWS: u16 = 24;
+pub const COLUMNS: u16 = 80;
 
 pub static SERIAL1: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM1_PORT));
+// Takes output over from COM1 once attached.
+static VIRTIO: Mutex<Option<VirtioConsole>> = Mutex::new(None);
+
+static INPUT: IrqMutex<InputBuffer> = IrqMutex::new(InputBuffer::new());
+static INPUT_ENABLED: AtomicBool = AtomicBool::new(false);
 
 pub fn init() {
     SERIAL1.lock().init();
 }
 
+/// Send output through the VMM's virtio console from now on, if it has one,
+/// with kernel messages and programs' output on ports of their own.
+pub fn attach_virtio(palloc: &PageAllocator, dm: &impl DirectMap) -> Result<()> {
+    if let Some(console) = VirtioConsole::probe(palloc, dm)? {
+        arch::without_interrupts(|| *VIRTIO.lock() = Some(console));
+    }
+    Ok(())
+}
+
+/// Have COM1 interrupt on every received byte and queue it for
+/// [`read_input`]. Needs the local APIC in x2APIC mode, which passes the
+/// legacy PIC's interrupts on.
+pub fn enable_input_interrupts() {
+    apic::accept_pic_interrupts();
+    pic::unmask(COM1_IRQ);
+    arch::without_interrupts(|| SERIAL1.lock().enable_rx_interrupt());
+    INPUT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether received bytes are being queued. Until they are, the console
+/// reads as empty.
+pub fn input_enabled() -> bool {
+    INPUT_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Whether [`read_input`] has anything to return.
+pub fn input_pending() -> bool {
+    !INPUT.lock().is_empty()
+}
+
+/// Move queued input into `buf`, oldest byte first. Returns how many bytes
+/// were copied.
+pub fn read_input(buf: &mut [u8]) -> usize {
+    INPUT.lock().pop_into(buf)
+}
+
+/// Drain the UART's receive FIFO into the input queue. Called from the COM1
+/// interrupt handler.
+pub fn receive() {
+    let serial = SERIAL1.lock();
+    let mut input = INPUT.lock();
+    while let Some(byte) = serial.read_byte() {
+        input.push(byte);
+    }
+}
+
+/// Release the serial port whoever holds it, so an NMI that landed in the
+/// middle of a print can still report. Sound only because there is one CPU:
+/// the holder is the interrupted code, which cannot touch the port again
+/// until the handler returns.
+pub fn break_lock() {
+    if SERIAL1.is_locked() {
+        unsafe { SERIAL1.force_unlock() };
+    }
+    if VIRTIO.is_locked() {
+        unsafe { VIRTIO.force_unlock() };
+    }
+}
+
+/// Write a program's console output.
 pub fn write_bytes(bytes: &[u8]) {
-    SERIAL1.lock().write_bytes(bytes);
+    // The output locks are taken with interrupts off, here and in `_print`:
+    // a process preempted mid-line would otherwise leave the next syscall
+    // that prints spinning with interrupts masked.
+    arch::without_interrupts(|| match VIRTIO.lock().as_mut() {
+        Some(console) => console.write_output(bytes),
+        None => SERIAL1.lock().write_bytes(bytes),
+    });
 }
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments<'_>) {
-    let _ = SERIAL1.lock().write_fmt(args);
+    arch::without_interrupts(|| {
+        let _ = match VIRTIO.lock().as_mut() {
+            Some(console) => console.write_fmt(args),
+            None => SERIAL1.lock().write_fmt(args),
+        };
+    });
 }
 
 pub struct SerialPort {
@@ -42,7 +141,16 @@ impl SerialPort {
         // Enable FIFO, clear queues, 14-byte threshold.
         self.write_reg(2, 0xC7);
         // IRQs disabled, RTS/DSR set.
-        self.write_reg(4, 0x03);
+        self.write_reg(4, MCR_RTS_DSR);
+    }
+
+    fn enable_rx_interrupt(&mut self) {
+        self.write_reg(4, MCR_RTS_DSR | MCR_OUT2);
+        self.write_reg(1, IER_RX_AVAILABLE);
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        (self.read_reg(5) & LSR_DATA_READY != 0).then(|| self.read_reg(0))
     }
 
     fn write_reg(&self, offset: u16, value: u8) {
@@ -80,28 +188,65 @@ impl Write for SerialPort {
     }
 }
 
-#[inline]
-fn outb(port: u16, value: u8) {
-    unsafe {
-        core::arch::asm!(
-            "out dx, al",
-            in("dx") port,
-            in("al") value,
-            options(nomem, nostack, preserves_flags),
-        );
+struct InputBuffer {
+    bytes: [u8; INPUT_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl InputBuffer {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; INPUT_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len == INPUT_BUFFER_SIZE {
+            return;
+        }
+        self.bytes[(self.head + self.len) % INPUT_BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop_into(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len);
+        for slot in &mut buf[..count] {
+            *slot = self.bytes[self.head];
+            self.head = (self.head + 1) % INPUT_BUFFER_SIZE;
+        }
+        self.len -= count;
+        count
     }
 }
 
-#[inline]
-fn inb(port: u16) -> u8 {
-    let value: u8;
-    unsafe {
-        core::arch::asm!(
-            "in al, dx",
-            in("dx") port,
-            out("al") value,
-            options(nomem, nostack, preserves_flags),
-        );
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_buffer_wraps_and_drops_overflow() {
+        let mut input = InputBuffer::new();
+        let mut buf = [0; INPUT_BUFFER_SIZE];
+        for byte in 0..200 {
+            input.push(byte);
+        }
+        assert_eq!(input.pop_into(&mut buf[..150]), 150);
+        for byte in 0..=255 {
+            input.push(byte);
+        }
+        // 50 left over, then room for 206 of the new ones.
+        assert_eq!(input.pop_into(&mut buf), INPUT_BUFFER_SIZE);
+        assert_eq!(buf[49], 199);
+        assert_eq!(buf[50], 0);
+        assert_eq!(buf[255], 205);
+        assert!(input.is_empty());
+        assert_eq!(input.pop_into(&mut buf), 0);
     }
-    value
 }
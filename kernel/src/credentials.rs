@@ -0,0 +1,24 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+static UID: AtomicU32 = AtomicU32::new(0);
+static GID: AtomicU32 = AtomicU32::new(0);
+
+/// Identity reported to every process. There is no per-process credential
+/// state yet; the VMM picks the ids through the boot run flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+pub fn init(credentials: Credentials) {
+    UID.store(credentials.uid, Ordering::SeqCst);
+    GID.store(credentials.gid, Ordering::SeqCst);
+}
+
+pub fn current() -> Credentials {
+    Credentials {
+        uid: UID.load(Ordering::SeqCst),
+        gid: GID.load(Ordering::SeqCst),
+    }
+}
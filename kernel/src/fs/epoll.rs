@@ -0,0 +1,162 @@
+use spin::Mutex;
+
+use super::errors::{FsError, Result};
+use super::fd::MAX_FDS;
+
+pub const MAX_INSTANCES: usize = 16;
+
+static INSTANCES: Mutex<EpollTable> = Mutex::new(EpollTable::new());
+
+/// One descriptor on an epoll interest list. `events` and `data` are kept
+/// exactly as userspace registered them; `reported` remembers the readiness
+/// last delivered so edge-triggered watches only fire on new events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watch {
+    pub fd: usize,
+    pub events: u32,
+    pub data: u64,
+    pub reported: u32,
+}
+
+impl Watch {
+    pub const fn new(fd: usize, events: u32, data: u64) -> Self {
+        Self {
+            fd,
+            events,
+            data,
+            reported: 0,
+        }
+    }
+}
+
+/// Interest lists are keyed by descriptor number, so an instance can watch
+/// at most one process's worth of descriptors.
+type InterestList = [Option<Watch>; MAX_FDS];
+
+pub struct EpollTable {
+    instances: [Option<InterestList>; MAX_INSTANCES],
+}
+
+impl EpollTable {
+    pub const fn new() -> Self {
+        Self {
+            instances: [None; MAX_INSTANCES],
+        }
+    }
+
+    pub fn create(&mut self) -> Result<usize> {
+        let id = self
+            .instances
+            .iter()
+            .position(Option::is_none)
+            .ok_or(FsError::TooManyFiles)?;
+        self.instances[id] = Some([None; MAX_FDS]);
+        Ok(id)
+    }
+
+    pub fn destroy(&mut self, id: usize) {
+        self.instances[id] = None;
+    }
+
+    pub fn add(&mut self, id: usize, watch: Watch) -> Result<()> {
+        let list = self.list(id)?;
+        if list
+            .iter()
+            .flatten()
+            .any(|existing| existing.fd == watch.fd)
+        {
+            return Err(FsError::AlreadyExists);
+        }
+        let slot = list
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(FsError::NoSpace)?;
+        *slot = Some(watch);
+        Ok(())
+    }
+
+    /// Replace the events and data of the watch on `fd`, re-arming it.
+    pub fn modify(&mut self, id: usize, fd: usize, events: u32, data: u64) -> Result<()> {
+        *self.watch(id, fd)? = Watch::new(fd, events, data);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: usize, fd: usize) -> Result<()> {
+        let list = self.list(id)?;
+        let slot = list
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|watch| watch.fd == fd))
+            .ok_or(FsError::NotFound)?;
+        *slot = None;
+        Ok(())
+    }
+
+    pub fn watches(&mut self, id: usize) -> Result<InterestList> {
+        self.list(id).copied()
+    }
+
+    pub fn watch(&mut self, id: usize, fd: usize) -> Result<&mut Watch> {
+        self.list(id)?
+            .iter_mut()
+            .flatten()
+            .find(|watch| watch.fd == fd)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn list(&mut self, id: usize) -> Result<&mut InterestList> {
+        self.instances
+            .get_mut(id)
+            .and_then(Option::as_mut)
+            .ok_or(FsError::InvalidArgument)
+    }
+}
+
+impl Default for EpollTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `f` on the shared table of epoll instances.
+pub fn with_instances<T>(f: impl FnOnce(&mut EpollTable) -> T) -> T {
+    f(&mut INSTANCES.lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interest_list_add_modify_remove() {
+        let mut table = EpollTable::new();
+        let id = table.create().unwrap();
+
+        table.add(id, Watch::new(1, 0x4, 7)).unwrap();
+        assert_eq!(
+            table.add(id, Watch::new(1, 0x1, 0)),
+            Err(FsError::AlreadyExists)
+        );
+
+        table.watch(id, 1).unwrap().reported = 0x4;
+        table.modify(id, 1, 0x1, 9).unwrap();
+        assert_eq!(table.watch(id, 1), Ok(&mut Watch::new(1, 0x1, 9)));
+
+        table.remove(id, 1).unwrap();
+        assert_eq!(table.remove(id, 1), Err(FsError::NotFound));
+        assert_eq!(table.modify(id, 1, 0, 0), Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn destroyed_instances_are_reused() {
+        let mut table = EpollTable::new();
+        for _ in 0..MAX_INSTANCES {
+            table.create().unwrap();
+        }
+        assert_eq!(table.create(), Err(FsError::TooManyFiles));
+
+        table.destroy(3);
+        assert_eq!(table.watches(3), Err(FsError::InvalidArgument));
+        assert_eq!(table.create(), Ok(3));
+        assert!(table.watches(3).unwrap().iter().all(Option::is_none));
+    }
+}
@@ -0,0 +1,65 @@
+use thiserror::Error as ThisError;
+
+use crate::net::errors::NetError;
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    #[error("no such file or directory")]
+    NotFound,
+
+    #[error("file exists")]
+    AlreadyExists,
+
+    #[error("not a directory")]
+    NotDirectory,
+
+    #[error("is a directory")]
+    IsDirectory,
+
+    #[error("directory not empty")]
+    NotEmpty,
+
+    #[error("permission denied")]
+    PermissionDenied,
+
+    #[error("resource busy")]
+    Busy,
+
+    #[error("invalid argument")]
+    InvalidArgument,
+
+    #[error("no space left on device")]
+    NoSpace,
+
+    #[error("bad file descriptor")]
+    BadDescriptor,
+
+    #[error("too many open files")]
+    TooManyFiles,
+
+    #[error("illegal seek")]
+    NotSeekable,
+
+    #[error("operation would block")]
+    WouldBlock,
+
+    #[error("read-only file system")]
+    ReadOnly,
+
+    #[error("cross-device link")]
+    CrossDevice,
+
+    #[error("input/output error")]
+    Io,
+
+    #[error("file size exceeds {max} bytes")]
+    FileTooLarge { max: usize },
+
+    #[error("name exceeds {max} bytes")]
+    NameTooLong { max: usize },
+
+    #[error("socket error: {0}")]
+    Net(#[from] NetError),
+}
+
+pub type Result<T> = core::result::Result<T, FsError>;
@@ -0,0 +1,134 @@
+use spin::Mutex;
+
+use super::Readiness;
+use super::errors::{FsError, Result};
+
+pub const MAX_COUNTERS: usize = 16;
+
+/// The largest value a counter holds; writes that would pass it block.
+pub const MAX_COUNT: u64 = u64::MAX - 1;
+
+static COUNTERS: Mutex<EventFdTable> = Mutex::new(EventFdTable::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Counter {
+    value: u64,
+    semaphore: bool,
+}
+
+pub struct EventFdTable {
+    counters: [Option<Counter>; MAX_COUNTERS],
+}
+
+impl EventFdTable {
+    pub const fn new() -> Self {
+        Self {
+            counters: [None; MAX_COUNTERS],
+        }
+    }
+
+    pub fn create(&mut self, initial: u64, semaphore: bool) -> Result<usize> {
+        let id = self
+            .counters
+            .iter()
+            .position(Option::is_none)
+            .ok_or(FsError::TooManyFiles)?;
+        self.counters[id] = Some(Counter {
+            value: initial,
+            semaphore,
+        });
+        Ok(id)
+    }
+
+    pub fn destroy(&mut self, id: usize) {
+        self.counters[id] = None;
+    }
+
+    /// Consume the count: all of it, or a single unit for semaphores.
+    pub fn take(&mut self, id: usize) -> Result<u64> {
+        let counter = self.counter(id)?;
+        if counter.value == 0 {
+            return Err(FsError::WouldBlock);
+        }
+        let taken = if counter.semaphore { 1 } else { counter.value };
+        counter.value -= taken;
+        Ok(taken)
+    }
+
+    pub fn add(&mut self, id: usize, value: u64) -> Result<()> {
+        if value > MAX_COUNT {
+            return Err(FsError::InvalidArgument);
+        }
+        let counter = self.counter(id)?;
+        if value > MAX_COUNT - counter.value {
+            return Err(FsError::WouldBlock);
+        }
+        counter.value += value;
+        Ok(())
+    }
+
+    pub fn readiness(&mut self, id: usize) -> Readiness {
+        self.counter(id)
+            .map(|counter| Readiness {
+                readable: counter.value > 0,
+                writable: counter.value < MAX_COUNT,
+            })
+            .unwrap_or_default()
+    }
+
+    fn counter(&mut self, id: usize) -> Result<&mut Counter> {
+        self.counters
+            .get_mut(id)
+            .and_then(Option::as_mut)
+            .ok_or(FsError::BadDescriptor)
+    }
+}
+
+impl Default for EventFdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `f` on the shared table of event counters.
+pub fn with_counters<T>(f: impl FnOnce(&mut EventFdTable) -> T) -> T {
+    f(&mut COUNTERS.lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_drains_fully_or_one_unit_at_a_time() {
+        let mut table = EventFdTable::new();
+        let plain = table.create(3, false).unwrap();
+        let semaphore = table.create(2, true).unwrap();
+
+        table.add(plain, 4).unwrap();
+        assert_eq!(table.take(plain), Ok(7));
+        assert_eq!(table.take(plain), Err(FsError::WouldBlock));
+
+        assert_eq!(table.take(semaphore), Ok(1));
+        assert_eq!(table.take(semaphore), Ok(1));
+        assert_eq!(table.take(semaphore), Err(FsError::WouldBlock));
+    }
+
+    #[test]
+    fn full_counter_blocks_writers() {
+        let mut table = EventFdTable::new();
+        let id = table.create(0, false).unwrap();
+        assert!(!table.readiness(id).readable);
+
+        table.add(id, MAX_COUNT).unwrap();
+        assert_eq!(
+            table.readiness(id),
+            Readiness {
+                readable: true,
+                writable: false,
+            }
+        );
+        assert_eq!(table.add(id, 1), Err(FsError::WouldBlock));
+        assert_eq!(table.add(id, u64::MAX), Err(FsError::InvalidArgument));
+    }
+}
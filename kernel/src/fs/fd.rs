@@ -0,0 +1,159 @@
+use super::errors::{FsError, Result};
+use crate::memory::vmm::MemoryUsage;
+use crate::process::{ProcessInfo, ProcessName};
+
+pub const MAX_FDS: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Console,
+    Inode(usize),
+    Epoll(usize),
+    EventFd(usize),
+    TimerFd(usize),
+    Socket(usize),
+    TcpSocket(usize),
+    /// A file on the host share, by the fid it was opened as.
+    HostFile(u32),
+    /// `/proc/self/statm`, holding the usage of its opener at open time.
+    ProcStatm(MemoryUsage),
+    /// `/proc/<pid>/comm`, holding the process name at open time.
+    ProcComm(ProcessName),
+    /// `/proc/<pid>/stat`, holding what the process had used at open time.
+    ProcStat(ProcessInfo),
+}
+
+impl FileKind {
+    /// Whether this is a file with contents and an offset into them, in
+    /// ramfs or on the host share.
+    pub fn is_file(&self) -> bool {
+        matches!(self, Self::Inode(_) | Self::HostFile(_))
+    }
+}
+
+/// An open file description: what the descriptor refers to, how it was
+/// opened and where the next read or write happens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpenFile {
+    pub kind: FileKind,
+    pub readable: bool,
+    pub writable: bool,
+    pub append: bool,
+    pub nonblocking: bool,
+    pub offset: usize,
+}
+
+impl OpenFile {
+    pub const fn new(kind: FileKind, readable: bool, writable: bool) -> Self {
+        Self {
+            kind,
+            readable,
+            writable,
+            append: false,
+            nonblocking: false,
+            offset: 0,
+        }
+    }
+
+    pub const fn console(readable: bool, writable: bool) -> Self {
+        Self::new(FileKind::Console, readable, writable)
+    }
+}
+
+/// Per-process descriptor table. New processes start with the console on
+/// stdin, stdout and stderr.
+pub struct FdTable {
+    files: [Option<OpenFile>; MAX_FDS],
+}
+
+impl FdTable {
+    pub const fn with_console() -> Self {
+        let mut files = [None; MAX_FDS];
+        files[0] = Some(OpenFile::console(true, false));
+        files[1] = Some(OpenFile::console(false, true));
+        files[2] = Some(OpenFile::console(false, true));
+        Self { files }
+    }
+
+    pub fn get(&self, fd: usize) -> Result<OpenFile> {
+        self.files
+            .get(fd)
+            .copied()
+            .flatten()
+            .ok_or(FsError::BadDescriptor)
+    }
+
+    pub fn get_mut(&mut self, fd: usize) -> Result<&mut OpenFile> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::as_mut)
+            .ok_or(FsError::BadDescriptor)
+    }
+
+    /// Store `file` under the lowest free descriptor.
+    pub fn install(&mut self, file: OpenFile) -> Result<usize> {
+        let fd = self
+            .files
+            .iter()
+            .position(Option::is_none)
+            .ok_or(FsError::TooManyFiles)?;
+        self.files[fd] = Some(file);
+        Ok(fd)
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Result<OpenFile> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(FsError::BadDescriptor)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, OpenFile)> + '_ {
+        self.files
+            .iter()
+            .enumerate()
+            .filter_map(|(fd, file)| file.map(|file| (fd, file)))
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = OpenFile> + '_ {
+        self.files.iter_mut().filter_map(Option::take)
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::with_console()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn install_reuses_lowest_free_descriptor() {
+        let mut table = FdTable::with_console();
+        let file = OpenFile::console(true, true);
+
+        assert_eq!(table.install(file), Ok(3));
+        assert_eq!(table.remove(1), Ok(OpenFile::console(false, true)));
+        assert_eq!(table.install(file), Ok(1));
+        assert_eq!(table.get(1), Ok(file));
+        assert_eq!(table.remove(1), Ok(file));
+        assert_eq!(table.remove(1), Err(FsError::BadDescriptor));
+        assert_eq!(table.get(MAX_FDS), Err(FsError::BadDescriptor));
+    }
+
+    #[test]
+    fn full_table_reports_too_many_files() {
+        let mut table = FdTable::with_console();
+        for _ in 3..MAX_FDS {
+            table.install(OpenFile::console(true, false)).unwrap();
+        }
+        assert_eq!(
+            table.install(OpenFile::console(true, false)),
+            Err(FsError::TooManyFiles)
+        );
+        assert_eq!(table.drain().count(), MAX_FDS);
+    }
+}
@@ -0,0 +1,452 @@
+//! A directory the VMM shares from the host, mounted at `/host` when the VMM
+//! has a 9p device. Files live on the host and every operation is a 9P
+//! round trip, so nothing is cached; the VMM decides what is writable.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use super::errors::{FsError, Result};
+use super::path::Path;
+use super::{FsStats, OpenOptions};
+use crate::credentials::Credentials;
+use crate::memory::{address::DirectMap, alloc::palloc::PageAllocator};
+use crate::virtio::p9::{self, Qid, Reader};
+use crate::virtio::share::{MSIZE, VirtioShare};
+
+pub const MOUNT_POINT: &[u8] = b"/host";
+
+/// Most data one `Tread` or `Twrite` carries.
+const IOUNIT: usize = MSIZE - p9::IO_HEADER_SIZE;
+/// The fid the share's root is attached as, which stays for good.
+const ROOT_FID: u32 = 0;
+
+static SHARE: Mutex<Option<HostFs>> = Mutex::new(None);
+// Set once the share is attached; until then `/host` is an ordinary
+// ramfs path.
+static MOUNTED: AtomicBool = AtomicBool::new(false);
+
+/// What `Tgetattr` tells of a file.
+#[derive(Clone, Copy, Debug)]
+struct Attr {
+    mode: u32,
+    size: usize,
+}
+
+impl Attr {
+    fn is_dir(&self) -> bool {
+        self.mode & p9::S_IFMT == p9::S_IFDIR
+    }
+}
+
+struct HostFs {
+    share: VirtioShare,
+    // Fids are never handed out twice while the share is up.
+    next_fid: u32,
+}
+
+/// Attach the VMM's shared directory, if it has one, at `MOUNT_POINT`.
+pub fn mount(palloc: &PageAllocator, dm: &impl DirectMap) -> Result<()> {
+    let Some(share) = VirtioShare::probe(palloc, dm)? else {
+        return Ok(());
+    };
+    let mut fs = HostFs {
+        share,
+        next_fid: ROOT_FID + 1,
+    };
+    let mut reply = fs.share.call(p9::TVERSION, |msg| {
+        msg.u32(MSIZE as u32).str(p9::VERSION);
+    })?;
+    let msize = reply.u32().ok_or(FsError::Io)?;
+    if reply.str() != Some(p9::VERSION) || (msize as usize) < MSIZE {
+        return Err(FsError::Io);
+    }
+    fs.share.call(p9::TATTACH, |msg| {
+        msg.u32(ROOT_FID)
+            .u32(p9::NOFID)
+            .str(b"")
+            .str(b"")
+            .u32(crate::credentials::current().uid);
+    })?;
+    crate::println!(
+        "hostfs: {} mounted at {}",
+        fs.share.tag().escape_ascii(),
+        MOUNT_POINT.escape_ascii()
+    );
+    *SHARE.lock() = Some(fs);
+    MOUNTED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Where `path` lies in the share, empty for the mount point itself, or
+/// `None` if it lies outside or nothing is mounted.
+pub fn relative(path: &Path) -> Option<&[u8]> {
+    if !MOUNTED.load(Ordering::SeqCst) {
+        return None;
+    }
+    match path.as_bytes().strip_prefix(MOUNT_POINT)? {
+        [] => Some(&[]),
+        [b'/', rest @ ..] => Some(rest),
+        _ => None,
+    }
+}
+
+fn with_share<T>(f: impl FnOnce(&mut HostFs) -> Result<T>) -> Result<T> {
+    f(SHARE.lock().as_mut().ok_or(FsError::NotFound)?)
+}
+
+pub fn lookup_directory(rel: &[u8]) -> Result<()> {
+    with_share(|fs| {
+        if fs.walk_attr(rel)?.is_dir() {
+            Ok(())
+        } else {
+            Err(FsError::NotDirectory)
+        }
+    })
+}
+
+/// Host owners mean nothing in the guest, so everyone gets the owner's
+/// permissions, and root everything but execution of files no one may run.
+/// A read-only share has no write permissions to give.
+pub fn access(rel: &[u8], mask: u32, who: Credentials) -> Result<()> {
+    let attr = with_share(|fs| fs.walk_attr(rel))?;
+    let granted = if who.uid == 0 {
+        let any_exec = attr.is_dir() || attr.mode & 0o111 != 0;
+        if any_exec { 0o7 } else { 0o6 }
+    } else {
+        attr.mode >> 6
+    };
+    if mask & !granted & 0o7 != 0 {
+        return Err(FsError::PermissionDenied);
+    }
+    Ok(())
+}
+
+/// Check that `rel` exists.
+pub fn lookup(rel: &[u8]) -> Result<()> {
+    with_share(|fs| fs.walk_attr(rel).map(|_| ()))
+}
+
+pub fn mkdir(rel: &[u8], mode: u32) -> Result<()> {
+    let (parent, name) = split(rel).ok_or(FsError::AlreadyExists)?;
+    with_share(|fs| {
+        fs.with_walk(parent, |fs, dir| {
+            fs.share.call(p9::TMKDIR, |msg| {
+                msg.u32(dir).str(name).u32(mode).u32(0);
+            })?;
+            Ok(())
+        })
+    })
+}
+
+pub fn rmdir(rel: &[u8]) -> Result<()> {
+    unlinkat(rel, p9::AT_REMOVEDIR)
+}
+
+pub fn unlink(rel: &[u8]) -> Result<()> {
+    unlinkat(rel, 0)
+}
+
+fn unlinkat(rel: &[u8], flags: u32) -> Result<()> {
+    let (parent, name) = split(rel).ok_or(FsError::Busy)?;
+    with_share(|fs| {
+        fs.with_walk(parent, |fs, dir| {
+            fs.share.call(p9::TUNLINKAT, |msg| {
+                msg.u32(dir).str(name).u32(flags);
+            })?;
+            Ok(())
+        })
+    })
+}
+
+pub fn rename(from: &[u8], to: &[u8]) -> Result<()> {
+    let (Some((from_parent, from_name)), Some((to_parent, to_name))) = (split(from), split(to))
+    else {
+        return Err(FsError::Busy);
+    };
+    with_share(|fs| {
+        fs.with_walk(from_parent, |fs, from_dir| {
+            fs.with_walk(to_parent, |fs, to_dir| {
+                fs.share.call(p9::TRENAMEAT, |msg| {
+                    msg.u32(from_dir).str(from_name).u32(to_dir).str(to_name);
+                })?;
+                Ok(())
+            })
+        })
+    })
+}
+
+pub fn truncate(rel: &[u8], len: usize) -> Result<()> {
+    with_share(|fs| fs.with_walk(rel, |fs, fid| fs.set_size(fid, len)))
+}
+
+/// Open `rel`, returning the fid that stands for it until `close`.
+pub fn open(rel: &[u8], options: &OpenOptions) -> Result<u32> {
+    let mut flags = match (options.read, options.write) {
+        (_, false) => p9::O_RDONLY,
+        (false, true) => p9::O_WRONLY,
+        (true, true) => p9::O_RDWR,
+    };
+    if options.truncate && options.write {
+        flags |= p9::O_TRUNC;
+    }
+    with_share(|fs| {
+        let fid = match fs.walk(rel) {
+            Ok(fid) if options.create && options.exclusive => {
+                fs.clunk(fid);
+                return Err(FsError::AlreadyExists);
+            }
+            Ok(fid) => fid,
+            Err(FsError::NotFound) if options.create => {
+                let (parent, name) = split(rel).ok_or(FsError::NotFound)?;
+                let fid = fs.walk(parent)?;
+                // The directory's fid becomes the new file's.
+                let create = fs.share.call(p9::TLCREATE, |msg| {
+                    msg.u32(fid)
+                        .str(name)
+                        .u32(flags | p9::O_CREAT | p9::O_EXCL)
+                        .u32(options.mode)
+                        .u32(0);
+                });
+                return match create {
+                    Ok(_) => Ok(fid),
+                    Err(err) => {
+                        fs.clunk(fid);
+                        Err(err)
+                    }
+                };
+            }
+            Err(err) => return Err(err),
+        };
+        let opened = fs.lopen(fid, flags).and_then(|qid| {
+            if qid.is_dir() && options.write {
+                Err(FsError::IsDirectory)
+            } else if !qid.is_dir() && options.directory {
+                Err(FsError::NotDirectory)
+            } else {
+                Ok(())
+            }
+        });
+        match opened {
+            Ok(()) => Ok(fid),
+            Err(err) => {
+                fs.clunk(fid);
+                Err(err)
+            }
+        }
+    })
+}
+
+pub fn close(fid: u32) {
+    let _ = with_share(|fs| {
+        fs.clunk(fid);
+        Ok(())
+    });
+}
+
+pub fn read(fid: u32, offset: usize, buf: &mut [u8]) -> Result<usize> {
+    with_share(|fs| {
+        let mut read = 0;
+        for chunk in buf.chunks_mut(IOUNIT) {
+            let mut reply = fs.share.call(p9::TREAD, |msg| {
+                msg.u32(fid)
+                    .u64((offset + read) as u64)
+                    .u32(chunk.len() as u32);
+            })?;
+            let count = reply.u32().ok_or(FsError::Io)? as usize;
+            let data = reply.bytes(count).ok_or(FsError::Io)?;
+            chunk[..count].copy_from_slice(data);
+            read += count;
+            if count < chunk.len() {
+                break;
+            }
+        }
+        Ok(read)
+    })
+}
+
+pub fn write(fid: u32, offset: usize, data: &[u8]) -> Result<usize> {
+    with_share(|fs| {
+        let mut written = 0;
+        for chunk in data.chunks(IOUNIT) {
+            let mut reply = fs.share.call(p9::TWRITE, |msg| {
+                msg.u32(fid)
+                    .u64((offset + written) as u64)
+                    .u32(chunk.len() as u32)
+                    .bytes(chunk);
+            })?;
+            let count = reply.u32().ok_or(FsError::Io)? as usize;
+            written += count;
+            if count < chunk.len() {
+                break;
+            }
+        }
+        Ok(written)
+    })
+}
+
+pub fn size(fid: u32) -> Result<usize> {
+    with_share(|fs| Ok(fs.getattr(fid)?.size))
+}
+
+pub fn truncate_file(fid: u32, len: usize) -> Result<()> {
+    with_share(|fs| fs.set_size(fid, len))
+}
+
+/// Statistics of the host filesystem holding `rel`.
+pub fn statfs(rel: &[u8]) -> Result<FsStats> {
+    with_share(|fs| fs.with_walk(rel, HostFs::statfs))
+}
+
+pub fn fstatfs(fid: u32) -> Result<FsStats> {
+    with_share(|fs| fs.statfs(fid))
+}
+
+// The directory `rel` is in and its name there, or `None` for the mount
+// point, which the share cannot add, remove or rename.
+fn split(rel: &[u8]) -> Option<(&[u8], &[u8])> {
+    if rel.is_empty() {
+        return None;
+    }
+    Some(match rel.iter().rposition(|&b| b == b'/') {
+        Some(sep) => (&rel[..sep], &rel[sep + 1..]),
+        None => (&[], rel),
+    })
+}
+
+impl HostFs {
+    /// A new fid for `rel`, walked from the root a `Twalk` at a time.
+    fn walk(&mut self, rel: &[u8]) -> Result<u32> {
+        let fid = self.next_fid;
+        self.next_fid = fid.checked_add(1).ok_or(FsError::TooManyFiles)?;
+        let mut names = rel
+            .split(|&b| b == b'/')
+            .filter(|name| !name.is_empty())
+            .peekable();
+        let mut from = ROOT_FID;
+        loop {
+            let mut chunk = [&[][..]; p9::MAX_WALK];
+            let mut count = 0;
+            while count < p9::MAX_WALK
+                && let Some(name) = names.next()
+            {
+                chunk[count] = name;
+                count += 1;
+            }
+            let walked = self
+                .share
+                .call(p9::TWALK, |msg| {
+                    msg.u32(from).u32(fid).u16(count as u16);
+                    for name in &chunk[..count] {
+                        msg.str(name);
+                    }
+                })
+                .and_then(|mut reply| match reply.u16() {
+                    Some(walked) if usize::from(walked) == count => Ok(()),
+                    // The walk stopped short of a name that does not exist.
+                    Some(_) => Err(FsError::NotFound),
+                    None => Err(FsError::Io),
+                });
+            if let Err(err) = walked {
+                // Only a complete walk makes the new fid.
+                if from == fid {
+                    self.clunk(fid);
+                }
+                return Err(err);
+            }
+            from = fid;
+            if names.peek().is_none() {
+                return Ok(fid);
+            }
+        }
+    }
+
+    /// Run `f` on a fid for `rel` that is given back afterwards.
+    fn with_walk<T>(
+        &mut self,
+        rel: &[u8],
+        f: impl FnOnce(&mut Self, u32) -> Result<T>,
+    ) -> Result<T> {
+        let fid = self.walk(rel)?;
+        let result = f(self, fid);
+        self.clunk(fid);
+        result
+    }
+
+    fn walk_attr(&mut self, rel: &[u8]) -> Result<Attr> {
+        self.with_walk(rel, Self::getattr)
+    }
+
+    fn clunk(&mut self, fid: u32) {
+        // The server forgets the fid whatever it answers.
+        let _ = self.share.call(p9::TCLUNK, |msg| {
+            msg.u32(fid);
+        });
+    }
+
+    fn lopen(&mut self, fid: u32, flags: u32) -> Result<Qid> {
+        let mut reply = self.share.call(p9::TLOPEN, |msg| {
+            msg.u32(fid).u32(flags);
+        })?;
+        reply.qid().ok_or(FsError::Io)
+    }
+
+    fn getattr(&mut self, fid: u32) -> Result<Attr> {
+        let mut reply = self.share.call(p9::TGETATTR, |msg| {
+            msg.u32(fid).u64(p9::GETATTR_MODE | p9::GETATTR_SIZE);
+        })?;
+        attr(&mut reply).ok_or(FsError::Io)
+    }
+
+    fn set_size(&mut self, fid: u32, len: usize) -> Result<()> {
+        self.share.call(p9::TSETATTR, |msg| {
+            msg.u32(fid).u32(p9::SETATTR_SIZE).u32(0).u32(0).u32(0);
+            msg.u64(len as u64);
+            // Access and modification times.
+            for _ in 0..4 {
+                msg.u64(0);
+            }
+        })?;
+        Ok(())
+    }
+
+    fn statfs(&mut self, fid: u32) -> Result<FsStats> {
+        let mut reply = self.share.call(p9::TSTATFS, |msg| {
+            msg.u32(fid);
+        })?;
+        fs_stats(&mut reply).ok_or(FsError::Io)
+    }
+}
+
+// `Rgetattr`: `valid`, the qid, then mode, owner, group, links and device
+// ahead of the size.
+fn attr(reply: &mut Reader<'_>) -> Option<Attr> {
+    reply.u64()?;
+    reply.qid()?;
+    let mode = reply.u32()?;
+    reply.bytes(4 + 4 + 8 + 8)?;
+    let size = reply.u64()? as usize;
+    Some(Attr { mode, size })
+}
+
+// `Rstatfs`: type, block size, blocks, free blocks, blocks free to
+// non-root, files, free files, filesystem id and longest name.
+fn fs_stats(reply: &mut Reader<'_>) -> Option<FsStats> {
+    reply.u32()?;
+    let block_size = reply.u32()? as usize;
+    let blocks = reply.u64()? as usize;
+    reply.u64()?;
+    let free_blocks = reply.u64()? as usize;
+    let files = reply.u64()? as usize;
+    let free_files = reply.u64()? as usize;
+    reply.u64()?;
+    let name_max = reply.u32()? as usize;
+    Some(FsStats {
+        block_size,
+        blocks,
+        free_blocks,
+        files,
+        free_files,
+        name_max,
+    })
+}
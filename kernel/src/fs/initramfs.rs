@@ -0,0 +1,200 @@
+use super::ROOT_FS;
+use super::errors::{FsError, Result};
+use super::path::Path;
+use super::ramfs::InodeKind;
+use crate::credentials::Credentials;
+use crate::memory::{address::DirectMap, alloc::kmalloc::KernelAllocator};
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_LEN: usize = 110;
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// One member of a newc cpio archive, the format `cpio -H newc` and the
+/// Linux kernel's initramfs use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub name: &'a [u8],
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+}
+
+/// The members of `archive` in order, up to its trailer or its end. A
+/// header that is cut short or not newc ends the walk with
+/// `InvalidArgument`.
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries { rest: archive }
+}
+
+pub struct Entries<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Entries<'a> {
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>> {
+        let header = self
+            .rest
+            .get(..HEADER_LEN)
+            .ok_or(FsError::InvalidArgument)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+        // Thirteen 8-digit hex fields follow the magic: ino, mode, uid, gid,
+        // nlink, mtime, filesize, four device numbers, namesize and check.
+        let field = |index: usize| -> Result<u32> {
+            let start = MAGIC.len() + index * 8;
+            let digits = core::str::from_utf8(&header[start..start + 8])
+                .map_err(|_| FsError::InvalidArgument)?;
+            u32::from_str_radix(digits, 16).map_err(|_| FsError::InvalidArgument)
+        };
+        let (mode, uid, gid) = (field(1)?, field(2)?, field(3)?);
+        let (size, name_size) = (field(6)? as usize, field(11)? as usize);
+
+        // The name ends in a NUL, and both it and the data are padded to
+        // four bytes from the start of the header.
+        let data_start = (HEADER_LEN + name_size).next_multiple_of(4);
+        let end = data_start + size;
+        if name_size == 0 || end > self.rest.len() {
+            return Err(FsError::InvalidArgument);
+        }
+        let name = &self.rest[HEADER_LEN..HEADER_LEN + name_size - 1];
+        let data = &self.rest[data_start..end];
+        self.rest = self.rest.get(end.next_multiple_of(4)..).unwrap_or_default();
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(Entry {
+            name,
+            mode,
+            uid,
+            gid,
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let next = self.next_entry().transpose();
+        if !matches!(next, Some(Ok(_))) {
+            self.rest = &[];
+        }
+        next
+    }
+}
+
+/// Unpack `archive` into the root filesystem, keeping each member's mode
+/// and owner, and return how many members it took. ramfs only has files
+/// and directories, so symlinks, devices and the like are skipped; a
+/// directory that is already there is kept as it is.
+pub fn unpack<DM: DirectMap>(archive: &[u8], kalloc: &KernelAllocator<'_, DM>) -> Result<usize> {
+    let mut fs = ROOT_FS.lock();
+    let mut unpacked = 0;
+    for entry in entries(archive) {
+        let entry = entry?;
+        let path = Path::root().resolve(entry.name)?;
+        let owner = Credentials {
+            uid: entry.uid,
+            gid: entry.gid,
+        };
+        if entry.is_dir() {
+            match fs.mkdir(&path, entry.mode, owner) {
+                Err(FsError::AlreadyExists)
+                    if fs.kind(fs.lookup(&path)?) == InodeKind::Directory => {}
+                result => {
+                    result?;
+                }
+            }
+        } else if entry.is_file() {
+            let ino = fs.create(&path, InodeKind::File, entry.mode, owner)?;
+            if fs.write(ino, 0, entry.data, kalloc)? < entry.data.len() {
+                return Err(FsError::NoSpace);
+            }
+        } else {
+            continue;
+        }
+        unpacked += 1;
+    }
+    Ok(unpacked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A newc member the way `cpio -o -H newc` writes one.
+    fn member(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [1, mode, 1000, 100, 1, 0, data.len() as u32, 0, 0, 0, 0];
+        archive.extend_from_slice(MAGIC);
+        for field in fields {
+            archive.extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        archive.extend_from_slice(format!("{:08x}{:08x}", name.len() + 1, 0).as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+
+    #[test]
+    fn newc_members_are_read_up_to_the_trailer() {
+        let mut archive = Vec::new();
+        member(&mut archive, ".", S_IFDIR | 0o755, b"");
+        member(&mut archive, "etc", S_IFDIR | 0o755, b"");
+        member(&mut archive, "etc/motd", S_IFREG | 0o644, b"hello\n");
+        member(&mut archive, "bin/sh", 0o120777, b"busybox");
+        member(&mut archive, "TRAILER!!!", 0, b"");
+        // Archives are padded out to a block, which is never read.
+        archive.resize(archive.len().next_multiple_of(512), 0);
+
+        let entries: Vec<_> = entries(&archive).map(Result::unwrap).collect();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name).collect();
+        assert_eq!(
+            names,
+            [&b"."[..], b"etc", b"etc/motd", b"bin/sh"].as_slice()
+        );
+        let motd = entries[2];
+        assert!(motd.is_file() && !motd.is_dir());
+        assert_eq!((motd.mode & 0o7777, motd.uid, motd.gid), (0o644, 1000, 100));
+        assert_eq!(motd.data, b"hello\n");
+        assert!(entries[1].is_dir());
+        assert!(!entries[3].is_file() && !entries[3].is_dir());
+    }
+
+    #[test]
+    fn malformed_archives_end_the_walk_with_an_error() {
+        let mut archive = Vec::new();
+        member(&mut archive, "file", S_IFREG | 0o644, b"contents");
+        let cut = &archive[..archive.len() - 8];
+        assert!(matches!(
+            entries(cut).collect::<Vec<_>>().as_slice(),
+            [Err(FsError::InvalidArgument)]
+        ));
+
+        archive[..6].copy_from_slice(b"070707");
+        let mut walk = entries(&archive);
+        assert_eq!(walk.next(), Some(Err(FsError::InvalidArgument)));
+        assert_eq!(walk.next(), None);
+    }
+}
@@ -0,0 +1,601 @@
+pub mod epoll;
+pub mod errors;
+pub mod eventfd;
+pub mod fd;
+pub mod hostfs;
+pub mod initramfs;
+pub mod path;
+pub mod procfs;
+pub mod ramfs;
+pub mod timerfd;
+
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::Kernel;
+use crate::console;
+use crate::credentials::{self, Credentials};
+use crate::memory::{address::DirectMap, alloc::palloc::PageAllocator, constants::PAGE_SIZE};
+use crate::net::inet;
+use crate::net::unix::{self, SocketType};
+use crate::process;
+use crate::time;
+
+use errors::{FsError, Result};
+use fd::{FileKind, OpenFile};
+use path::Path;
+use ramfs::{InodeKind, RamFs};
+use timerfd::TimerSetting;
+
+/// The root filesystem. The host share, when the VMM has one, is the only
+/// thing mounted on it.
+static ROOT_FS: Mutex<RamFs> = Mutex::new(RamFs::new());
+
+/// Every process runs code linked into the kernel image, so that is what
+/// `/proc/self/exe` points at.
+const SELF_EXE_LINK: &[u8] = b"/proc/self/exe";
+const SELF_EXE_TARGET: &[u8] = b"/kernel";
+
+/// How a file is opened, decoded from the `O_*` flags of open(2).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    pub exclusive: bool,
+    pub truncate: bool,
+    pub append: bool,
+    pub nonblocking: bool,
+    pub directory: bool,
+    pub mode: u32,
+}
+
+/// Capacity and usage of a filesystem. ramfs keeps file data in pages from
+/// the page allocator, so its blocks are pages and its free space is
+/// whatever the allocator has left.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FsStats {
+    pub block_size: usize,
+    pub blocks: usize,
+    pub free_blocks: usize,
+    pub files: usize,
+    pub free_files: usize,
+    pub name_max: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Whence {
+    Set,
+    Current,
+    End,
+}
+
+/// Check that `path` names an existing directory.
+pub fn lookup_directory(path: &Path) -> Result<()> {
+    if let Some(rel) = hostfs::relative(path) {
+        return hostfs::lookup_directory(rel);
+    }
+    let fs = ROOT_FS.lock();
+    match fs.kind(fs.lookup(path)?) {
+        InodeKind::Directory => Ok(()),
+        InodeKind::File => Err(FsError::NotDirectory),
+    }
+}
+
+pub fn access(path: &Path, mask: u32, who: Credentials) -> Result<()> {
+    if let Some(rel) = hostfs::relative(path) {
+        return hostfs::access(rel, mask, who);
+    }
+    let fs = ROOT_FS.lock();
+    fs.access(fs.lookup(path)?, mask, who)
+}
+
+/// Target of the symbolic link at `path`. Neither ramfs nor the host share
+/// has symlinks to show, so every existing file answers `InvalidArgument`.
+pub fn readlink(path: &Path) -> Result<&'static [u8]> {
+    if path.as_bytes() == SELF_EXE_LINK {
+        return Ok(SELF_EXE_TARGET);
+    }
+    match hostfs::relative(path) {
+        Some(rel) => hostfs::lookup(rel)?,
+        None => ROOT_FS.lock().lookup(path).map(|_| ())?,
+    }
+    Err(FsError::InvalidArgument)
+}
+
+pub fn mkdir(path: &Path, mode: u32) -> Result<()> {
+    if let Some(rel) = hostfs::relative(path) {
+        return hostfs::mkdir(rel, mode);
+    }
+    ROOT_FS
+        .lock()
+        .mkdir(path, mode, credentials::current())
+        .map(|_| ())
+}
+
+pub fn rmdir<DM: DirectMap>(kernel: &Kernel<'_, DM>, path: &Path) -> Result<()> {
+    if let Some(rel) = hostfs::relative(path) {
+        return hostfs::rmdir(rel);
+    }
+    ROOT_FS.lock().rmdir(path, kernel.kalloc)
+}
+
+pub fn unlink<DM: DirectMap>(kernel: &Kernel<'_, DM>, path: &Path) -> Result<()> {
+    if let Some(rel) = hostfs::relative(path) {
+        return hostfs::unlink(rel);
+    }
+    ROOT_FS.lock().unlink(path, kernel.kalloc)
+}
+
+/// Rename within one filesystem; nothing moves between ramfs and the host
+/// share.
+pub fn rename<DM: DirectMap>(kernel: &Kernel<'_, DM>, from: &Path, to: &Path) -> Result<()> {
+    match (hostfs::relative(from), hostfs::relative(to)) {
+        (Some(from), Some(to)) => hostfs::rename(from, to),
+        (None, None) => ROOT_FS.lock().rename(from, to, kernel.kalloc),
+        _ => Err(FsError::CrossDevice),
+    }
+}
+
+/// Page allocator shrinker that drops all-zero ramfs blocks. Nothing is
+/// released if the filesystem or the kernel allocator is locked, as they are
+/// when a ramfs write or a heap allocation is what ran out of memory. Blocks
+/// share allocator pages, so the pages given back are counted rather than
+/// assumed.
+pub fn shrink_ramfs(pages: usize) -> usize {
+    let (Some(kernel), Some(mut fs)) = (crate::try_active_kernel(), ROOT_FS.try_lock()) else {
+        return 0;
+    };
+    if kernel.kalloc.try_stats().is_none() {
+        return 0;
+    }
+    let used = kernel.palloc.get_stats().used_pages;
+    fs.trim(pages.saturating_mul(ramfs::BLOCKS_PER_PAGE), kernel.kalloc);
+    used.saturating_sub(kernel.palloc.get_stats().used_pages)
+}
+
+pub fn truncate<DM: DirectMap>(kernel: &Kernel<'_, DM>, path: &Path, len: usize) -> Result<()> {
+    if let Some(rel) = hostfs::relative(path) {
+        return hostfs::truncate(rel, len);
+    }
+    let mut fs = ROOT_FS.lock();
+    let ino = fs.lookup(path)?;
+    fs.truncate(ino, len, kernel.kalloc)
+}
+
+pub fn open<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    path: &Path,
+    options: &OpenOptions,
+) -> Result<OpenFile> {
+    if path.as_bytes() == procfs::SELF_STATM {
+        return open_self_statm(kernel, options);
+    }
+    if let Some(pid) = procfs::comm_pid(path.as_bytes()) {
+        return open_comm(kernel, pid, options);
+    }
+    if let Some(pid) = procfs::stat_pid(path.as_bytes()) {
+        return open_stat(kernel, pid, options);
+    }
+    if let Some(rel) = hostfs::relative(path) {
+        let fid = hostfs::open(rel, options)?;
+        return Ok(OpenFile {
+            append: options.append,
+            nonblocking: options.nonblocking,
+            ..OpenFile::new(FileKind::HostFile(fid), options.read, options.write)
+        });
+    }
+
+    let mut fs = ROOT_FS.lock();
+    let who = credentials::current();
+    let ino = match fs.lookup(path) {
+        Ok(_) if options.create && options.exclusive => return Err(FsError::AlreadyExists),
+        Ok(ino) => {
+            let mask = if options.read { 0o4 } else { 0 } | if options.write { 0o2 } else { 0 };
+            fs.access(ino, mask, who)?;
+            ino
+        }
+        // Whoever creates a file may open it regardless of the mode it gets.
+        Err(FsError::NotFound) if options.create => {
+            fs.create(path, InodeKind::File, options.mode, who)?
+        }
+        Err(err) => return Err(err),
+    };
+
+    match fs.kind(ino) {
+        InodeKind::Directory if options.write => return Err(FsError::IsDirectory),
+        InodeKind::File if options.directory => return Err(FsError::NotDirectory),
+        InodeKind::File if options.truncate && options.write => {
+            fs.truncate(ino, 0, kernel.kalloc)?;
+        }
+        _ => {}
+    }
+
+    fs.open(ino);
+    Ok(OpenFile {
+        append: options.append,
+        nonblocking: options.nonblocking,
+        ..OpenFile::new(FileKind::Inode(ino), options.read, options.write)
+    })
+}
+
+// The usage is taken now rather than at read time: reads run with the
+// process table locked.
+fn open_self_statm<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    options: &OpenOptions,
+) -> Result<OpenFile> {
+    if options.directory {
+        return Err(FsError::NotDirectory);
+    }
+    if options.write {
+        return Err(FsError::PermissionDenied);
+    }
+    let usage = process::memory_usage(kernel, 0).map_err(|_| FsError::NotFound)?;
+    Ok(OpenFile {
+        nonblocking: options.nonblocking,
+        ..OpenFile::new(FileKind::ProcStatm(usage), true, false)
+    })
+}
+
+fn open_comm<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    pid: usize,
+    options: &OpenOptions,
+) -> Result<OpenFile> {
+    if options.directory {
+        return Err(FsError::NotDirectory);
+    }
+    if options.write {
+        return Err(FsError::PermissionDenied);
+    }
+    let name = process::name(kernel, pid).ok_or(FsError::NotFound)?;
+    Ok(OpenFile {
+        nonblocking: options.nonblocking,
+        ..OpenFile::new(FileKind::ProcComm(name), true, false)
+    })
+}
+
+fn open_stat<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    pid: usize,
+    options: &OpenOptions,
+) -> Result<OpenFile> {
+    if options.directory {
+        return Err(FsError::NotDirectory);
+    }
+    if options.write {
+        return Err(FsError::PermissionDenied);
+    }
+    let info = process::info(kernel, pid).ok_or(FsError::NotFound)?;
+    Ok(OpenFile {
+        nonblocking: options.nonblocking,
+        ..OpenFile::new(FileKind::ProcStat(info), true, false)
+    })
+}
+
+/// Create an epoll instance with an empty interest list.
+pub fn epoll_create() -> Result<OpenFile> {
+    let id = epoll::with_instances(|instances| instances.create())?;
+    Ok(OpenFile::new(FileKind::Epoll(id), true, true))
+}
+
+/// Create an event counter starting at `initial`. Semaphore counters hand
+/// out one unit per read instead of draining the whole count.
+pub fn eventfd_create(initial: u64, semaphore: bool, nonblocking: bool) -> Result<OpenFile> {
+    let id = eventfd::with_counters(|counters| counters.create(initial, semaphore))?;
+    Ok(OpenFile {
+        nonblocking,
+        ..OpenFile::new(FileKind::EventFd(id), true, true)
+    })
+}
+
+/// Create a disarmed timer on a clock that reads `epoch` when the monotonic
+/// clock reads zero.
+pub fn timerfd_create(epoch: Duration, nonblocking: bool) -> Result<OpenFile> {
+    let id = timerfd::with_timers(|timers| timers.create(epoch))?;
+    Ok(OpenFile {
+        nonblocking,
+        ..OpenFile::new(FileKind::TimerFd(id), true, false)
+    })
+}
+
+/// Re-arm the timer behind `file`, returning its previous setting.
+pub fn timerfd_settime(file: &OpenFile, new: TimerSetting, absolute: bool) -> Result<TimerSetting> {
+    let FileKind::TimerFd(id) = file.kind else {
+        return Err(FsError::InvalidArgument);
+    };
+    timerfd::with_timers(|timers| timers.set(id, new, absolute, time::monotonic()))
+}
+
+pub fn timerfd_gettime(file: &OpenFile) -> Result<TimerSetting> {
+    let FileKind::TimerFd(id) = file.kind else {
+        return Err(FsError::InvalidArgument);
+    };
+    timerfd::with_timers(|timers| timers.get(id, time::monotonic()))
+}
+
+/// Create an unconnected unix-domain socket.
+pub fn socket_create(ty: SocketType, nonblocking: bool) -> Result<OpenFile> {
+    let id = unix::with_sockets(|sockets| sockets.create(ty))?;
+    Ok(socket_file(id, nonblocking))
+}
+
+/// Wrap an existing socket, such as an accepted connection, in a file.
+pub fn socket_file(id: usize, nonblocking: bool) -> OpenFile {
+    OpenFile {
+        nonblocking,
+        ..OpenFile::new(FileKind::Socket(id), true, true)
+    }
+}
+
+/// Create an unconnected TCP socket.
+pub fn tcp_socket_create(nonblocking: bool) -> Result<OpenFile> {
+    let id = inet::with_stack(|stack| stack.create())?;
+    Ok(tcp_socket_file(id, nonblocking))
+}
+
+pub fn tcp_socket_file(id: usize, nonblocking: bool) -> OpenFile {
+    OpenFile {
+        nonblocking,
+        ..OpenFile::new(FileKind::TcpSocket(id), true, true)
+    }
+}
+
+/// Let go of what `file` refers to. Fails when the data of an unlinked file
+/// cannot be freed, though the file is closed all the same.
+pub fn close<DM: DirectMap>(kernel: &Kernel<'_, DM>, file: OpenFile) -> Result<()> {
+    match file.kind {
+        FileKind::Console
+        | FileKind::ProcStatm(_)
+        | FileKind::ProcComm(_)
+        | FileKind::ProcStat(_) => {}
+        FileKind::Inode(ino) => return ROOT_FS.lock().release(ino, kernel.kalloc),
+        FileKind::HostFile(fid) => hostfs::close(fid),
+        FileKind::Epoll(id) => epoll::with_instances(|instances| instances.destroy(id)),
+        FileKind::EventFd(id) => eventfd::with_counters(|counters| counters.destroy(id)),
+        FileKind::TimerFd(id) => timerfd::with_timers(|timers| timers.destroy(id)),
+        FileKind::Socket(id) => unix::with_sockets(|sockets| sockets.close(id)),
+        FileKind::TcpSocket(id) => {
+            // The stack is always up if a TCP socket exists.
+            let _ = inet::with_stack(|stack| {
+                stack.close(id);
+                Ok(())
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Whether I/O on a descriptor would complete without blocking.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Readiness {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+/// Ramfs and host files never block: their I/O completes in place. Epoll instances
+/// cannot be nested, so they never report ready. The console, event
+/// counters, timers and sockets follow their queues; a console without input
+/// interrupts reads as end of file, which is always ready.
+pub fn poll(file: &OpenFile) -> Readiness {
+    match file.kind {
+        FileKind::Console => Readiness {
+            readable: !console::input_enabled() || console::input_pending(),
+            writable: true,
+        },
+        FileKind::Inode(_)
+        | FileKind::HostFile(_)
+        | FileKind::ProcStatm(_)
+        | FileKind::ProcComm(_)
+        | FileKind::ProcStat(_) => Readiness {
+            readable: true,
+            writable: true,
+        },
+        FileKind::Epoll(_) => Readiness::default(),
+        FileKind::EventFd(id) => eventfd::with_counters(|counters| counters.readiness(id)),
+        FileKind::TimerFd(id) => {
+            timerfd::with_timers(|timers| timers.readiness(id, time::monotonic()))
+        }
+        FileKind::Socket(id) => unix::with_sockets(|sockets| sockets.readiness(id)),
+        FileKind::TcpSocket(id) => {
+            inet::with_stack(|stack| Ok(stack.readiness(id))).unwrap_or_default()
+        }
+    }
+}
+
+/// Read at the file offset and advance it. The console returns what its
+/// input interrupts queued, and hits end of file when they are off.
+pub fn read<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    file: &mut OpenFile,
+    buf: &mut [u8],
+) -> Result<usize> {
+    if !file.readable {
+        return Err(FsError::BadDescriptor);
+    }
+    match file.kind {
+        FileKind::Console => match console::read_input(buf) {
+            0 if console::input_enabled() && !buf.is_empty() => Err(FsError::WouldBlock),
+            read => Ok(read),
+        },
+        FileKind::Epoll(_) => Err(FsError::InvalidArgument),
+        FileKind::EventFd(id) => {
+            let buf = buf.first_chunk_mut::<8>().ok_or(FsError::InvalidArgument)?;
+            *buf = eventfd::with_counters(|counters| counters.take(id))?.to_ne_bytes();
+            Ok(8)
+        }
+        FileKind::TimerFd(id) => {
+            let buf = buf.first_chunk_mut::<8>().ok_or(FsError::InvalidArgument)?;
+            let expirations = timerfd::with_timers(|timers| timers.take(id, time::monotonic()))?;
+            *buf = expirations.to_ne_bytes();
+            Ok(8)
+        }
+        FileKind::Socket(id) => Ok(unix::with_sockets(|sockets| sockets.recv(id, buf))?.len),
+        FileKind::TcpSocket(id) => inet::with_stack(|stack| stack.recv(id, buf)),
+        FileKind::ProcStatm(usage) => {
+            let mut text = [0; procfs::STATM_MAX_LEN];
+            let len = procfs::statm(usage, &mut text);
+            Ok(read_generated(file, &text[..len], buf))
+        }
+        FileKind::ProcComm(name) => {
+            let mut text = [0; procfs::COMM_MAX_LEN];
+            let len = procfs::comm(&name, &mut text);
+            Ok(read_generated(file, &text[..len], buf))
+        }
+        FileKind::ProcStat(info) => {
+            let mut text = [0; procfs::STAT_MAX_LEN];
+            let len = procfs::stat(&info, &mut text);
+            Ok(read_generated(file, &text[..len], buf))
+        }
+        FileKind::Inode(ino) => {
+            let read = ROOT_FS
+                .lock()
+                .read(ino, file.offset, buf, kernel.kalloc.direct_map())?;
+            file.offset += read;
+            Ok(read)
+        }
+        FileKind::HostFile(fid) => {
+            let read = hostfs::read(fid, file.offset, buf)?;
+            file.offset += read;
+            Ok(read)
+        }
+    }
+}
+
+// Read from text generated for a /proc file, at and past the file offset.
+fn read_generated(file: &mut OpenFile, text: &[u8], buf: &mut [u8]) -> usize {
+    let rest = text.get(file.offset..).unwrap_or_default();
+    let read = rest.len().min(buf.len());
+    buf[..read].copy_from_slice(&rest[..read]);
+    file.offset += read;
+    read
+}
+
+pub fn write<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    file: &mut OpenFile,
+    data: &[u8],
+) -> Result<usize> {
+    if !file.writable {
+        return Err(FsError::BadDescriptor);
+    }
+    match file.kind {
+        FileKind::Console => {
+            console::write_bytes(data);
+            Ok(data.len())
+        }
+        FileKind::Epoll(_)
+        | FileKind::TimerFd(_)
+        | FileKind::ProcStatm(_)
+        | FileKind::ProcComm(_)
+        | FileKind::ProcStat(_) => Err(FsError::InvalidArgument),
+        FileKind::EventFd(id) => {
+            let value = data
+                .first_chunk::<8>()
+                .map(|bytes| u64::from_ne_bytes(*bytes))
+                .ok_or(FsError::InvalidArgument)?;
+            eventfd::with_counters(|counters| counters.add(id, value))?;
+            Ok(8)
+        }
+        FileKind::Socket(id) => unix::with_sockets(|sockets| sockets.send(id, data, None)),
+        FileKind::TcpSocket(id) => inet::with_stack(|stack| stack.send(id, data)),
+        FileKind::Inode(ino) => {
+            let mut fs = ROOT_FS.lock();
+            if file.append {
+                file.offset = fs.size(ino);
+            }
+            let written = fs.write(ino, file.offset, data, kernel.kalloc)?;
+            file.offset += written;
+            Ok(written)
+        }
+        FileKind::HostFile(fid) => {
+            if file.append {
+                file.offset = hostfs::size(fid)?;
+            }
+            let written = hostfs::write(fid, file.offset, data)?;
+            file.offset += written;
+            Ok(written)
+        }
+    }
+}
+
+pub fn seek(file: &mut OpenFile, offset: i64, whence: Whence) -> Result<usize> {
+    let base = match (whence, file.kind) {
+        (Whence::Set, kind) if kind.is_file() => 0,
+        (Whence::Current, kind) if kind.is_file() => file.offset,
+        (Whence::End, FileKind::Inode(ino)) => ROOT_FS.lock().size(ino),
+        (Whence::End, FileKind::HostFile(fid)) => hostfs::size(fid)?,
+        _ => return Err(FsError::NotSeekable),
+    };
+    let target = (base as i64)
+        .checked_add(offset)
+        .and_then(|target| usize::try_from(target).ok())
+        .ok_or(FsError::InvalidArgument)?;
+    file.offset = target;
+    Ok(target)
+}
+
+pub fn truncate_file<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    file: &OpenFile,
+    len: usize,
+) -> Result<()> {
+    match file.kind {
+        FileKind::Inode(ino) if file.writable => ROOT_FS.lock().truncate(ino, len, kernel.kalloc),
+        FileKind::HostFile(fid) if file.writable => hostfs::truncate_file(fid, len),
+        _ => Err(FsError::InvalidArgument),
+    }
+}
+
+/// Statistics of the filesystem holding `path`.
+pub fn statfs<DM: DirectMap>(kernel: &Kernel<'_, DM>, path: &Path) -> Result<FsStats> {
+    if let Some(rel) = hostfs::relative(path) {
+        return hostfs::statfs(rel);
+    }
+    let fs = ROOT_FS.lock();
+    fs.lookup(path)?;
+    Ok(ramfs_stats(&fs, kernel.palloc))
+}
+
+/// Whether `path` lies on the host share rather than in ramfs.
+pub fn on_host_share(path: &Path) -> bool {
+    hostfs::relative(path).is_some()
+}
+
+/// Statistics of the filesystem holding an open ramfs or host file.
+pub fn fstatfs<DM: DirectMap>(kernel: &Kernel<'_, DM>, file: &OpenFile) -> Result<FsStats> {
+    match file.kind {
+        FileKind::Inode(_) => Ok(ramfs_stats(&ROOT_FS.lock(), kernel.palloc)),
+        FileKind::HostFile(fid) => hostfs::fstatfs(fid),
+        _ => Err(FsError::InvalidArgument),
+    }
+}
+
+fn ramfs_stats(fs: &RamFs, palloc: &PageAllocator) -> FsStats {
+    let pages = palloc.get_stats();
+    FsStats {
+        block_size: PAGE_SIZE,
+        blocks: pages.allocatable_limit_pages,
+        free_blocks: pages
+            .allocatable_limit_pages
+            .saturating_sub(pages.used_pages),
+        files: ramfs::MAX_INODES,
+        free_files: fs.free_inodes(),
+        name_max: ramfs::NAME_MAX,
+    }
+}
+
+/// Path of the directory behind an open descriptor, used as the base for
+/// `*at` syscalls. Directories on the host share keep no path to give.
+pub fn directory_path(file: &OpenFile) -> Result<Path> {
+    let FileKind::Inode(ino) = file.kind else {
+        return Err(FsError::NotDirectory);
+    };
+    let fs = ROOT_FS.lock();
+    if fs.kind(ino) != InodeKind::Directory {
+        return Err(FsError::NotDirectory);
+    }
+    fs.path_of(ino)
+}
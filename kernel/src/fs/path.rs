@@ -0,0 +1,130 @@
+use super::errors::{FsError, Result};
+
+/// Longest path accepted by the kernel, including the terminating NUL.
+pub const PATH_MAX: usize = 4096;
+
+/// Absolute, normalized path: starts with `/`, has no empty, `.` or `..`
+/// components and no trailing slash (except for the root itself).
+#[derive(Clone)]
+pub struct Path {
+    buf: [u8; PATH_MAX],
+    len: usize,
+}
+
+impl Path {
+    pub const fn root() -> Self {
+        let mut buf = [0; PATH_MAX];
+        buf[0] = b'/';
+        Self { buf, len: 1 }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.len == 1
+    }
+
+    pub fn components(&self) -> impl Iterator<Item = &[u8]> {
+        self.as_bytes()
+            .split(|&b| b == b'/')
+            .filter(|name| !name.is_empty())
+    }
+
+    /// Resolve `path` lexically: absolute paths start over from the root,
+    /// relative ones are appended to `self`.
+    pub fn resolve(&self, path: &[u8]) -> Result<Self> {
+        if path.is_empty() {
+            return Err(FsError::NotFound);
+        }
+
+        let mut resolved = if path[0] == b'/' {
+            Self::root()
+        } else {
+            self.clone()
+        };
+        for name in path.split(|&b| b == b'/') {
+            match name {
+                b"" | b"." => {}
+                b".." => resolved.pop(),
+                name => resolved.push(name)?,
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn push(&mut self, name: &[u8]) -> Result<()> {
+        let sep = usize::from(!self.is_root());
+        // Leave room for the NUL terminator that getcwd hands back.
+        if self.len + sep + name.len() >= PATH_MAX {
+            return Err(FsError::NameTooLong { max: PATH_MAX });
+        }
+        if sep == 1 {
+            self.buf[self.len] = b'/';
+        }
+        let start = self.len + sep;
+        self.buf[start..start + name.len()].copy_from_slice(name);
+        self.len = start + name.len();
+        Ok(())
+    }
+
+    fn pop(&mut self) {
+        let last_sep = self
+            .as_bytes()
+            .iter()
+            .rposition(|&b| b == b'/')
+            .unwrap_or(0);
+        self.len = last_sep.max(1);
+    }
+}
+
+impl PartialEq for Path {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for Path {}
+
+impl core::fmt::Debug for Path {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "\"{}\"", self.as_bytes().escape_ascii())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(cwd: &[u8], path: &[u8]) -> Result<Vec<u8>> {
+        let cwd = Path::root().resolve(cwd)?;
+        Ok(cwd.resolve(path)?.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn relative_paths_join_the_working_directory() {
+        assert_eq!(resolve(b"/usr", b"lib/./x").unwrap(), b"/usr/lib/x");
+        assert_eq!(
+            resolve(b"/usr/lib", b"../bin//sh/").unwrap(),
+            b"/usr/bin/sh"
+        );
+        assert_eq!(resolve(b"/usr", b"/etc").unwrap(), b"/etc");
+    }
+
+    #[test]
+    fn dotdot_stops_at_root() {
+        assert_eq!(resolve(b"/a", b"../../..").unwrap(), b"/");
+        assert!(Path::root().resolve(b"/a/..").unwrap().is_root());
+        assert_eq!(Path::root().resolve(b""), Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn overlong_paths_are_rejected() {
+        let long = vec![b'a'; PATH_MAX];
+        assert_eq!(
+            Path::root().resolve(&long),
+            Err(FsError::NameTooLong { max: PATH_MAX })
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! Files under `/proc` that are generated from kernel state instead of being
+//! stored in ramfs.
+
+use core::fmt::{self, Write};
+
+use crate::memory::vmm::{MemoryUsage, USER_PAGE_SIZE};
+use crate::process::{NAME_MAX_LEN, ProcessInfo, ProcessName, TaskState};
+use crate::time;
+
+pub const SELF_STATM: &[u8] = b"/proc/self/statm";
+
+/// Longest rendering of a comm file: the name and a newline.
+pub const COMM_MAX_LEN: usize = NAME_MAX_LEN + 1;
+
+/// Longest rendering of a statm line: seven 20-digit counts, their
+/// separators and the newline.
+pub const STATM_MAX_LEN: usize = 7 * 21;
+
+/// Longest rendering of a stat line: the parenthesised name and sixteen
+/// other fields of at most 20 characters, each with its separator.
+pub const STAT_MAX_LEN: usize = NAME_MAX_LEN + 3 + 16 * 21;
+
+/// Render `usage` in the layout of Linux's `/proc/<pid>/statm`: total,
+/// resident, shared, text, library, data and dirty pages. Every mapping is
+/// private anonymous memory, so nothing is shared, text or library, and all
+/// of it counts as data.
+pub fn statm(usage: MemoryUsage, buf: &mut [u8; STATM_MAX_LEN]) -> usize {
+    let size = usage.mapped_bytes / USER_PAGE_SIZE;
+    let resident = usage.resident_bytes / USER_PAGE_SIZE;
+    let mut cursor = Cursor { buf, len: 0 };
+    writeln!(cursor, "{size} {resident} 0 0 0 {size} 0").expect("statm fits its buffer");
+    cursor.len
+}
+
+/// The pid a `/proc/<pid>/comm` or `/proc/self/comm` path names, with 0
+/// standing for `self`. `None` for any other path.
+pub fn comm_pid(path: &[u8]) -> Option<usize> {
+    file_pid(path, b"/comm")
+}
+
+/// The pid a `/proc/<pid>/stat` or `/proc/self/stat` path names, with 0
+/// standing for `self`. `None` for any other path.
+pub fn stat_pid(path: &[u8]) -> Option<usize> {
+    file_pid(path, b"/stat")
+}
+
+fn file_pid(path: &[u8], file: &[u8]) -> Option<usize> {
+    let pid = path.strip_prefix(b"/proc/")?.strip_suffix(file)?;
+    if pid == b"self" {
+        return Some(0);
+    }
+    if pid.is_empty() || !pid.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    core::str::from_utf8(pid)
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|&pid| pid != 0)
+}
+
+/// Render `name` as Linux's `/proc/<pid>/comm` does, newline-terminated.
+pub fn comm(name: &ProcessName, buf: &mut [u8; COMM_MAX_LEN]) -> usize {
+    let len = name.as_bytes().len();
+    buf[..len].copy_from_slice(name.as_bytes());
+    buf[len] = b'\n';
+    len + 1
+}
+
+/// Render `info` as the first seventeen fields of Linux's
+/// `/proc/<pid>/stat`, up to the CPU times in [`time::USER_HZ`] ticks.
+/// There are no process groups, sessions or terminals, and page faults are
+/// not counted, so those fields are zero (or -1 for the terminal's group).
+pub fn stat(info: &ProcessInfo, buf: &mut [u8; STAT_MAX_LEN]) -> usize {
+    let state = match info.state {
+        TaskState::Running | TaskState::Ready => 'R',
+        TaskState::Blocked => 'S',
+    };
+    let ticks = time::clock_ticks;
+    let mut cursor = Cursor { buf, len: 0 };
+    write!(cursor, "{} (", info.pid).expect("stat fits its buffer");
+    let name = info.name.as_bytes();
+    cursor.buf[cursor.len..cursor.len + name.len()].copy_from_slice(name);
+    cursor.len += name.len();
+    writeln!(
+        cursor,
+        ") {state} {} 0 0 0 -1 0 0 0 0 0 {} {} {} {}",
+        info.ppid,
+        ticks(info.cpu.user),
+        ticks(info.cpu.system),
+        ticks(info.children_cpu.user),
+        ticks(info.children_cpu.system),
+    )
+    .expect("stat fits its buffer");
+    cursor.len
+}
+
+struct Cursor<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl Write for Cursor<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use super::*;
+    use crate::process::CpuTime;
+
+    #[test]
+    fn statm_reports_pages() {
+        let usage = MemoryUsage {
+            mapped_bytes: 10 * USER_PAGE_SIZE,
+            resident_bytes: 3 * USER_PAGE_SIZE,
+        };
+        let mut buf = [0; STATM_MAX_LEN];
+        let len = statm(usage, &mut buf);
+        assert_eq!(&buf[..len], b"10 3 0 0 0 10 0\n");
+
+        let usage = MemoryUsage {
+            mapped_bytes: usize::MAX,
+            resident_bytes: usize::MAX,
+        };
+        assert!(statm(usage, &mut buf) <= STATM_MAX_LEN);
+    }
+
+    #[test]
+    fn comm_paths_name_a_pid_or_self() {
+        assert_eq!(comm_pid(b"/proc/self/comm"), Some(0));
+        assert_eq!(comm_pid(b"/proc/42/comm"), Some(42));
+        for path in [
+            &b"/proc/0/comm"[..],
+            b"/proc/+4/comm",
+            b"/proc//comm",
+            b"/proc/self/statm",
+            b"/proc/4/comm/x",
+        ] {
+            assert_eq!(comm_pid(path), None);
+        }
+
+        let mut buf = [0; COMM_MAX_LEN];
+        let len = comm(&ProcessName::new(b"a-much-too-long-name"), &mut buf);
+        assert_eq!(&buf[..len], b"a-much-too-long\n");
+    }
+
+    #[test]
+    fn stat_reports_cpu_time_in_clock_ticks() {
+        assert_eq!(stat_pid(b"/proc/self/stat"), Some(0));
+        assert_eq!(stat_pid(b"/proc/7/stat"), Some(7));
+        assert_eq!(stat_pid(b"/proc/7/statm"), None);
+
+        let mut info = ProcessInfo {
+            pid: 7,
+            ppid: 1,
+            state: TaskState::Blocked,
+            name: ProcessName::new(b"sleepy"),
+            cpu: CpuTime {
+                user: Duration::from_millis(1500),
+                system: Duration::from_millis(20),
+            },
+            children_cpu: CpuTime {
+                user: Duration::from_millis(9),
+                system: Duration::from_secs(2),
+            },
+        };
+        let mut buf = [0; STAT_MAX_LEN];
+        let len = stat(&info, &mut buf);
+        assert_eq!(
+            &buf[..len],
+            b"7 (sleepy) S 1 0 0 0 -1 0 0 0 0 0 150 2 0 200\n"
+        );
+
+        info.pid = usize::MAX;
+        info.ppid = usize::MAX;
+        info.name = ProcessName::new(b"a-much-too-long-name");
+        let forever = Duration::MAX;
+        info.cpu = CpuTime {
+            user: forever,
+            system: forever,
+        };
+        info.children_cpu = info.cpu;
+        assert!(stat(&info, &mut buf) <= STAT_MAX_LEN);
+    }
+}
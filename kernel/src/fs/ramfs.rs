@@ -0,0 +1,713 @@
+use alloc::vec::Vec;
+
+use super::errors::{FsError, Result};
+use super::path::Path;
+use crate::credentials::Credentials;
+use crate::memory::{
+    address::{DirectMap, PhysicalAddr},
+    alloc::kmalloc::KernelAllocator,
+    constants::PAGE_SIZE,
+};
+
+pub const MAX_INODES: usize = 128;
+pub const NAME_MAX: usize = 255;
+pub const ROOT_INO: usize = 0;
+
+// Parent of an inode that was removed from the tree while still open.
+const DETACHED: usize = usize::MAX;
+
+pub const MAX_FILE_SIZE: usize = 16 * PAGE_SIZE;
+// File data is kept in blocks from the kernel allocator, so a small file
+// does not hold a whole allocator page.
+pub const BLOCK_SIZE: usize = 0x1000;
+pub const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InodeKind {
+    Directory,
+    File,
+}
+
+struct Inode {
+    kind: InodeKind,
+    parent: usize,
+    name: [u8; NAME_MAX],
+    name_len: u8,
+    mode: u32,
+    owner: Credentials,
+    open_count: usize,
+    size: usize,
+    // Data blocks are allocated on first write; holes, and anything past
+    // the last block, read back as zeros.
+    blocks: Vec<Option<PhysicalAddr>>,
+}
+
+impl Inode {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len as usize]
+    }
+
+    fn set_name(&mut self, parent: usize, name: &[u8]) {
+        self.parent = parent;
+        self.name[..name.len()].copy_from_slice(name);
+        self.name_len = name.len() as u8;
+    }
+
+    fn release_blocks_from<DM: DirectMap>(
+        &mut self,
+        first: usize,
+        kalloc: &KernelAllocator<'_, DM>,
+    ) -> Result<()> {
+        while self.blocks.len() > first {
+            if let Some(addr) = self.blocks.pop().flatten() {
+                kalloc.free(addr, BLOCK_SIZE).map_err(|_| FsError::Io)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// In-memory filesystem backed by a fixed inode table. Every inode records
+/// its parent and name, so directories hold no entry lists of their own and
+/// there are no hard links.
+pub struct RamFs {
+    inodes: [Option<Inode>; MAX_INODES],
+}
+
+impl RamFs {
+    pub const fn new() -> Self {
+        let mut inodes = [const { None }; MAX_INODES];
+        let root = Some(Inode {
+            kind: InodeKind::Directory,
+            parent: ROOT_INO,
+            name: [0; NAME_MAX],
+            name_len: 0,
+            mode: 0o755,
+            owner: Credentials { uid: 0, gid: 0 },
+            open_count: 0,
+            size: 0,
+            blocks: Vec::new(),
+        });
+        // The slot starts out empty, so there is nothing to drop, which a
+        // const fn could not do.
+        core::mem::forget(core::mem::replace(&mut inodes[ROOT_INO], root));
+        Self { inodes }
+    }
+
+    pub fn lookup(&self, path: &Path) -> Result<usize> {
+        self.walk(path.components())
+    }
+
+    pub fn kind(&self, ino: usize) -> InodeKind {
+        self.inode(ino).kind
+    }
+
+    pub fn mode(&self, ino: usize) -> u32 {
+        self.inode(ino).mode
+    }
+
+    pub fn size(&self, ino: usize) -> usize {
+        self.inode(ino).size
+    }
+
+    pub fn free_inodes(&self) -> usize {
+        self.inodes.iter().filter(|inode| inode.is_none()).count()
+    }
+
+    /// Check `who` for the permissions in `mask` (4 = read, 2 = write,
+    /// 1 = execute). Root passes everything except executing a file that has
+    /// no execute bit at all.
+    pub fn access(&self, ino: usize, mask: u32, who: Credentials) -> Result<()> {
+        let inode = self.inode(ino);
+        let granted = if who.uid == 0 {
+            let any_exec = inode.kind == InodeKind::Directory || inode.mode & 0o111 != 0;
+            if any_exec { 0o7 } else { 0o6 }
+        } else if who.uid == inode.owner.uid {
+            inode.mode >> 6
+        } else if who.gid == inode.owner.gid {
+            inode.mode >> 3
+        } else {
+            inode.mode
+        };
+
+        if mask & !granted & 0o7 != 0 {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    pub fn mkdir(&mut self, path: &Path, mode: u32, owner: Credentials) -> Result<usize> {
+        self.create(path, InodeKind::Directory, mode, owner)
+    }
+
+    pub fn create(
+        &mut self,
+        path: &Path,
+        kind: InodeKind,
+        mode: u32,
+        owner: Credentials,
+    ) -> Result<usize> {
+        let (parent, name) = self.lookup_parent(path)?;
+        if self.child(parent, name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        let ino = self
+            .inodes
+            .iter()
+            .position(Option::is_none)
+            .ok_or(FsError::NoSpace)?;
+
+        let mut inode = Inode {
+            kind,
+            parent,
+            name: [0; NAME_MAX],
+            name_len: 0,
+            mode: mode & 0o7777,
+            owner,
+            open_count: 0,
+            size: 0,
+            blocks: Vec::new(),
+        };
+        inode.set_name(parent, name);
+        self.inodes[ino] = Some(inode);
+        Ok(ino)
+    }
+
+    pub fn rmdir<DM: DirectMap>(
+        &mut self,
+        path: &Path,
+        kalloc: &KernelAllocator<'_, DM>,
+    ) -> Result<()> {
+        let ino = self.lookup(path)?;
+        if ino == ROOT_INO {
+            return Err(FsError::Busy);
+        }
+        if self.kind(ino) != InodeKind::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        if self.has_children(ino) {
+            return Err(FsError::NotEmpty);
+        }
+        self.remove(ino, kalloc)
+    }
+
+    pub fn unlink<DM: DirectMap>(
+        &mut self,
+        path: &Path,
+        kalloc: &KernelAllocator<'_, DM>,
+    ) -> Result<()> {
+        let ino = self.lookup(path)?;
+        if self.kind(ino) == InodeKind::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        self.remove(ino, kalloc)
+    }
+
+    /// Resize a file. Growing only moves the end of file; blocks past the new
+    /// end are freed when shrinking.
+    pub fn truncate<DM: DirectMap>(
+        &mut self,
+        ino: usize,
+        len: usize,
+        kalloc: &KernelAllocator<'_, DM>,
+    ) -> Result<()> {
+        if self.kind(ino) == InodeKind::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        if len > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge { max: MAX_FILE_SIZE });
+        }
+
+        let inode = self.inodes[ino].as_mut().expect("inode must exist");
+        if len < inode.size {
+            inode.release_blocks_from(len.div_ceil(BLOCK_SIZE), kalloc)?;
+
+            // Clear what is left of the last block so regrowth reads zeros.
+            let tail = len % BLOCK_SIZE;
+            if tail != 0
+                && let Some(&Some(addr)) = inode.blocks.get(len / BLOCK_SIZE)
+            {
+                let block = addr.to_virtual(kalloc.direct_map()).as_ptr::<u8>();
+                unsafe { core::ptr::write_bytes(block.add(tail), 0, BLOCK_SIZE - tail) };
+            }
+        }
+        inode.size = len;
+        Ok(())
+    }
+
+    /// Copy file contents at `offset` into `buf`, returning the number of
+    /// bytes read. Reads stop at end of file.
+    pub fn read(
+        &self,
+        ino: usize,
+        offset: usize,
+        buf: &mut [u8],
+        dm: &impl DirectMap,
+    ) -> Result<usize> {
+        let inode = self.inode(ino);
+        if inode.kind == InodeKind::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        let len = buf.len().min(inode.size.saturating_sub(offset));
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let within = pos % BLOCK_SIZE;
+            let chunk = (BLOCK_SIZE - within).min(len - done);
+            let out = &mut buf[done..done + chunk];
+            match inode.blocks.get(pos / BLOCK_SIZE).copied().flatten() {
+                Some(addr) => {
+                    let block = addr.to_virtual(dm).as_ptr::<u8>();
+                    let src = unsafe { core::slice::from_raw_parts(block.add(within), chunk) };
+                    out.copy_from_slice(src);
+                }
+                None => out.fill(0),
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// Store `data` at `offset`, allocating data blocks as they are touched.
+    /// Writes that would cross `MAX_FILE_SIZE` are cut short.
+    pub fn write<DM: DirectMap>(
+        &mut self,
+        ino: usize,
+        offset: usize,
+        data: &[u8],
+        kalloc: &KernelAllocator<'_, DM>,
+    ) -> Result<usize> {
+        if self.kind(ino) == InodeKind::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if offset >= MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge { max: MAX_FILE_SIZE });
+        }
+
+        let inode = self.inodes[ino].as_mut().expect("inode must exist");
+        let len = data.len().min(MAX_FILE_SIZE - offset);
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let within = pos % BLOCK_SIZE;
+            let chunk = (BLOCK_SIZE - within).min(len - done);
+            let index = pos / BLOCK_SIZE;
+            if index >= inode.blocks.len() {
+                inode
+                    .blocks
+                    .try_reserve(index + 1 - inode.blocks.len())
+                    .map_err(|_| FsError::NoSpace)?;
+                inode.blocks.resize(index + 1, None);
+            }
+            let addr = match inode.blocks[index] {
+                Some(addr) => addr,
+                None => {
+                    let addr = kalloc.calloc(BLOCK_SIZE).map_err(|_| FsError::NoSpace)?;
+                    inode.blocks[index] = Some(addr);
+                    addr
+                }
+            };
+            let block = addr.to_virtual(kalloc.direct_map()).as_ptr::<u8>();
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[done..].as_ptr(), block.add(within), chunk);
+            }
+            done += chunk;
+            inode.size = inode.size.max(pos + chunk);
+        }
+        Ok(len)
+    }
+
+    /// Pin `ino` for an open file description. A pinned inode that is
+    /// unlinked keeps its data until the last `release`.
+    pub fn open(&mut self, ino: usize) {
+        self.inodes[ino]
+            .as_mut()
+            .expect("inode must exist")
+            .open_count += 1;
+    }
+
+    pub fn release<DM: DirectMap>(
+        &mut self,
+        ino: usize,
+        kalloc: &KernelAllocator<'_, DM>,
+    ) -> Result<()> {
+        let inode = self.inodes[ino].as_mut().expect("inode must exist");
+        inode.open_count -= 1;
+        if inode.open_count == 0 && inode.parent == DETACHED {
+            return self.remove(ino, kalloc);
+        }
+        Ok(())
+    }
+
+    /// Free up to `blocks` data blocks that hold nothing but zeros. They read
+    /// back the same as holes, so no file changes. Returns the number freed.
+    pub fn trim<DM: DirectMap>(
+        &mut self,
+        blocks: usize,
+        kalloc: &KernelAllocator<'_, DM>,
+    ) -> usize {
+        let dm = kalloc.direct_map();
+        let mut freed = 0;
+        let slots = self
+            .inodes
+            .iter_mut()
+            .flatten()
+            .flat_map(|inode| &mut inode.blocks);
+        for slot in slots {
+            if freed == blocks {
+                break;
+            }
+            let Some(addr) = *slot else {
+                continue;
+            };
+            let words = addr.to_virtual(dm).as_ptr::<u64>();
+            let words = unsafe { core::slice::from_raw_parts(words, BLOCK_SIZE / 8) };
+            if words.iter().all(|&word| word == 0) && kalloc.free(addr, BLOCK_SIZE).is_ok() {
+                *slot = None;
+                freed += 1;
+            }
+        }
+        freed
+    }
+
+    /// Absolute path of `ino`, rebuilt from the parent links.
+    pub fn path_of(&self, ino: usize) -> Result<Path> {
+        let mut chain = [ROOT_INO; MAX_INODES];
+        let mut depth = 0;
+        let mut cur = ino;
+        while cur != ROOT_INO {
+            let inode = self.inode(cur);
+            if inode.parent == DETACHED {
+                return Err(FsError::NotFound);
+            }
+            chain[depth] = cur;
+            depth += 1;
+            cur = inode.parent;
+        }
+
+        let mut path = Path::root();
+        for &ino in chain[..depth].iter().rev() {
+            path = path.resolve(self.inode(ino).name())?;
+        }
+        Ok(path)
+    }
+
+    /// Move `from` to `to`, replacing a file with a file or a directory with
+    /// an empty directory.
+    pub fn rename<DM: DirectMap>(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        kalloc: &KernelAllocator<'_, DM>,
+    ) -> Result<()> {
+        let src = self.lookup(from)?;
+        if src == ROOT_INO {
+            return Err(FsError::Busy);
+        }
+        let (parent, name) = self.lookup_parent(to)?;
+        if self.is_within(parent, src) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        if let Some(dst) = self.child(parent, name) {
+            if dst == src {
+                return Ok(());
+            }
+            match (self.kind(src), self.kind(dst)) {
+                (InodeKind::Directory, InodeKind::File) => return Err(FsError::NotDirectory),
+                (InodeKind::File, InodeKind::Directory) => return Err(FsError::IsDirectory),
+                (InodeKind::Directory, InodeKind::Directory) if self.has_children(dst) => {
+                    return Err(FsError::NotEmpty);
+                }
+                _ => {}
+            }
+            self.remove(dst, kalloc)?;
+        }
+
+        self.inodes[src]
+            .as_mut()
+            .expect("looked-up inode must exist")
+            .set_name(parent, name);
+        Ok(())
+    }
+
+    // The inode goes away even if freeing its data fails; the error is
+    // passed on.
+    fn remove<DM: DirectMap>(
+        &mut self,
+        ino: usize,
+        kalloc: &KernelAllocator<'_, DM>,
+    ) -> Result<()> {
+        let Some(inode) = self.inodes[ino].as_mut() else {
+            return Ok(());
+        };
+        if inode.open_count > 0 {
+            inode.parent = DETACHED;
+            return Ok(());
+        }
+        let released = inode.release_blocks_from(0, kalloc);
+        self.inodes[ino] = None;
+        released
+    }
+
+    fn inode(&self, ino: usize) -> &Inode {
+        self.inodes[ino].as_ref().expect("inode must exist")
+    }
+
+    fn walk<'p>(&self, components: impl Iterator<Item = &'p [u8]>) -> Result<usize> {
+        let mut ino = ROOT_INO;
+        for name in components {
+            if self.kind(ino) != InodeKind::Directory {
+                return Err(FsError::NotDirectory);
+            }
+            ino = self.child(ino, name).ok_or(FsError::NotFound)?;
+        }
+        Ok(ino)
+    }
+
+    // Resolve the directory that holds the last component of `path`.
+    fn lookup_parent<'p>(&self, path: &'p Path) -> Result<(usize, &'p [u8])> {
+        let depth = path.components().count();
+        let name = path.components().last().ok_or(FsError::AlreadyExists)?;
+        if name.len() > NAME_MAX {
+            return Err(FsError::NameTooLong { max: NAME_MAX });
+        }
+
+        let parent = self.walk(path.components().take(depth - 1))?;
+        if self.kind(parent) != InodeKind::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        Ok((parent, name))
+    }
+
+    fn child(&self, dir: usize, name: &[u8]) -> Option<usize> {
+        self.inodes.iter().enumerate().position(|(ino, inode)| {
+            ino != ROOT_INO
+                && inode
+                    .as_ref()
+                    .is_some_and(|inode| inode.parent == dir && inode.name() == name)
+        })
+    }
+
+    fn has_children(&self, dir: usize) -> bool {
+        self.inodes.iter().enumerate().any(|(ino, inode)| {
+            ino != ROOT_INO && inode.as_ref().is_some_and(|inode| inode.parent == dir)
+        })
+    }
+
+    // Whether `ino` is `ancestor` or lies somewhere below it.
+    fn is_within(&self, mut ino: usize, ancestor: usize) -> bool {
+        loop {
+            if ino == ancestor {
+                return true;
+            }
+            if ino == ROOT_INO {
+                return false;
+            }
+            ino = self.inode(ino).parent;
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{address::KernelDirectMap, alloc::palloc::PageAllocator};
+
+    const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+
+    fn path(p: &str) -> Path {
+        Path::root().resolve(p.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn mkdir_and_rmdir_follow_posix_errors() {
+        let palloc = PageAllocator::new();
+        let kalloc = KernelAllocator::new(&KernelDirectMap, &palloc);
+        let mut fs = RamFs::new();
+        fs.mkdir(&path("/tmp"), 0o1777, ROOT).unwrap();
+        fs.mkdir(&path("/tmp/a"), 0o755, ROOT).unwrap();
+
+        assert_eq!(fs.mode(fs.lookup(&path("/tmp")).unwrap()), 0o1777);
+        assert_eq!(
+            fs.mkdir(&path("/tmp"), 0o755, ROOT),
+            Err(FsError::AlreadyExists)
+        );
+        assert_eq!(
+            fs.mkdir(&path("/"), 0o755, ROOT),
+            Err(FsError::AlreadyExists)
+        );
+        assert_eq!(fs.mkdir(&path("/x/y"), 0o755, ROOT), Err(FsError::NotFound));
+        assert_eq!(fs.rmdir(&path("/tmp"), &kalloc), Err(FsError::NotEmpty));
+        assert_eq!(fs.rmdir(&path("/"), &kalloc), Err(FsError::Busy));
+
+        fs.rmdir(&path("/tmp/a"), &kalloc).unwrap();
+        fs.rmdir(&path("/tmp"), &kalloc).unwrap();
+        assert_eq!(fs.lookup(&path("/tmp")), Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn unlink_only_removes_files() {
+        let palloc = PageAllocator::new();
+        let kalloc = KernelAllocator::new(&KernelDirectMap, &palloc);
+        let mut fs = RamFs::new();
+        fs.mkdir(&path("/d"), 0o755, ROOT).unwrap();
+        fs.create(&path("/d/f"), InodeKind::File, 0o644, ROOT)
+            .unwrap();
+
+        assert_eq!(fs.unlink(&path("/d"), &kalloc), Err(FsError::IsDirectory));
+        assert_eq!(fs.rmdir(&path("/d/f"), &kalloc), Err(FsError::NotDirectory));
+        assert_eq!(
+            fs.mkdir(&path("/d/f/g"), 0o755, ROOT),
+            Err(FsError::NotDirectory)
+        );
+        fs.unlink(&path("/d/f"), &kalloc).unwrap();
+        assert_eq!(fs.unlink(&path("/d/f"), &kalloc), Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn rename_moves_and_replaces_entries() {
+        let palloc = PageAllocator::new();
+        let kalloc = KernelAllocator::new(&KernelDirectMap, &palloc);
+        let mut fs = RamFs::new();
+        fs.mkdir(&path("/a"), 0o755, ROOT).unwrap();
+        fs.mkdir(&path("/a/sub"), 0o755, ROOT).unwrap();
+        fs.mkdir(&path("/b"), 0o755, ROOT).unwrap();
+        let file = fs
+            .create(&path("/a/f"), InodeKind::File, 0o644, ROOT)
+            .unwrap();
+        fs.create(&path("/b/g"), InodeKind::File, 0o644, ROOT)
+            .unwrap();
+
+        fs.rename(&path("/a/f"), &path("/b/g"), &kalloc).unwrap();
+        assert_eq!(fs.lookup(&path("/b/g")), Ok(file));
+        assert_eq!(fs.lookup(&path("/a/f")), Err(FsError::NotFound));
+
+        assert_eq!(
+            fs.rename(&path("/a"), &path("/a/sub/x"), &kalloc),
+            Err(FsError::InvalidArgument)
+        );
+        assert_eq!(
+            fs.rename(&path("/a"), &path("/b"), &kalloc),
+            Err(FsError::NotEmpty)
+        );
+        assert_eq!(
+            fs.rename(&path("/a"), &path("/b/g"), &kalloc),
+            Err(FsError::NotDirectory)
+        );
+        assert_eq!(
+            fs.rename(&path("/b/g"), &path("/a"), &kalloc),
+            Err(FsError::IsDirectory)
+        );
+
+        fs.rename(&path("/a"), &path("/b/a"), &kalloc).unwrap();
+        assert!(fs.lookup(&path("/b/a/sub")).is_ok());
+    }
+
+    #[test]
+    fn inode_table_exhaustion_reports_no_space() {
+        let mut fs = RamFs::new();
+        for i in 1..MAX_INODES {
+            fs.create(&path(&format!("/f{i}")), InodeKind::File, 0o644, ROOT)
+                .unwrap();
+        }
+        assert_eq!(fs.free_inodes(), 0);
+        assert_eq!(
+            fs.create(&path("/full"), InodeKind::File, 0o644, ROOT),
+            Err(FsError::NoSpace)
+        );
+    }
+
+    #[test]
+    fn truncate_grows_sparsely_and_rejects_directories() {
+        let palloc = PageAllocator::new();
+        let kalloc = KernelAllocator::new(&KernelDirectMap, &palloc);
+        let used = palloc.get_stats().used_pages;
+        let mut fs = RamFs::new();
+        let file = fs
+            .create(&path("/f"), InodeKind::File, 0o644, ROOT)
+            .unwrap();
+
+        fs.truncate(file, 3 * PAGE_SIZE + 1, &kalloc).unwrap();
+        assert_eq!(fs.size(file), 3 * PAGE_SIZE + 1);
+        assert_eq!(palloc.get_stats().used_pages, used);
+
+        fs.truncate(file, 10, &kalloc).unwrap();
+        assert_eq!(fs.size(file), 10);
+        assert_eq!(
+            fs.truncate(file, MAX_FILE_SIZE + 1, &kalloc),
+            Err(FsError::FileTooLarge { max: MAX_FILE_SIZE })
+        );
+        assert_eq!(fs.truncate(ROOT_INO, 0, &kalloc), Err(FsError::IsDirectory));
+    }
+
+    #[test]
+    fn access_checks_owner_group_and_other_bits() {
+        let user = Credentials {
+            uid: 1000,
+            gid: 100,
+        };
+        let mut fs = RamFs::new();
+        let file = fs
+            .create(&path("/f"), InodeKind::File, 0o640, user)
+            .unwrap();
+
+        assert_eq!(fs.access(file, 0o6, user), Ok(()));
+        assert_eq!(fs.access(file, 0o1, user), Err(FsError::PermissionDenied));
+        let group = Credentials {
+            uid: 1001,
+            gid: 100,
+        };
+        assert_eq!(fs.access(file, 0o4, group), Ok(()));
+        assert_eq!(fs.access(file, 0o2, group), Err(FsError::PermissionDenied));
+        let other = Credentials {
+            uid: 1002,
+            gid: 101,
+        };
+        assert_eq!(fs.access(file, 0o4, other), Err(FsError::PermissionDenied));
+        assert_eq!(fs.access(file, 0, other), Ok(()));
+
+        assert_eq!(fs.access(file, 0o6, ROOT), Ok(()));
+        assert_eq!(fs.access(file, 0o1, ROOT), Err(FsError::PermissionDenied));
+        assert_eq!(fs.access(ROOT_INO, 0o7, ROOT), Ok(()));
+    }
+
+    #[test]
+    fn unlinked_open_inode_survives_until_release() {
+        let palloc = PageAllocator::new();
+        let kalloc = KernelAllocator::new(&KernelDirectMap, &palloc);
+        let mut fs = RamFs::new();
+        fs.mkdir(&path("/d"), 0o755, ROOT).unwrap();
+        let file = fs
+            .create(&path("/d/f"), InodeKind::File, 0o644, ROOT)
+            .unwrap();
+        assert_eq!(fs.path_of(file).unwrap(), path("/d/f"));
+
+        fs.open(file);
+        fs.unlink(&path("/d/f"), &kalloc).unwrap();
+        assert_eq!(fs.lookup(&path("/d/f")), Err(FsError::NotFound));
+        assert_eq!(fs.path_of(file), Err(FsError::NotFound));
+        assert_eq!(fs.size(file), 0);
+        fs.rmdir(&path("/d"), &kalloc).unwrap();
+        let other = fs
+            .create(&path("/g"), InodeKind::File, 0o644, ROOT)
+            .unwrap();
+        assert_ne!(other, file);
+
+        fs.release(file, &kalloc).unwrap();
+        let reused = fs
+            .create(&path("/h"), InodeKind::File, 0o644, ROOT)
+            .unwrap();
+        assert_eq!(reused, file);
+    }
+}
@@ -0,0 +1,258 @@
+use core::time::Duration;
+
+use spin::Mutex;
+
+use super::Readiness;
+use super::errors::{FsError, Result};
+
+pub const MAX_TIMERS: usize = 16;
+
+static TIMERS: Mutex<TimerTable> = Mutex::new(TimerTable::new());
+
+/// When a timer fires next and how often it repeats; a zero `interval` makes
+/// it one-shot and `None` means disarmed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimerSetting {
+    pub value: Option<Duration>,
+    pub interval: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Timer {
+    deadline: Option<Duration>,
+    interval: Duration,
+    expirations: u64,
+    // What the timer's clock reads when the monotonic clock reads zero, to
+    // take absolute deadlines from.
+    epoch: Duration,
+}
+
+impl Timer {
+    // Fold every deadline that has passed by `now` into the expiration count.
+    // Returns whether any had.
+    fn advance(&mut self, now: Duration) -> bool {
+        let Some(deadline) = self.deadline.filter(|&deadline| now >= deadline) else {
+            return false;
+        };
+        if self.interval.is_zero() {
+            self.expirations += 1;
+            self.deadline = None;
+            return true;
+        }
+        let elapsed = (now - deadline).as_nanos();
+        let period = self.interval.as_nanos();
+        let missed = u64::try_from(elapsed / period).unwrap_or(u64::MAX);
+        self.expirations = self.expirations.saturating_add(missed).saturating_add(1);
+        self.deadline = Some(now + Duration::from_nanos((period - elapsed % period) as u64));
+        true
+    }
+
+    fn setting(&self, now: Duration) -> TimerSetting {
+        TimerSetting {
+            value: self.deadline.map(|deadline| deadline.saturating_sub(now)),
+            interval: self.interval,
+        }
+    }
+}
+
+/// Timers are driven by the monotonic clock. Callers pass the current time
+/// in, so expiry is only observed when a timer is read, polled or queried.
+/// A timer on another clock takes absolute deadlines on that clock, which
+/// are moved to the monotonic one by the clock's epoch.
+pub struct TimerTable {
+    timers: [Option<Timer>; MAX_TIMERS],
+}
+
+impl TimerTable {
+    pub const fn new() -> Self {
+        Self {
+            timers: [None; MAX_TIMERS],
+        }
+    }
+
+    /// Add a disarmed timer on a clock that reads `epoch` when the monotonic
+    /// clock reads zero.
+    pub fn create(&mut self, epoch: Duration) -> Result<usize> {
+        let id = self
+            .timers
+            .iter()
+            .position(Option::is_none)
+            .ok_or(FsError::TooManyFiles)?;
+        self.timers[id] = Some(Timer {
+            epoch,
+            ..Timer::default()
+        });
+        Ok(id)
+    }
+
+    pub fn destroy(&mut self, id: usize) {
+        self.timers[id] = None;
+    }
+
+    /// Arm or disarm the timer, returning its previous setting. `value` is
+    /// relative to `now` unless `absolute` is set. Pending expirations are
+    /// discarded.
+    pub fn set(
+        &mut self,
+        id: usize,
+        new: TimerSetting,
+        absolute: bool,
+        now: Duration,
+    ) -> Result<TimerSetting> {
+        let timer = self.timer(id, now)?;
+        let old = timer.setting(now);
+        *timer = Timer {
+            deadline: new.value.map(|value| {
+                if absolute {
+                    value.saturating_sub(timer.epoch)
+                } else {
+                    now + value
+                }
+            }),
+            interval: new.interval,
+            expirations: 0,
+            epoch: timer.epoch,
+        };
+        Ok(old)
+    }
+
+    pub fn get(&mut self, id: usize, now: Duration) -> Result<TimerSetting> {
+        Ok(self.timer(id, now)?.setting(now))
+    }
+
+    /// Consume the number of expirations since the last read.
+    pub fn take(&mut self, id: usize, now: Duration) -> Result<u64> {
+        let timer = self.timer(id, now)?;
+        match core::mem::take(&mut timer.expirations) {
+            0 => Err(FsError::WouldBlock),
+            expirations => Ok(expirations),
+        }
+    }
+
+    /// Bring every timer up to `now`, returning whether any fired.
+    pub fn advance_all(&mut self, now: Duration) -> bool {
+        let mut fired = false;
+        for timer in self.timers.iter_mut().flatten() {
+            fired |= timer.advance(now);
+        }
+        fired
+    }
+
+    pub fn readiness(&mut self, id: usize, now: Duration) -> Readiness {
+        Readiness {
+            readable: self.timer(id, now).is_ok_and(|timer| timer.expirations > 0),
+            writable: false,
+        }
+    }
+
+    fn timer(&mut self, id: usize, now: Duration) -> Result<&mut Timer> {
+        let timer = self
+            .timers
+            .get_mut(id)
+            .and_then(Option::as_mut)
+            .ok_or(FsError::BadDescriptor)?;
+        timer.advance(now);
+        Ok(timer)
+    }
+}
+
+impl Default for TimerTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `f` on the shared table of timers.
+pub fn with_timers<T>(f: impl FnOnce(&mut TimerTable) -> T) -> T {
+    f(&mut TIMERS.lock())
+}
+
+/// [`TimerTable::advance_all`] on the shared table, for the timer tick.
+/// Reports nothing fired when the tick interrupted a holder of the table,
+/// which sees the expirations itself.
+pub fn fire_due(now: Duration) -> bool {
+    TIMERS
+        .try_lock()
+        .is_some_and(|mut timers| timers.advance_all(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn one_shot_timer_fires_once() {
+        let mut table = TimerTable::new();
+        let id = table.create(Duration::ZERO).unwrap();
+        let setting = TimerSetting {
+            value: Some(ms(10)),
+            interval: Duration::ZERO,
+        };
+
+        assert_eq!(
+            table.set(id, setting, false, ms(100)),
+            Ok(TimerSetting::default())
+        );
+        assert_eq!(table.take(id, ms(105)), Err(FsError::WouldBlock));
+        assert_eq!(table.get(id, ms(105)).unwrap().value, Some(ms(5)));
+        assert!(table.readiness(id, ms(110)).readable);
+        assert_eq!(table.take(id, ms(200)), Ok(1));
+        assert_eq!(table.get(id, ms(200)), Ok(TimerSetting::default()));
+    }
+
+    #[test]
+    fn periodic_timer_counts_missed_intervals() {
+        let mut table = TimerTable::new();
+        let id = table.create(Duration::ZERO).unwrap();
+        let setting = TimerSetting {
+            value: Some(ms(50)),
+            interval: ms(20),
+        };
+
+        table.set(id, setting, true, ms(0)).unwrap();
+        assert_eq!(table.take(id, ms(95)), Ok(3));
+        assert_eq!(table.get(id, ms(95)).unwrap().value, Some(ms(15)));
+
+        let old = table
+            .set(id, TimerSetting::default(), false, ms(100))
+            .unwrap();
+        assert_eq!(old.interval, ms(20));
+        assert_eq!(table.take(id, ms(500)), Err(FsError::WouldBlock));
+    }
+
+    #[test]
+    fn absolute_deadlines_are_taken_on_the_timers_clock() {
+        let mut table = TimerTable::new();
+        let id = table.create(ms(1_000_000)).unwrap();
+        let setting = TimerSetting {
+            value: Some(ms(1_000_050)),
+            interval: Duration::ZERO,
+        };
+
+        table.set(id, setting, true, ms(10)).unwrap();
+        assert_eq!(table.get(id, ms(10)).unwrap().value, Some(ms(40)));
+        assert_eq!(table.take(id, ms(50)), Ok(1));
+    }
+
+    #[test]
+    fn advancing_all_timers_reports_only_new_expirations() {
+        let mut table = TimerTable::new();
+        let id = table.create(Duration::ZERO).unwrap();
+        table.create(Duration::ZERO).unwrap();
+        let setting = TimerSetting {
+            value: Some(ms(10)),
+            interval: Duration::ZERO,
+        };
+
+        table.set(id, setting, false, ms(0)).unwrap();
+        assert!(!table.advance_all(ms(5)));
+        assert!(table.advance_all(ms(10)));
+        // Already counted, so the next tick has nothing to report.
+        assert!(!table.advance_all(ms(20)));
+        assert_eq!(table.take(id, ms(20)), Ok(1));
+    }
+}
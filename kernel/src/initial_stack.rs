@@ -0,0 +1,210 @@
+use thiserror::Error as ThisError;
+
+use crate::memory::vmm::USER_PAGE_SIZE;
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+const WORD: usize = size_of::<u64>();
+const AUXV_ENTRIES: usize = 7;
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    #[error("initial stack needs {needed} bytes, only {available} available")]
+    TooLarge { needed: usize, available: usize },
+}
+
+/// What the program loader knows about the image, passed on through auxv.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub phdr: u64,
+    pub phent: u64,
+    pub phnum: u64,
+    pub entry: u64,
+    pub random: [u8; 16],
+}
+
+/// Lay out the System V x86-64 initial process stack at the top of `stack`,
+/// whose last byte sits just below the user address `stack_top`, and return
+/// the initial stack pointer.
+///
+/// From the returned address up: argc, the argv pointers, a null, the envp
+/// pointers, a null, auxv pairs ending in AT_NULL, then the AT_RANDOM bytes
+/// and the strings themselves.
+pub fn build(
+    stack: &mut [u8],
+    stack_top: usize,
+    argv: &[&[u8]],
+    envp: &[&[u8]],
+    image: &ImageInfo,
+) -> Result<usize, StackError> {
+    let strings: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * AUXV_ENTRIES;
+    // The vector starts at the 16-byte aligned stack pointer, below the
+    // aligned start of the data it points into.
+    let rsp = stack_top
+        .checked_sub(strings + image.random.len())
+        .and_then(|data_start| (data_start & !0xf).checked_sub(words * WORD))
+        .map(|rsp| rsp & !0xf);
+
+    let bottom = stack_top.saturating_sub(stack.len());
+    let rsp = match rsp {
+        Some(rsp) if rsp >= bottom => rsp,
+        _ => {
+            let needed = rsp.map_or(strings + image.random.len() + words * WORD, |rsp| {
+                stack_top - rsp
+            });
+            return Err(StackError::TooLarge {
+                needed,
+                available: stack.len(),
+            });
+        }
+    };
+    let mut writer = Writer { stack, bottom };
+
+    let mut string_addr = stack_top - strings;
+    let random_addr = string_addr - image.random.len();
+    writer.write(random_addr, &image.random);
+
+    let mut vector_addr = rsp;
+    let mut push = |writer: &mut Writer, value: u64| {
+        writer.write(vector_addr, &value.to_ne_bytes());
+        vector_addr += WORD;
+    };
+    push(&mut writer, argv.len() as u64);
+    for list in [argv, envp] {
+        for string in list {
+            writer.write(string_addr, string);
+            writer.write(string_addr + string.len(), &[0]);
+            push(&mut writer, string_addr as u64);
+            string_addr += string.len() + 1;
+        }
+        push(&mut writer, 0);
+    }
+
+    let auxv: [(u64, u64); AUXV_ENTRIES] = [
+        (AT_PHDR, image.phdr),
+        (AT_PHENT, image.phent),
+        (AT_PHNUM, image.phnum),
+        (AT_PAGESZ, USER_PAGE_SIZE as u64),
+        (AT_ENTRY, image.entry),
+        (AT_RANDOM, random_addr as u64),
+        (AT_NULL, 0),
+    ];
+    for (key, value) in auxv {
+        push(&mut writer, key);
+        push(&mut writer, value);
+    }
+
+    Ok(rsp)
+}
+
+struct Writer<'a> {
+    stack: &'a mut [u8],
+    bottom: usize,
+}
+
+impl Writer<'_> {
+    fn write(&mut self, addr: usize, bytes: &[u8]) {
+        let offset = addr - self.bottom;
+        self.stack[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOP: usize = 0x7fff_ffff_f000;
+
+    struct Reader<'a> {
+        stack: &'a [u8],
+    }
+
+    impl Reader<'_> {
+        fn word(&self, addr: usize) -> u64 {
+            let offset = addr - (TOP - self.stack.len());
+            u64::from_ne_bytes(self.stack[offset..offset + WORD].try_into().unwrap())
+        }
+
+        fn string(&self, addr: u64) -> &[u8] {
+            let offset = addr as usize - (TOP - self.stack.len());
+            let len = self.stack[offset..].iter().position(|&b| b == 0).unwrap();
+            &self.stack[offset..offset + len]
+        }
+    }
+
+    #[test]
+    fn stack_holds_arguments_environment_and_auxv() {
+        let mut stack = vec![0xaa; 4096];
+        let image = ImageInfo {
+            phdr: 0x40_0040,
+            phent: 56,
+            phnum: 9,
+            entry: 0x40_1000,
+            random: [7; 16],
+        };
+        let rsp = build(
+            &mut stack,
+            TOP,
+            &[b"/bin/true", b"-v"],
+            &[b"HOME=/"],
+            &image,
+        )
+        .unwrap();
+        assert_eq!(rsp % 16, 0);
+
+        let reader = Reader { stack: &stack };
+        assert_eq!(reader.word(rsp), 2);
+        assert_eq!(reader.string(reader.word(rsp + 8)), b"/bin/true");
+        assert_eq!(reader.string(reader.word(rsp + 16)), b"-v");
+        assert_eq!(reader.word(rsp + 24), 0);
+        assert_eq!(reader.string(reader.word(rsp + 32)), b"HOME=/");
+        assert_eq!(reader.word(rsp + 40), 0);
+
+        let mut auxv = std::collections::HashMap::new();
+        let mut addr = rsp + 48;
+        loop {
+            let (key, value) = (reader.word(addr), reader.word(addr + 8));
+            addr += 16;
+            if key == AT_NULL {
+                break;
+            }
+            auxv.insert(key, value);
+        }
+        assert_eq!(auxv[&AT_PHDR], image.phdr);
+        assert_eq!(auxv[&AT_PHNUM], 9);
+        assert_eq!(auxv[&AT_ENTRY], image.entry);
+        assert_eq!(auxv[&AT_PAGESZ], USER_PAGE_SIZE as u64);
+
+        let random = auxv[&AT_RANDOM] as usize - (TOP - stack.len());
+        assert_eq!(stack[random..random + 16], [7; 16]);
+    }
+
+    #[test]
+    fn oversized_arguments_are_rejected() {
+        let mut stack = [0; 128];
+        let arg = [b'x'; 100];
+        let err = build(&mut stack, TOP, &[&arg], &[], &ImageInfo::default());
+        assert!(matches!(
+            err,
+            Err(StackError::TooLarge { available: 128, .. })
+        ));
+    }
+
+    #[test]
+    fn arguments_larger_than_the_address_space_below_are_rejected() {
+        let mut stack = [0; 64];
+        let arg = [b'x'; 100];
+        let err = build(&mut stack, 64, &[&arg], &[], &ImageInfo::default());
+        assert!(matches!(
+            err,
+            Err(StackError::TooLarge { available: 64, .. })
+        ));
+    }
+}
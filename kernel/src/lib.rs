@@ -1,26 +1,42 @@
 #![cfg_attr(not(test), no_std)]
 
+extern crate alloc;
+
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::memory::{
     address::{DirectMap, KernelDirectMap},
-    alloc::{kmalloc::KernelAllocator, palloc::PageAllocator},
+    alloc::{kmalloc::KernelAllocator, palloc::PageAllocator, pshare::PageShares},
     pagetable::RootPageTable,
 };
 
+pub mod arch;
+pub mod backtrace;
 pub mod boot;
 pub mod console;
+pub mod credentials;
 pub mod error;
+pub mod fs;
+pub mod initial_stack;
+pub mod limits;
 pub mod memory;
+pub mod net;
 pub mod process;
+pub mod random;
 mod scheduler;
+pub mod seccomp;
+pub mod sync;
 pub mod syscall;
+pub mod time;
+pub mod virtio;
+pub mod wait;
 
 static ACTIVE_KERNEL: AtomicUsize = AtomicUsize::new(0);
 
 pub struct Kernel<'i, DM: DirectMap> {
     pub palloc: &'i PageAllocator,
     pub kalloc: &'i KernelAllocator<'i, DM>,
+    pub pshare: &'i PageShares<'i, DM>,
     pub page_table: &'i RootPageTable<'i, DM>,
     pub process: process::ProcessState<'i, DM>,
 }
@@ -29,11 +45,13 @@ impl<'i, DM: DirectMap> Kernel<'i, DM> {
     pub fn new(
         palloc: &'i PageAllocator,
         kalloc: &'i KernelAllocator<'i, DM>,
+        pshare: &'i PageShares<'i, DM>,
         page_table: &'i RootPageTable<'i, DM>,
     ) -> Self {
         Self {
             palloc,
             kalloc,
+            pshare,
             page_table,
             process: process::ProcessState::new(),
         }
@@ -46,9 +64,15 @@ pub fn set_active_kernel(kernel: &Kernel<'_, KernelDirectMap>) {
 }
 
 pub fn active_kernel<'i>() -> &'i Kernel<'i, KernelDirectMap> {
+    try_active_kernel().expect("active kernel is not initialized")
+}
+
+pub fn try_active_kernel<'i>() -> Option<&'i Kernel<'i, KernelDirectMap>> {
     let ptr = ACTIVE_KERNEL.load(Ordering::SeqCst);
-    assert!(ptr != 0, "active kernel is not initialized");
-    unsafe { &*(ptr as *const Kernel<'i, KernelDirectMap>) }
+    if ptr == 0 {
+        return None;
+    }
+    Some(unsafe { &*(ptr as *const Kernel<'i, KernelDirectMap>) })
 }
 
 #[macro_export]
@@ -0,0 +1,183 @@
+use thiserror::Error as ThisError;
+
+pub const RLIMIT_CPU: usize = 0;
+pub const RLIMIT_FSIZE: usize = 1;
+pub const RLIMIT_DATA: usize = 2;
+pub const RLIMIT_STACK: usize = 3;
+pub const RLIMIT_CORE: usize = 4;
+pub const RLIMIT_RSS: usize = 5;
+pub const RLIMIT_NPROC: usize = 6;
+pub const RLIMIT_NOFILE: usize = 7;
+pub const RLIMIT_MEMLOCK: usize = 8;
+pub const RLIMIT_AS: usize = 9;
+pub const RLIM_NLIMITS: usize = 16;
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    #[error("unknown resource {resource}")]
+    InvalidResource { resource: usize },
+
+    #[error("soft limit {cur:#x} exceeds hard limit {max:#x}")]
+    SoftAboveHard { cur: u64, max: u64 },
+
+    #[error("hard limit of resource {resource} cannot be raised to {max:#x}")]
+    HardLimitRaise { resource: usize, max: u64 },
+
+    #[error("stack limit is fixed at {size:#x} bytes")]
+    StackPinned { size: u64 },
+
+    #[error("no process with pid {pid}")]
+    NoSuchProcess { pid: usize },
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rlimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+impl Rlimit {
+    pub const INFINITE: Self = Self {
+        cur: RLIM_INFINITY,
+        max: RLIM_INFINITY,
+    };
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ResourceLimits {
+    limits: [Rlimit; RLIM_NLIMITS],
+    stack_size: u64,
+}
+
+impl ResourceLimits {
+    /// Limits for a process whose stack was allocated with `stack_size` bytes.
+    /// Stacks never grow, so the stack limit is pinned to that allocation:
+    /// there is no growth to hold a lower limit against, and no room for a
+    /// higher one.
+    pub const fn new(stack_size: u64) -> Self {
+        let mut limits = [Rlimit::INFINITE; RLIM_NLIMITS];
+        limits[RLIMIT_STACK] = Rlimit {
+            cur: stack_size,
+            max: stack_size,
+        };
+        Self { limits, stack_size }
+    }
+
+    pub fn get(&self, resource: usize) -> Result<Rlimit, LimitError> {
+        self.limits
+            .get(resource)
+            .copied()
+            .ok_or(LimitError::InvalidResource { resource })
+    }
+
+    /// Replace a limit. `privileged` callers may raise hard limits. The stack
+    /// limit only accepts the size allocated at spawn, as nothing would
+    /// enforce any other value.
+    pub fn set(
+        &mut self,
+        resource: usize,
+        new: Rlimit,
+        privileged: bool,
+    ) -> Result<(), LimitError> {
+        let old = self.get(resource)?;
+        if new.cur > new.max {
+            return Err(LimitError::SoftAboveHard {
+                cur: new.cur,
+                max: new.max,
+            });
+        }
+
+        if resource == RLIMIT_STACK && new != old {
+            return Err(LimitError::StackPinned {
+                size: self.stack_size,
+            });
+        }
+
+        if new.max > old.max && !privileged {
+            return Err(LimitError::HardLimitRaise {
+                resource,
+                max: new.max,
+            });
+        }
+
+        self.limits[resource] = new;
+        Ok(())
+    }
+
+    /// Soft address-space limit in bytes, as enforced by the VMM.
+    pub fn address_space(&self) -> usize {
+        usize::try_from(self.limits[RLIMIT_AS].cur).unwrap_or(usize::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STACK: u64 = 2 << 20;
+
+    #[test]
+    fn defaults_are_unlimited_except_stack() {
+        let limits = ResourceLimits::new(STACK);
+        assert_eq!(limits.get(RLIMIT_AS).unwrap(), Rlimit::INFINITE);
+        assert_eq!(
+            limits.get(RLIMIT_STACK).unwrap(),
+            Rlimit {
+                cur: STACK,
+                max: STACK
+            }
+        );
+        assert_eq!(
+            limits.get(RLIM_NLIMITS),
+            Err(LimitError::InvalidResource {
+                resource: RLIM_NLIMITS
+            })
+        );
+    }
+
+    #[test]
+    fn soft_limit_cannot_exceed_hard_limit() {
+        let mut limits = ResourceLimits::new(STACK);
+        let err = limits.set(RLIMIT_AS, Rlimit { cur: 2, max: 1 }, true);
+        assert_eq!(err, Err(LimitError::SoftAboveHard { cur: 2, max: 1 }));
+    }
+
+    #[test]
+    fn hard_limits_only_rise_with_privilege_and_never_past_stack() {
+        let mut limits = ResourceLimits::new(STACK);
+        let lowered = Rlimit {
+            cur: 4096,
+            max: 8192,
+        };
+        limits.set(RLIMIT_AS, lowered, false).unwrap();
+        assert_eq!(limits.address_space(), 4096);
+
+        assert!(limits.set(RLIMIT_AS, Rlimit::INFINITE, false).is_err());
+        limits.set(RLIMIT_AS, Rlimit::INFINITE, true).unwrap();
+
+        let grown_stack = Rlimit {
+            cur: STACK,
+            max: STACK * 2,
+        };
+        assert!(limits.set(RLIMIT_STACK, grown_stack, true).is_err());
+    }
+
+    #[test]
+    fn stack_limit_only_takes_its_pinned_value() {
+        let mut limits = ResourceLimits::new(STACK);
+        let pinned = limits.get(RLIMIT_STACK).unwrap();
+        limits.set(RLIMIT_STACK, pinned, false).unwrap();
+
+        let lowered = Rlimit {
+            cur: STACK / 2,
+            max: STACK,
+        };
+        assert_eq!(
+            limits.set(RLIMIT_STACK, lowered, true),
+            Err(LimitError::StackPinned { size: STACK })
+        );
+        assert_eq!(limits.get(RLIMIT_STACK), Ok(pinned));
+    }
+}
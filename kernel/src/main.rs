@@ -1,34 +1,108 @@
 #![no_std]
 #![no_main]
 
+use core::ffi::{CStr, c_char};
+use core::time::Duration;
+
 use kernel::{
-    Kernel, boot,
+    Kernel,
+    boot::{self, E820_RAM, Initramfs, MemoryMap, MemoryRegion},
+    credentials::{self, Credentials},
     memory::{
-        address::KernelDirectMap,
-        alloc::{kmalloc::KernelAllocator, palloc::PageAllocator},
-        constants::DIRECT_MAP_PML4,
-        pagetable::RootPageTable,
+        address::{KernelDirectMap, PhysicalAddr, VirtualAddr},
+        alloc::{kmalloc::KernelAllocator, palloc::PageAllocator, pshare::PageShares},
+        constants::{DIRECT_MAP_PML4, PAGE_SIZE, PALLOC_FIRST_PAGE},
+        pagetable::{self, RootPageTable},
     },
-    process, syscall,
+    process,
+    seccomp::{SECCOMP_SET_MODE_FILTER, SECCOMP_SET_MODE_STRICT, SockFilter, SockFprog},
+    syscall,
+    wait::WaitQueue,
 };
 
-static PAGE_ALLOCATOR: PageAllocator = PageAllocator::new();
+static PAGE_ALLOCATOR: PageAllocator = PageAllocator::empty();
+// Free pages below which the page allocator asks subsystems to give memory
+// back.
+const LOW_MEMORY_PAGES: usize = 4;
 static KERNEL_DIRECT_MAP: KernelDirectMap = KernelDirectMap;
 
+#[global_allocator]
 static KERNEL_ALLOCATOR: KernelAllocator<KernelDirectMap> =
     KernelAllocator::new(&KERNEL_DIRECT_MAP, &PAGE_ALLOCATOR);
 
+static PAGE_SHARES: PageShares<KernelDirectMap> =
+    PageShares::new(&KERNEL_DIRECT_MAP, &PAGE_ALLOCATOR);
+
 static KERNEL_PAGE_TABLE: RootPageTable<KernelDirectMap> =
     unsafe { RootPageTable::from_paddr(DIRECT_MAP_PML4, &KERNEL_ALLOCATOR) };
 
 #[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
-    let kernel = Kernel::new(&PAGE_ALLOCATOR, &KERNEL_ALLOCATOR, &KERNEL_PAGE_TABLE);
+pub extern "C" fn _start(cpu: usize) -> ! {
+    let mailbox = boot::startup_mailbox(&KERNEL_DIRECT_MAP);
+    mailbox.check_in();
+    // Nothing is shared between CPUs yet, so everything runs on the boot
+    // vCPU and the rest stay out of its way.
+    if cpu != 0 {
+        boot::park();
+    }
+
+    kernel::arch::init();
+    mailbox.set_idt_loaded();
+    let boot_info =
+        boot::read_boot_info(&KERNEL_DIRECT_MAP).expect("boot info of this kernel's version");
+    kernel::time::init(
+        &KERNEL_DIRECT_MAP,
+        Duration::from_nanos(boot_info.wall_clock_nanos),
+    );
+    PAGE_ALLOCATOR.add_memory_map(&boot_info.memory_map);
+    let run_flags = boot_info.flags;
+    if run_flags.scrub_on_free() {
+        PAGE_ALLOCATOR.scrub_on_free(&KERNEL_DIRECT_MAP);
+    }
+    PAGE_ALLOCATOR
+        .register_shrinker(kernel::fs::shrink_ramfs)
+        .expect("register ramfs shrinker");
+    PAGE_ALLOCATOR.set_low_memory_threshold(LOW_MEMORY_PAGES);
+    let kernel = Kernel::new(
+        &PAGE_ALLOCATOR,
+        &KERNEL_ALLOCATOR,
+        &PAGE_SHARES,
+        &KERNEL_PAGE_TABLE,
+    );
     kernel::set_active_kernel(&kernel);
 
     kernel::console::init();
+    kernel::console::attach_virtio(&PAGE_ALLOCATOR, &KERNEL_DIRECT_MAP)
+        .expect("virtio console init");
+    let command_line = boot_info.command_line(&KERNEL_DIRECT_MAP);
+    if !command_line.is_empty() {
+        kernel::println!("kernel: command line: {}", command_line.escape_ascii());
+    }
+    if boot_info.cpu_count > 1 {
+        kernel::println!(
+            "kernel: running on 1 of {} vCPUs, the rest parked",
+            boot_info.cpu_count
+        );
+    }
     syscall::init();
-    let run_flags = kernel::boot::read_run_flags(&KERNEL_DIRECT_MAP);
+    if kernel::arch::apic::start_timer() {
+        kernel::console::enable_input_interrupts();
+    } else {
+        kernel::println!("kernel: no x2APIC, processes are only switched on yield");
+    }
+    if run_flags.quantum_ticks() != 0 {
+        process::set_quantum(&kernel, run_flags.quantum_ticks());
+    }
+    kernel::net::inet::init(&PAGE_ALLOCATOR, &KERNEL_DIRECT_MAP).expect("network stack init");
+    if !boot_info.initramfs.is_empty() {
+        unpack_initramfs(boot_info.initramfs);
+    }
+    kernel::fs::hostfs::mount(&PAGE_ALLOCATOR, &KERNEL_DIRECT_MAP).expect("host share mount");
+    kernel::virtio::balloon::attach(&PAGE_ALLOCATOR, &KERNEL_DIRECT_MAP).expect("balloon init");
+    credentials::init(Credentials {
+        uid: run_flags.uid(),
+        gid: run_flags.gid(),
+    });
 
     if run_flags.run_tests() {
         kernel::println!("kernel: boot (integration-tests)");
@@ -36,17 +110,48 @@ pub extern "C" fn _start() -> ! {
     }
 
     kernel::println!("kernel: boot");
-    let p1 = process::spawn(&kernel, task_a);
-    let p2 = process::spawn(&kernel, task_b);
+    let p1 = process::spawn(&kernel, "task_a", task, b'A' as usize).expect("spawn task A");
+    let p2 = process::spawn(&kernel, "task_b", task, b'B' as usize).expect("spawn task B");
     kernel::println!("kernel: spawned pid={} pid={}", p1, p2);
     process::run(&kernel)
 }
 
+// Unpack the VMM's initramfs into the root filesystem, then hand the pages
+// it was loaded into to the page allocator.
+fn unpack_initramfs(initramfs: Initramfs) {
+    let base = PhysicalAddr::new(initramfs.base as usize);
+    // SAFETY: the VMM wrote the archive there and reserved its pages, which
+    // nothing else uses until they are handed over below.
+    let archive = unsafe {
+        core::slice::from_raw_parts(
+            base.to_virtual(&KERNEL_DIRECT_MAP).as_ptr::<u8>(),
+            initramfs.len as usize,
+        )
+    };
+    let count =
+        kernel::fs::initramfs::unpack(archive, &KERNEL_ALLOCATOR).expect("initramfs unpack");
+    let mut map = MemoryMap::empty();
+    let len = initramfs.len.next_multiple_of(PAGE_SIZE as u64);
+    map.push(MemoryRegion::new(initramfs.base, len, E820_RAM))
+        .expect("an empty memory map has room");
+    PAGE_ALLOCATOR.add_memory_map(&map);
+    kernel::println!("initramfs: unpacked {} entries", count);
+}
+
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     kernel::console::init();
+    // Ahead of the panic line, which the host takes everything after as
+    // the failure's details.
+    if let Some(kernel) = kernel::try_active_kernel() {
+        process::try_dump_trace(kernel);
+    }
+    kernel::backtrace::print();
     kernel::println!("kernel panic: {}", info);
+    if let Some(stats) = KERNEL_ALLOCATOR.try_stats() {
+        kernel::println!("{}", stats);
+    }
 
     if kernel::boot::read_run_flags(&KERNEL_DIRECT_MAP).run_tests() {
         kernel::boot::signal_kernel_tests_failure();
@@ -56,10 +161,71 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn kt_spawn(entry: usize) -> usize {
+extern "C" fn kt_spawn(name: *const u8, name_len: usize, entry: usize, arg: usize) -> i64 {
+    let kernel = kernel::active_kernel();
+    let name =
+        unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(name, name_len)) };
+    let entry_fn: process::ProcessFn = unsafe { core::mem::transmute(entry) };
+    match process::spawn(kernel, name, entry_fn, arg) {
+        Ok(pid) => pid as i64,
+        Err(err) => -syscall::Errno::from(err).code(),
+    }
+}
+
+// `kt_spawn`, with the new process in a copy of the caller's address space.
+#[unsafe(no_mangle)]
+extern "C" fn kt_spawn_forked(name: *const u8, name_len: usize, entry: usize, arg: usize) -> i64 {
     let kernel = kernel::active_kernel();
+    let name =
+        unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(name, name_len)) };
     let entry_fn: process::ProcessFn = unsafe { core::mem::transmute(entry) };
-    process::spawn(kernel, entry_fn)
+    match process::spawn_forked(kernel, name, entry_fn, arg, &[], &[]) {
+        Ok(pid) => pid as i64,
+        Err(err) => -syscall::Errno::from(err).code(),
+    }
+}
+
+// Entry `index` of `process::list`, or -1 past the end. Returns the length of
+// the name copied to `name`, which has room for NAME_MAX_LEN bytes.
+#[unsafe(no_mangle)]
+extern "C" fn kt_process_at(index: usize, pid: *mut u64, state: *mut u8, name: *mut u8) -> i64 {
+    let Some(info) = process::list(kernel::active_kernel()).get(index).copied() else {
+        return -1;
+    };
+    let bytes = info.name.as_bytes();
+    unsafe {
+        *pid = info.pid as u64;
+        *state = info.state as u8;
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), name, bytes.len());
+    }
+    bytes.len() as i64
+}
+
+// Copies the newest `len` scheduler trace records to `records`, oldest first,
+// each as its time in nanoseconds, an event kind, a pid and one argument:
+// kind 0 is a switch to the pid in the argument, 1 a wakeup, 2 a block with
+// the deadline in nanoseconds or u64::MAX, and 3 an exit with its wait4(2)
+// status. Returns how many were copied.
+#[unsafe(no_mangle)]
+extern "C" fn kt_sched_trace(records: *mut [u64; 4], len: usize) -> usize {
+    let trace = process::trace(kernel::active_kernel());
+    let newest = &trace[trace.len().saturating_sub(len)..];
+    for (i, record) in newest.iter().enumerate() {
+        let (kind, pid, arg) = match record.event {
+            process::TraceEvent::Switch { from, to } => (0, from, to as u64),
+            process::TraceEvent::Wakeup { pid } => (1, pid, 0),
+            process::TraceEvent::Block { pid, deadline } => (
+                2,
+                pid,
+                deadline.map_or(u64::MAX, |deadline| deadline.as_nanos() as u64),
+            ),
+            process::TraceEvent::Exit { pid, status } => (3, pid, status.wait_status() as u64),
+        };
+        unsafe {
+            *records.add(i) = [record.at.as_nanos() as u64, kind, pid as u64, arg];
+        }
+    }
+    newest.len()
 }
 
 #[unsafe(no_mangle)]
@@ -67,16 +233,520 @@ extern "C" fn kt_has_pid(pid: usize) -> bool {
     process::has_pid(kernel::active_kernel(), pid)
 }
 
+// The wait4(2) status word of exited process `pid`, or -1 while it is alive
+// or forgotten.
+#[unsafe(no_mangle)]
+extern "C" fn kt_exit_status(pid: usize) -> i64 {
+    process::exit_status(kernel::active_kernel(), pid)
+        .map_or(-1, |status| i64::from(status.wait_status()))
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_getppid() -> i64 {
+    syscall::getppid()
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_kill(pid: i64, signal: u64) -> i64 {
+    syscall::kill(pid, signal)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_wait4(pid: i64, status: *mut i32, options: u64) -> i64 {
+    syscall::wait4(pid, unsafe { status.as_mut() }, options, None)
+}
+
+// getrusage(2), with the CPU times in microseconds.
+#[unsafe(no_mangle)]
+extern "C" fn kt_getrusage(who: i64, user_us: *mut u64, system_us: *mut u64) -> i64 {
+    let mut usage = syscall::Rusage::default();
+    let result = syscall::getrusage(who, &mut usage);
+    let micros = |time: syscall::Timeval| (time.tv_sec * 1_000_000 + time.tv_usec) as u64;
+    unsafe {
+        *user_us = micros(usage.ru_utime);
+        *system_us = micros(usage.ru_stime);
+    }
+    result
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_times(buf: *mut syscall::Tms) -> i64 {
+    syscall::times(unsafe { buf.as_mut() })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_initial_stack() -> usize {
+    process::initial_stack(kernel::active_kernel())
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn kt_yield_now() {
     process::yield_now(kernel::active_kernel())
 }
 
+#[unsafe(no_mangle)]
+extern "C" fn kt_set_segment_bases(fs: u64, gs: u64) {
+    kernel::arch::wrmsr(kernel::arch::IA32_FS_BASE, fs);
+    kernel::arch::wrmsr(kernel::arch::IA32_GS_BASE, gs);
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_segment_bases(fs: *mut u64, gs: *mut u64) {
+    unsafe {
+        *fs = kernel::arch::rdmsr(kernel::arch::IA32_FS_BASE);
+        *gs = kernel::arch::rdmsr(kernel::arch::IA32_GS_BASE);
+    }
+}
+
+// Wait queue the integration tests sleep on and wake directly.
+static TEST_WAIT_QUEUE: WaitQueue = WaitQueue::new();
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_wait_sleep(timeout_ms: i64) {
+    let deadline = u64::try_from(timeout_ms)
+        .ok()
+        .map(|ms| kernel::time::monotonic() + Duration::from_millis(ms));
+    // Like a syscall, check and sleep with interrupts masked.
+    kernel::arch::without_interrupts(|| TEST_WAIT_QUEUE.sleep(kernel::active_kernel(), deadline))
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_wait_wake() {
+    TEST_WAIT_QUEUE.wake_all(kernel::active_kernel())
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn kt_mmap_anonymous(len: usize) -> i64 {
     syscall::mmap_anonymous(len)
 }
 
+#[unsafe(no_mangle)]
+extern "C" fn kt_mmap(addr: usize, len: usize, prot: u64, flags: u64) -> i64 {
+    syscall::mmap(addr, len, prot, flags, -1, 0)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_brk(addr: usize) -> i64 {
+    syscall::brk(addr)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_setrlimit(resource: usize, cur: u64, max: u64) -> i64 {
+    syscall::setrlimit(resource, &kernel::limits::Rlimit { cur, max })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_getpriority(which: u64, who: usize) -> i64 {
+    syscall::getpriority(which, who)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_setpriority(which: u64, who: usize, nice: i32) -> i64 {
+    syscall::setpriority(which, who, nice)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_sched_setaffinity(pid: usize, mask: *const u64, words: usize) -> i64 {
+    syscall::sched_setaffinity(pid, unsafe { core::slice::from_raw_parts(mask, words) })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_sched_getaffinity(pid: usize, mask: *mut u64, words: usize) -> i64 {
+    syscall::sched_getaffinity(pid, unsafe { core::slice::from_raw_parts_mut(mask, words) })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_memory_usage(pid: usize, mapped: *mut u64, resident: *mut u64) -> i64 {
+    match process::memory_usage(kernel::active_kernel(), pid) {
+        Ok(usage) => {
+            unsafe {
+                *mapped = usage.mapped_bytes as u64;
+                *resident = usage.resident_bytes as u64;
+            }
+            0
+        }
+        Err(err) => -syscall::Errno::from(err).code(),
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_boot_memory(ram_pages: *mut u64, allocatable_pages: *mut u64) {
+    let first_page = PALLOC_FIRST_PAGE.as_u64();
+    let ram: u64 = boot::read_boot_info(&KERNEL_DIRECT_MAP)
+        .expect("boot info of this kernel's version")
+        .memory_map
+        .ram()
+        .map(|region| region.end().saturating_sub(region.base.max(first_page)))
+        .sum();
+    unsafe {
+        *ram_pages = ram / PAGE_SIZE as u64;
+        *allocatable_pages = PAGE_ALLOCATOR.get_stats().allocatable_limit_pages as u64;
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_hypervisor_vendor(vendor: *mut [u8; 12]) -> bool {
+    match kernel::arch::cpu::hypervisor_vendor() {
+        Some(found) => {
+            unsafe { *vendor = found };
+            true
+        }
+        None => false,
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_kmalloc_stats(live_objects: *mut u64, bytes_in_use: *mut u64) {
+    let stats = KERNEL_ALLOCATOR.stats();
+    kernel::println!("{}", stats);
+    unsafe {
+        *live_objects = stats.live_objects() as u64;
+        *bytes_in_use = stats.bytes_in_use() as u64;
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_translate(
+    vaddr: usize,
+    paddr: *mut u64,
+    writable: *mut bool,
+    user: *mut bool,
+) -> bool {
+    let root = kernel::arch::current_page_table();
+    let vaddr = VirtualAddr::new(vaddr);
+    let Some(mapping) = pagetable::translate(root, vaddr, &KERNEL_DIRECT_MAP) else {
+        return false;
+    };
+    unsafe {
+        *paddr = mapping.paddr_of(vaddr).as_u64();
+        *writable = mapping.flags.writable;
+        *user = mapping.flags.user;
+    }
+    true
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_mapped_bytes(start: usize, end: usize) -> u64 {
+    let root = kernel::arch::current_page_table();
+    let mut bytes = 0;
+    pagetable::walk(root, start..end, &KERNEL_DIRECT_MAP, |mapping| {
+        bytes += mapping.len as u64;
+    });
+    bytes
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_mkdir(path: *const c_char, mode: u32) -> i64 {
+    syscall::mkdir(unsafe { CStr::from_ptr(path) }, mode)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_rmdir(path: *const c_char) -> i64 {
+    syscall::rmdir(unsafe { CStr::from_ptr(path) })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_chdir(path: *const c_char) -> i64 {
+    syscall::chdir(unsafe { CStr::from_ptr(path) })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_getcwd(buf: *mut u8, len: usize) -> i64 {
+    syscall::getcwd(unsafe { core::slice::from_raw_parts_mut(buf, len) })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_umask(mask: u32) -> i64 {
+    syscall::umask(mask)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_access(path: *const c_char, mode: u64) -> i64 {
+    syscall::access(unsafe { CStr::from_ptr(path) }, mode)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_readlink(path: *const c_char, buf: *mut u8, len: usize) -> i64 {
+    syscall::readlink(unsafe { CStr::from_ptr(path) }, unsafe {
+        core::slice::from_raw_parts_mut(buf, len)
+    })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_openat(dirfd: i64, path: *const c_char, flags: u64, mode: u32) -> i64 {
+    syscall::openat(dirfd, unsafe { CStr::from_ptr(path) }, flags, mode)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_close(fd: u64) -> i64 {
+    syscall::close(fd)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_read(fd: u64, buf: *mut u8, len: usize) -> i64 {
+    syscall::read(fd, unsafe { core::slice::from_raw_parts_mut(buf, len) })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_write(fd: u64, buf: *const u8, len: usize) -> i64 {
+    syscall::write(fd, unsafe { core::slice::from_raw_parts(buf, len) })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_lseek(fd: u64, offset: i64, whence: u64) -> i64 {
+    syscall::lseek(fd, offset, whence)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_statfs(
+    path: *const c_char,
+    f_type: *mut i64,
+    bfree: *mut u64,
+    ffree: *mut u64,
+) -> i64 {
+    let mut buf = syscall::Statfs::default();
+    let ret = syscall::statfs(unsafe { CStr::from_ptr(path) }, &mut buf);
+    unsafe {
+        *f_type = buf.f_type;
+        *bfree = buf.f_bfree;
+        *ffree = buf.f_ffree;
+    }
+    ret
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_fstatfs(fd: u64, f_type: *mut i64, bfree: *mut u64, ffree: *mut u64) -> i64 {
+    let mut buf = syscall::Statfs::default();
+    let ret = syscall::fstatfs(fd, &mut buf);
+    unsafe {
+        *f_type = buf.f_type;
+        *bfree = buf.f_bfree;
+        *ffree = buf.f_ffree;
+    }
+    ret
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_sendfile(out_fd: u64, in_fd: u64, offset: *mut i64, count: usize) -> i64 {
+    syscall::sendfile(out_fd, in_fd, unsafe { offset.as_mut() }, count)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_ftruncate(fd: u64, len: i64) -> i64 {
+    syscall::ftruncate(fd, len)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_unlink(path: *const c_char) -> i64 {
+    syscall::unlink(unsafe { CStr::from_ptr(path) })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_shrink_ramfs(pages: usize) -> usize {
+    kernel::fs::shrink_ramfs(pages)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_poll(fd: i32, events: i16, timeout_ms: i32) -> i64 {
+    let mut fds = [syscall::PollFd {
+        fd,
+        events,
+        revents: 0,
+    }];
+    syscall::poll(&mut fds, timeout_ms)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_epoll_create1(flags: u64) -> i64 {
+    syscall::epoll_create1(flags)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_epoll_ctl(epfd: u64, op: u64, fd: u64, events: u32, data: u64) -> i64 {
+    let event = syscall::EpollEvent { events, data };
+    syscall::epoll_ctl(epfd, op, fd, Some(&event))
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_epoll_wait(epfd: u64, timeout_ms: i32, events: *mut u32, data: *mut u64) -> i64 {
+    let mut ready = [syscall::EpollEvent::default()];
+    let count = syscall::epoll_wait(epfd, &mut ready, timeout_ms);
+    unsafe {
+        *events = ready[0].events;
+        *data = ready[0].data;
+    }
+    count
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_eventfd(initval: u32, flags: u64) -> i64 {
+    syscall::eventfd(initval, flags)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_timerfd_create(clockid: u64, flags: u64) -> i64 {
+    syscall::timerfd_create(clockid, flags)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_timerfd_settime(fd: u64, value_ns: u64, interval_ns: u64) -> i64 {
+    let timespec = |ns: u64| syscall::Timespec {
+        tv_sec: (ns / 1_000_000_000) as i64,
+        tv_nsec: (ns % 1_000_000_000) as i64,
+    };
+    let new = syscall::Itimerspec {
+        it_interval: timespec(interval_ns),
+        it_value: timespec(value_ns),
+    };
+    syscall::timerfd_settime(fd, 0, &new, None)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_socket(ty: u64) -> i64 {
+    syscall::socket(syscall::AF_UNIX, ty, 0)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_tcp_socket(flags: u64) -> i64 {
+    syscall::socket(
+        syscall::AF_INET,
+        syscall::SOCK_STREAM | flags,
+        syscall::IPPROTO_TCP,
+    )
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_bind_loopback(fd: u64, port: u16) -> i64 {
+    syscall::bind_in(
+        fd,
+        &syscall::SockaddrIn::new(syscall::INADDR_LOOPBACK, port),
+    )
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_connect_loopback(fd: u64, port: u16) -> i64 {
+    syscall::connect_in(
+        fd,
+        &syscall::SockaddrIn::new(syscall::INADDR_LOOPBACK, port),
+    )
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_bind(fd: u64, name: *const u8, len: usize) -> i64 {
+    let (addr, len) = sockaddr(name, len);
+    syscall::bind(fd, &addr, len)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_listen(fd: u64, backlog: i32) -> i64 {
+    syscall::listen(fd, backlog)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_connect(fd: u64, name: *const u8, len: usize) -> i64 {
+    let (addr, len) = sockaddr(name, len);
+    syscall::connect(fd, &addr, len)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_accept4(fd: u64, flags: u64) -> i64 {
+    syscall::accept4(fd, flags)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_sendmsg(
+    fd: u64,
+    buf: *const u8,
+    len: usize,
+    to: *const u8,
+    to_len: usize,
+    flags: u64,
+) -> i64 {
+    // Split the payload so the kernel has to gather it.
+    let half = len / 2;
+    let mut iov = [
+        syscall::Iovec {
+            iov_base: buf as *mut u8,
+            iov_len: half,
+        },
+        syscall::Iovec {
+            iov_base: unsafe { buf.add(half) } as *mut u8,
+            iov_len: len - half,
+        },
+    ];
+    let (mut addr, addr_len) = sockaddr(to, to_len);
+    let msg = syscall::Msghdr {
+        msg_name: if to.is_null() {
+            core::ptr::null_mut()
+        } else {
+            &mut addr as *mut syscall::SockaddrUn as *mut u8
+        },
+        msg_namelen: addr_len as u32,
+        msg_iov: iov.as_mut_ptr(),
+        msg_iovlen: iov.len(),
+        msg_control: core::ptr::null_mut(),
+        msg_controllen: 0,
+        msg_flags: 0,
+    };
+    syscall::sendmsg(fd, &msg, flags)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_recvmsg(
+    fd: u64,
+    buf: *mut u8,
+    len: usize,
+    flags: u64,
+    from: *mut u8,
+    from_len: *mut usize,
+    truncated: *mut bool,
+) -> i64 {
+    let mut iov = [syscall::Iovec {
+        iov_base: buf,
+        iov_len: len,
+    }];
+    let mut addr = syscall::SockaddrUn::new();
+    let mut msg = syscall::Msghdr {
+        msg_name: &mut addr as *mut syscall::SockaddrUn as *mut u8,
+        msg_namelen: size_of::<syscall::SockaddrUn>() as u32,
+        msg_iov: iov.as_mut_ptr(),
+        msg_iovlen: iov.len(),
+        msg_control: core::ptr::null_mut(),
+        msg_controllen: 0,
+        msg_flags: 0,
+    };
+    let received = syscall::recvmsg(fd, &mut msg, flags);
+    let name_len = (msg.msg_namelen as usize).saturating_sub(2);
+    unsafe {
+        core::ptr::copy_nonoverlapping(addr.sun_path.as_ptr(), from, name_len);
+        *from_len = name_len;
+        *truncated = msg.msg_flags & syscall::MSG_TRUNC != 0;
+    }
+    received
+}
+
+// Build an AF_UNIX address from raw name bytes; a leading NUL makes it
+// abstract.
+fn sockaddr(name: *const u8, len: usize) -> (syscall::SockaddrUn, usize) {
+    let mut addr = syscall::SockaddrUn::new();
+    if !name.is_null() {
+        let len = len.min(addr.sun_path.len());
+        unsafe { core::ptr::copy_nonoverlapping(name, addr.sun_path.as_mut_ptr(), len) };
+    }
+    (addr, 2 + len)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_seccomp_strict() -> i64 {
+    syscall::seccomp(SECCOMP_SET_MODE_STRICT, 0, None)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kt_seccomp_filter(filter: *const SockFilter, len: u16) -> i64 {
+    let prog = SockFprog { len, filter };
+    syscall::seccomp(SECCOMP_SET_MODE_FILTER, 0, Some(&prog))
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn kt_exit(status: i32) -> ! {
     syscall::exit(status)
@@ -92,22 +762,21 @@ extern "C" fn kt_signal_failure() -> ! {
     boot::signal_kernel_tests_failure()
 }
 
-fn task_a() {
-    let mut i = 0;
-    while i < 5 {
-        kernel::println!("task A (pid={}): tick {}", syscall::getpid(), i);
-        i += 1;
-        let _ = syscall::sched_yield();
-    }
-    let _ = syscall::write(1, b"task A: done via SYS_write\n");
-}
-
-fn task_b() {
+// `name` is the ASCII letter the task reports itself as.
+fn task(name: usize) {
+    let name = name as u8;
     let mut i = 0;
     while i < 5 {
-        kernel::println!("task B (pid={}): tick {}", syscall::getpid(), i);
+        kernel::println!(
+            "task {} (pid={}): tick {}",
+            name as char,
+            syscall::getpid(),
+            i
+        );
         i += 1;
         let _ = syscall::sched_yield();
     }
-    let _ = syscall::write(1, b"task B: done via SYS_write\n");
+    let mut done = *b"task ?: done via SYS_write\n";
+    done[5] = name;
+    let _ = syscall::write(1, &done);
 }
@@ -74,6 +74,10 @@ impl VirtualAddr {
         (self.0 >> 21) & 0x1FF
     }
 
+    pub const fn pt_index(self) -> usize {
+        (self.0 >> 12) & 0x1FF
+    }
+
     pub const fn as_ptr<T>(self) -> *mut T {
         self.0 as *mut T
     }
@@ -1,9 +1,15 @@
-use core::ptr::write_bytes;
-
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ptr::{copy_nonoverlapping, null_mut, write_bytes};
+
+#[cfg(feature = "alloc-debug")]
+use super::POISON_FREE;
+use super::check_free;
+use crate::arch;
 use crate::memory::{
-    address::{DirectMap, PhysicalAddr},
+    address::{DirectMap, PhysicalAddr, VirtualAddr},
     alloc::palloc::PageAllocator,
-    constants::PAGE_SIZE,
+    constants::{MAX_PHYSICAL_ADDR, PAGE_SIZE},
     errors::{MemoryError, Result},
 };
 
@@ -12,11 +18,30 @@ const MAX_SHIFT: u32 = 24; // 16 MiB
 const MIN_ALLOC_SIZE: usize = 1 << MIN_SHIFT;
 const MAX_ALLOC_SIZE: usize = 1 << MAX_SHIFT;
 const SMALL_CLASS_COUNT: usize = 12; // 1 KiB .. 2 MiB
-const MAX_SLABS_PER_CLASS: usize = 512;
-const MAX_LARGE_ALLOCS: usize = 256;
 const FREE_LIST_END: u16 = u16::MAX;
 const PAGE_MASK: usize = !(PAGE_SIZE - 1);
-const SMALL_SLAB_MAP_SIZE: usize = 4096;
+
+// With `redzone`, canary bytes follow each object and the block ends with
+// its requested size, so an overrun is caught when the block is freed
+// rather than when it corrupts the next block's free-list link.
+#[cfg(feature = "redzone")]
+const REDZONE_SIZE: usize = 16;
+#[cfg(feature = "redzone")]
+const REDZONE_BYTE: u8 = 0xa5;
+#[cfg(feature = "redzone")]
+const REDZONE_OVERHEAD: usize = REDZONE_SIZE + size_of::<u64>();
+#[cfg(not(feature = "redzone"))]
+const REDZONE_OVERHEAD: usize = 0;
+
+// Every physical page has a u32 owner entry: 0 when kmalloc does not own it,
+// LARGE_OWNER | pages for the first page of a large allocation, and the slab
+// descriptor id + 1 otherwise. The whole map fits in a single page.
+const PAGE_COUNT: usize = (MAX_PHYSICAL_ADDR + 1) / PAGE_SIZE;
+const LARGE_OWNER: u32 = 1 << 31;
+const _: () = assert!(PAGE_COUNT * size_of::<u32>() <= PAGE_SIZE);
+
+const NO_SLAB: u32 = u32::MAX;
+const SLABS_PER_CHUNK: usize = (PAGE_SIZE - size_of::<PhysicalAddr>()) / size_of::<Slab>();
 
 const SMALL_CLASS_SIZES: [u32; SMALL_CLASS_COUNT] = [
     1 << 10,
@@ -33,82 +58,98 @@ const SMALL_CLASS_SIZES: [u32; SMALL_CLASS_COUNT] = [
     1 << 21,
 ];
 
-#[derive(Clone, Copy)]
-struct Slab {
-    in_use: bool,
-    base: PhysicalAddr,
-    capacity: u16,
-    free_count: u16,
-    free_head: u16,
-}
-
-impl Slab {
-    const fn empty() -> Self {
-        Self {
-            in_use: false,
-            base: PhysicalAddr::new(0),
-            capacity: 0,
-            free_count: 0,
-            free_head: FREE_LIST_END,
-        }
-    }
+/// Usage of one small size class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    pub block_size: usize,
+    pub slabs: usize,
+    pub live_objects: usize,
 }
 
-#[derive(Clone, Copy)]
-struct SizeClass {
-    block_size: u32,
-    slabs: [Slab; MAX_SLABS_PER_CLASS],
-    last_alloc_slab: usize,
+/// A snapshot of what the kernel heap holds. Small allocations are counted
+/// per size class; allocations above PAGE_SIZE take whole pages and are
+/// counted together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub classes: [ClassStats; SMALL_CLASS_COUNT],
+    pub large_objects: usize,
+    pub large_pages: usize,
 }
 
-impl SizeClass {
-    const fn new(block_size: u32) -> Self {
-        Self {
-            block_size,
-            slabs: [Slab::empty(); MAX_SLABS_PER_CLASS],
-            last_alloc_slab: 0,
-        }
+impl Stats {
+    pub fn live_objects(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|class| class.live_objects)
+            .sum::<usize>()
+            + self.large_objects
     }
-}
 
-#[derive(Clone, Copy)]
-struct LargeAlloc {
-    in_use: bool,
-    base: PhysicalAddr,
-    pages: usize,
+    /// Bytes handed out, counting each allocation at its rounded size.
+    pub fn bytes_in_use(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|class| class.live_objects * class.block_size)
+            .sum::<usize>()
+            + self.large_pages * PAGE_SIZE
+    }
 }
 
-impl LargeAlloc {
-    const fn empty() -> Self {
-        Self {
-            in_use: false,
-            base: PhysicalAddr::new(0),
-            pages: 0,
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "kmalloc: {} objects, {} bytes in use",
+            self.live_objects(),
+            self.bytes_in_use()
+        )?;
+        for class in self.classes.iter().filter(|class| class.slabs != 0) {
+            write!(
+                f,
+                "\n  {:>7} B: {} slabs, {} objects",
+                class.block_size, class.slabs, class.live_objects
+            )?;
+        }
+        if self.large_objects != 0 {
+            write!(
+                f,
+                "\n  large: {} objects, {} pages",
+                self.large_objects, self.large_pages
+            )?;
         }
+        Ok(())
     }
 }
 
+// `prev`/`next` link the slab into its class's partial list while it has free
+// blocks, and `next` chains unused descriptors.
 #[derive(Clone, Copy)]
-struct SmallSlabMapEntry {
-    key_page_plus_one: u32,
-    value: u16,
-    _reserved: u16,
+struct Slab {
+    base: PhysicalAddr,
+    class_idx: u16,
+    capacity: u16,
+    free_count: u16,
+    free_head: u16,
+    prev: u32,
+    next: u32,
 }
 
-impl SmallSlabMapEntry {
-    const fn empty() -> Self {
-        Self {
-            key_page_plus_one: 0,
-            value: 0,
-            _reserved: 0,
-        }
-    }
+// Slab descriptors live in page-sized chunks taken from palloc. Chunks are
+// pushed onto the front of the chain, so the newest one holds the highest ids.
+#[repr(C)]
+struct SlabChunk {
+    next: PhysicalAddr,
+    slabs: [Slab; SLABS_PER_CHUNK],
 }
 
 struct KernelAllocatorImpl<'i, DM: DirectMap> {
-    small: [SizeClass; SMALL_CLASS_COUNT],
-    small_slab_map: [SmallSlabMapEntry; SMALL_SLAB_MAP_SIZE],
-    large: [LargeAlloc; MAX_LARGE_ALLOCS],
+    partial: [u32; SMALL_CLASS_COUNT],
+    owners: Option<PhysicalAddr>,
+    chunks: Option<PhysicalAddr>,
+    chunk_count: usize,
+    next_slab: u32,
+    free_slabs: u32,
+    stats: Stats,
     palloc: &'i PageAllocator,
     dm: &'i DM,
 }
@@ -116,22 +157,46 @@ struct KernelAllocatorImpl<'i, DM: DirectMap> {
 impl<'i, DM: DirectMap> KernelAllocatorImpl<'i, DM> {
     const fn new(dm: &'i DM, page_alloc: &'i PageAllocator) -> Self {
         Self {
-            small: build_small_classes(),
-            small_slab_map: [SmallSlabMapEntry::empty(); SMALL_SLAB_MAP_SIZE],
-            large: [LargeAlloc::empty(); MAX_LARGE_ALLOCS],
+            partial: [NO_SLAB; SMALL_CLASS_COUNT],
+            owners: None,
+            chunks: None,
+            chunk_count: 0,
+            next_slab: 0,
+            free_slabs: NO_SLAB,
+            stats: Stats {
+                classes: class_stats(),
+                large_objects: 0,
+                large_pages: 0,
+            },
             palloc: page_alloc,
             dm,
         }
     }
 
     fn alloc(&mut self, size: usize) -> Result<PhysicalAddr> {
-        let class_size = size_to_class(size)?;
+        let class_size = size_to_class(size.saturating_add(REDZONE_OVERHEAD))?;
 
-        if class_size <= PAGE_SIZE {
+        let addr = if class_size <= PAGE_SIZE {
             self.alloc_small(class_size as u32)
         } else {
             self.alloc_large(class_size)
+        }?;
+        #[cfg(feature = "redzone")]
+        arm_redzone(addr, size, class_size, self.dm);
+        Ok(addr)
+    }
+
+    // Blocks are aligned to their size class and large allocations to a
+    // page, so rounding the size up to the alignment is enough for any
+    // alignment up to PAGE_SIZE.
+    fn alloc_aligned(&mut self, size: usize, align: usize) -> Result<PhysicalAddr> {
+        if !align.is_power_of_two() || align > PAGE_SIZE {
+            return Err(MemoryError::UnsupportedAlignment {
+                align,
+                max: PAGE_SIZE,
+            });
         }
+        self.alloc(size.max(align))
     }
 
     fn calloc(&mut self, size: usize) -> Result<PhysicalAddr> {
@@ -144,59 +209,104 @@ impl<'i, DM: DirectMap> KernelAllocatorImpl<'i, DM> {
         Ok(addr)
     }
 
-    fn free(&mut self, ptr: PhysicalAddr) -> Result<()> {
-        if self.free_small(ptr)? {
-            return Ok(());
+    // A block keeps its address while the new size rounds to the class it
+    // already has; otherwise it moves to a block of the right class.
+    fn realloc(&mut self, ptr: PhysicalAddr, new_size: usize) -> Result<PhysicalAddr> {
+        let block_size = self.block_size(ptr)?;
+        if size_to_class(new_size.saturating_add(REDZONE_OVERHEAD))? == block_size {
+            #[cfg(feature = "redzone")]
+            {
+                check_redzone(ptr, block_size, self.dm)?;
+                arm_redzone(ptr, new_size, block_size, self.dm);
+            }
+            return Ok(ptr);
         }
 
-        self.free_large(ptr)
+        let new = self.alloc(new_size)?;
+        unsafe {
+            copy_nonoverlapping(
+                ptr.to_virtual(self.dm).as_ptr::<u8>(),
+                new.to_virtual(self.dm).as_ptr::<u8>(),
+                block_size.min(new_size),
+            );
+        }
+        self.free(ptr)?;
+        Ok(new)
+    }
+
+    fn block_size(&mut self, ptr: PhysicalAddr) -> Result<usize> {
+        let owner = self.owner(ptr.as_usize() & PAGE_MASK);
+        if owner & LARGE_OWNER != 0 {
+            Ok((owner & !LARGE_OWNER) as usize * PAGE_SIZE)
+        } else if owner != 0 {
+            Ok(SMALL_CLASS_SIZES[self.slab(owner - 1).class_idx as usize] as usize)
+        } else {
+            Err(MemoryError::UnknownAllocation {
+                addr: ptr.as_usize(),
+            })
+        }
+    }
+
+    fn free(&mut self, ptr: PhysicalAddr) -> Result<()> {
+        let owner = self.owner(ptr.as_usize() & PAGE_MASK);
+        if owner & LARGE_OWNER != 0 {
+            self.free_large(ptr, (owner & !LARGE_OWNER) as usize)
+        } else if owner != 0 {
+            self.free_small(ptr, owner - 1)
+        } else {
+            Err(MemoryError::UnknownAllocation {
+                addr: ptr.as_usize(),
+            })
+        }
     }
 
     fn alloc_small(&mut self, block_size: u32) -> Result<PhysicalAddr> {
         let class_idx = (block_size.trailing_zeros() - MIN_SHIFT) as usize;
-        let start_idx = self.small[class_idx].last_alloc_slab;
-
-        for offset in 0..MAX_SLABS_PER_CLASS {
-            let slab_idx = (start_idx + offset) % MAX_SLABS_PER_CLASS;
-            let slab = &mut self.small[class_idx].slabs[slab_idx];
-            if slab.in_use && slab.free_count > 0 {
-                self.small[class_idx].last_alloc_slab = slab_idx;
-                let block = self.small[class_idx].block_size as usize;
-                return alloc_from_small_slab(slab, block, self.dm);
-            }
+        let id = match self.partial[class_idx] {
+            NO_SLAB => self.new_slab(class_idx)?,
+            id => id,
+        };
+
+        let dm = self.dm;
+        let slab = self.slab(id);
+        let addr = alloc_from_small_slab(slab, block_size as usize, dm)?;
+        if slab.free_count == 0 {
+            self.unlink_partial(id);
         }
+        self.stats.classes[class_idx].live_objects += 1;
+        Ok(addr)
+    }
 
-        for slab_idx in 0..MAX_SLABS_PER_CLASS {
-            if !self.small[class_idx].slabs[slab_idx].in_use {
-                let base = {
-                    let slab = &mut self.small[class_idx].slabs[slab_idx];
-                    let block = self.small[class_idx].block_size;
-                    init_small_slab(self.palloc, slab, block, self.dm)?;
-                    slab.base.as_usize()
-                };
-                self.small_slab_map_insert(base, class_idx, slab_idx)?;
-                self.small[class_idx].last_alloc_slab = slab_idx;
-                let slab = &mut self.small[class_idx].slabs[slab_idx];
-                let block = self.small[class_idx].block_size as usize;
-                return alloc_from_small_slab(slab, block, self.dm);
+    fn new_slab(&mut self, class_idx: usize) -> Result<u32> {
+        let base = self.palloc.alloc(1)?;
+        let id = match self.alloc_descriptor() {
+            Ok(id) => id,
+            Err(err) => {
+                self.palloc.free(base)?;
+                return Err(err);
             }
+        };
+        if let Err(err) = self.set_owner(base, id + 1) {
+            self.free_descriptor(id);
+            self.palloc.free(base)?;
+            return Err(err);
         }
 
-        Err(MemoryError::TooManySlabs {
-            class_size: self.small[class_idx].block_size,
-        })
+        let dm = self.dm;
+        init_small_slab(self.slab(id), base, class_idx, dm)?;
+        self.push_partial(id);
+        self.stats.classes[class_idx].slabs += 1;
+        Ok(id)
     }
 
-    fn free_small(&mut self, addr: PhysicalAddr) -> Result<bool> {
+    fn free_small(&mut self, addr: PhysicalAddr, id: u32) -> Result<()> {
+        let dm = self.dm;
+        #[cfg(not(feature = "alloc-debug"))]
+        let scrub = self.palloc.scrubs_on_free();
+        let slab = self.slab(id);
         let p = addr.as_usize();
-        let page_base = p & PAGE_MASK;
-        let Some((class_idx, slab_idx)) = self.small_slab_map_get(page_base) else {
-            return Ok(false);
-        };
-
-        let slab = &mut self.small[class_idx].slabs[slab_idx];
         let offset = p - slab.base.as_usize();
-        let block_size = self.small[class_idx].block_size as usize;
+        let block_size = SMALL_CLASS_SIZES[slab.class_idx as usize] as usize;
 
         if offset % block_size != 0 {
             return Err(MemoryError::SlabAlignmentMismatch {
@@ -206,162 +316,207 @@ impl<'i, DM: DirectMap> KernelAllocatorImpl<'i, DM> {
         }
 
         let idx = (offset / block_size) as u16;
+        let class_idx = slab.class_idx as usize;
+        #[cfg(feature = "alloc-debug")]
+        if slab_free_list_contains(slab, idx, dm) {
+            return Err(MemoryError::DoubleFree { addr: p });
+        }
+        #[cfg(feature = "redzone")]
+        check_redzone(addr, block_size, dm)?;
+        // The free-list link goes in over the poison.
+        #[cfg(feature = "alloc-debug")]
+        unsafe {
+            write_bytes(addr.to_virtual(dm).as_ptr::<u8>(), POISON_FREE, block_size);
+        }
+        // Blocks are scrubbed like pages; the slab page itself is scrubbed
+        // again once the whole slab goes back to the page allocator.
+        #[cfg(not(feature = "alloc-debug"))]
+        if scrub {
+            unsafe { write_bytes(addr.to_virtual(dm).as_ptr::<u8>(), 0, block_size) };
+        }
         unsafe {
-            *small_slab_link_ptr(slab, idx, self.dm) = slab.free_head;
+            *small_slab_link_ptr(slab, idx, dm) = slab.free_head;
         }
+        let was_full = slab.free_count == 0;
         slab.free_head = idx;
         slab.free_count += 1;
+        let now_empty = slab.free_count == slab.capacity;
+        let base = slab.base;
+        self.stats.classes[class_idx].live_objects -= 1;
 
-        if slab.free_count == slab.capacity {
-            let base = slab.base;
-            *slab = Slab::empty();
-            self.small_slab_map_remove(page_base);
+        if now_empty {
+            if !was_full {
+                self.unlink_partial(id);
+            }
+            self.set_owner(base, 0)?;
+            self.free_descriptor(id);
             self.palloc.free(base)?;
+            self.stats.classes[class_idx].slabs -= 1;
+        } else if was_full {
+            self.push_partial(id);
         }
 
-        Ok(true)
+        Ok(())
     }
 
     fn alloc_large(&mut self, class_size: usize) -> Result<PhysicalAddr> {
         let pages = class_size.div_ceil(PAGE_SIZE);
         let base = self.palloc.alloc(pages)?;
 
-        for slot in &mut self.large {
-            if !slot.in_use {
-                *slot = LargeAlloc {
-                    in_use: true,
-                    base,
-                    pages,
-                };
-                return Ok(base);
+        if let Err(err) = self.set_owner(base, LARGE_OWNER | pages as u32) {
+            for page in 0..pages {
+                self.palloc.free(base.add(page * PAGE_SIZE))?;
             }
+            return Err(err);
+        }
+        self.stats.large_objects += 1;
+        self.stats.large_pages += pages;
+        Ok(base)
+    }
+
+    fn free_large(&mut self, addr: PhysicalAddr, pages: usize) -> Result<()> {
+        if addr.as_usize() & !PAGE_MASK != 0 {
+            return Err(MemoryError::UnknownAllocation {
+                addr: addr.as_usize(),
+            });
         }
 
+        #[cfg(feature = "redzone")]
+        check_redzone(addr, pages * PAGE_SIZE, self.dm)?;
+        #[cfg(feature = "alloc-debug")]
+        unsafe {
+            write_bytes(
+                addr.to_virtual(self.dm).as_ptr::<u8>(),
+                POISON_FREE,
+                pages * PAGE_SIZE,
+            );
+        }
+        self.set_owner(addr, 0)?;
         for page in 0..pages {
-            self.palloc.free(base.add(page * PAGE_SIZE))?;
+            self.palloc.free(addr.add(page * PAGE_SIZE))?;
         }
-        Err(MemoryError::TooManyLargeAllocations)
+        self.stats.large_objects -= 1;
+        self.stats.large_pages -= pages;
+        Ok(())
     }
 
-    fn free_large(&mut self, addr: PhysicalAddr) -> Result<()> {
-        for slot in &mut self.large {
-            if slot.in_use && slot.base == addr {
-                for page in 0..slot.pages {
-                    self.palloc.free(slot.base.add(page * PAGE_SIZE))?;
-                }
-                *slot = LargeAlloc::empty();
-                return Ok(());
-            }
+    fn owner(&self, page_base: usize) -> u32 {
+        match self.owners {
+            Some(map) if page_base / PAGE_SIZE < PAGE_COUNT => unsafe {
+                *owner_ptr(map, page_base, self.dm)
+            },
+            _ => 0,
         }
-
-        Err(MemoryError::UnknownAllocation {
-            addr: addr.as_usize(),
-        })
     }
 
-    fn small_slab_map_insert(
-        &mut self,
-        page_base: usize,
-        class_idx: usize,
-        slab_idx: usize,
-    ) -> Result<()> {
-        let value = (class_idx * MAX_SLABS_PER_CLASS + slab_idx + 1) as u16;
-        for probe in 0..SMALL_SLAB_MAP_SIZE {
-            let idx = (hash_page_base(page_base) + probe) & (SMALL_SLAB_MAP_SIZE - 1);
-            let entry = self.small_slab_map[idx];
-            if entry.value == 0 || entry.key_page_plus_one == to_page_plus_one(page_base) {
-                self.small_slab_map[idx] = SmallSlabMapEntry {
-                    key_page_plus_one: to_page_plus_one(page_base),
-                    value,
-                    _reserved: 0,
-                };
-                return Ok(());
+    // The owner map is allocated on first use and never given back.
+    fn set_owner(&mut self, page_base: PhysicalAddr, owner: u32) -> Result<()> {
+        let map = match self.owners {
+            Some(map) => map,
+            None => {
+                let map = self.palloc.alloc(1)?;
+                unsafe {
+                    write_bytes(map.to_virtual(self.dm).as_ptr::<u8>(), 0, PAGE_SIZE);
+                }
+                self.owners = Some(map);
+                map
             }
-        }
+        };
 
-        Err(MemoryError::TooManySlabs {
-            class_size: self.small[class_idx].block_size,
-        })
+        unsafe {
+            *owner_ptr(map, page_base.as_usize(), self.dm) = owner;
+        }
+        Ok(())
     }
 
-    fn small_slab_map_get(&self, page_base: usize) -> Option<(usize, usize)> {
-        for probe in 0..SMALL_SLAB_MAP_SIZE {
-            let idx = (hash_page_base(page_base) + probe) & (SMALL_SLAB_MAP_SIZE - 1);
-            let entry = self.small_slab_map[idx];
-            if entry.value == 0 {
-                return None;
-            }
-            if entry.key_page_plus_one == to_page_plus_one(page_base) {
-                let unpacked = entry.value as usize - 1;
-                return Some((
-                    unpacked / MAX_SLABS_PER_CLASS,
-                    unpacked % MAX_SLABS_PER_CLASS,
-                ));
-            }
+    fn slab(&mut self, id: u32) -> &mut Slab {
+        let chunk_idx = id as usize / SLABS_PER_CHUNK;
+        let mut chunk = self.chunks.expect("slab descriptor without a chunk");
+        for _ in chunk_idx + 1..self.chunk_count {
+            chunk = unsafe { (*chunk_ptr(chunk, self.dm)).next };
         }
-
-        None
+        unsafe { &mut (*chunk_ptr(chunk, self.dm)).slabs[id as usize % SLABS_PER_CHUNK] }
     }
 
-    fn small_slab_map_remove(&mut self, page_base: usize) {
-        let mut removed_idx = None;
-        for probe in 0..SMALL_SLAB_MAP_SIZE {
-            let idx = (hash_page_base(page_base) + probe) & (SMALL_SLAB_MAP_SIZE - 1);
-            let entry = self.small_slab_map[idx];
-            if entry.value == 0 {
-                return;
-            }
-            if entry.key_page_plus_one == to_page_plus_one(page_base) {
-                removed_idx = Some(idx);
-                break;
-            }
+    fn alloc_descriptor(&mut self) -> Result<u32> {
+        if self.free_slabs != NO_SLAB {
+            let id = self.free_slabs;
+            self.free_slabs = self.slab(id).next;
+            return Ok(id);
         }
 
-        let Some(remove_idx) = removed_idx else {
-            return;
-        };
-
-        self.small_slab_map[remove_idx] = SmallSlabMapEntry::empty();
-        let mut scan = (remove_idx + 1) & (SMALL_SLAB_MAP_SIZE - 1);
-        for _ in 0..SMALL_SLAB_MAP_SIZE {
-            let entry = self.small_slab_map[scan];
-            if entry.value == 0 {
-                return;
-            }
-            self.small_slab_map[scan] = SmallSlabMapEntry::empty();
-
-            for probe in 0..SMALL_SLAB_MAP_SIZE {
-                let idx = (hash_page_base(from_page_plus_one(entry.key_page_plus_one)) + probe)
-                    & (SMALL_SLAB_MAP_SIZE - 1);
-                if self.small_slab_map[idx].value == 0 {
-                    self.small_slab_map[idx] = entry;
-                    break;
+        if self.next_slab as usize == self.chunk_count * SLABS_PER_CHUNK {
+            let chunk = self.palloc.alloc(1)?;
+            if let Some(head) = self.chunks {
+                unsafe {
+                    (*chunk_ptr(chunk, self.dm)).next = head;
                 }
             }
+            self.chunks = Some(chunk);
+            self.chunk_count += 1;
+        }
+
+        let id = self.next_slab;
+        self.next_slab += 1;
+        Ok(id)
+    }
+
+    fn free_descriptor(&mut self, id: u32) {
+        let next = self.free_slabs;
+        self.slab(id).next = next;
+        self.free_slabs = id;
+    }
+
+    fn push_partial(&mut self, id: u32) {
+        let slab = self.slab(id);
+        let class_idx = slab.class_idx as usize;
+        let head = self.partial[class_idx];
+        let slab = self.slab(id);
+        slab.prev = NO_SLAB;
+        slab.next = head;
+        if head != NO_SLAB {
+            self.slab(head).prev = id;
+        }
+        self.partial[class_idx] = id;
+    }
 
-            scan = (scan + 1) & (SMALL_SLAB_MAP_SIZE - 1);
+    fn unlink_partial(&mut self, id: u32) {
+        let Slab {
+            class_idx,
+            prev,
+            next,
+            ..
+        } = *self.slab(id);
+        if prev == NO_SLAB {
+            self.partial[class_idx as usize] = next;
+        } else {
+            self.slab(prev).next = next;
+        }
+        if next != NO_SLAB {
+            self.slab(next).prev = prev;
         }
     }
 }
 
 fn init_small_slab(
-    palloc: &PageAllocator,
     slab: &mut Slab,
-    block_size: u32,
+    base: PhysicalAddr,
+    class_idx: usize,
     dm: &impl DirectMap,
 ) -> Result<()> {
-    let base = palloc.alloc(1)?;
-    let capacity = (PAGE_SIZE / block_size as usize) as u16;
+    let capacity = (PAGE_SIZE / SMALL_CLASS_SIZES[class_idx] as usize) as u16;
     if capacity == 0 {
         return Err(MemoryError::InvalidSlabCapacity);
     }
 
     *slab = Slab {
-        in_use: true,
         base,
+        class_idx: class_idx as u16,
         capacity,
         free_count: capacity,
         free_head: 0,
+        prev: NO_SLAB,
+        next: NO_SLAB,
     };
 
     for i in 0..capacity {
@@ -378,23 +533,6 @@ fn init_small_slab(
     Ok(())
 }
 
-const fn build_small_classes() -> [SizeClass; SMALL_CLASS_COUNT] {
-    [
-        SizeClass::new(SMALL_CLASS_SIZES[0]),
-        SizeClass::new(SMALL_CLASS_SIZES[1]),
-        SizeClass::new(SMALL_CLASS_SIZES[2]),
-        SizeClass::new(SMALL_CLASS_SIZES[3]),
-        SizeClass::new(SMALL_CLASS_SIZES[4]),
-        SizeClass::new(SMALL_CLASS_SIZES[5]),
-        SizeClass::new(SMALL_CLASS_SIZES[6]),
-        SizeClass::new(SMALL_CLASS_SIZES[7]),
-        SizeClass::new(SMALL_CLASS_SIZES[8]),
-        SizeClass::new(SMALL_CLASS_SIZES[9]),
-        SizeClass::new(SMALL_CLASS_SIZES[10]),
-        SizeClass::new(SMALL_CLASS_SIZES[11]),
-    ]
-}
-
 fn size_to_class(size: usize) -> Result<usize> {
     let requested = if size == 0 { MIN_ALLOC_SIZE } else { size };
     if requested > MAX_ALLOC_SIZE {
@@ -407,7 +545,42 @@ fn size_to_class(size: usize) -> Result<usize> {
     Ok(requested.next_power_of_two().max(MIN_ALLOC_SIZE))
 }
 
-fn alloc_from_small_slab(slab: &mut Slab, block_size: usize, dm: &impl DirectMap) -> Result<PhysicalAddr> {
+// Fill the canary after the first `size` bytes of the block and record
+// `size` in the block's last word.
+#[cfg(feature = "redzone")]
+fn arm_redzone(addr: PhysicalAddr, size: usize, block_size: usize, dm: &impl DirectMap) {
+    let block = addr.to_virtual(dm).as_ptr::<u8>();
+    unsafe {
+        write_bytes(block.add(size), REDZONE_BYTE, REDZONE_SIZE);
+        let trailer = block.add(block_size - size_of::<u64>()) as *mut u64;
+        trailer.write_unaligned(size as u64);
+    }
+}
+
+#[cfg(feature = "redzone")]
+fn check_redzone(addr: PhysicalAddr, block_size: usize, dm: &impl DirectMap) -> Result<()> {
+    let block = addr.to_virtual(dm).as_ptr::<u8>();
+    let size = unsafe { (block.add(block_size - size_of::<u64>()) as *const u64).read_unaligned() };
+    let intact = usize::try_from(size).is_ok_and(|size| {
+        size <= block_size - REDZONE_OVERHEAD
+            && unsafe { core::slice::from_raw_parts(block.add(size), REDZONE_SIZE) }
+                .iter()
+                .all(|&byte| byte == REDZONE_BYTE)
+    });
+    if intact {
+        Ok(())
+    } else {
+        Err(MemoryError::RedzoneCorrupted {
+            addr: addr.as_usize(),
+        })
+    }
+}
+
+fn alloc_from_small_slab(
+    slab: &mut Slab,
+    block_size: usize,
+    dm: &impl DirectMap,
+) -> Result<PhysicalAddr> {
     let idx = slab.free_head;
     if idx == FREE_LIST_END {
         return Err(MemoryError::SlabEmpty);
@@ -421,24 +594,47 @@ fn alloc_from_small_slab(slab: &mut Slab, block_size: usize, dm: &impl DirectMap
     Ok(slab.base.add(offset))
 }
 
+#[cfg(feature = "alloc-debug")]
+fn slab_free_list_contains(slab: &Slab, idx: u16, dm: &impl DirectMap) -> bool {
+    let mut next = slab.free_head;
+    for _ in 0..slab.free_count {
+        if next == idx {
+            return true;
+        }
+        next = unsafe { *small_slab_link_ptr(slab, next, dm) };
+    }
+    false
+}
+
 unsafe fn small_slab_link_ptr(slab: &Slab, idx: u16, map: &impl DirectMap) -> *mut u16 {
     let addr = slab.base.as_usize() + idx as usize * (PAGE_SIZE / slab.capacity as usize);
     PhysicalAddr::new(addr).to_virtual(map).as_ptr::<u16>()
 }
 
 #[inline(always)]
-const fn hash_page_base(page_base: usize) -> usize {
-    ((page_base >> 21).wrapping_mul(0x9E37_79B9_7F4A_7C15usize)) >> 2
+fn owner_ptr(map: PhysicalAddr, page_base: usize, dm: &impl DirectMap) -> *mut u32 {
+    map.add(page_base / PAGE_SIZE * size_of::<u32>())
+        .to_virtual(dm)
+        .as_ptr::<u32>()
 }
 
-#[inline(always)]
-const fn to_page_plus_one(page_base: usize) -> u32 {
-    (page_base / PAGE_SIZE + 1) as u32
+const fn class_stats() -> [ClassStats; SMALL_CLASS_COUNT] {
+    let mut classes = [ClassStats {
+        block_size: 0,
+        slabs: 0,
+        live_objects: 0,
+    }; SMALL_CLASS_COUNT];
+    let mut i = 0;
+    while i < SMALL_CLASS_COUNT {
+        classes[i].block_size = SMALL_CLASS_SIZES[i] as usize;
+        i += 1;
+    }
+    classes
 }
 
 #[inline(always)]
-const fn from_page_plus_one(page_plus_one: u32) -> usize {
-    (page_plus_one as usize - 1) * PAGE_SIZE
+fn chunk_ptr(chunk: PhysicalAddr, dm: &impl DirectMap) -> *mut SlabChunk {
+    chunk.to_virtual(dm).as_ptr::<SlabChunk>()
 }
 
 pub struct KernelAllocator<'i, DM: DirectMap>(spin::Mutex<KernelAllocatorImpl<'i, DM>>);
@@ -452,25 +648,114 @@ impl<'i, DM: DirectMap> KernelAllocator<'i, DM> {
         self.0.lock().alloc(size)
     }
 
+    /// Allocate `size` bytes starting on a multiple of `align`, which must be
+    /// a power of two no larger than PAGE_SIZE.
+    pub fn alloc_aligned(&self, size: usize, align: usize) -> Result<PhysicalAddr> {
+        self.0.lock().alloc_aligned(size, align)
+    }
+
     pub fn free(&self, ptr: PhysicalAddr, _size: usize) -> Result<()> {
-        self.0.lock().free(ptr)
+        check_free(self.0.lock().free(ptr))
     }
 
     pub fn calloc(&self, size: usize) -> Result<PhysicalAddr> {
         self.0.lock().calloc(size)
     }
 
+    /// Resize the block at `ptr` to `new_size` bytes, keeping its contents up
+    /// to the smaller of the two sizes. The block stays in place when the new
+    /// size falls in the same size class; otherwise a new block is allocated,
+    /// filled and returned, and the old one freed. On failure the old block
+    /// is left untouched.
+    pub fn realloc(&self, ptr: PhysicalAddr, new_size: usize) -> Result<PhysicalAddr> {
+        self.0.lock().realloc(ptr, new_size)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.0.lock().stats
+    }
+
+    /// Like [`KernelAllocator::stats`], but gives up instead of waiting if
+    /// the allocator is locked, as it may be when the kernel panics.
+    pub fn try_stats(&self) -> Option<Stats> {
+        self.0.try_lock().map(|inner| inner.stats)
+    }
+
     pub fn direct_map(&self) -> &'i DM {
         self.0.lock().dm
     }
 }
 
+// Process code allocates with interrupts enabled, and a process preempted
+// while holding the heap lock would leave every syscall that allocates
+// spinning on it with interrupts masked. The lock is only taken with them
+// off.
+unsafe impl<DM: DirectMap> GlobalAlloc for KernelAllocator<'_, DM> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        arch::without_interrupts(|| {
+            let mut inner = self.0.lock();
+            match inner.alloc_aligned(layout.size(), layout.align()) {
+                Ok(addr) => addr.to_virtual(inner.dm).as_ptr(),
+                Err(_) => null_mut(),
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        arch::without_interrupts(|| {
+            let mut inner = self.0.lock();
+            let addr = VirtualAddr::new(ptr as usize)
+                .to_physical(inner.dm)
+                .expect("kernel heap pointer outside the direct map");
+            inner.free(addr).expect("free kernel heap block");
+        })
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        arch::without_interrupts(|| {
+            let mut inner = self.0.lock();
+            let addr = VirtualAddr::new(ptr as usize)
+                .to_physical(inner.dm)
+                .expect("kernel heap pointer outside the direct map");
+            match inner.realloc(addr, new_size.max(layout.align())) {
+                Ok(addr) => addr.to_virtual(inner.dm).as_ptr(),
+                Err(_) => null_mut(),
+            }
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::memory::address::KernelDirectMap;
-
     use super::*;
 
+    // Backs the low physical addresses palloc hands out with zeroed host
+    // memory, so the allocator can write its metadata.
+    struct HeapDirectMap(Vec<u8>);
+
+    impl HeapDirectMap {
+        fn new() -> Self {
+            Self(vec![0; 1 << 32])
+        }
+    }
+
+    impl DirectMap for HeapDirectMap {
+        fn p2v(&self, paddr: PhysicalAddr) -> VirtualAddr {
+            assert!(paddr.as_usize() < self.0.len());
+            VirtualAddr::new(self.0.as_ptr() as usize + paddr.as_usize())
+        }
+
+        fn v2p(&self, vaddr: VirtualAddr) -> Result<PhysicalAddr> {
+            vaddr
+                .as_usize()
+                .checked_sub(self.0.as_ptr() as usize)
+                .map(PhysicalAddr::new)
+                .ok_or(MemoryError::VirtualToPhysical {
+                    addr: vaddr.as_usize(),
+                })
+        }
+    }
+
     #[test]
     fn class_rounding_works() {
         assert_eq!(size_to_class(0).unwrap(), 1024);
@@ -500,8 +785,9 @@ mod tests {
     }
 
     #[test]
+    #[cfg(not(feature = "redzone"))]
     fn kmalloc_large_is_contiguous_and_reused() {
-        let dm = KernelDirectMap;
+        let dm = HeapDirectMap::new();
         let page_alloc = Box::new(PageAllocator::new());
         let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
 
@@ -518,7 +804,7 @@ mod tests {
 
     #[test]
     fn kmalloc_large_allocations_do_not_overlap() {
-        let dm = KernelDirectMap;
+        let dm = HeapDirectMap::new();
         let page_alloc = Box::new(PageAllocator::new());
         let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
 
@@ -534,8 +820,9 @@ mod tests {
     }
 
     #[test]
+    #[cfg(not(feature = "redzone"))]
     fn kmalloc_large_free_and_realloc_same_class_reuses_address() {
-        let dm = KernelDirectMap;
+        let dm = HeapDirectMap::new();
         let page_alloc = Box::new(PageAllocator::new());
         let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
 
@@ -547,4 +834,284 @@ mod tests {
         let c = alloc.alloc(1 << 24).unwrap();
         assert_eq!(c.as_u64(), b.as_u64());
     }
+
+    #[test]
+    #[cfg(not(feature = "redzone"))]
+    fn kmalloc_small_slabs_are_not_capped() {
+        let dm = HeapDirectMap::new();
+        let page_alloc = Box::new(PageAllocator::new());
+        let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
+
+        // One 2 MiB block per slab, well past the old 512 slabs per class.
+        let blocks: Vec<_> = (0..600).map(|_| alloc.alloc(PAGE_SIZE).unwrap()).collect();
+        let used = page_alloc.get_stats().used_pages;
+
+        for &block in &blocks {
+            alloc.free(block, PAGE_SIZE).unwrap();
+        }
+        let idle = page_alloc.get_stats().used_pages;
+        assert_eq!(used - idle, blocks.len());
+
+        // Released descriptors and pages are reused on the next round.
+        for _ in 0..blocks.len() {
+            alloc.alloc(PAGE_SIZE).unwrap();
+        }
+        assert_eq!(page_alloc.get_stats().used_pages, used);
+    }
+
+    #[test]
+    fn kmalloc_large_allocations_are_not_capped() {
+        let dm = HeapDirectMap::new();
+        let page_alloc = Box::new(PageAllocator::new());
+        let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
+
+        let blocks: Vec<_> = (0..300).map(|_| alloc.alloc(1 << 22).unwrap()).collect();
+        for &block in &blocks {
+            alloc.free(block, 1 << 22).unwrap();
+        }
+
+        // Only the page owner map stays behind.
+        assert_eq!(page_alloc.get_stats().used_pages, 1);
+    }
+
+    #[test]
+    fn stats_count_slabs_objects_and_bytes() {
+        let dm = HeapDirectMap::new();
+        let page_alloc = Box::new(PageAllocator::new());
+        let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
+
+        let small: Vec<_> = (0..3).map(|_| alloc.alloc(1500).unwrap()).collect();
+        let large = alloc.alloc(3 * PAGE_SIZE).unwrap();
+        let stats = alloc.stats();
+        let class = stats.classes[1];
+        assert_eq!(
+            (class.block_size, class.slabs, class.live_objects),
+            (2048, 1, 3)
+        );
+        assert_eq!((stats.large_objects, stats.large_pages), (1, 4));
+        assert_eq!(stats.live_objects(), 4);
+        assert_eq!(stats.bytes_in_use(), 3 * 2048 + 4 * PAGE_SIZE);
+        assert_eq!(alloc.try_stats(), Some(stats));
+
+        let report = format!("{stats}");
+        assert!(report.starts_with("kmalloc: 4 objects, "));
+        assert!(report.contains("\n     2048 B: 1 slabs, 3 objects"));
+        assert!(report.contains("\n  large: 1 objects, 4 pages"));
+
+        for block in small {
+            alloc.free(block, 1500).unwrap();
+        }
+        alloc.free(large, 3 * PAGE_SIZE).unwrap();
+        let stats = alloc.stats();
+        assert_eq!(stats.classes[1].slabs, 0);
+        assert_eq!((stats.live_objects(), stats.bytes_in_use()), (0, 0));
+        assert_eq!(format!("{stats}"), "kmalloc: 0 objects, 0 bytes in use");
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "alloc-debug", feature = "redzone")))]
+    fn kmalloc_small_blocks_are_reused_and_freed_back() {
+        let dm = HeapDirectMap::new();
+        let page_alloc = Box::new(PageAllocator::new());
+        let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
+
+        let a = alloc.alloc(1024).unwrap();
+        let b = alloc.alloc(1000).unwrap();
+        assert_eq!(a.as_usize() & PAGE_MASK, b.as_usize() & PAGE_MASK);
+        assert_ne!(a, b);
+
+        alloc.free(a, 1024).unwrap();
+        assert_eq!(alloc.alloc(1024).unwrap(), a);
+
+        assert!(matches!(
+            alloc.free(a.add(1), 1024),
+            Err(MemoryError::SlabAlignmentMismatch { .. })
+        ));
+        alloc.free(a, 1024).unwrap();
+        alloc.free(b, 1024).unwrap();
+        assert!(matches!(
+            alloc.free(a, 1024),
+            Err(MemoryError::UnknownAllocation { .. })
+        ));
+
+        // The owner map and the descriptor chunk stay allocated.
+        assert_eq!(page_alloc.get_stats().used_pages, 2);
+    }
+
+    #[test]
+    fn kmalloc_aligned_allocations_start_on_the_boundary() {
+        let dm = HeapDirectMap::new();
+        let page_alloc = Box::new(PageAllocator::new());
+        let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
+
+        // Offset the slab so an unaligned block would be handed out next.
+        let _small = alloc.alloc(1024).unwrap();
+        for align in [4096, 1 << 16, PAGE_SIZE] {
+            let block = alloc.alloc_aligned(100, align).unwrap();
+            assert_eq!(block.as_usize() % align, 0, "align {align:#x}");
+        }
+        let large = alloc.alloc_aligned(3 << 20, PAGE_SIZE).unwrap();
+        assert_eq!(large.as_usize() % PAGE_SIZE, 0);
+
+        for align in [0, 3, 2 * PAGE_SIZE] {
+            assert!(matches!(
+                alloc.alloc_aligned(100, align),
+                Err(MemoryError::UnsupportedAlignment { .. })
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "alloc-debug", feature = "redzone")))]
+    fn krealloc_stays_in_place_within_class_and_moves_otherwise() {
+        let dm = HeapDirectMap::new();
+        let page_alloc = Box::new(PageAllocator::new());
+        let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
+
+        let a = alloc.alloc(1500).unwrap();
+        let bytes = |addr: PhysicalAddr, len| unsafe {
+            core::slice::from_raw_parts_mut(addr.to_virtual(&dm).as_ptr::<u8>(), len)
+        };
+        bytes(a, 1500).fill(0xab);
+        assert_eq!(alloc.realloc(a, 2048).unwrap(), a);
+        assert_eq!(alloc.realloc(a, 1025).unwrap(), a);
+
+        // Growing into a large allocation carries the contents over.
+        let b = alloc.realloc(a, 3 << 20).unwrap();
+        assert_ne!(b, a);
+        assert!(bytes(b, 1500).iter().all(|&byte| byte == 0xab));
+        assert!(matches!(
+            alloc.free(a, 2048),
+            Err(MemoryError::UnknownAllocation { .. })
+        ));
+
+        // Shrinking keeps the prefix that still fits.
+        let c = alloc.realloc(b, 100).unwrap();
+        assert!(bytes(c, 100).iter().all(|&byte| byte == 0xab));
+        alloc.free(c, 100).unwrap();
+
+        assert!(matches!(
+            alloc.realloc(c, 4096),
+            Err(MemoryError::UnknownAllocation { .. })
+        ));
+        assert!(matches!(
+            alloc.realloc(PhysicalAddr::new(0), MAX_ALLOC_SIZE + 1),
+            Err(MemoryError::UnknownAllocation { .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(not(feature = "alloc-debug"))]
+    fn scrubbing_zeroes_freed_blocks_and_pages() {
+        let dm: &'static HeapDirectMap = Box::leak(Box::new(HeapDirectMap::new()));
+        let page_alloc = Box::new(PageAllocator::new());
+        page_alloc.scrub_on_free(dm);
+        let alloc = Box::new(KernelAllocator::new(dm, &page_alloc));
+        let bytes = |addr: PhysicalAddr, len| unsafe {
+            core::slice::from_raw_parts_mut(addr.to_virtual(dm).as_ptr::<u8>(), len)
+        };
+
+        // A second block keeps the slab alive once the first is freed. The
+        // first two bytes of a free block hold the free-list link.
+        let small = alloc.alloc(4096).unwrap();
+        let _keep = alloc.alloc(4096).unwrap();
+        bytes(small, 4096).fill(0xab);
+        alloc.free(small, 4096).unwrap();
+        assert!(bytes(small, 4096)[2..].iter().all(|&byte| byte == 0));
+
+        let large = alloc.alloc(3 << 20).unwrap();
+        bytes(large, 3 << 20).fill(0xab);
+        alloc.free(large, 3 << 20).unwrap();
+        assert!(bytes(large, 4 << 20).iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    #[cfg(feature = "alloc-debug")]
+    fn debug_kmalloc_poisons_freed_memory() {
+        let dm = HeapDirectMap::new();
+        let page_alloc = Box::new(PageAllocator::new());
+        let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
+        let bytes = |addr: PhysicalAddr, len| unsafe {
+            core::slice::from_raw_parts(addr.to_virtual(&dm).as_ptr::<u8>(), len)
+        };
+
+        // A second block keeps the slab alive once the first is freed. The
+        // first two bytes of a free block hold the free-list link.
+        let small = alloc.alloc(4096).unwrap();
+        let _keep = alloc.alloc(4096).unwrap();
+        alloc.free(small, 4096).unwrap();
+        assert!(
+            bytes(small, 4096)[2..]
+                .iter()
+                .all(|&byte| byte == POISON_FREE)
+        );
+
+        let large = alloc.alloc(3 << 20).unwrap();
+        alloc.free(large, 3 << 20).unwrap();
+        assert!(
+            bytes(large, 4 << 20)
+                .iter()
+                .all(|&byte| byte == POISON_FREE)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "alloc-debug")]
+    #[should_panic(expected = "double free")]
+    fn debug_kmalloc_panics_on_double_free() {
+        let dm = HeapDirectMap::new();
+        let page_alloc = Box::new(PageAllocator::new());
+        let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
+
+        let a = alloc.alloc(1024).unwrap();
+        let _keep = alloc.alloc(1024).unwrap();
+        alloc.free(a, 1024).unwrap();
+        let _ = alloc.free(a, 1024);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc-debug")]
+    #[should_panic(expected = "bad free")]
+    fn debug_kmalloc_panics_on_unknown_pointer() {
+        let dm = HeapDirectMap::new();
+        let page_alloc = Box::new(PageAllocator::new());
+        let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
+
+        let a = alloc.alloc(1024).unwrap();
+        let _ = alloc.free(a.add(PAGE_SIZE), 1024);
+    }
+
+    #[test]
+    #[cfg(all(feature = "redzone", not(feature = "alloc-debug")))]
+    fn redzone_catches_writes_past_the_object() {
+        let dm = HeapDirectMap::new();
+        let page_alloc = Box::new(PageAllocator::new());
+        let alloc = Box::new(KernelAllocator::new(&dm, &page_alloc));
+        let bytes = |addr: PhysicalAddr, len: usize| unsafe {
+            core::slice::from_raw_parts_mut(addr.to_virtual(&dm).as_ptr::<u8>(), len)
+        };
+
+        // Filling the object exactly is fine, even when it shares its class
+        // with the redzone.
+        let exact = alloc.alloc(1000).unwrap();
+        bytes(exact, 1000).fill(0xff);
+        alloc.free(exact, 1000).unwrap();
+
+        for size in [1000, 3 * PAGE_SIZE] {
+            let block = alloc.alloc(size).unwrap();
+            bytes(block, size + 1)[size] = 0;
+            assert_eq!(
+                alloc.free(block, size),
+                Err(MemoryError::RedzoneCorrupted {
+                    addr: block.as_usize()
+                })
+            );
+        }
+
+        // Growing in place moves the canary to the new end.
+        let block = alloc.alloc(100).unwrap();
+        assert_eq!(alloc.realloc(block, 900).unwrap(), block);
+        bytes(block, 900).fill(0xff);
+        alloc.free(block, 900).unwrap();
+    }
 }
@@ -1,2 +1,20 @@
 pub mod kmalloc;
 pub mod palloc;
+pub mod pshare;
+
+use crate::memory::errors::Result;
+
+/// Byte that freed kmalloc memory is filled with under `alloc-debug`, so a
+/// read through a dangling pointer stands out.
+#[cfg(feature = "alloc-debug")]
+pub const POISON_FREE: u8 = 0x6b;
+
+// A bad free is a bug in the caller. Normally the error is handed back;
+// `alloc-debug` stops the kernel on the spot with the error that caught it.
+fn check_free(result: Result<()>) -> Result<()> {
+    #[cfg(feature = "alloc-debug")]
+    if let Err(err) = result {
+        panic!("bad free: {err}");
+    }
+    result
+}
@@ -1,11 +1,48 @@
+use super::check_free;
+use crate::boot::MemoryMap;
 use crate::memory::{
-    address::PhysicalAddr,
+    address::{DirectMap, PhysicalAddr},
     constants::{MAX_PHYSICAL_ADDR, PAGE_SIZE, PALLOC_FIRST_PAGE},
     errors::{MemoryError, Result},
 };
 
-const BITMAP_SIZE: usize = MAX_PHYSICAL_ADDR / PAGE_SIZE / 64;
-const PAGE_COUNT: usize = MAX_PHYSICAL_ADDR / PAGE_SIZE;
+const PAGE_COUNT: usize = (MAX_PHYSICAL_ADDR + 1) / PAGE_SIZE;
+const BITMAP_SIZE: usize = PAGE_COUNT.div_ceil(64);
+
+// Blocks go up to 2^MAX_ORDER pages (2 GiB).
+const MAX_ORDER: usize = 10;
+const ORDERS: usize = MAX_ORDER + 1;
+const FREE_WORDS: usize = free_area_offset(ORDERS);
+const SUMMARY_WORDS: usize = FREE_WORDS.div_ceil(64);
+
+const MAX_SHRINKERS: usize = 8;
+
+/// Called under memory pressure with the number of pages the allocator is
+/// short of; frees what it can and returns how many pages it gave back.
+/// Shrinkers run without the allocator locked, but an allocation they make
+/// does not call them again.
+pub type Shrinker = fn(pages: usize) -> usize;
+
+/// Called once enough pages have been freed since free memory was last
+/// reported, to pass the free blocks on through
+/// [`PageAllocator::report_free`]. Runs without the allocator locked.
+pub type FreeReporter = fn(palloc: &PageAllocator);
+
+// Contiguous runs are for the few device buffers that need them, so a short
+// table of live runs is enough.
+const MAX_CONTIGUOUS_RUNS: usize = 64;
+
+// Word offset of each order's bitmap in `free`; order k has one bit per
+// aligned block of 2^k pages that fits below PAGE_COUNT.
+const fn free_area_offset(order: usize) -> usize {
+    let mut offset = 0;
+    let mut k = 0;
+    while k < order {
+        offset += (PAGE_COUNT >> k).div_ceil(64);
+        k += 1;
+    }
+    offset
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Stats {
@@ -16,13 +53,45 @@ pub struct Stats {
     pub allocatable_limit_bytes: usize,
 }
 
+/// Buddy allocator over 2 MiB pages. Every free block is recorded at exactly
+/// one order. The allocator has to be usable before anything is mapped, so
+/// each order's free list is a bitmap over block indices. A summary bitmap
+/// with one bit per non-empty word finds the lowest free block of an order
+/// without walking the empty words before it. Allocations split the
+/// lowest-addressed block that is big enough, which keeps memory packed at
+/// low addresses. Contiguous runs are carved out of whatever free blocks
+/// cover them and kept in a table, so each run is freed as one allocation.
 #[repr(align(4096))]
 #[repr(C)]
 struct PageAllocatorImpl {
-    bitmap: [u64; BITMAP_SIZE],
+    used: [u64; BITMAP_SIZE],
+    free: [u64; FREE_WORDS],
+    // Bit `w` is set when `free[w]` is non-zero.
+    nonempty: [u64; SUMMARY_WORDS],
+    free_count: [usize; ORDERS],
+    used_pages: usize,
     peak_memory_usage: usize,
-    #[cfg(feature = "bench-memory-limit")]
-    memory_limit_page_exclusive: usize,
+    total_pages: usize,
+    // One past the highest page ever handed to the allocator.
+    end_page: usize,
+    // Live contiguous runs as (first page, page count); the first
+    // `run_count` entries are in use.
+    runs: [(usize, usize); MAX_CONTIGUOUS_RUNS],
+    run_count: usize,
+    shrinkers: [Option<Shrinker>; MAX_SHRINKERS],
+    // Shrinkers run once as free pages drop below this, and again only
+    // after free pages have recovered past it.
+    low_memory_pages: usize,
+    below_low_memory: bool,
+    shrinking: bool,
+    // Set when freed pages are zeroed before they can be handed out again.
+    scrub: Option<&'static (dyn DirectMap + Sync)>,
+    reporter: Option<FreeReporter>,
+    // The reporter runs once this many pages have been freed since free
+    // memory was last reported.
+    report_threshold: usize,
+    freed_since_report: usize,
+    reporting: bool,
 }
 
 impl PageAllocatorImpl {
@@ -30,125 +99,392 @@ impl PageAllocatorImpl {
         PALLOC_FIRST_PAGE.as_usize() / PAGE_SIZE
     }
 
+    const fn empty() -> Self {
+        Self {
+            used: [0; BITMAP_SIZE],
+            free: [0; FREE_WORDS],
+            nonempty: [0; SUMMARY_WORDS],
+            free_count: [0; ORDERS],
+            used_pages: 0,
+            peak_memory_usage: 0,
+            total_pages: 0,
+            end_page: 0,
+            runs: [(0, 0); MAX_CONTIGUOUS_RUNS],
+            run_count: 0,
+            shrinkers: [None; MAX_SHRINKERS],
+            low_memory_pages: 0,
+            below_low_memory: false,
+            shrinking: false,
+            scrub: None,
+            reporter: None,
+            report_threshold: 0,
+            freed_since_report: 0,
+            reporting: false,
+        }
+    }
+
     const fn new() -> Self {
-        let mut bitmap = [0; BITMAP_SIZE];
-        let mut page = 0;
-        let reserved_pages = Self::reserved_pages();
+        Self::with_page_limit(PAGE_COUNT)
+    }
 
-        while page < reserved_pages {
-            let word = page / 64;
-            let bit = page % 64;
-            bitmap[word] |= 1 << bit;
-            page += 1;
+    // Pages from the first allocatable one up to `page_limit` start out free.
+    const fn with_page_limit(page_limit: usize) -> Self {
+        let mut inner = Self::empty();
+        inner.add_free_range(Self::reserved_pages(), page_limit);
+        inner
+    }
+
+    // Hand pages `start..end` to the allocator.
+    const fn add_free_range(&mut self, start: usize, end: usize) {
+        self.insert_free_range(start, end);
+        self.total_pages += end.saturating_sub(start);
+        if end > self.end_page {
+            self.end_page = end;
         }
+    }
 
-        Self {
-            bitmap,
-            peak_memory_usage: 0,
-            #[cfg(feature = "bench-memory-limit")]
-            memory_limit_page_exclusive: PAGE_COUNT,
+    // Record pages `start..end` as free, carved into the largest aligned
+    // blocks that fit.
+    const fn insert_free_range(&mut self, start: usize, end: usize) {
+        let mut page = start;
+        while page < end {
+            let mut order = MAX_ORDER;
+            while !page.is_multiple_of(1 << order) || page + (1 << order) > end {
+                order -= 1;
+            }
+            self.insert_free(page, order);
+            page += 1 << order;
+        }
+    }
+
+    // Only whole pages of RAM count; anything below the first allocatable
+    // page or past the direct map is skipped. Regions must not overlap.
+    fn add_memory_map(&mut self, map: &MemoryMap) {
+        for region in map.ram() {
+            let start = (region.base as usize)
+                .div_ceil(PAGE_SIZE)
+                .max(Self::reserved_pages());
+            let end = (region.end() as usize / PAGE_SIZE).min(PAGE_COUNT);
+            if start < end {
+                self.add_free_range(start, end);
+            }
         }
     }
 
     #[cfg(feature = "bench-memory-limit")]
     fn with_memory_limit(memory_limit: usize) -> Self {
-        let mut inner = Self::new();
-        let reserved_pages = Self::reserved_pages();
         let limit_pages = memory_limit.div_ceil(PAGE_SIZE);
-        inner.memory_limit_page_exclusive =
-            reserved_pages.saturating_add(limit_pages).min(PAGE_COUNT);
-        inner
+        Self::with_page_limit(
+            Self::reserved_pages()
+                .saturating_add(limit_pages)
+                .min(PAGE_COUNT),
+        )
     }
 
     fn alloc(&mut self, pages: usize) -> Result<PhysicalAddr> {
         if pages == 0 {
             return Err(MemoryError::InvalidPageCount { pages });
         }
+        let order = pages.next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return Err(MemoryError::OutOfMemory);
+        }
 
-        let search_limit = self.page_search_limit();
-        if pages > search_limit {
+        let Some((start, mut found)) = (order..ORDERS)
+            .filter_map(|k| self.lowest_free(k).map(|block| (block, k)))
+            .min()
+        else {
             return Err(MemoryError::OutOfMemory);
+        };
+        self.remove_free(start, found);
+        while found > order {
+            found -= 1;
+            self.insert_free(start + (1 << found), found);
         }
 
-        let mut run_start = 0;
-        let mut run_len = 0;
+        for page in start..start + pages {
+            self.set_used(page, true);
+        }
+        self.used_pages += pages;
+        // Give back the tail of the block that was only needed for rounding.
+        for page in start + pages..start + (1 << order) {
+            self.release(page);
+        }
 
-        for page in 0..search_limit {
-            if self.is_page_used(page) {
-                run_len = 0;
-                continue;
-            }
+        self.record_peak(start + pages);
+        Ok(PhysicalAddr::new(start * PAGE_SIZE))
+    }
 
-            if run_len == 0 {
-                run_start = page;
-            }
+    // Unlike `alloc`, the run is not rounded up to a buddy block, so it fits
+    // any free stretch of `pages` pages that starts on the alignment, even
+    // one split across several blocks.
+    fn alloc_contiguous(&mut self, pages: usize, align: usize) -> Result<PhysicalAddr> {
+        if pages == 0 {
+            return Err(MemoryError::InvalidPageCount { pages });
+        }
+        if !align.is_power_of_two() || align > MAX_PHYSICAL_ADDR + 1 {
+            return Err(MemoryError::UnsupportedAlignment {
+                align,
+                max: MAX_PHYSICAL_ADDR + 1,
+            });
+        }
+        if self.run_count == MAX_CONTIGUOUS_RUNS {
+            return Err(MemoryError::OutOfMemory);
+        }
+        let align_pages = (align / PAGE_SIZE).max(1);
 
-            run_len += 1;
-            if run_len == pages {
-                self.mark_pages(run_start, pages, true);
-                let reserved_pages = Self::reserved_pages();
-                let footprint_pages = (run_start + pages).saturating_sub(reserved_pages);
-                self.peak_memory_usage = self.peak_memory_usage.max(footprint_pages * PAGE_SIZE);
-                return Ok(PhysicalAddr::new(run_start * PAGE_SIZE));
-            }
+        let start = self.find_free_run(pages, align_pages)?;
+        self.take_free_range(start, start + pages);
+        for page in start..start + pages {
+            self.set_used(page, true);
         }
+        self.used_pages += pages;
+        self.runs[self.run_count] = (start, pages);
+        self.run_count += 1;
 
-        Err(MemoryError::OutOfMemory)
+        self.record_peak(start + pages);
+        Ok(PhysicalAddr::new(start * PAGE_SIZE))
     }
 
-    fn page_search_limit(&self) -> usize {
-        #[cfg(feature = "bench-memory-limit")]
-        {
-            return self.memory_limit_page_exclusive;
+    // Lowest aligned start of `pages` free pages. Free stretches are stepped
+    // over a whole block at a time.
+    fn find_free_run(&self, pages: usize, align_pages: usize) -> Result<usize> {
+        let mut start = Self::reserved_pages().next_multiple_of(align_pages);
+        let mut page = start;
+        while start + pages <= self.end_page {
+            if page >= start + pages {
+                return Ok(start);
+            }
+            match self.free_block_containing(page) {
+                Some((block, order)) => page = block + (1 << order),
+                None => {
+                    start = (page + 1).next_multiple_of(align_pages);
+                    page = start;
+                }
+            }
         }
+        Err(MemoryError::OutOfMemory)
+    }
 
-        #[cfg(not(feature = "bench-memory-limit"))]
-        {
-            PAGE_COUNT
+    fn free_block_containing(&self, page: usize) -> Option<(usize, usize)> {
+        (0..ORDERS)
+            .map(|order| (page & !((1 << order) - 1), order))
+            .find(|&(block, order)| self.is_free(block, order))
+    }
+
+    // Pull pages `start..end`, which must all be free, out of the free
+    // blocks, giving back the parts of those blocks outside the range.
+    fn take_free_range(&mut self, start: usize, end: usize) {
+        let mut page = start;
+        while page < end {
+            let (block, order) = self
+                .free_block_containing(page)
+                .expect("page in a free run is free");
+            let block_end = block + (1 << order);
+            self.remove_free(block, order);
+            self.insert_free_range(block, page);
+            self.insert_free_range(end.min(block_end), block_end);
+            page = block_end;
         }
     }
 
+    fn record_peak(&mut self, end_page: usize) {
+        let footprint_pages = end_page.saturating_sub(Self::reserved_pages());
+        self.peak_memory_usage = self.peak_memory_usage.max(footprint_pages * PAGE_SIZE);
+    }
+
+    // Pages are freed one at a time, whatever size they were allocated with,
+    // and merge with their buddies as those become free. A contiguous run is
+    // the exception: it is freed whole from its first page, and none of its
+    // other pages can be freed alone.
     fn free(&mut self, addr: PhysicalAddr) -> Result<()> {
-        let page_index = addr.as_usize() / PAGE_SIZE;
-        self.mark_pages(page_index, 1, false);
+        // Normally any address inside a page frees it; debug builds insist on
+        // the address the page was handed out at.
+        #[cfg(feature = "alloc-debug")]
+        if !addr.as_usize().is_multiple_of(PAGE_SIZE) {
+            return Err(MemoryError::UnknownAllocation {
+                addr: addr.as_usize(),
+            });
+        }
+        let page = addr.as_usize() / PAGE_SIZE;
+        if page >= PAGE_COUNT || !self.is_used(page) {
+            return Err(MemoryError::UnknownAllocation {
+                addr: addr.as_usize(),
+            });
+        }
+        let live_runs = &self.runs[..self.run_count];
+        let Some(run) = live_runs
+            .iter()
+            .position(|&(start, pages)| (start..start + pages).contains(&page))
+        else {
+            self.free_page(page);
+            return Ok(());
+        };
+        let (start, pages) = self.runs[run];
+        if page != start {
+            return Err(MemoryError::UnknownAllocation {
+                addr: addr.as_usize(),
+            });
+        }
+        self.run_count -= 1;
+        self.runs[run] = self.runs[self.run_count];
+        for page in start..start + pages {
+            self.free_page(page);
+        }
         Ok(())
     }
 
-    fn is_page_used(&self, page_index: usize) -> bool {
-        let word_index = page_index / 64;
-        let bit_index = page_index % 64;
-        (self.bitmap[word_index] & (1 << bit_index)) != 0
+    fn free_page(&mut self, page: usize) {
+        if let Some(dm) = self.scrub {
+            let base = PhysicalAddr::new(page * PAGE_SIZE);
+            unsafe { core::ptr::write_bytes(dm.p2v(base).as_ptr::<u8>(), 0, PAGE_SIZE) };
+        }
+        self.set_used(page, false);
+        self.used_pages -= 1;
+        self.freed_since_report += 1;
+        self.release(page);
     }
 
-    fn mark_pages(&mut self, start_page: usize, pages: usize, used: bool) {
-        for page in start_page..start_page + pages {
-            let word = page / 64;
-            let bit = page % 64;
-            if used {
-                self.bitmap[word] |= 1 << bit;
-            } else {
-                self.bitmap[word] &= !(1 << bit);
+    fn release(&mut self, page: usize) {
+        let mut block = page;
+        let mut order = 0;
+        while order < MAX_ORDER {
+            let buddy = block ^ (1 << order);
+            if !self.is_free(buddy, order) {
+                break;
             }
+            self.remove_free(buddy, order);
+            block = block.min(buddy);
+            order += 1;
         }
+        self.insert_free(block, order);
     }
 
-    fn used_pages(&self) -> usize {
-        let mut used = 0usize;
-        for &word in &self.bitmap {
-            used += word.count_ones() as usize;
+    // The order's words are contiguous and it has a free block, so the first
+    // non-empty word at or past its base belongs to it.
+    fn lowest_free(&self, order: usize) -> Option<usize> {
+        if self.free_count[order] == 0 {
+            return None;
         }
+        let base = free_area_offset(order);
+        let mut summary = base / 64;
+        let mut bits = self.nonempty[summary] & (u64::MAX << (base % 64));
+        while bits == 0 {
+            summary += 1;
+            bits = self.nonempty[summary];
+        }
+        let word = summary * 64 + bits.trailing_zeros() as usize;
+        let index = (word - base) * 64 + self.free[word].trailing_zeros() as usize;
+        Some(index << order)
+    }
 
-        used.saturating_sub(Self::reserved_pages())
+    const fn insert_free(&mut self, block: usize, order: usize) {
+        let index = block >> order;
+        let word = free_area_offset(order) + index / 64;
+        self.free[word] |= 1 << (index % 64);
+        self.nonempty[word / 64] |= 1 << (word % 64);
+        self.free_count[order] += 1;
+    }
+
+    fn remove_free(&mut self, block: usize, order: usize) {
+        let index = block >> order;
+        let word = free_area_offset(order) + index / 64;
+        self.free[word] &= !(1 << (index % 64));
+        if self.free[word] == 0 {
+            self.nonempty[word / 64] &= !(1 << (word % 64));
+        }
+        self.free_count[order] -= 1;
+    }
+
+    fn is_free(&self, block: usize, order: usize) -> bool {
+        let index = block >> order;
+        index < PAGE_COUNT >> order
+            && self.free[free_area_offset(order) + index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn is_used(&self, page: usize) -> bool {
+        (self.used[page / 64] & (1 << (page % 64))) != 0
+    }
+
+    fn set_used(&mut self, page: usize, used: bool) {
+        if used {
+            self.used[page / 64] |= 1 << (page % 64);
+        } else {
+            self.used[page / 64] &= !(1 << (page % 64));
+        }
+    }
+
+    fn register_shrinker(&mut self, shrinker: Shrinker) -> Result<()> {
+        let slot = self
+            .shrinkers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(MemoryError::TooManyShrinkers { max: MAX_SHRINKERS })?;
+        *slot = Some(shrinker);
+        Ok(())
+    }
+
+    // How many pages shrinkers should release after an allocation of
+    // `pages` ended with `result`, or None when they need not run. A caller
+    // that gets a count must call `end_shrinking` once they are done.
+    fn start_shrinking(&mut self, pages: usize, result: &Result<PhysicalAddr>) -> Option<usize> {
+        if self.shrinking || self.shrinkers.iter().all(Option::is_none) {
+            return None;
+        }
+        let free_pages = self.total_pages - self.used_pages;
+        let below = free_pages < self.low_memory_pages;
+        let crossed = below && !self.below_low_memory;
+        self.below_low_memory = below;
+        let wanted = match result {
+            Err(MemoryError::OutOfMemory) => pages,
+            Ok(_) if crossed => self.low_memory_pages - free_pages,
+            _ => return None,
+        };
+        self.shrinking = true;
+        Some(wanted)
+    }
+
+    fn end_shrinking(&mut self) {
+        self.shrinking = false;
+    }
+
+    // The reporter, when enough has been freed for it to run. A caller that
+    // gets one must clear `reporting` once it returns.
+    fn start_reporting(&mut self) -> Option<FreeReporter> {
+        if self.reporting || self.freed_since_report < self.report_threshold {
+            return None;
+        }
+        let reporter = self.reporter?;
+        self.reporting = true;
+        Some(reporter)
+    }
+
+    // Every free block as its first page and page count, cut off at the
+    // highest page ever allocated: nothing past it has been touched.
+    fn touched_free_blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let touched_end = Self::reserved_pages() + self.peak_memory_usage / PAGE_SIZE;
+        (0..ORDERS)
+            .flat_map(move |order| {
+                let base = free_area_offset(order);
+                (base..free_area_offset(order + 1))
+                    .filter(|&word| self.nonempty[word / 64] & (1 << (word % 64)) != 0)
+                    .flat_map(move |word| {
+                        let bits = self.free[word];
+                        (0..64)
+                            .filter(move |bit| bits & (1 << bit) != 0)
+                            .map(move |bit| ((word - base) * 64 + bit) << order)
+                    })
+                    .map(move |block| (block, block + (1 << order)))
+            })
+            .filter(move |&(start, _)| start < touched_end)
+            .map(move |(start, end)| (start, end.min(touched_end) - start))
     }
 
     fn stats(&self) -> Stats {
-        let used_pages = self.used_pages();
-        let alloc_limit_pages = self
-            .page_search_limit()
-            .saturating_sub(Self::reserved_pages());
+        let alloc_limit_pages = self.total_pages;
         Stats {
-            used_pages,
-            used_bytes: used_pages * PAGE_SIZE,
+            used_pages: self.used_pages,
+            used_bytes: self.used_pages * PAGE_SIZE,
             peak_memory_usage: self.peak_memory_usage,
             allocatable_limit_pages: alloc_limit_pages,
             allocatable_limit_bytes: alloc_limit_pages * PAGE_SIZE,
@@ -159,10 +495,31 @@ impl PageAllocatorImpl {
 pub struct PageAllocator(spin::Mutex<PageAllocatorImpl>);
 
 impl PageAllocator {
+    /// An allocator that owns every page of the direct map past the kernel.
     pub const fn new() -> Self {
         Self(spin::Mutex::new(PageAllocatorImpl::new()))
     }
 
+    /// An allocator with no pages; memory is added from the boot memory map
+    /// with [`PageAllocator::add_memory_map`].
+    pub const fn empty() -> Self {
+        Self(spin::Mutex::new(PageAllocatorImpl::empty()))
+    }
+
+    pub fn add_memory_map(&self, map: &MemoryMap) {
+        self.0.lock().add_memory_map(map)
+    }
+
+    /// Zero every page as it is freed, through `dm`, so nothing a page held
+    /// survives into its next allocation.
+    pub fn scrub_on_free(&self, dm: &'static (dyn DirectMap + Sync)) {
+        self.0.lock().scrub = Some(dm);
+    }
+
+    pub fn scrubs_on_free(&self) -> bool {
+        self.0.lock().scrub.is_some()
+    }
+
     #[cfg(feature = "bench-memory-limit")]
     pub fn with_memory_limit(memory_limit: usize) -> Self {
         Self(spin::Mutex::new(PageAllocatorImpl::with_memory_limit(
@@ -171,11 +528,92 @@ impl PageAllocator {
     }
 
     pub fn alloc(&self, pages: usize) -> Result<PhysicalAddr> {
-        self.0.lock().alloc(pages)
+        self.alloc_with(pages, |inner| inner.alloc(pages))
+    }
+
+    /// Allocate `pages` physically contiguous pages starting on a multiple
+    /// of `align` bytes, for buffers a device reads by physical address. The
+    /// run is a single allocation: freeing its first page frees all of it.
+    pub fn alloc_contiguous(&self, pages: usize, align: usize) -> Result<PhysicalAddr> {
+        self.alloc_with(pages, |inner| inner.alloc_contiguous(pages, align))
+    }
+
+    /// Add `shrinker` to the callbacks run under memory pressure: before an
+    /// allocation gives up with OutOfMemory, and when free pages first drop
+    /// below the low-memory threshold.
+    pub fn register_shrinker(&self, shrinker: Shrinker) -> Result<()> {
+        self.0.lock().register_shrinker(shrinker)
+    }
+
+    /// Run the shrinkers when fewer than `pages` pages are left free.
+    pub fn set_low_memory_threshold(&self, pages: usize) {
+        self.0.lock().low_memory_pages = pages;
+    }
+
+    // Shrinkers free pages themselves, so they are called with the lock
+    // dropped. A failed allocation is retried once if they released any.
+    fn alloc_with(
+        &self,
+        pages: usize,
+        alloc: impl Fn(&mut PageAllocatorImpl) -> Result<PhysicalAddr>,
+    ) -> Result<PhysicalAddr> {
+        let mut inner = self.0.lock();
+        let result = alloc(&mut inner);
+        let Some(wanted) = inner.start_shrinking(pages, &result) else {
+            return result;
+        };
+        let shrinkers = inner.shrinkers;
+        drop(inner);
+
+        let released: usize = shrinkers
+            .iter()
+            .flatten()
+            .map(|shrink| shrink(wanted))
+            .sum();
+        let mut inner = self.0.lock();
+        inner.end_shrinking();
+        match result {
+            Err(_) if released > 0 => alloc(&mut inner),
+            result => result,
+        }
     }
 
+    /// Have `reporter` called as pages are freed, once `threshold` of them
+    /// have been since free memory was last reported.
+    pub fn set_free_reporter(&self, reporter: FreeReporter, threshold: usize) {
+        let mut inner = self.0.lock();
+        inner.reporter = Some(reporter);
+        inner.report_threshold = threshold;
+    }
+
+    /// Hand `report` the free blocks the guest has touched, as their address
+    /// and page count, so the host can take back the memory behind them.
+    /// The allocator stays locked until `report` returns, so none of them
+    /// is handed out while the host is still dropping it; `report` must not
+    /// allocate or free pages.
+    pub fn report_free<R>(
+        &self,
+        report: impl FnOnce(&mut dyn Iterator<Item = (PhysicalAddr, usize)>) -> R,
+    ) -> R {
+        let mut inner = self.0.lock();
+        inner.freed_since_report = 0;
+        let mut blocks = inner
+            .touched_free_blocks()
+            .map(|(page, pages)| (PhysicalAddr::new(page * PAGE_SIZE), pages));
+        report(&mut blocks)
+    }
+
+    // The reporter is called with the lock dropped, as shrinkers are.
     pub fn free(&self, addr: PhysicalAddr) -> Result<()> {
-        self.0.lock().free(addr)
+        let mut inner = self.0.lock();
+        let result = inner.free(addr);
+        let reporter = inner.start_reporting();
+        drop(inner);
+        if let Some(report) = reporter {
+            report(self);
+            self.0.lock().reporting = false;
+        }
+        check_free(result)
     }
 
     pub fn get_stats(&self) -> Stats {
@@ -199,4 +637,278 @@ mod tests {
         let addr3 = allocator.alloc(1).unwrap();
         assert_eq!(addr3, PhysicalAddr::new(first_page)); // should reuse the freed page
     }
+
+    #[test]
+    fn freed_pages_merge_back_into_larger_blocks() {
+        let first = PageAllocatorImpl::reserved_pages();
+        let mut allocator = Box::new(PageAllocatorImpl::with_page_limit(first + 16));
+
+        let pages: Vec<_> = (0..16).map(|_| allocator.alloc(1).unwrap()).collect();
+        assert_eq!(allocator.alloc(1), Err(MemoryError::OutOfMemory));
+        for page in pages {
+            allocator.free(page).unwrap();
+        }
+
+        // Any 16 pages hold an aligned run of 8, which only exists again if
+        // the single pages coalesced.
+        let block = allocator.alloc(8).unwrap();
+        assert_eq!(block.as_usize() % (8 * PAGE_SIZE), 0);
+        assert_eq!(allocator.stats().used_pages, 8);
+    }
+
+    #[test]
+    fn lowest_free_block_matches_a_linear_scan() {
+        let first = PageAllocatorImpl::reserved_pages();
+        let limit = first + 3000;
+        let mut allocator = Box::new(PageAllocatorImpl::with_page_limit(limit));
+        let linear = |allocator: &PageAllocatorImpl, order: usize| {
+            (0..limit >> order)
+                .find(|&index| allocator.is_free(index << order, order))
+                .map(|index| index << order)
+        };
+
+        // A fixed LCG mixes allocations of every size with frees in between.
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut live = Vec::new();
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let roll = (seed >> 33) as usize;
+            if roll.is_multiple_of(3) && !live.is_empty() {
+                let page: PhysicalAddr = live.swap_remove(roll % live.len());
+                allocator.free(page).unwrap();
+            } else if let Ok(block) = allocator.alloc(1 << (roll % 6)) {
+                for page in 0..1 << (roll % 6) {
+                    live.push(block.add(page * PAGE_SIZE));
+                }
+            }
+            for order in 0..ORDERS {
+                assert_eq!(allocator.lowest_free(order), linear(&allocator, order));
+            }
+        }
+    }
+
+    #[test]
+    fn contiguous_runs_span_blocks_and_free_as_one() {
+        let first = PageAllocatorImpl::reserved_pages();
+        let mut allocator = Box::new(PageAllocatorImpl::with_page_limit(first + 8));
+        let page = |index: usize| PhysicalAddr::new((first + index) * PAGE_SIZE);
+
+        // Leave pages 1..4 free: three pages, but no free block of four.
+        let pages: Vec<_> = (0..8).map(|_| allocator.alloc(1).unwrap()).collect();
+        for &freed in &pages[1..4] {
+            allocator.free(freed).unwrap();
+        }
+        assert_eq!(allocator.alloc(3), Err(MemoryError::OutOfMemory));
+        let run = allocator.alloc_contiguous(3, PAGE_SIZE).unwrap();
+        assert_eq!(run, page(1));
+        assert_eq!(allocator.stats().used_pages, 8);
+        assert_eq!(
+            allocator.alloc_contiguous(1, PAGE_SIZE),
+            Err(MemoryError::OutOfMemory)
+        );
+
+        // Pages inside the run are not allocations of their own.
+        assert_eq!(
+            allocator.free(page(2)),
+            Err(MemoryError::UnknownAllocation {
+                addr: page(2).as_usize()
+            })
+        );
+        allocator.free(run).unwrap();
+        assert_eq!(allocator.stats().used_pages, 5);
+        assert!(allocator.free(page(2)).is_err());
+
+        // The run has to start on the alignment, skipping free page 1.
+        let aligned = allocator.alloc_contiguous(2, 2 * PAGE_SIZE).unwrap();
+        assert_eq!(aligned, page(2));
+        allocator.free(aligned).unwrap();
+        for freed in pages
+            .into_iter()
+            .filter(|&p| p != page(1) && p != page(2) && p != page(3))
+        {
+            allocator.free(freed).unwrap();
+        }
+        // Everything is free again; the run covers both halves of the
+        // range even though they never merge into one block.
+        assert_eq!(allocator.alloc(8), Err(MemoryError::OutOfMemory));
+        assert_eq!(allocator.alloc_contiguous(8, PAGE_SIZE).unwrap(), page(0));
+        assert_eq!(allocator.stats().used_pages, 8);
+
+        assert_eq!(
+            allocator.alloc_contiguous(0, PAGE_SIZE),
+            Err(MemoryError::InvalidPageCount { pages: 0 })
+        );
+        assert!(matches!(
+            allocator.alloc_contiguous(1, 3 * PAGE_SIZE),
+            Err(MemoryError::UnsupportedAlignment { .. })
+        ));
+    }
+
+    #[test]
+    fn shrinkers_run_before_out_of_memory_and_below_the_threshold() {
+        use crate::boot::{E820_RAM, MemoryRegion};
+        use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+        // Pages a cache is holding on to, handed back when asked.
+        static ALLOCATOR: AtomicPtr<PageAllocator> = AtomicPtr::new(core::ptr::null_mut());
+        static HELD: spin::Mutex<Vec<PhysicalAddr>> = spin::Mutex::new(Vec::new());
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn release_held(pages: usize) -> usize {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            let allocator = unsafe { &*ALLOCATOR.load(Ordering::SeqCst) };
+            let mut held = HELD.lock();
+            let count = pages.min(held.len());
+            for page in held.drain(..count) {
+                allocator.free(page).unwrap();
+            }
+            count
+        }
+
+        let allocator: &'static PageAllocator = Box::leak(Box::new(PageAllocator::empty()));
+        ALLOCATOR.store(allocator as *const _ as *mut _, Ordering::SeqCst);
+        let mut map = MemoryMap::empty();
+        let first = PALLOC_FIRST_PAGE.as_u64();
+        map.push(MemoryRegion::new(first, 8 * PAGE_SIZE as u64, E820_RAM))
+            .unwrap();
+        allocator.add_memory_map(&map);
+
+        let pages: Vec<_> = (0..8).map(|_| allocator.alloc(1).unwrap()).collect();
+        HELD.lock().extend_from_slice(&pages[5..]);
+        assert_eq!(allocator.alloc(1), Err(MemoryError::OutOfMemory));
+
+        allocator.register_shrinker(release_held).unwrap();
+        assert_eq!(allocator.alloc(1).unwrap(), pages[5]);
+        assert_eq!((CALLS.load(Ordering::SeqCst), HELD.lock().len()), (1, 2));
+
+        // Dropping below the threshold asks for the shortfall once.
+        for &page in &pages[..4] {
+            allocator.free(page).unwrap();
+        }
+        allocator.set_low_memory_threshold(5);
+        allocator.alloc(1).unwrap();
+        assert_eq!((CALLS.load(Ordering::SeqCst), HELD.lock().len()), (2, 0));
+        allocator.alloc(1).unwrap();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+        for _ in 1..MAX_SHRINKERS {
+            allocator.register_shrinker(release_held).unwrap();
+        }
+        assert_eq!(
+            allocator.register_shrinker(release_held),
+            Err(MemoryError::TooManyShrinkers { max: MAX_SHRINKERS })
+        );
+    }
+
+    #[test]
+    fn freed_memory_is_reported_once_enough_has_been_freed() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        // Blocks reported, and their pages, over every report.
+        static BLOCKS: AtomicUsize = AtomicUsize::new(0);
+        static PAGES: AtomicUsize = AtomicUsize::new(0);
+        fn count_free(allocator: &PageAllocator) {
+            allocator.report_free(|blocks| {
+                for (addr, pages) in blocks {
+                    assert!(addr.as_usize().is_multiple_of(PAGE_SIZE));
+                    BLOCKS.fetch_add(1, Ordering::SeqCst);
+                    PAGES.fetch_add(pages, Ordering::SeqCst);
+                }
+            });
+        }
+
+        let allocator = Box::new(PageAllocator::new());
+        allocator.set_free_reporter(count_free, 4);
+        let pages: Vec<_> = (0..8).map(|_| allocator.alloc(1).unwrap()).collect();
+        for &page in &pages[..3] {
+            allocator.free(page).unwrap();
+        }
+        assert_eq!(PAGES.load(Ordering::SeqCst), 0);
+
+        // Only the pages ever allocated are reported, not the untouched
+        // rest of memory past them: the first four as one block.
+        allocator.free(pages[3]).unwrap();
+        assert_eq!(
+            (BLOCKS.load(Ordering::SeqCst), PAGES.load(Ordering::SeqCst)),
+            (1, 4)
+        );
+
+        // The count starts over after each report.
+        for &page in &pages[4..7] {
+            allocator.free(page).unwrap();
+        }
+        assert_eq!(PAGES.load(Ordering::SeqCst), 4);
+        allocator.free(pages[7]).unwrap();
+        assert_eq!(PAGES.load(Ordering::SeqCst), 12);
+    }
+
+    #[test]
+    fn memory_map_ram_regions_become_allocatable() {
+        use crate::boot::{E820_RAM, E820_RESERVED, MemoryRegion};
+
+        let first = PALLOC_FIRST_PAGE.as_u64();
+        let page = PAGE_SIZE as u64;
+        let mut map = MemoryMap::empty();
+        // The reserved prefix is never handed out even if reported as RAM,
+        // and partial pages at either end of a region are dropped.
+        map.push(MemoryRegion::new(0, first + 2 * page + 1, E820_RAM))
+            .unwrap();
+        map.push(MemoryRegion::new(first + 2 * page, page, E820_RESERVED))
+            .unwrap();
+        map.push(MemoryRegion::new(first + 3 * page + 1, 4 * page, E820_RAM))
+            .unwrap();
+
+        let allocator = Box::new(PageAllocator::empty());
+        assert_eq!(allocator.alloc(1), Err(MemoryError::OutOfMemory));
+        allocator.add_memory_map(&map);
+        assert_eq!(allocator.get_stats().allocatable_limit_pages, 5);
+
+        let mut pages: Vec<_> = (0..5)
+            .map(|_| allocator.alloc(1).unwrap().as_usize())
+            .collect();
+        assert_eq!(allocator.alloc(1), Err(MemoryError::OutOfMemory));
+        pages.sort();
+        let expected: Vec<_> = [0, 1, 4, 5, 6]
+            .map(|index| PALLOC_FIRST_PAGE.as_usize() + index * PAGE_SIZE)
+            .into();
+        assert_eq!(pages, expected);
+    }
+
+    #[test]
+    #[cfg(not(feature = "alloc-debug"))]
+    fn rounding_tail_is_returned_and_pages_free_individually() {
+        let allocator = Box::new(PageAllocator::new());
+        let block = allocator.alloc(3).unwrap();
+        assert_eq!(block.as_usize() % (4 * PAGE_SIZE), 0);
+        assert_eq!(allocator.get_stats().used_pages, 3);
+
+        for page in 0..3 {
+            allocator.free(block.add(page * PAGE_SIZE)).unwrap();
+        }
+        assert_eq!(allocator.get_stats().used_pages, 0);
+        assert_eq!(
+            allocator.free(block),
+            Err(MemoryError::UnknownAllocation {
+                addr: block.as_usize()
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "alloc-debug")]
+    #[should_panic(expected = "bad free")]
+    fn debug_palloc_panics_on_double_free() {
+        let allocator = Box::new(PageAllocator::new());
+        let page = allocator.alloc(1).unwrap();
+        allocator.free(page).unwrap();
+        let _ = allocator.free(page);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc-debug")]
+    #[should_panic(expected = "bad free")]
+    fn debug_palloc_panics_on_address_inside_a_page() {
+        let allocator = Box::new(PageAllocator::new());
+        let page = allocator.alloc(1).unwrap();
+        let _ = allocator.free(page.add(8));
+    }
 }
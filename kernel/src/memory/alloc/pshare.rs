@@ -0,0 +1,280 @@
+use crate::memory::{
+    address::{DirectMap, PhysicalAddr},
+    alloc::palloc::PageAllocator,
+    constants::{MAX_PHYSICAL_ADDR, PAGE_SIZE},
+    errors::{MemoryError, Result},
+};
+
+// Descriptors are kept per 4 KiB frame, the smallest page a mapping can use.
+const FRAME_SIZE: usize = 0x1000;
+const FRAME_COUNT: usize = (MAX_PHYSICAL_ADDR + 1) / FRAME_SIZE;
+// One allocator page holds the descriptors of this many frames.
+const FRAMES_PER_CHUNK: usize = PAGE_SIZE / size_of::<Frame>();
+const CHUNK_COUNT: usize = FRAME_COUNT.div_ceil(FRAMES_PER_CHUNK);
+
+/// What a mapped frame holds, beyond how many mappings share it.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameFlags {
+    bits: u32,
+}
+
+impl FrameFlags {
+    // Mapped MAP_SHARED: a write through any mapping is seen through all of
+    // them, so the frame is mapped again rather than copied.
+    const SHARED_BIT: u32 = 1 << 0;
+
+    pub const fn empty() -> Self {
+        Self { bits: 0 }
+    }
+
+    pub const fn with_shared(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.bits |= Self::SHARED_BIT;
+        } else {
+            self.bits &= !Self::SHARED_BIT;
+        }
+        self
+    }
+
+    pub const fn shared(self) -> bool {
+        (self.bits & Self::SHARED_BIT) != 0
+    }
+}
+
+/// The descriptor of one 4 KiB frame. All zeroes while nothing maps the
+/// frame.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Frame {
+    /// Mappings of the frame, across every address space.
+    pub refcount: u32,
+    pub flags: FrameFlags,
+    /// The address space that first mapped it, by its root page table.
+    pub owner: u64,
+}
+
+struct PageSharesImpl<'i, DM: DirectMap> {
+    // Page holding the descriptors of each run of frames, allocated on the
+    // first reference into the run.
+    chunks: [Option<PhysicalAddr>; CHUNK_COUNT],
+    // Frames with a nonzero refcount in each chunk. A chunk goes back to the
+    // allocator once this drops to 0.
+    live: [u32; CHUNK_COUNT],
+    palloc: &'i PageAllocator,
+    dm: &'i DM,
+}
+
+impl<DM: DirectMap> PageSharesImpl<'_, DM> {
+    fn locate(addr: PhysicalAddr) -> Result<(usize, usize)> {
+        let frame = addr.as_usize() / FRAME_SIZE;
+        if frame >= FRAME_COUNT {
+            return Err(MemoryError::PhysicalPageOutOfRange { page: frame });
+        }
+        Ok((frame / FRAMES_PER_CHUNK, frame % FRAMES_PER_CHUNK))
+    }
+
+    fn frame_mut(&mut self, chunk: PhysicalAddr, index: usize) -> &mut Frame {
+        // SAFETY: the chunk page is owned by this table and `index` is
+        // within it.
+        unsafe { &mut *chunk.to_virtual(self.dm).as_ptr::<Frame>().add(index) }
+    }
+
+    fn frame(&mut self, addr: PhysicalAddr) -> Result<Frame> {
+        let (chunk, index) = Self::locate(addr)?;
+        Ok(match self.chunks[chunk] {
+            Some(page) => *self.frame_mut(page, index),
+            None => Frame::default(),
+        })
+    }
+
+    fn get(&mut self, addr: PhysicalAddr, owner: u64, flags: FrameFlags) -> Result<Frame> {
+        let (chunk, index) = Self::locate(addr)?;
+        let page = match self.chunks[chunk] {
+            Some(page) => page,
+            None => {
+                let page = self.palloc.alloc(1)?;
+                // SAFETY: the page was just allocated for this table.
+                unsafe {
+                    page.to_virtual(self.dm)
+                        .as_ptr::<Frame>()
+                        .write_bytes(0, FRAMES_PER_CHUNK);
+                }
+                self.chunks[chunk] = Some(page);
+                page
+            }
+        };
+
+        let frame = self.frame_mut(page, index);
+        if frame.refcount == 0 {
+            *frame = Frame {
+                refcount: 1,
+                flags,
+                owner,
+            };
+            let frame = *frame;
+            self.live[chunk] += 1;
+            return Ok(frame);
+        }
+        frame.refcount =
+            frame
+                .refcount
+                .checked_add(1)
+                .ok_or(MemoryError::PageRefcountOverflow {
+                    addr: addr.as_usize(),
+                })?;
+        Ok(*frame)
+    }
+
+    fn put(&mut self, addr: PhysicalAddr) -> Result<u32> {
+        let (chunk, index) = Self::locate(addr)?;
+        let unknown = MemoryError::UnknownAllocation {
+            addr: addr.as_usize(),
+        };
+        let page = self.chunks[chunk].ok_or(unknown)?;
+
+        let frame = self.frame_mut(page, index);
+        if frame.refcount == 0 {
+            return Err(unknown);
+        }
+        frame.refcount -= 1;
+        let remaining = frame.refcount;
+        if remaining == 0 {
+            *frame = Frame::default();
+            self.live[chunk] -= 1;
+            if self.live[chunk] == 0 {
+                self.chunks[chunk] = None;
+                self.palloc.free(page)?;
+            }
+        }
+        Ok(remaining)
+    }
+}
+
+/// Descriptors of the physical frames mapped into address spaces: how many
+/// mappings share each one, what it holds, and who mapped it first. A frame
+/// is counted from its first `get` until the `put` that brings it back to 0,
+/// at which point its owner frees it.
+pub struct PageShares<'i, DM: DirectMap>(spin::Mutex<PageSharesImpl<'i, DM>>);
+
+impl<'i, DM: DirectMap> PageShares<'i, DM> {
+    pub const fn new(dm: &'i DM, palloc: &'i PageAllocator) -> Self {
+        Self(spin::Mutex::new(PageSharesImpl {
+            chunks: [None; CHUNK_COUNT],
+            live: [0; CHUNK_COUNT],
+            palloc,
+            dm,
+        }))
+    }
+
+    /// The descriptor of the frame at `addr`.
+    pub fn frame(&self, addr: PhysicalAddr) -> Result<Frame> {
+        self.0.lock().frame(addr)
+    }
+
+    /// Take a reference on the frame at `addr`, returning its descriptor.
+    /// The first reference records `owner` and `flags`; later ones keep
+    /// them.
+    pub fn get(&self, addr: PhysicalAddr, owner: u64, flags: FrameFlags) -> Result<Frame> {
+        self.0.lock().get(addr, owner, flags)
+    }
+
+    /// Drop a reference on the frame at `addr`, returning how many are left.
+    /// Fails for a frame nothing holds.
+    pub fn put(&self, addr: PhysicalAddr) -> Result<u32> {
+        self.0.lock().put(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{address::VirtualAddr, constants::PALLOC_FIRST_PAGE};
+
+    use super::*;
+
+    // Backs the allocator's first pages, where the counts go, with host
+    // memory.
+    struct HeapDirectMap(Vec<u8>);
+
+    impl DirectMap for HeapDirectMap {
+        fn p2v(&self, paddr: PhysicalAddr) -> VirtualAddr {
+            assert!(paddr.as_usize() < self.0.len());
+            VirtualAddr::new(self.0.as_ptr() as usize + paddr.as_usize())
+        }
+
+        fn v2p(&self, _: VirtualAddr) -> Result<PhysicalAddr> {
+            unreachable!("counts are only reached by physical address")
+        }
+    }
+
+    #[test]
+    fn descriptors_follow_gets_and_puts() {
+        let dm = HeapDirectMap(vec![0; PALLOC_FIRST_PAGE.as_usize() + 2 * PAGE_SIZE]);
+        let palloc = Box::new(PageAllocator::new());
+        let shares = PageShares::new(&dm, &palloc);
+        let frame = PhysicalAddr::new(0x1234_5000);
+        let neighbour = PhysicalAddr::new(0x1234_6000);
+        let shared = FrameFlags::empty().with_shared(true);
+
+        assert_eq!(shares.frame(frame), Ok(Frame::default()));
+        assert_eq!(
+            shares.put(frame),
+            Err(MemoryError::UnknownAllocation {
+                addr: frame.as_usize()
+            })
+        );
+
+        let first = Frame {
+            refcount: 1,
+            flags: shared,
+            owner: 7,
+        };
+        assert_eq!(shares.get(frame, 7, shared), Ok(first));
+        // Later references keep what the first one recorded.
+        assert_eq!(
+            shares.get(frame, 8, FrameFlags::empty()),
+            Ok(Frame {
+                refcount: 2,
+                ..first
+            })
+        );
+        assert!(
+            !shares
+                .get(neighbour, 8, FrameFlags::empty())
+                .unwrap()
+                .flags
+                .shared()
+        );
+        // The descriptors took a page of their own.
+        assert_eq!(palloc.get_stats().used_pages, 1);
+
+        assert_eq!(shares.put(frame), Ok(1));
+        assert_eq!(shares.put(frame), Ok(0));
+        assert_eq!(shares.frame(frame), Ok(Frame::default()));
+        assert_eq!(
+            shares.put(frame),
+            Err(MemoryError::UnknownAllocation {
+                addr: frame.as_usize()
+            })
+        );
+        assert_eq!(palloc.get_stats().used_pages, 1);
+
+        // The last reference in the chunk gives its page back.
+        assert_eq!(shares.put(neighbour), Ok(0));
+        assert_eq!(palloc.get_stats().used_pages, 0);
+    }
+
+    #[test]
+    fn refcounts_go_past_255_sharers() {
+        let dm = HeapDirectMap(vec![0; PALLOC_FIRST_PAGE.as_usize() + 2 * PAGE_SIZE]);
+        let palloc = Box::new(PageAllocator::new());
+        let shares = PageShares::new(&dm, &palloc);
+        let frame = PhysicalAddr::new(0x2000);
+
+        for _ in 0..300 {
+            shares.get(frame, 1, FrameFlags::empty()).unwrap();
+        }
+        assert_eq!(shares.frame(frame).unwrap().refcount, 300);
+        assert_eq!(shares.put(frame), Ok(299));
+    }
+}
@@ -1,9 +1,11 @@
 use crate::{
-    boot::RunFlags,
+    boot::{BootInfo, PvClock, StartupMailbox},
     memory::address::{PhysicalAddr, VirtualAddr},
 };
 
 pub const PAGE_SIZE: usize = 2 << 20;
+// user memory is mapped with 4KB pages through a PT level below the PD
+pub const SMALL_PAGE_SIZE: usize = 4 << 10;
 pub const MAX_PHYSICAL_ADDR: usize = 0x0000_00FF_FFFF_FFFF;
 
 pub const PAGE_TABLE_ENTRIES: usize = 512;
@@ -19,7 +21,7 @@ pub const DIRECT_MAP_PML4: PhysicalAddr = PhysicalAddr::new(0x0);
 
 // the PML4 entry index for the direct map region; this is used to set up the initial page tables
 pub const DIRECT_MAP_PML4_OFFSET: usize = DIRECT_MAP_OFFSET.pml4_index();
-pub const DIRECT_MAP_PML4_ENTRIES_COUNT: usize = DIRECT_MAP_PDPT_COUNT.div_ceil(PAGE_TABLE_ENTRIES); // number of PML4 entries needed to cover the direct map region
+pub const DIRECT_MAP_PML4_ENTRIES_COUNT: usize = DIRECT_MAP_PDPT_COUNT; // one PML4 entry per PDPT covers the direct map region
 
 pub const DIRECT_MAP_PDPT: PhysicalAddr = DIRECT_MAP_PML4.add(PAGE_TABLE_SIZE);
 pub const DIRECT_MAP_PDPT_COUNT: usize =
@@ -32,20 +34,50 @@ pub const DIRECT_MAP_PD_COUNT: usize =
 // pdpd and pd for the kernel code (we need to reserver 2gb of virtual address space for kernel code, for code-model=kernel)
 pub const KERNEL_CODE_PDPD: PhysicalAddr = DIRECT_MAP_PD.add(DIRECT_MAP_PD_COUNT * PAGE_TABLE_SIZE);
 pub const KERNEL_CODE_PD: PhysicalAddr = KERNEL_CODE_PDPD.add(PAGE_TABLE_SIZE);
+// the first 2mb of kernel code are mapped with 4kb pages, so each ELF segment gets its own permissions
+pub const KERNEL_CODE_PT: PhysicalAddr = KERNEL_CODE_PD.add(PAGE_TABLE_SIZE);
 
 const KERNEL_STACK_SIZE: usize = 0x1000 * 8; // 32KB stack
-pub const KERNEL_STACK: PhysicalAddr = KERNEL_CODE_PD
+pub const KERNEL_STACK: PhysicalAddr = KERNEL_CODE_PT
     .add(PAGE_TABLE_SIZE + KERNEL_STACK_SIZE)
     .align_up(PAGE_SIZE);
 
+// Most vCPUs the VM creates; each one enters the kernel on its own stack.
+pub const MAX_CPUS: usize = 8;
+
+/// Top of the stack vCPU `cpu` enters the kernel on. The boot vCPU's is
+/// KERNEL_STACK and the others' follow it down, towards the page tables.
+pub const fn entry_stack_top(cpu: usize) -> PhysicalAddr {
+    PhysicalAddr::new(KERNEL_STACK.as_usize() - cpu * KERNEL_STACK_SIZE)
+}
+
 pub const KERNEL_CODE_PHYS: PhysicalAddr = KERNEL_STACK; // stack will grow down from this point, code will grow up
-pub const KERNEL_CODE_SIZE: usize = PAGE_SIZE - RUN_FLAGS_SIZE;
+pub const KERNEL_CODE_SIZE: usize =
+    PAGE_SIZE - BOOT_INFO_SIZE - STARTUP_MAILBOX_SIZE - COMMAND_LINE_MAX - PVCLOCK_SIZE;
+
+// Everything the VM tells the kernel before it starts.
+pub const BOOT_INFO_PHYS: PhysicalAddr = KERNEL_CODE_PHYS.add(KERNEL_CODE_SIZE);
+pub const BOOT_INFO_SIZE: usize = size_of::<BootInfo>();
 
-// Boot-time flags written by VM before kernel starts.
-pub const RUN_FLAGS_PHYS: PhysicalAddr = KERNEL_CODE_PHYS.add(KERNEL_CODE_SIZE);
-pub const RUN_FLAGS_SIZE: usize = size_of::<RunFlags>();
+// Where vCPUs check in as they reach the kernel.
+pub const STARTUP_MAILBOX_PHYS: PhysicalAddr = BOOT_INFO_PHYS.add(BOOT_INFO_SIZE);
+pub const STARTUP_MAILBOX_SIZE: usize = size_of::<StartupMailbox>();
 
-pub const PALLOC_FIRST_PAGE: PhysicalAddr = RUN_FLAGS_PHYS.add(RUN_FLAGS_SIZE);
+// Where the VM puts the command line the boot info points at.
+pub const COMMAND_LINE_PHYS: PhysicalAddr = STARTUP_MAILBOX_PHYS.add(STARTUP_MAILBOX_SIZE);
+pub const COMMAND_LINE_MAX: usize = 256;
+
+// Each vCPU's kvmclock, which KVM keeps current once the VM enables it.
+// Last, so that ending on a page boundary aligns it.
+pub const PVCLOCK_PHYS: PhysicalAddr = COMMAND_LINE_PHYS.add(COMMAND_LINE_MAX);
+pub const PVCLOCK_SIZE: usize = size_of::<[PvClock; MAX_CPUS]>();
+
+pub const PALLOC_FIRST_PAGE: PhysicalAddr = PVCLOCK_PHYS.add(PVCLOCK_SIZE);
+
+// Registers of the VMM's virtio devices, in the last page the direct map
+// covers. Guest RAM stops short of it.
+pub const VIRTIO_MMIO_PHYS: PhysicalAddr = PhysicalAddr::new(MAX_PHYSICAL_ADDR + 1 - PAGE_SIZE);
+pub const VIRTIO_MMIO_SIZE: usize = PAGE_SIZE;
 
 #[cfg(test)]
 mod tests {
@@ -74,6 +106,11 @@ mod tests {
             0,
             "Kernel PD must be 4KB aligned"
         );
+        assert_eq!(
+            KERNEL_CODE_PT.as_u64() % 4096,
+            0,
+            "Kernel PT must be 4KB aligned"
+        );
 
         assert_eq!(
             KERNEL_CODE_PHYS.as_u64() % (2 << 20),
@@ -91,13 +128,54 @@ mod tests {
 
         let kernel_pd_end = KERNEL_CODE_PD.as_usize() + (PAGE_TABLE_ENTRIES * 8);
         assert!(
-            kernel_pd_end <= KERNEL_STACK.as_usize()
-                || KERNEL_STACK.as_usize() < KERNEL_CODE_PD.as_usize(),
-            "Kernel PD tables overlap with Stack! End: {:#x}, Stack: {:#x}",
+            kernel_pd_end <= KERNEL_CODE_PT.as_usize(),
+            "Kernel PD overlaps with Kernel PT! End: {:#x}, Next: {:#x}",
             kernel_pd_end,
+            KERNEL_CODE_PT.as_usize()
+        );
+
+        let kernel_pt_end = KERNEL_CODE_PT.as_usize() + (PAGE_TABLE_ENTRIES * 8);
+        assert!(
+            kernel_pt_end <= KERNEL_STACK.as_usize()
+                || KERNEL_STACK.as_usize() < KERNEL_CODE_PT.as_usize(),
+            "Kernel PT overlaps with Stack! End: {:#x}, Stack: {:#x}",
+            kernel_pt_end,
             KERNEL_STACK.as_usize()
         );
 
+        assert_eq!(
+            BOOT_INFO_PHYS.as_usize() % align_of::<BootInfo>(),
+            0,
+            "Boot info must be naturally aligned"
+        );
+        assert_eq!(
+            STARTUP_MAILBOX_PHYS.as_usize() % align_of::<StartupMailbox>(),
+            0,
+            "Startup mailbox must be naturally aligned"
+        );
+        assert_eq!(
+            PVCLOCK_PHYS.as_usize() % align_of::<PvClock>(),
+            0,
+            "kvmclocks must be aligned so none crosses a page"
+        );
+        assert!(
+            entry_stack_top(MAX_CPUS).as_usize() >= kernel_pt_end,
+            "Entry stacks overlap with Kernel PT! Lowest: {:#x}, PT end: {:#x}",
+            entry_stack_top(MAX_CPUS).as_usize(),
+            kernel_pt_end
+        );
+        assert_eq!(
+            PALLOC_FIRST_PAGE.as_usize() % PAGE_SIZE,
+            0,
+            "Boot info must end on a page boundary"
+        );
+
+        assert_eq!(
+            VIRTIO_MMIO_PHYS.as_usize() + VIRTIO_MMIO_SIZE,
+            MAX_PHYSICAL_ADDR + 1,
+            "Virtio registers must end the direct map"
+        );
+
         assert_eq!(KERNEL_CODE_VIRT.pml4_index(), PAGE_TABLE_ENTRIES - 1);
 
         assert!(KERNEL_CODE_VIRT.pdpt_index() == PAGE_TABLE_ENTRIES - 2);
@@ -23,12 +23,6 @@ pub enum MemoryError {
     #[error("allocation too large: requested {requested} bytes, max {max} bytes")]
     AllocationTooLarge { requested: usize, max: usize },
 
-    #[error("too many slabs for class {class_size}")]
-    TooManySlabs { class_size: u32 },
-
-    #[error("too many active large allocations")]
-    TooManyLargeAllocations,
-
     #[error("unknown allocation at physical address {addr:#x}")]
     UnknownAllocation { addr: usize },
 
@@ -38,14 +32,32 @@ pub enum MemoryError {
     #[error("pointer {addr:#x} does not match slab alignment {block_size}")]
     SlabAlignmentMismatch { addr: usize, block_size: usize },
 
+    #[error("no room for another shrinker: at most {max} can be registered")]
+    TooManyShrinkers { max: usize },
+
     #[error("invalid slab capacity")]
     InvalidSlabCapacity,
 
     #[error("slab is empty")]
     SlabEmpty,
 
+    #[error("double free of {addr:#x}")]
+    DoubleFree { addr: usize },
+
+    #[error("write past the end of the block at {addr:#x}")]
+    RedzoneCorrupted { addr: usize },
+
+    #[error("unsupported alignment {align:#x}: must be a power of two up to {max:#x}")]
+    UnsupportedAlignment { align: usize, max: usize },
+
     #[error("page refcount overflow at physical address {addr:#x}")]
     PageRefcountOverflow { addr: usize },
+
+    #[error("no mapping allows the access to {addr:#x}")]
+    AccessViolation { addr: usize },
+
+    #[error("mapping {requested} more bytes exceeds address space limit {limit}")]
+    AddressSpaceLimit { requested: usize, limit: usize },
 }
 
 pub type Result<T> = core::result::Result<T, MemoryError>;
@@ -3,4 +3,5 @@ pub mod alloc;
 pub mod constants;
 pub mod errors;
 pub mod pagetable;
+pub mod tlb;
 pub mod vmm;
@@ -1,19 +1,61 @@
+use core::fmt::Display;
+use core::ops::Range;
 use core::ptr::copy_nonoverlapping;
 
+use crate::arch::cpu::{self, Features};
 use crate::memory::alloc::kmalloc::KernelAllocator;
 use crate::memory::{
     address::{DirectMap, PhysicalAddr, VirtualAddr},
-    constants::{DIRECT_MAP_OFFSET, PAGE_TABLE_ENTRIES, PAGE_TABLE_SIZE},
+    constants::{
+        DIRECT_MAP_OFFSET, PAGE_SIZE, PAGE_TABLE_ENTRIES, PAGE_TABLE_SIZE, SMALL_PAGE_SIZE,
+    },
     errors::{MemoryError, Result},
+    tlb,
 };
 
 const PRESENT: usize = 1 << 0;
 const WRITABLE: usize = 1 << 1;
 const USER_ACCESSIBLE: usize = 1 << 2;
 const HUGE_PAGE: usize = 1 << 7;
+// Software bit marking a PROT_NONE leaf: the page stays allocated and the
+// range stays reserved, but the entry is not present to the CPU.
+const NO_ACCESS: usize = 1 << 9;
+const NO_EXECUTE: usize = 1 << 63;
 const ADDR_MASK: usize = 0x000F_FFFF_FFFF_F000;
 const USER_PML4_LIMIT: usize = DIRECT_MAP_OFFSET.pml4_index();
 
+/// Size of a leaf mapping: a PT entry maps 4KB, a PD entry with the PS bit
+/// maps 2MB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Small,
+    Huge,
+}
+
+impl PageSize {
+    pub const fn bytes(self) -> usize {
+        match self {
+            Self::Small => SMALL_PAGE_SIZE,
+            Self::Huge => PAGE_SIZE,
+        }
+    }
+
+    const fn leaf_level(self) -> PageTableLevel {
+        match self {
+            Self::Small => PageTableLevel::Pt,
+            Self::Huge => PageTableLevel::Pd,
+        }
+    }
+}
+
+/// What a user leaf mapping lets the process do with the page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageAccess {
+    None,
+    Read,
+    ReadWrite,
+}
+
 #[derive(Clone, Copy)]
 pub struct PageTableEntry(usize);
 
@@ -22,17 +64,63 @@ impl PageTableEntry {
         self.0 = addr.as_usize() | PRESENT | WRITABLE | USER_ACCESSIBLE;
     }
 
-    pub fn set_paddr(&mut self, addr: PhysicalAddr) {
-        self.0 = addr.as_usize() | PRESENT | WRITABLE | USER_ACCESSIBLE | HUGE_PAGE;
+    // Leaves only ever map data; all code runs from the kernel image.
+    pub fn set_paddr(&mut self, addr: PhysicalAddr, size: PageSize, access: PageAccess) {
+        let access = match access {
+            PageAccess::None => NO_ACCESS,
+            PageAccess::Read => PRESENT,
+            PageAccess::ReadWrite => PRESENT | WRITABLE,
+        };
+        self.0 = addr.as_usize() | access | USER_ACCESSIBLE;
+        // Without NX the bit is reserved and any access through it faults.
+        if cpu::features().contains(Features::NX) {
+            self.0 |= NO_EXECUTE;
+        }
+        if size == PageSize::Huge {
+            self.0 |= HUGE_PAGE;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = 0;
     }
 
     pub fn is_present(&self) -> bool {
         (self.0 & PRESENT) != 0
     }
 
+    /// Present, or a PROT_NONE leaf that still owns its page.
+    pub fn is_mapped(&self) -> bool {
+        (self.0 & (PRESENT | NO_ACCESS)) != 0
+    }
+
+    pub fn access(&self) -> PageAccess {
+        if (self.0 & NO_ACCESS) != 0 {
+            PageAccess::None
+        } else if (self.0 & WRITABLE) != 0 {
+            PageAccess::ReadWrite
+        } else {
+            PageAccess::Read
+        }
+    }
+
     pub fn addr(&self) -> PhysicalAddr {
         PhysicalAddr::new(self.0 & ADDR_MASK)
     }
+
+    // Only meaningful for leaves: in a PT entry bit 7 is PAT, which is never
+    // set.
+    pub fn page_size(&self) -> PageSize {
+        if self.is_huge() {
+            PageSize::Huge
+        } else {
+            PageSize::Small
+        }
+    }
+
+    fn is_huge(&self) -> bool {
+        (self.0 & HUGE_PAGE) != 0
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -40,6 +128,7 @@ enum PageTableLevel {
     Pml4,
     Pdpt,
     Pd,
+    Pt,
 }
 
 impl PageTableLevel {
@@ -47,7 +136,18 @@ impl PageTableLevel {
         match self {
             Self::Pml4 => Some(Self::Pdpt),
             Self::Pdpt => Some(Self::Pd),
-            Self::Pd => None,
+            Self::Pd => Some(Self::Pt),
+            Self::Pt => None,
+        }
+    }
+
+    // Bit of the virtual address where this level's index starts.
+    fn shift(self) -> usize {
+        match self {
+            Self::Pml4 => 39,
+            Self::Pdpt => 30,
+            Self::Pd => 21,
+            Self::Pt => 12,
         }
     }
 }
@@ -65,9 +165,10 @@ impl PageTable {
     pub fn get<DM: DirectMap>(
         &mut self,
         vaddr: VirtualAddr,
+        size: PageSize,
         kalloc: &KernelAllocator<DM>,
     ) -> Result<&mut PageTableEntry> {
-        self.get_level(vaddr, PageTableLevel::Pml4, kalloc)
+        self.get_level(vaddr, PageTableLevel::Pml4, size.leaf_level(), kalloc)
     }
 
     pub fn get_if_present<DM: DirectMap>(
@@ -82,9 +183,10 @@ impl PageTable {
         &mut self,
         vaddr: VirtualAddr,
         level: PageTableLevel,
+        leaf: PageTableLevel,
         kalloc: &KernelAllocator<DM>,
     ) -> Result<&mut PageTableEntry> {
-        if level == PageTableLevel::Pd {
+        if level == leaf {
             return Ok(&mut self.entries[index_for(level, vaddr)]);
         }
 
@@ -92,6 +194,11 @@ impl PageTable {
 
         if !entry.is_present() {
             entry.set_table(kalloc.calloc(PAGE_TABLE_SIZE)?);
+        } else if entry.is_huge() {
+            // A 4KB page cannot go inside a range a 2MB page already maps.
+            return Err(MemoryError::AlreadyMapped {
+                addr: vaddr.as_usize(),
+            });
         }
 
         let Some(next) = level.next() else {
@@ -101,7 +208,7 @@ impl PageTable {
         };
 
         let child = unsafe { Self::from_paddr_mut(entry.addr(), kalloc.direct_map()) };
-        child.get_level(vaddr, next, kalloc)
+        child.get_level(vaddr, next, leaf, kalloc)
     }
 
     fn get_present_level(
@@ -112,22 +219,48 @@ impl PageTable {
     ) -> Result<Option<PageTableEntry>> {
         let entry = self.entries[index_for(level, vaddr)];
 
-        if !entry.is_present() {
+        if !entry.is_mapped() {
             return Ok(None);
         }
 
-        if level == PageTableLevel::Pd {
+        let Some(next) = level.next().filter(|_| !entry.is_huge()) else {
             return Ok(Some(entry));
-        }
-
-        let Some(next) = level.next() else {
-            return Ok(None);
         };
 
         let child = unsafe { Self::from_paddr_mut(entry.addr(), map) };
         child.get_present_level(vaddr, next, map)
     }
 
+    fn for_each_user_page_level(
+        &mut self,
+        level: PageTableLevel,
+        base: usize,
+        map: &impl DirectMap,
+        f: &mut impl FnMut(VirtualAddr, &mut PageTableEntry) -> Result<()>,
+    ) -> Result<()> {
+        let end = if level == PageTableLevel::Pml4 {
+            USER_PML4_LIMIT
+        } else {
+            PAGE_TABLE_ENTRIES
+        };
+
+        for i in 0..end {
+            let entry = &mut self.entries[i];
+            if !entry.is_mapped() {
+                continue;
+            }
+            let vaddr = base | (i << level.shift());
+            match level.next().filter(|_| !entry.is_huge()) {
+                Some(next) => {
+                    let child = unsafe { Self::from_paddr_mut(entry.addr(), map) };
+                    child.for_each_user_page_level(next, vaddr, map, f)?;
+                }
+                None => f(VirtualAddr::new(vaddr), entry)?,
+            }
+        }
+        Ok(())
+    }
+
     pub fn free<DM: DirectMap>(&mut self, kalloc: &KernelAllocator<DM>) -> Result<()> {
         self.free_level(PageTableLevel::Pml4, kalloc)
     }
@@ -143,20 +276,17 @@ impl PageTable {
             PAGE_TABLE_ENTRIES
         };
 
-        if let Some(next) = level.next() {
-            for i in 0..end {
-                let entry = self.entries[i];
-                if entry.is_present() {
+        for i in 0..end {
+            let entry = self.entries[i];
+            if !entry.is_mapped() {
+                continue;
+            }
+            match level.next().filter(|_| !entry.is_huge()) {
+                Some(next) => {
                     let child = unsafe { Self::from_paddr_mut(entry.addr(), kalloc.direct_map()) };
                     child.free_level(next, kalloc)?;
                 }
-            }
-        } else {
-            for i in 0..end {
-                let entry = self.entries[i];
-                if entry.is_present() {
-                    kalloc.free(entry.addr(), PAGE_TABLE_SIZE)?;
-                }
+                None => kalloc.free(entry.addr(), entry.page_size().bytes())?,
             }
         }
 
@@ -177,6 +307,7 @@ fn index_for(level: PageTableLevel, vaddr: VirtualAddr) -> usize {
         PageTableLevel::Pml4 => vaddr.pml4_index(),
         PageTableLevel::Pdpt => vaddr.pdpt_index(),
         PageTableLevel::Pd => vaddr.pd_index(),
+        PageTableLevel::Pt => vaddr.pt_index(),
     }
 }
 
@@ -219,14 +350,43 @@ impl<'i, DM: DirectMap> RootPageTable<'i, DM> {
         self.addr
     }
 
-    pub fn get(&mut self, addr: VirtualAddr) -> Result<&mut PageTableEntry> {
-        self.get_pml4().get(addr, self.kalloc)
+    /// The leaf entry for a `size` page at `addr`, creating the tables
+    /// above it as needed.
+    pub fn get(&mut self, addr: VirtualAddr, size: PageSize) -> Result<&mut PageTableEntry> {
+        self.get_pml4().get(addr, size, self.kalloc)
     }
 
     pub fn get_if_present(&self, addr: VirtualAddr) -> Result<Option<PageTableEntry>> {
         self.get_pml4().get_if_present(addr, self.kalloc)
     }
 
+    /// Remove the mapping of `addr` and drop it from the TLB, returning the
+    /// page it pointed to.
+    pub fn unmap(&mut self, addr: VirtualAddr) -> Result<Option<PhysicalAddr>> {
+        let Some(present) = self.get_if_present(addr)? else {
+            return Ok(None);
+        };
+        let entry = self.get(addr, present.page_size())?;
+        let paddr = entry.addr();
+        entry.clear();
+        tlb::flush_page(addr);
+        Ok(Some(paddr))
+    }
+
+    /// Call `f` with the address and entry of every page mapped in the user
+    /// half, stopping at the first error.
+    pub fn for_each_user_page(
+        &mut self,
+        mut f: impl FnMut(VirtualAddr, &mut PageTableEntry) -> Result<()>,
+    ) -> Result<()> {
+        self.get_pml4().for_each_user_page_level(
+            PageTableLevel::Pml4,
+            0,
+            self.kalloc.direct_map(),
+            &mut f,
+        )
+    }
+
     fn get_pml4(&self) -> &mut PageTable {
         unsafe { PageTable::from_paddr_mut(self.addr, self.kalloc.direct_map()) }
     }
@@ -237,3 +397,246 @@ impl<DM: DirectMap> Drop for RootPageTable<'_, DM> {
         self.get_pml4().free(self.kalloc).unwrap();
     }
 }
+
+/// Permissions of a leaf mapping as the CPU sees them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MappingFlags {
+    /// Clear for a PROT_NONE leaf, which keeps its page but faults on access.
+    pub accessible: bool,
+    pub writable: bool,
+    pub user: bool,
+    pub executable: bool,
+}
+
+impl MappingFlags {
+    fn of(entry: PageTableEntry) -> Self {
+        Self {
+            accessible: entry.is_present(),
+            writable: entry.0 & WRITABLE != 0,
+            user: entry.0 & USER_ACCESSIBLE != 0,
+            executable: entry.0 & NO_EXECUTE == 0,
+        }
+    }
+}
+
+impl Display for MappingFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let bit = |set: bool, c: char| if set && self.accessible { c } else { '-' };
+        write!(
+            f,
+            "{}{}{} {}",
+            bit(true, 'r'),
+            bit(self.writable, 'w'),
+            bit(self.executable, 'x'),
+            if self.user { "user" } else { "kernel" }
+        )
+    }
+}
+
+/// A virtually and physically contiguous run of leaf mappings that share
+/// their flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub vaddr: VirtualAddr,
+    pub paddr: PhysicalAddr,
+    pub len: usize,
+    pub flags: MappingFlags,
+}
+
+impl Mapping {
+    /// The physical address `vaddr` translates to; `vaddr` must fall inside
+    /// the mapping.
+    pub fn paddr_of(&self, vaddr: VirtualAddr) -> PhysicalAddr {
+        self.paddr.add(vaddr.as_usize() - self.vaddr.as_usize())
+    }
+
+    fn extends(&self, next: &Mapping) -> bool {
+        self.flags == next.flags
+            && self.vaddr.as_usize().wrapping_add(self.len) == next.vaddr.as_usize()
+            && self.paddr.as_usize() + self.len == next.paddr.as_usize()
+    }
+}
+
+impl Display for Mapping {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}-{:#018x} -> {} {}",
+            self.vaddr,
+            self.vaddr.as_usize().wrapping_add(self.len),
+            self.paddr,
+            self.flags
+        )
+    }
+}
+
+/// The leaf page that maps `vaddr` in the tables rooted at `root`. Reads the
+/// tables only, so it is safe to call from fault and panic paths.
+pub fn translate(root: PhysicalAddr, vaddr: VirtualAddr, map: &impl DirectMap) -> Option<Mapping> {
+    let mut table = root;
+    let mut level = PageTableLevel::Pml4;
+    loop {
+        let entry = read_entry(table, index_for(level, vaddr), map);
+        if !entry.is_mapped() {
+            return None;
+        }
+        match level.next().filter(|_| !entry.is_huge()) {
+            Some(next) => {
+                table = entry.addr();
+                level = next;
+            }
+            None => {
+                let span = level_span(level);
+                return Some(Mapping {
+                    vaddr: VirtualAddr::new(vaddr.as_usize() & !(span - 1)),
+                    paddr: entry.addr(),
+                    len: span,
+                    flags: MappingFlags::of(entry),
+                });
+            }
+        }
+    }
+}
+
+/// Call `f` for every mapping overlapping `range`, in address order, with
+/// neighbouring leaves merged.
+pub fn walk(
+    root: PhysicalAddr,
+    range: Range<usize>,
+    map: &impl DirectMap,
+    mut f: impl FnMut(Mapping),
+) {
+    let mut pending: Option<Mapping> = None;
+    walk_level(
+        root,
+        PageTableLevel::Pml4,
+        0,
+        &range,
+        map,
+        &mut |leaf: Mapping| match pending.as_mut() {
+            Some(run) if run.extends(&leaf) => run.len += leaf.len,
+            _ => {
+                if let Some(run) = pending.replace(leaf) {
+                    f(run);
+                }
+            }
+        },
+    );
+    if let Some(run) = pending {
+        f(run);
+    }
+}
+
+/// Print every mapping overlapping `range` to the console.
+pub fn dump(root: PhysicalAddr, range: Range<usize>, map: &impl DirectMap) {
+    crate::println!(
+        "page table {} [{:#x}, {:#x}):",
+        root,
+        range.start,
+        range.end
+    );
+    walk(root, range, map, |mapping| crate::println!("  {}", mapping));
+}
+
+fn walk_level(
+    table: PhysicalAddr,
+    level: PageTableLevel,
+    base: usize,
+    range: &Range<usize>,
+    map: &impl DirectMap,
+    f: &mut impl FnMut(Mapping),
+) {
+    let span = level_span(level);
+    for index in 0..PAGE_TABLE_ENTRIES {
+        let start = canonical(base + index * span);
+        let last = start + (span - 1);
+        if last < range.start || start >= range.end {
+            continue;
+        }
+        let entry = read_entry(table, index, map);
+        if !entry.is_mapped() {
+            continue;
+        }
+        match level.next().filter(|_| !entry.is_huge()) {
+            Some(next) => walk_level(entry.addr(), next, start, range, map, f),
+            None => f(Mapping {
+                vaddr: VirtualAddr::new(start),
+                paddr: entry.addr(),
+                len: span,
+                flags: MappingFlags::of(entry),
+            }),
+        }
+    }
+}
+
+fn read_entry(table: PhysicalAddr, index: usize, map: &impl DirectMap) -> PageTableEntry {
+    let entries = table.to_virtual(map).as_ptr::<PageTableEntry>();
+    unsafe { entries.add(index).read() }
+}
+
+const fn level_span(level: PageTableLevel) -> usize {
+    match level {
+        PageTableLevel::Pml4 => 1 << 39,
+        PageTableLevel::Pdpt => 1 << 30,
+        PageTableLevel::Pd => PAGE_SIZE,
+        PageTableLevel::Pt => SMALL_PAGE_SIZE,
+    }
+}
+
+// Addresses in the upper half of the PML4 have bit 47 copied into the top
+// bits.
+const fn canonical(addr: usize) -> usize {
+    if addr & (1 << 47) != 0 {
+        addr | 0xFFFF_0000_0000_0000
+    } else {
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mappings_merge_and_print_their_flags() {
+        let flags = MappingFlags {
+            accessible: true,
+            writable: true,
+            user: true,
+            executable: false,
+        };
+        let mut run = Mapping {
+            vaddr: VirtualAddr::new(0x1000_0000),
+            paddr: PhysicalAddr::new(0x60_0000),
+            len: SMALL_PAGE_SIZE,
+            flags,
+        };
+        let next = Mapping {
+            vaddr: run.vaddr.add(SMALL_PAGE_SIZE),
+            paddr: run.paddr.add(SMALL_PAGE_SIZE),
+            ..run
+        };
+        assert!(run.extends(&next));
+        assert!(!run.extends(&Mapping {
+            paddr: run.paddr,
+            ..next
+        }));
+        run.len += next.len;
+
+        assert_eq!(
+            run.paddr_of(VirtualAddr::new(0x1000_1008)),
+            PhysicalAddr::new(0x60_1008)
+        );
+        assert_eq!(
+            run.to_string(),
+            "0x0000000010000000-0x0000000010002000 -> 0x0000000000600000 rw- user"
+        );
+
+        let none = MappingFlags {
+            accessible: false,
+            ..flags
+        };
+        assert_eq!(none.to_string(), "--- user");
+        assert_eq!(canonical(1 << 47), 0xFFFF_8000_0000_0000);
+    }
+}
@@ -0,0 +1,44 @@
+//! TLB maintenance. A translation that is narrowed or removed in the page
+//! tables has to be dropped from the TLB before the page behind it is reused.
+//! Only the running CPU is flushed; with a single vCPU there is nobody else
+//! to shoot down.
+
+use core::arch::asm;
+
+use crate::memory::{address::VirtualAddr, constants::SMALL_PAGE_SIZE};
+
+// Past this many pages, reloading CR3 is cheaper than one invlpg per page.
+const FLUSH_ALL_THRESHOLD: usize = 32;
+
+/// Drop the translation for the page holding `addr`.
+pub fn flush_page(addr: VirtualAddr) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) addr.as_usize(), options(nostack, preserves_flags));
+    }
+}
+
+/// Drop the translations for every page overlapping `[start, start + len)`.
+pub fn flush_range(start: VirtualAddr, len: usize) {
+    let first = start.as_usize() & !(SMALL_PAGE_SIZE - 1);
+    let end = start.as_usize().saturating_add(len);
+    let pages = end.saturating_sub(first).div_ceil(SMALL_PAGE_SIZE);
+    if pages > FLUSH_ALL_THRESHOLD {
+        flush_all();
+        return;
+    }
+    for page in 0..pages {
+        flush_page(VirtualAddr::new(first + page * SMALL_PAGE_SIZE));
+    }
+}
+
+/// Drop every non-global translation by reloading CR3.
+pub fn flush_all() {
+    unsafe {
+        asm!(
+            "mov {tmp}, cr3",
+            "mov cr3, {tmp}",
+            tmp = out(reg) _,
+            options(nostack, preserves_flags),
+        );
+    }
+}
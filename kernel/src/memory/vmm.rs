@@ -1,15 +1,70 @@
+use core::ptr::copy_nonoverlapping;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
 use crate::memory::{
     address::{DirectMap, PhysicalAddr, VirtualAddr},
-    alloc::kmalloc::KernelAllocator,
-    constants::PAGE_SIZE,
+    alloc::{
+        kmalloc::KernelAllocator,
+        pshare::{FrameFlags, PageShares},
+    },
     errors::{MemoryError, Result},
-    pagetable::RootPageTable,
+    pagetable::{PageAccess, PageSize, RootPageTable},
 };
+use crate::syscall::MAP_SHARED;
+
+// User memory is mapped with 4KB pages so a mapping can be changed page by
+// page; only the direct map uses 2MB pages.
+const USER_PAGE: PageSize = PageSize::Small;
+pub const USER_PAGE_SIZE: usize = USER_PAGE.bytes();
 
 const USER_HEAP_BASE: usize = 0x0000_0001_0000_0000;
 const USER_MMAP_BASE: usize = 0x0000_0004_0000_0000;
 const USER_MMAP_LIMIT: usize = 0x0000_7000_0000_0000;
+const MAP_PRIVATE: u64 = 0x02;
 const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_POPULATE: u64 = 0x8000;
+const MAP_FIXED_NOREPLACE: u64 = 0x100000;
+const PROT_READ: u64 = 0x1;
+const PROT_WRITE: u64 = 0x2;
+const PROT_EXEC: u64 = 0x4;
+
+/// Where the pages of a region come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backing {
+    Anonymous,
+    Heap,
+}
+
+/// A run of user pages mapped with the same protection and flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vma {
+    pub start: usize,
+    pub len: usize,
+    pub prot: u64,
+    pub flags: u64,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+
+    /// What the frames mapped into the region are marked with.
+    pub fn frame_flags(&self) -> FrameFlags {
+        FrameFlags::empty().with_shared(self.flags & MAP_SHARED != 0)
+    }
+}
+
+/// How much memory an address space uses: bytes reserved through brk and
+/// mmap, and how many of those are backed by pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub mapped_bytes: usize,
+    pub resident_bytes: usize,
+}
 
 pub struct Vmm<'i, DM: DirectMap> {
     heap_base: usize,
@@ -17,7 +72,15 @@ pub struct Vmm<'i, DM: DirectMap> {
     brk_mapped_end: usize,
     mmap_base: usize,
     mmap_next: usize,
+    mapped_bytes: usize,
+    resident_bytes: usize,
+    address_space_limit: usize,
+    // Keyed by start address; regions never overlap.
+    vmas: BTreeMap<usize, Vma>,
     kalloc: &'i KernelAllocator<'i, DM>,
+    // Every mapped page holds a reference here; the page is freed when the
+    // last one goes.
+    pshare: &'i PageShares<'i, DM>,
     page_table: RootPageTable<'i, DM>,
 }
 
@@ -25,6 +88,7 @@ impl<'i, DM: DirectMap> Vmm<'i, DM> {
     pub fn new(
         kernel_page_table: &'i RootPageTable<'i, DM>,
         kalloc: &'i KernelAllocator<'i, DM>,
+        pshare: &'i PageShares<'i, DM>,
     ) -> Result<Self> {
         Ok(Self {
             heap_base: USER_HEAP_BASE,
@@ -32,27 +96,123 @@ impl<'i, DM: DirectMap> Vmm<'i, DM> {
             brk_mapped_end: USER_HEAP_BASE,
             mmap_base: USER_MMAP_BASE,
             mmap_next: USER_MMAP_BASE,
+            mapped_bytes: 0,
+            resident_bytes: 0,
+            address_space_limit: usize::MAX,
+            vmas: BTreeMap::new(),
             kalloc,
+            pshare,
             page_table: RootPageTable::new(kernel_page_table, kalloc)?,
         })
     }
 
+    /// A new address space with the same layout as this one. Pages mapped
+    /// MAP_SHARED are mapped into both; every other page is copied.
+    pub fn duplicate(&mut self, kernel_page_table: &'i RootPageTable<'i, DM>) -> Result<Self> {
+        let mut child = Self::new(kernel_page_table, self.kalloc, self.pshare)?;
+        child.heap_base = self.heap_base;
+        child.brk = self.brk;
+        child.brk_mapped_end = self.brk_mapped_end;
+        child.mmap_base = self.mmap_base;
+        child.mmap_next = self.mmap_next;
+        child.mapped_bytes = self.mapped_bytes;
+        child.resident_bytes = self.resident_bytes;
+        child.address_space_limit = self.address_space_limit;
+        child.vmas = self.vmas.clone();
+
+        let (kalloc, map) = (self.kalloc, self.kalloc.direct_map());
+        let pshare = self.pshare;
+        self.page_table.for_each_user_page(|vaddr, entry| {
+            let flags = pshare.frame(entry.addr())?.flags;
+            if flags.shared() {
+                return child.map_frame(entry.addr(), vaddr, entry.access(), flags);
+            }
+
+            let copy = kalloc.alloc(USER_PAGE_SIZE)?;
+            unsafe {
+                copy_nonoverlapping(
+                    entry.addr().to_virtual(map).as_ptr::<u8>(),
+                    copy.to_virtual(map).as_ptr::<u8>(),
+                    USER_PAGE_SIZE,
+                );
+            }
+            if let Err(err) = child.map_frame(copy, vaddr, entry.access(), flags) {
+                kalloc.free(copy, USER_PAGE_SIZE)?;
+                return Err(err);
+            }
+            Ok(())
+        })?;
+
+        Ok(child)
+    }
+
     pub fn root(&self) -> PhysicalAddr {
         self.page_table.addr()
     }
 
-    fn map_user_memory(&mut self, paddr: PhysicalAddr, vaddr: VirtualAddr) -> Result<()> {
-        let pde = self.page_table.get(vaddr)?;
-        if pde.is_present() {
+    /// The mapped regions in address order.
+    pub fn vmas(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// Bytes of address space reserved through brk and mmap, whether or not
+    /// their pages have been touched yet.
+    pub fn mapped_bytes(&self) -> usize {
+        self.mapped_bytes
+    }
+
+    /// Bytes of user pages actually backed by memory (the RSS).
+    pub fn resident_bytes(&self) -> usize {
+        self.resident_bytes
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            mapped_bytes: self.mapped_bytes,
+            resident_bytes: self.resident_bytes,
+        }
+    }
+
+    /// Cap on `mapped_bytes` (RLIMIT_AS). Existing mappings above a lowered
+    /// limit stay in place; only further growth fails.
+    pub fn set_address_space_limit(&mut self, limit: usize) {
+        self.address_space_limit = limit;
+    }
+
+    fn check_address_space(&self, additional: usize) -> Result<()> {
+        let within_limit = self
+            .mapped_bytes
+            .checked_add(additional)
+            .is_some_and(|total| total <= self.address_space_limit);
+        if within_limit {
+            Ok(())
+        } else {
+            Err(MemoryError::AddressSpaceLimit {
+                requested: additional,
+                limit: self.address_space_limit,
+            })
+        }
+    }
+
+    fn map_user_memory(
+        &mut self,
+        paddr: PhysicalAddr,
+        vaddr: VirtualAddr,
+        access: PageAccess,
+    ) -> Result<()> {
+        let pte = self.page_table.get(vaddr, USER_PAGE)?;
+        if pte.is_mapped() {
             return Err(MemoryError::AlreadyMapped {
                 addr: vaddr.as_usize(),
             });
         }
-        pde.set_paddr(paddr);
+        pte.set_paddr(paddr, USER_PAGE, access);
 
         Ok(())
     }
 
+    /// Move the program break. Lowering it unmaps the pages wholly above the
+    /// new break and gives them back to the allocator.
     pub fn brk(&mut self, requested: usize) -> Result<usize> {
         if requested == 0 {
             return Ok(self.brk);
@@ -61,26 +221,56 @@ impl<'i, DM: DirectMap> Vmm<'i, DM> {
             return Err(MemoryError::VirtualToPhysical { addr: requested });
         }
 
-        let target_mapped_end = align_up(requested, PAGE_SIZE).ok_or(MemoryError::OutOfMemory)?;
+        let target_mapped_end =
+            align_up(requested, USER_PAGE_SIZE).ok_or(MemoryError::OutOfMemory)?;
+        self.check_address_space(target_mapped_end.saturating_sub(self.brk_mapped_end))?;
         while self.brk_mapped_end < target_mapped_end {
-            self.map_user_page(self.brk_mapped_end)?;
-            self.brk_mapped_end += PAGE_SIZE;
+            self.map_user_page(
+                self.brk_mapped_end,
+                PageAccess::ReadWrite,
+                FrameFlags::empty(),
+            )?;
+            self.brk_mapped_end += USER_PAGE_SIZE;
+        }
+        while self.brk_mapped_end > target_mapped_end {
+            self.unmap_user_page(self.brk_mapped_end - USER_PAGE_SIZE)?;
+            self.brk_mapped_end -= USER_PAGE_SIZE;
+        }
+
+        if let Some(heap) = self.vmas.remove(&self.heap_base) {
+            self.mapped_bytes -= heap.len;
+        }
+        if self.brk_mapped_end > self.heap_base {
+            self.mapped_bytes += self.brk_mapped_end - self.heap_base;
+            self.vmas.insert(
+                self.heap_base,
+                Vma {
+                    start: self.heap_base,
+                    len: self.brk_mapped_end - self.heap_base,
+                    prot: PROT_READ | PROT_WRITE,
+                    flags: MAP_PRIVATE | MAP_ANONYMOUS,
+                    backing: Backing::Heap,
+                },
+            );
         }
 
         self.brk = requested;
         Ok(requested)
     }
 
-    pub fn mmap(&mut self, hint: usize, len: usize, flags: u64) -> Result<usize> {
+    pub fn mmap(&mut self, hint: usize, len: usize, prot: u64, flags: u64) -> Result<usize> {
         if len == 0 {
             return Err(MemoryError::InvalidPageCount { pages: 0 });
         }
+        let access = page_access(prot);
 
-        let len_aligned = align_up(len, PAGE_SIZE).ok_or(MemoryError::OutOfMemory)?;
-        let brk_limit = align_up(self.brk, PAGE_SIZE).ok_or(MemoryError::OutOfMemory)?;
+        let len_aligned = align_up(len, USER_PAGE_SIZE).ok_or(MemoryError::OutOfMemory)?;
+        let brk_limit = align_up(self.brk, USER_PAGE_SIZE).ok_or(MemoryError::OutOfMemory)?;
 
-        if flags & MAP_FIXED != 0 {
-            if hint == 0 || hint % PAGE_SIZE != 0 {
+        // MAP_FIXED replaces whatever is mapped in the range, while
+        // MAP_FIXED_NOREPLACE fails instead.
+        if flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+            if hint == 0 || hint % USER_PAGE_SIZE != 0 {
                 return Err(MemoryError::VirtualToPhysical { addr: hint });
             }
             let start = hint;
@@ -90,16 +280,22 @@ impl<'i, DM: DirectMap> Vmm<'i, DM> {
             if start < self.mmap_base || start < brk_limit || end > USER_MMAP_LIMIT {
                 return Err(MemoryError::OutOfMemory);
             }
-            if !self.range_is_unmapped(start, end)? {
+            let replaced = self.mapped_bytes_in(start, end);
+            if flags & MAP_FIXED_NOREPLACE != 0 && replaced != 0 {
                 return Err(MemoryError::AlreadyMapped { addr: start });
             }
-            self.map_user_range(start, end)?;
+            self.check_address_space(len_aligned - replaced)?;
+            self.remove_vmas(start, end)?;
+            let vma = self.insert_vma(start, len_aligned, prot, flags);
+            self.populate(vma, access)?;
             return Ok(start);
         }
 
+        self.check_address_space(len_aligned)?;
+
         let mut start = self.mmap_next.max(self.mmap_base);
         if hint != 0 {
-            let hinted = align_up(hint, PAGE_SIZE).ok_or(MemoryError::OutOfMemory)?;
+            let hinted = align_up(hint, USER_PAGE_SIZE).ok_or(MemoryError::OutOfMemory)?;
             if hinted > start {
                 start = hinted;
             }
@@ -116,47 +312,224 @@ impl<'i, DM: DirectMap> Vmm<'i, DM> {
                 return Err(MemoryError::OutOfMemory);
             }
 
-            if self.range_is_unmapped(start, end)? {
-                self.map_user_range(start, end)?;
-                self.mmap_next = end;
-                return Ok(start);
+            match self.last_vma_before(end).filter(|vma| vma.end() > start) {
+                Some(vma) => start = vma.end(),
+                None => {
+                    let vma = self.insert_vma(start, len_aligned, prot, flags);
+                    self.mmap_next = end;
+                    self.populate(vma, access)?;
+                    return Ok(start);
+                }
             }
+        }
+    }
 
-            start = start
-                .checked_add(PAGE_SIZE)
-                .ok_or(MemoryError::OutOfMemory)?;
+    /// Back the page holding `addr` after a fault on it. Anonymous mappings
+    /// get their pages on first touch rather than at mmap time.
+    pub fn handle_fault(&mut self, addr: usize, write: bool) -> Result<()> {
+        let vma = self
+            .last_vma_before(addr + 1)
+            .filter(|vma| vma.end() > addr)
+            .ok_or(MemoryError::AccessViolation { addr })?;
+        let access = page_access(vma.prot);
+        if access == PageAccess::None || (write && access != PageAccess::ReadWrite) {
+            return Err(MemoryError::AccessViolation { addr });
         }
+
+        let page = addr & !(USER_PAGE_SIZE - 1);
+        if self
+            .page_table
+            .get_if_present(VirtualAddr::new(page))?
+            .is_some()
+        {
+            // Another access already brought the page in.
+            return Ok(());
+        }
+        self.map_user_page(page, access, vma.frame_flags())
     }
 
-    fn range_is_unmapped(&mut self, start: usize, end: usize) -> Result<bool> {
-        let mut vaddr = start;
-        while vaddr < end {
-            let entry = self.page_table.get_if_present(VirtualAddr::new(vaddr))?;
-            if entry.is_some_and(|e| e.is_present()) {
-                return Ok(false);
+    // Shared pages are mapped up front, like MAP_POPULATE ones: a page first
+    // touched after a fork has to be the same page in both processes.
+    fn populate(&mut self, vma: Vma, access: PageAccess) -> Result<()> {
+        let flags = vma.frame_flags();
+        if flags.shared() || vma.flags & MAP_POPULATE != 0 {
+            self.map_user_range(vma.start, vma.end(), access, flags)?;
+        }
+        Ok(())
+    }
+
+    // The only region that can overlap a range ending at `end` is the last
+    // one starting below it.
+    fn last_vma_before(&self, end: usize) -> Option<Vma> {
+        self.vmas.range(..end).next_back().map(|(_, vma)| *vma)
+    }
+
+    fn overlapping_vmas(&self, start: usize, end: usize) -> impl Iterator<Item = Vma> + '_ {
+        self.vmas
+            .range(..end)
+            .map(|(_, vma)| *vma)
+            .filter(move |vma| vma.end() > start)
+    }
+
+    fn mapped_bytes_in(&self, start: usize, end: usize) -> usize {
+        self.overlapping_vmas(start, end)
+            .map(|vma| vma.end().min(end) - vma.start.max(start))
+            .sum()
+    }
+
+    fn insert_vma(&mut self, start: usize, len: usize, prot: u64, flags: u64) -> Vma {
+        self.mapped_bytes += len;
+        let vma = Vma {
+            start,
+            len,
+            prot,
+            flags,
+            backing: Backing::Anonymous,
+        };
+        self.vmas.insert(start, vma);
+        vma
+    }
+
+    /// Unmap `[start, end)`, trimming or splitting the regions that straddle
+    /// its edges.
+    fn remove_vmas(&mut self, start: usize, end: usize) -> Result<()> {
+        self.mapped_bytes -= self.mapped_bytes_in(start, end);
+        let overlapping: Vec<Vma> = self.overlapping_vmas(start, end).collect();
+        for vma in overlapping {
+            self.vmas.remove(&vma.start);
+            if vma.start < start {
+                self.vmas.insert(
+                    vma.start,
+                    Vma {
+                        len: start - vma.start,
+                        ..vma
+                    },
+                );
+            }
+            if vma.end() > end {
+                self.vmas.insert(
+                    end,
+                    Vma {
+                        start: end,
+                        len: vma.end() - end,
+                        ..vma
+                    },
+                );
             }
-            vaddr += PAGE_SIZE;
         }
-        Ok(true)
+        self.unmap_user_range(start, end)
     }
 
-    fn map_user_range(&mut self, start: usize, end: usize) -> Result<()> {
+    fn map_user_range(
+        &mut self,
+        start: usize,
+        end: usize,
+        access: PageAccess,
+        flags: FrameFlags,
+    ) -> Result<()> {
         let mut vaddr = start;
         while vaddr < end {
-            self.map_user_page(vaddr)?;
-            vaddr += PAGE_SIZE;
+            self.map_user_page(vaddr, access, flags)?;
+            vaddr += USER_PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    fn map_user_page(&mut self, vaddr: usize, access: PageAccess, flags: FrameFlags) -> Result<()> {
+        // User pages share a size class with page tables, so never hand one out
+        // with old contents.
+        let paddr = self.kalloc.calloc(USER_PAGE_SIZE)?;
+        if let Err(err) = self.map_frame(paddr, VirtualAddr::new(vaddr), access, flags) {
+            self.kalloc.free(paddr, USER_PAGE_SIZE)?;
+            return Err(err);
         }
+        self.resident_bytes += USER_PAGE_SIZE;
         Ok(())
     }
 
-    fn map_user_page(&mut self, vaddr: usize) -> Result<()> {
-        let paddr = self.kalloc.alloc(PAGE_SIZE)?;
-        if let Err(err) = self.map_user_memory(paddr, VirtualAddr::new(vaddr)) {
-            self.kalloc.free(paddr, PAGE_SIZE)?;
+    // Map `paddr` at `vaddr`, taking a reference on it.
+    fn map_frame(
+        &mut self,
+        paddr: PhysicalAddr,
+        vaddr: VirtualAddr,
+        access: PageAccess,
+        flags: FrameFlags,
+    ) -> Result<()> {
+        self.pshare.get(paddr, self.root().as_u64(), flags)?;
+        if let Err(err) = self.map_user_memory(paddr, vaddr, access) {
+            self.pshare.put(paddr)?;
             return Err(err);
         }
         Ok(())
     }
+
+    fn unmap_user_range(&mut self, start: usize, end: usize) -> Result<()> {
+        let mut vaddr = start;
+        while vaddr < end {
+            if let Some(paddr) = self.page_table.unmap(VirtualAddr::new(vaddr))? {
+                release(self.kalloc, self.pshare, paddr)?;
+                self.resident_bytes -= USER_PAGE_SIZE;
+            }
+            vaddr += USER_PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    fn unmap_user_page(&mut self, vaddr: usize) -> Result<()> {
+        let paddr = self
+            .page_table
+            .unmap(VirtualAddr::new(vaddr))?
+            .ok_or(MemoryError::VirtualToPhysical { addr: vaddr })?;
+        release(self.kalloc, self.pshare, paddr)?;
+        self.resident_bytes -= USER_PAGE_SIZE;
+        Ok(())
+    }
+}
+
+impl<DM: DirectMap> Drop for Vmm<'_, DM> {
+    fn drop(&mut self) {
+        let (kalloc, pshare) = (self.kalloc, self.pshare);
+        let released = self.page_table.for_each_user_page(|vaddr, entry| {
+            let paddr = entry.addr();
+            entry.clear();
+            if let Err(err) = release(kalloc, pshare, paddr) {
+                crate::println!(
+                    "vmm: leaking page {:#x} at {:#x}: {}",
+                    paddr.as_usize(),
+                    vaddr.as_usize(),
+                    err
+                );
+            }
+            Ok(())
+        });
+        if let Err(err) = released {
+            crate::println!("vmm: failed to release user pages: {}", err);
+        }
+    }
+}
+
+// Drop a mapping's reference on `paddr`, freeing the page with the last one.
+fn release<DM: DirectMap>(
+    kalloc: &KernelAllocator<DM>,
+    pshare: &PageShares<DM>,
+    paddr: PhysicalAddr,
+) -> Result<()> {
+    if pshare.put(paddr)? == 0 {
+        kalloc.free(paddr, USER_PAGE_SIZE)?;
+    }
+    Ok(())
+}
+
+// x86 pages cannot be write-only, and user memory is never executed, so
+// PROT_WRITE implies read and PROT_EXEC maps like PROT_READ.
+fn page_access(prot: u64) -> PageAccess {
+    if prot & PROT_WRITE != 0 {
+        PageAccess::ReadWrite
+    } else if prot & (PROT_READ | PROT_EXEC) != 0 {
+        PageAccess::Read
+    } else {
+        PageAccess::None
+    }
 }
 
 fn align_up(value: usize, align: usize) -> Option<usize> {
@@ -0,0 +1,46 @@
+use thiserror::Error as ThisError;
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    #[error("address already in use")]
+    AddressInUse,
+
+    #[error("address not available")]
+    AddressNotAvailable,
+
+    #[error("connection refused")]
+    ConnectionRefused,
+
+    #[error("socket is not connected")]
+    NotConnected,
+
+    #[error("socket is already connected")]
+    AlreadyConnected,
+
+    #[error("peer closed the connection")]
+    BrokenPipe,
+
+    #[error("destination address required")]
+    DestinationRequired,
+
+    #[error("message of {len} bytes exceeds {max} bytes")]
+    MessageTooLong { len: usize, max: usize },
+
+    #[error("protocol wrong type for socket")]
+    WrongType,
+
+    #[error("operation not supported on socket")]
+    NotSupported,
+
+    #[error("descriptor is not a socket")]
+    NotSocket,
+
+    #[error("network is unreachable")]
+    NetworkUnreachable,
+
+    #[error("network is down")]
+    NetworkDown,
+
+    #[error("no socket buffers available")]
+    NoBuffers,
+}
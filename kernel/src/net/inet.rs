@@ -0,0 +1,664 @@
+use core::ptr::NonNull;
+
+use smoltcp::iface::{Config, Interface, PollResult, SocketHandle, SocketSet, SocketStorage};
+use smoltcp::socket::tcp::{self, State as TcpState};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpListenEndpoint, Ipv4Address,
+};
+use spin::Mutex;
+
+use super::errors::NetError;
+use super::loopback::{self, Loopback, Packet};
+use super::uplink::{self, Uplink};
+use super::virtio_net::VirtioNet;
+use crate::fs::Readiness;
+use crate::fs::errors::{FsError, Result};
+use crate::memory::{address::DirectMap, alloc::palloc::PageAllocator, constants::PAGE_SIZE};
+
+pub const MAX_SOCKETS: usize = 16;
+pub const MAX_BACKLOG: usize = 4;
+pub const BUFFER_SIZE: usize = 8192;
+pub const LOOPBACK_ADDR: Ipv4Address = Ipv4Address::new(127, 0, 0, 1);
+
+// smoltcp sockets, including the spares a listener keeps for its backlog.
+const MAX_CONNECTIONS: usize = 32;
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+static STACK: Mutex<Option<InetStack>> = Mutex::new(None);
+
+/// Memory lent to smoltcp for as long as the stack exists: socket slots,
+/// a receive and a transmit buffer per connection and the loopback queue.
+#[repr(C)]
+pub struct Arena {
+    sockets: [SocketStorage<'static>; MAX_CONNECTIONS],
+    buffers: [[u8; 2 * BUFFER_SIZE]; MAX_CONNECTIONS],
+    packets: [Packet; loopback::QUEUE_LEN],
+    scratch: Packet,
+}
+
+const _: () = assert!(size_of::<Arena>() <= PAGE_SIZE);
+
+impl Arena {
+    /// # Safety
+    ///
+    /// `ptr` must be valid and suitably aligned for an `Arena`, and the
+    /// memory must not be used for anything else afterwards.
+    pub unsafe fn init(ptr: *mut Arena) -> &'static mut Arena {
+        unsafe {
+            core::ptr::write_bytes(ptr.cast::<u8>(), 0, size_of::<Arena>());
+            for slot in 0..MAX_CONNECTIONS {
+                (&raw mut (*ptr).sockets[slot]).write(SocketStorage::EMPTY);
+            }
+            &mut *ptr
+        }
+    }
+}
+
+// Connection buffers are handed to smoltcp one pair at a time, so they are
+// kept as a raw pointer rather than a borrow of the whole array.
+struct Buffers(NonNull<[u8; 2 * BUFFER_SIZE]>);
+
+// SAFETY: the buffers are only reached through the stack that owns them.
+unsafe impl Send for Buffers {}
+
+impl Buffers {
+    /// # Safety
+    ///
+    /// No socket may still hold the buffers of connection `index`.
+    unsafe fn pair(&mut self, index: usize) -> (&'static mut [u8], &'static mut [u8]) {
+        let pair = unsafe { &mut *self.0.as_ptr().add(index) };
+        pair.split_at_mut(BUFFER_SIZE)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Connection {
+    handle: SocketHandle,
+    // Closed by its descriptor; removed once the handshake has finished.
+    orphaned: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Open,
+    Listening {
+        backlog: [Option<usize>; MAX_BACKLOG],
+        size: usize,
+    },
+    Stream {
+        conn: usize,
+        connecting: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Socket {
+    local: Option<IpListenEndpoint>,
+    state: State,
+}
+
+/// TCP over IPv4. Every socket operation polls the interface, which is the
+/// only way packets move: there are no interrupts to drive it.
+pub struct InetStack {
+    iface: Interface,
+    device: Loopback,
+    sockets: SocketSet<'static>,
+    buffers: Buffers,
+    connections: [Option<Connection>; MAX_CONNECTIONS],
+    slots: [Option<Socket>; MAX_SOCKETS],
+    next_port: u16,
+}
+
+impl InetStack {
+    pub fn new(arena: &'static mut Arena, seed: u64, now: Instant) -> Self {
+        let mut device = Loopback::new(&mut arena.packets, &mut arena.scratch);
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = seed;
+        let mut iface = Interface::new(config, &mut device, now);
+        iface.update_ip_addrs(|addrs| {
+            addrs
+                .push(IpCidr::new(IpAddress::Ipv4(LOOPBACK_ADDR), 8))
+                .expect("interface has room for the loopback address");
+        });
+
+        Self {
+            iface,
+            device,
+            sockets: SocketSet::new(&mut arena.sockets[..]),
+            buffers: Buffers(NonNull::from(&mut arena.buffers).cast()),
+            connections: [None; MAX_CONNECTIONS],
+            slots: [None; MAX_SOCKETS],
+            next_port: FIRST_EPHEMERAL_PORT,
+        }
+    }
+
+    /// Reach beyond the loopback network through `uplink`, which gets the
+    /// route to everywhere else.
+    pub fn attach(&mut self, mut uplink: Uplink<VirtioNet>) {
+        let config = uplink.config();
+        self.iface.update_ip_addrs(|addrs| {
+            addrs
+                .push(IpCidr::Ipv4(config.addr))
+                .expect("interface has room for the uplink address");
+        });
+        self.iface
+            .routes_mut()
+            .add_default_ipv4_route(config.gateway)
+            .expect("interface has room for the default route");
+        uplink.announce();
+        self.device.attach(uplink);
+    }
+
+    /// Move packets and drop closed connections nobody refers to. Returns
+    /// whether any socket may have changed state.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        let changed = self.iface.poll(now, &mut self.device, &mut self.sockets)
+            == PollResult::SocketStateChanged;
+        for index in 0..MAX_CONNECTIONS {
+            let Some(conn) = self.connections[index].filter(|conn| conn.orphaned) else {
+                continue;
+            };
+            let state = self.sockets.get::<tcp::Socket>(conn.handle).state();
+            if matches!(state, TcpState::Closed | TcpState::TimeWait) {
+                self.sockets.remove(conn.handle);
+                self.connections[index] = None;
+            }
+        }
+        changed
+    }
+
+    pub fn create(&mut self) -> Result<usize> {
+        let id = self
+            .slots
+            .iter()
+            .position(Option::is_none)
+            .ok_or(NetError::NoBuffers)?;
+        self.slots[id] = Some(Socket {
+            local: None,
+            state: State::Open,
+        });
+        Ok(id)
+    }
+
+    /// Close a socket. Streams finish with a FIN; connections still waiting
+    /// in a listener's backlog are reset.
+    pub fn close(&mut self, id: usize) {
+        let Some(socket) = self.slots.get_mut(id).and_then(Option::take) else {
+            return;
+        };
+        match socket.state {
+            State::Open => {}
+            State::Listening { backlog, .. } => {
+                for conn in backlog.into_iter().flatten() {
+                    self.tcp(conn).abort();
+                    self.release(conn);
+                }
+            }
+            State::Stream { conn, .. } => {
+                self.tcp(conn).close();
+                self.release(conn);
+            }
+        }
+    }
+
+    /// Bind to a local address and port. Port 0 picks an ephemeral port.
+    pub fn bind(&mut self, id: usize, local: IpListenEndpoint) -> Result<()> {
+        let socket = self.socket(id)?;
+        if socket.local.is_some() || socket.state != State::Open {
+            return Err(FsError::InvalidArgument);
+        }
+        let addr = local.addr.filter(|addr| !addr.is_unspecified());
+        if addr.is_some_and(|addr| !self.iface.has_ip_addr(addr)) {
+            return Err(NetError::AddressNotAvailable.into());
+        }
+        let port = match local.port {
+            0 => self.ephemeral_port()?,
+            port if self.port_in_use(port) => return Err(NetError::AddressInUse.into()),
+            port => port,
+        };
+        self.socket(id)?.local = Some(IpListenEndpoint { addr, port });
+        Ok(())
+    }
+
+    pub fn listen(&mut self, id: usize, backlog: usize) -> Result<()> {
+        match self.socket(id)?.state {
+            State::Open => {}
+            State::Listening { .. } => return Ok(()),
+            State::Stream { .. } => return Err(FsError::InvalidArgument),
+        }
+        if self.socket(id)?.local.is_none() {
+            self.bind(id, IpListenEndpoint::default())?;
+        }
+
+        let size = backlog.clamp(1, MAX_BACKLOG);
+        let mut entries = [None; MAX_BACKLOG];
+        entries[0] = Some(self.listening_connection(id)?);
+        for entry in &mut entries[1..size] {
+            *entry = self.listening_connection(id).ok();
+        }
+        self.socket(id)?.state = State::Listening {
+            backlog: entries,
+            size,
+        };
+        Ok(())
+    }
+
+    /// Start or continue connecting to `remote`. The first call sends the
+    /// SYN and reports WouldBlock; later calls do the same until the
+    /// handshake finishes or is refused.
+    pub fn connect(&mut self, id: usize, remote: IpEndpoint) -> Result<()> {
+        let socket = *self.socket(id)?;
+        match socket.state {
+            State::Open => {}
+            State::Listening { .. } => return Err(FsError::InvalidArgument),
+            State::Stream {
+                connecting: false, ..
+            } => return Err(NetError::AlreadyConnected.into()),
+            State::Stream {
+                conn,
+                connecting: true,
+            } => return self.finish_connect(id, conn),
+        }
+
+        if !self.routable(remote.addr) {
+            return Err(NetError::NetworkUnreachable.into());
+        }
+        let mut local = match socket.local {
+            Some(local) => local,
+            None => IpListenEndpoint::from(self.ephemeral_port()?),
+        };
+        // smoltcp would send from its first address whatever the
+        // destination.
+        local.addr = local.addr.or_else(|| self.source_addr(remote.addr));
+        let conn = self.open_connection()?;
+        let cx = self.iface.context();
+        let started = self
+            .sockets
+            .get_mut::<tcp::Socket>(self.connections[conn].expect("connection is open").handle)
+            .connect(cx, remote, local);
+        if started.is_err() {
+            self.release(conn);
+            return Err(FsError::InvalidArgument);
+        }
+        self.socket(id)?.state = State::Stream {
+            conn,
+            connecting: true,
+        };
+        Err(FsError::WouldBlock)
+    }
+
+    /// Take the first fully established connection off a listener, along
+    /// with the peer's address.
+    pub fn accept(&mut self, id: usize) -> Result<(usize, Option<IpEndpoint>)> {
+        let State::Listening { mut backlog, size } = self.socket(id)?.state else {
+            return Err(FsError::InvalidArgument);
+        };
+        // Top up entries that could not be replaced for lack of buffers.
+        for entry in backlog[..size].iter_mut().filter(|entry| entry.is_none()) {
+            *entry = self.listening_connection(id).ok();
+        }
+        self.socket(id)?.state = State::Listening { backlog, size };
+
+        let ready = backlog.iter().position(|entry| {
+            entry.is_some_and(|conn| {
+                matches!(
+                    self.tcp(conn).state(),
+                    TcpState::Established | TcpState::CloseWait
+                )
+            })
+        });
+        let Some(index) = ready else {
+            return Err(FsError::WouldBlock);
+        };
+        let server = self.create()?;
+        let conn = backlog[index].take().expect("ready entry is set");
+        self.slots[server] = Some(Socket {
+            local: None,
+            state: State::Stream {
+                conn,
+                connecting: false,
+            },
+        });
+        backlog[index] = self.listening_connection(id).ok();
+        self.socket(id)?.state = State::Listening { backlog, size };
+        Ok((server, self.tcp(conn).remote_endpoint()))
+    }
+
+    pub fn send(&mut self, id: usize, data: &[u8]) -> Result<usize> {
+        let socket = self.stream(id)?;
+        match socket.state() {
+            TcpState::SynSent | TcpState::SynReceived => Err(FsError::WouldBlock),
+            _ if !socket.may_send() => Err(NetError::BrokenPipe.into()),
+            _ if data.is_empty() => Ok(0),
+            _ => match socket.send_slice(data) {
+                Ok(0) => Err(FsError::WouldBlock),
+                Ok(sent) => Ok(sent),
+                Err(_) => Err(NetError::BrokenPipe.into()),
+            },
+        }
+    }
+
+    /// Read buffered data. A peer that has closed its end reads as end of
+    /// file once the buffer is drained.
+    pub fn recv(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let socket = self.stream(id)?;
+        match socket.state() {
+            TcpState::SynSent | TcpState::SynReceived => Err(FsError::WouldBlock),
+            _ if buf.is_empty() => Ok(0),
+            _ => match socket.recv_slice(buf) {
+                Ok(0) if socket.may_recv() => Err(FsError::WouldBlock),
+                Ok(len) => Ok(len),
+                Err(_) => Ok(0),
+            },
+        }
+    }
+
+    pub fn readiness(&mut self, id: usize) -> Readiness {
+        let Ok(socket) = self.socket(id) else {
+            return Readiness::default();
+        };
+        match socket.state {
+            State::Open => Readiness::default(),
+            State::Listening { backlog, .. } => Readiness {
+                readable: backlog.into_iter().flatten().any(|conn| {
+                    matches!(
+                        self.tcp(conn).state(),
+                        TcpState::Established | TcpState::CloseWait
+                    )
+                }),
+                writable: false,
+            },
+            State::Stream { conn, .. } => {
+                let socket = self.tcp(conn);
+                if matches!(socket.state(), TcpState::SynSent | TcpState::SynReceived) {
+                    return Readiness::default();
+                }
+                Readiness {
+                    readable: socket.can_recv() || !socket.may_recv(),
+                    writable: socket.can_send() || !socket.may_send(),
+                }
+            }
+        }
+    }
+
+    fn finish_connect(&mut self, id: usize, conn: usize) -> Result<()> {
+        match self.tcp(conn).state() {
+            TcpState::SynSent | TcpState::SynReceived => Err(FsError::WouldBlock),
+            TcpState::Closed => {
+                self.release(conn);
+                self.socket(id)?.state = State::Open;
+                Err(NetError::ConnectionRefused.into())
+            }
+            _ => {
+                self.socket(id)?.state = State::Stream {
+                    conn,
+                    connecting: false,
+                };
+                Ok(())
+            }
+        }
+    }
+
+    fn listening_connection(&mut self, id: usize) -> Result<usize> {
+        let local = self.socket(id)?.local.expect("listener is bound");
+        let conn = self.open_connection()?;
+        if self.tcp(conn).listen(local).is_err() {
+            self.release(conn);
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(conn)
+    }
+
+    fn open_connection(&mut self) -> Result<usize> {
+        let index = self
+            .connections
+            .iter()
+            .position(Option::is_none)
+            .ok_or(NetError::NoBuffers)?;
+        // SAFETY: the slot is free, so its previous socket has been removed.
+        let (rx, tx) = unsafe { self.buffers.pair(index) };
+        let socket = tcp::Socket::new(tcp::SocketBuffer::new(rx), tcp::SocketBuffer::new(tx));
+        let handle = self.sockets.add(socket);
+        self.connections[index] = Some(Connection {
+            handle,
+            orphaned: false,
+        });
+        Ok(index)
+    }
+
+    fn release(&mut self, conn: usize) {
+        if let Some(conn) = self.connections[conn].as_mut() {
+            conn.orphaned = true;
+        }
+    }
+
+    fn ephemeral_port(&mut self) -> Result<u16> {
+        for _ in FIRST_EPHEMERAL_PORT..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+            if !self.port_in_use(port) {
+                return Ok(port);
+            }
+        }
+        Err(NetError::AddressInUse.into())
+    }
+
+    // Without an uplink there are no routes beyond the loopback subnet.
+    fn routable(&self, addr: IpAddress) -> bool {
+        self.source_addr(addr).is_some()
+    }
+
+    // The address of the subnet `remote` is on, or the uplink's for
+    // anything past the gateway.
+    fn source_addr(&self, remote: IpAddress) -> Option<IpAddress> {
+        self.iface
+            .ip_addrs()
+            .iter()
+            .find(|cidr| cidr.contains_addr(&remote))
+            .map(|cidr| cidr.address())
+            .or_else(|| Some(IpAddress::Ipv4(self.device.uplink_config()?.addr.address())))
+    }
+
+    fn port_in_use(&self, port: u16) -> bool {
+        let bound = self
+            .slots
+            .iter()
+            .flatten()
+            .any(|socket| socket.local.is_some_and(|local| local.port == port));
+        bound
+            || self.connections.iter().flatten().any(|conn| {
+                let socket = self.sockets.get::<tcp::Socket>(conn.handle);
+                socket
+                    .local_endpoint()
+                    .is_some_and(|local| local.port == port)
+            })
+    }
+
+    fn stream(&mut self, id: usize) -> Result<&mut tcp::Socket<'static>> {
+        match self.socket(id)?.state {
+            State::Stream { conn, .. } => Ok(self.tcp(conn)),
+            _ => Err(NetError::NotConnected.into()),
+        }
+    }
+
+    fn tcp(&mut self, conn: usize) -> &mut tcp::Socket<'static> {
+        let handle = self.connections[conn].expect("connection is open").handle;
+        self.sockets.get_mut::<tcp::Socket>(handle)
+    }
+
+    fn socket(&mut self, id: usize) -> Result<&mut Socket> {
+        self.slots
+            .get_mut(id)
+            .and_then(Option::as_mut)
+            .ok_or(FsError::BadDescriptor)
+    }
+}
+
+/// Bring up the stack on a page of its own.
+pub fn init(palloc: &PageAllocator, dm: &impl DirectMap) -> Result<()> {
+    let page = palloc.alloc(1).map_err(|_| FsError::NoSpace)?;
+    // SAFETY: the page was just allocated and is never freed.
+    let arena = unsafe { Arena::init(page.to_virtual(dm).as_ptr()) };
+    let mut stack = InetStack::new(arena, crate::time::rdtsc(), now());
+    if let Some(nic) = VirtioNet::probe(palloc, dm)? {
+        let uplink = Uplink::new(nic, uplink::DEFAULT_CONFIG);
+        crate::println!(
+            "net: virtio-net {} at {} via {}",
+            uplink.mac(),
+            uplink.config().addr,
+            uplink.config().gateway
+        );
+        stack.attach(uplink);
+        // Answer whatever arrived while the device came up.
+        stack.poll(now());
+    }
+    *STACK.lock() = Some(stack);
+    Ok(())
+}
+
+/// Run `f` on the shared stack, polling the interface before and after so
+/// that whatever `f` queued is on its way.
+pub fn with_stack<T>(f: impl FnOnce(&mut InetStack) -> Result<T>) -> Result<T> {
+    let mut stack = STACK.lock();
+    let stack = stack.as_mut().ok_or(NetError::NetworkDown)?;
+    stack.poll(now());
+    let result = f(stack);
+    stack.poll(now());
+    result
+}
+
+/// Poll the shared stack from the timer tick: no interrupt moves packets, so
+/// sockets nobody touches would otherwise stand still. Returns whether any
+/// socket may have changed state, and false when the tick interrupted a
+/// holder of the stack.
+pub fn poll_pending() -> bool {
+    let Some(mut stack) = STACK.try_lock() else {
+        return false;
+    };
+    stack.as_mut().is_some_and(|stack| stack.poll(now()))
+}
+
+fn now() -> Instant {
+    Instant::from_micros(crate::time::monotonic().as_micros() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack() -> InetStack {
+        let layout = std::alloc::Layout::new::<Arena>();
+        let arena = unsafe { Arena::init(std::alloc::alloc(layout).cast()) };
+        InetStack::new(arena, 1, Instant::ZERO)
+    }
+
+    // Poll a few times with the clock moving, long enough for delayed ACKs
+    // and both halves of a close to go through.
+    fn settle(stack: &mut InetStack, clock: &mut Instant) {
+        for _ in 0..4 {
+            *clock += smoltcp::time::Duration::from_millis(20);
+            stack.poll(*clock);
+        }
+    }
+
+    fn endpoint(port: u16) -> IpEndpoint {
+        IpEndpoint::new(IpAddress::Ipv4(LOOPBACK_ADDR), port)
+    }
+
+    fn listener(stack: &mut InetStack, port: u16) -> usize {
+        let id = stack.create().unwrap();
+        stack.bind(id, IpListenEndpoint::from(port)).unwrap();
+        stack.listen(id, 2).unwrap();
+        id
+    }
+
+    #[test]
+    fn loopback_connection_exchanges_data_and_closes() {
+        let mut stack = stack();
+        let mut clock = Instant::ZERO;
+        let server = listener(&mut stack, 80);
+
+        let client = stack.create().unwrap();
+        assert_eq!(stack.accept(server), Err(FsError::WouldBlock));
+        assert_eq!(
+            stack.connect(client, endpoint(80)),
+            Err(FsError::WouldBlock)
+        );
+        settle(&mut stack, &mut clock);
+        assert_eq!(stack.connect(client, endpoint(80)), Ok(()));
+        assert_eq!(
+            stack.connect(client, endpoint(80)),
+            Err(NetError::AlreadyConnected.into())
+        );
+
+        assert!(stack.readiness(server).readable);
+        let (conn, peer) = stack.accept(server).unwrap();
+        assert_eq!(peer.map(|peer| peer.port), Some(FIRST_EPHEMERAL_PORT));
+
+        assert_eq!(stack.send(client, b"hello"), Ok(5));
+        settle(&mut stack, &mut clock);
+        let mut buf = [0; 16];
+        assert_eq!(stack.recv(conn, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(stack.recv(conn, &mut buf), Err(FsError::WouldBlock));
+
+        stack.close(client);
+        settle(&mut stack, &mut clock);
+        assert!(stack.readiness(conn).readable);
+        assert_eq!(stack.recv(conn, &mut buf), Ok(0));
+        stack.close(conn);
+        settle(&mut stack, &mut clock);
+        // Only the listener's backlog is left.
+        assert_eq!(stack.connections.iter().flatten().count(), 2);
+    }
+
+    #[test]
+    fn connecting_to_a_closed_port_is_refused() {
+        let mut stack = stack();
+        let mut clock = Instant::ZERO;
+        let client = stack.create().unwrap();
+
+        assert_eq!(
+            stack.connect(client, endpoint(81)),
+            Err(FsError::WouldBlock)
+        );
+        settle(&mut stack, &mut clock);
+        assert_eq!(
+            stack.connect(client, endpoint(81)),
+            Err(NetError::ConnectionRefused.into())
+        );
+        let remote = IpEndpoint::new(IpAddress::v4(10, 0, 0, 1), 80);
+        assert_eq!(
+            stack.connect(client, remote),
+            Err(NetError::NetworkUnreachable.into())
+        );
+    }
+
+    #[test]
+    fn bind_checks_ports_and_addresses() {
+        let mut stack = stack();
+        let first = listener(&mut stack, 8080);
+        let second = stack.create().unwrap();
+
+        assert_eq!(
+            stack.bind(second, IpListenEndpoint::from(8080)),
+            Err(NetError::AddressInUse.into())
+        );
+        let foreign = IpListenEndpoint {
+            addr: Some(IpAddress::v4(10, 0, 0, 1)),
+            port: 9000,
+        };
+        assert_eq!(
+            stack.bind(second, foreign),
+            Err(NetError::AddressNotAvailable.into())
+        );
+
+        stack.close(first);
+        assert_eq!(stack.bind(second, IpListenEndpoint::from(8080)), Ok(()));
+        assert_eq!(
+            stack.bind(second, IpListenEndpoint::from(8081)),
+            Err(FsError::InvalidArgument)
+        );
+    }
+}
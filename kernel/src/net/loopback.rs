@@ -0,0 +1,207 @@
+use smoltcp::phy::{self, ChecksumCapabilities, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::Ipv4Address;
+
+use super::uplink::{self, Uplink, UplinkConfig};
+use super::virtio_net::VirtioNet;
+
+pub const MTU: usize = 4096;
+pub const QUEUE_LEN: usize = 16;
+
+pub type Packet = [u8; MTU];
+
+/// Transmitted packets waiting to be received again, oldest first.
+struct Queue {
+    packets: &'static mut [Packet; QUEUE_LEN],
+    lens: [usize; QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl Queue {
+    fn is_full(&self) -> bool {
+        self.len == QUEUE_LEN
+    }
+
+    fn pop(&mut self, out: &mut Packet) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let len = self.lens[self.head];
+        out[..len].copy_from_slice(&self.packets[self.head][..len]);
+        self.head = (self.head + 1) % QUEUE_LEN;
+        self.len -= 1;
+        Some(len)
+    }
+
+    // Write a packet into the slot past the newest. It only joins the
+    // queue once committed.
+    fn stage<R>(&mut self, len: usize, f: impl FnOnce(&mut [u8]) -> R) -> (&[u8], R) {
+        assert!(!self.is_full(), "loopback queue overflow");
+        let tail = (self.head + self.len) % QUEUE_LEN;
+        let result = f(&mut self.packets[tail][..len]);
+        (&self.packets[tail][..len], result)
+    }
+
+    fn commit(&mut self, len: usize) {
+        let tail = (self.head + self.len) % QUEUE_LEN;
+        self.lens[tail] = len;
+        self.len += 1;
+    }
+}
+
+/// An IP-level link that hands every packet for the interface's own
+/// addresses back to it, so connections to them work without any device.
+/// Everything else goes out through the uplink, if there is one.
+pub struct Loopback {
+    queue: Queue,
+    scratch: &'static mut Packet,
+    uplink: Option<Uplink<VirtioNet>>,
+}
+
+impl Loopback {
+    pub fn new(packets: &'static mut [Packet; QUEUE_LEN], scratch: &'static mut Packet) -> Self {
+        Self {
+            queue: Queue {
+                packets,
+                lens: [0; QUEUE_LEN],
+                head: 0,
+                len: 0,
+            },
+            scratch,
+            uplink: None,
+        }
+    }
+
+    pub fn attach(&mut self, uplink: Uplink<VirtioNet>) {
+        self.uplink = Some(uplink);
+    }
+
+    pub fn uplink_config(&self) -> Option<UplinkConfig> {
+        self.uplink.as_ref().map(Uplink::config)
+    }
+}
+
+impl Device for Loopback {
+    type RxToken<'a> = RxToken<'a>;
+    type TxToken<'a> = TxToken<'a>;
+
+    // Popping the packet first leaves room for the reply the interface may
+    // send through the paired transmit token.
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken<'_>, TxToken<'_>)> {
+        let len = match self.queue.pop(self.scratch) {
+            Some(len) => len,
+            None => self.uplink.as_mut()?.receive(&mut self.scratch[..])?,
+        };
+        Some((
+            RxToken {
+                packet: &self.scratch[..len],
+            },
+            TxToken {
+                queue: &mut self.queue,
+                uplink: self.uplink.as_mut(),
+            },
+        ))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        (!self.queue.is_full()).then_some(TxToken {
+            queue: &mut self.queue,
+            uplink: self.uplink.as_mut(),
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = match self.uplink {
+            Some(_) => uplink::MTU,
+            None => MTU,
+        };
+        caps.checksum = ChecksumCapabilities::ignored();
+        caps
+    }
+}
+
+pub struct RxToken<'a> {
+    packet: &'a [u8],
+}
+
+impl phy::RxToken for RxToken<'_> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(self.packet)
+    }
+}
+
+pub struct TxToken<'a> {
+    queue: &'a mut Queue,
+    uplink: Option<&'a mut Uplink<VirtioNet>>,
+}
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let (packet, result) = self.queue.stage(len, f);
+        match self.uplink {
+            Some(uplink) if !is_local(packet, uplink.config().addr.address()) => {
+                uplink.send(packet)
+            }
+            _ => self.queue.commit(len),
+        }
+        result
+    }
+}
+
+// Whether `packet` is for the loopback network or the uplink's own address.
+fn is_local(packet: &[u8], uplink_addr: Ipv4Address) -> bool {
+    uplink::destination(packet).is_none_or(|dst| dst.is_loopback() || dst == uplink_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::{RxToken as _, TxToken as _};
+
+    fn loopback() -> Loopback {
+        let packets = Box::leak(Box::new([[0; MTU]; QUEUE_LEN]));
+        let scratch = Box::leak(Box::new([0; MTU]));
+        Loopback::new(packets, scratch)
+    }
+
+    #[test]
+    fn transmitted_packets_come_back_in_order() {
+        let mut lo = loopback();
+        let now = Instant::ZERO;
+
+        for byte in 1..=3u8 {
+            let tx = lo.transmit(now).unwrap();
+            tx.consume(byte as usize, |buf| buf.fill(byte));
+        }
+        for byte in 1..=3u8 {
+            let (rx, _) = lo.receive(now).unwrap();
+            rx.consume(|packet| assert_eq!(packet, &[byte].repeat(byte as usize)[..]));
+        }
+        assert!(lo.receive(now).is_none());
+    }
+
+    #[test]
+    fn full_queue_refuses_to_transmit() {
+        let mut lo = loopback();
+        let now = Instant::ZERO;
+
+        for _ in 0..QUEUE_LEN {
+            lo.transmit(now).unwrap().consume(1, |_| ());
+        }
+        assert!(lo.transmit(now).is_none());
+
+        // Receiving frees the slot the reply token writes into.
+        let (_, reply) = lo.receive(now).unwrap();
+        reply.consume(2, |buf| buf.fill(7));
+        assert!(lo.transmit(now).is_none());
+    }
+}
@@ -0,0 +1,6 @@
+pub mod errors;
+pub mod inet;
+pub mod loopback;
+pub mod unix;
+pub mod uplink;
+pub mod virtio_net;
@@ -0,0 +1,514 @@
+use spin::Mutex;
+
+use super::errors::NetError;
+use crate::fs::Readiness;
+use crate::fs::errors::{FsError, Result};
+
+pub const MAX_SOCKETS: usize = 16;
+pub const MAX_BACKLOG: usize = 8;
+pub const BUFFER_SIZE: usize = 4096;
+pub const UNIX_PATH_MAX: usize = 108;
+
+// Datagrams are queued as a length, the sender's name and the payload.
+const DATAGRAM_HEADER: usize = 3;
+
+static SOCKETS: Mutex<SocketTable> = Mutex::new(SocketTable::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketType {
+    Stream,
+    Datagram,
+}
+
+/// A socket name: either a resolved filesystem path or an abstract name,
+/// which starts with a NUL byte. Names live in their own namespace and do
+/// not show up in ramfs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    bytes: [u8; UNIX_PATH_MAX],
+    len: usize,
+}
+
+impl Address {
+    pub fn new(name: &[u8]) -> Result<Self> {
+        if name.is_empty() {
+            return Err(FsError::InvalidArgument);
+        }
+        if name.len() > UNIX_PATH_MAX {
+            return Err(FsError::NameTooLong { max: UNIX_PATH_MAX });
+        }
+        let mut bytes = [0; UNIX_PATH_MAX];
+        bytes[..name.len()].copy_from_slice(name);
+        Ok(Self {
+            bytes,
+            len: name.len(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl core::fmt::Debug for Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Address({:?})", self.as_bytes().escape_ascii())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Open,
+    Listening {
+        backlog: usize,
+    },
+    Connected {
+        peer: usize,
+    },
+    /// The other end of a stream went away; buffered data can still be read.
+    Disconnected,
+}
+
+/// Byte FIFO backing a socket's receive queue.
+#[derive(Clone, Copy)]
+struct Ring {
+    data: [u8; BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            data: [0; BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn free(&self) -> usize {
+        BUFFER_SIZE - self.len
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> usize {
+        let count = bytes.len().min(self.free());
+        for (i, &byte) in bytes[..count].iter().enumerate() {
+            self.data[(self.head + self.len + i) % BUFFER_SIZE] = byte;
+        }
+        self.len += count;
+        count
+    }
+
+    fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len);
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            *byte = self.data[(self.head + i) % BUFFER_SIZE];
+        }
+        self.discard(count);
+        count
+    }
+
+    fn discard(&mut self, count: usize) {
+        self.head = (self.head + count) % BUFFER_SIZE;
+        self.len -= count;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Socket {
+    ty: SocketType,
+    name: Option<Address>,
+    state: State,
+    pending: [Option<usize>; MAX_BACKLOG],
+    default_peer: Option<Address>,
+    rx: Ring,
+}
+
+impl Socket {
+    const fn new(ty: SocketType) -> Self {
+        Self {
+            ty,
+            name: None,
+            state: State::Open,
+            pending: [None; MAX_BACKLOG],
+            default_peer: None,
+            rx: Ring::new(),
+        }
+    }
+}
+
+/// A received message: how much was copied out, who sent it and whether the
+/// datagram was longer than the buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Received {
+    pub len: usize,
+    pub from: Option<Address>,
+    pub truncated: bool,
+}
+
+pub struct SocketTable {
+    sockets: [Option<Socket>; MAX_SOCKETS],
+}
+
+impl SocketTable {
+    pub const fn new() -> Self {
+        Self {
+            sockets: [None; MAX_SOCKETS],
+        }
+    }
+
+    pub fn create(&mut self, ty: SocketType) -> Result<usize> {
+        let id = self
+            .sockets
+            .iter()
+            .position(Option::is_none)
+            .ok_or(NetError::NoBuffers)?;
+        self.sockets[id] = Some(Socket::new(ty));
+        Ok(id)
+    }
+
+    /// Close a socket. The peer of a stream sees end of file, and connections
+    /// still waiting to be accepted are dropped.
+    pub fn close(&mut self, id: usize) {
+        let Some(socket) = self.sockets[id].take() else {
+            return;
+        };
+        if let State::Connected { peer } = socket.state {
+            self.disconnect(peer);
+        }
+        for server in socket.pending.into_iter().flatten() {
+            self.close(server);
+        }
+    }
+
+    pub fn bind(&mut self, id: usize, name: Address) -> Result<()> {
+        if self.find(&name).is_some() {
+            return Err(NetError::AddressInUse.into());
+        }
+        let socket = self.socket(id)?;
+        if socket.name.is_some() {
+            return Err(FsError::InvalidArgument);
+        }
+        socket.name = Some(name);
+        Ok(())
+    }
+
+    pub fn listen(&mut self, id: usize, backlog: usize) -> Result<()> {
+        let socket = self.socket(id)?;
+        if socket.ty != SocketType::Stream {
+            return Err(NetError::NotSupported.into());
+        }
+        match socket.state {
+            State::Open | State::Listening { .. } if socket.name.is_some() => {
+                socket.state = State::Listening {
+                    backlog: backlog.clamp(1, MAX_BACKLOG),
+                };
+                Ok(())
+            }
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    /// Connect to the socket bound to `name`. Streams are connected as soon as
+    /// the listener has room in its backlog; datagram sockets only remember
+    /// `name` as their default destination.
+    pub fn connect(&mut self, id: usize, name: Address) -> Result<()> {
+        let ty = self.socket(id)?.ty;
+        let target = self.find(&name).ok_or(FsError::NotFound)?;
+        if self.socket(target)?.ty != ty {
+            return Err(NetError::WrongType.into());
+        }
+        if ty == SocketType::Datagram {
+            self.socket(id)?.default_peer = Some(name);
+            return Ok(());
+        }
+
+        match self.socket(id)?.state {
+            State::Open => {}
+            State::Listening { .. } => return Err(FsError::InvalidArgument),
+            State::Connected { .. } | State::Disconnected => {
+                return Err(NetError::AlreadyConnected.into());
+            }
+        }
+        let listener = self.socket(target)?;
+        let State::Listening { backlog } = listener.state else {
+            return Err(NetError::ConnectionRefused.into());
+        };
+        if listener.pending.iter().flatten().count() >= backlog {
+            return Err(FsError::WouldBlock);
+        }
+
+        let server = self.create(SocketType::Stream)?;
+        self.socket(server)?.state = State::Connected { peer: id };
+        self.socket(id)?.state = State::Connected { peer: server };
+        let listener = self.socket(target)?;
+        let slot = listener
+            .pending
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("backlog has room");
+        *slot = Some(server);
+        Ok(())
+    }
+
+    /// Take the oldest connection waiting on a listening socket.
+    pub fn accept(&mut self, id: usize) -> Result<usize> {
+        let socket = self.socket(id)?;
+        if !matches!(socket.state, State::Listening { .. }) {
+            return Err(FsError::InvalidArgument);
+        }
+        let server = socket.pending[0].ok_or(FsError::WouldBlock)?;
+        socket.pending.rotate_left(1);
+        socket.pending[MAX_BACKLOG - 1] = None;
+        Ok(server)
+    }
+
+    /// Queue `data` for the peer. Streams accept partial writes; a datagram
+    /// is delivered whole or not at all.
+    pub fn send(&mut self, id: usize, data: &[u8], to: Option<Address>) -> Result<usize> {
+        let socket = *self.socket(id)?;
+        match socket.ty {
+            SocketType::Stream => {
+                if to.is_some() {
+                    return Err(NetError::AlreadyConnected.into());
+                }
+                let peer = match socket.state {
+                    State::Connected { peer } => peer,
+                    State::Disconnected => return Err(NetError::BrokenPipe.into()),
+                    _ => return Err(NetError::NotConnected.into()),
+                };
+                if data.is_empty() {
+                    return Ok(0);
+                }
+                match self.socket(peer)?.rx.push(data) {
+                    0 => Err(FsError::WouldBlock),
+                    sent => Ok(sent),
+                }
+            }
+            SocketType::Datagram => {
+                let to = to
+                    .or(socket.default_peer)
+                    .ok_or(NetError::DestinationRequired)?;
+                let target = self.find(&to).ok_or(NetError::ConnectionRefused)?;
+                if self.socket(target)?.ty != SocketType::Datagram {
+                    return Err(NetError::WrongType.into());
+                }
+
+                let from = socket.name.as_ref().map_or(&[][..], Address::as_bytes);
+                let record = DATAGRAM_HEADER + from.len() + data.len();
+                if record > BUFFER_SIZE {
+                    let max = BUFFER_SIZE - DATAGRAM_HEADER - from.len();
+                    return Err(NetError::MessageTooLong {
+                        len: data.len(),
+                        max,
+                    }
+                    .into());
+                }
+                let rx = &mut self.socket(target)?.rx;
+                if rx.free() < record {
+                    return Err(FsError::WouldBlock);
+                }
+                let len = (data.len() as u16).to_le_bytes();
+                rx.push(&[len[0], len[1], from.len() as u8]);
+                rx.push(from);
+                rx.push(data);
+                Ok(data.len())
+            }
+        }
+    }
+
+    pub fn recv(&mut self, id: usize, buf: &mut [u8]) -> Result<Received> {
+        let socket = self.socket(id)?;
+        match socket.ty {
+            SocketType::Stream => {
+                if !matches!(socket.state, State::Connected { .. } | State::Disconnected) {
+                    return Err(NetError::NotConnected.into());
+                }
+                let len = socket.rx.pop(buf);
+                if len == 0 && !buf.is_empty() && socket.state != State::Disconnected {
+                    return Err(FsError::WouldBlock);
+                }
+                Ok(Received {
+                    len,
+                    from: None,
+                    truncated: false,
+                })
+            }
+            SocketType::Datagram => {
+                if socket.rx.len == 0 {
+                    return Err(FsError::WouldBlock);
+                }
+                let mut header = [0; DATAGRAM_HEADER];
+                socket.rx.pop(&mut header);
+                let size = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let mut from = [0; UNIX_PATH_MAX];
+                let from_len = socket.rx.pop(&mut from[..header[2] as usize]);
+                let fits = size.min(buf.len());
+                let len = socket.rx.pop(&mut buf[..fits]);
+                socket.rx.discard(size - len);
+                Ok(Received {
+                    len,
+                    from: Address::new(&from[..from_len]).ok(),
+                    truncated: len < size,
+                })
+            }
+        }
+    }
+
+    pub fn readiness(&mut self, id: usize) -> Readiness {
+        let Ok(socket) = self.socket(id) else {
+            return Readiness::default();
+        };
+        let state = socket.state;
+        let buffered = socket.rx.len > 0;
+        match state {
+            State::Listening { .. } => Readiness {
+                readable: socket.pending[0].is_some(),
+                writable: false,
+            },
+            State::Connected { peer } if socket.ty == SocketType::Stream => Readiness {
+                readable: buffered,
+                writable: self.socket(peer).is_ok_and(|peer| peer.rx.free() > 0),
+            },
+            State::Disconnected => Readiness {
+                readable: true,
+                writable: true,
+            },
+            _ => Readiness {
+                readable: buffered,
+                writable: socket.ty == SocketType::Datagram,
+            },
+        }
+    }
+
+    /// The name of the socket on the other end of a connected stream.
+    pub fn peer_name(&mut self, id: usize) -> Result<Option<Address>> {
+        match self.socket(id)?.state {
+            State::Connected { peer } => Ok(self.socket(peer)?.name),
+            _ => Err(NetError::NotConnected.into()),
+        }
+    }
+
+    pub fn socket_type(&mut self, id: usize) -> Result<SocketType> {
+        Ok(self.socket(id)?.ty)
+    }
+
+    fn disconnect(&mut self, id: usize) {
+        if let Some(socket) = self.sockets[id].as_mut() {
+            socket.state = State::Disconnected;
+        }
+    }
+
+    fn find(&self, name: &Address) -> Option<usize> {
+        self.sockets.iter().position(|socket| {
+            socket
+                .as_ref()
+                .is_some_and(|socket| socket.name == Some(*name))
+        })
+    }
+
+    fn socket(&mut self, id: usize) -> Result<&mut Socket> {
+        self.sockets
+            .get_mut(id)
+            .and_then(Option::as_mut)
+            .ok_or(FsError::BadDescriptor)
+    }
+}
+
+impl Default for SocketTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `f` on the shared table of unix-domain sockets.
+pub fn with_sockets<T>(f: impl FnOnce(&mut SocketTable) -> T) -> T {
+    f(&mut SOCKETS.lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(bytes: &[u8]) -> Address {
+        Address::new(bytes).unwrap()
+    }
+
+    #[test]
+    fn stream_connect_accept_and_exchange() {
+        let mut table = SocketTable::new();
+        let listener = table.create(SocketType::Stream).unwrap();
+        let client = table.create(SocketType::Stream).unwrap();
+
+        assert_eq!(table.connect(client, name(b"/srv")), Err(FsError::NotFound));
+        table.bind(listener, name(b"/srv")).unwrap();
+        assert_eq!(
+            table.connect(client, name(b"/srv")),
+            Err(NetError::ConnectionRefused.into())
+        );
+        table.listen(listener, 1).unwrap();
+        assert_eq!(table.accept(listener), Err(FsError::WouldBlock));
+
+        table.connect(client, name(b"/srv")).unwrap();
+        assert!(table.readiness(listener).readable);
+        let server = table.accept(listener).unwrap();
+
+        assert_eq!(table.send(client, b"ping", None), Ok(4));
+        let mut buf = [0; 8];
+        assert_eq!(table.recv(server, &mut buf).unwrap().len, 4);
+        assert_eq!(&buf[..4], b"ping");
+        assert_eq!(table.recv(server, &mut buf), Err(FsError::WouldBlock));
+
+        table.close(client);
+        assert_eq!(table.recv(server, &mut buf).unwrap().len, 0);
+        assert_eq!(
+            table.send(server, b"x", None),
+            Err(NetError::BrokenPipe.into())
+        );
+    }
+
+    #[test]
+    fn full_stream_buffer_takes_partial_writes() {
+        let mut table = SocketTable::new();
+        let listener = table.create(SocketType::Stream).unwrap();
+        let client = table.create(SocketType::Stream).unwrap();
+        table.bind(listener, name(b"\0abstract")).unwrap();
+        table.listen(listener, 4).unwrap();
+        table.connect(client, name(b"\0abstract")).unwrap();
+
+        let data = [7u8; BUFFER_SIZE + 10];
+        assert_eq!(table.send(client, &data, None), Ok(BUFFER_SIZE));
+        assert!(!table.readiness(client).writable);
+        assert_eq!(table.send(client, &data, None), Err(FsError::WouldBlock));
+    }
+
+    #[test]
+    fn datagrams_keep_boundaries_and_sender() {
+        let mut table = SocketTable::new();
+        let receiver = table.create(SocketType::Datagram).unwrap();
+        let sender = table.create(SocketType::Datagram).unwrap();
+        table.bind(receiver, name(b"/rx")).unwrap();
+        table.bind(sender, name(b"/tx")).unwrap();
+        assert_eq!(
+            table.bind(sender, name(b"/rx")),
+            Err(NetError::AddressInUse.into())
+        );
+
+        assert_eq!(
+            table.send(sender, b"one", None),
+            Err(NetError::DestinationRequired.into())
+        );
+        table.send(sender, b"one", Some(name(b"/rx"))).unwrap();
+        table.connect(sender, name(b"/rx")).unwrap();
+        table.send(sender, b"second", None).unwrap();
+
+        let mut buf = [0; 4];
+        let first = table.recv(receiver, &mut buf).unwrap();
+        assert_eq!((first.len, first.truncated), (3, false));
+        assert_eq!(first.from, Some(name(b"/tx")));
+        let second = table.recv(receiver, &mut buf).unwrap();
+        assert_eq!((second.len, second.truncated), (4, true));
+        assert_eq!(&buf, b"seco");
+        assert_eq!(table.recv(receiver, &mut buf), Err(FsError::WouldBlock));
+    }
+}
@@ -0,0 +1,430 @@
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    EthernetRepr, Ipv4Address, Ipv4Cidr, Ipv4Packet,
+};
+
+/// Largest IP packet an Ethernet frame carries.
+pub const MTU: usize = 1500;
+/// Largest frame without its frame check sequence, which NICs take care of.
+pub const FRAME_SIZE: usize = MTU + 14;
+
+// Neighbours whose link addresses are remembered. Entries never expire:
+// nothing on a VM's network changes its address under it.
+const NEIGHBORS: usize = 8;
+
+/// A network card: whole Ethernet frames in and out.
+pub trait Nic: Send {
+    fn mac(&self) -> EthernetAddress;
+
+    /// Queue `frame` for sending. Returns false, dropping the frame, if
+    /// the card has no room.
+    fn transmit(&mut self, frame: &[u8]) -> bool;
+
+    /// Copy the next frame that arrived to `buf`, returning its length.
+    fn receive(&mut self, buf: &mut [u8; FRAME_SIZE]) -> Option<usize>;
+}
+
+/// Addresses of a guest on a network of its own: its address and subnet
+/// and the router to everything else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UplinkConfig {
+    pub addr: Ipv4Cidr,
+    pub gateway: Ipv4Address,
+}
+
+/// The default network: the guest at 10.0.2.15 behind a router at 10.0.2.2,
+/// the addresses other VMMs' user networking hands out too.
+pub const DEFAULT_CONFIG: UplinkConfig = UplinkConfig {
+    addr: Ipv4Cidr::new(Ipv4Address::new(10, 0, 2, 15), 24),
+    gateway: Ipv4Address::new(10, 0, 2, 2),
+};
+
+/// Carries IP packets over an Ethernet card, answering and asking ARP
+/// queries on the way. A packet whose next hop has no known link address
+/// waits, one at a time, while the next hop is asked for it.
+pub struct Uplink<N> {
+    nic: N,
+    config: UplinkConfig,
+    neighbors: [Option<(Ipv4Address, EthernetAddress)>; NEIGHBORS],
+    next_neighbor: usize,
+    // The packet waiting for its next hop's link address.
+    pending: Option<(Ipv4Address, usize)>,
+    pending_packet: [u8; MTU],
+    frame: [u8; FRAME_SIZE],
+}
+
+impl<N: Nic> Uplink<N> {
+    pub fn new(nic: N, config: UplinkConfig) -> Self {
+        Self {
+            nic,
+            config,
+            neighbors: [None; NEIGHBORS],
+            next_neighbor: 0,
+            pending: None,
+            pending_packet: [0; MTU],
+            frame: [0; FRAME_SIZE],
+        }
+    }
+
+    pub fn config(&self) -> UplinkConfig {
+        self.config
+    }
+
+    pub fn mac(&self) -> EthernetAddress {
+        self.nic.mac()
+    }
+
+    /// Tell the network which card has the guest's address, with a
+    /// gratuitous ARP request.
+    pub fn announce(&mut self) {
+        let addr = self.config.addr.address();
+        self.send_arp(
+            ArpOperation::Request,
+            EthernetAddress::BROADCAST,
+            EthernetAddress([0; 6]),
+            addr,
+        );
+    }
+
+    /// Send an IP packet towards its destination. Packets that are not
+    /// IPv4 or do not fit a frame are dropped.
+    pub fn send(&mut self, packet: &[u8]) {
+        let Some(dst) = destination(packet) else {
+            return;
+        };
+        if packet.len() > MTU {
+            return;
+        }
+        let next_hop = if self.config.addr.contains_addr(&dst) || dst.is_broadcast() {
+            dst
+        } else {
+            self.config.gateway
+        };
+        if next_hop.is_broadcast() || Some(next_hop) == self.config.addr.broadcast() {
+            self.send_ip(EthernetAddress::BROADCAST, packet);
+            return;
+        }
+        if let Some(mac) = self.neighbor(next_hop) {
+            self.send_ip(mac, packet);
+            return;
+        }
+        self.pending_packet[..packet.len()].copy_from_slice(packet);
+        self.pending = Some((next_hop, packet.len()));
+        self.send_arp(
+            ArpOperation::Request,
+            EthernetAddress::BROADCAST,
+            EthernetAddress([0; 6]),
+            next_hop,
+        );
+    }
+
+    /// Copy the next IP packet for the guest to `buf`, returning its
+    /// length. ARP traffic is dealt with on the way.
+    pub fn receive(&mut self, buf: &mut [u8]) -> Option<usize> {
+        loop {
+            let len = self.nic.receive(&mut self.frame)?;
+            let Ok(frame) = EthernetFrame::new_checked(&self.frame[..len]) else {
+                continue;
+            };
+            let dst = frame.dst_addr();
+            if dst != self.nic.mac() && !dst.is_broadcast() {
+                continue;
+            }
+            match frame.ethertype() {
+                EthernetProtocol::Arp => {
+                    if let Ok(arp) = ArpPacket::new_checked(frame.payload())
+                        && let Ok(arp) = ArpRepr::parse(&arp)
+                    {
+                        self.handle_arp(arp);
+                    }
+                }
+                EthernetProtocol::Ipv4 => {
+                    let packet = frame.payload();
+                    if packet.len() > buf.len() {
+                        continue;
+                    }
+                    buf[..packet.len()].copy_from_slice(packet);
+                    return Some(packet.len());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn handle_arp(&mut self, arp: ArpRepr) {
+        let ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr,
+            source_protocol_addr,
+            target_protocol_addr,
+            ..
+        } = arp
+        else {
+            return;
+        };
+        let addr = self.config.addr.address();
+        if !source_hardware_addr.is_unicast()
+            || !self.config.addr.contains_addr(&source_protocol_addr)
+            || source_protocol_addr == addr
+        {
+            return;
+        }
+        // Whoever asks for the guest is about to talk to it, and whoever
+        // answers is who a waiting packet is for.
+        if target_protocol_addr == addr || operation == ArpOperation::Reply {
+            self.learn(source_protocol_addr, source_hardware_addr);
+        }
+        if operation == ArpOperation::Request && target_protocol_addr == addr {
+            self.send_arp(
+                ArpOperation::Reply,
+                source_hardware_addr,
+                source_hardware_addr,
+                source_protocol_addr,
+            );
+        }
+        if let Some((next_hop, len)) = self.pending
+            && next_hop == source_protocol_addr
+        {
+            self.pending = None;
+            let len = frame_ip(
+                &mut self.frame,
+                self.nic.mac(),
+                source_hardware_addr,
+                &self.pending_packet[..len],
+            );
+            self.nic.transmit(&self.frame[..len]);
+        }
+    }
+
+    fn learn(&mut self, ip: Ipv4Address, mac: EthernetAddress) {
+        let slot = self
+            .neighbors
+            .iter()
+            .position(|entry| entry.is_some_and(|(known, _)| known == ip))
+            .unwrap_or_else(|| {
+                let slot = self.next_neighbor;
+                self.next_neighbor = (slot + 1) % NEIGHBORS;
+                slot
+            });
+        self.neighbors[slot] = Some((ip, mac));
+    }
+
+    fn neighbor(&self, ip: Ipv4Address) -> Option<EthernetAddress> {
+        self.neighbors
+            .iter()
+            .flatten()
+            .find(|(known, _)| *known == ip)
+            .map(|(_, mac)| *mac)
+    }
+
+    fn send_ip(&mut self, dst: EthernetAddress, packet: &[u8]) {
+        let len = frame_ip(&mut self.frame, self.nic.mac(), dst, packet);
+        self.nic.transmit(&self.frame[..len]);
+    }
+
+    fn send_arp(
+        &mut self,
+        operation: ArpOperation,
+        dst: EthernetAddress,
+        target_hardware_addr: EthernetAddress,
+        target_protocol_addr: Ipv4Address,
+    ) {
+        let arp = ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr: self.nic.mac(),
+            source_protocol_addr: self.config.addr.address(),
+            target_hardware_addr,
+            target_protocol_addr,
+        };
+        let ethernet = EthernetRepr {
+            src_addr: self.nic.mac(),
+            dst_addr: dst,
+            ethertype: EthernetProtocol::Arp,
+        };
+        let len = ethernet.buffer_len() + arp.buffer_len();
+        let mut frame = EthernetFrame::new_unchecked(&mut self.frame[..len]);
+        ethernet.emit(&mut frame);
+        arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+        self.nic.transmit(&self.frame[..len]);
+    }
+}
+
+// Wrap `packet` in a frame from `src` to `dst`, returning the frame's length.
+fn frame_ip(
+    buf: &mut [u8; FRAME_SIZE],
+    src: EthernetAddress,
+    dst: EthernetAddress,
+    packet: &[u8],
+) -> usize {
+    let ethernet = EthernetRepr {
+        src_addr: src,
+        dst_addr: dst,
+        ethertype: EthernetProtocol::Ipv4,
+    };
+    let len = ethernet.buffer_len() + packet.len();
+    let mut frame = EthernetFrame::new_unchecked(&mut buf[..len]);
+    ethernet.emit(&mut frame);
+    frame.payload_mut().copy_from_slice(packet);
+    len
+}
+
+/// Where an IPv4 packet is going, if it is one.
+pub fn destination(packet: &[u8]) -> Option<Ipv4Address> {
+    let packet = Ipv4Packet::new_checked(packet).ok()?;
+    (packet.version() == 4).then(|| packet.dst_addr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    const GUEST_MAC: EthernetAddress = EthernetAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+    const ROUTER_MAC: EthernetAddress = EthernetAddress([0x52, 0x54, 0, 0, 0, 2]);
+
+    #[derive(Default)]
+    struct FakeNic {
+        sent: Vec<Vec<u8>>,
+        arriving: VecDeque<Vec<u8>>,
+    }
+
+    impl Nic for FakeNic {
+        fn mac(&self) -> EthernetAddress {
+            GUEST_MAC
+        }
+
+        fn transmit(&mut self, frame: &[u8]) -> bool {
+            self.sent.push(frame.to_vec());
+            true
+        }
+
+        fn receive(&mut self, buf: &mut [u8; FRAME_SIZE]) -> Option<usize> {
+            let frame = self.arriving.pop_front()?;
+            buf[..frame.len()].copy_from_slice(&frame);
+            Some(frame.len())
+        }
+    }
+
+    fn uplink() -> Uplink<FakeNic> {
+        Uplink::new(FakeNic::default(), DEFAULT_CONFIG)
+    }
+
+    fn ip_packet(dst: Ipv4Address) -> Vec<u8> {
+        let mut packet = std::vec![0; 20];
+        let mut ip = Ipv4Packet::new_unchecked(&mut packet[..]);
+        ip.set_version(4);
+        ip.set_header_len(20);
+        ip.set_total_len(20);
+        ip.set_dst_addr(dst);
+        packet
+    }
+
+    fn arp_frame(operation: ArpOperation, target: Ipv4Address) -> Vec<u8> {
+        let arp = ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr: ROUTER_MAC,
+            source_protocol_addr: DEFAULT_CONFIG.gateway,
+            target_hardware_addr: GUEST_MAC,
+            target_protocol_addr: target,
+        };
+        let ethernet = EthernetRepr {
+            src_addr: ROUTER_MAC,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Arp,
+        };
+        let mut frame = std::vec![0; ethernet.buffer_len() + arp.buffer_len()];
+        let mut eth = EthernetFrame::new_unchecked(&mut frame[..]);
+        ethernet.emit(&mut eth);
+        arp.emit(&mut ArpPacket::new_unchecked(eth.payload_mut()));
+        frame
+    }
+
+    // The operation, sender and target of an ARP frame.
+    fn parse_arp(frame: &[u8]) -> (ArpOperation, EthernetAddress, EthernetAddress, Ipv4Address) {
+        let frame = EthernetFrame::new_checked(frame).unwrap();
+        assert_eq!(frame.ethertype(), EthernetProtocol::Arp);
+        let arp = ArpRepr::parse(&ArpPacket::new_checked(frame.payload()).unwrap()).unwrap();
+        let ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr,
+            target_hardware_addr,
+            target_protocol_addr,
+            ..
+        } = arp
+        else {
+            panic!("not an Ethernet/IPv4 ARP packet");
+        };
+        (
+            operation,
+            source_hardware_addr,
+            target_hardware_addr,
+            target_protocol_addr,
+        )
+    }
+
+    fn ip_frame(packet: &[u8]) -> Vec<u8> {
+        let mut frame = [0; FRAME_SIZE];
+        let len = frame_ip(&mut frame, ROUTER_MAC, GUEST_MAC, packet);
+        frame[..len].to_vec()
+    }
+
+    #[test]
+    fn packets_off_the_subnet_wait_for_the_routers_address() {
+        let mut uplink = uplink();
+        let packet = ip_packet(Ipv4Address::new(192, 0, 2, 1));
+
+        uplink.send(&packet);
+        let sent = core::mem::take(&mut uplink.nic.sent);
+        assert_eq!(sent.len(), 1);
+        let (operation, _, _, target_protocol_addr) = parse_arp(&sent[0]);
+        assert_eq!(operation, ArpOperation::Request);
+        assert_eq!(target_protocol_addr, DEFAULT_CONFIG.gateway);
+
+        let reply = arp_frame(ArpOperation::Reply, DEFAULT_CONFIG.addr.address());
+        uplink.nic.arriving.push_back(reply);
+        assert_eq!(uplink.receive(&mut [0; MTU]), None);
+        let sent = core::mem::take(&mut uplink.nic.sent);
+        assert_eq!(sent.len(), 1, "the waiting packet goes out");
+        let frame = EthernetFrame::new_checked(&sent[0][..]).unwrap();
+        assert_eq!(frame.dst_addr(), ROUTER_MAC);
+        assert_eq!(frame.payload(), &packet[..]);
+
+        // Now known, the router is not asked again.
+        uplink.send(&packet);
+        assert_eq!(uplink.nic.sent.len(), 1);
+        assert_eq!(
+            EthernetFrame::new_checked(&uplink.nic.sent[0][..])
+                .unwrap()
+                .ethertype(),
+            EthernetProtocol::Ipv4
+        );
+    }
+
+    #[test]
+    fn requests_for_the_guest_are_answered_and_ip_passed_up() {
+        let mut uplink = uplink();
+        let request = arp_frame(ArpOperation::Request, DEFAULT_CONFIG.addr.address());
+        let elsewhere = arp_frame(ArpOperation::Request, Ipv4Address::new(10, 0, 2, 3));
+        let packet = ip_packet(DEFAULT_CONFIG.addr.address());
+        uplink
+            .nic
+            .arriving
+            .extend([request, elsewhere, ip_frame(&packet)]);
+
+        let mut buf = [0; MTU];
+        assert_eq!(uplink.receive(&mut buf), Some(packet.len()));
+        assert_eq!(&buf[..packet.len()], &packet[..]);
+        assert_eq!(
+            uplink.nic.sent.len(),
+            1,
+            "only the guest's address is answered"
+        );
+        let (operation, source_hardware_addr, target_hardware_addr, _) =
+            parse_arp(&uplink.nic.sent[0]);
+        assert_eq!(operation, ArpOperation::Reply);
+        assert_eq!(source_hardware_addr, GUEST_MAC);
+        assert_eq!(target_hardware_addr, ROUTER_MAC);
+        assert_eq!(uplink.neighbor(DEFAULT_CONFIG.gateway), Some(ROUTER_MAC));
+    }
+}
@@ -0,0 +1,87 @@
+use smoltcp::wire::EthernetAddress;
+
+use super::uplink::{FRAME_SIZE, Nic};
+use crate::fs::errors::Result;
+use crate::memory::{address::DirectMap, alloc::palloc::PageAllocator};
+use crate::virtio::driver::{BUFFER_SIZE, Queue, Transport};
+use crate::virtio::{DESC_F_WRITE, DEVICE_NET, NET_F_MAC, NET_HDR_SIZE, NET_SLOT};
+
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+const _: () = assert!(NET_HDR_SIZE + FRAME_SIZE <= BUFFER_SIZE);
+
+/// The VMM's virtio network device, driven by polling. Like the rest of the
+/// network stack it only moves when the stack is polled.
+pub struct VirtioNet {
+    transport: Transport,
+    mac: EthernetAddress,
+    rx: Queue,
+    tx: Queue,
+}
+
+// SAFETY: the registers and queues are only reached through the network
+// stack's lock.
+unsafe impl Send for VirtioNet {}
+
+impl VirtioNet {
+    /// Bring up the network device if the VMM has one.
+    pub fn probe(palloc: &PageAllocator, dm: &impl DirectMap) -> Result<Option<Self>> {
+        let Some(mut transport) = Transport::find(NET_SLOT, DEVICE_NET, dm) else {
+            return Ok(None);
+        };
+        let Some(features) = transport.negotiate(NET_F_MAC) else {
+            return Ok(None);
+        };
+        let Some([mut rx, tx]) = transport.setup_queues([RX_QUEUE, TX_QUEUE], palloc, dm)? else {
+            return Ok(None);
+        };
+        let mac = if features & NET_F_MAC != 0 {
+            core::array::from_fn(|i| transport.config_byte(i))
+        } else {
+            // A locally administered address of our own.
+            [0x02, 0, 0, 0, 0, 1]
+        };
+        rx.offer_all();
+        transport.start();
+        transport.notify(RX_QUEUE);
+        Ok(Some(Self {
+            transport,
+            mac: EthernetAddress(mac),
+            rx,
+            tx,
+        }))
+    }
+}
+
+impl Nic for VirtioNet {
+    fn mac(&self) -> EthernetAddress {
+        self.mac
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> bool {
+        if frame.len() > FRAME_SIZE {
+            return false;
+        }
+        let Some(id) = self.tx.take_free() else {
+            return false;
+        };
+        let buffer = self.tx.buffer(id);
+        buffer[..NET_HDR_SIZE].fill(0);
+        buffer[NET_HDR_SIZE..NET_HDR_SIZE + frame.len()].copy_from_slice(frame);
+        self.tx.offer(id, NET_HDR_SIZE + frame.len(), 0);
+        self.transport.notify(TX_QUEUE);
+        true
+    }
+
+    fn receive(&mut self, buf: &mut [u8; FRAME_SIZE]) -> Option<usize> {
+        let used = self.rx.take_used()?;
+        let id = used.id as u16;
+        let len = (used.len as usize)
+            .saturating_sub(NET_HDR_SIZE)
+            .min(FRAME_SIZE);
+        buf[..len].copy_from_slice(&self.rx.buffer(id)[NET_HDR_SIZE..NET_HDR_SIZE + len]);
+        self.rx.offer(id, BUFFER_SIZE, DESC_F_WRITE);
+        self.transport.notify(RX_QUEUE);
+        Some(len)
+    }
+}
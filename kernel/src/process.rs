@@ -1,91 +1,393 @@
+use alloc::vec::Vec;
 use core::arch::global_asm;
 use core::ptr::{null, null_mut};
+use core::time::Duration;
+
+use thiserror::Error as ThisError;
 
 use crate::Kernel;
+use crate::arch::{self, percpu::this_cpu};
+use crate::boot;
+use crate::credentials;
+use crate::fs::{self, errors::Result as FsResult, fd::FdTable, path::Path};
+use crate::initial_stack::{self, ImageInfo, StackError};
+use crate::limits::{LimitError, RLIMIT_AS, ResourceLimits, Rlimit};
 use crate::memory::{
     address::{DirectMap, PhysicalAddr},
     constants::PAGE_SIZE,
-    errors::Result as MemoryResult,
-    vmm::Vmm,
+    errors::{MemoryError, Result as MemoryResult},
+    vmm::{MemoryUsage, Vmm},
 };
-use crate::scheduler::{Context, ExitPlan, MAX_PROCESSES, Scheduler, SwitchPlan};
+use crate::random;
+use crate::scheduler::{ALL_CPUS, Context, NICE_MAX, NICE_MIN, Reap, Scheduler, SwitchPlan};
+use crate::seccomp::{Seccomp, SeccompData, Verdict};
+use crate::sync::{IrqMutex, IrqMutexGuard};
+use crate::time;
+use crate::wait::WaitQueue;
 
 const PROCESS_STACK_PAGES: usize = 1;
+// Syscalls run on a stack of their own rather than whatever the caller had.
+const KERNEL_STACK_SIZE: usize = 64 * 1024;
+const DEFAULT_UMASK: u32 = 0o022;
+// The idle task only ever runs the timer interrupt and the switch away.
+const IDLE_STACK_SIZE: usize = 16 * 1024;
+
+/// Longest process name kept, as Linux's `TASK_COMM_LEN` without the NUL.
+pub const NAME_MAX_LEN: usize = 15;
+
+/// Signals a process killed by the kernel reports, numbered as on Linux.
+pub const SIGILL: u8 = 4;
+pub const SIGTRAP: u8 = 5;
+pub const SIGBUS: u8 = 7;
+pub const SIGFPE: u8 = 8;
+pub const SIGKILL: u8 = 9;
+pub const SIGSEGV: u8 = 11;
+pub const SIGSYS: u8 = 31;
+
+pub type ProcessFn = fn(usize);
+
+pub use crate::scheduler::{
+    CpuTime, ExitStatus, TRACE_CAPACITY, TaskState, TraceEvent, TraceRecord,
+};
+
+// Parents waiting in `wait` for a child to exit.
+static CHILD_EXITS: WaitQueue = WaitQueue::new();
+
+#[repr(C, align(16))]
+struct IdleStack([u8; IDLE_STACK_SIZE]);
+
+// Only the idle task touches it, and only the one CPU runs that.
+static mut IDLE_STACK: IdleStack = IdleStack([0; IDLE_STACK_SIZE]);
+
+/// Short name of a process, cut to [`NAME_MAX_LEN`] bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProcessName {
+    bytes: [u8; NAME_MAX_LEN],
+    len: usize,
+}
+
+impl ProcessName {
+    pub fn new(name: &[u8]) -> Self {
+        let len = name.len().min(NAME_MAX_LEN);
+        let mut bytes = [0; NAME_MAX_LEN];
+        bytes[..len].copy_from_slice(&name[..len]);
+        Self { bytes, len }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// A live process as [`list`] reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: usize,
+    pub ppid: usize,
+    pub state: TaskState,
+    pub name: ProcessName,
+    pub cpu: CpuTime,
+    /// What the children it reaped used, their own children included.
+    pub children_cpu: CpuTime,
+}
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    #[error("no free pid")]
+    NoFreePid,
+
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+
+    #[error(transparent)]
+    Stack(#[from] StackError),
+}
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityError {
+    #[error("no process with pid {pid}")]
+    NoSuchProcess { pid: usize },
+
+    #[error("only root may raise priority to nice {nice}")]
+    PermissionDenied { nice: i32 },
+}
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityError {
+    #[error("no process with pid {pid}")]
+    NoSuchProcess { pid: usize },
 
-pub type ProcessFn = fn();
+    #[error("affinity mask {mask:#x} allows no online CPU")]
+    NoOnlineCpu { mask: u64 },
+}
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KillError {
+    #[error("no process with pid {pid}")]
+    NoSuchProcess { pid: usize },
+}
+
+/// A child collected by [`wait`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExitedChild {
+    pub pid: usize,
+    pub status: ExitStatus,
+    /// What it used, its own reaped children included.
+    pub cpu: CpuTime,
+}
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    #[error("no child to wait for")]
+    NoChildren,
+}
+
+// The stacks a process runs on. Its exit path is still using one of them,
+// so they outlive everything else it owns.
+#[derive(Clone, Copy)]
+struct Stacks {
+    base: PhysicalAddr,
+    pages: usize,
+    kernel: PhysicalAddr,
+}
+
+// The argument vector and environment a process starts with.
+struct StartArgs<'a> {
+    argv: &'a [&'a [u8]],
+    envp: &'a [&'a [u8]],
+}
 
 struct Process<'i, DM: DirectMap> {
+    name: ProcessName,
     vmm: Vmm<'i, DM>,
-    stack_base: PhysicalAddr,
-    stack_pages: usize,
+    stacks: Stacks,
+    // Where argc sits, with argv, envp and auxv above it.
+    initial_stack: usize,
+    limits: ResourceLimits,
+    cwd: Path,
+    umask: u32,
+    files: FdTable,
+    seccomp: Seccomp,
 }
 
 pub struct ProcessState<'i, DM: DirectMap> {
-    inner: spin::Mutex<ProcessStateInner<'i, DM>>,
+    // Masks interrupts while held, so the timer never finds it taken by the
+    // process it interrupted.
+    // The pid each CPU runs lives in its per-CPU area, outside the lock, so
+    // asking who runs never waits for it.
+    inner: IrqMutex<ProcessStateInner<'i, DM>>,
 }
 
 struct ProcessStateInner<'i, DM: DirectMap> {
     scheduler: Scheduler,
-    processes: [Option<Process<'i, DM>>; MAX_PROCESSES],
+    // Indexed by scheduler slot and grown along with its table.
+    processes: Vec<Option<Process<'i, DM>>>,
+    // Stacks of the process that exited last, freed by whoever runs next.
+    exited_stacks: Option<Stacks>,
 }
 
 impl<'i, DM: DirectMap> ProcessState<'i, DM> {
     pub fn new() -> Self {
         Self {
-            inner: spin::Mutex::new(ProcessStateInner {
+            inner: IrqMutex::new(ProcessStateInner {
                 scheduler: Scheduler::new(),
-                processes: core::array::from_fn(|_| None),
+                processes: Vec::new(),
+                exited_stacks: None,
             }),
         }
     }
 
-    fn spawn(&self, kernel: &Kernel<'i, DM>, entry: ProcessFn) -> usize {
-        let vmm = Vmm::new(kernel.page_table, kernel.kalloc).expect("create vmm");
-        let stack_base = kernel
-            .palloc
-            .alloc(PROCESS_STACK_PAGES)
-            .expect("allocate process stack");
+    fn spawn(
+        &self,
+        kernel: &Kernel<'i, DM>,
+        name: ProcessName,
+        vmm: Vmm<'i, DM>,
+        entry: ProcessFn,
+        arg: usize,
+        args: StartArgs<'_>,
+    ) -> Result<usize, SpawnError> {
+        let stacks = alloc_stacks(kernel)?;
+        let direct_map = kernel.kalloc.direct_map();
+
+        let stack_base = stacks.base.to_virtual(direct_map);
+        let stack_len = PAGE_SIZE * stacks.pages;
+        let kernel_stack_top = stacks.kernel.to_virtual(direct_map).add(KERNEL_STACK_SIZE);
 
-        let stack_top = stack_base
-            .to_virtual(kernel.kalloc.direct_map())
-            .add(PAGE_SIZE * PROCESS_STACK_PAGES);
+        // Processes run a kernel function rather than an ELF image, so there
+        // are no program headers: AT_PHDR, AT_PHENT and AT_PHNUM stay 0, and
+        // AT_ENTRY is the kernel address of `entry`.
+        let mut image = ImageInfo {
+            entry: entry as usize as u64,
+            ..ImageInfo::default()
+        };
+        random::fill(&mut image.random);
+        // SAFETY: the stack was just allocated and nothing else uses it.
+        let stack = unsafe { core::slice::from_raw_parts_mut(stack_base.as_ptr(), stack_len) };
+        let initial_stack = match initial_stack::build(
+            stack,
+            stack_base.as_usize() + stack_len,
+            args.argv,
+            args.envp,
+            &image,
+        ) {
+            Ok(initial_stack) => initial_stack,
+            Err(err) => {
+                free_stacks(kernel, stacks);
+                return Err(err.into());
+            }
+        };
 
         // Keep SysV stack alignment for first frame (entry sees RSP % 16 == 8).
-        let initial_rsp = stack_top.as_usize() - 2 * core::mem::size_of::<u64>();
+        let initial_rsp = initial_stack - 2 * core::mem::size_of::<u64>();
         unsafe {
             *(initial_rsp as *mut u64) = process_trampoline as *const () as usize as u64;
         }
 
         let mut inner = self.inner.lock();
-        let spawn = inner
-            .scheduler
-            .spawn(entry, initial_rsp as u64, vmm.root().as_u64());
+        if inner.scheduler.reserve_slot().is_err() || inner.processes.try_reserve(1).is_err() {
+            drop(inner);
+            free_stacks(kernel, stacks);
+            return Err(MemoryError::OutOfMemory.into());
+        }
+        let Some(spawn) = inner.scheduler.spawn(
+            entry,
+            arg,
+            initial_rsp as u64,
+            kernel_stack_top.as_u64(),
+            vmm.root().as_u64(),
+        ) else {
+            drop(inner);
+            free_stacks(kernel, stacks);
+            return Err(SpawnError::NoFreePid);
+        };
+        if spawn.slot == inner.processes.len() {
+            inner.processes.push(None);
+        }
         inner.processes[spawn.slot] = Some(Process {
+            name,
             vmm,
-            stack_base,
-            stack_pages: PROCESS_STACK_PAGES,
+            stacks,
+            initial_stack,
+            limits: ResourceLimits::new((PAGE_SIZE * PROCESS_STACK_PAGES) as u64),
+            cwd: Path::root(),
+            umask: DEFAULT_UMASK,
+            files: FdTable::with_console(),
+            seccomp: Seccomp::new(),
         });
-        spawn.pid
+        Ok(spawn.pid)
+    }
+
+    // Every switch first settles the CPU time of whatever ran until now.
+    fn plan_kernel_to_first(&self) -> Option<Switch<'_, 'i, DM>> {
+        let mut inner = self.inner.lock();
+        inner.scheduler.charge(time::monotonic());
+        let plan = inner.scheduler.plan_kernel_to_first()?;
+        Some(self.switch(inner, plan))
+    }
+
+    fn plan_yield(&self) -> Option<Switch<'_, 'i, DM>> {
+        let mut inner = self.inner.lock();
+        inner.scheduler.charge(time::monotonic());
+        let plan = inner.scheduler.plan_yield()?;
+        Some(self.switch(inner, plan))
     }
 
-    fn plan_kernel_to_first(&self) -> Option<SwitchPlan> {
-        self.inner.lock().scheduler.plan_kernel_to_first()
+    fn plan_preempt(&self) -> Option<Switch<'_, 'i, DM>> {
+        let mut inner = self.inner.lock();
+        let now = time::monotonic();
+        inner.scheduler.charge(now);
+        inner.scheduler.wake_expired(now);
+        let plan = inner.scheduler.plan_preempt()?;
+        Some(self.switch(inner, plan))
+    }
+
+    fn plan_block(&self, wake_at: Option<Duration>) -> Switch<'_, 'i, DM> {
+        let mut inner = self.inner.lock();
+        inner.scheduler.charge(time::monotonic());
+        let plan = inner.scheduler.plan_block(wake_at);
+        self.switch(inner, plan)
     }
 
-    fn plan_yield(&self) -> Option<SwitchPlan> {
-        self.inner.lock().scheduler.plan_yield()
+    fn plan_after_exit(&self) -> Switch<'_, 'i, DM> {
+        let mut inner = self.inner.lock();
+        let plan = inner.scheduler.plan_after_exit();
+        self.switch(inner, plan)
+    }
+
+    // The CPU belongs to whatever the scheduler made current from here on.
+    fn switch<'a>(
+        &self,
+        inner: IrqMutexGuard<'a, ProcessStateInner<'i, DM>>,
+        plan: SwitchPlan,
+    ) -> Switch<'a, 'i, DM> {
+        this_cpu().set_current_pid(inner.scheduler.current_pid());
+        Switch { inner, plan }
+    }
+
+    // A context running for the first time has no guard of its own to drop,
+    // so it releases the lock it was switched to under here.
+    fn finish_first_switch(&self) {
+        unsafe { self.inner.force_unlock() }
+    }
+
+    fn set_quantum(&self, ticks: u32) {
+        self.inner.lock().scheduler.set_quantum(ticks);
+    }
+
+    fn set_in_syscall(&self, in_syscall: bool) {
+        self.inner
+            .lock()
+            .scheduler
+            .set_in_syscall(time::monotonic(), in_syscall);
+    }
+
+    fn wake(&self, pid: usize) -> bool {
+        let mut inner = self.inner.lock();
+        inner.scheduler.charge(time::monotonic());
+        inner.scheduler.wake(pid)
+    }
+
+    fn reset_idle(&self, cr3: u64) {
+        let stack_top = &raw mut IDLE_STACK as usize + IDLE_STACK_SIZE;
+        // The same first frame a process gets in `spawn`.
+        let initial_rsp = stack_top - 2 * core::mem::size_of::<u64>();
+        unsafe {
+            *(initial_rsp as *mut u64) = idle_loop as *const () as usize as u64;
+        }
+        self.inner
+            .lock()
+            .scheduler
+            .reset_idle(initial_rsp as u64, cr3);
     }
 
-    fn plan_exit_current(&self) -> (SwitchPlan, Process<'i, DM>) {
+    fn exit_current(&self, status: ExitStatus) -> Process<'i, DM> {
         let mut inner = self.inner.lock();
-        let ExitPlan {
-            switch,
-            exited_slot,
-        } = inner.scheduler.plan_exit_current();
-        let process = inner.processes[exited_slot]
+        inner.scheduler.charge(time::monotonic());
+        let exited_slot = inner.scheduler.exit_current(status);
+        inner.processes[exited_slot]
             .take()
-            .expect("exited process slot must be populated");
-        (switch, process)
+            .expect("exited process slot must be populated")
+    }
+
+    fn set_exited_stacks(&self, stacks: Stacks) {
+        let previous = self.inner.lock().exited_stacks.replace(stacks);
+        debug_assert!(previous.is_none(), "exited stacks were not freed");
+    }
+
+    fn take_exited_stacks(&self) -> Option<Stacks> {
+        self.inner.lock().exited_stacks.take()
+    }
+
+    fn reap(&self, pid: Option<usize>) -> Reap {
+        self.inner.lock().scheduler.reap(pid)
+    }
+
+    fn exit_status(&self, pid: usize) -> Option<ExitStatus> {
+        self.inner.lock().scheduler.exit_status(pid)
+    }
+
+    fn init_status(&self) -> Option<ExitStatus> {
+        self.inner.lock().scheduler.init_status()
     }
 
     fn current_entry(&self) -> ProcessFn {
@@ -93,17 +395,206 @@ impl<'i, DM: DirectMap> ProcessState<'i, DM> {
     }
 
     fn current_pid(&self) -> usize {
-        self.inner.lock().scheduler.current_pid()
+        this_cpu().current_pid()
     }
 
     fn has_pid(&self, pid: usize) -> bool {
         self.inner.lock().scheduler.has_pid(pid)
     }
 
-    fn with_current_process_mut<T>(
+    fn current_ppid(&self) -> usize {
+        self.inner.lock().scheduler.current_ppid()
+    }
+
+    fn priority(&self, pid: usize) -> Result<i32, PriorityError> {
+        let inner = self.inner.lock();
+        let slot =
+            Self::slot_for(&inner.scheduler, pid).ok_or(PriorityError::NoSuchProcess { pid })?;
+        Ok(inner.scheduler.priority_at(slot))
+    }
+
+    fn set_priority(&self, pid: usize, nice: i32, privileged: bool) -> Result<(), PriorityError> {
+        let mut inner = self.inner.lock();
+        let slot =
+            Self::slot_for(&inner.scheduler, pid).ok_or(PriorityError::NoSuchProcess { pid })?;
+        let raising = nice.clamp(NICE_MIN, NICE_MAX) < inner.scheduler.priority_at(slot);
+        if raising && !privileged {
+            return Err(PriorityError::PermissionDenied { nice });
+        }
+        inner.scheduler.set_priority_at(slot, nice);
+        Ok(())
+    }
+
+    fn affinity(&self, pid: usize) -> Result<u64, AffinityError> {
+        let inner = self.inner.lock();
+        let slot =
+            Self::slot_for(&inner.scheduler, pid).ok_or(AffinityError::NoSuchProcess { pid })?;
+        Ok(inner.scheduler.affinity_at(slot))
+    }
+
+    fn set_affinity(&self, pid: usize, mask: u64) -> Result<(), AffinityError> {
+        let mut inner = self.inner.lock();
+        let slot =
+            Self::slot_for(&inner.scheduler, pid).ok_or(AffinityError::NoSuchProcess { pid })?;
+        if mask & ALL_CPUS == 0 {
+            return Err(AffinityError::NoOnlineCpu { mask });
+        }
+        inner.scheduler.set_affinity_at(slot, mask & ALL_CPUS);
+        Ok(())
+    }
+
+    // Slot of live process `pid`, or of the caller for pid 0.
+    fn slot_for(scheduler: &Scheduler, pid: usize) -> Option<usize> {
+        if pid == 0 {
+            scheduler.current_slot()
+        } else {
+            scheduler.slot_of(pid)
+        }
+    }
+
+    fn name(&self, pid: usize) -> Option<ProcessName> {
+        let inner = self.inner.lock();
+        let slot = Self::slot_for(&inner.scheduler, pid)?;
+        Some(inner.processes[slot].as_ref()?.name)
+    }
+
+    fn info(&self, pid: usize) -> Option<ProcessInfo> {
+        let mut inner = self.inner.lock();
+        inner.scheduler.charge(time::monotonic());
+        let slot = Self::slot_for(&inner.scheduler, pid)?;
+        let pid = inner.scheduler.pid_at(slot);
+        let state = inner.scheduler.state_at(slot)?;
+        Self::info_at(&inner, slot, pid, state)
+    }
+
+    fn list(&self) -> Vec<ProcessInfo> {
+        let mut inner = self.inner.lock();
+        inner.scheduler.charge(time::monotonic());
+        inner
+            .scheduler
+            .live()
+            .filter_map(|(slot, pid, state)| Self::info_at(&inner, slot, pid, state))
+            .collect()
+    }
+
+    fn info_at(
+        inner: &ProcessStateInner<'i, DM>,
+        slot: usize,
+        pid: usize,
+        state: TaskState,
+    ) -> Option<ProcessInfo> {
+        let (cpu, children_cpu) = inner.scheduler.cpu_time_at(slot);
+        Some(ProcessInfo {
+            pid,
+            ppid: inner.scheduler.parent_at(slot),
+            state,
+            name: inner.processes[slot].as_ref()?.name,
+            cpu,
+            children_cpu,
+        })
+    }
+
+    /// Pick the process holding the most resident memory. A victim other than
+    /// the caller is retired and handed back for cleanup; `None` means the
+    /// caller itself was picked and has to exit.
+    fn plan_oom_kill(&self) -> (usize, usize, Option<Process<'i, DM>>) {
+        let mut inner = self.inner.lock();
+        let (slot, resident) = inner
+            .processes
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, proc)| Some((slot, proc.as_ref()?.vmm.resident_bytes())))
+            .max_by_key(|&(_, resident)| resident)
+            .expect("out of memory with no process to kill");
+        let pid = inner.scheduler.pid_at(slot);
+        (pid, resident, Self::kill_at(&mut inner, slot))
+    }
+
+    /// Retire process `pid` as SIGKILL would, like `plan_oom_kill` does its
+    /// victim.
+    fn plan_kill(&self, pid: usize) -> Result<Option<Process<'i, DM>>, KillError> {
+        let mut inner = self.inner.lock();
+        let slot = inner
+            .scheduler
+            .slot_of(pid)
+            .ok_or(KillError::NoSuchProcess { pid })?;
+        Ok(Self::kill_at(&mut inner, slot))
+    }
+
+    // Anything but the caller is off the CPU, so nothing runs on its stacks
+    // or page tables and it can be handed back for cleanup straight away.
+    // The caller gets `None` and has to exit on its own.
+    fn kill_at(inner: &mut ProcessStateInner<'i, DM>, slot: usize) -> Option<Process<'i, DM>> {
+        if inner.scheduler.current_slot() == Some(slot) {
+            return None;
+        }
+        inner.scheduler.charge(time::monotonic());
+        inner.scheduler.kill(slot, ExitStatus::Killed(SIGKILL));
+        inner.processes[slot].take()
+    }
+
+    fn trace(&self) -> Vec<TraceRecord> {
+        self.inner.lock().scheduler.trace().collect()
+    }
+
+    // Runs from the panic handler, which may have interrupted a holder of
+    // the lock.
+    fn try_dump_trace(&self) -> bool {
+        let Some(inner) = self.inner.try_lock() else {
+            return false;
+        };
+        for record in inner.scheduler.trace() {
+            crate::println!("sched: {}", record);
+        }
+        true
+    }
+
+    // Like handle_page_fault, this runs in exception context and must not spin.
+    fn try_current_pid(&self) -> Option<usize> {
+        let inner = self.inner.try_lock()?;
+        inner.scheduler.current_slot()?;
+        Some(inner.scheduler.current_pid())
+    }
+
+    fn with_process_mut<T>(
         &self,
-        f: impl FnOnce(&mut Process<'i, DM>) -> MemoryResult<T>,
-    ) -> MemoryResult<T> {
+        pid: usize,
+        f: impl FnOnce(&mut Process<'i, DM>) -> Result<T, LimitError>,
+    ) -> Result<T, LimitError> {
+        let mut inner = self.inner.lock();
+        let process = Self::slot_for(&inner.scheduler, pid)
+            .and_then(|slot| inner.processes[slot].as_mut())
+            .ok_or(LimitError::NoSuchProcess { pid })?;
+        f(process)
+    }
+
+    fn try_with_current_process_mut<T>(
+        &self,
+        f: impl FnOnce(&mut Process<'i, DM>) -> T,
+    ) -> Option<T> {
+        let mut inner = self.inner.lock();
+        let current = inner.scheduler.current_slot()?;
+        inner.processes[current].as_mut().map(f)
+    }
+
+    // A fault can hit while this CPU already holds the lock, e.g. when a
+    // syscall touches user memory under it, so never spin here.
+    fn handle_page_fault(&self, addr: usize, write: bool) -> MemoryResult<()> {
+        let mut inner = self
+            .inner
+            .try_lock()
+            .unwrap_or_else(|| panic!("page fault at {addr:#x} with process state locked"));
+        let current = inner
+            .scheduler
+            .current_slot()
+            .ok_or(MemoryError::AccessViolation { addr })?;
+        let process = inner.processes[current]
+            .as_mut()
+            .ok_or(MemoryError::AccessViolation { addr })?;
+        process.vmm.handle_fault(addr, write)
+    }
+
+    fn with_current_process_mut<T>(&self, f: impl FnOnce(&mut Process<'i, DM>) -> T) -> T {
         let mut inner = self.inner.lock();
         let current = inner.scheduler.current_slot().expect("no running process");
         let process = inner.processes[current]
@@ -150,7 +641,18 @@ __context_switch:
     mov rdx, cr3
     mov [rax + 136], rdx
 
-    fxsave64 [rax + 144]
+    fxsave64 [rax + 160]
+
+    mov r8, rax
+    mov ecx, {fs_base}
+    rdmsr
+    mov [r8 + 144], eax
+    mov [r8 + 148], edx
+    mov ecx, {gs_base}
+    rdmsr
+    mov [r8 + 152], eax
+    mov [r8 + 156], edx
+    mov rax, r8
 
     mov rdx, [rsp]
     mov [rax + 24], rdx
@@ -164,7 +666,16 @@ __context_switch:
     mov rcx, [r8 + 136]
     mov cr3, rcx
 
-    fxrstor64 [r8 + 144]
+    fxrstor64 [r8 + 160]
+
+    mov ecx, {fs_base}
+    mov eax, [r8 + 144]
+    mov edx, [r8 + 148]
+    wrmsr
+    mov ecx, {gs_base}
+    mov eax, [r8 + 152]
+    mov edx, [r8 + 156]
+    wrmsr
 
     mov r15, [r8 + 112]
     mov r14, [r8 + 104]
@@ -189,15 +700,30 @@ __context_switch:
     mov r8, [r8 + 56]
 
     ret
-"#
+"#,
+    fs_base = const arch::IA32_FS_BASE,
+    gs_base = const arch::IA32_GS_BASE,
 );
 
 unsafe extern "C" {
     fn __context_switch();
 }
 
+// A switch planned with the process state locked. The plan points into the
+// process table, so the lock stays held until the switch is done, and the
+// context switched to releases it.
+struct Switch<'a, 'i, DM: DirectMap> {
+    inner: IrqMutexGuard<'a, ProcessStateInner<'i, DM>>,
+    plan: SwitchPlan,
+}
+
+// The guard stays on this context's stack while others run. Once switched
+// back to, dropping it releases the lock whoever switched here took.
 #[inline(always)]
-unsafe fn switch_context(plan: SwitchPlan) {
+unsafe fn switch_context<DM: DirectMap>(switch: Switch<'_, '_, DM>) {
+    let Switch { inner, plan } = switch;
+    arch::percpu::this_cpu().set_kernel_stack(plan.kernel_stack);
+    arch::gdt::set_kernel_stack(plan.kernel_stack);
     unsafe {
         SWITCH_OLD_CTX = plan.old;
     }
@@ -207,89 +733,531 @@ unsafe fn switch_context(plan: SwitchPlan) {
     unsafe {
         __context_switch();
     }
+    drop(inner);
 }
 
-extern "C" fn process_trampoline() -> ! {
+// The first switch to a process restores the spawn argument into rdi, which
+// is where this picks it up. Processes run with interrupts enabled so the
+// timer can preempt them.
+extern "C" fn process_trampoline(arg: usize) -> ! {
     let kernel = crate::active_kernel();
+    kernel.process.finish_first_switch();
+    free_exited_stacks(kernel);
+    arch::enable_interrupts();
     let entry = kernel.process.current_entry();
-    entry();
-    terminate_current(kernel);
+    entry(arg);
+    terminate_current(kernel, ExitStatus::Exited(0));
+}
+
+/// Start a process called `name` running `entry(arg)`, with its name as its
+/// only argument and an empty environment. Fails when every pid is in use
+/// or when its address space, stack or process table entry cannot be
+/// allocated.
+pub fn spawn<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    name: &str,
+    entry: ProcessFn,
+    arg: usize,
+) -> Result<usize, SpawnError> {
+    spawn_with_args(kernel, name, entry, arg, &[name.as_bytes()], &[])
+}
+
+/// [`spawn`], with `argv` and `envp` laid out on the new process's stack
+/// the way the System V ABI hands them to a program, auxv included. Fails
+/// as `spawn` does, and when they do not fit on the stack.
+pub fn spawn_with_args<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    name: &str,
+    entry: ProcessFn,
+    arg: usize,
+    argv: &[&[u8]],
+    envp: &[&[u8]],
+) -> Result<usize, SpawnError> {
+    let name = ProcessName::new(name.as_bytes());
+    arch::without_interrupts(|| {
+        let vmm = Vmm::new(kernel.page_table, kernel.kalloc, kernel.pshare)?;
+        kernel
+            .process
+            .spawn(kernel, name, vmm, entry, arg, StartArgs { argv, envp })
+    })
 }
 
-pub fn spawn<DM: DirectMap>(kernel: &Kernel<'_, DM>, entry: ProcessFn) -> usize {
-    kernel.process.spawn(kernel, entry)
+/// [`spawn_with_args`], with the new process starting in a copy of the
+/// calling process's address space rather than an empty one. Pages mapped
+/// MAP_SHARED stay shared between the two; every other page is copied.
+pub fn spawn_forked<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    name: &str,
+    entry: ProcessFn,
+    arg: usize,
+    argv: &[&[u8]],
+    envp: &[&[u8]],
+) -> Result<usize, SpawnError> {
+    let name = ProcessName::new(name.as_bytes());
+    arch::without_interrupts(|| {
+        let vmm = kernel
+            .process
+            .with_current_process_mut(|proc| proc.vmm.duplicate(kernel.page_table))?;
+        kernel
+            .process
+            .spawn(kernel, name, vmm, entry, arg, StartArgs { argv, envp })
+    })
 }
 
+/// Where the calling process's System V initial stack starts: argc, with
+/// the argv and envp pointers and the auxv pairs above it.
+pub fn initial_stack<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> usize {
+    kernel
+        .process
+        .with_current_process_mut(|proc| proc.initial_stack)
+}
+
+/// Let other processes run. From the kernel's own context this runs them
+/// until none is left.
 pub fn yield_now<DM: DirectMap>(kernel: &Kernel<'_, DM>) {
-    let plan = kernel.process.plan_yield();
-    if let Some(plan) = plan {
+    if kernel.process.current_pid() == 0 {
+        run_processes(kernel);
+        return;
+    }
+    if let Some(switch) = kernel.process.plan_yield() {
+        unsafe {
+            switch_context(switch);
+        }
+        free_exited_stacks(kernel);
+    }
+}
+
+/// Take the calling process off the CPU until [`wake`] names it or until the
+/// monotonic clock reaches `deadline`, checked on every timer tick. Without
+/// a timer nothing would end the wait, and the kernel's own context cannot
+/// block, so both just yield.
+pub fn block_current<DM: DirectMap>(kernel: &Kernel<'_, DM>, deadline: Option<Duration>) {
+    if !arch::apic::timer_running() || kernel.process.current_pid() == 0 {
+        yield_now(kernel);
+        return;
+    }
+    let switch = kernel.process.plan_block(deadline);
+    unsafe {
+        switch_context(switch);
+    }
+    free_exited_stacks(kernel);
+}
+
+/// Make blocked process `pid` ready again. Returns whether it was blocked.
+pub fn wake<DM: DirectMap>(kernel: &Kernel<'_, DM>, pid: usize) -> bool {
+    kernel.process.wake(pid)
+}
+
+/// Switch away from the running process on a timer tick, after waking the
+/// processes whose deadline passed. Leaves it running when nothing more
+/// urgent is ready. Holding the process table masks interrupts, so a tick
+/// never lands while the interrupted code owns it.
+pub fn preempt<DM: DirectMap>(kernel: &Kernel<'_, DM>) {
+    if let Some(switch) = kernel.process.plan_preempt() {
         unsafe {
-            switch_context(plan);
+            switch_context(switch);
         }
+        free_exited_stacks(kernel);
     }
 }
 
+/// Run processes until none is left, then power off with the status of the
+/// first process as the VM's.
 pub fn run<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> ! {
-    loop {
-        match kernel.process.plan_kernel_to_first() {
-            Some(plan) => unsafe {
-                switch_context(plan);
-            },
-            None => loop {
-                unsafe {
-                    core::arch::asm!("hlt", options(nomem, nostack, preserves_flags));
-                }
-            },
+    run_processes(kernel);
+    let status = kernel
+        .process
+        .init_status()
+        .unwrap_or(ExitStatus::Exited(0));
+    boot::power_off(status)
+}
+
+// Dispatch processes from the kernel's own context until none is left.
+// While they are all blocked the idle task has the CPU instead, so the
+// kernel's context only gets it back once the last one is gone. Each round
+// starts the idle task afresh, dropping the interrupt frame it was last
+// switched away in.
+fn run_processes<DM: DirectMap>(kernel: &Kernel<'_, DM>) {
+    kernel.process.reset_idle(kernel.page_table.addr().as_u64());
+    while let Some(switch) = kernel.process.plan_kernel_to_first() {
+        unsafe {
+            switch_context(switch);
         }
+        free_exited_stacks(kernel);
     }
 }
 
-fn exit_current<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> ! {
-    let (switch, process) = kernel.process.plan_exit_current();
-    cleanup_process(kernel, process);
+// Halt until an interrupt arrives, for as long as no process is ready. The
+// timer tick that finds one switches away from here; the idle task comes
+// back where it left off, inside that tick, next time everything blocks.
+extern "C" fn idle_loop() -> ! {
+    let kernel = crate::active_kernel();
+    kernel.process.finish_first_switch();
+    free_exited_stacks(kernel);
+    loop {
+        arch::wait_for_interrupt();
+    }
+}
 
+fn exit_current<DM: DirectMap>(kernel: &Kernel<'_, DM>, status: ExitStatus) -> ! {
+    // Once planned, the scheduler already considers the next process
+    // current; a tick from here on would save this context over it.
+    arch::disable_interrupts();
+    let process = kernel.process.exit_current(status);
+    // Cleanup frees the page tables this process is still running on. The
+    // kernel's own tables map everything the exit path touches.
     unsafe {
-        switch_context(switch);
+        arch::load_page_table(kernel.page_table.addr());
+    }
+    // The exit path is still running on one of the process's stacks, so
+    // freeing them is left to whichever context runs next.
+    let stacks = release_process(kernel, process);
+    kernel.process.set_exited_stacks(stacks);
+    CHILD_EXITS.wake_all(kernel);
+
+    // Planned only now: the process table may have changed during cleanup.
+    unsafe {
+        switch_context(kernel.process.plan_after_exit());
     }
     unreachable!("exit_current should never return");
 }
 
 fn cleanup_process<DM: DirectMap>(kernel: &Kernel<'_, DM>, process: Process<'_, DM>) {
+    let stacks = release_process(kernel, process);
+    free_stacks(kernel, stacks);
+}
+
+// Free everything a process owns except its stacks, which are handed back.
+fn release_process<DM: DirectMap>(kernel: &Kernel<'_, DM>, mut process: Process<'_, DM>) -> Stacks {
     drop(process.vmm);
+    for file in process.files.drain() {
+        if let Err(err) = fs::close(kernel, file) {
+            crate::println!("process: failed to close a file: {}", err);
+        }
+    }
+    process.stacks
+}
+
+fn free_exited_stacks<DM: DirectMap>(kernel: &Kernel<'_, DM>) {
+    if let Some(stacks) = kernel.process.take_exited_stacks() {
+        free_stacks(kernel, stacks);
+    }
+}
+
+fn alloc_stacks<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> MemoryResult<Stacks> {
+    let kernel_stack = kernel.kalloc.alloc(KERNEL_STACK_SIZE)?;
+    let base = kernel.palloc.alloc(PROCESS_STACK_PAGES).inspect_err(|_| {
+        kernel
+            .kalloc
+            .free(kernel_stack, KERNEL_STACK_SIZE)
+            .expect("free kernel stack");
+    })?;
+    Ok(Stacks {
+        base,
+        pages: PROCESS_STACK_PAGES,
+        kernel: kernel_stack,
+    })
+}
 
-    for page in 0..process.stack_pages {
+fn free_stacks<DM: DirectMap>(kernel: &Kernel<'_, DM>, stacks: Stacks) {
+    for page in 0..stacks.pages {
         kernel
             .palloc
-            .free(process.stack_base.add(PAGE_SIZE * page))
+            .free(stacks.base.add(PAGE_SIZE * page))
             .expect("free process stack");
     }
+    kernel
+        .kalloc
+        .free(stacks.kernel, KERNEL_STACK_SIZE)
+        .expect("free kernel stack");
+}
+
+/// End the calling process with `status`, kept for its parent to collect.
+pub fn terminate_current<DM: DirectMap>(kernel: &Kernel<'_, DM>, status: ExitStatus) -> ! {
+    exit_current(kernel, status)
+}
+
+/// Wait for a child of the caller to exit and collect its status: child
+/// `pid`, or any child for `None`. With `nohang` this returns `Ok(None)`
+/// instead of blocking while the children are still running.
+pub fn wait<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    pid: Option<usize>,
+    nohang: bool,
+) -> Result<Option<ExitedChild>, WaitError> {
+    loop {
+        match kernel.process.reap(pid) {
+            Reap::Reaped { pid, status, cpu } => return Ok(Some(ExitedChild { pid, status, cpu })),
+            Reap::NoChildren => return Err(WaitError::NoChildren),
+            Reap::NotYet if nohang => return Ok(None),
+            Reap::NotYet => CHILD_EXITS.sleep(kernel, None),
+        }
+    }
 }
 
-pub fn terminate_current<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> ! {
-    exit_current(kernel)
+/// How process `pid` ended, as long as its process table slot has not been
+/// reused. `None` while it is alive or once it is forgotten.
+pub fn exit_status<DM: DirectMap>(kernel: &Kernel<'_, DM>, pid: usize) -> Option<ExitStatus> {
+    kernel.process.exit_status(pid)
 }
 
 pub fn current_pid<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> usize {
     kernel.process.current_pid()
 }
 
+/// Pid of the caller's parent: whoever spawned it, init once that exited,
+/// or 0 for the kernel.
+pub fn parent_pid<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> usize {
+    kernel.process.current_ppid()
+}
+
 pub fn has_pid<DM: DirectMap>(kernel: &Kernel<'_, DM>, pid: usize) -> bool {
     kernel.process.has_pid(pid)
 }
 
+/// Name of process `pid`, or of the caller for pid 0.
+pub fn name<DM: DirectMap>(kernel: &Kernel<'_, DM>, pid: usize) -> Option<ProcessName> {
+    kernel.process.name(pid)
+}
+
+/// What [`list`] would report for process `pid`, or for the caller for
+/// pid 0, with CPU times up to now.
+pub fn info<DM: DirectMap>(kernel: &Kernel<'_, DM>, pid: usize) -> Option<ProcessInfo> {
+    kernel.process.info(pid)
+}
+
+/// Charge the caller's CPU time from here on as system time while
+/// `in_syscall`, and as user time otherwise.
+pub fn set_in_syscall<DM: DirectMap>(kernel: &Kernel<'_, DM>, in_syscall: bool) {
+    kernel.process.set_in_syscall(in_syscall)
+}
+
+/// Let each process run `ticks` timer ticks before a ready one of the same
+/// priority takes over. More urgent processes still preempt it on the next
+/// tick. The quantum is at least one tick.
+pub fn set_quantum<DM: DirectMap>(kernel: &Kernel<'_, DM>, ticks: u32) {
+    kernel.process.set_quantum(ticks)
+}
+
+/// Every live process, in process table order.
+pub fn list<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> Vec<ProcessInfo> {
+    kernel.process.list()
+}
+
+/// The scheduler's last [`TRACE_CAPACITY`] switches, wakeups, blocks and
+/// exits, oldest first.
+pub fn trace<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> Vec<TraceRecord> {
+    kernel.process.trace()
+}
+
+/// Print the scheduler trace over serial, oldest record first. Returns
+/// `false` without printing when the process state is locked, since this
+/// has to be safe from a panic.
+pub fn try_dump_trace<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> bool {
+    kernel.process.try_dump_trace()
+}
+
+/// Nice value of process `pid`, or of the caller for pid 0.
+pub fn priority<DM: DirectMap>(kernel: &Kernel<'_, DM>, pid: usize) -> Result<i32, PriorityError> {
+    kernel.process.priority(pid)
+}
+
+/// Change the nice value of `pid` (0 = the caller). Values past either end
+/// of the range are clamped, and only root may make a process more urgent.
+pub fn set_priority<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    pid: usize,
+    nice: i32,
+) -> Result<(), PriorityError> {
+    let privileged = credentials::current().uid == 0;
+    kernel.process.set_priority(pid, nice, privileged)
+}
+
+/// CPUs process `pid` (0 = the caller) may run on, one bit each.
+pub fn affinity<DM: DirectMap>(kernel: &Kernel<'_, DM>, pid: usize) -> Result<u64, AffinityError> {
+    kernel.process.affinity(pid)
+}
+
+/// Restrict `pid` (0 = the caller) to the CPUs set in `mask`. Bits past the
+/// last CPU are dropped, and a mask without any CPU left is refused.
+pub fn set_affinity<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    pid: usize,
+    mask: u64,
+) -> Result<(), AffinityError> {
+    kernel.process.set_affinity(pid, mask)
+}
+
+/// The running process's pid, or `None` when no process is running or the
+/// process state is locked by the interrupted code.
+pub fn try_current_pid<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> Option<usize> {
+    kernel.process.try_current_pid()
+}
+
 pub fn brk<DM: DirectMap>(kernel: &Kernel<'_, DM>, requested: usize) -> MemoryResult<usize> {
     kernel
         .process
         .with_current_process_mut(|proc| proc.vmm.brk(requested))
 }
 
+pub fn handle_page_fault<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    addr: usize,
+    write: bool,
+) -> MemoryResult<()> {
+    kernel.process.handle_page_fault(addr, write)
+}
+
+/// Make room after a page could not be backed by killing the process with
+/// the largest resident set. Returns when another process was killed, so the
+/// access can be retried, and never returns when the caller was picked.
+pub fn oom_kill<DM: DirectMap>(kernel: &Kernel<'_, DM>) {
+    let (pid, resident, victim) = kernel.process.plan_oom_kill();
+    crate::println!(
+        "out of memory: killed pid {} ({} KiB resident)",
+        pid,
+        resident / 1024
+    );
+    finish_kill(kernel, victim);
+}
+
+/// Kill process `pid` as SIGKILL does: it exits with that signal as its
+/// status and gets no chance to run again. When `pid` is the caller this
+/// does not return.
+pub fn kill<DM: DirectMap>(kernel: &Kernel<'_, DM>, pid: usize) -> Result<(), KillError> {
+    let victim = kernel.process.plan_kill(pid)?;
+    finish_kill(kernel, victim);
+    Ok(())
+}
+
+// Tear down a retired victim, or end the caller when it was the one picked.
+fn finish_kill<DM: DirectMap>(kernel: &Kernel<'_, DM>, victim: Option<Process<'_, DM>>) {
+    match victim {
+        Some(process) => {
+            cleanup_process(kernel, process);
+            CHILD_EXITS.wake_all(kernel);
+        }
+        None => exit_current(kernel, ExitStatus::Killed(SIGKILL)),
+    }
+}
+
+/// Memory use of process `pid`, or of the caller for pid 0.
+pub fn memory_usage<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    pid: usize,
+) -> Result<MemoryUsage, LimitError> {
+    kernel
+        .process
+        .with_process_mut(pid, |proc| Ok(proc.vmm.usage()))
+}
+
 pub fn mmap<DM: DirectMap>(
     kernel: &Kernel<'_, DM>,
     hint: usize,
     len: usize,
+    prot: u64,
     flags: u64,
 ) -> MemoryResult<usize> {
     kernel
         .process
-        .with_current_process_mut(|proc| proc.vmm.mmap(hint, len, flags))
+        .with_current_process_mut(|proc| proc.vmm.mmap(hint, len, prot, flags))
+}
+
+/// Read and optionally replace a resource limit of `pid` (0 = the caller),
+/// returning the previous value.
+pub fn prlimit<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    pid: usize,
+    resource: usize,
+    new: Option<Rlimit>,
+) -> Result<Rlimit, LimitError> {
+    let privileged = credentials::current().uid == 0;
+    kernel.process.with_process_mut(pid, |proc| {
+        let old = proc.limits.get(resource)?;
+        if let Some(new) = new {
+            proc.limits.set(resource, new, privileged)?;
+            if resource == RLIMIT_AS {
+                proc.vmm
+                    .set_address_space_limit(proc.limits.address_space());
+            }
+        }
+        Ok(old)
+    })
+}
+
+/// Working directory of the calling process.
+pub fn getcwd<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> Path {
+    kernel
+        .process
+        .with_current_process_mut(|proc| proc.cwd.clone())
+}
+
+/// Permission bits the calling process clears from the mode of every file
+/// and directory it creates.
+pub fn umask<DM: DirectMap>(kernel: &Kernel<'_, DM>) -> u32 {
+    kernel.process.with_current_process_mut(|proc| proc.umask)
+}
+
+/// Replace the file-creation mask of the calling process, returning the
+/// previous one.
+pub fn set_umask<DM: DirectMap>(kernel: &Kernel<'_, DM>, mask: u32) -> u32 {
+    kernel
+        .process
+        .with_current_process_mut(|proc| core::mem::replace(&mut proc.umask, mask & 0o777))
+}
+
+/// Turn a caller-supplied path into an absolute one. Relative paths start at
+/// the directory open as `dirfd`, or at the working directory for `None`.
+pub fn resolve_path_at<DM: DirectMap>(
+    kernel: &Kernel<'_, DM>,
+    dirfd: Option<usize>,
+    path: &[u8],
+) -> FsResult<Path> {
+    kernel.process.with_current_process_mut(|proc| match dirfd {
+        Some(fd) if path.first() != Some(&b'/') => {
+            fs::directory_path(&proc.files.get(fd)?)?.resolve(path)
+        }
+        _ => proc.cwd.resolve(path),
+    })
+}
+
+/// Run `f` on the descriptor table of the calling process, or return `None`
+/// when no process is running.
+pub fn with_files<DM: DirectMap, T>(
+    kernel: &Kernel<'_, DM>,
+    f: impl FnOnce(&mut FdTable) -> T,
+) -> Option<T> {
+    kernel
+        .process
+        .try_with_current_process_mut(|proc| f(&mut proc.files))
+}
+
+/// How the seccomp state of the calling process treats a system call.
+/// Everything is allowed when no process is running.
+pub fn seccomp_verdict<DM: DirectMap>(kernel: &Kernel<'_, DM>, data: &SeccompData) -> Verdict {
+    kernel
+        .process
+        .try_with_current_process_mut(|proc| proc.seccomp.check(data))
+        .unwrap_or(Verdict::Allow)
+}
+
+/// Run `f` on the seccomp state of the calling process.
+pub fn with_seccomp<DM: DirectMap, T>(
+    kernel: &Kernel<'_, DM>,
+    f: impl FnOnce(&mut Seccomp) -> T,
+) -> T {
+    kernel
+        .process
+        .with_current_process_mut(|proc| f(&mut proc.seccomp))
+}
+
+/// Change the working directory of the calling process; relative `path`s are
+/// resolved against the current one.
+pub fn chdir<DM: DirectMap>(kernel: &Kernel<'_, DM>, path: &[u8]) -> FsResult<()> {
+    kernel.process.with_current_process_mut(|proc| {
+        let cwd = proc.cwd.resolve(path)?;
+        fs::lookup_directory(&cwd)?;
+        proc.cwd = cwd;
+        Ok(())
+    })
 }
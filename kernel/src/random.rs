@@ -0,0 +1,178 @@
+use core::arch::asm;
+
+use spin::Mutex;
+
+use crate::arch::cpu::{self, Features};
+use crate::time::rdtsc;
+
+const RDRAND_RETRIES: usize = 10;
+
+static FALLBACK_POOL: Mutex<ChaChaPool> = Mutex::new(ChaChaPool::new());
+
+/// Fill `buf` with random bytes, preferring the CPU's RDRAND and falling back
+/// to a ChaCha20 pool when the instruction is missing or keeps failing.
+pub fn fill(buf: &mut [u8]) {
+    if cpu::features().contains(Features::RDRAND) && fill_rdrand(buf) {
+        return;
+    }
+    FALLBACK_POOL.lock().fill(buf);
+}
+
+fn fill_rdrand(buf: &mut [u8]) -> bool {
+    for chunk in buf.chunks_mut(8) {
+        let Some(value) = rdrand64() else {
+            return false;
+        };
+        chunk.copy_from_slice(&value.to_ne_bytes()[..chunk.len()]);
+    }
+    true
+}
+
+fn rdrand64() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!(
+                "rdrand {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack),
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// ChaCha20 keystream with fast key erasure: after every request the key is
+/// replaced with fresh keystream so earlier output cannot be reconstructed.
+struct ChaChaPool {
+    key: [u32; 8],
+    seeded: bool,
+}
+
+impl ChaChaPool {
+    const fn new() -> Self {
+        Self {
+            key: [0; 8],
+            seeded: false,
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        if !self.seeded {
+            self.seed();
+        }
+
+        let mut counter = 0u32;
+        for chunk in buf.chunks_mut(64) {
+            counter += 1;
+            let block = chacha20_block(&self.key, counter, &[0; 3]);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+
+        let block = chacha20_block(&self.key, 0, &[0; 3]);
+        for (i, word) in self.key.iter_mut().enumerate() {
+            *word = u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+    }
+
+    // Without a hardware source the best entropy available is TSC jitter.
+    fn seed(&mut self) {
+        for word in self.key.iter_mut() {
+            let sample = rdtsc();
+            *word ^= (sample as u32) ^ ((sample >> 32) as u32).rotate_left(16);
+        }
+        self.seeded = true;
+    }
+}
+
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u8; 64] {
+    let mut state = [
+        0x6170_7865,
+        0x3320_646e,
+        0x7962_2d32,
+        0x6b20_6574,
+        key[0],
+        key[1],
+        key[2],
+        key[3],
+        key[4],
+        key[5],
+        key[6],
+        key[7],
+        counter,
+        nonce[0],
+        nonce[1],
+        nonce[2],
+    ];
+    let initial = state;
+
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut out = [0u8; 64];
+    for (i, word) in state.iter().enumerate() {
+        let value = word.wrapping_add(initial[i]);
+        out[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    out
+}
+
+#[inline(always)]
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chacha20_block_matches_rfc8439_vector() {
+        let key: [u32; 8] = core::array::from_fn(|i| {
+            let base = (i * 4) as u32;
+            u32::from_le_bytes([base as u8, base as u8 + 1, base as u8 + 2, base as u8 + 3])
+        });
+        let nonce = [0x0900_0000, 0x4a00_0000, 0];
+
+        let block = chacha20_block(&key, 1, &nonce);
+        assert_eq!(
+            block[..16],
+            [
+                0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20,
+                0x71, 0xc4
+            ]
+        );
+    }
+
+    #[test]
+    fn fallback_pool_never_repeats_output() {
+        let mut pool = ChaChaPool::new();
+        let mut a = [0u8; 100];
+        let mut b = [0u8; 100];
+        pool.fill(&mut a);
+        pool.fill(&mut b);
+        assert_ne!(a, b);
+        assert_ne!(a, [0u8; 100]);
+    }
+}
@@ -1,9 +1,33 @@
+use alloc::{collections::TryReserveError, vec::Vec};
 use core::arch::asm;
+use core::fmt;
+use core::ops::{Add, AddAssign};
+use core::time::Duration;
 
-pub(crate) const MAX_PROCESSES: usize = 8;
+/// Largest pid handed out before counting starts again from 1, as Linux's
+/// default `pid_max`.
+pub(crate) const PID_MAX: usize = 32768;
+// Adopts orphans, and its exit status becomes the VM's exit code.
+const INIT_PID: usize = 1;
 const NO_PROCESS: usize = usize::MAX;
+// Timer ticks in a time slice unless the run flags ask for another quantum.
+const DEFAULT_QUANTUM_TICKS: u32 = 1;
 
-pub type ProcessFn = fn();
+/// CPUs processes are scheduled on. The VMM creates a single vCPU.
+pub const CPU_COUNT: usize = 1;
+/// Affinity mask allowing every CPU.
+pub const ALL_CPUS: u64 = (1 << CPU_COUNT) - 1;
+// The CPU this scheduler hands out.
+pub(crate) const THIS_CPU: usize = 0;
+
+/// Scheduler events kept in its trace; older ones are overwritten.
+pub const TRACE_CAPACITY: usize = 128;
+
+/// Nice values as setpriority(2) takes them: lower runs first.
+pub const NICE_MIN: i32 = -20;
+pub const NICE_MAX: i32 = 19;
+
+pub type ProcessFn = fn(usize);
 
 #[repr(C, align(16))]
 #[derive(Clone, Copy)]
@@ -26,6 +50,10 @@ pub struct Context {
     rsp: u64,
     rflags: u64,
     cr3: u64,
+    // Thread-local and per-CPU data hang off these, and nothing but the
+    // MSRs keeps them.
+    fs_base: u64,
+    gs_base: u64,
     fxstate: [u8; 512],
 }
 
@@ -50,42 +78,255 @@ impl Context {
             rsp: 0,
             rflags: 0x2,
             cr3: 0,
+            fs_base: 0,
+            gs_base: 0,
             fxstate: [0; 512],
         }
     }
 }
 
+/// How a process ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// It called exit(2) with this code.
+    Exited(u8),
+    /// The kernel killed it, as this signal would have.
+    Killed(u8),
+}
+
+impl ExitStatus {
+    /// The status word wait4(2) reports, as `WEXITSTATUS` and `WTERMSIG`
+    /// take apart.
+    pub fn wait_status(self) -> i32 {
+        match self {
+            Self::Exited(code) => i32::from(code) << 8,
+            Self::Killed(signal) => i32::from(signal),
+        }
+    }
+
+    /// The status behind a word `wait_status` made: a signal in the low
+    /// seven bits, or else the exit code in the byte above. 0x7f there marks
+    /// a stopped or continued process, which has not ended, so gives `None`.
+    pub fn from_wait_status(status: u16) -> Option<Self> {
+        match status & 0x7f {
+            0 => Some(Self::Exited((status >> 8) as u8)),
+            0x7f => None,
+            signal => Some(Self::Killed(signal as u8)),
+        }
+    }
+
+    /// The status as a shell reports it: the exit code, or 128 plus the
+    /// signal for a killed process.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::Exited(code) => code,
+            Self::Killed(signal) => 128u8.wrapping_add(signal),
+        }
+    }
+}
+
+/// CPU time spent running a process's own code and inside its syscalls.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuTime {
+    pub user: Duration,
+    pub system: Duration,
+}
+
+impl CpuTime {
+    pub const ZERO: Self = Self {
+        user: Duration::ZERO,
+        system: Duration::ZERO,
+    };
+}
+
+impl Add for CpuTime {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            user: self.user + other.user,
+            system: self.system + other.system,
+        }
+    }
+}
+
+impl AddAssign for CpuTime {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+/// Something the scheduler did, as kept in its trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// The CPU went from `from` to `to`. Pid 0 stands for the kernel's own
+    /// context and for the idle task, as Linux numbers its idle task.
+    Switch { from: usize, to: usize },
+    /// Blocked process `pid` became ready again.
+    Wakeup { pid: usize },
+    /// Process `pid` went to sleep until woken, or until `deadline`.
+    Block {
+        pid: usize,
+        deadline: Option<Duration>,
+    },
+    /// Process `pid` exited or was killed.
+    Exit { pid: usize, status: ExitStatus },
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Switch { from, to } => write!(f, "switch {from} -> {to}"),
+            Self::Wakeup { pid } => write!(f, "wakeup {pid}"),
+            Self::Block {
+                pid,
+                deadline: None,
+            } => write!(f, "block {pid}"),
+            Self::Block {
+                pid,
+                deadline: Some(deadline),
+            } => write!(f, "block {pid} until {deadline:?}"),
+            Self::Exit {
+                pid,
+                status: ExitStatus::Exited(code),
+            } => write!(f, "exit {pid} code {code}"),
+            Self::Exit {
+                pid,
+                status: ExitStatus::Killed(signal),
+            } => write!(f, "exit {pid} signal {signal}"),
+        }
+    }
+}
+
+/// A trace event and when it happened, on the monotonic clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub at: Duration,
+    pub event: TraceEvent,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:06}] {}",
+            self.at.as_secs(),
+            self.at.subsec_micros(),
+            self.event
+        )
+    }
+}
+
+// Fixed size so recording never allocates, not even from the timer.
+struct Trace {
+    records: [TraceRecord; TRACE_CAPACITY],
+    // Records ever written; the newest sits just before `written` modulo
+    // the capacity.
+    written: usize,
+}
+
+impl Trace {
+    const fn new() -> Self {
+        Self {
+            records: [TraceRecord {
+                at: Duration::ZERO,
+                event: TraceEvent::Wakeup { pid: 0 },
+            }; TRACE_CAPACITY],
+            written: 0,
+        }
+    }
+
+    fn record(&mut self, record: TraceRecord) {
+        self.records[self.written % TRACE_CAPACITY] = record;
+        self.written += 1;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = TraceRecord> + '_ {
+        let kept = self.written.min(TRACE_CAPACITY);
+        (self.written - kept..self.written).map(|i| self.records[i % TRACE_CAPACITY])
+    }
+}
+
+/// What a live process is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    Running,
+    Ready,
+    Blocked,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum State {
     Empty,
     Ready,
     Running,
+    Blocked,
+    // Exited with a live parent that has not collected its status yet.
+    Zombie,
+    // Exited and collected, or orphaned; the slot is free for reuse.
     Exited,
 }
 
 #[derive(Clone, Copy)]
 struct Process {
     id: usize,
+    // Pid of the process that spawned it, 0 for the kernel.
+    parent: usize,
     state: State,
+    // Nice value; each one is its own level of the ready queue.
+    priority: i32,
+    // CPUs it may run on, one bit each.
+    affinity: u64,
+    // When a blocked process wakes up on its own, if ever.
+    wake_at: Option<Duration>,
+    // Top of the stack its syscalls run on.
+    kernel_stack: u64,
+    // Whether CPU time is charged to it as system rather than user time.
+    in_syscall: bool,
+    cpu: CpuTime,
+    // Everything its reaped children used, their own children included.
+    children_cpu: CpuTime,
     context: Context,
     entry: Option<ProcessFn>,
+    // Kept until the slot is reused.
+    exit_status: Option<ExitStatus>,
 }
 
 impl Process {
     const fn empty() -> Self {
         Self {
             id: 0,
+            parent: 0,
             state: State::Empty,
+            priority: 0,
+            affinity: ALL_CPUS,
+            wake_at: None,
+            kernel_stack: 0,
+            in_syscall: false,
+            cpu: CpuTime::ZERO,
+            children_cpu: CpuTime::ZERO,
             context: Context::empty(),
             entry: None,
+            exit_status: None,
         }
     }
+
+    // Ready, and allowed on the CPU being scheduled.
+    fn runnable(&self) -> bool {
+        self.state == State::Ready && self.affinity & (1 << THIS_CPU) != 0
+    }
 }
 
+// Points into the process table, so it has to be carried out before
+// anything can grow the table again.
 #[derive(Clone, Copy)]
 pub struct SwitchPlan {
     pub old: *mut Context,
     pub new: *const Context,
+    /// Kernel stack of the context switched to; 0 for the kernel's own
+    /// context, which has no separate one.
+    pub kernel_stack: u64,
 }
 
 pub struct SpawnPlan {
@@ -93,70 +334,222 @@ pub struct SpawnPlan {
     pub pid: usize,
 }
 
-pub struct ExitPlan {
-    pub switch: SwitchPlan,
-    pub exited_slot: usize,
+/// What a parent waiting for its children finds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reap {
+    /// Child `pid` exited with `status` after using `cpu`, its own reaped
+    /// children included; its pid may be reused from now on.
+    Reaped {
+        pid: usize,
+        status: ExitStatus,
+        cpu: CpuTime,
+    },
+    /// Matching children exist but none has exited yet.
+    NotYet,
+    /// No child matches.
+    NoChildren,
 }
 
 pub(crate) struct Scheduler {
     kernel_context: Context,
-    processes: [Process; MAX_PROCESSES],
+    // Halts the CPU while every process is blocked. The timer preempts it
+    // like any process once one of them is ready again.
+    idle_context: Context,
+    idling: bool,
+    // Where the registers of an exiting process go. Its slot may already
+    // belong to another process by the time it switches away.
+    exited_context: Context,
+    // Slots of exited processes are reused before the table grows.
+    processes: Vec<Process>,
     current: usize,
     next_pid: usize,
+    init_status: Option<ExitStatus>,
+    // Up to when the running process has been charged for the CPU.
+    charged_until: Duration,
+    // Timer ticks a process runs before others of its priority get a turn,
+    // and how many of them the running process has left.
+    quantum: u32,
+    slice_left: u32,
+    trace: Trace,
 }
 
 impl Scheduler {
     pub(crate) const fn new() -> Self {
         Self {
             kernel_context: Context::empty(),
-            processes: [Process::empty(); MAX_PROCESSES],
+            idle_context: Context::empty(),
+            idling: false,
+            exited_context: Context::empty(),
+            processes: Vec::new(),
             current: NO_PROCESS,
             next_pid: 1,
+            init_status: None,
+            charged_until: Duration::ZERO,
+            quantum: DEFAULT_QUANTUM_TICKS,
+            slice_left: 0,
+            trace: Trace::new(),
         }
     }
 
-    pub(crate) fn spawn(&mut self, entry: ProcessFn, rsp: u64, cr3: u64) -> SpawnPlan {
-        let slot = self
-            .processes
-            .iter()
-            .position(|proc| proc.state == State::Empty || proc.state == State::Exited)
-            .expect("process table is full");
+    /// Let processes run `ticks` timer ticks before one of the same priority
+    /// takes over, from their next turn on. At least one tick is given.
+    pub(crate) fn set_quantum(&mut self, ticks: u32) {
+        self.quantum = ticks.max(1);
+    }
+
+    /// Make sure `spawn` finds a slot without allocating, so a failed
+    /// allocation is reported before anything was set up.
+    pub(crate) fn reserve_slot(&mut self) -> Result<(), TryReserveError> {
+        if self.free_slot().is_some() {
+            return Ok(());
+        }
+        self.processes.try_reserve(1)
+    }
 
-        let pid = self.next_pid;
-        self.next_pid += 1;
+    /// Claim a slot for a new process, or `None` when every pid is taken. The
+    /// new process starts at its parent's priority, with `arg` in rdi for
+    /// its first function. Call `reserve_slot` first; past that, the table
+    /// grows as needed.
+    pub(crate) fn spawn(
+        &mut self,
+        entry: ProcessFn,
+        arg: usize,
+        rsp: u64,
+        kernel_stack: u64,
+        cr3: u64,
+    ) -> Option<SpawnPlan> {
+        let pid = self.alloc_pid()?;
+        let slot = self.free_slot().unwrap_or_else(|| {
+            self.processes.push(Process::empty());
+            self.processes.len() - 1
+        });
+        let (priority, affinity) = self.current_slot().map_or((0, ALL_CPUS), |current| {
+            let proc = &self.processes[current];
+            (proc.priority, proc.affinity)
+        });
+        let parent = self.current_pid();
 
         self.processes[slot] = Process {
             id: pid,
+            parent,
             state: State::Ready,
+            priority,
+            affinity,
+            wake_at: None,
+            kernel_stack,
+            in_syscall: false,
+            cpu: CpuTime::ZERO,
+            children_cpu: CpuTime::ZERO,
+            // Interrupts stay masked until its first function has released
+            // the lock the switch to it was made under.
             context: Context {
+                rdi: arg as u64,
                 rsp,
                 cr3,
                 ..Context::empty()
             },
             entry: Some(entry),
+            exit_status: None,
         };
 
         save_current_fxstate(&mut self.processes[slot].context);
-        SpawnPlan { slot, pid }
+        Some(SpawnPlan { slot, pid })
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.processes
+            .iter()
+            .position(|proc| proc.state == State::Empty || proc.state == State::Exited)
+    }
+
+    // Pids count up to PID_MAX and then wrap, skipping those still in the
+    // table, so a pid is only reused long after its process exited and an
+    // exit status always belongs to the pid it is looked up by.
+    fn alloc_pid(&mut self) -> Option<usize> {
+        for _ in 0..PID_MAX {
+            let pid = self.next_pid;
+            self.next_pid = if pid == PID_MAX { 1 } else { pid + 1 };
+            let taken = self
+                .processes
+                .iter()
+                .any(|proc| proc.id == pid && proc.state != State::Empty);
+            if !taken {
+                return Some(pid);
+            }
+        }
+        None
+    }
+
+    /// Start the idle task over with its stack at `rsp`, whose top holds
+    /// the address it begins at, in the address space rooted at `cr3`.
+    /// Only while it is not running.
+    pub(crate) fn reset_idle(&mut self, rsp: u64, cr3: u64) {
+        assert!(!self.idling, "cannot reset the idle task while it runs");
+        self.idle_context = Context {
+            rsp,
+            cr3,
+            ..Context::empty()
+        };
+        save_current_fxstate(&mut self.idle_context);
     }
 
     pub(crate) fn plan_kernel_to_first(&mut self) -> Option<SwitchPlan> {
-        let next = self.find_next_ready(NO_PROCESS)?;
-        self.processes[next].state = State::Running;
-        self.current = next;
+        let next = self.find_next_by_priority(NO_PROCESS)?;
+        self.start_running(next);
         Some(SwitchPlan {
             old: &mut self.kernel_context as *mut Context,
             new: &self.processes[next].context as *const Context,
+            kernel_stack: self.processes[next].kernel_stack,
         })
     }
 
+    /// Hand the CPU to the next ready process whatever its priority. Without
+    /// blocking, a process that yields is usually waiting on another one, so
+    /// a yield must reach lower levels too or they would never catch up.
     pub(crate) fn plan_yield(&mut self) -> Option<SwitchPlan> {
         if self.current == NO_PROCESS {
             return self.plan_kernel_to_first();
         }
 
         let current = self.current;
-        let next = self.find_next_ready(current)?;
+        let next = self.find_next(current, |_| true)?;
+        self.plan_switch(current, next)
+    }
+
+    /// Pick what runs after a timer tick: the highest-priority ready process,
+    /// taking turns with the running one if it is on the same level and its
+    /// time slice is used up. Only ever switches from one process to
+    /// another; a tick never pulls the kernel's own context into the
+    /// rotation, though it does end the idle task's turn as soon as a
+    /// process is ready.
+    pub(crate) fn plan_preempt(&mut self) -> Option<SwitchPlan> {
+        if self.idling {
+            let next = self.find_next_by_priority(NO_PROCESS)?;
+            self.start_running(next);
+            return Some(SwitchPlan {
+                old: &mut self.idle_context as *mut Context,
+                new: &self.processes[next].context as *const Context,
+                kernel_stack: self.processes[next].kernel_stack,
+            });
+        }
+        if self.current == NO_PROCESS {
+            return None;
+        }
+
+        let current = self.current;
+        self.slice_left = self.slice_left.saturating_sub(1);
+        let next = self.find_next_by_priority(current)?;
+        let (next_priority, priority) = (
+            self.processes[next].priority,
+            self.processes[current].priority,
+        );
+        if next_priority > priority || (next_priority == priority && self.slice_left > 0) {
+            return None;
+        }
+        self.plan_switch(current, next)
+    }
+
+    fn plan_switch(&mut self, current: usize, next: usize) -> Option<SwitchPlan> {
         if next == current {
             return None;
         }
@@ -164,44 +557,270 @@ impl Scheduler {
         if self.processes[current].state == State::Running {
             self.processes[current].state = State::Ready;
         }
-        self.processes[next].state = State::Running;
-        self.current = next;
+        self.start_running(next);
 
         Some(SwitchPlan {
             old: &mut self.processes[current].context as *mut Context,
             new: &self.processes[next].context as *const Context,
+            kernel_stack: self.processes[next].kernel_stack,
         })
     }
 
-    pub(crate) fn plan_exit_current(&mut self) -> ExitPlan {
+    /// Retire the running process and pick what runs next; returns the
+    /// slot it had. The switch itself is planned by `plan_after_exit`, once
+    /// the process has been torn down.
+    pub(crate) fn exit_current(&mut self, status: ExitStatus) -> usize {
         let current = self.current;
         assert!(current != NO_PROCESS, "no running process to exit");
 
-        self.processes[current].state = State::Exited;
-        self.processes[current].entry = None;
-        self.processes[current].context.cr3 = 0;
+        self.retire(current, status);
+        self.leave();
+        current
+    }
 
-        let switch = if let Some(next) = self.find_next_ready(current) {
-            self.processes[next].state = State::Running;
-            self.current = next;
-            SwitchPlan {
-                old: &mut self.processes[current].context as *mut Context,
-                new: &self.processes[next].context as *const Context,
+    /// Switch from a process that `exit_current` retired to whatever was
+    /// picked to run after it.
+    pub(crate) fn plan_after_exit(&mut self) -> SwitchPlan {
+        let (new, kernel_stack) = self.running_context();
+        SwitchPlan {
+            old: &mut self.exited_context as *mut Context,
+            new,
+            kernel_stack,
+        }
+    }
+
+    /// Take the running process off the CPU until `wake` picks it, or until
+    /// `wake_expired` is called at or after `wake_at`.
+    pub(crate) fn plan_block(&mut self, wake_at: Option<Duration>) -> SwitchPlan {
+        let current = self.current;
+        assert!(current != NO_PROCESS, "no running process to block");
+
+        self.processes[current].state = State::Blocked;
+        self.processes[current].wake_at = wake_at;
+        self.record(TraceEvent::Block {
+            pid: self.processes[current].id,
+            deadline: wake_at,
+        });
+        self.leave();
+        let (new, kernel_stack) = self.running_context();
+        SwitchPlan {
+            old: &mut self.processes[current].context as *mut Context,
+            new,
+            kernel_stack,
+        }
+    }
+
+    /// Make blocked process `pid` ready again. Returns whether it was
+    /// blocked.
+    pub(crate) fn wake(&mut self, pid: usize) -> bool {
+        let Some(slot) = self.slot_of(pid) else {
+            return false;
+        };
+        let proc = &mut self.processes[slot];
+        if proc.state != State::Blocked {
+            return false;
+        }
+        proc.state = State::Ready;
+        proc.wake_at = None;
+        self.record(TraceEvent::Wakeup { pid });
+        true
+    }
+
+    /// Wake every blocked process whose `wake_at` is not after `now`.
+    pub(crate) fn wake_expired(&mut self, now: Duration) {
+        for slot in 0..self.processes.len() {
+            let proc = &mut self.processes[slot];
+            if proc.state == State::Blocked && proc.wake_at.is_some_and(|wake_at| wake_at <= now) {
+                proc.state = State::Ready;
+                proc.wake_at = None;
+                let pid = proc.id;
+                self.record(TraceEvent::Wakeup { pid });
             }
+        }
+    }
+
+    pub(crate) fn has_blocked(&self) -> bool {
+        self.processes
+            .iter()
+            .any(|proc| proc.state == State::Blocked)
+    }
+
+    // Hand the CPU from the current process, which no longer runs, to the
+    // most urgent ready process. Without one it goes to the idle task while
+    // others are blocked, and back to the kernel's own context once none is
+    // left.
+    fn leave(&mut self) {
+        match self.find_next_by_priority(self.current) {
+            Some(next) => self.start_running(next),
+            None => {
+                self.record_switch(0);
+                self.idling = self.has_blocked();
+                self.current = NO_PROCESS;
+            }
+        }
+    }
+
+    // The context the CPU is given to, and the kernel stack that goes with
+    // it.
+    fn running_context(&self) -> (*const Context, u64) {
+        if let Some(current) = self.current_slot() {
+            let proc = &self.processes[current];
+            (&proc.context as *const Context, proc.kernel_stack)
+        } else if self.idling {
+            (&self.idle_context as *const Context, 0)
         } else {
-            self.current = NO_PROCESS;
-            SwitchPlan {
-                old: &mut self.processes[current].context as *mut Context,
-                new: &self.kernel_context as *const Context,
+            (&self.kernel_context as *const Context, 0)
+        }
+    }
+
+    /// Retire a process that is not running, the same way an exiting one
+    /// is; the running process has to go through `exit_current`.
+    pub(crate) fn kill(&mut self, slot: usize, status: ExitStatus) {
+        assert!(slot != self.current, "cannot kill the running process");
+        assert!(
+            matches!(self.processes[slot].state, State::Ready | State::Blocked),
+            "only ready or blocked processes can be killed"
+        );
+        self.retire(slot, status);
+    }
+
+    // A process with a live parent stays a zombie until the parent reaps it.
+    // Nobody would ever reap the kernel's children, so those free their slot
+    // straight away. Its own children are adopted by init, or by the kernel
+    // once init is gone.
+    fn retire(&mut self, slot: usize, status: ExitStatus) {
+        let Process { id, parent, .. } = self.processes[slot];
+        let reaper_alive = parent != 0 && self.has_pid(parent);
+        let proc = &mut self.processes[slot];
+        proc.state = if reaper_alive {
+            State::Zombie
+        } else {
+            State::Exited
+        };
+        proc.exit_status = Some(status);
+        proc.entry = None;
+        proc.context.cr3 = 0;
+        self.record(TraceEvent::Exit { pid: id, status });
+
+        let adopter = if self.has_pid(INIT_PID) { INIT_PID } else { 0 };
+        for child in &mut self.processes {
+            if child.parent != id {
+                continue;
+            }
+            match child.state {
+                State::Ready | State::Running | State::Blocked => child.parent = adopter,
+                State::Zombie if adopter != 0 => child.parent = adopter,
+                State::Zombie => {
+                    child.parent = 0;
+                    child.state = State::Exited;
+                }
+                State::Empty | State::Exited => {}
             }
+        }
+        if id == INIT_PID {
+            self.init_status = Some(status);
+        }
+    }
+
+    /// Collect an exited child of the running process: child `pid`, or any
+    /// child for `None`. A reaped child's pid is free to be reused.
+    pub(crate) fn reap(&mut self, pid: Option<usize>) -> Reap {
+        let parent = self.current_pid();
+        let mut waiting = false;
+        let mut zombie = None;
+        for (slot, child) in self.processes.iter().enumerate() {
+            if child.parent != parent || pid.is_some_and(|pid| child.id != pid) {
+                continue;
+            }
+            match child.state {
+                State::Zombie => {
+                    zombie = Some(slot);
+                    break;
+                }
+                State::Ready | State::Running | State::Blocked => waiting = true,
+                State::Empty | State::Exited => {}
+            }
+        }
+        let Some(slot) = zombie else {
+            return if waiting {
+                Reap::NotYet
+            } else {
+                Reap::NoChildren
+            };
         };
 
-        ExitPlan {
-            switch,
-            exited_slot: current,
+        let child = &mut self.processes[slot];
+        child.state = State::Exited;
+        child.parent = 0;
+        let pid = child.id;
+        let status = child.exit_status.expect("zombie without exit status");
+        let cpu = child.cpu + child.children_cpu;
+        if let Some(current) = self.current_slot() {
+            self.processes[current].children_cpu += cpu;
+        }
+        Reap::Reaped { pid, status, cpu }
+    }
+
+    /// Charge the running process for the CPU up to `now`, as system time
+    /// while it is inside a syscall and as user time otherwise. Time the
+    /// kernel's own context runs is not charged to anyone.
+    pub(crate) fn charge(&mut self, now: Duration) {
+        let elapsed = now.saturating_sub(self.charged_until);
+        self.charged_until = now;
+        if let Some(current) = self.current_slot() {
+            let proc = &mut self.processes[current];
+            if proc.in_syscall {
+                proc.cpu.system += elapsed;
+            } else {
+                proc.cpu.user += elapsed;
+            }
+        }
+    }
+
+    /// Note the running process entering or leaving a syscall at `now`.
+    pub(crate) fn set_in_syscall(&mut self, now: Duration, in_syscall: bool) {
+        self.charge(now);
+        if let Some(current) = self.current_slot() {
+            self.processes[current].in_syscall = in_syscall;
         }
     }
 
+    /// The trace, oldest record first. Only the last [`TRACE_CAPACITY`]
+    /// events are kept.
+    pub(crate) fn trace(&self) -> impl Iterator<Item = TraceRecord> + '_ {
+        self.trace.iter()
+    }
+
+    // Stamped with the time CPU time was last charged up to, which callers
+    // bring up to date before asking the scheduler for anything.
+    fn record(&mut self, event: TraceEvent) {
+        self.trace.record(TraceRecord {
+            at: self.charged_until,
+            event,
+        });
+    }
+
+    // The CPU leaves whatever runs now, the kernel's context and the idle
+    // task included, for pid `to`.
+    fn record_switch(&mut self, to: usize) {
+        let from = self.current_pid();
+        self.record(TraceEvent::Switch { from, to });
+    }
+
+    /// How process `pid` ended, while its slot remembers: until the slot is
+    /// reused. `None` while it is still alive.
+    pub(crate) fn exit_status(&self, pid: usize) -> Option<ExitStatus> {
+        self.processes
+            .iter()
+            .find(|proc| proc.id == pid && matches!(proc.state, State::Zombie | State::Exited))
+            .and_then(|proc| proc.exit_status)
+    }
+
+    /// How the first process, pid 1, ended the last time it ran.
+    pub(crate) fn init_status(&self) -> Option<ExitStatus> {
+        self.init_status
+    }
+
     pub(crate) fn current_entry(&self) -> ProcessFn {
         assert!(self.current != NO_PROCESS, "no running process");
         self.processes[self.current]
@@ -225,20 +844,104 @@ impl Scheduler {
         }
     }
 
+    /// Pid of the running process's parent; 0 when it is the kernel, and
+    /// for the kernel's own context.
+    pub(crate) fn current_ppid(&self) -> usize {
+        self.current_slot()
+            .map_or(0, |current| self.processes[current].parent)
+    }
+
+    pub(crate) fn pid_at(&self, slot: usize) -> usize {
+        self.processes[slot].id
+    }
+
+    pub(crate) fn parent_at(&self, slot: usize) -> usize {
+        self.processes[slot].parent
+    }
+
+    /// CPU time of the process in `slot`, and of the children it reaped.
+    pub(crate) fn cpu_time_at(&self, slot: usize) -> (CpuTime, CpuTime) {
+        let proc = &self.processes[slot];
+        (proc.cpu, proc.children_cpu)
+    }
+
+    pub(crate) fn affinity_at(&self, slot: usize) -> u64 {
+        self.processes[slot].affinity
+    }
+
+    /// Let the process in `slot` run only on the CPUs set in `mask`. A
+    /// process allowed on none of them never runs again.
+    pub(crate) fn set_affinity_at(&mut self, slot: usize, mask: u64) {
+        self.processes[slot].affinity = mask;
+    }
+
+    pub(crate) fn priority_at(&self, slot: usize) -> i32 {
+        self.processes[slot].priority
+    }
+
+    /// Move the process in `slot` to another level, clamped to the range of
+    /// nice values. Takes effect from the next timer tick.
+    pub(crate) fn set_priority_at(&mut self, slot: usize, nice: i32) {
+        self.processes[slot].priority = nice.clamp(NICE_MIN, NICE_MAX);
+    }
+
+    /// Slot, pid and state of every live process, in table order.
+    pub(crate) fn live(&self) -> impl Iterator<Item = (usize, usize, TaskState)> + '_ {
+        (0..self.processes.len())
+            .filter_map(|slot| Some((slot, self.processes[slot].id, self.state_at(slot)?)))
+    }
+
+    /// What the process in `slot` is doing, or `None` when it is not alive.
+    pub(crate) fn state_at(&self, slot: usize) -> Option<TaskState> {
+        match self.processes[slot].state {
+            State::Running => Some(TaskState::Running),
+            State::Ready => Some(TaskState::Ready),
+            State::Blocked => Some(TaskState::Blocked),
+            State::Empty | State::Zombie | State::Exited => None,
+        }
+    }
+
     pub(crate) fn has_pid(&self, pid: usize) -> bool {
-        self.processes.iter().any(|proc| {
-            proc.id == pid && (proc.state == State::Ready || proc.state == State::Running)
+        self.slot_of(pid).is_some()
+    }
+
+    pub(crate) fn slot_of(&self, pid: usize) -> Option<usize> {
+        self.processes.iter().position(|proc| {
+            proc.id == pid && matches!(proc.state, State::Ready | State::Running | State::Blocked)
         })
     }
 
-    fn find_next_ready(&self, current: usize) -> Option<usize> {
-        for i in 0..MAX_PROCESSES {
+    // Every turn on the CPU starts with a full time slice.
+    fn start_running(&mut self, slot: usize) {
+        self.record_switch(self.processes[slot].id);
+        self.processes[slot].state = State::Running;
+        self.current = slot;
+        self.idling = false;
+        self.slice_left = self.quantum;
+    }
+
+    // The first ready process on the highest level that has one, starting
+    // after `current` so processes on the same level take turns.
+    fn find_next_by_priority(&self, current: usize) -> Option<usize> {
+        let best = self
+            .processes
+            .iter()
+            .filter(|proc| proc.runnable())
+            .map(|proc| proc.priority)
+            .min()?;
+        self.find_next(current, |proc| proc.priority == best)
+    }
+
+    fn find_next(&self, current: usize, eligible: impl Fn(&Process) -> bool) -> Option<usize> {
+        let len = self.processes.len();
+        for i in 0..len {
             let idx = if current == NO_PROCESS {
                 i
             } else {
-                (current + i + 1) % MAX_PROCESSES
+                (current + i + 1) % len
             };
-            if self.processes[idx].state == State::Ready {
+            let proc = &self.processes[idx];
+            if proc.runnable() && eligible(proc) {
                 return Some(idx);
             }
         }
@@ -256,3 +959,486 @@ fn save_current_fxstate(context: &mut Context) {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    fn entry(_: usize) {}
+
+    #[test]
+    fn context_layout_matches_the_context_switch() {
+        // `__context_switch` addresses these fields by offset, and fxsave64
+        // needs its area 16-byte aligned.
+        assert_eq!(offset_of!(Context, rsp), 120);
+        assert_eq!(offset_of!(Context, rflags), 128);
+        assert_eq!(offset_of!(Context, cr3), 136);
+        assert_eq!(offset_of!(Context, fs_base), 144);
+        assert_eq!(offset_of!(Context, gs_base), 152);
+        assert_eq!(offset_of!(Context, fxstate), 160);
+    }
+
+    #[test]
+    fn ticks_favour_higher_priority_and_yields_reach_every_level() {
+        let mut scheduler = Scheduler::new();
+        let batch = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        let other_batch = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        let reader = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        scheduler.set_priority_at(batch.slot, 10);
+        scheduler.set_priority_at(other_batch.slot, 10);
+        scheduler.set_priority_at(reader.slot, -5);
+
+        // The most urgent process runs first and keeps the CPU across ticks.
+        scheduler.plan_kernel_to_first().unwrap();
+        assert_eq!(scheduler.current_pid(), reader.pid);
+        assert!(scheduler.plan_preempt().is_none());
+
+        // Yielding hands the CPU down a level; the next tick takes it back.
+        scheduler.plan_yield().unwrap();
+        assert_eq!(scheduler.current_pid(), batch.pid);
+        scheduler.plan_preempt().unwrap();
+        assert_eq!(scheduler.current_pid(), reader.pid);
+
+        // Processes on one level take turns.
+        scheduler.exit_current(ExitStatus::Exited(0));
+        assert_eq!(scheduler.current_pid(), batch.pid);
+        scheduler.plan_preempt().unwrap();
+        assert_eq!(scheduler.current_pid(), other_batch.pid);
+        scheduler.plan_preempt().unwrap();
+        assert_eq!(scheduler.current_pid(), batch.pid);
+
+        // Children inherit the level and nice values are clamped.
+        let child = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        assert_eq!(scheduler.priority_at(child.slot), 10);
+        scheduler.set_priority_at(child.slot, 100);
+        assert_eq!(scheduler.priority_at(child.slot), NICE_MAX);
+    }
+
+    #[test]
+    fn processes_on_one_level_take_turns_after_a_full_slice() {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_quantum(3);
+        let first = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        let second = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+
+        scheduler.plan_kernel_to_first().unwrap();
+        assert!(scheduler.plan_preempt().is_none());
+        assert!(scheduler.plan_preempt().is_none());
+        scheduler.plan_preempt().unwrap();
+        assert_eq!(scheduler.current_pid(), second.pid);
+
+        // A yield gives up the rest of the slice, and the next turn starts
+        // a full one.
+        scheduler.plan_yield().unwrap();
+        assert_eq!(scheduler.current_pid(), first.pid);
+        assert!(scheduler.plan_preempt().is_none());
+
+        // A more urgent process does not wait for the slice to run out.
+        let urgent = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        scheduler.set_priority_at(urgent.slot, -1);
+        scheduler.plan_preempt().unwrap();
+        assert_eq!(scheduler.current_pid(), urgent.pid);
+
+        // A slice that ran out while only less urgent processes were ready
+        // ends at the first tick that finds an equal. No quantum is shorter
+        // than a tick.
+        scheduler.set_quantum(0);
+        scheduler.exit_current(ExitStatus::Exited(0));
+        scheduler.set_priority_at(second.slot, 1);
+        assert!(scheduler.plan_preempt().is_none());
+        scheduler.set_priority_at(second.slot, 0);
+        scheduler.plan_preempt().unwrap();
+        assert_eq!(scheduler.current_pid(), second.pid);
+    }
+
+    #[test]
+    fn processes_only_run_on_cpus_their_affinity_allows() {
+        let mut scheduler = Scheduler::new();
+        let pinned = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        let elsewhere = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        assert_eq!(scheduler.affinity_at(pinned.slot), ALL_CPUS);
+        scheduler.set_affinity_at(elsewhere.slot, 1 << (THIS_CPU + 1));
+
+        scheduler.plan_kernel_to_first().unwrap();
+        assert_eq!(scheduler.current_pid(), pinned.pid);
+        assert!(scheduler.plan_preempt().is_none());
+        assert!(scheduler.plan_yield().is_none());
+
+        // Children start out with their parent's mask.
+        let mask = ALL_CPUS | 1 << 5;
+        scheduler.set_affinity_at(pinned.slot, mask);
+        let child = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        assert_eq!(scheduler.affinity_at(child.slot), mask);
+
+        scheduler.set_affinity_at(elsewhere.slot, ALL_CPUS);
+        scheduler.exit_current(ExitStatus::Exited(0));
+        assert_eq!(scheduler.current_pid(), elsewhere.pid);
+    }
+
+    #[test]
+    fn blocked_processes_wait_for_a_wake_or_their_deadline() {
+        let mut scheduler = Scheduler::new();
+        let sleeper = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        let waiter = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+
+        scheduler.plan_kernel_to_first().unwrap();
+        scheduler.plan_block(Some(Duration::from_millis(10)));
+        assert_eq!(scheduler.current_pid(), waiter.pid);
+        assert!(
+            scheduler.has_pid(sleeper.pid),
+            "blocked processes stay alive"
+        );
+
+        // Nothing else is ready, so the idle task gets the CPU until a tick
+        // finds someone to run.
+        let idle = &scheduler.idle_context as *const Context;
+        assert_eq!(scheduler.plan_block(None).new, idle);
+        assert_eq!(scheduler.current_pid(), 0);
+
+        scheduler.wake_expired(Duration::from_millis(9));
+        assert!(scheduler.plan_preempt().is_none());
+        scheduler.wake_expired(Duration::from_millis(10));
+        let plan = scheduler.plan_preempt().unwrap();
+        assert_eq!(plan.old.cast_const(), idle);
+        assert_eq!(scheduler.current_pid(), sleeper.pid);
+
+        // Waking is only for blocked processes.
+        assert!(!scheduler.wake(sleeper.pid));
+        assert!(scheduler.wake(waiter.pid));
+        scheduler.exit_current(ExitStatus::Exited(0));
+        assert_eq!(scheduler.current_pid(), waiter.pid);
+        assert!(!scheduler.has_blocked());
+
+        // The kernel's own context only gets the CPU back once every process
+        // is gone.
+        let kernel = &scheduler.kernel_context as *const Context;
+        scheduler.exit_current(ExitStatus::Exited(0));
+        let plan = scheduler.plan_after_exit();
+        assert_eq!(plan.new, kernel);
+        assert_eq!(
+            plan.old.cast_const(),
+            &scheduler.exited_context as *const Context
+        );
+        assert!(scheduler.plan_preempt().is_none());
+    }
+
+    #[test]
+    fn trace_records_switches_wakeups_blocks_and_exits() {
+        let mut scheduler = Scheduler::new();
+        let sleeper = scheduler.spawn(entry, 0, 0, 0, 0).unwrap().pid;
+        let waker = scheduler.spawn(entry, 0, 0, 0, 0).unwrap().pid;
+        let at = Duration::from_millis;
+
+        scheduler.charge(at(1));
+        scheduler.plan_kernel_to_first().unwrap();
+        scheduler.charge(at(2));
+        scheduler.plan_block(None);
+        scheduler.charge(at(3));
+        scheduler.wake(sleeper);
+        scheduler.exit_current(ExitStatus::Exited(3));
+        scheduler.charge(at(4));
+        scheduler.exit_current(ExitStatus::Killed(9));
+
+        let events = [
+            (
+                at(1),
+                TraceEvent::Switch {
+                    from: 0,
+                    to: sleeper,
+                },
+            ),
+            (
+                at(2),
+                TraceEvent::Block {
+                    pid: sleeper,
+                    deadline: None,
+                },
+            ),
+            (
+                at(2),
+                TraceEvent::Switch {
+                    from: sleeper,
+                    to: waker,
+                },
+            ),
+            (at(3), TraceEvent::Wakeup { pid: sleeper }),
+            (
+                at(3),
+                TraceEvent::Exit {
+                    pid: waker,
+                    status: ExitStatus::Exited(3),
+                },
+            ),
+            (
+                at(3),
+                TraceEvent::Switch {
+                    from: waker,
+                    to: sleeper,
+                },
+            ),
+            (
+                at(4),
+                TraceEvent::Exit {
+                    pid: sleeper,
+                    status: ExitStatus::Killed(9),
+                },
+            ),
+            (
+                at(4),
+                TraceEvent::Switch {
+                    from: sleeper,
+                    to: 0,
+                },
+            ),
+        ]
+        .map(|(at, event)| TraceRecord { at, event });
+        assert!(scheduler.trace().eq(events));
+        assert_eq!(
+            events[3].to_string(),
+            format!("[    0.003000] wakeup {sleeper}")
+        );
+
+        // Only the newest records survive a full ring.
+        for pid in 0..TRACE_CAPACITY + 1 {
+            scheduler.record(TraceEvent::Wakeup { pid });
+        }
+        let mut trace = scheduler.trace();
+        assert_eq!(trace.next().unwrap().event, TraceEvent::Wakeup { pid: 1 });
+        assert_eq!(trace.count(), TRACE_CAPACITY - 1);
+    }
+
+    #[test]
+    fn live_reports_what_each_process_is_doing() {
+        let mut scheduler = Scheduler::new();
+        let blocked = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        let running = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        let ready = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        let killed = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+
+        scheduler.plan_kernel_to_first().unwrap();
+        scheduler.plan_block(None);
+        scheduler.kill(killed.slot, ExitStatus::Killed(9));
+
+        let live: Vec<_> = scheduler.live().collect();
+        assert_eq!(
+            live,
+            [
+                (blocked.slot, blocked.pid, TaskState::Blocked),
+                (running.slot, running.pid, TaskState::Running),
+                (ready.slot, ready.pid, TaskState::Ready),
+            ]
+        );
+    }
+
+    #[test]
+    fn switches_carry_the_kernel_stack_of_the_next_context() {
+        let mut scheduler = Scheduler::new();
+        scheduler.spawn(entry, 0, 0, 0x1000, 0).unwrap();
+        scheduler.spawn(entry, 0, 0, 0x2000, 0).unwrap();
+
+        assert_eq!(
+            scheduler.plan_kernel_to_first().unwrap().kernel_stack,
+            0x1000
+        );
+        assert_eq!(scheduler.plan_yield().unwrap().kernel_stack, 0x2000);
+        scheduler.exit_current(ExitStatus::Exited(0));
+        assert_eq!(scheduler.plan_after_exit().kernel_stack, 0x1000);
+        scheduler.exit_current(ExitStatus::Exited(0));
+        assert_eq!(scheduler.plan_after_exit().kernel_stack, 0);
+    }
+
+    #[test]
+    fn spawn_argument_starts_out_in_rdi() {
+        let mut scheduler = Scheduler::new();
+        scheduler.spawn(entry, 0xfeed, 0, 0, 0).unwrap();
+
+        let plan = scheduler.plan_kernel_to_first().unwrap();
+        assert_eq!(unsafe { (*plan.new).rdi }, 0xfeed);
+    }
+
+    #[test]
+    fn table_grows_and_pids_wrap_around_live_processes() {
+        let mut scheduler = Scheduler::new();
+        let spawned: Vec<_> = (0..20)
+            .map(|_| {
+                scheduler.reserve_slot().unwrap();
+                scheduler.spawn(entry, 0, 0, 0, 0).unwrap()
+            })
+            .collect();
+        assert_eq!(spawned.last().unwrap().pid, 20);
+
+        // An exited slot is reused instead of growing the table.
+        scheduler.kill(spawned[3].slot, ExitStatus::Killed(9));
+        scheduler.next_pid = PID_MAX;
+        let last = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        assert_eq!((last.slot, last.pid), (spawned[3].slot, PID_MAX));
+
+        // Counting starts over at 1 and skips the pids still in use. With no
+        // exited slot left, the table grows.
+        scheduler.reserve_slot().unwrap();
+        let wrapped = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        assert_eq!((wrapped.slot, wrapped.pid), (20, spawned[3].pid));
+    }
+
+    #[test]
+    fn exited_children_wait_for_their_parent_to_reap_them() {
+        let mut scheduler = Scheduler::new();
+        let parent = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        scheduler.plan_kernel_to_first().unwrap();
+        let child = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        let orphan = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        assert_eq!(scheduler.reap(None), Reap::NotYet);
+
+        // An exited child keeps its status and its slot until it is reaped.
+        scheduler.plan_yield().unwrap();
+        assert_eq!(scheduler.current_pid(), child.pid);
+        scheduler.exit_current(ExitStatus::Exited(3));
+        assert_eq!(scheduler.current_pid(), orphan.pid);
+        scheduler.plan_yield().unwrap();
+        assert_eq!(scheduler.current_pid(), parent.pid);
+        assert!(!scheduler.has_pid(child.pid));
+        assert_eq!(
+            scheduler.exit_status(child.pid),
+            Some(ExitStatus::Exited(3))
+        );
+        assert_eq!(scheduler.free_slot(), None);
+
+        assert_eq!(scheduler.reap(Some(orphan.pid)), Reap::NotYet);
+        assert_eq!(
+            scheduler.reap(None),
+            Reap::Reaped {
+                pid: child.pid,
+                status: ExitStatus::Exited(3),
+                cpu: CpuTime::ZERO,
+            }
+        );
+        assert_eq!(scheduler.reap(Some(child.pid)), Reap::NoChildren);
+        assert_eq!(scheduler.free_slot(), Some(child.slot));
+
+        // Children outliving init go to the kernel, which never reaps, so
+        // their slots are free as soon as they exit.
+        scheduler.exit_current(ExitStatus::Exited(1));
+        assert_eq!(scheduler.init_status(), Some(ExitStatus::Exited(1)));
+        assert_eq!(scheduler.current_pid(), orphan.pid);
+        scheduler.exit_current(ExitStatus::Killed(9));
+        assert_eq!(
+            scheduler.exit_status(orphan.pid),
+            Some(ExitStatus::Killed(9))
+        );
+        assert_eq!(scheduler.reap(None), Reap::NoChildren);
+        assert_eq!(scheduler.free_slot(), Some(parent.slot));
+    }
+
+    #[test]
+    fn exit_statuses_encode_like_linux() {
+        assert_eq!(ExitStatus::Exited(3).wait_status(), 0x300);
+        assert_eq!(ExitStatus::Killed(9).wait_status(), 9);
+        assert_eq!(ExitStatus::Exited(3).exit_code(), 3);
+        assert_eq!(ExitStatus::Killed(9).exit_code(), 137);
+        for status in [
+            ExitStatus::Exited(0),
+            ExitStatus::Exited(255),
+            ExitStatus::Killed(31),
+        ] {
+            assert_eq!(
+                ExitStatus::from_wait_status(status.wait_status() as u16),
+                Some(status)
+            );
+        }
+        assert_eq!(ExitStatus::from_wait_status(0x137f), None);
+        assert_eq!(ExitStatus::from_wait_status(0xffff), None);
+    }
+
+    #[test]
+    fn init_adopts_orphans_and_reaps_them() {
+        let mut scheduler = Scheduler::new();
+        let init = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        assert_eq!(init.pid, INIT_PID);
+        scheduler.plan_kernel_to_first().unwrap();
+        assert_eq!(scheduler.current_ppid(), 0);
+        let parent = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+
+        scheduler.plan_yield().unwrap();
+        assert_eq!(scheduler.current_pid(), parent.pid);
+        assert_eq!(scheduler.current_ppid(), init.pid);
+        let orphan = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        let zombie = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+
+        // One child exits unreaped, the other outlives its parent; init
+        // inherits both.
+        scheduler.plan_yield().unwrap();
+        scheduler.plan_yield().unwrap();
+        assert_eq!(scheduler.current_pid(), zombie.pid);
+        scheduler.exit_current(ExitStatus::Exited(2));
+        assert_eq!(scheduler.current_pid(), init.pid);
+        scheduler.plan_yield().unwrap();
+        assert_eq!(scheduler.current_pid(), parent.pid);
+        scheduler.exit_current(ExitStatus::Exited(0));
+        assert_eq!(scheduler.current_pid(), orphan.pid);
+        assert_eq!(scheduler.current_ppid(), init.pid);
+
+        scheduler.plan_yield().unwrap();
+        assert_eq!(scheduler.current_pid(), init.pid);
+        assert_eq!(
+            scheduler.reap(Some(parent.pid)),
+            Reap::Reaped {
+                pid: parent.pid,
+                status: ExitStatus::Exited(0),
+                cpu: CpuTime::ZERO,
+            }
+        );
+        assert_eq!(
+            scheduler.reap(None),
+            Reap::Reaped {
+                pid: zombie.pid,
+                status: ExitStatus::Exited(2),
+                cpu: CpuTime::ZERO,
+            }
+        );
+        assert_eq!(scheduler.reap(None), Reap::NotYet);
+    }
+
+    #[test]
+    fn cpu_time_is_charged_as_user_or_system_time() {
+        let ms = Duration::from_millis;
+        let mut scheduler = Scheduler::new();
+        let parent = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+
+        // Time in the kernel's own context is nobody's.
+        scheduler.charge(ms(5));
+        scheduler.plan_kernel_to_first().unwrap();
+        scheduler.set_in_syscall(ms(8), true);
+        scheduler.set_in_syscall(ms(10), false);
+        let child = scheduler.spawn(entry, 0, 0, 0, 0).unwrap();
+        scheduler.charge(ms(11));
+        scheduler.plan_yield().unwrap();
+        assert_eq!(
+            scheduler.cpu_time_at(parent.slot),
+            (
+                CpuTime {
+                    user: ms(4),
+                    system: ms(2),
+                },
+                CpuTime::ZERO
+            )
+        );
+
+        // Reaping adds what the child used to its parent's children time.
+        scheduler.charge(ms(20));
+        scheduler.exit_current(ExitStatus::Exited(0));
+        let child_cpu = CpuTime {
+            user: ms(9),
+            system: ms(0),
+        };
+        assert_eq!(
+            scheduler.reap(None),
+            Reap::Reaped {
+                pid: child.pid,
+                status: ExitStatus::Exited(0),
+                cpu: child_cpu,
+            }
+        );
+        assert_eq!(scheduler.cpu_time_at(parent.slot).1, child_cpu);
+    }
+}
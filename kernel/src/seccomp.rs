@@ -0,0 +1,477 @@
+use thiserror::Error as ThisError;
+
+use crate::syscall::{SYS_EXIT, SYS_READ, SYS_RT_SIGRETURN, SYS_WRITE};
+
+pub const SECCOMP_SET_MODE_STRICT: u32 = 0;
+pub const SECCOMP_SET_MODE_FILTER: u32 = 1;
+pub const SECCOMP_GET_ACTION_AVAIL: u32 = 2;
+
+pub const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+pub const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+pub const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+pub const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+pub const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+pub const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+pub const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+pub const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+pub const SECCOMP_RET_ACTION_FULL: u32 = 0xffff_0000;
+pub const SECCOMP_RET_DATA: u32 = 0x0000_ffff;
+
+pub const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+
+// Classic BPF opcodes accepted in filters.
+pub const BPF_LD_W_ABS: u16 = 0x20;
+pub const BPF_LD_W_LEN: u16 = 0x80;
+pub const BPF_LD_IMM: u16 = 0x00;
+pub const BPF_LDX_IMM: u16 = 0x01;
+pub const BPF_ALU_AND_K: u16 = 0x54;
+pub const BPF_ALU_OR_K: u16 = 0x44;
+pub const BPF_ALU_ADD_K: u16 = 0x04;
+pub const BPF_ALU_SUB_K: u16 = 0x14;
+pub const BPF_ALU_LSH_K: u16 = 0x64;
+pub const BPF_ALU_RSH_K: u16 = 0x74;
+pub const BPF_ALU_XOR_K: u16 = 0xa4;
+pub const BPF_JMP_JA: u16 = 0x05;
+pub const BPF_JMP_JEQ_K: u16 = 0x15;
+pub const BPF_JMP_JGT_K: u16 = 0x25;
+pub const BPF_JMP_JGE_K: u16 = 0x35;
+pub const BPF_JMP_JSET_K: u16 = 0x45;
+pub const BPF_JMP_JEQ_X: u16 = 0x1d;
+pub const BPF_JMP_JGT_X: u16 = 0x2d;
+pub const BPF_JMP_JGE_X: u16 = 0x3d;
+pub const BPF_JMP_JSET_X: u16 = 0x4d;
+pub const BPF_RET_K: u16 = 0x06;
+pub const BPF_RET_A: u16 = 0x16;
+pub const BPF_MISC_TAX: u16 = 0x07;
+pub const BPF_MISC_TXA: u16 = 0x87;
+
+/// Longest program a single filter may have, as in Linux.
+pub const BPF_MAXINSNS: usize = 4096;
+/// Instructions shared by all filters of one process.
+pub const MAX_INSNS: usize = 128;
+pub const MAX_FILTERS: usize = 8;
+
+const SECCOMP_DATA_SIZE: u32 = size_of::<SeccompData>() as u32;
+const MAX_ERRNO: u32 = 4095;
+const ENOSYS: u16 = 38;
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompError {
+    #[error("filter length {len} is out of range")]
+    InvalidLength { len: usize },
+
+    #[error("invalid filter instruction at {pc}")]
+    InvalidInstruction { pc: usize },
+
+    #[error("no room for {len} more filter instructions")]
+    OutOfSpace { len: usize },
+
+    #[error("seccomp mode cannot change once set")]
+    ModeChange,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl SockFilter {
+    pub const fn stmt(code: u16, k: u32) -> Self {
+        Self {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    pub const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Self {
+        Self { code, jt, jf, k }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SockFprog {
+    pub len: u16,
+    pub filter: *const SockFilter,
+}
+
+/// The view of a system call that filters load words from.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SeccompData {
+    pub nr: i32,
+    pub arch: u32,
+    pub instruction_pointer: u64,
+    pub args: [u64; 6],
+}
+
+impl SeccompData {
+    // `offset` is a checked, aligned offset into the structure.
+    fn word(&self, offset: u32) -> u32 {
+        let half = |value: u64| (value >> (8 * (offset % 8))) as u32;
+        match offset {
+            0 => self.nr as u32,
+            4 => self.arch,
+            8 | 12 => half(self.instruction_pointer),
+            _ => half(self.args[(offset as usize - 16) / 8]),
+        }
+    }
+}
+
+/// What happens to a system call once every filter has seen it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Errno(u16),
+    Kill,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Disabled,
+    Strict,
+    Filter,
+}
+
+/// Per-process seccomp state. Filters are only ever added, and all of them
+/// run on every system call; the most restrictive result wins.
+#[derive(Clone, Copy, Debug)]
+pub struct Seccomp {
+    mode: Mode,
+    insns: [SockFilter; MAX_INSNS],
+    // Filter `i` occupies `insns[ends[i - 1]..ends[i]]`.
+    ends: [usize; MAX_FILTERS],
+    filters: usize,
+}
+
+impl Seccomp {
+    pub const fn new() -> Self {
+        Self {
+            mode: Mode::Disabled,
+            insns: [SockFilter::stmt(0, 0); MAX_INSNS],
+            ends: [0; MAX_FILTERS],
+            filters: 0,
+        }
+    }
+
+    /// Only `read`, `write` and `exit` are allowed from now on.
+    pub fn set_strict(&mut self) -> Result<(), SeccompError> {
+        self.set_mode(Mode::Strict)
+    }
+
+    /// Validate `program` and stack it on top of the installed filters.
+    pub fn add_filter(&mut self, program: &[SockFilter]) -> Result<(), SeccompError> {
+        if program.is_empty() || program.len() > BPF_MAXINSNS {
+            return Err(SeccompError::InvalidLength { len: program.len() });
+        }
+        check_program(program)?;
+
+        let start = self.used();
+        let end = start + program.len();
+        if self.filters == MAX_FILTERS || end > MAX_INSNS {
+            return Err(SeccompError::OutOfSpace { len: program.len() });
+        }
+        self.set_mode(Mode::Filter)?;
+        self.insns[start..end].copy_from_slice(program);
+        self.ends[self.filters] = end;
+        self.filters += 1;
+        Ok(())
+    }
+
+    pub fn check(&self, data: &SeccompData) -> Verdict {
+        match self.mode {
+            Mode::Disabled => Verdict::Allow,
+            Mode::Strict if strict_allows(data.nr) => Verdict::Allow,
+            Mode::Strict => Verdict::Kill,
+            Mode::Filter => verdict(self.run_filters(data)),
+        }
+    }
+
+    fn set_mode(&mut self, mode: Mode) -> Result<(), SeccompError> {
+        if self.mode != Mode::Disabled && self.mode != mode {
+            return Err(SeccompError::ModeChange);
+        }
+        self.mode = mode;
+        Ok(())
+    }
+
+    fn used(&self) -> usize {
+        self.filters
+            .checked_sub(1)
+            .map_or(0, |last| self.ends[last])
+    }
+
+    // Newest filter first. Action values order by precedence when compared
+    // as signed numbers, with KILL_PROCESS the most negative.
+    fn run_filters(&self, data: &SeccompData) -> u32 {
+        (0..self.filters)
+            .rev()
+            .map(|i| {
+                let start = i.checked_sub(1).map_or(0, |prev| self.ends[prev]);
+                run(&self.insns[start..self.ends[i]], data)
+            })
+            .min_by_key(|&ret| (ret & SECCOMP_RET_ACTION_FULL) as i32)
+            .unwrap_or(SECCOMP_RET_ALLOW)
+    }
+}
+
+impl Default for Seccomp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether filters may return `action`. There are no signals, tracers or
+/// notification listeners, so only the actions that need none are offered.
+pub fn action_available(action: u32) -> bool {
+    matches!(
+        action,
+        SECCOMP_RET_KILL_PROCESS
+            | SECCOMP_RET_KILL_THREAD
+            | SECCOMP_RET_ERRNO
+            | SECCOMP_RET_LOG
+            | SECCOMP_RET_ALLOW
+    )
+}
+
+fn strict_allows(nr: i32) -> bool {
+    matches!(
+        nr as u64,
+        SYS_READ | SYS_WRITE | SYS_EXIT | SYS_RT_SIGRETURN
+    )
+}
+
+// A filter asking for a tracer or listener that is not there gets ENOSYS,
+// as on Linux; SIGSYS cannot be delivered, so TRAP kills instead.
+fn verdict(ret: u32) -> Verdict {
+    match ret & SECCOMP_RET_ACTION_FULL {
+        SECCOMP_RET_ALLOW | SECCOMP_RET_LOG => Verdict::Allow,
+        SECCOMP_RET_ERRNO => Verdict::Errno((ret & SECCOMP_RET_DATA).min(MAX_ERRNO) as u16),
+        SECCOMP_RET_TRACE | SECCOMP_RET_USER_NOTIF => Verdict::Errno(ENOSYS),
+        _ => Verdict::Kill,
+    }
+}
+
+// Jumps only go forward and the program ends in a return, so every run
+// terminates at a return instruction.
+fn check_program(program: &[SockFilter]) -> Result<(), SeccompError> {
+    for (pc, insn) in program.iter().enumerate() {
+        let remaining = program.len() - pc - 1;
+        let valid = match insn.code {
+            BPF_LD_W_ABS => insn.k % 4 == 0 && insn.k < SECCOMP_DATA_SIZE,
+            BPF_JMP_JA => (insn.k as usize) < remaining,
+            BPF_JMP_JEQ_K | BPF_JMP_JGT_K | BPF_JMP_JGE_K | BPF_JMP_JSET_K | BPF_JMP_JEQ_X
+            | BPF_JMP_JGT_X | BPF_JMP_JGE_X | BPF_JMP_JSET_X => {
+                (insn.jt as usize) < remaining && (insn.jf as usize) < remaining
+            }
+            BPF_ALU_LSH_K | BPF_ALU_RSH_K => insn.k < 32,
+            BPF_LD_W_LEN | BPF_LD_IMM | BPF_LDX_IMM | BPF_ALU_AND_K | BPF_ALU_OR_K
+            | BPF_ALU_ADD_K | BPF_ALU_SUB_K | BPF_ALU_XOR_K | BPF_RET_K | BPF_RET_A
+            | BPF_MISC_TAX | BPF_MISC_TXA => true,
+            _ => false,
+        };
+        if !valid {
+            return Err(SeccompError::InvalidInstruction { pc });
+        }
+    }
+
+    let last = program.len() - 1;
+    match program[last].code {
+        BPF_RET_K | BPF_RET_A => Ok(()),
+        _ => Err(SeccompError::InvalidInstruction { pc: last }),
+    }
+}
+
+fn run(program: &[SockFilter], data: &SeccompData) -> u32 {
+    let (mut a, mut x) = (0u32, 0u32);
+    let mut pc = 0;
+    loop {
+        let insn = program[pc];
+        pc += 1;
+        let taken = match insn.code {
+            BPF_LD_W_ABS => {
+                a = data.word(insn.k);
+                continue;
+            }
+            BPF_LD_W_LEN => {
+                a = SECCOMP_DATA_SIZE;
+                continue;
+            }
+            BPF_LD_IMM => {
+                a = insn.k;
+                continue;
+            }
+            BPF_LDX_IMM => {
+                x = insn.k;
+                continue;
+            }
+            BPF_ALU_AND_K => {
+                a &= insn.k;
+                continue;
+            }
+            BPF_ALU_OR_K => {
+                a |= insn.k;
+                continue;
+            }
+            BPF_ALU_ADD_K => {
+                a = a.wrapping_add(insn.k);
+                continue;
+            }
+            BPF_ALU_SUB_K => {
+                a = a.wrapping_sub(insn.k);
+                continue;
+            }
+            BPF_ALU_LSH_K => {
+                a <<= insn.k;
+                continue;
+            }
+            BPF_ALU_RSH_K => {
+                a >>= insn.k;
+                continue;
+            }
+            BPF_ALU_XOR_K => {
+                a ^= insn.k;
+                continue;
+            }
+            BPF_MISC_TAX => {
+                x = a;
+                continue;
+            }
+            BPF_MISC_TXA => {
+                a = x;
+                continue;
+            }
+            BPF_JMP_JA => {
+                pc += insn.k as usize;
+                continue;
+            }
+            BPF_JMP_JEQ_K => a == insn.k,
+            BPF_JMP_JGT_K => a > insn.k,
+            BPF_JMP_JGE_K => a >= insn.k,
+            BPF_JMP_JSET_K => a & insn.k != 0,
+            BPF_JMP_JEQ_X => a == x,
+            BPF_JMP_JGT_X => a > x,
+            BPF_JMP_JGE_X => a >= x,
+            BPF_JMP_JSET_X => a & x != 0,
+            BPF_RET_K => return insn.k,
+            BPF_RET_A => return a,
+            code => unreachable!("unchecked filter opcode {code:#x}"),
+        };
+        pc += if taken { insn.jt } else { insn.jf } as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NR: u32 = core::mem::offset_of!(SeccompData, nr) as u32;
+    const ARCH: u32 = core::mem::offset_of!(SeccompData, arch) as u32;
+    const ARG0: u32 = core::mem::offset_of!(SeccompData, args) as u32;
+
+    fn data(nr: i32, arg0: u64) -> SeccompData {
+        SeccompData {
+            nr,
+            arch: AUDIT_ARCH_X86_64,
+            instruction_pointer: 0,
+            args: [arg0, 0, 0, 0, 0, 0],
+        }
+    }
+
+    // Deny `nr` with EPERM and allow everything else on x86_64.
+    fn deny(nr: u32) -> [SockFilter; 6] {
+        [
+            SockFilter::stmt(BPF_LD_W_ABS, ARCH),
+            SockFilter::jump(BPF_JMP_JEQ_K, AUDIT_ARCH_X86_64, 1, 0),
+            SockFilter::stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            SockFilter::stmt(BPF_LD_W_ABS, NR),
+            SockFilter::jump(BPF_JMP_JEQ_K, nr, 1, 0),
+            SockFilter::stmt(BPF_RET_K, SECCOMP_RET_ALLOW),
+        ]
+    }
+
+    #[test]
+    fn filter_matches_syscall_number_and_arguments() {
+        let mut seccomp = Seccomp::new();
+        let mut program = deny(39).to_vec();
+        program.push(SockFilter::stmt(BPF_LD_W_ABS, ARG0));
+        program.push(SockFilter::jump(BPF_JMP_JSET_K, 0x2, 0, 1));
+        program.push(SockFilter::stmt(BPF_RET_K, SECCOMP_RET_ERRNO | 1));
+        program.push(SockFilter::stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+        seccomp.add_filter(&program).unwrap();
+
+        assert_eq!(seccomp.check(&data(0, 0)), Verdict::Allow);
+        assert_eq!(seccomp.check(&data(39, 0)), Verdict::Allow);
+        assert_eq!(seccomp.check(&data(39, 0x2)), Verdict::Errno(1));
+
+        let mut foreign = data(0, 0);
+        foreign.arch = 0x4000_0003;
+        assert_eq!(seccomp.check(&foreign), Verdict::Kill);
+    }
+
+    #[test]
+    fn most_restrictive_filter_wins() {
+        let mut seccomp = Seccomp::new();
+        let errno = [SockFilter::stmt(BPF_RET_K, SECCOMP_RET_ERRNO | 13)];
+        let log = [SockFilter::stmt(BPF_RET_K, SECCOMP_RET_LOG)];
+        seccomp.add_filter(&log).unwrap();
+        seccomp.add_filter(&errno).unwrap();
+        seccomp.add_filter(&log).unwrap();
+        assert_eq!(seccomp.check(&data(0, 0)), Verdict::Errno(13));
+
+        let kill = [SockFilter::stmt(BPF_RET_K, SECCOMP_RET_KILL_THREAD)];
+        seccomp.add_filter(&kill).unwrap();
+        assert_eq!(seccomp.check(&data(0, 0)), Verdict::Kill);
+    }
+
+    #[test]
+    fn strict_mode_allows_only_basic_io() {
+        let mut seccomp = Seccomp::new();
+        seccomp.set_strict().unwrap();
+        assert_eq!(seccomp.check(&data(1, 0)), Verdict::Allow);
+        assert_eq!(seccomp.check(&data(60, 0)), Verdict::Allow);
+        assert_eq!(seccomp.check(&data(231, 0)), Verdict::Kill);
+
+        let allow = [SockFilter::stmt(BPF_RET_K, SECCOMP_RET_ALLOW)];
+        assert_eq!(seccomp.add_filter(&allow), Err(SeccompError::ModeChange));
+    }
+
+    #[test]
+    fn invalid_programs_are_rejected() {
+        let mut seccomp = Seccomp::new();
+        assert_eq!(
+            seccomp.add_filter(&[]),
+            Err(SeccompError::InvalidLength { len: 0 })
+        );
+
+        let no_return = [SockFilter::stmt(BPF_LD_W_ABS, NR)];
+        let misaligned = [
+            SockFilter::stmt(BPF_LD_W_ABS, 2),
+            SockFilter::stmt(BPF_RET_A, 0),
+        ];
+        let past_end = [
+            SockFilter::jump(BPF_JMP_JEQ_K, 0, 1, 0),
+            SockFilter::stmt(BPF_RET_A, 0),
+        ];
+        let scratch_store = [SockFilter::stmt(0x02, 0), SockFilter::stmt(BPF_RET_A, 0)];
+        for program in [&no_return[..], &misaligned, &past_end, &scratch_store] {
+            assert!(matches!(
+                seccomp.add_filter(program),
+                Err(SeccompError::InvalidInstruction { .. })
+            ));
+        }
+        assert_eq!(seccomp.check(&data(0, 0)), Verdict::Allow);
+
+        let long = [SockFilter::stmt(BPF_RET_K, SECCOMP_RET_ALLOW); MAX_INSNS + 1];
+        assert_eq!(
+            seccomp.add_filter(&long),
+            Err(SeccompError::OutOfSpace { len: MAX_INSNS + 1 })
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use crate::arch;
+
+/// A spinlock that masks interrupts on this CPU for as long as it is held,
+/// so an interrupt handler taking it can never spin on a holder it
+/// interrupted.
+pub struct IrqMutex<T> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let interrupts_enabled = arch::interrupts_enabled();
+        arch::disable_interrupts();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_enabled,
+        }
+    }
+
+    /// Take the lock if nobody holds it. For paths that may run while this
+    /// CPU holds it, such as exception handlers, where spinning would never
+    /// end.
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let interrupts_enabled = arch::interrupts_enabled();
+        arch::disable_interrupts();
+        let Some(guard) = self.inner.try_lock() else {
+            if interrupts_enabled {
+                arch::enable_interrupts();
+            }
+            return None;
+        };
+        Some(IrqMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            interrupts_enabled,
+        })
+    }
+
+    /// Release the lock without a guard, leaving interrupts masked.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held, and its holder must have handed it over to
+    /// the caller, as a switch to a context that never took it does.
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() }
+    }
+}
+
+/// Access to the data of a locked [`IrqMutex`]. Dropping it releases the
+/// lock and unmasks interrupts again if they were enabled when it was
+/// taken.
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Interrupts stay masked until the lock is free.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            arch::enable_interrupts();
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{
+    fs::errors::FsError,
+    limits::LimitError,
+    memory::errors::MemoryError,
+    net::errors::NetError,
+    process::{AffinityError, KillError, PriorityError, SpawnError, WaitError},
+    seccomp::SeccompError,
+};
+
+pub type SyscallResult<T = u64> = Result<T, Errno>;
+
+/// Linux error numbers a syscall can fail with. Callers see them negated in
+/// rax.
+#[allow(clippy::upper_case_acronyms)]
+#[repr(i64)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EIO = 5,
+    E2BIG = 7,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOTTY = 25,
+    EFBIG = 27,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    EPIPE = 32,
+    ERANGE = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ENOTSOCK = 88,
+    EDESTADDRREQ = 89,
+    EMSGSIZE = 90,
+    EPROTOTYPE = 91,
+    EPROTONOSUPPORT = 93,
+    ESOCKTNOSUPPORT = 94,
+    EOPNOTSUPP = 95,
+    EAFNOSUPPORT = 97,
+    EADDRINUSE = 98,
+    EADDRNOTAVAIL = 99,
+    ENETDOWN = 100,
+    ENETUNREACH = 101,
+    ENOBUFS = 105,
+    EISCONN = 106,
+    ENOTCONN = 107,
+    ECONNREFUSED = 111,
+    EINPROGRESS = 115,
+}
+
+impl Errno {
+    pub const fn code(self) -> i64 {
+        self as i64
+    }
+}
+
+/// Encode a handler result the way the syscall instruction returns it.
+pub fn into_raw(result: SyscallResult) -> u64 {
+    result.unwrap_or_else(|errno| (-errno.code()) as u64)
+}
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => Self::ENOENT,
+            FsError::AlreadyExists => Self::EEXIST,
+            FsError::NotDirectory => Self::ENOTDIR,
+            FsError::IsDirectory => Self::EISDIR,
+            FsError::NotEmpty => Self::ENOTEMPTY,
+            FsError::PermissionDenied => Self::EACCES,
+            FsError::Busy => Self::EBUSY,
+            FsError::InvalidArgument => Self::EINVAL,
+            FsError::NoSpace => Self::ENOSPC,
+            FsError::FileTooLarge { .. } => Self::EFBIG,
+            FsError::NameTooLong { .. } => Self::ENAMETOOLONG,
+            FsError::BadDescriptor => Self::EBADF,
+            FsError::TooManyFiles => Self::EMFILE,
+            FsError::NotSeekable => Self::ESPIPE,
+            FsError::WouldBlock => Self::EAGAIN,
+            FsError::ReadOnly => Self::EROFS,
+            FsError::CrossDevice => Self::EXDEV,
+            FsError::Io => Self::EIO,
+            FsError::Net(err) => err.into(),
+        }
+    }
+}
+
+impl From<NetError> for Errno {
+    fn from(err: NetError) -> Self {
+        match err {
+            NetError::AddressInUse => Self::EADDRINUSE,
+            NetError::AddressNotAvailable => Self::EADDRNOTAVAIL,
+            NetError::ConnectionRefused => Self::ECONNREFUSED,
+            NetError::NotConnected => Self::ENOTCONN,
+            NetError::AlreadyConnected => Self::EISCONN,
+            NetError::BrokenPipe => Self::EPIPE,
+            NetError::DestinationRequired => Self::EDESTADDRREQ,
+            NetError::MessageTooLong { .. } => Self::EMSGSIZE,
+            NetError::WrongType => Self::EPROTOTYPE,
+            NetError::NotSupported => Self::EOPNOTSUPP,
+            NetError::NotSocket => Self::ENOTSOCK,
+            NetError::NetworkUnreachable => Self::ENETUNREACH,
+            NetError::NetworkDown => Self::ENETDOWN,
+            NetError::NoBuffers => Self::ENOBUFS,
+        }
+    }
+}
+
+impl From<MemoryError> for Errno {
+    fn from(err: MemoryError) -> Self {
+        match err {
+            MemoryError::OutOfMemory | MemoryError::AddressSpaceLimit { .. } => Self::ENOMEM,
+            MemoryError::AlreadyMapped { .. } => Self::EEXIST,
+            MemoryError::AccessViolation { .. } => Self::EFAULT,
+            _ => Self::EINVAL,
+        }
+    }
+}
+
+impl From<SpawnError> for Errno {
+    fn from(err: SpawnError) -> Self {
+        match err {
+            SpawnError::NoFreePid => Self::EAGAIN,
+            SpawnError::Memory(err) => err.into(),
+            SpawnError::Stack(_) => Self::E2BIG,
+        }
+    }
+}
+
+impl From<PriorityError> for Errno {
+    fn from(err: PriorityError) -> Self {
+        match err {
+            PriorityError::NoSuchProcess { .. } => Self::ESRCH,
+            PriorityError::PermissionDenied { .. } => Self::EACCES,
+        }
+    }
+}
+
+impl From<AffinityError> for Errno {
+    fn from(err: AffinityError) -> Self {
+        match err {
+            AffinityError::NoSuchProcess { .. } => Self::ESRCH,
+            AffinityError::NoOnlineCpu { .. } => Self::EINVAL,
+        }
+    }
+}
+
+impl From<KillError> for Errno {
+    fn from(err: KillError) -> Self {
+        match err {
+            KillError::NoSuchProcess { .. } => Self::ESRCH,
+        }
+    }
+}
+
+impl From<WaitError> for Errno {
+    fn from(err: WaitError) -> Self {
+        match err {
+            WaitError::NoChildren => Self::ECHILD,
+        }
+    }
+}
+
+impl From<LimitError> for Errno {
+    fn from(err: LimitError) -> Self {
+        match err {
+            LimitError::InvalidResource { .. }
+            | LimitError::SoftAboveHard { .. }
+            | LimitError::StackPinned { .. } => Self::EINVAL,
+            LimitError::HardLimitRaise { .. } => Self::EPERM,
+            LimitError::NoSuchProcess { .. } => Self::ESRCH,
+        }
+    }
+}
+
+impl From<SeccompError> for Errno {
+    fn from(err: SeccompError) -> Self {
+        match err {
+            SeccompError::InvalidLength { .. }
+            | SeccompError::InvalidInstruction { .. }
+            | SeccompError::ModeChange => Self::EINVAL,
+            SeccompError::OutOfSpace { .. } => Self::ENOMEM,
+        }
+    }
+}
@@ -1,20 +1,122 @@
-use core::arch::{asm, global_asm};
+use core::arch::global_asm;
+use core::time::Duration;
 
-use crate::{console, memory::errors::MemoryError, process};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use spin::Mutex;
 
+use super::errno::{
+    self,
+    Errno::{self, *},
+    SyscallResult,
+};
 use super::{
-    MAP_ANONYMOUS, MAP_PRIVATE, MAP_SHARED, SYS_BRK, SYS_EXIT, SYS_EXIT_GROUP, SYS_GETPID,
-    SYS_MMAP, SYS_SCHED_YIELD, SYS_WRITE,
+    AF_INET, AF_UNIX, ANON_INODE_FS_MAGIC, AT_EACCESS, AT_FDCWD, AT_SYMLINK_NOFOLLOW, B38400,
+    CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_REALTIME, CREAD, CS8, DEVPTS_SUPER_MAGIC, ECHO, ECHOE,
+    ECHOK, EFD_CLOEXEC, EFD_NONBLOCK, EFD_SEMAPHORE, EPOLL_CLOEXEC, EPOLL_CTL_ADD, EPOLL_CTL_DEL,
+    EPOLL_CTL_MOD, EPOLLERR, EPOLLET, EPOLLHUP, EPOLLIN, EPOLLONESHOT, EPOLLOUT, EPOLLRDNORM,
+    EPOLLWRNORM, EpollEvent, F_OK, FD_SETSIZE, FdSet, GRND_INSECURE, GRND_NONBLOCK, GRND_RANDOM,
+    ICANON, ICRNL, IEXTEN, IPPROTO_TCP, ISIG, IXON, Iovec, Itimerspec, MAP_ANONYMOUS, MAP_FIXED,
+    MAP_FIXED_NOREPLACE, MAP_GROWSDOWN, MAP_NORESERVE, MAP_POPULATE, MAP_PRIVATE, MAP_SHARED,
+    MAP_SHARED_VALIDATE, MAP_STACK, MSG_DONTWAIT, MSG_OOB, MSG_TRUNC, Msghdr, NCCS, O_ACCMODE,
+    O_APPEND, O_CREAT, O_DIRECTORY, O_EXCL, O_NONBLOCK, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, ONLCR,
+    OPOST, POLLERR, POLLHUP, POLLIN, POLLNVAL, POLLOUT, POLLRDNORM, POLLWRNORM, PRIO_PROCESS,
+    PROC_SUPER_MAGIC, PROT_EXEC, PROT_READ, PROT_WRITE, PollFd, R_OK, RAMFS_MAGIC, RUSAGE_CHILDREN,
+    RUSAGE_SELF, RUSAGE_THREAD, Rusage, SEEK_CUR, SEEK_END, SEEK_SET, SOCK_CLOEXEC, SOCK_DGRAM,
+    SOCK_NONBLOCK, SOCK_STREAM, SOCK_TYPE_MASK, SOCKFS_MAGIC, SYS_ACCEPT, SYS_ACCEPT4, SYS_ACCESS,
+    SYS_BIND, SYS_BRK, SYS_CHDIR, SYS_CLOCK_GETTIME, SYS_CLOSE, SYS_CONNECT, SYS_EPOLL_CREATE,
+    SYS_EPOLL_CREATE1, SYS_EPOLL_CTL, SYS_EPOLL_PWAIT, SYS_EPOLL_WAIT, SYS_EVENTFD, SYS_EVENTFD2,
+    SYS_EXIT, SYS_EXIT_GROUP, SYS_FACCESSAT, SYS_FSTATFS, SYS_FTRUNCATE, SYS_GETCWD, SYS_GETEGID,
+    SYS_GETEUID, SYS_GETGID, SYS_GETGROUPS, SYS_GETPID, SYS_GETPPID, SYS_GETPRIORITY,
+    SYS_GETRANDOM, SYS_GETRLIMIT, SYS_GETRUSAGE, SYS_GETUID, SYS_IOCTL, SYS_KILL, SYS_LISTEN,
+    SYS_LSEEK, SYS_MKDIR, SYS_MMAP, SYS_OPEN, SYS_OPENAT, SYS_POLL, SYS_PRLIMIT64, SYS_READ,
+    SYS_READLINK, SYS_READLINKAT, SYS_RECVFROM, SYS_RECVMSG, SYS_RENAME, SYS_RMDIR,
+    SYS_SCHED_GETAFFINITY, SYS_SCHED_SETAFFINITY, SYS_SCHED_YIELD, SYS_SECCOMP, SYS_SELECT,
+    SYS_SENDFILE, SYS_SENDMSG, SYS_SENDTO, SYS_SETPRIORITY, SYS_SETRLIMIT, SYS_SOCKET, SYS_STATFS,
+    SYS_TIMERFD_CREATE, SYS_TIMERFD_GETTIME, SYS_TIMERFD_SETTIME, SYS_TIMES, SYS_TRUNCATE,
+    SYS_UMASK, SYS_UNAME, SYS_UNLINK, SYS_WAIT4, SYS_WRITE, SockaddrIn, SockaddrUn, Statfs, TCGETS,
+    TFD_CLOEXEC, TFD_NONBLOCK, TFD_TIMER_ABSTIME, TFD_TIMER_CANCEL_ON_SET, TIOCGWINSZ, Termios,
+    Timespec, Timeval, Tms, UTSNAME_FIELD_LEN, Utsname, V9FS_MAGIC, W_OK, WNOHANG, Winsize, X_OK,
+};
+use crate::{
+    Kernel,
+    arch::{
+        RFLAGS_IF,
+        gdt::{KERNEL_CS, USER_SS},
+        percpu, rdmsr, wrmsr,
+    },
+    console, credentials,
+    fs::{
+        self, FsStats, OpenOptions, Whence,
+        epoll::{self, Watch},
+        errors::FsError,
+        fd::{FdTable, FileKind, OpenFile},
+        path::{PATH_MAX, Path},
+        timerfd::{self, TimerSetting},
+    },
+    limits::Rlimit,
+    memory::address::DirectMap,
+    net::{
+        errors::NetError,
+        inet::{self, InetStack},
+        unix::{self, Address, SocketTable, SocketType},
+    },
+    process::{self, CpuTime, ExitStatus},
+    random,
+    seccomp::{
+        self, AUDIT_ARCH_X86_64, SECCOMP_GET_ACTION_AVAIL, SECCOMP_SET_MODE_FILTER,
+        SECCOMP_SET_MODE_STRICT, SeccompData, SockFilter, SockFprog, Verdict,
+    },
+    time,
+    wait::WaitQueue,
 };
 
-const STDOUT_FD: u64 = 1;
-const STDERR_FD: u64 = 2;
+// Linux refuses longer iovec arrays with EMSGSIZE.
+const UIO_MAXIOV: usize = 1024;
+const SUN_PATH_OFFSET: usize = core::mem::offset_of!(SockaddrUn, sun_path);
+
+// Syscalls run on a small kernel stack, so vectored socket I/O is staged here.
+static MESSAGE_BUFFER: Mutex<[u8; unix::BUFFER_SIZE]> = Mutex::new([0; unix::BUFFER_SIZE]);
+
+// Anonymous pages are faulted in on first touch unless MAP_POPULATE asks for
+// them up front. MAP_STACK changes nothing, and a MAP_GROWSDOWN mapping stays
+// at the size it was created with.
+const MMAP_KNOWN_FLAGS: u64 = MAP_SHARED_VALIDATE
+    | MAP_FIXED
+    | MAP_ANONYMOUS
+    | MAP_GROWSDOWN
+    | MAP_NORESERVE
+    | MAP_POPULATE
+    | MAP_STACK
+    | MAP_FIXED_NOREPLACE;
+const MMAP_KNOWN_PROT: u64 = PROT_READ | PROT_WRITE | PROT_EXEC;
+
+// sendfile copies through the kernel stack in chunks of this size.
+const SENDFILE_CHUNK: usize = 4096;
+
+// Linux caps a single getrandom call at 32 MiB - 1 bytes.
+const GETRANDOM_MAX: u64 = (1 << 25) - 1;
+
+const UTS_SYSNAME: &str = "Hostel";
+const UTS_NODENAME: &str = "hostel";
+const UTS_RELEASE: &str = env!("CARGO_PKG_VERSION");
+const UTS_VERSION: &str = concat!("#1 hostel ", env!("CARGO_PKG_VERSION"));
+const UTS_MACHINE: &str = "x86_64";
+const UTS_DOMAINNAME: &str = "(none)";
+
+// Cooked mode on a 38400 baud 8N1 line, as `stty sane` leaves it.
+const CONSOLE_TERMIOS: Termios = Termios {
+    c_iflag: ICRNL | IXON,
+    c_oflag: OPOST | ONLCR,
+    c_cflag: B38400 | CS8 | CREAD,
+    c_lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | IEXTEN,
+    c_line: 0,
+    c_cc: CONSOLE_CONTROL_CHARS,
+};
 
-const EBADF: i64 = 9;
-const EFAULT: i64 = 14;
-const EINVAL: i64 = 22;
-const ENOMEM: i64 = 12;
-const ENOSYS: i64 = 38;
+// ^C ^\ DEL ^U ^D, VTIME 0, VMIN 1, then ^Q ^S ^Z ^R ^O ^W ^V.
+const CONSOLE_CONTROL_CHARS: [u8; NCCS] = [
+    0x03, 0x1c, 0x7f, 0x15, 0x04, 0, 1, 0, 0x11, 0x13, 0x1a, 0, 0x12, 0x0f, 0x17, 0x16, 0, 0, 0,
+];
 
 const IA32_STAR: u32 = 0xC000_0081;
 const IA32_LSTAR: u32 = 0xC000_0082;
@@ -22,19 +124,26 @@ const IA32_FMASK: u32 = 0xC000_0084;
 const IA32_EFER: u32 = 0xC000_0080;
 const EFER_SCE: u64 = 1 << 0;
 
-// These selectors match VM x86 setup in src/vm/x64.rs.
-const KERNEL_CS_SELECTOR: u64 = 0x8;
-const USER_CS_SELECTOR: u64 = 0x1b;
-
-#[inline]
-const fn errno(code: i64) -> u64 {
-    (-code) as u64
-}
-
 global_asm!(
     r#"
     .global __syscall_entry
 __syscall_entry:
+    // Interrupts are masked, so nothing else can use the scratch slot or
+    // switch processes before the caller's RSP is saved on the new stack.
+    // The per-CPU area is only swapped in for that long; the caller's GS
+    // base is back before any Rust code runs.
+    swapgs
+    mov gs:[{scratch_rsp}], rsp
+    cmp qword ptr gs:[{kernel_stack}], 0
+    je 1f
+    mov rsp, gs:[{kernel_stack}]
+1:
+    // Nine 8-byte slots follow, so pad to keep the call below aligned.
+    and rsp, -16
+    sub rsp, 8
+    push qword ptr gs:[{scratch_rsp}]
+    swapgs
+
     // syscall saved return RIP -> RCX, old RFLAGS -> R11.
     push rcx
     push r11
@@ -66,12 +175,15 @@ __syscall_entry:
     add rsp, 40
     pop r11
     pop rcx
+    pop rsp
 
     // Return to the original CPL0 caller without SYSRET.
     push r11
     popfq
     jmp rcx
-"#
+"#,
+    scratch_rsp = const percpu::SCRATCH_RSP_OFFSET,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
 );
 
 unsafe extern "C" {
@@ -84,10 +196,14 @@ pub(super) fn install() {
     wrmsr(IA32_EFER, efer);
 
     // STAR layout for SYSCALL/SYSRET. We only use SYSCALL path in ring0.
-    let star = (KERNEL_CS_SELECTOR << 32) | (USER_CS_SELECTOR << 48);
+    // SYSCALL loads CS from the low selector and SS from 8 above it; SYSRET
+    // loads SS from 8 above the high one and CS from 16 above it.
+    let star = (u64::from(KERNEL_CS) << 32) | (u64::from(USER_SS - 8) << 48);
     wrmsr(IA32_STAR, star);
     wrmsr(IA32_LSTAR, __syscall_entry as *const () as usize as u64);
-    wrmsr(IA32_FMASK, 0);
+    // Syscalls run with interrupts masked, so the timer never preempts a
+    // process while it is inside the kernel.
+    wrmsr(IA32_FMASK, RFLAGS_IF);
 }
 
 #[unsafe(no_mangle)]
@@ -100,139 +216,2037 @@ extern "C" fn __syscall_dispatch(
     arg4: u64,
     arg5: u64,
 ) -> u64 {
+    // The entry stub does not pass the return address down, so filters see a
+    // zero instruction pointer.
+    if let Some(kernel) = crate::try_active_kernel() {
+        let data = SeccompData {
+            nr: nr as i32,
+            arch: AUDIT_ARCH_X86_64,
+            instruction_pointer: 0,
+            args: [arg0, arg1, arg2, arg3, arg4, arg5],
+        };
+        match process::seccomp_verdict(kernel, &data) {
+            Verdict::Allow => {}
+            // Filters may pick any errno, not just the ones handlers use.
+            Verdict::Errno(code) => return (-(code as i64)) as u64,
+            Verdict::Kill => {
+                process::terminate_current(kernel, ExitStatus::Killed(process::SIGSYS))
+            }
+        }
+    }
+
+    if let Some(kernel) = crate::try_active_kernel() {
+        process::set_in_syscall(kernel, true);
+    }
+    let result = dispatch(nr, arg0, arg1, arg2, arg3, arg4, arg5);
+    // Any syscall may have made a descriptor ready, so whoever sleeps in
+    // `block_current` gets to check again.
+    if let Some(kernel) = crate::try_active_kernel() {
+        IO_WAIT.wake_all(kernel);
+        process::set_in_syscall(kernel, false);
+    }
+    errno::into_raw(result)
+}
+
+fn dispatch(
+    nr: u64,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> SyscallResult {
     match nr {
+        SYS_READ => sys_read(arg0, arg1, arg2),
         SYS_WRITE => sys_write(arg0, arg1, arg2),
+        SYS_OPEN => sys_openat(AT_FDCWD, arg0, arg1, arg2),
+        SYS_OPENAT => sys_openat(arg0 as i64, arg1, arg2, arg3),
+        SYS_CLOSE => sys_close(arg0),
+        SYS_LSEEK => sys_lseek(arg0, arg1 as i64, arg2),
+        SYS_POLL => sys_poll(arg0, arg1, arg2 as i32),
+        SYS_SELECT => sys_select(arg0 as i32, arg1, arg2, arg3, arg4),
+        SYS_EPOLL_CREATE => sys_epoll_create(arg0 as i32),
+        SYS_EPOLL_CREATE1 => sys_epoll_create1(arg0),
+        SYS_EPOLL_CTL => sys_epoll_ctl(arg0, arg1, arg2, arg3),
+        // There are no signals to mask, so epoll_pwait is plain epoll_wait.
+        SYS_EPOLL_WAIT | SYS_EPOLL_PWAIT => sys_epoll_wait(arg0, arg1, arg2 as i32, arg3 as i32),
+        SYS_EVENTFD => sys_eventfd2(arg0 as u32, 0),
+        SYS_EVENTFD2 => sys_eventfd2(arg0 as u32, arg1),
+        SYS_CLOCK_GETTIME => sys_clock_gettime(arg0, arg1),
+        SYS_TIMERFD_CREATE => sys_timerfd_create(arg0, arg1),
+        SYS_TIMERFD_SETTIME => sys_timerfd_settime(arg0, arg1, arg2, arg3),
+        SYS_TIMERFD_GETTIME => sys_timerfd_gettime(arg0, arg1),
+        SYS_SENDFILE => sys_sendfile(arg0, arg1, arg2, arg3),
+        SYS_SOCKET => sys_socket(arg0, arg1, arg2),
+        SYS_BIND => sys_bind(arg0, arg1, arg2),
+        SYS_LISTEN => sys_listen(arg0, arg1 as i32),
+        SYS_CONNECT => sys_connect(arg0, arg1, arg2),
+        SYS_ACCEPT => sys_accept4(arg0, arg1, arg2, 0),
+        SYS_ACCEPT4 => sys_accept4(arg0, arg1, arg2, arg3),
+        SYS_SENDTO => sys_sendto(arg0, arg1, arg2, arg3, arg4, arg5),
+        SYS_RECVFROM => sys_recvfrom(arg0, arg1, arg2, arg3, arg4, arg5),
+        SYS_SENDMSG => sys_sendmsg(arg0, arg1, arg2),
+        SYS_RECVMSG => sys_recvmsg(arg0, arg1, arg2),
+        SYS_IOCTL => sys_ioctl(arg0, arg1, arg2),
         SYS_BRK => sys_brk(arg0),
         SYS_MMAP => sys_mmap(arg0, arg1, arg2, arg3, arg4 as i64, arg5),
-        SYS_GETPID => process::current_pid(crate::active_kernel()) as u64,
+        SYS_UNAME => sys_uname(arg0),
+        SYS_GETCWD => sys_getcwd(arg0, arg1),
+        SYS_CHDIR => sys_chdir(arg0),
+        SYS_MKDIR => sys_mkdir(arg0, arg1),
+        SYS_RMDIR => sys_path_op(arg0, |path| fs::rmdir(crate::active_kernel(), path)),
+        SYS_UNLINK => sys_path_op(arg0, |path| fs::unlink(crate::active_kernel(), path)),
+        SYS_STATFS => sys_statfs(arg0, arg1),
+        SYS_FSTATFS => sys_fstatfs(arg0, arg1),
+        SYS_TRUNCATE => sys_truncate(arg0, arg1 as i64),
+        SYS_FTRUNCATE => sys_ftruncate(arg0, arg1 as i64),
+        SYS_RENAME => sys_rename(arg0, arg1),
+        SYS_ACCESS => sys_faccessat(AT_FDCWD, arg0, arg1, 0),
+        SYS_FACCESSAT => sys_faccessat(arg0 as i64, arg1, arg2, arg3),
+        SYS_READLINK => sys_readlinkat(AT_FDCWD, arg0, arg1, arg2),
+        SYS_READLINKAT => sys_readlinkat(arg0 as i64, arg1, arg2, arg3),
+        SYS_UMASK => Ok(process::set_umask(crate::active_kernel(), arg0 as u32) as u64),
+        SYS_GETPID => Ok(process::current_pid(crate::active_kernel()) as u64),
+        SYS_GETPPID => Ok(process::parent_pid(crate::active_kernel()) as u64),
+        SYS_GETUID | SYS_GETEUID => Ok(credentials::current().uid as u64),
+        SYS_GETGID | SYS_GETEGID => Ok(credentials::current().gid as u64),
+        SYS_GETGROUPS => sys_getgroups(arg0, arg1),
+        SYS_GETRANDOM => sys_getrandom(arg0, arg1, arg2),
+        SYS_SECCOMP => sys_seccomp(arg0 as u32, arg1 as u32, arg2),
+        SYS_GETRLIMIT => sys_prlimit64(0, arg0, 0, arg1),
+        SYS_SETRLIMIT => sys_prlimit64(0, arg0, arg1, 0),
+        SYS_PRLIMIT64 => sys_prlimit64(arg0, arg1, arg2, arg3),
+        SYS_WAIT4 => sys_wait4(arg0 as i64, arg1, arg2, arg3),
+        SYS_GETRUSAGE => sys_getrusage(arg0 as i64, arg1),
+        SYS_TIMES => sys_times(arg0),
+        SYS_GETPRIORITY => sys_getpriority(arg0, arg1),
+        SYS_SETPRIORITY => sys_setpriority(arg0, arg1, arg2 as i32),
+        SYS_SCHED_SETAFFINITY => sys_sched_setaffinity(arg0, arg1, arg2),
+        SYS_SCHED_GETAFFINITY => sys_sched_getaffinity(arg0, arg1, arg2),
+        SYS_KILL => sys_kill(arg0 as i64, arg1),
         SYS_SCHED_YIELD => {
             process::yield_now(crate::active_kernel());
-            0
+            Ok(0)
         }
+        // Only the low byte of the status reaches the parent, as on Linux.
         SYS_EXIT | SYS_EXIT_GROUP => {
-            let _status = arg0 as i32;
-            process::terminate_current(crate::active_kernel())
+            process::terminate_current(crate::active_kernel(), ExitStatus::Exited(arg0 as u8))
+        }
+        _ => Err(ENOSYS),
+    }
+}
+
+fn sys_write(fd: u64, ptr: u64, len: u64) -> SyscallResult {
+    let bytes = fd_buffer(fd, ptr, len)?;
+    let written = blocking_io(fd, false, |file| {
+        fs::write(crate::active_kernel(), file, bytes)
+    })?;
+    Ok(written as u64)
+}
+
+fn sys_read(fd: u64, ptr: u64, len: u64) -> SyscallResult {
+    let buf = fd_buffer(fd, ptr, len)?;
+    let read = blocking_io(fd, false, |file| {
+        fs::read(crate::active_kernel(), file, buf)
+    })?;
+    Ok(read as u64)
+}
+
+// Copy from `in_fd` to `out_fd` without going through user memory. With
+// `offset_ptr` set the input is read from `*offset_ptr`, which is advanced
+// instead of the file offset. Only the first read waits for data; after
+// that a short count is returned whenever the input runs dry.
+fn sys_sendfile(out_fd: u64, in_fd: u64, offset_ptr: u64, count: u64) -> SyscallResult {
+    sendfile(out_fd, in_fd, offset_ptr, count).map(|sent| sent as u64)
+}
+
+fn sendfile(out_fd: u64, in_fd: u64, offset_ptr: u64, count: u64) -> SyscallResult<usize> {
+    let mut input = with_fd(in_fd, |file| Ok(*file))?;
+    let output = with_fd(out_fd, |file| Ok(*file))?;
+    if !input.readable || !output.writable {
+        return Err(EBADF);
+    }
+    if offset_ptr != 0 {
+        if !input.kind.is_file() {
+            return Err(ESPIPE);
+        }
+        let offset = unsafe { core::ptr::read_unaligned(offset_ptr as *const i64) };
+        input.offset = usize::try_from(offset).map_err(|_| EINVAL)?;
+    }
+
+    let kernel = crate::active_kernel();
+    let count = usize::try_from(count).unwrap_or(usize::MAX);
+    let mut buf = [0u8; SENDFILE_CHUNK];
+    let mut sent = 0;
+    let mut failure = None;
+    while sent < count {
+        let chunk = (count - sent).min(buf.len());
+        let read = match fs::read(kernel, &mut input, &mut buf[..chunk]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(FsError::WouldBlock) if sent == 0 && !input.nonblocking => {
+                block_current(None);
+                continue;
+            }
+            Err(FsError::WouldBlock) if sent > 0 => break,
+            Err(err) => {
+                failure = Some(err.into());
+                break;
+            }
+        };
+
+        let mut written = 0;
+        while written < read {
+            match blocking_io(out_fd, false, |file| {
+                fs::write(kernel, file, &buf[written..read])
+            }) {
+                Ok(len) => written += len,
+                Err(code) => {
+                    failure = Some(code);
+                    break;
+                }
+            }
+        }
+        sent += written;
+        if written < read {
+            // Leave what could not be written for the next read. Data taken
+            // from anything but a file is lost, as on Linux.
+            if input.kind.is_file() {
+                input.offset -= read - written;
+            }
+            break;
         }
-        _ => errno(ENOSYS),
+    }
+
+    if offset_ptr != 0 {
+        unsafe { core::ptr::write_unaligned(offset_ptr as *mut i64, input.offset as i64) };
+    } else {
+        with_fd(in_fd, |file| {
+            file.offset = input.offset;
+            Ok(())
+        })?;
+    }
+    match failure {
+        Some(code) if sent == 0 => Err(code),
+        _ => Ok(sent),
     }
 }
 
-fn sys_write(fd: u64, ptr: u64, len: u64) -> u64 {
-    if fd != STDOUT_FD && fd != STDERR_FD {
-        return errno(EBADF);
+// Retry until the operation stops reporting WouldBlock, unless the
+// descriptor is non-blocking or the caller asked not to wait, in which case
+// the caller gets EAGAIN.
+fn blocking_io<T>(
+    fd: u64,
+    dontwait: bool,
+    mut op: impl FnMut(&mut OpenFile) -> fs::errors::Result<T>,
+) -> SyscallResult<T> {
+    loop {
+        let attempt = with_fd(fd, |file| match op(file) {
+            Err(FsError::WouldBlock) if !(file.nonblocking || dontwait) => Ok(None),
+            result => result.map(Some),
+        })?;
+        match attempt {
+            Some(done) => return Ok(done),
+            None => block_current(None),
+        }
     }
+}
+
+// Validate the descriptor before the buffer so a bad fd wins over a bad
+// pointer, as on Linux.
+fn fd_buffer<'a>(fd: u64, ptr: u64, len: u64) -> SyscallResult<&'a mut [u8]> {
+    with_fd(fd, |_| Ok(()))?;
     if len == 0 {
-        return 0;
+        return Ok(&mut []);
     }
     if ptr == 0 {
-        return errno(EFAULT);
+        return Err(EFAULT);
     }
+    let len = usize::try_from(len).map_err(|_| EINVAL)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len) })
+}
 
-    let Ok(len) = usize::try_from(len) else {
-        return errno(EINVAL);
+// Outside a process (early boot, host unit tests) only the console
+// descriptors exist.
+fn with_fd<T>(
+    fd: u64,
+    op: impl FnOnce(&mut OpenFile) -> fs::errors::Result<T>,
+) -> SyscallResult<T> {
+    let fd = usize::try_from(fd).map_err(|_| EBADF)?;
+    let mut op = Some(op);
+    let mut run = |files: &mut FdTable| {
+        let file = files.get_mut(fd)?;
+        (op.take().expect("descriptor op runs once"))(file)
     };
 
-    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len) };
-    console::write_bytes(bytes);
-    len as u64
+    let in_process =
+        crate::try_active_kernel().and_then(|kernel| process::with_files(kernel, &mut run));
+    let result = match in_process {
+        Some(result) => result,
+        None => run(&mut FdTable::with_console()),
+    };
+    result.map_err(Errno::from)
+}
+
+fn sys_openat(dirfd: i64, ptr: u64, flags: u64, mode: u64) -> SyscallResult {
+    let kernel = crate::active_kernel();
+    let options = open_options(flags, mode)?;
+    let options = OpenOptions {
+        mode: options.mode & !process::umask(kernel),
+        ..options
+    };
+
+    let path = resolve_user_path_at(dirfd, ptr)?;
+    let file = fs::open(kernel, &path, &options)?;
+    install_file(file).map(|fd| fd as u64)
+}
+
+// Give `file` a descriptor in the calling process, closing it again if the
+// table is full.
+fn install_file(file: OpenFile) -> SyscallResult<usize> {
+    let kernel = crate::active_kernel();
+    process::with_files(kernel, |files| files.install(file))
+        .expect("installing a descriptor requires a running process")
+        .map_err(|err| {
+            // The full table is the error worth reporting.
+            let _ = fs::close(kernel, file);
+            err.into()
+        })
+}
+
+fn open_options(flags: u64, mode: u64) -> SyscallResult<OpenOptions> {
+    let (read, write) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(EINVAL),
+    };
+    Ok(OpenOptions {
+        read,
+        write,
+        create: flags & O_CREAT != 0,
+        exclusive: flags & O_EXCL != 0,
+        truncate: flags & O_TRUNC != 0,
+        append: flags & O_APPEND != 0,
+        nonblocking: flags & O_NONBLOCK != 0,
+        directory: flags & O_DIRECTORY != 0,
+        mode: mode as u32,
+    })
 }
 
-fn sys_brk(addr: u64) -> u64 {
-    match process::brk(crate::active_kernel(), addr as usize) {
-        Ok(cur) => cur as u64,
-        Err(err) => errno(memory_errno(err)),
+fn sys_close(fd: u64) -> SyscallResult {
+    let Ok(fd) = usize::try_from(fd) else {
+        return Err(EBADF);
+    };
+    let kernel = crate::active_kernel();
+    match process::with_files(kernel, |files| files.remove(fd)) {
+        Some(Ok(file)) => {
+            forget_epoll_watches(fd);
+            fs::close(kernel, file)?;
+            Ok(0)
+        }
+        Some(Err(err)) => Err(err.into()),
+        None => Err(EBADF),
     }
 }
 
-fn sys_mmap(addr: u64, len: u64, _prot: u64, flags: u64, fd: i64, offset: u64) -> u64 {
-    let Ok(len) = usize::try_from(len) else {
-        return errno(EINVAL);
+// A closed descriptor drops off every interest list of the process, so a
+// later file that reuses the number is not watched by accident.
+fn forget_epoll_watches(fd: usize) {
+    process::with_files(crate::active_kernel(), |files| {
+        for (_, file) in files.iter() {
+            if let FileKind::Epoll(id) = file.kind {
+                let _ = epoll::with_instances(|instances| instances.remove(id, fd));
+            }
+        }
+    });
+}
+
+fn sys_lseek(fd: u64, offset: i64, whence: u64) -> SyscallResult {
+    let whence = match whence {
+        SEEK_SET => Whence::Set,
+        SEEK_CUR => Whence::Current,
+        SEEK_END => Whence::End,
+        _ => return Err(EINVAL),
     };
-    if len == 0 {
-        return errno(EINVAL);
+    with_fd(fd, |file| fs::seek(file, offset, whence)).map(|offset| offset as u64)
+}
+
+fn sys_poll(ptr: u64, nfds: u64, timeout_ms: i32) -> SyscallResult {
+    let Ok(nfds) = usize::try_from(nfds) else {
+        return Err(EINVAL);
+    };
+    if nfds > 0 && ptr == 0 {
+        return Err(EFAULT);
+    }
+
+    let fds = ptr as *mut PollFd;
+    Ok(wait_ready(poll_deadline(timeout_ms), || {
+        let mut ready = 0;
+        for i in 0..nfds {
+            unsafe {
+                let mut pollfd = core::ptr::read_unaligned(fds.add(i));
+                pollfd.revents = poll_revents(&pollfd);
+                if pollfd.revents != 0 {
+                    ready += 1;
+                }
+                core::ptr::write_unaligned(fds.add(i), pollfd);
+            }
+        }
+        ready
+    }))
+}
+
+// A negative timeout waits forever.
+fn poll_deadline(timeout_ms: i32) -> Option<Duration> {
+    u64::try_from(timeout_ms)
+        .ok()
+        .map(|ms| time::monotonic() + Duration::from_millis(ms))
+}
+
+// Negative descriptors are skipped; closed ones report POLLNVAL instead of
+// failing the whole call. Errors and hangups are reported unrequested.
+fn poll_revents(pollfd: &PollFd) -> i16 {
+    if pollfd.fd < 0 {
+        return 0;
     }
-    if offset != 0 {
-        return errno(EINVAL);
+    match with_fd(pollfd.fd as u64, |file| Ok(fs::poll(file))) {
+        Ok(readiness) => {
+            let mut revents = 0;
+            if readiness.readable {
+                revents |= POLLIN | POLLRDNORM;
+            }
+            if readiness.writable {
+                revents |= POLLOUT | POLLWRNORM;
+            }
+            revents & (pollfd.events | POLLERR | POLLHUP)
+        }
+        Err(_) => POLLNVAL,
     }
+}
 
-    let sharing = flags & (MAP_PRIVATE | MAP_SHARED);
-    if sharing == 0 {
-        return errno(EINVAL);
+fn sys_select(
+    nfds: i32,
+    readfds: u64,
+    writefds: u64,
+    exceptfds: u64,
+    timeout_ptr: u64,
+) -> SyscallResult {
+    let nfds = match usize::try_from(nfds) {
+        Ok(nfds) if nfds <= FD_SETSIZE => nfds,
+        _ => return Err(EINVAL),
+    };
+    let deadline = if timeout_ptr == 0 {
+        None
+    } else {
+        let timeout = unsafe { core::ptr::read_unaligned(timeout_ptr as *const Timeval) };
+        let (Ok(secs), Ok(micros)) = (
+            u64::try_from(timeout.tv_sec),
+            u32::try_from(timeout.tv_usec),
+        ) else {
+            return Err(EINVAL);
+        };
+        if micros >= 1_000_000 {
+            return Err(EINVAL);
+        }
+        Some(time::monotonic() + Duration::new(secs, micros * 1_000))
+    };
+
+    let read_set =
+        |ptr: u64| (ptr != 0).then(|| unsafe { core::ptr::read_unaligned(ptr as *const FdSet) });
+    let wanted = [read_set(readfds), read_set(writefds), read_set(exceptfds)];
+    let requested = |fd: usize| wanted.iter().flatten().any(|set| set.contains(fd));
+    // Every requested descriptor has to be open before anything waits.
+    if (0..nfds).any(|fd| requested(fd) && with_fd(fd as u64, |_| Ok(())).is_err()) {
+        return Err(EBADF);
     }
-    if (flags & MAP_ANONYMOUS) == 0 {
-        return errno(ENOSYS);
+
+    let mut result = [FdSet::new(); 3];
+    let ready = wait_ready(deadline, || {
+        result = [FdSet::new(); 3];
+        let mut ready = 0;
+        for fd in (0..nfds).filter(|&fd| requested(fd)) {
+            let Ok(readiness) = with_fd(fd as u64, |file| Ok(fs::poll(file))) else {
+                continue;
+            };
+            for (set, is_ready) in [(0, readiness.readable), (1, readiness.writable)] {
+                if is_ready && wanted[set].is_some_and(|wanted| wanted.contains(fd)) {
+                    result[set].insert(fd);
+                    ready += 1;
+                }
+            }
+        }
+        ready
+    });
+
+    for (ptr, set) in [readfds, writefds, exceptfds].into_iter().zip(result) {
+        if ptr != 0 {
+            unsafe { core::ptr::write_unaligned(ptr as *mut FdSet, set) };
+        }
     }
-    if fd != -1 {
-        return errno(EINVAL);
+    // Like Linux, report how much of the timeout is left.
+    if let Some(deadline) = deadline {
+        let left = deadline.saturating_sub(time::monotonic());
+        let left = Timeval {
+            tv_sec: left.as_secs() as i64,
+            tv_usec: left.subsec_micros() as i64,
+        };
+        unsafe { core::ptr::write_unaligned(timeout_ptr as *mut Timeval, left) };
     }
+    Ok(ready)
+}
 
-    match process::mmap(crate::active_kernel(), addr as usize, len, flags) {
-        Ok(mapped) => mapped as u64,
-        Err(err) => errno(memory_errno(err)),
+fn sys_epoll_create(size: i32) -> SyscallResult {
+    if size <= 0 {
+        return Err(EINVAL);
     }
+    sys_epoll_create1(0)
 }
 
-const fn memory_errno(err: MemoryError) -> i64 {
-    match err {
-        MemoryError::OutOfMemory | MemoryError::TooManyLargeAllocations => ENOMEM,
-        MemoryError::AlreadyMapped { .. } => ENOMEM,
-        _ => EINVAL,
+fn sys_epoll_create1(flags: u64) -> SyscallResult {
+    if flags & !EPOLL_CLOEXEC != 0 {
+        return Err(EINVAL);
     }
+    let file = fs::epoll_create()?;
+    install_file(file).map(|fd| fd as u64)
 }
 
-#[inline]
-fn wrmsr(msr: u32, value: u64) {
-    let lo = value as u32;
-    let hi = (value >> 32) as u32;
-    unsafe {
-        asm!(
-            "wrmsr",
-            in("ecx") msr,
-            in("eax") lo,
-            in("edx") hi,
-            options(nostack, preserves_flags),
-        );
+fn sys_epoll_ctl(epfd: u64, op: u64, fd: u64, event_ptr: u64) -> SyscallResult {
+    let id = epoll_instance(epfd)?;
+    let target = with_fd(fd, |file| Ok(file.kind))?;
+    if fd == epfd {
+        return Err(EINVAL);
+    }
+    // Regular files and directories are always ready; Linux refuses to
+    // watch them rather than report them forever.
+    if target.is_file() {
+        return Err(EPERM);
     }
+
+    let fd = fd as usize;
+    let event = match op {
+        EPOLL_CTL_DEL => EpollEvent::default(),
+        _ if event_ptr == 0 => return Err(EFAULT),
+        _ => unsafe { core::ptr::read_unaligned(event_ptr as *const EpollEvent) },
+    };
+    epoll::with_instances(|instances| match op {
+        EPOLL_CTL_ADD => instances.add(id, Watch::new(fd, event.events, event.data)),
+        EPOLL_CTL_MOD => instances.modify(id, fd, event.events, event.data),
+        EPOLL_CTL_DEL => instances.remove(id, fd),
+        _ => Err(FsError::InvalidArgument),
+    })?;
+    Ok(0)
 }
 
-#[inline]
-fn rdmsr(msr: u32) -> u64 {
-    let lo: u32;
-    let hi: u32;
-    unsafe {
-        asm!(
-            "rdmsr",
-            in("ecx") msr,
-            out("eax") lo,
-            out("edx") hi,
-            options(nostack, preserves_flags),
-        );
+fn sys_epoll_wait(epfd: u64, ptr: u64, maxevents: i32, timeout_ms: i32) -> SyscallResult {
+    let maxevents = match usize::try_from(maxevents) {
+        Ok(maxevents) if maxevents > 0 => maxevents,
+        _ => return Err(EINVAL),
+    };
+    let id = epoll_instance(epfd)?;
+    if ptr == 0 {
+        return Err(EFAULT);
     }
-    ((hi as u64) << 32) | lo as u64
+
+    let events = ptr as *mut EpollEvent;
+    Ok(wait_ready(poll_deadline(timeout_ms), || {
+        let mut ready = 0;
+        for event in collect_epoll_events(id).take(maxevents) {
+            unsafe { core::ptr::write_unaligned(events.add(ready), event) };
+            ready += 1;
+        }
+        ready as u64
+    }))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn epoll_instance(epfd: u64) -> SyscallResult<usize> {
+    match with_fd(epfd, |file| Ok(file.kind))? {
+        FileKind::Epoll(id) => Ok(id),
+        _ => Err(EINVAL),
+    }
+}
 
-    #[test]
-    fn unsupported_syscall_returns_enosys() {
-        assert_eq!(__syscall_dispatch(0xdead, 0, 0, 0, 0, 0, 0) as i64, -ENOSYS);
+// Level-triggered watches report whatever is ready; edge-triggered ones only
+// readiness that was not there on the previous scan. One-shot watches are
+// disarmed once they fire until EPOLL_CTL_MOD re-arms them.
+fn collect_epoll_events(id: usize) -> impl Iterator<Item = EpollEvent> {
+    let watches = epoll::with_instances(|instances| instances.watches(id)).unwrap_or_default();
+    watches.into_iter().flatten().filter_map(move |watch| {
+        if watch.events == 0 {
+            return None;
+        }
+        let readiness = with_fd(watch.fd as u64, |file| Ok(fs::poll(file))).ok()?;
+        let mut events = 0;
+        if readiness.readable {
+            events |= EPOLLIN | EPOLLRDNORM;
+        }
+        if readiness.writable {
+            events |= EPOLLOUT | EPOLLWRNORM;
+        }
+        events &= watch.events | EPOLLERR | EPOLLHUP;
+
+        let fresh = if watch.events & EPOLLET != 0 {
+            events & !watch.reported
+        } else {
+            events
+        };
+        epoll::with_instances(|instances| {
+            if let Ok(current) = instances.watch(id, watch.fd) {
+                current.reported = events;
+                if fresh != 0 && watch.events & EPOLLONESHOT != 0 {
+                    current.events = 0;
+                }
+            }
+        });
+        (fresh != 0).then_some(EpollEvent {
+            events: fresh,
+            data: watch.data,
+        })
+    })
+}
+
+// Rescan until something is ready or the deadline passes.
+fn wait_ready(deadline: Option<Duration>, mut scan: impl FnMut() -> u64) -> u64 {
+    loop {
+        let ready = scan();
+        if ready > 0 || deadline.is_some_and(|deadline| time::monotonic() >= deadline) {
+            return ready;
+        }
+        block_current(deadline);
     }
+}
 
-    #[test]
-    fn write_rejects_unknown_fd() {
-        assert_eq!(
-            __syscall_dispatch(SYS_WRITE, 7, 0, 0, 0, 0, 0) as i64,
-            -EBADF
-        );
+// Processes waiting for a descriptor to become ready.
+static IO_WAIT: WaitQueue = WaitQueue::new();
+
+// Sleep until a descriptor may have become ready or `deadline` passes.
+// Every syscall wakes the sleepers on its way out; what happens outside
+// syscalls wakes them through `wake_io_waiters` and `poll_io`.
+fn block_current(deadline: Option<Duration>) {
+    match crate::try_active_kernel() {
+        Some(kernel) => IO_WAIT.sleep(kernel, deadline),
+        None => core::hint::spin_loop(),
     }
+}
 
-    #[test]
-    fn write_rejects_null_pointer_for_non_zero_len() {
+/// Wake processes waiting for a descriptor, for readiness that comes from
+/// outside a syscall, such as console input.
+pub fn wake_io_waiters<DM: DirectMap>(kernel: &Kernel<'_, DM>) {
+    IO_WAIT.wake_all(kernel);
+}
+
+/// Fire due timerfds and move network packets, which nothing else does
+/// between syscalls, and wake the processes waiting for a descriptor if
+/// either made progress. Called on every timer tick.
+pub fn poll_io<DM: DirectMap>(kernel: &Kernel<'_, DM>) {
+    if IO_WAIT.is_empty() {
+        return;
+    }
+    let fired = timerfd::fire_due(time::monotonic());
+    if inet::poll_pending() || fired {
+        IO_WAIT.wake_all(kernel);
+    }
+}
+
+fn sys_eventfd2(initval: u32, flags: u64) -> SyscallResult {
+    if flags & !(EFD_SEMAPHORE | EFD_NONBLOCK | EFD_CLOEXEC) != 0 {
+        return Err(EINVAL);
+    }
+    let semaphore = flags & EFD_SEMAPHORE != 0;
+    let nonblocking = flags & EFD_NONBLOCK != 0;
+    let file = fs::eventfd_create(initval as u64, semaphore, nonblocking)?;
+    install_file(file).map(|fd| fd as u64)
+}
+
+// The monotonic and boot-time clocks are both time since boot, as the guest
+// never suspends.
+fn sys_clock_gettime(clockid: u64, ptr: u64) -> SyscallResult {
+    let now = match clockid {
+        CLOCK_REALTIME => time::realtime(),
+        CLOCK_MONOTONIC | CLOCK_BOOTTIME => time::monotonic(),
+        _ => return Err(EINVAL),
+    };
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+    let now = timespec(now);
+    unsafe { core::ptr::write_unaligned(ptr as *mut Timespec, now) };
+    Ok(0)
+}
+
+// Realtime timers tick on the monotonic clock too, which the realtime clock
+// only differs from by the wall-clock time at boot.
+fn sys_timerfd_create(clockid: u64, flags: u64) -> SyscallResult {
+    let epoch = match clockid {
+        CLOCK_REALTIME => time::boot_wall_clock(),
+        CLOCK_MONOTONIC | CLOCK_BOOTTIME => Duration::ZERO,
+        _ => return Err(EINVAL),
+    };
+    if flags & !(TFD_NONBLOCK | TFD_CLOEXEC) != 0 {
+        return Err(EINVAL);
+    }
+    let file = fs::timerfd_create(epoch, flags & TFD_NONBLOCK != 0)?;
+    install_file(file).map(|fd| fd as u64)
+}
+
+fn sys_timerfd_settime(fd: u64, flags: u64, new_ptr: u64, old_ptr: u64) -> SyscallResult {
+    if flags & !(TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET) != 0 {
+        return Err(EINVAL);
+    }
+    if new_ptr == 0 {
+        return Err(EFAULT);
+    }
+    let new = unsafe { core::ptr::read_unaligned(new_ptr as *const Itimerspec) };
+    let (Some(value), Some(interval)) = (
+        timespec_duration(new.it_value),
+        timespec_duration(new.it_interval),
+    ) else {
+        return Err(EINVAL);
+    };
+    // A zero value disarms the timer whatever the interval.
+    let setting = TimerSetting {
+        value: (!value.is_zero()).then_some(value),
+        interval,
+    };
+
+    let absolute = flags & TFD_TIMER_ABSTIME != 0;
+    let old = with_fd(fd, |file| fs::timerfd_settime(file, setting, absolute))?;
+    if old_ptr != 0 {
+        unsafe { core::ptr::write_unaligned(old_ptr as *mut Itimerspec, itimerspec(old)) };
+    }
+    Ok(0)
+}
+
+fn sys_timerfd_gettime(fd: u64, ptr: u64) -> SyscallResult {
+    let current = with_fd(fd, |file| fs::timerfd_gettime(file))?;
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+    unsafe { core::ptr::write_unaligned(ptr as *mut Itimerspec, itimerspec(current)) };
+    Ok(0)
+}
+
+fn timespec_duration(ts: Timespec) -> Option<Duration> {
+    let secs = u64::try_from(ts.tv_sec).ok()?;
+    let nanos = u32::try_from(ts.tv_nsec)
+        .ok()
+        .filter(|&nanos| nanos < 1_000_000_000)?;
+    Some(Duration::new(secs, nanos))
+}
+
+fn timeval(duration: Duration) -> Timeval {
+    Timeval {
+        tv_sec: duration.as_secs() as i64,
+        tv_usec: duration.subsec_micros() as i64,
+    }
+}
+
+fn timespec(duration: Duration) -> Timespec {
+    Timespec {
+        tv_sec: duration.as_secs() as i64,
+        tv_nsec: duration.subsec_nanos() as i64,
+    }
+}
+
+// An expired or disarmed timer reads back as a zero value.
+fn itimerspec(setting: TimerSetting) -> Itimerspec {
+    Itimerspec {
+        it_interval: timespec(setting.interval),
+        it_value: timespec(setting.value.unwrap_or_default()),
+    }
+}
+
+fn sys_socket(domain: u64, ty: u64, protocol: u64) -> SyscallResult {
+    if !matches!(domain, AF_UNIX | AF_INET) {
+        return Err(EAFNOSUPPORT);
+    }
+    if ty & !(SOCK_TYPE_MASK | SOCK_NONBLOCK | SOCK_CLOEXEC) != 0 {
+        return Err(EINVAL);
+    }
+    let nonblocking = ty & SOCK_NONBLOCK != 0;
+    let created = match (domain, ty & SOCK_TYPE_MASK) {
+        (AF_UNIX, SOCK_STREAM | SOCK_DGRAM) if protocol != 0 => return Err(EPROTONOSUPPORT),
+        (AF_UNIX, SOCK_STREAM) => fs::socket_create(SocketType::Stream, nonblocking),
+        (AF_UNIX, SOCK_DGRAM) => fs::socket_create(SocketType::Datagram, nonblocking),
+        (AF_INET, SOCK_STREAM) if !matches!(protocol, 0 | IPPROTO_TCP) => {
+            return Err(EPROTONOSUPPORT);
+        }
+        (AF_INET, SOCK_STREAM) => fs::tcp_socket_create(nonblocking),
+        _ => return Err(ESOCKTNOSUPPORT),
+    };
+    install_file(created?).map(|fd| fd as u64)
+}
+
+fn sys_bind(fd: u64, addr: u64, len: u64) -> SyscallResult {
+    let name = user_socket_address(addr, len)?;
+    socket_io(fd, 0, |socket| socket.bind(name))?;
+    Ok(0)
+}
+
+fn sys_listen(fd: u64, backlog: i32) -> SyscallResult {
+    let backlog = usize::try_from(backlog).unwrap_or(0);
+    socket_io(fd, 0, |socket| socket.listen(backlog))?;
+    Ok(0)
+}
+
+// A blocking connect waits for room in a unix listener's backlog or for
+// the TCP handshake to finish. Non-blocking TCP connects carry on in the
+// background.
+fn sys_connect(fd: u64, addr: u64, len: u64) -> SyscallResult {
+    let name = user_socket_address(addr, len)?;
+    match socket_io(fd, 0, |socket| socket.connect(name)) {
+        Err(EAGAIN) if matches!(name, SocketAddress::Inet(_)) => Err(EINPROGRESS),
+        result => result.map(|()| 0),
+    }
+}
+
+fn sys_accept4(fd: u64, addr: u64, len_ptr: u64, flags: u64) -> SyscallResult {
+    if flags & !(SOCK_NONBLOCK | SOCK_CLOEXEC) != 0 {
+        return Err(EINVAL);
+    }
+    let nonblocking = flags & SOCK_NONBLOCK != 0;
+    let (file, peer) = socket_io(fd, 0, |socket| socket.accept(nonblocking))?;
+    let fd = install_file(file)?;
+    write_socket_address(peer, addr, len_ptr)?;
+    Ok(fd as u64)
+}
+
+fn sys_sendto(fd: u64, ptr: u64, len: u64, flags: u64, addr: u64, addr_len: u64) -> SyscallResult {
+    if flags & MSG_OOB != 0 {
+        return Err(EOPNOTSUPP);
+    }
+    let data = fd_buffer(fd, ptr, len)?;
+    let to = match addr {
+        0 => None,
+        addr => Some(user_socket_address(addr, addr_len)?),
+    };
+    socket_io(fd, flags, |socket| socket.send(data, to)).map(|sent| sent as u64)
+}
+
+fn sys_recvfrom(fd: u64, ptr: u64, len: u64, flags: u64, addr: u64, len_ptr: u64) -> SyscallResult {
+    if flags & MSG_OOB != 0 {
+        return Err(EOPNOTSUPP);
+    }
+    let buf = fd_buffer(fd, ptr, len)?;
+    let received = socket_io(fd, flags, |socket| socket.recv(buf))?;
+    write_socket_address(received.from, addr, len_ptr)?;
+    Ok(received.len as u64)
+}
+
+// Ancillary data such as SCM_RIGHTS is not supported.
+fn sys_sendmsg(fd: u64, msg_ptr: u64, flags: u64) -> SyscallResult {
+    if flags & MSG_OOB != 0 {
+        return Err(EOPNOTSUPP);
+    }
+    let result = user_msghdr(msg_ptr).and_then(|msg| {
+        if msg.msg_controllen != 0 {
+            return Err(EOPNOTSUPP);
+        }
+        let to = match msg.msg_name as u64 {
+            0 => None,
+            addr => Some(user_socket_address(addr, msg.msg_namelen as u64)?),
+        };
+        let iov = user_iovecs(&msg)?;
+        let total = iov
+            .iter()
+            .fold(0usize, |total, iov| total.saturating_add(iov.iov_len));
+        socket_io(fd, flags, |mut socket| {
+            if total > unix::BUFFER_SIZE
+                && let SocketRef::Unix(sockets, id) = &mut socket
+                && sockets.socket_type(*id)? == SocketType::Datagram
+            {
+                return Err(NetError::MessageTooLong {
+                    len: total,
+                    max: unix::BUFFER_SIZE,
+                }
+                .into());
+            }
+            let mut buffer = MESSAGE_BUFFER.lock();
+            let mut len = 0;
+            for iov in iov {
+                let chunk = iov.iov_len.min(buffer.len() - len);
+                let src = unsafe { core::slice::from_raw_parts(iov.iov_base, chunk) };
+                buffer[len..len + chunk].copy_from_slice(src);
+                len += chunk;
+            }
+            socket.send(&buffer[..len], to)
+        })
+    });
+    result.map(|sent| sent as u64)
+}
+
+fn sys_recvmsg(fd: u64, msg_ptr: u64, flags: u64) -> SyscallResult {
+    if flags & MSG_OOB != 0 {
+        return Err(EOPNOTSUPP);
+    }
+    let result = user_msghdr(msg_ptr).and_then(|msg| {
+        let iov = user_iovecs(&msg)?;
+        let total = iov
+            .iter()
+            .fold(0usize, |total, iov| total.saturating_add(iov.iov_len));
+        let received = socket_io(fd, flags, |socket| {
+            let mut buffer = MESSAGE_BUFFER.lock();
+            let received = socket.recv(&mut buffer[..total.min(unix::BUFFER_SIZE)])?;
+            let mut offset = 0;
+            for iov in iov {
+                let chunk = iov.iov_len.min(received.len - offset);
+                let dst = unsafe { core::slice::from_raw_parts_mut(iov.iov_base, chunk) };
+                dst.copy_from_slice(&buffer[offset..offset + chunk]);
+                offset += chunk;
+            }
+            Ok(received)
+        })?;
+
+        let user = msg_ptr as *mut Msghdr;
+        let namelen = unsafe { &raw mut (*user).msg_namelen } as u64;
+        write_socket_address(received.from, msg.msg_name as u64, namelen)?;
+        let msg_flags = if received.truncated { MSG_TRUNC } else { 0 };
+        unsafe {
+            (&raw mut (*user).msg_controllen).write_unaligned(0);
+            (&raw mut (*user).msg_flags).write_unaligned(msg_flags);
+        }
+        Ok(received.len)
+    });
+    result.map(|read| read as u64)
+}
+
+/// A socket of either family, as reached through its descriptor.
+enum SocketRef<'a> {
+    Unix(&'a mut SocketTable, usize),
+    Inet(&'a mut InetStack, usize),
+}
+
+/// An address passed in by user space.
+#[derive(Clone, Copy)]
+enum SocketAddress {
+    Unix(Address),
+    Inet(IpEndpoint),
+}
+
+/// An address to report back to user space. Unnamed unix sockets report
+/// just the family.
+#[derive(Clone, Copy)]
+enum SocketName {
+    Unix(Option<Address>),
+    Inet(IpEndpoint),
+}
+
+/// What a receive copied out; connected TCP sockets have no sender to name.
+struct Message {
+    len: usize,
+    from: Option<SocketName>,
+    truncated: bool,
+}
+
+// Addresses of the other family are rejected with EINVAL.
+impl SocketRef<'_> {
+    fn bind(self, name: SocketAddress) -> fs::errors::Result<()> {
+        match (self, name) {
+            (Self::Unix(sockets, id), SocketAddress::Unix(name)) => sockets.bind(id, name),
+            (Self::Inet(stack, id), SocketAddress::Inet(name)) => stack.bind(id, name.into()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn listen(self, backlog: usize) -> fs::errors::Result<()> {
+        match self {
+            Self::Unix(sockets, id) => sockets.listen(id, backlog),
+            Self::Inet(stack, id) => stack.listen(id, backlog),
+        }
+    }
+
+    fn connect(self, name: SocketAddress) -> fs::errors::Result<()> {
+        match (self, name) {
+            (Self::Unix(sockets, id), SocketAddress::Unix(name)) => sockets.connect(id, name),
+            (Self::Inet(stack, id), SocketAddress::Inet(name)) => stack.connect(id, name),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn accept(self, nonblocking: bool) -> fs::errors::Result<(OpenFile, Option<SocketName>)> {
+        match self {
+            Self::Unix(sockets, id) => {
+                let server = sockets.accept(id)?;
+                let peer = sockets.peer_name(server)?;
+                Ok((
+                    fs::socket_file(server, nonblocking),
+                    Some(SocketName::Unix(peer)),
+                ))
+            }
+            Self::Inet(stack, id) => {
+                let (server, peer) = stack.accept(id)?;
+                Ok((
+                    fs::tcp_socket_file(server, nonblocking),
+                    peer.map(SocketName::Inet),
+                ))
+            }
+        }
+    }
+
+    // A destination given for a connected TCP socket is ignored, as on Linux.
+    fn send(self, data: &[u8], to: Option<SocketAddress>) -> fs::errors::Result<usize> {
+        match (self, to) {
+            (Self::Unix(sockets, id), None) => sockets.send(id, data, None),
+            (Self::Unix(sockets, id), Some(SocketAddress::Unix(to))) => {
+                sockets.send(id, data, Some(to))
+            }
+            (Self::Inet(stack, id), None | Some(SocketAddress::Inet(_))) => stack.send(id, data),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn recv(self, buf: &mut [u8]) -> fs::errors::Result<Message> {
+        match self {
+            Self::Unix(sockets, id) => {
+                let received = sockets.recv(id, buf)?;
+                Ok(Message {
+                    len: received.len,
+                    from: Some(SocketName::Unix(received.from)),
+                    truncated: received.truncated,
+                })
+            }
+            Self::Inet(stack, id) => Ok(Message {
+                len: stack.recv(id, buf)?,
+                from: None,
+                truncated: false,
+            }),
+        }
+    }
+}
+
+// Run `op` on the socket behind `fd`, waiting as `blocking_io` does.
+// `flags` may carry MSG_DONTWAIT.
+fn socket_io<T>(
+    fd: u64,
+    flags: u64,
+    mut op: impl FnMut(SocketRef<'_>) -> fs::errors::Result<T>,
+) -> SyscallResult<T> {
+    blocking_io(fd, flags & MSG_DONTWAIT != 0, |file| match file.kind {
+        FileKind::Socket(id) => unix::with_sockets(|sockets| op(SocketRef::Unix(sockets, id))),
+        FileKind::TcpSocket(id) => inet::with_stack(|stack| op(SocketRef::Inet(stack, id))),
+        _ => Err(NetError::NotSocket.into()),
+    })
+}
+
+// Read a `sockaddr_un` or `sockaddr_in`, going by the family.
+fn user_socket_address(ptr: u64, len: u64) -> SyscallResult<SocketAddress> {
+    let len = usize::try_from(len).map_err(|_| EINVAL)?;
+    if len < size_of::<u16>() {
+        return Err(EINVAL);
+    }
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len) };
+    match u16::from_ne_bytes([bytes[0], bytes[1]]) as u64 {
+        AF_UNIX => user_unix_address(bytes).map(SocketAddress::Unix),
+        AF_INET if len >= size_of::<SockaddrIn>() => {
+            let sin = unsafe { core::ptr::read_unaligned(ptr as *const SockaddrIn) };
+            let addr = Ipv4Address::from_bits(u32::from_be(sin.sin_addr));
+            Ok(SocketAddress::Inet(IpEndpoint::new(
+                addr.into(),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        _ => Err(EINVAL),
+    }
+}
+
+// Abstract names start with a NUL byte and use the whole length; path
+// names stop at the first NUL and are resolved against the working
+// directory.
+fn user_unix_address(bytes: &[u8]) -> SyscallResult<Address> {
+    if !(SUN_PATH_OFFSET..=size_of::<SockaddrUn>()).contains(&bytes.len()) {
+        return Err(EINVAL);
+    }
+    let name = &bytes[SUN_PATH_OFFSET..];
+    match name {
+        [] | [0] => Err(EINVAL),
+        [0, ..] => Address::new(name).map_err(Errno::from),
+        _ => {
+            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            let path = process::resolve_path_at(crate::active_kernel(), None, &name[..end])?;
+            Address::new(path.as_bytes()).map_err(Errno::from)
+        }
+    }
+}
+
+// Store `name` at `ptr`, truncated to the capacity in `*len_ptr`, and put
+// the full length back in `*len_ptr`. Without a name the length is zero.
+fn write_socket_address(name: Option<SocketName>, ptr: u64, len_ptr: u64) -> SyscallResult<()> {
+    if ptr == 0 {
+        return Ok(());
+    }
+    if len_ptr == 0 {
+        return Err(EFAULT);
+    }
+    let capacity = unsafe { core::ptr::read_unaligned(len_ptr as *const i32) };
+    let capacity = usize::try_from(capacity).map_err(|_| EINVAL)?;
+
+    let (sockaddr, sockaddr_in);
+    let (bytes, len) = match name {
+        None => (&[][..], 0),
+        Some(SocketName::Unix(name)) => {
+            let name = name.as_ref().map_or(&[][..], Address::as_bytes);
+            let mut sun = SockaddrUn::new();
+            sun.sun_path[..name.len()].copy_from_slice(name);
+            sockaddr = sun;
+            // Path names are reported with their terminating NUL.
+            let nul = usize::from(name.first().is_some_and(|&b| b != 0));
+            let len = (SUN_PATH_OFFSET + name.len() + nul).min(size_of::<SockaddrUn>());
+            (as_bytes(&sockaddr), len)
+        }
+        Some(SocketName::Inet(endpoint)) => {
+            let IpAddress::Ipv4(addr) = endpoint.addr;
+            sockaddr_in = SockaddrIn::new(addr.to_bits(), endpoint.port);
+            (as_bytes(&sockaddr_in), size_of::<SockaddrIn>())
+        }
+    };
+
+    let copied = len.min(capacity);
+    unsafe {
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr as *mut u8, copied);
+        core::ptr::write_unaligned(len_ptr as *mut u32, len as u32);
+    }
+    Ok(())
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn user_msghdr(ptr: u64) -> SyscallResult<Msghdr> {
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { core::ptr::read_unaligned(ptr as *const Msghdr) })
+}
+
+fn user_iovecs<'a>(msg: &Msghdr) -> SyscallResult<&'a [Iovec]> {
+    if msg.msg_iovlen == 0 {
+        return Ok(&[]);
+    }
+    if msg.msg_iovlen > UIO_MAXIOV {
+        return Err(EMSGSIZE);
+    }
+    if msg.msg_iov.is_null() {
+        return Err(EFAULT);
+    }
+    let iov = unsafe { core::slice::from_raw_parts(msg.msg_iov, msg.msg_iovlen) };
+    if iov
+        .iter()
+        .any(|iov| iov.iov_len > 0 && iov.iov_base.is_null())
+    {
+        return Err(EFAULT);
+    }
+    Ok(iov)
+}
+
+// Only the console is a terminal; everything else rejects tty requests
+// with ENOTTY, which is what `isatty()` checks for.
+fn sys_ioctl(fd: u64, request: u64, arg: u64) -> SyscallResult {
+    if with_fd(fd, |file| Ok(file.kind))? != FileKind::Console {
+        return Err(ENOTTY);
+    }
+    if !matches!(request, TCGETS | TIOCGWINSZ) {
+        return Err(ENOTTY);
+    }
+    if arg == 0 {
+        return Err(EFAULT);
+    }
+
+    unsafe {
+        match request {
+            TCGETS => core::ptr::write_unaligned(arg as *mut Termios, CONSOLE_TERMIOS),
+            _ => core::ptr::write_unaligned(
+                arg as *mut Winsize,
+                Winsize {
+                    ws_row: console::ROWS,
+                    ws_col: console::COLUMNS,
+                    ..Winsize::default()
+                },
+            ),
+        }
+    }
+    Ok(0)
+}
+
+fn sys_uname(ptr: u64) -> SyscallResult {
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+
+    let mut uts = Utsname::zeroed();
+    copy_uts_field(&mut uts.sysname, UTS_SYSNAME);
+    copy_uts_field(&mut uts.nodename, UTS_NODENAME);
+    copy_uts_field(&mut uts.release, UTS_RELEASE);
+    copy_uts_field(&mut uts.version, UTS_VERSION);
+    copy_uts_field(&mut uts.machine, UTS_MACHINE);
+    copy_uts_field(&mut uts.domainname, UTS_DOMAINNAME);
+
+    unsafe {
+        core::ptr::write_unaligned(ptr as *mut Utsname, uts);
+    }
+    Ok(0)
+}
+
+// Fields are NUL-terminated, so at most UTSNAME_FIELD_LEN - 1 bytes are copied.
+fn copy_uts_field(field: &mut [u8; UTSNAME_FIELD_LEN], value: &str) {
+    let len = value.len().min(UTSNAME_FIELD_LEN - 1);
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+}
+
+// The only group a process belongs to is its primary gid.
+fn sys_getgroups(size: u64, list: u64) -> SyscallResult {
+    const GROUP_COUNT: u64 = 1;
+
+    if size == 0 {
+        return Ok(GROUP_COUNT);
+    }
+    if size > i32::MAX as u64 {
+        return Err(EINVAL);
+    }
+    if list == 0 {
+        return Err(EFAULT);
+    }
+
+    unsafe {
+        core::ptr::write_unaligned(list as *mut u32, credentials::current().gid);
+    }
+    Ok(GROUP_COUNT)
+}
+
+fn sys_getrandom(ptr: u64, len: u64, flags: u64) -> SyscallResult {
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0 {
+        return Err(EINVAL);
+    }
+    if flags & (GRND_RANDOM | GRND_INSECURE) == (GRND_RANDOM | GRND_INSECURE) {
+        return Err(EINVAL);
+    }
+    if len == 0 {
+        return Ok(0);
+    }
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+
+    // The pool is always initialized, so GRND_NONBLOCK never has to bail out.
+    let len = len.min(GETRANDOM_MAX) as usize;
+    let buf = unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len) };
+    random::fill(buf);
+    Ok(len as u64)
+}
+
+// There are no process groups: pid 0, the caller's group, holds all of its
+// children, and no pid below -1 names a group that exists.
+fn sys_wait4(pid: i64, status_ptr: u64, options: u64, rusage_ptr: u64) -> SyscallResult {
+    if options & !WNOHANG != 0 {
+        return Err(EINVAL);
+    }
+    let pid = match pid {
+        -1 | 0 => None,
+        pid if pid > 0 => Some(pid as usize),
+        _ => return Err(ECHILD),
+    };
+    let nohang = options & WNOHANG != 0;
+    let Some(child) = process::wait(crate::active_kernel(), pid, nohang)? else {
+        return Ok(0);
+    };
+    if status_ptr != 0 {
+        let status = child.status.wait_status();
+        unsafe { core::ptr::write_unaligned(status_ptr as *mut i32, status) };
+    }
+    if rusage_ptr != 0 {
+        unsafe { core::ptr::write_unaligned(rusage_ptr as *mut Rusage, rusage(child.cpu)) };
+    }
+    Ok(child.pid as u64)
+}
+
+// The kernel's own context is not a process and has used nothing.
+fn current_cpu_times() -> (CpuTime, CpuTime) {
+    process::info(crate::active_kernel(), 0)
+        .map_or_else(Default::default, |info| (info.cpu, info.children_cpu))
+}
+
+// Every process is a single thread, so the thread's usage is the process's.
+fn sys_getrusage(who: i64, ptr: u64) -> SyscallResult {
+    let (cpu, children_cpu) = current_cpu_times();
+    let cpu = match who {
+        RUSAGE_SELF | RUSAGE_THREAD => cpu,
+        RUSAGE_CHILDREN => children_cpu,
+        _ => return Err(EINVAL),
+    };
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+    unsafe { core::ptr::write_unaligned(ptr as *mut Rusage, rusage(cpu)) };
+    Ok(0)
+}
+
+fn sys_times(ptr: u64) -> SyscallResult {
+    if ptr != 0 {
+        let (cpu, children_cpu) = current_cpu_times();
+        let ticks = |duration| time::clock_ticks(duration) as i64;
+        let tms = Tms {
+            tms_utime: ticks(cpu.user),
+            tms_stime: ticks(cpu.system),
+            tms_cutime: ticks(children_cpu.user),
+            tms_cstime: ticks(children_cpu.system),
+        };
+        unsafe { core::ptr::write_unaligned(ptr as *mut Tms, tms) };
+    }
+    Ok(time::clock_ticks(time::monotonic()))
+}
+
+fn rusage(cpu: CpuTime) -> Rusage {
+    Rusage {
+        ru_utime: timeval(cpu.user),
+        ru_stime: timeval(cpu.system),
+        ..Rusage::default()
+    }
+}
+
+// There are no process groups and every process belongs to the one user,
+// so priorities can only be addressed per process.
+fn sys_getpriority(which: u64, who: u64) -> SyscallResult {
+    if which != PRIO_PROCESS {
+        return Err(EINVAL);
+    }
+    let nice = process::priority(crate::active_kernel(), who as usize)?;
+    // Like Linux, report 20 - nice so a valid result is never negative.
+    Ok((20 - nice) as u64)
+}
+
+fn sys_setpriority(which: u64, who: u64, nice: i32) -> SyscallResult {
+    if which != PRIO_PROCESS {
+        return Err(EINVAL);
+    }
+    process::set_priority(crate::active_kernel(), who as usize, nice)?;
+    Ok(0)
+}
+
+// Masks cover CPU_COUNT CPUs, which fit one word. Whatever a longer user
+// mask holds past it only names CPUs that do not exist.
+fn sys_sched_setaffinity(pid: u64, len: u64, ptr: u64) -> SyscallResult {
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+    let mut mask = [0; size_of::<u64>()];
+    let len = (len as usize).min(mask.len());
+    unsafe { core::ptr::copy_nonoverlapping(ptr as *const u8, mask.as_mut_ptr(), len) };
+    process::set_affinity(
+        crate::active_kernel(),
+        pid as usize,
+        u64::from_ne_bytes(mask),
+    )?;
+    Ok(0)
+}
+
+// Like Linux, the buffer has to be made of whole words that cover every CPU.
+fn sys_sched_getaffinity(pid: u64, len: u64, ptr: u64) -> SyscallResult {
+    let word = size_of::<u64>() as u64;
+    if len < word || !len.is_multiple_of(word) {
+        return Err(EINVAL);
+    }
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+    let mask = process::affinity(crate::active_kernel(), pid as usize)?;
+    unsafe { core::ptr::write_unaligned(ptr as *mut u64, mask) };
+    Ok(word)
+}
+
+// Without process groups only a single process can be named, and without
+// signal delivery SIGKILL is the only signal there is to send. Signal 0
+// just checks that the process exists.
+fn sys_kill(pid: i64, signal: u64) -> SyscallResult {
+    let Some(pid) = usize::try_from(pid).ok().filter(|&pid| pid != 0) else {
+        return Err(EINVAL);
+    };
+    let kernel = crate::active_kernel();
+    match signal {
+        0 if process::has_pid(kernel, pid) => Ok(0),
+        0 => Err(ESRCH),
+        signal if signal == u64::from(process::SIGKILL) => {
+            process::kill(kernel, pid)?;
+            Ok(0)
+        }
+        _ => Err(EINVAL),
+    }
+}
+
+fn sys_prlimit64(pid: u64, resource: u64, new_ptr: u64, old_ptr: u64) -> SyscallResult {
+    let Ok(resource) = usize::try_from(resource) else {
+        return Err(EINVAL);
+    };
+    let Ok(pid) = usize::try_from(pid) else {
+        return Err(ESRCH);
+    };
+
+    let new = if new_ptr == 0 {
+        None
+    } else {
+        Some(unsafe { core::ptr::read_unaligned(new_ptr as *const Rlimit) })
+    };
+
+    let old = process::prlimit(crate::active_kernel(), pid, resource, new)?;
+    if old_ptr != 0 {
+        unsafe {
+            core::ptr::write_unaligned(old_ptr as *mut Rlimit, old);
+        }
+    }
+    Ok(0)
+}
+
+// There is no exec, so no process can gain privileges and filters need no
+// no_new_privs.
+fn sys_seccomp(operation: u32, flags: u32, args: u64) -> SyscallResult {
+    match operation {
+        SECCOMP_SET_MODE_STRICT => {
+            if flags != 0 || args != 0 {
+                return Err(EINVAL);
+            }
+            process::with_seccomp(crate::active_kernel(), |state| state.set_strict())?;
+            Ok(0)
+        }
+        SECCOMP_SET_MODE_FILTER => {
+            if flags != 0 {
+                return Err(EINVAL);
+            }
+            let program = user_filter(args)?;
+            process::with_seccomp(crate::active_kernel(), |state| state.add_filter(program))?;
+            Ok(0)
+        }
+        SECCOMP_GET_ACTION_AVAIL => {
+            if flags != 0 {
+                return Err(EINVAL);
+            }
+            if args == 0 {
+                return Err(EFAULT);
+            }
+            let action = unsafe { core::ptr::read_unaligned(args as *const u32) };
+            if seccomp::action_available(action) {
+                Ok(0)
+            } else {
+                Err(EOPNOTSUPP)
+            }
+        }
+        _ => Err(EINVAL),
+    }
+}
+
+fn user_filter<'a>(ptr: u64) -> SyscallResult<&'a [SockFilter]> {
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+    let prog = unsafe { core::ptr::read_unaligned(ptr as *const SockFprog) };
+    if prog.len == 0 {
+        return Ok(&[]);
+    }
+    if prog.filter.is_null() {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(prog.filter, prog.len as usize) })
+}
+
+fn sys_getcwd(ptr: u64, size: u64) -> SyscallResult {
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+
+    let cwd = process::getcwd(crate::active_kernel());
+    let path = cwd.as_bytes();
+    let len = path.len() + 1;
+    if size < len as u64 {
+        return Err(ERANGE);
+    }
+
+    let buf = unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len) };
+    buf[..path.len()].copy_from_slice(path);
+    buf[path.len()] = 0;
+    Ok(len as u64)
+}
+
+fn sys_chdir(ptr: u64) -> SyscallResult {
+    let path = user_path(ptr)?;
+    process::chdir(crate::active_kernel(), path)?;
+    Ok(0)
+}
+
+fn sys_mkdir(ptr: u64, mode: u64) -> SyscallResult {
+    let mode = mode as u32 & !process::umask(crate::active_kernel());
+    sys_path_op(ptr, |path| fs::mkdir(path, mode))
+}
+
+fn sys_path_op(ptr: u64, op: impl FnOnce(&Path) -> fs::errors::Result<()>) -> SyscallResult {
+    let path = resolve_user_path(ptr)?;
+    op(&path)?;
+    Ok(0)
+}
+
+fn sys_rename(from: u64, to: u64) -> SyscallResult {
+    let from = resolve_user_path(from)?;
+    let to = resolve_user_path(to)?;
+    fs::rename(crate::active_kernel(), &from, &to)?;
+    Ok(0)
+}
+
+fn sys_truncate(ptr: u64, len: i64) -> SyscallResult {
+    let Ok(len) = usize::try_from(len) else {
+        return Err(EINVAL);
+    };
+    sys_path_op(ptr, |path| fs::truncate(crate::active_kernel(), path, len))
+}
+
+fn sys_statfs(ptr: u64, buf: u64) -> SyscallResult {
+    let path = resolve_user_path(ptr)?;
+    let stats = fs::statfs(crate::active_kernel(), &path)?;
+    let magic = if fs::on_host_share(&path) {
+        V9FS_MAGIC
+    } else {
+        RAMFS_MAGIC
+    };
+    write_statfs(buf, magic, stats)?;
+    Ok(0)
+}
+
+// Descriptors outside ramfs report the pseudo filesystem Linux keeps them
+// on, which has no space of its own.
+fn sys_fstatfs(fd: u64, buf: u64) -> SyscallResult {
+    let file = with_fd(fd, |file| Ok(*file))?;
+    let (magic, stats) = match file.kind {
+        FileKind::Inode(_) => (RAMFS_MAGIC, fs::fstatfs(crate::active_kernel(), &file)?),
+        FileKind::HostFile(_) => (V9FS_MAGIC, fs::fstatfs(crate::active_kernel(), &file)?),
+        FileKind::Console => (DEVPTS_SUPER_MAGIC, FsStats::default()),
+        FileKind::ProcStatm(_) | FileKind::ProcComm(_) | FileKind::ProcStat(_) => {
+            (PROC_SUPER_MAGIC, FsStats::default())
+        }
+        FileKind::Socket(_) | FileKind::TcpSocket(_) => (SOCKFS_MAGIC, FsStats::default()),
+        FileKind::Epoll(_) | FileKind::EventFd(_) | FileKind::TimerFd(_) => {
+            (ANON_INODE_FS_MAGIC, FsStats::default())
+        }
+    };
+    write_statfs(buf, magic, stats)?;
+    Ok(0)
+}
+
+fn write_statfs(ptr: u64, magic: i64, stats: FsStats) -> SyscallResult<()> {
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+    let statfs = Statfs {
+        f_type: magic,
+        f_bsize: stats.block_size as i64,
+        f_blocks: stats.blocks as u64,
+        f_bfree: stats.free_blocks as u64,
+        f_bavail: stats.free_blocks as u64,
+        f_files: stats.files as u64,
+        f_ffree: stats.free_files as u64,
+        f_namelen: stats.name_max as i64,
+        f_frsize: stats.block_size as i64,
+        ..Statfs::default()
+    };
+    unsafe { core::ptr::write_unaligned(ptr as *mut Statfs, statfs) };
+    Ok(())
+}
+
+fn sys_ftruncate(fd: u64, len: i64) -> SyscallResult {
+    with_fd(fd, |file| {
+        let len = usize::try_from(len).map_err(|_| FsError::InvalidArgument)?;
+        match file.kind {
+            FileKind::Inode(_) | FileKind::HostFile(_) => {
+                fs::truncate_file(crate::active_kernel(), file, len)
+            }
+            _ => Err(FsError::InvalidArgument),
+        }
+    })?;
+    Ok(0)
+}
+
+// Real and effective ids never differ, so AT_EACCESS changes nothing.
+fn sys_faccessat(dirfd: i64, ptr: u64, mode: u64, flags: u64) -> SyscallResult {
+    if mode & !(F_OK | R_OK | W_OK | X_OK) != 0 {
+        return Err(EINVAL);
+    }
+    if flags & !(AT_EACCESS | AT_SYMLINK_NOFOLLOW) != 0 {
+        return Err(EINVAL);
+    }
+
+    let path = resolve_user_path_at(dirfd, ptr)?;
+    fs::access(&path, mode as u32, credentials::current())?;
+    Ok(0)
+}
+
+fn sys_readlinkat(dirfd: i64, ptr: u64, buf: u64, size: u64) -> SyscallResult {
+    if size == 0 || size > i32::MAX as u64 {
+        return Err(EINVAL);
+    }
+
+    let path = resolve_user_path_at(dirfd, ptr)?;
+    let target = fs::readlink(&path)?;
+    if buf == 0 {
+        return Err(EFAULT);
+    }
+
+    // readlink never NUL-terminates and silently truncates.
+    let len = target.len().min(size as usize);
+    let out = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+    out.copy_from_slice(&target[..len]);
+    Ok(len as u64)
+}
+
+fn resolve_user_path_at(dirfd: i64, ptr: u64) -> SyscallResult<Path> {
+    let path = user_path(ptr)?;
+    let dirfd = match dirfd {
+        AT_FDCWD => None,
+        fd => Some(usize::try_from(fd).map_err(|_| EBADF)?),
+    };
+    process::resolve_path_at(crate::active_kernel(), dirfd, path).map_err(Errno::from)
+}
+
+fn resolve_user_path(ptr: u64) -> SyscallResult<Path> {
+    resolve_user_path_at(AT_FDCWD, ptr)
+}
+
+// Borrow a NUL-terminated path from the caller, scanning at most PATH_MAX bytes.
+fn user_path<'a>(ptr: u64) -> SyscallResult<&'a [u8]> {
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+
+    let base = ptr as *const u8;
+    for len in 0..PATH_MAX {
+        if unsafe { base.add(len).read() } == 0 {
+            return Ok(unsafe { core::slice::from_raw_parts(base, len) });
+        }
+    }
+    Err(ENAMETOOLONG)
+}
+
+fn sys_brk(addr: u64) -> SyscallResult {
+    let cur = process::brk(crate::active_kernel(), addr as usize)?;
+    Ok(cur as u64)
+}
+
+fn sys_mmap(addr: u64, len: u64, prot: u64, flags: u64, fd: i64, offset: u64) -> SyscallResult {
+    let Ok(len) = usize::try_from(len) else {
+        return Err(EINVAL);
+    };
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    if offset != 0 || prot & !MMAP_KNOWN_PROT != 0 {
+        return Err(EINVAL);
+    }
+
+    let sharing = flags & (MAP_PRIVATE | MAP_SHARED);
+    if sharing == 0 {
+        return Err(EINVAL);
+    }
+    if sharing == MAP_SHARED_VALIDATE && flags & !MMAP_KNOWN_FLAGS != 0 {
+        return Err(EOPNOTSUPP);
+    }
+    if (flags & MAP_ANONYMOUS) == 0 {
+        return Err(ENOSYS);
+    }
+    if fd != -1 {
+        return Err(EINVAL);
+    }
+
+    let mapped = process::mmap(crate::active_kernel(), addr as usize, len, prot, flags)?;
+    Ok(mapped as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_syscall_returns_enosys() {
+        assert_eq!(dispatch(0xdead, 0, 0, 0, 0, 0, 0), Err(ENOSYS));
+        assert_eq!(
+            __syscall_dispatch(0xdead, 0, 0, 0, 0, 0, 0) as i64,
+            -ENOSYS.code()
+        );
+    }
+
+    #[test]
+    fn write_rejects_unknown_fd() {
+        assert_eq!(dispatch(SYS_WRITE, 7, 0, 0, 0, 0, 0), Err(EBADF));
+    }
+
+    #[test]
+    fn write_rejects_null_pointer_for_non_zero_len() {
+        assert_eq!(dispatch(SYS_WRITE, 1, 0, 1, 0, 0, 0), Err(EFAULT));
+    }
+
+    #[test]
+    fn uname_reports_hostel_identity() {
+        let mut uts = Utsname::zeroed();
+        let ptr = &mut uts as *mut Utsname as u64;
+        assert_eq!(dispatch(SYS_UNAME, ptr, 0, 0, 0, 0, 0), Ok(0));
+
+        let field = |bytes: &[u8]| {
+            let len = bytes.iter().position(|&b| b == 0).unwrap();
+            std::str::from_utf8(&bytes[..len]).unwrap().to_string()
+        };
+        assert_eq!(field(&uts.sysname), "Hostel");
+        assert_eq!(field(&uts.release), env!("CARGO_PKG_VERSION"));
+        assert_eq!(field(&uts.machine), "x86_64");
+    }
+
+    #[test]
+    fn credentials_default_to_root() {
+        for nr in [SYS_GETUID, SYS_GETEUID, SYS_GETGID, SYS_GETEGID] {
+            assert_eq!(dispatch(nr, 0, 0, 0, 0, 0, 0), Ok(0));
+        }
+    }
+
+    #[test]
+    fn getgroups_reports_primary_group() {
+        let mut groups = [u32::MAX; 4];
+        let ptr = groups.as_mut_ptr() as u64;
+
+        assert_eq!(dispatch(SYS_GETGROUPS, 0, 0, 0, 0, 0, 0), Ok(1));
+        assert_eq!(dispatch(SYS_GETGROUPS, 4, ptr, 0, 0, 0, 0), Ok(1));
+        assert_eq!(groups[0], 0);
+        assert_eq!(groups[1], u32::MAX);
+    }
+
+    #[test]
+    fn getrandom_fills_buffer() {
+        let mut buf = [0u8; 64];
+        let ptr = buf.as_mut_ptr() as u64;
+        assert_eq!(dispatch(SYS_GETRANDOM, ptr, 64, 0, 0, 0, 0), Ok(64));
+        assert_ne!(buf, [0u8; 64]);
+    }
+
+    #[test]
+    fn getrandom_rejects_unknown_flags() {
+        let mut buf = [0u8; 8];
+        let ptr = buf.as_mut_ptr() as u64;
+        assert_eq!(dispatch(SYS_GETRANDOM, ptr, 8, 0x80, 0, 0, 0), Err(EINVAL));
+        assert_eq!(
+            dispatch(SYS_GETRANDOM, ptr, 8, GRND_RANDOM | GRND_INSECURE, 0, 0, 0),
+            Err(EINVAL)
+        );
+    }
+
+    #[test]
+    fn path_arguments_must_be_terminated_within_path_max() {
+        let unterminated = vec![b'a'; PATH_MAX];
+        assert_eq!(user_path(0), Err(EFAULT));
+        assert_eq!(user_path(c"/tmp".as_ptr() as u64), Ok(&b"/tmp"[..]));
+        assert_eq!(user_path(unterminated.as_ptr() as u64), Err(ENAMETOOLONG));
+    }
+
+    #[test]
+    fn ftruncate_rejects_console_and_unknown_fds() {
+        assert_eq!(dispatch(SYS_FTRUNCATE, 1, 0, 0, 0, 0, 0), Err(EINVAL));
+        assert_eq!(dispatch(SYS_FTRUNCATE, 5, 0, 0, 0, 0, 0), Err(EBADF));
+    }
+
+    #[test]
+    fn faccessat_rejects_unknown_mode_and_flags() {
+        let path = c"/".as_ptr() as u64;
+        assert_eq!(dispatch(SYS_ACCESS, path, 0o10, 0, 0, 0, 0), Err(EINVAL));
+        assert_eq!(
+            dispatch(SYS_FACCESSAT, AT_FDCWD as u64, path, R_OK, 0x1, 0, 0),
+            Err(EINVAL)
+        );
+    }
+
+    #[test]
+    fn readlink_rejects_empty_buffer() {
+        let path = c"/proc/self/exe".as_ptr() as u64;
+        assert_eq!(dispatch(SYS_READLINK, path, 0, 0, 0, 0, 0), Err(EINVAL));
+    }
+
+    #[test]
+    fn poll_reports_console_readiness_and_closed_fds() {
+        let mut fds = [
+            PollFd {
+                fd: 1,
+                events: POLLOUT,
+                revents: 0,
+            },
+            PollFd {
+                fd: 7,
+                events: POLLIN,
+                revents: 0,
+            },
+            PollFd {
+                fd: -1,
+                events: POLLIN,
+                revents: POLLIN,
+            },
+        ];
+        let ptr = fds.as_mut_ptr() as u64;
+        assert_eq!(dispatch(SYS_POLL, ptr, 3, 0, 0, 0, 0), Ok(2));
+        assert_eq!(fds[0].revents, POLLOUT);
+        assert_eq!(fds[1].revents, POLLNVAL);
+        assert_eq!(fds[2].revents, 0);
+    }
+
+    #[test]
+    fn clock_gettime_reads_the_monotonic_clock() {
+        let mut ts = Timespec::default();
+        let ptr = &mut ts as *mut Timespec as u64;
+        let before = time::monotonic();
+        assert_eq!(
+            dispatch(SYS_CLOCK_GETTIME, CLOCK_MONOTONIC, ptr, 0, 0, 0, 0),
+            Ok(0)
+        );
+        assert!(timespec_duration(ts).unwrap() >= before);
+        assert_eq!(
+            dispatch(SYS_CLOCK_GETTIME, 42, ptr, 0, 0, 0, 0),
+            Err(EINVAL)
+        );
+        assert_eq!(
+            dispatch(SYS_CLOCK_GETTIME, CLOCK_BOOTTIME, 0, 0, 0, 0, 0),
+            Err(EFAULT)
+        );
+    }
+
+    #[test]
+    fn poll_without_ready_fds_times_out() {
+        assert_eq!(dispatch(SYS_POLL, 0, 0, 1, 0, 0, 0), Ok(0));
+    }
+
+    #[test]
+    fn select_splits_readiness_between_sets() {
+        let mut read = FdSet::new();
+        let mut write = FdSet::new();
+        let mut except = FdSet::new();
+        read.insert(0);
+        write.insert(1);
+        write.insert(2);
+        except.insert(2);
+        let mut timeout = Timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        };
+
+        let ready = dispatch(
+            SYS_SELECT,
+            3,
+            &mut read as *mut FdSet as u64,
+            &mut write as *mut FdSet as u64,
+            &mut except as *mut FdSet as u64,
+            &mut timeout as *mut Timeval as u64,
+            0,
+        );
+        assert_eq!(ready, Ok(3));
+        assert!(read.contains(0) && write.contains(1) && write.contains(2));
+        assert_eq!(except, FdSet::new());
+    }
+
+    #[test]
+    fn select_rejects_closed_fds_and_bad_arguments() {
+        let mut read = FdSet::new();
+        read.insert(7);
+        let read = &mut read as *mut FdSet as u64;
+        assert_eq!(dispatch(SYS_SELECT, 8, read, 0, 0, 0, 0), Err(EBADF));
+        assert_eq!(
+            dispatch(SYS_SELECT, FD_SETSIZE as u64 + 1, 0, 0, 0, 0, 0),
+            Err(EINVAL)
+        );
+
+        let mut timeout = Timeval {
+            tv_sec: 0,
+            tv_usec: 1_000_000,
+        };
+        let timeout = &mut timeout as *mut Timeval as u64;
+        assert_eq!(dispatch(SYS_SELECT, 0, 0, 0, 0, timeout, 0), Err(EINVAL));
+    }
+
+    #[test]
+    fn epoll_rejects_bad_flags_and_non_epoll_fds() {
+        assert_eq!(dispatch(SYS_EPOLL_CREATE1, 1, 0, 0, 0, 0, 0), Err(EINVAL));
+        assert_eq!(dispatch(SYS_EPOLL_CREATE, 0, 0, 0, 0, 0, 0), Err(EINVAL));
+
+        let mut event = EpollEvent::default();
+        let ptr = &mut event as *mut EpollEvent as u64;
+        assert_eq!(
+            dispatch(SYS_EPOLL_CTL, 1, EPOLL_CTL_ADD, 2, ptr, 0, 0),
+            Err(EINVAL)
+        );
+        assert_eq!(dispatch(SYS_EPOLL_WAIT, 1, ptr, 1, 0, 0, 0), Err(EINVAL));
+        assert_eq!(dispatch(SYS_EPOLL_WAIT, 9, ptr, 0, 0, 0, 0), Err(EINVAL));
+        assert_eq!(dispatch(SYS_EPOLL_WAIT, 9, ptr, 1, 0, 0, 0), Err(EBADF));
+    }
+
+    #[test]
+    fn eventfd_rejects_unknown_flags() {
+        assert_eq!(dispatch(SYS_EVENTFD2, 0, 0x8, 0, 0, 0, 0), Err(EINVAL));
+    }
+
+    #[test]
+    fn timerfd_rejects_unknown_clocks_and_bad_times() {
+        assert_eq!(dispatch(SYS_TIMERFD_CREATE, 2, 0, 0, 0, 0, 0), Err(EINVAL));
+        assert_eq!(
+            dispatch(SYS_TIMERFD_CREATE, CLOCK_MONOTONIC, 0x1, 0, 0, 0, 0),
+            Err(EINVAL)
+        );
+
+        let bad = Itimerspec {
+            it_interval: Timespec::default(),
+            it_value: Timespec {
+                tv_sec: 0,
+                tv_nsec: 1_000_000_000,
+            },
+        };
+        let ptr = &bad as *const Itimerspec as u64;
+        assert_eq!(
+            dispatch(SYS_TIMERFD_SETTIME, 1, 0, ptr, 0, 0, 0),
+            Err(EINVAL)
+        );
+        let good = Itimerspec::default();
+        let ptr = &good as *const Itimerspec as u64;
+        assert_eq!(
+            dispatch(SYS_TIMERFD_SETTIME, 1, 0, ptr, 0, 0, 0),
+            Err(EINVAL)
+        );
+    }
+
+    #[test]
+    fn fstatfs_reports_pseudo_filesystems() {
+        let mut statfs = Statfs::default();
+        let ptr = &mut statfs as *mut Statfs as u64;
+        assert_eq!(dispatch(SYS_FSTATFS, 1, ptr, 0, 0, 0, 0), Ok(0));
+        assert_eq!(statfs.f_type, DEVPTS_SUPER_MAGIC);
+        assert_eq!(statfs.f_blocks, 0);
+        assert_eq!(dispatch(SYS_FSTATFS, 1, 0, 0, 0, 0, 0), Err(EFAULT));
+        assert_eq!(dispatch(SYS_FSTATFS, 99, ptr, 0, 0, 0, 0), Err(EBADF));
+    }
+
+    #[test]
+    fn sendfile_checks_descriptors_before_copying() {
+        let mut offset = 0i64;
+        let ptr = &mut offset as *mut i64 as u64;
+        assert_eq!(dispatch(SYS_SENDFILE, 1, 99, 0, 16, 0, 0), Err(EBADF));
+        assert_eq!(dispatch(SYS_SENDFILE, 0, 1, 0, 16, 0, 0), Err(EBADF));
+        assert_eq!(dispatch(SYS_SENDFILE, 1, 0, ptr, 16, 0, 0), Err(ESPIPE));
+    }
+
+    #[test]
+    fn console_answers_terminal_ioctls() {
+        let mut termios = Termios {
+            c_iflag: 0,
+            c_oflag: 0,
+            c_cflag: 0,
+            c_lflag: 0,
+            c_line: 0,
+            c_cc: [0; NCCS],
+        };
+        let ptr = &mut termios as *mut Termios as u64;
+        assert_eq!(dispatch(SYS_IOCTL, 1, TCGETS, ptr, 0, 0, 0), Ok(0));
+        assert_eq!(termios, CONSOLE_TERMIOS);
+        assert_ne!(termios.c_lflag & ICANON, 0);
+
+        let mut size = Winsize::default();
+        let ptr = &mut size as *mut Winsize as u64;
+        assert_eq!(dispatch(SYS_IOCTL, 0, TIOCGWINSZ, ptr, 0, 0, 0), Ok(0));
+        assert_eq!((size.ws_row, size.ws_col), (24, 80));
+    }
+
+    #[test]
+    fn ioctl_rejects_unknown_requests_and_fds() {
+        assert_eq!(dispatch(SYS_IOCTL, 1, 0x5402, 0, 0, 0, 0), Err(ENOTTY));
+        assert_eq!(dispatch(SYS_IOCTL, 7, TCGETS, 0, 0, 0, 0), Err(EBADF));
+        assert_eq!(dispatch(SYS_IOCTL, 2, TIOCGWINSZ, 0, 0, 0, 0), Err(EFAULT));
+    }
+
+    #[test]
+    fn socket_rejects_unsupported_families_and_types() {
+        assert_eq!(
+            dispatch(SYS_SOCKET, 10, SOCK_STREAM, 0, 0, 0, 0),
+            Err(EAFNOSUPPORT)
+        );
+        assert_eq!(
+            dispatch(SYS_SOCKET, AF_INET, SOCK_DGRAM, 0, 0, 0, 0),
+            Err(ESOCKTNOSUPPORT)
+        );
+        assert_eq!(
+            dispatch(SYS_SOCKET, AF_INET, SOCK_STREAM, 17, 0, 0, 0),
+            Err(EPROTONOSUPPORT)
+        );
+        assert_eq!(
+            dispatch(SYS_SOCKET, AF_UNIX, 3, 0, 0, 0, 0),
+            Err(ESOCKTNOSUPPORT)
+        );
+        assert_eq!(
+            dispatch(SYS_SOCKET, AF_UNIX, SOCK_DGRAM, 17, 0, 0, 0),
+            Err(EPROTONOSUPPORT)
+        );
+    }
+
+    #[test]
+    fn bind_validates_address_then_descriptor() {
+        let mut addr = SockaddrUn::new();
+        addr.sun_path[..4].copy_from_slice(b"\0abc");
+        let ptr = &addr as *const SockaddrUn as u64;
+        let len = (SUN_PATH_OFFSET + 4) as u64;
+
+        assert_eq!(dispatch(SYS_BIND, 1, ptr, len, 0, 0, 0), Err(ENOTSOCK));
+        assert_eq!(dispatch(SYS_BIND, 1, ptr, 1, 0, 0, 0), Err(EINVAL));
+        assert_eq!(dispatch(SYS_BIND, 1, 0, len, 0, 0, 0), Err(EFAULT));
+        let inet = SockaddrUn {
+            sun_family: 2,
+            ..addr
+        };
+        let ptr = &inet as *const SockaddrUn as u64;
+        assert_eq!(dispatch(SYS_BIND, 1, ptr, len, 0, 0, 0), Err(EINVAL));
+    }
+
+    #[test]
+    fn kill_rejects_process_groups() {
+        let sigkill = u64::from(process::SIGKILL);
+        assert_eq!(dispatch(SYS_KILL, 0, sigkill, 0, 0, 0, 0), Err(EINVAL));
+        assert_eq!(
+            dispatch(SYS_KILL, -1i64 as u64, sigkill, 0, 0, 0, 0),
+            Err(EINVAL)
+        );
+        assert_eq!(dispatch(SYS_KILL, -7i64 as u64, 0, 0, 0, 0, 0), Err(EINVAL));
+    }
+
+    #[test]
+    fn uname_rejects_null_pointer() {
+        assert_eq!(dispatch(SYS_UNAME, 0, 0, 0, 0, 0, 0), Err(EFAULT));
+    }
+
+    #[test]
+    fn seccomp_reports_available_actions_and_rejects_bad_requests() {
+        let query = |action: u32| {
+            let ptr = &action as *const u32 as u64;
+            dispatch(
+                SYS_SECCOMP,
+                SECCOMP_GET_ACTION_AVAIL as u64,
+                0,
+                ptr,
+                0,
+                0,
+                0,
+            )
+        };
+        assert_eq!(query(seccomp::SECCOMP_RET_ERRNO), Ok(0));
+        assert_eq!(query(seccomp::SECCOMP_RET_KILL_PROCESS), Ok(0));
+        assert_eq!(query(seccomp::SECCOMP_RET_TRAP), Err(EOPNOTSUPP));
+
+        let strict = SECCOMP_SET_MODE_STRICT as u64;
+        let filter = SECCOMP_SET_MODE_FILTER as u64;
+        assert_eq!(dispatch(SYS_SECCOMP, strict, 1, 0, 0, 0, 0), Err(EINVAL));
+        assert_eq!(dispatch(SYS_SECCOMP, filter, 0, 0, 0, 0, 0), Err(EFAULT));
+        assert_eq!(dispatch(SYS_SECCOMP, 3, 0, 0, 0, 0, 0), Err(EINVAL));
+    }
+
+    #[test]
+    fn mmap_rejects_unknown_prot_bits() {
+        let anon = MAP_PRIVATE | MAP_ANONYMOUS;
         assert_eq!(
-            __syscall_dispatch(SYS_WRITE, 1, 0, 1, 0, 0, 0) as i64,
-            -EFAULT
+            dispatch(SYS_MMAP, 0, 4096, 0x8, anon, -1i64 as u64, 0),
+            Err(EINVAL)
         );
     }
 }
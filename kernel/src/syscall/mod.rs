@@ -1,19 +1,436 @@
 use core::arch::asm;
+use core::ffi::CStr;
 
+use crate::limits::Rlimit;
+use crate::seccomp::SockFprog;
+
+mod errno;
 mod handlers;
 
+pub use errno::{Errno, SyscallResult};
+pub use handlers::{poll_io, wake_io_waiters};
+
+pub const SYS_READ: u64 = 0;
 pub const SYS_WRITE: u64 = 1;
+pub const SYS_OPEN: u64 = 2;
+pub const SYS_CLOSE: u64 = 3;
+pub const SYS_POLL: u64 = 7;
+pub const SYS_LSEEK: u64 = 8;
 pub const SYS_MMAP: u64 = 9;
 pub const SYS_BRK: u64 = 12;
+pub const SYS_RT_SIGRETURN: u64 = 15;
+pub const SYS_IOCTL: u64 = 16;
+pub const SYS_ACCESS: u64 = 21;
+pub const SYS_SELECT: u64 = 23;
 pub const SYS_SCHED_YIELD: u64 = 24;
 pub const SYS_GETPID: u64 = 39;
+pub const SYS_SENDFILE: u64 = 40;
+pub const SYS_SOCKET: u64 = 41;
+pub const SYS_CONNECT: u64 = 42;
+pub const SYS_ACCEPT: u64 = 43;
+pub const SYS_SENDTO: u64 = 44;
+pub const SYS_RECVFROM: u64 = 45;
+pub const SYS_SENDMSG: u64 = 46;
+pub const SYS_RECVMSG: u64 = 47;
+pub const SYS_BIND: u64 = 49;
+pub const SYS_LISTEN: u64 = 50;
 pub const SYS_EXIT: u64 = 60;
+pub const SYS_WAIT4: u64 = 61;
+pub const SYS_KILL: u64 = 62;
+pub const SYS_UNAME: u64 = 63;
+pub const SYS_TRUNCATE: u64 = 76;
+pub const SYS_FTRUNCATE: u64 = 77;
+pub const SYS_GETCWD: u64 = 79;
+pub const SYS_CHDIR: u64 = 80;
+pub const SYS_RENAME: u64 = 82;
+pub const SYS_MKDIR: u64 = 83;
+pub const SYS_RMDIR: u64 = 84;
+pub const SYS_UNLINK: u64 = 87;
+pub const SYS_READLINK: u64 = 89;
+pub const SYS_UMASK: u64 = 95;
+pub const SYS_GETRLIMIT: u64 = 97;
+pub const SYS_GETRUSAGE: u64 = 98;
+pub const SYS_TIMES: u64 = 100;
+pub const SYS_GETUID: u64 = 102;
+pub const SYS_GETGID: u64 = 104;
+pub const SYS_GETEUID: u64 = 107;
+pub const SYS_GETEGID: u64 = 108;
+pub const SYS_GETPPID: u64 = 110;
+pub const SYS_GETGROUPS: u64 = 115;
+pub const SYS_STATFS: u64 = 137;
+pub const SYS_FSTATFS: u64 = 138;
+pub const SYS_GETPRIORITY: u64 = 140;
+pub const SYS_SETPRIORITY: u64 = 141;
+pub const SYS_SETRLIMIT: u64 = 160;
+pub const SYS_SCHED_SETAFFINITY: u64 = 203;
+pub const SYS_SCHED_GETAFFINITY: u64 = 204;
+pub const SYS_EPOLL_CREATE: u64 = 213;
+pub const SYS_CLOCK_GETTIME: u64 = 228;
 pub const SYS_EXIT_GROUP: u64 = 231;
+pub const SYS_EPOLL_WAIT: u64 = 232;
+pub const SYS_EPOLL_CTL: u64 = 233;
+pub const SYS_OPENAT: u64 = 257;
+pub const SYS_READLINKAT: u64 = 267;
+pub const SYS_FACCESSAT: u64 = 269;
+pub const SYS_EPOLL_PWAIT: u64 = 281;
+pub const SYS_TIMERFD_CREATE: u64 = 283;
+pub const SYS_EVENTFD: u64 = 284;
+pub const SYS_TIMERFD_SETTIME: u64 = 286;
+pub const SYS_TIMERFD_GETTIME: u64 = 287;
+pub const SYS_ACCEPT4: u64 = 288;
+pub const SYS_EVENTFD2: u64 = 290;
+pub const SYS_EPOLL_CREATE1: u64 = 291;
+pub const SYS_PRLIMIT64: u64 = 302;
+pub const SYS_SECCOMP: u64 = 317;
+pub const SYS_GETRANDOM: u64 = 318;
+
+pub const PROT_NONE: u64 = 0x0;
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
 
 pub const MAP_SHARED: u64 = 0x01;
 pub const MAP_PRIVATE: u64 = 0x02;
 pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_SHARED_VALIDATE: u64 = 0x03;
 pub const MAP_ANONYMOUS: u64 = 0x20;
+pub const MAP_GROWSDOWN: u64 = 0x100;
+pub const MAP_NORESERVE: u64 = 0x4000;
+pub const MAP_POPULATE: u64 = 0x8000;
+pub const MAP_STACK: u64 = 0x20000;
+pub const MAP_FIXED_NOREPLACE: u64 = 0x100000;
+
+pub const O_RDONLY: u64 = 0o0;
+pub const O_WRONLY: u64 = 0o1;
+pub const O_RDWR: u64 = 0o2;
+pub const O_ACCMODE: u64 = 0o3;
+pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+pub const O_NONBLOCK: u64 = 0o4000;
+pub const O_DIRECTORY: u64 = 0o200000;
+pub const O_CLOEXEC: u64 = 0o2000000;
+
+pub const PRIO_PROCESS: u64 = 0;
+pub const PRIO_PGRP: u64 = 1;
+pub const PRIO_USER: u64 = 2;
+
+pub const WNOHANG: u64 = 1;
+
+pub const RUSAGE_SELF: i64 = 0;
+pub const RUSAGE_CHILDREN: i64 = -1;
+pub const RUSAGE_THREAD: i64 = 1;
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+pub const AT_FDCWD: i64 = -100;
+pub const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+pub const AT_EACCESS: u64 = 0x200;
+
+pub const F_OK: u64 = 0;
+pub const X_OK: u64 = 1;
+pub const W_OK: u64 = 2;
+pub const R_OK: u64 = 4;
+
+pub const POLLIN: i16 = 0x001;
+pub const POLLPRI: i16 = 0x002;
+pub const POLLOUT: i16 = 0x004;
+pub const POLLERR: i16 = 0x008;
+pub const POLLHUP: i16 = 0x010;
+pub const POLLNVAL: i16 = 0x020;
+pub const POLLRDNORM: i16 = 0x040;
+pub const POLLWRNORM: i16 = 0x100;
+
+pub const FD_SETSIZE: usize = 1024;
+
+pub const EFD_SEMAPHORE: u64 = 0o1;
+pub const EFD_NONBLOCK: u64 = O_NONBLOCK;
+pub const EFD_CLOEXEC: u64 = O_CLOEXEC;
+
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+pub const CLOCK_BOOTTIME: u64 = 7;
+
+pub const TFD_NONBLOCK: u64 = O_NONBLOCK;
+pub const TFD_CLOEXEC: u64 = O_CLOEXEC;
+pub const TFD_TIMER_ABSTIME: u64 = 1 << 0;
+pub const TFD_TIMER_CANCEL_ON_SET: u64 = 1 << 1;
+
+pub const AF_UNIX: u64 = 1;
+pub const AF_INET: u64 = 2;
+pub const SOCK_STREAM: u64 = 1;
+pub const SOCK_DGRAM: u64 = 2;
+pub const SOCK_TYPE_MASK: u64 = 0xf;
+pub const SOCK_NONBLOCK: u64 = O_NONBLOCK;
+pub const SOCK_CLOEXEC: u64 = O_CLOEXEC;
+pub const IPPROTO_TCP: u64 = 6;
+pub const INADDR_ANY: u32 = 0;
+pub const INADDR_LOOPBACK: u32 = 0x7f00_0001;
+
+pub const MSG_OOB: u64 = 0x01;
+pub const MSG_TRUNC: i32 = 0x20;
+pub const MSG_DONTWAIT: u64 = 0x40;
+pub const MSG_NOSIGNAL: u64 = 0x4000;
+
+pub const EPOLL_CLOEXEC: u64 = O_CLOEXEC;
+pub const EPOLL_CTL_ADD: u64 = 1;
+pub const EPOLL_CTL_DEL: u64 = 2;
+pub const EPOLL_CTL_MOD: u64 = 3;
+pub const EPOLLIN: u32 = 0x001;
+pub const EPOLLPRI: u32 = 0x002;
+pub const EPOLLOUT: u32 = 0x004;
+pub const EPOLLERR: u32 = 0x008;
+pub const EPOLLHUP: u32 = 0x010;
+pub const EPOLLRDNORM: u32 = 0x040;
+pub const EPOLLWRNORM: u32 = 0x100;
+pub const EPOLLRDHUP: u32 = 0x2000;
+pub const EPOLLONESHOT: u32 = 1 << 30;
+pub const EPOLLET: u32 = 1 << 31;
+
+pub const TCGETS: u64 = 0x5401;
+pub const TIOCGWINSZ: u64 = 0x5413;
+
+pub const ICRNL: u32 = 0o400;
+pub const IXON: u32 = 0o2000;
+pub const OPOST: u32 = 0o1;
+pub const ONLCR: u32 = 0o4;
+pub const B38400: u32 = 0o17;
+pub const CS8: u32 = 0o60;
+pub const CREAD: u32 = 0o200;
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+pub const ECHOE: u32 = 0o20;
+pub const ECHOK: u32 = 0o40;
+pub const IEXTEN: u32 = 0o100000;
+
+pub const GRND_NONBLOCK: u64 = 0x01;
+pub const GRND_RANDOM: u64 = 0x02;
+pub const GRND_INSECURE: u64 = 0x04;
+
+pub const UTSNAME_FIELD_LEN: usize = 65;
+
+pub const RAMFS_MAGIC: i64 = 0x8584_58f6;
+pub const SOCKFS_MAGIC: i64 = 0x534f_434b;
+pub const ANON_INODE_FS_MAGIC: i64 = 0x0904_1934;
+pub const DEVPTS_SUPER_MAGIC: i64 = 0x1cd1;
+pub const PROC_SUPER_MAGIC: i64 = 0x9fa0;
+pub const V9FS_MAGIC: i64 = 0x0102_1997;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Utsname {
+    pub sysname: [u8; UTSNAME_FIELD_LEN],
+    pub nodename: [u8; UTSNAME_FIELD_LEN],
+    pub release: [u8; UTSNAME_FIELD_LEN],
+    pub version: [u8; UTSNAME_FIELD_LEN],
+    pub machine: [u8; UTSNAME_FIELD_LEN],
+    pub domainname: [u8; UTSNAME_FIELD_LEN],
+}
+
+impl Utsname {
+    pub const fn zeroed() -> Self {
+        Self {
+            sysname: [0; UTSNAME_FIELD_LEN],
+            nodename: [0; UTSNAME_FIELD_LEN],
+            release: [0; UTSNAME_FIELD_LEN],
+            version: [0; UTSNAME_FIELD_LEN],
+            machine: [0; UTSNAME_FIELD_LEN],
+            domainname: [0; UTSNAME_FIELD_LEN],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: i16,
+    pub revents: i16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FdSet {
+    pub bits: [u64; FD_SETSIZE / 64],
+}
+
+impl FdSet {
+    pub const fn new() -> Self {
+        Self {
+            bits: [0; FD_SETSIZE / 64],
+        }
+    }
+
+    pub fn insert(&mut self, fd: usize) {
+        self.bits[fd / 64] |= 1 << (fd % 64);
+    }
+
+    pub fn contains(&self, fd: usize) -> bool {
+        self.bits[fd / 64] & (1 << (fd % 64)) != 0
+    }
+}
+
+impl Default for FdSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `struct epoll_event`, which x86_64 Linux declares packed.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EpollEvent {
+    pub events: u32,
+    pub data: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+/// Resource usage as getrusage(2) and wait4(2) report it. Only CPU time is
+/// accounted; the counters always come back zeroed.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rusage {
+    pub ru_utime: Timeval,
+    pub ru_stime: Timeval,
+    // ru_maxrss through ru_nivcsw.
+    pub ru_counters: [i64; 14],
+}
+
+/// CPU time in clock ticks, as times(2) reports it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tms {
+    pub tms_utime: i64,
+    pub tms_stime: i64,
+    pub tms_cutime: i64,
+    pub tms_cstime: i64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Statfs {
+    pub f_type: i64,
+    pub f_bsize: i64,
+    pub f_blocks: u64,
+    pub f_bfree: u64,
+    pub f_bavail: u64,
+    pub f_files: u64,
+    pub f_ffree: u64,
+    pub f_fsid: [i32; 2],
+    pub f_namelen: i64,
+    pub f_frsize: i64,
+    pub f_flags: i64,
+    pub f_spare: [i64; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Itimerspec {
+    pub it_interval: Timespec,
+    pub it_value: Timespec,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SockaddrUn {
+    pub sun_family: u16,
+    pub sun_path: [u8; 108],
+}
+
+impl SockaddrUn {
+    pub const fn new() -> Self {
+        Self {
+            sun_family: AF_UNIX as u16,
+            sun_path: [0; 108],
+        }
+    }
+}
+
+impl Default for SockaddrUn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Port and address are stored in network byte order.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SockaddrIn {
+    pub sin_family: u16,
+    pub sin_port: u16,
+    pub sin_addr: u32,
+    pub sin_zero: [u8; 8],
+}
+
+impl SockaddrIn {
+    pub const fn new(addr: u32, port: u16) -> Self {
+        Self {
+            sin_family: AF_INET as u16,
+            sin_port: port.to_be(),
+            sin_addr: addr.to_be(),
+            sin_zero: [0; 8],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Iovec {
+    pub iov_base: *mut u8,
+    pub iov_len: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Msghdr {
+    pub msg_name: *mut u8,
+    pub msg_namelen: u32,
+    pub msg_iov: *mut Iovec,
+    pub msg_iovlen: usize,
+    pub msg_control: *mut u8,
+    pub msg_controllen: usize,
+    pub msg_flags: i32,
+}
+
+pub const NCCS: usize = 19;
+
+/// Kernel-side `struct termios` as exchanged by TCGETS.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; NCCS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Winsize {
+    pub ws_row: u16,
+    pub ws_col: u16,
+    pub ws_xpixel: u16,
+    pub ws_ypixel: u16,
+}
 
 pub fn init() {
     handlers::install();
@@ -41,13 +458,483 @@ pub fn syscall6(nr: u64, a0: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64) -
 }
 
 pub fn write(fd: u64, buf: &[u8]) -> i64 {
-    syscall6(SYS_WRITE, fd, buf.as_ptr() as u64, buf.len() as u64, 0, 0, 0)
+    syscall6(
+        SYS_WRITE,
+        fd,
+        buf.as_ptr() as u64,
+        buf.len() as u64,
+        0,
+        0,
+        0,
+    )
 }
 
 pub fn getpid() -> i64 {
     syscall6(SYS_GETPID, 0, 0, 0, 0, 0, 0)
 }
 
+pub fn getppid() -> i64 {
+    syscall6(SYS_GETPPID, 0, 0, 0, 0, 0, 0)
+}
+
+pub fn kill(pid: i64, signal: u64) -> i64 {
+    syscall6(SYS_KILL, pid as u64, signal, 0, 0, 0, 0)
+}
+
+pub fn uname(buf: &mut Utsname) -> i64 {
+    syscall6(SYS_UNAME, buf as *mut Utsname as u64, 0, 0, 0, 0, 0)
+}
+
+pub fn getuid() -> i64 {
+    syscall6(SYS_GETUID, 0, 0, 0, 0, 0, 0)
+}
+
+pub fn geteuid() -> i64 {
+    syscall6(SYS_GETEUID, 0, 0, 0, 0, 0, 0)
+}
+
+pub fn getgid() -> i64 {
+    syscall6(SYS_GETGID, 0, 0, 0, 0, 0, 0)
+}
+
+pub fn getegid() -> i64 {
+    syscall6(SYS_GETEGID, 0, 0, 0, 0, 0, 0)
+}
+
+pub fn getgroups(list: &mut [u32]) -> i64 {
+    syscall6(
+        SYS_GETGROUPS,
+        list.len() as u64,
+        list.as_mut_ptr() as u64,
+        0,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn seccomp(operation: u32, flags: u32, prog: Option<&SockFprog>) -> i64 {
+    let prog = prog.map_or(0, |prog| prog as *const SockFprog as u64);
+    syscall6(SYS_SECCOMP, operation as u64, flags as u64, prog, 0, 0, 0)
+}
+
+pub fn getrandom(buf: &mut [u8], flags: u64) -> i64 {
+    syscall6(
+        SYS_GETRANDOM,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+        flags,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> i64 {
+    syscall6(
+        SYS_READ,
+        fd,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn open(path: &CStr, flags: u64, mode: u32) -> i64 {
+    syscall6(SYS_OPEN, path.as_ptr() as u64, flags, mode as u64, 0, 0, 0)
+}
+
+pub fn openat(dirfd: i64, path: &CStr, flags: u64, mode: u32) -> i64 {
+    syscall6(
+        SYS_OPENAT,
+        dirfd as u64,
+        path.as_ptr() as u64,
+        flags,
+        mode as u64,
+        0,
+        0,
+    )
+}
+
+pub fn close(fd: u64) -> i64 {
+    syscall6(SYS_CLOSE, fd, 0, 0, 0, 0, 0)
+}
+
+pub fn lseek(fd: u64, offset: i64, whence: u64) -> i64 {
+    syscall6(SYS_LSEEK, fd, offset as u64, whence, 0, 0, 0)
+}
+
+pub fn statfs(path: &CStr, buf: &mut Statfs) -> i64 {
+    syscall6(
+        SYS_STATFS,
+        path.as_ptr() as u64,
+        buf as *mut Statfs as u64,
+        0,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn fstatfs(fd: u64, buf: &mut Statfs) -> i64 {
+    syscall6(SYS_FSTATFS, fd, buf as *mut Statfs as u64, 0, 0, 0, 0)
+}
+
+pub fn sendfile(out_fd: u64, in_fd: u64, offset: Option<&mut i64>, count: usize) -> i64 {
+    let offset = offset.map_or(0, |offset| offset as *mut i64 as u64);
+    syscall6(SYS_SENDFILE, out_fd, in_fd, offset, count as u64, 0, 0)
+}
+
+pub fn ioctl(fd: u64, request: u64, arg: u64) -> i64 {
+    syscall6(SYS_IOCTL, fd, request, arg, 0, 0, 0)
+}
+
+pub fn poll(fds: &mut [PollFd], timeout_ms: i32) -> i64 {
+    syscall6(
+        SYS_POLL,
+        fds.as_mut_ptr() as u64,
+        fds.len() as u64,
+        timeout_ms as u64,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn select(
+    nfds: usize,
+    readfds: Option<&mut FdSet>,
+    writefds: Option<&mut FdSet>,
+    exceptfds: Option<&mut FdSet>,
+    timeout: Option<&mut Timeval>,
+) -> i64 {
+    let set = |set: Option<&mut FdSet>| set.map_or(0, |set| set as *mut FdSet as u64);
+    let timeout = timeout.map_or(0, |timeout| timeout as *mut Timeval as u64);
+    syscall6(
+        SYS_SELECT,
+        nfds as u64,
+        set(readfds),
+        set(writefds),
+        set(exceptfds),
+        timeout,
+        0,
+    )
+}
+
+pub fn eventfd(initval: u32, flags: u64) -> i64 {
+    syscall6(SYS_EVENTFD2, initval as u64, flags, 0, 0, 0, 0)
+}
+
+pub fn clock_gettime(clockid: u64, ts: &mut Timespec) -> i64 {
+    syscall6(
+        SYS_CLOCK_GETTIME,
+        clockid,
+        ts as *mut Timespec as u64,
+        0,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn timerfd_create(clockid: u64, flags: u64) -> i64 {
+    syscall6(SYS_TIMERFD_CREATE, clockid, flags, 0, 0, 0, 0)
+}
+
+pub fn timerfd_settime(fd: u64, flags: u64, new: &Itimerspec, old: Option<&mut Itimerspec>) -> i64 {
+    let new = new as *const Itimerspec as u64;
+    let old = old.map_or(0, |old| old as *mut Itimerspec as u64);
+    syscall6(SYS_TIMERFD_SETTIME, fd, flags, new, old, 0, 0)
+}
+
+pub fn timerfd_gettime(fd: u64, current: &mut Itimerspec) -> i64 {
+    syscall6(
+        SYS_TIMERFD_GETTIME,
+        fd,
+        current as *mut Itimerspec as u64,
+        0,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn socket(domain: u64, ty: u64, protocol: u64) -> i64 {
+    syscall6(SYS_SOCKET, domain, ty, protocol, 0, 0, 0)
+}
+
+pub fn bind(fd: u64, addr: &SockaddrUn, len: usize) -> i64 {
+    syscall6(
+        SYS_BIND,
+        fd,
+        addr as *const SockaddrUn as u64,
+        len as u64,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn bind_in(fd: u64, addr: &SockaddrIn) -> i64 {
+    syscall6(
+        SYS_BIND,
+        fd,
+        addr as *const SockaddrIn as u64,
+        size_of::<SockaddrIn>() as u64,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn listen(fd: u64, backlog: i32) -> i64 {
+    syscall6(SYS_LISTEN, fd, backlog as u64, 0, 0, 0, 0)
+}
+
+pub fn connect(fd: u64, addr: &SockaddrUn, len: usize) -> i64 {
+    syscall6(
+        SYS_CONNECT,
+        fd,
+        addr as *const SockaddrUn as u64,
+        len as u64,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn connect_in(fd: u64, addr: &SockaddrIn) -> i64 {
+    syscall6(
+        SYS_CONNECT,
+        fd,
+        addr as *const SockaddrIn as u64,
+        size_of::<SockaddrIn>() as u64,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn accept4(fd: u64, flags: u64) -> i64 {
+    syscall6(SYS_ACCEPT4, fd, 0, 0, flags, 0, 0)
+}
+
+pub fn sendmsg(fd: u64, msg: &Msghdr, flags: u64) -> i64 {
+    syscall6(SYS_SENDMSG, fd, msg as *const Msghdr as u64, flags, 0, 0, 0)
+}
+
+pub fn recvmsg(fd: u64, msg: &mut Msghdr, flags: u64) -> i64 {
+    syscall6(SYS_RECVMSG, fd, msg as *mut Msghdr as u64, flags, 0, 0, 0)
+}
+
+pub fn epoll_create1(flags: u64) -> i64 {
+    syscall6(SYS_EPOLL_CREATE1, flags, 0, 0, 0, 0, 0)
+}
+
+pub fn epoll_ctl(epfd: u64, op: u64, fd: u64, event: Option<&EpollEvent>) -> i64 {
+    let event = event.map_or(0, |event| event as *const EpollEvent as u64);
+    syscall6(SYS_EPOLL_CTL, epfd, op, fd, event, 0, 0)
+}
+
+pub fn epoll_wait(epfd: u64, events: &mut [EpollEvent], timeout_ms: i32) -> i64 {
+    syscall6(
+        SYS_EPOLL_WAIT,
+        epfd,
+        events.as_mut_ptr() as u64,
+        events.len() as u64,
+        timeout_ms as u64,
+        0,
+        0,
+    )
+}
+
+pub fn getcwd(buf: &mut [u8]) -> i64 {
+    syscall6(
+        SYS_GETCWD,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+        0,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn chdir(path: &CStr) -> i64 {
+    syscall6(SYS_CHDIR, path.as_ptr() as u64, 0, 0, 0, 0, 0)
+}
+
+pub fn umask(mask: u32) -> i64 {
+    syscall6(SYS_UMASK, mask as u64, 0, 0, 0, 0, 0)
+}
+
+pub fn mkdir(path: &CStr, mode: u32) -> i64 {
+    syscall6(SYS_MKDIR, path.as_ptr() as u64, mode as u64, 0, 0, 0, 0)
+}
+
+pub fn rmdir(path: &CStr) -> i64 {
+    syscall6(SYS_RMDIR, path.as_ptr() as u64, 0, 0, 0, 0, 0)
+}
+
+pub fn unlink(path: &CStr) -> i64 {
+    syscall6(SYS_UNLINK, path.as_ptr() as u64, 0, 0, 0, 0, 0)
+}
+
+pub fn rename(from: &CStr, to: &CStr) -> i64 {
+    syscall6(
+        SYS_RENAME,
+        from.as_ptr() as u64,
+        to.as_ptr() as u64,
+        0,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn truncate(path: &CStr, len: i64) -> i64 {
+    syscall6(SYS_TRUNCATE, path.as_ptr() as u64, len as u64, 0, 0, 0, 0)
+}
+
+pub fn ftruncate(fd: u64, len: i64) -> i64 {
+    syscall6(SYS_FTRUNCATE, fd, len as u64, 0, 0, 0, 0)
+}
+
+pub fn access(path: &CStr, mode: u64) -> i64 {
+    syscall6(SYS_ACCESS, path.as_ptr() as u64, mode, 0, 0, 0, 0)
+}
+
+pub fn faccessat(dirfd: i64, path: &CStr, mode: u64, flags: u64) -> i64 {
+    syscall6(
+        SYS_FACCESSAT,
+        dirfd as u64,
+        path.as_ptr() as u64,
+        mode,
+        flags,
+        0,
+        0,
+    )
+}
+
+pub fn readlink(path: &CStr, buf: &mut [u8]) -> i64 {
+    syscall6(
+        SYS_READLINK,
+        path.as_ptr() as u64,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn readlinkat(dirfd: i64, path: &CStr, buf: &mut [u8]) -> i64 {
+    syscall6(
+        SYS_READLINKAT,
+        dirfd as u64,
+        path.as_ptr() as u64,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+        0,
+        0,
+    )
+}
+
+pub fn getrlimit(resource: usize, limit: &mut Rlimit) -> i64 {
+    syscall6(
+        SYS_GETRLIMIT,
+        resource as u64,
+        limit as *mut Rlimit as u64,
+        0,
+        0,
+        0,
+        0,
+    )
+}
+
+pub fn setrlimit(resource: usize, limit: &Rlimit) -> i64 {
+    syscall6(
+        SYS_SETRLIMIT,
+        resource as u64,
+        limit as *const Rlimit as u64,
+        0,
+        0,
+        0,
+        0,
+    )
+}
+
+/// Raw getpriority(2): `20 - nice`, so success is never negative.
+pub fn getpriority(which: u64, who: usize) -> i64 {
+    syscall6(SYS_GETPRIORITY, which, who as u64, 0, 0, 0, 0)
+}
+
+pub fn setpriority(which: u64, who: usize, nice: i32) -> i64 {
+    syscall6(SYS_SETPRIORITY, which, who as u64, nice as u64, 0, 0, 0)
+}
+
+/// Raw sched_setaffinity(2) with a mask of `mask.len() * 64` CPUs.
+pub fn sched_setaffinity(pid: usize, mask: &[u64]) -> i64 {
+    let len = size_of_val(mask) as u64;
+    syscall6(
+        SYS_SCHED_SETAFFINITY,
+        pid as u64,
+        len,
+        mask.as_ptr() as u64,
+        0,
+        0,
+        0,
+    )
+}
+
+/// Raw sched_getaffinity(2): the number of bytes of `mask` filled in.
+pub fn sched_getaffinity(pid: usize, mask: &mut [u64]) -> i64 {
+    let len = size_of_val(mask) as u64;
+    syscall6(
+        SYS_SCHED_GETAFFINITY,
+        pid as u64,
+        len,
+        mask.as_mut_ptr() as u64,
+        0,
+        0,
+        0,
+    )
+}
+
+/// Raw wait4(2): the pid of the reaped child, 0 under `WNOHANG` while none
+/// has exited.
+pub fn wait4(pid: i64, status: Option<&mut i32>, options: u64, rusage: Option<&mut Rusage>) -> i64 {
+    let status = status.map_or(0, |status| status as *mut i32 as u64);
+    let rusage = rusage.map_or(0, |rusage| rusage as *mut Rusage as u64);
+    syscall6(SYS_WAIT4, pid as u64, status, options, rusage, 0, 0)
+}
+
+pub fn getrusage(who: i64, usage: &mut Rusage) -> i64 {
+    syscall6(
+        SYS_GETRUSAGE,
+        who as u64,
+        usage as *mut Rusage as u64,
+        0,
+        0,
+        0,
+        0,
+    )
+}
+
+/// Raw times(2): clock ticks since boot, with the CPU times in `buf`.
+pub fn times(buf: Option<&mut Tms>) -> i64 {
+    let buf = buf.map_or(0, |buf| buf as *mut Tms as u64);
+    syscall6(SYS_TIMES, buf, 0, 0, 0, 0, 0)
+}
+
+pub fn prlimit(pid: usize, resource: usize, new: Option<&Rlimit>, old: Option<&mut Rlimit>) -> i64 {
+    let new = new.map_or(0, |limit| limit as *const Rlimit as u64);
+    let old = old.map_or(0, |limit| limit as *mut Rlimit as u64);
+    syscall6(SYS_PRLIMIT64, pid as u64, resource as u64, new, old, 0, 0)
+}
+
 pub fn sched_yield() -> i64 {
     syscall6(SYS_SCHED_YIELD, 0, 0, 0, 0, 0, 0)
 }
@@ -94,7 +981,14 @@ pub fn mmap(addr: usize, len: usize, prot: u64, flags: u64, fd: i64, offset: u64
 }
 
 pub fn mmap_anonymous(len: usize) -> i64 {
-    mmap(0, len, 0, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+    mmap(
+        0,
+        len,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+        -1,
+        0,
+    )
 }
 
 pub fn exit(status: i32) -> ! {
@@ -0,0 +1,153 @@
+use core::arch::{asm, x86_64::__cpuid};
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::arch::{kvmclock, pit};
+use crate::boot::{self, PvClock};
+use crate::memory::address::DirectMap;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Assumed when neither CPUID nor the PIT gives the TSC frequency.
+const FALLBACK_TSC_HZ: u64 = 1_000_000_000;
+
+/// Clock ticks per second in which times(2) and `/proc` report CPU time, as
+/// Linux's `USER_HZ`.
+pub const USER_HZ: u64 = 100;
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+// Wall-clock time as the guest powered on, in nanoseconds since the epoch.
+static BOOT_WALL_CLOCK: AtomicU64 = AtomicU64::new(0);
+// The kvmclock the clock reads, once `init` has found KVM keeping one.
+static PVCLOCK: AtomicPtr<PvClock> = AtomicPtr::new(core::ptr::null_mut());
+
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack));
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+/// Settle the clock: kvmclock when the VM set it up, which KVM keeps in step
+/// with the host and which gives the TSC frequency too. Otherwise the TSC,
+/// its frequency exact when CPUID leaf 0x15 reports it and counted against
+/// the PIT when not. The realtime clock counts on from `wall_clock`, the
+/// time the VM powered the guest on at. Runs once at boot, before anything
+/// reads the clock; until then the clock is the TSC at the frequency CPUID
+/// gives, and the realtime clock starts at the epoch.
+pub fn init(map: &impl DirectMap, wall_clock: Duration) {
+    BOOT_WALL_CLOCK.store(wall_clock.as_nanos() as u64, Ordering::Relaxed);
+    // The boot vCPU's, as it is the only one running the kernel.
+    let clock = boot::pvclock(map, 0);
+    // SAFETY: the boot info is always mapped.
+    if let Some(snapshot) = unsafe { kvmclock::snapshot(clock) } {
+        TSC_HZ.store(snapshot.tsc_hz(), Ordering::Relaxed);
+        PVCLOCK.store(clock.cast_mut(), Ordering::Relaxed);
+        return;
+    }
+    let hz = crystal_tsc_hz()
+        .or_else(pit::measure_tsc_hz)
+        .or_else(nominal_tsc_hz)
+        .unwrap_or(FALLBACK_TSC_HZ);
+    TSC_HZ.store(hz, Ordering::Relaxed);
+}
+
+/// Time elapsed since the guest powered on.
+pub fn monotonic() -> Duration {
+    let clock = PVCLOCK.load(Ordering::Relaxed);
+    // SAFETY: only ever set to the boot info's kvmclock.
+    if !clock.is_null()
+        && let Some(snapshot) = unsafe { kvmclock::snapshot(clock) }
+    {
+        return Duration::from_nanos(snapshot.nanos_at(rdtsc()));
+    }
+    ticks_to_duration(rdtsc(), tsc_hz())
+}
+
+/// Wall-clock time as the guest powered on, since the Unix epoch.
+pub fn boot_wall_clock() -> Duration {
+    Duration::from_nanos(BOOT_WALL_CLOCK.load(Ordering::Relaxed))
+}
+
+/// Wall-clock time since the Unix epoch: [`monotonic`] time on from
+/// [`boot_wall_clock`]. The guest never sets it, so it only moves forward.
+pub fn realtime() -> Duration {
+    boot_wall_clock() + monotonic()
+}
+
+/// [`monotonic`] in nanoseconds.
+pub fn monotonic_nanos() -> u64 {
+    monotonic().as_nanos() as u64
+}
+
+pub fn tsc_hz() -> u64 {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => {
+            let hz = crystal_tsc_hz()
+                .or_else(nominal_tsc_hz)
+                .unwrap_or(FALLBACK_TSC_HZ);
+            TSC_HZ.store(hz, Ordering::Relaxed);
+            hz
+        }
+        hz => hz,
+    }
+}
+
+/// `duration` in [`USER_HZ`] clock ticks, rounded down.
+pub fn clock_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * USER_HZ as u128 / NANOS_PER_SEC as u128) as u64
+}
+
+// Leaf 0x15 gives the exact crystal ratio, but is often left out under
+// nested virtualization.
+fn crystal_tsc_hz() -> Option<u64> {
+    if __cpuid(0).eax < 0x15 {
+        return None;
+    }
+    let leaf = __cpuid(0x15);
+    (leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0)
+        .then(|| leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64)
+}
+
+// Leaf 0x16 only gives the nominal base frequency in MHz.
+fn nominal_tsc_hz() -> Option<u64> {
+    if __cpuid(0).eax < 0x16 {
+        return None;
+    }
+    let mhz = __cpuid(0x16).eax & 0xffff;
+    (mhz != 0).then(|| mhz as u64 * 1_000_000)
+}
+
+fn ticks_to_duration(ticks: u64, hz: u64) -> Duration {
+    let nanos = (ticks % hz) as u128 * NANOS_PER_SEC as u128 / hz as u128;
+    Duration::new(ticks / hz, nanos as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_convert_without_overflow() {
+        let hz = 3_000_000_000;
+        assert_eq!(ticks_to_duration(hz / 2, hz), Duration::from_millis(500));
+        assert_eq!(
+            ticks_to_duration(u64::MAX, hz),
+            Duration::new(u64::MAX / hz, 236_517_205)
+        );
+    }
+
+    #[test]
+    fn clock_ticks_round_down() {
+        assert_eq!(clock_ticks(Duration::from_millis(19)), 1);
+        assert_eq!(clock_ticks(Duration::from_secs(3)), 300);
+    }
+
+    #[test]
+    fn monotonic_never_goes_backwards() {
+        let first = monotonic();
+        assert!(monotonic() >= first);
+    }
+}
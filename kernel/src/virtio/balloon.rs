@@ -0,0 +1,99 @@
+use spin::Mutex;
+
+use super::driver::{QUEUE_SIZE, Queue, Transport};
+use super::{
+    BALLOON_F_REPORTING, BALLOON_REPORTING_QUEUE, BALLOON_SLOT, DESC_F_WRITE, DEVICE_BALLOON,
+};
+use crate::fs::errors::Result;
+use crate::memory::{address::DirectMap, alloc::palloc::PageAllocator, constants::PAGE_SIZE};
+
+/// Pages freed between reports: 8 MiB, so a report, which hands the host
+/// every free block the guest has touched, comes once for a process's worth
+/// of memory rather than once a page.
+const REPORT_THRESHOLD: usize = 4;
+
+static BALLOON: Mutex<Option<VirtioBalloon>> = Mutex::new(None);
+
+/// The VMM's balloon, used only to report free pages: the host drops the
+/// memory behind them, so what hostel holds on to follows what the guest
+/// uses rather than the most it ever used. Nothing is inflated; the guest
+/// keeps every page and finds those it reported zeroed when it uses them
+/// again. The device is done with a report by the time its notification
+/// returns, so the driver never waits on interrupts.
+pub struct VirtioBalloon {
+    transport: Transport,
+    queue: Queue,
+}
+
+// SAFETY: the registers and the queue are only reached through the
+// balloon's lock.
+unsafe impl Send for VirtioBalloon {}
+
+impl VirtioBalloon {
+    /// Bring up the balloon device if the VMM has one that takes reports.
+    pub fn probe(palloc: &PageAllocator, dm: &impl DirectMap) -> Result<Option<Self>> {
+        let Some(mut transport) = Transport::find(BALLOON_SLOT, DEVICE_BALLOON, dm) else {
+            return Ok(None);
+        };
+        let Some(features) = transport.negotiate(BALLOON_F_REPORTING) else {
+            return Ok(None);
+        };
+        if features & BALLOON_F_REPORTING == 0 {
+            return Ok(None);
+        }
+        let Some([queue]) = transport.setup_queues([BALLOON_REPORTING_QUEUE], palloc, dm)? else {
+            return Ok(None);
+        };
+        transport.start();
+        Ok(Some(Self { transport, queue }))
+    }
+
+    /// Report every free block `palloc` has that the guest has touched, a
+    /// queue's worth at a time.
+    pub fn report(&mut self, palloc: &PageAllocator) {
+        palloc.report_free(|blocks| {
+            let mut batch = 0;
+            for (addr, pages) in blocks {
+                self.queue
+                    .offer_memory(batch, addr, pages * PAGE_SIZE, DESC_F_WRITE);
+                batch += 1;
+                if batch == QUEUE_SIZE {
+                    self.flush();
+                    batch = 0;
+                }
+            }
+            if batch != 0 {
+                self.flush();
+            }
+        });
+    }
+
+    // Have the device take the blocks offered so far. It hands every one
+    // back before the notification returns, leaving each descriptor free
+    // for the next batch.
+    fn flush(&mut self) {
+        self.transport.notify(BALLOON_REPORTING_QUEUE);
+        while self.queue.take_used().is_some() {}
+    }
+}
+
+/// Report freed memory to the VMM's balloon from now on, if it has one.
+pub fn attach(palloc: &PageAllocator, dm: &impl DirectMap) -> Result<()> {
+    let Some(balloon) = VirtioBalloon::probe(palloc, dm)? else {
+        return Ok(());
+    };
+    *BALLOON.lock() = Some(balloon);
+    palloc.set_free_reporter(report_free, REPORT_THRESHOLD);
+    crate::println!("balloon: reporting free memory to the host");
+    Ok(())
+}
+
+// A free that lands while a report is under way, as from an interrupt,
+// leaves it be; the next one reports instead.
+fn report_free(palloc: &PageAllocator) {
+    if let Some(mut balloon) = BALLOON.try_lock()
+        && let Some(balloon) = balloon.as_mut()
+    {
+        balloon.report(palloc);
+    }
+}
@@ -0,0 +1,101 @@
+use core::fmt;
+
+use super::driver::{BUFFER_SIZE, Queue, Transport};
+use super::{
+    CONSOLE_CONFIG_MAX_NR_PORTS, CONSOLE_F_MULTIPORT, CONSOLE_PORT_LOG, CONSOLE_PORT_OUTPUT,
+    CONSOLE_SLOT, DEVICE_CONSOLE, console_tx_queue,
+};
+use crate::fs::errors::Result;
+use crate::memory::{address::DirectMap, alloc::palloc::PageAllocator};
+
+/// The VMM's virtio console, for output only: a write costs the VMM one
+/// exit rather than one per byte. Input stays with COM1.
+///
+/// The driver skips the control handshake, so it never learns port names
+/// or whether the host has opened them; the VMM takes every port it offers
+/// as open.
+pub struct VirtioConsole {
+    transport: Transport,
+    log: Queue,
+    // Programs' output, when the console has a port for it.
+    output: Option<Queue>,
+}
+
+// SAFETY: the registers and queues are only reached through the console's
+// lock.
+unsafe impl Send for VirtioConsole {}
+
+impl VirtioConsole {
+    /// Bring up the console device if the VMM has one.
+    pub fn probe(palloc: &PageAllocator, dm: &impl DirectMap) -> Result<Option<Self>> {
+        let Some(mut transport) = Transport::find(CONSOLE_SLOT, DEVICE_CONSOLE, dm) else {
+            return Ok(None);
+        };
+        let Some(features) = transport.negotiate(CONSOLE_F_MULTIPORT) else {
+            return Ok(None);
+        };
+        let ports = if features & CONSOLE_F_MULTIPORT != 0 {
+            u32::from_le_bytes(core::array::from_fn(|i| {
+                transport.config_byte(CONSOLE_CONFIG_MAX_NR_PORTS + i)
+            }))
+        } else {
+            1
+        };
+        let log_queue = console_tx_queue(CONSOLE_PORT_LOG);
+        let (log, output) = if ports > CONSOLE_PORT_OUTPUT {
+            let output_queue = console_tx_queue(CONSOLE_PORT_OUTPUT);
+            match transport.setup_queues([log_queue, output_queue], palloc, dm)? {
+                Some([log, output]) => (log, Some(output)),
+                None => return Ok(None),
+            }
+        } else {
+            match transport.setup_queues([log_queue], palloc, dm)? {
+                Some([log]) => (log, None),
+                None => return Ok(None),
+            }
+        };
+        transport.start();
+        Ok(Some(Self {
+            transport,
+            log,
+            output,
+        }))
+    }
+
+    /// Send kernel messages.
+    pub fn write_log(&mut self, bytes: &[u8]) {
+        send(&self.transport, &mut self.log, CONSOLE_PORT_LOG, bytes);
+    }
+
+    /// Send what a program wrote to its console, to the log's port if there
+    /// is no other.
+    pub fn write_output(&mut self, bytes: &[u8]) {
+        match &mut self.output {
+            Some(output) => send(&self.transport, output, CONSOLE_PORT_OUTPUT, bytes),
+            None => self.write_log(bytes),
+        }
+    }
+}
+
+impl fmt::Write for VirtioConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_log(s.as_bytes());
+        Ok(())
+    }
+}
+
+fn send(transport: &Transport, queue: &mut Queue, port: u32, bytes: &[u8]) {
+    for chunk in bytes.chunks(BUFFER_SIZE) {
+        // The VMM hands buffers back as it is notified of them, so one is
+        // free by the time the next is wanted.
+        let id = loop {
+            if let Some(id) = queue.take_free() {
+                break id;
+            }
+            core::hint::spin_loop();
+        };
+        queue.buffer(id)[..chunk.len()].copy_from_slice(chunk);
+        queue.offer(id, chunk.len(), 0);
+        transport.notify(console_tx_queue(port));
+    }
+}
@@ -0,0 +1,302 @@
+//! What the kernel's virtio drivers share: bringing a device up through its
+//! registers, and virtqueues with a buffer of their own behind each
+//! descriptor. Drivers poll; the devices' interrupts stay masked.
+
+use core::ptr;
+use core::sync::atomic::{Ordering, fence};
+
+use super::{
+    DESC_F_NEXT, DESC_F_WRITE, Descriptor, F_VERSION_1, RING_ENTRIES, RING_IDX, UsedElem, reg,
+    slot_phys,
+};
+use crate::fs::errors::{FsError, Result};
+use crate::memory::{
+    address::{DirectMap, PhysicalAddr, VirtualAddr},
+    alloc::palloc::PageAllocator,
+    constants::PAGE_SIZE,
+};
+
+/// Entries in every queue a driver sets up.
+pub const QUEUE_SIZE: u16 = 16;
+/// Each descriptor's buffer.
+pub const BUFFER_SIZE: usize = 2048;
+
+// Where a queue's parts sit in its share of the driver's page.
+const DESC_OFFSET: usize = 0;
+const AVAIL_OFFSET: usize = 0x1000;
+const USED_OFFSET: usize = 0x2000;
+const BUFFERS_OFFSET: usize = 0x3000;
+const QUEUE_AREA: usize = 0x10000;
+const _: () = assert!(BUFFERS_OFFSET + QUEUE_SIZE as usize * BUFFER_SIZE <= QUEUE_AREA);
+
+/// A device's registers, from finding it to handing it its queues.
+pub struct Transport {
+    regs: VirtualAddr,
+    status: u32,
+}
+
+impl Transport {
+    /// The device in `slot`, if the VMM put one of type `device_type`
+    /// there, reset and acknowledged.
+    pub fn find(slot: usize, device_type: u32, dm: &impl DirectMap) -> Option<Self> {
+        let regs = slot_phys(slot).to_virtual(dm);
+        if read_reg(regs, reg::MAGIC_VALUE) != super::MAGIC
+            || read_reg(regs, reg::VERSION) != super::VERSION
+            || read_reg(regs, reg::DEVICE_ID) != device_type
+        {
+            return None;
+        }
+        let mut transport = Self { regs, status: 0 };
+        transport.set_status(0);
+        transport.set_status(super::STATUS_ACKNOWLEDGE | super::STATUS_DRIVER);
+        Some(transport)
+    }
+
+    /// Take those of `wanted` the device offers, returning them. Devices
+    /// that do not follow virtio 1.x, or refuse the choice, are failed.
+    pub fn negotiate(&mut self, wanted: u64) -> Option<u64> {
+        let mut offered = 0;
+        for sel in [1, 0] {
+            self.write(reg::DEVICE_FEATURES_SEL, sel);
+            offered = (offered << 32) | self.read(reg::DEVICE_FEATURES) as u64;
+        }
+        if offered & F_VERSION_1 == 0 {
+            self.fail();
+            return None;
+        }
+        let features = offered & (wanted | F_VERSION_1);
+        for (sel, half) in [(0, features as u32), (1, (features >> 32) as u32)] {
+            self.write(reg::DRIVER_FEATURES_SEL, sel);
+            self.write(reg::DRIVER_FEATURES, half);
+        }
+        self.set_status(self.status | super::STATUS_FEATURES_OK);
+        if self.read(reg::STATUS) & super::STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return None;
+        }
+        Some(features)
+    }
+
+    /// Set up queues `indices` on a page of their own that is never given
+    /// back, empty. The device is failed, and the page freed, if it cannot
+    /// take them.
+    pub fn setup_queues<const N: usize>(
+        &mut self,
+        indices: [u32; N],
+        palloc: &PageAllocator,
+        dm: &impl DirectMap,
+    ) -> Result<Option<[Queue; N]>> {
+        const { assert!(N * QUEUE_AREA <= PAGE_SIZE) };
+        let page = palloc.alloc(1).map_err(|_| FsError::NoSpace)?;
+        let virt = page.to_virtual(dm);
+        // SAFETY: the page was just allocated. Zeroing the queues' areas
+        // leaves their rings empty.
+        unsafe { ptr::write_bytes(virt.as_ptr::<u8>(), 0, N * QUEUE_AREA) };
+        let queues: [Queue; N] = core::array::from_fn(|i| {
+            Queue::new(virt.add(i * QUEUE_AREA), page.add(i * QUEUE_AREA))
+        });
+        for (index, queue) in indices.into_iter().zip(&queues) {
+            self.write(reg::QUEUE_SEL, index);
+            if self.read(reg::QUEUE_NUM_MAX) < QUEUE_SIZE as u32 {
+                self.fail();
+                let _ = palloc.free(page);
+                return Ok(None);
+            }
+            self.write(reg::QUEUE_NUM, QUEUE_SIZE as u32);
+            for (low, offset) in [
+                (reg::QUEUE_DESC_LOW, DESC_OFFSET),
+                (reg::QUEUE_DRIVER_LOW, AVAIL_OFFSET),
+                (reg::QUEUE_DEVICE_LOW, USED_OFFSET),
+            ] {
+                let addr = queue.phys.add(offset).as_u64();
+                self.write(low, addr as u32);
+                self.write(low + 4, (addr >> 32) as u32);
+            }
+            self.write(reg::QUEUE_READY, 1);
+        }
+        Ok(Some(queues))
+    }
+
+    /// Byte `offset` of the device-specific configuration.
+    pub fn config_byte(&self, offset: usize) -> u8 {
+        // SAFETY: the configuration space is device memory the VMM reads
+        // back a byte at a time.
+        unsafe { ptr::read_volatile(self.regs.add(reg::CONFIG as usize + offset).as_ptr::<u8>()) }
+    }
+
+    /// Hand the device over to the driver, which may use its queues from
+    /// now on.
+    pub fn start(&mut self) {
+        self.set_status(self.status | super::STATUS_DRIVER_OK);
+    }
+
+    /// Tell the device queue `index` has new buffers.
+    pub fn notify(&self, index: u32) {
+        self.write(reg::QUEUE_NOTIFY, index);
+    }
+
+    fn fail(&mut self) {
+        self.set_status(self.status | super::STATUS_FAILED);
+    }
+
+    fn set_status(&mut self, status: u32) {
+        self.status = status;
+        self.write(reg::STATUS, status);
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        read_reg(self.regs, offset)
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        // SAFETY: as for `read_reg`.
+        unsafe { ptr::write_volatile(self.regs.add(offset as usize).as_ptr::<u32>(), value) }
+    }
+}
+
+fn read_reg(regs: VirtualAddr, offset: u64) -> u32 {
+    // SAFETY: `regs` maps a device's registers, which the VMM emulates.
+    unsafe { ptr::read_volatile(regs.add(offset as usize).as_ptr::<u32>()) }
+}
+
+/// One virtqueue with a buffer of its own behind each descriptor, so a
+/// descriptor's index is also its buffer's.
+pub struct Queue {
+    virt: VirtualAddr,
+    phys: PhysicalAddr,
+    // The next free entry of the available ring, and the next used-ring
+    // entry not yet seen.
+    avail_idx: u16,
+    used_idx: u16,
+    // Descriptors the device does not have, used as a stack. Only queues
+    // the driver sends on keep it up to date.
+    free: [u16; QUEUE_SIZE as usize],
+    free_len: usize,
+}
+
+impl Queue {
+    fn new(virt: VirtualAddr, phys: PhysicalAddr) -> Self {
+        Self {
+            virt,
+            phys,
+            avail_idx: 0,
+            used_idx: 0,
+            free: core::array::from_fn(|i| i as u16),
+            free_len: QUEUE_SIZE as usize,
+        }
+    }
+
+    pub fn buffer(&mut self, id: u16) -> &mut [u8; BUFFER_SIZE] {
+        let offset = BUFFERS_OFFSET + id as usize * BUFFER_SIZE;
+        // SAFETY: the buffer lies in the queue's area, which only this
+        // queue uses, and the device is not using descriptor `id`.
+        unsafe { &mut *self.virt.add(offset).as_ptr() }
+    }
+
+    /// Buffers `id` onwards, `count` of them, which lie back to back, as
+    /// one.
+    pub fn buffers(&mut self, id: u16, count: u16) -> &mut [u8] {
+        assert!(id + count <= QUEUE_SIZE);
+        let offset = BUFFERS_OFFSET + id as usize * BUFFER_SIZE;
+        // SAFETY: as for `buffer`, for each of them.
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.virt.add(offset).as_ptr(),
+                count as usize * BUFFER_SIZE,
+            )
+        }
+    }
+
+    /// Hand every buffer to the device to write to, for a queue the driver
+    /// receives on. The caller notifies the device.
+    pub fn offer_all(&mut self) {
+        for id in 0..QUEUE_SIZE {
+            self.offer(id, BUFFER_SIZE, DESC_F_WRITE);
+        }
+        self.free_len = 0;
+    }
+
+    /// A buffer the device is not using, for a queue the driver sends on.
+    pub fn take_free(&mut self) -> Option<u16> {
+        while let Some(used) = self.take_used() {
+            self.free[self.free_len] = used.id as u16;
+            self.free_len += 1;
+        }
+        self.free_len = self.free_len.checked_sub(1)?;
+        Some(self.free[self.free_len])
+    }
+
+    /// Make the first `len` bytes of buffer `id` available to the device.
+    /// The caller notifies it.
+    pub fn offer(&mut self, id: u16, len: usize, flags: u16) {
+        self.describe(id, len, flags, 0);
+        self.publish(id);
+    }
+
+    /// Make a request of the first `len` bytes from buffer `id` on, with
+    /// the first `reply_len` bytes from buffer `reply` on for the device to
+    /// answer in, available as one chain. Either may span buffers, as
+    /// `buffers` hands them out. The caller notifies the device.
+    pub fn offer_request(&mut self, id: u16, len: usize, reply: u16, reply_len: usize) {
+        self.describe(id, len, DESC_F_NEXT, reply);
+        self.describe(reply, reply_len, DESC_F_WRITE, 0);
+        self.publish(id);
+    }
+
+    /// Make `len` bytes at `addr`, memory the driver owns outside the
+    /// queue's buffers, available to the device under descriptor `id`. The
+    /// caller notifies it.
+    pub fn offer_memory(&mut self, id: u16, addr: PhysicalAddr, len: usize, flags: u16) {
+        self.write_descriptor(id, addr, len, flags, 0);
+        self.publish(id);
+    }
+
+    fn describe(&mut self, id: u16, len: usize, flags: u16, next: u16) {
+        let buffer = self.phys.add(BUFFERS_OFFSET + id as usize * BUFFER_SIZE);
+        self.write_descriptor(id, buffer, len, flags, next);
+    }
+
+    fn write_descriptor(&mut self, id: u16, addr: PhysicalAddr, len: usize, flags: u16, next: u16) {
+        let desc = Descriptor {
+            addr: addr.as_u64(),
+            len: len as u32,
+            flags,
+            next,
+        };
+        let table = self.virt.add(DESC_OFFSET).as_ptr::<Descriptor>();
+        // SAFETY: the table has QUEUE_SIZE entries and `id` is below that.
+        unsafe { ptr::write_volatile(table.add(id as usize), desc) };
+    }
+
+    // Put the chain starting at descriptor `id` in the available ring.
+    fn publish(&mut self, id: u16) {
+        let ring = self.virt.add(AVAIL_OFFSET);
+        let slot = RING_ENTRIES as usize + (self.avail_idx % QUEUE_SIZE) as usize * 2;
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        // SAFETY: both lie in the available ring.
+        unsafe {
+            ptr::write_volatile(ring.add(slot).as_ptr::<u16>(), id);
+            // The entry must be visible before the index that covers it.
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(ring.add(RING_IDX as usize).as_ptr::<u16>(), self.avail_idx);
+        }
+    }
+
+    /// The next buffer the device is done with.
+    pub fn take_used(&mut self) -> Option<UsedElem> {
+        let ring = self.virt.add(USED_OFFSET);
+        // SAFETY: both lie in the used ring.
+        unsafe {
+            let idx = ptr::read_volatile(ring.add(RING_IDX as usize).as_ptr::<u16>());
+            if idx == self.used_idx {
+                return None;
+            }
+            // The entry is read only after the index that covers it.
+            fence(Ordering::SeqCst);
+            let slot = RING_ENTRIES as usize
+                + (self.used_idx % QUEUE_SIZE) as usize * size_of::<UsedElem>();
+            self.used_idx = self.used_idx.wrapping_add(1);
+            Some(ptr::read_volatile(ring.add(slot).as_ptr::<UsedElem>()))
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! The parts of virtio the VMM and the kernel's drivers agree on: the
+//! registers of the MMIO transport, version 2, and the layout of split
+//! virtqueues.
+
+use crate::memory::address::PhysicalAddr;
+use crate::memory::constants::VIRTIO_MMIO_PHYS;
+
+pub mod balloon;
+pub mod console;
+pub mod driver;
+pub mod p9;
+pub mod share;
+
+/// Each device's registers take one slot of the virtio MMIO window.
+pub const SLOT_SIZE: usize = 0x1000;
+/// The slot of the network device.
+pub const NET_SLOT: usize = 0;
+/// The slot of the console device.
+pub const CONSOLE_SLOT: usize = 1;
+/// The slot of the 9p device sharing a host directory.
+pub const SHARE_SLOT: usize = 2;
+/// The slot of the balloon device the kernel reports free memory to.
+pub const BALLOON_SLOT: usize = 3;
+
+/// Where the registers of the device in `slot` start.
+pub const fn slot_phys(slot: usize) -> PhysicalAddr {
+    VIRTIO_MMIO_PHYS.add(slot * SLOT_SIZE)
+}
+
+/// "virt", read from `reg::MAGIC_VALUE`. A slot without a device reads 0.
+pub const MAGIC: u32 = 0x7472_6976;
+pub const VERSION: u32 = 2;
+/// "HSTL".
+pub const VENDOR_ID: u32 = 0x4c54_5348;
+
+pub const DEVICE_NET: u32 = 1;
+pub const DEVICE_CONSOLE: u32 = 3;
+pub const DEVICE_BALLOON: u32 = 5;
+pub const DEVICE_9P: u32 = 9;
+
+/// Register offsets into a slot. Everything is 32 bits wide except the
+/// device-specific configuration, which is read in whatever sizes its
+/// fields have.
+pub mod reg {
+    pub const MAGIC_VALUE: u64 = 0x000;
+    pub const VERSION: u64 = 0x004;
+    pub const DEVICE_ID: u64 = 0x008;
+    pub const VENDOR_ID: u64 = 0x00c;
+    pub const DEVICE_FEATURES: u64 = 0x010;
+    pub const DEVICE_FEATURES_SEL: u64 = 0x014;
+    pub const DRIVER_FEATURES: u64 = 0x020;
+    pub const DRIVER_FEATURES_SEL: u64 = 0x024;
+    pub const QUEUE_SEL: u64 = 0x030;
+    pub const QUEUE_NUM_MAX: u64 = 0x034;
+    pub const QUEUE_NUM: u64 = 0x038;
+    pub const QUEUE_READY: u64 = 0x044;
+    pub const QUEUE_NOTIFY: u64 = 0x050;
+    pub const INTERRUPT_STATUS: u64 = 0x060;
+    pub const INTERRUPT_ACK: u64 = 0x064;
+    pub const STATUS: u64 = 0x070;
+    pub const QUEUE_DESC_LOW: u64 = 0x080;
+    pub const QUEUE_DESC_HIGH: u64 = 0x084;
+    pub const QUEUE_DRIVER_LOW: u64 = 0x090;
+    pub const QUEUE_DRIVER_HIGH: u64 = 0x094;
+    pub const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+    pub const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+    pub const CONFIG_GENERATION: u64 = 0x0fc;
+    pub const CONFIG: u64 = 0x100;
+}
+
+// Bits of `reg::STATUS`, set by the driver in this order as it brings the
+// device up. Writing 0 resets the device; the device refuses features it
+// does not offer by leaving FEATURES_OK clear.
+pub const STATUS_ACKNOWLEDGE: u32 = 1;
+pub const STATUS_DRIVER: u32 = 2;
+pub const STATUS_FEATURES_OK: u32 = 8;
+pub const STATUS_DRIVER_OK: u32 = 4;
+pub const STATUS_FAILED: u32 = 128;
+
+/// `reg::INTERRUPT_STATUS` bit for buffers put in a used ring.
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+
+/// The only transport feature: the device follows virtio 1.x rather than
+/// the legacy interface.
+pub const F_VERSION_1: u64 = 1 << 32;
+/// The network device has a MAC address in its configuration.
+pub const NET_F_MAC: u64 = 1 << 5;
+
+/// The console has ports past the first, as many as `max_nr_ports` in its
+/// configuration, and control queues the driver learns of them over.
+pub const CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+/// Offset of `max_nr_ports: u32` in the console's configuration, after its
+/// `cols` and `rows`.
+pub const CONSOLE_CONFIG_MAX_NR_PORTS: usize = 4;
+/// The console port the kernel logs to.
+pub const CONSOLE_PORT_LOG: u32 = 0;
+/// The console port programs' output goes to, when the console has it.
+pub const CONSOLE_PORT_OUTPUT: u32 = 1;
+
+/// The console's control queues, between port 0's queues and the rest.
+pub const CONSOLE_CONTROL_RX_QUEUE: u32 = 2;
+pub const CONSOLE_CONTROL_TX_QUEUE: u32 = 3;
+
+/// The queue the console driver receives `port`'s input on.
+pub const fn console_rx_queue(port: u32) -> u32 {
+    if port == 0 { 0 } else { 2 + 2 * port }
+}
+
+/// The queue the console driver sends `port`'s output on.
+pub const fn console_tx_queue(port: u32) -> u32 {
+    console_rx_queue(port) + 1
+}
+
+/// A control message, `virtio_console_control`: a port's `id: u32`, then
+/// `event: u16` and `value: u16`.
+pub const CONSOLE_CONTROL_SIZE: usize = 8;
+
+// Control events. The driver says it is ready, then that each port the
+// device adds is; the device adds ports and opens them, naming one the
+// console.
+pub const CONSOLE_DEVICE_READY: u16 = 0;
+pub const CONSOLE_DEVICE_ADD: u16 = 1;
+pub const CONSOLE_PORT_READY: u16 = 3;
+pub const CONSOLE_CONSOLE_PORT: u16 = 4;
+pub const CONSOLE_PORT_OPEN: u16 = 6;
+
+/// The 9p device names its share in its configuration: `tag_len: u16`,
+/// then the tag.
+pub const P9_F_MOUNT_TAG: u64 = 1 << 0;
+
+/// The balloon takes reports of free pages, which the host may drop and
+/// the guest finds zeroed when it next uses them.
+pub const BALLOON_F_REPORTING: u64 = 1 << 5;
+/// The queue free pages are reported on, after the inflate and deflate
+/// queues, as the balloon has no statistics or hinting queues between.
+pub const BALLOON_REPORTING_QUEUE: u32 = 2;
+
+/// `virtio_net_hdr` ahead of every frame. Without offloads the driver
+/// leaves it zeroed and the device only sets `num_buffers` to 1.
+pub const NET_HDR_SIZE: usize = 12;
+/// Offset of `num_buffers` in the header.
+pub const NET_HDR_NUM_BUFFERS: usize = 10;
+
+pub const DESC_F_NEXT: u16 = 1;
+pub const DESC_F_WRITE: u16 = 2;
+
+/// An entry of a virtqueue's descriptor table.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+// The driver (available) ring is `flags: u16, idx: u16, ring: [u16; size]`
+// and the device (used) ring `flags: u16, idx: u16, ring: [UsedElem; size]`.
+pub const RING_IDX: u64 = 2;
+pub const RING_ENTRIES: u64 = 4;
+
+/// An entry of a used ring: the head of a descriptor chain and how many
+/// bytes the device wrote to it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsedElem {
+    pub id: u32,
+    pub len: u32,
+}
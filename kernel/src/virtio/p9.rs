@@ -0,0 +1,285 @@
+//! The part of 9P2000.L the host share speaks, shared by the VMM's server
+//! and the kernel's client. Every message is `size: u32, type: u8,
+//! tag: u16` and then its fields, little endian; strings are a `u16`
+//! length and that many bytes.
+//!
+//! Each request travels in a descriptor chain of its own, the request
+//! readable and room for the reply writable, and the device answers it
+//! before the notification returns. Tags therefore never matter and the
+//! client leaves them 0.
+
+/// What the client asks for in `Tversion`, and the only dialect the server
+/// speaks.
+pub const VERSION: &[u8] = b"9P2000.L";
+/// `afid` of an attach without authentication.
+pub const NOFID: u32 = !0;
+/// Names a `Twalk` may carry.
+pub const MAX_WALK: usize = 16;
+
+/// Bytes ahead of each message's fields.
+pub const HEADER_SIZE: usize = 7;
+/// Bytes of `Twrite` ahead of the data it carries, header included, which
+/// leaves room for the shorter `Rread` ahead of the same data.
+pub const IO_HEADER_SIZE: usize = HEADER_SIZE + 16;
+
+// Message types. Every reply is its request's type plus one, or `RLERROR`.
+pub const RLERROR: u8 = 7;
+pub const TSTATFS: u8 = 8;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TMKDIR: u8 = 72;
+pub const TRENAMEAT: u8 = 74;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TATTACH: u8 = 104;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+
+/// `Tgetattr` fields that carry the mode, and the size.
+pub const GETATTR_MODE: u64 = 0x1;
+pub const GETATTR_SIZE: u64 = 0x200;
+/// The only `Tsetattr` field the client sets.
+pub const SETATTR_SIZE: u32 = 0x8;
+/// `Tunlinkat` flag removing a directory rather than a file.
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+/// `Qid::ty` bit for directories.
+pub const QTDIR: u8 = 0x80;
+/// The file type bits of a mode, and the directory type.
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+
+// `Tlopen` and `Tlcreate` flags, those of open(2).
+pub const O_RDONLY: u32 = 0o0;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_ACCMODE: u32 = 0o3;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+
+/// Longest name in a directory the client expects the host to take.
+pub const NAME_MAX: usize = 255;
+
+// Errors `Rlerror` carries: the host's errno values, which the server
+// passes on, and its own.
+pub const EPERM: u32 = 1;
+pub const ENOENT: u32 = 2;
+pub const EIO: u32 = 5;
+pub const EBADF: u32 = 9;
+pub const EACCES: u32 = 13;
+pub const EBUSY: u32 = 16;
+pub const EEXIST: u32 = 17;
+pub const EXDEV: u32 = 18;
+pub const ENOTDIR: u32 = 20;
+pub const EISDIR: u32 = 21;
+pub const EINVAL: u32 = 22;
+pub const ENOSPC: u32 = 28;
+pub const EROFS: u32 = 30;
+pub const ENAMETOOLONG: u32 = 36;
+pub const ENOSYS: u32 = 38;
+pub const ENOTEMPTY: u32 = 39;
+
+/// What the server names a file by, for as long as it exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Qid {
+    pub ty: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+impl Qid {
+    pub fn is_dir(&self) -> bool {
+        self.ty & QTDIR != 0
+    }
+}
+
+/// Builds a message in a buffer, past its header until `finish` fills that
+/// in. Fields that do not fit are dropped and fail `finish`.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        let overflow = buf.len() < HEADER_SIZE;
+        Self {
+            buf,
+            len: HEADER_SIZE,
+            overflow,
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.bytes(&[value])
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn str(&mut self, value: &[u8]) -> &mut Self {
+        match u16::try_from(value.len()) {
+            Ok(len) => self.u16(len).bytes(value),
+            Err(_) => {
+                self.overflow = true;
+                self
+            }
+        }
+    }
+
+    pub fn qid(&mut self, qid: Qid) -> &mut Self {
+        self.u8(qid.ty).u32(qid.version).u64(qid.path)
+    }
+
+    /// Raw bytes, such as the data of `Rread` or `Twrite`.
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        match self.buf.get_mut(self.len..self.len + value.len()) {
+            Some(dst) if !self.overflow => {
+                dst.copy_from_slice(value);
+                self.len += value.len();
+            }
+            _ => self.overflow = true,
+        }
+        self
+    }
+
+    /// How many more bytes fit.
+    pub fn room(&self) -> usize {
+        if self.overflow {
+            0
+        } else {
+            self.buf.len() - self.len
+        }
+    }
+
+    /// Fill in the header as a message of type `ty`, returning its length.
+    pub fn finish(&mut self, ty: u8, tag: u16) -> Option<usize> {
+        if self.overflow {
+            return None;
+        }
+        self.buf[0..4].copy_from_slice(&(self.len as u32).to_le_bytes());
+        self.buf[4] = ty;
+        self.buf[5..7].copy_from_slice(&tag.to_le_bytes());
+        Some(self.len)
+    }
+}
+
+/// Takes a message's fields apart, from past its header.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// The message at the start of `buf`, with its type and tag, if `buf`
+    /// holds all of it.
+    pub fn new(buf: &'a [u8]) -> Option<(u8, u16, Self)> {
+        let header = buf.first_chunk::<HEADER_SIZE>()?;
+        let size = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let buf = buf.get(..size).filter(|_| size >= HEADER_SIZE)?;
+        let tag = u16::from_le_bytes([header[5], header[6]]);
+        Some((
+            header[4],
+            tag,
+            Self {
+                buf,
+                pos: HEADER_SIZE,
+            },
+        ))
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn str(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.bytes(len.into())
+    }
+
+    pub fn qid(&mut self) -> Option<Qid> {
+        Some(Qid {
+            ty: self.u8()?,
+            version: self.u32()?,
+            path: self.u64()?,
+        })
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_read_back_as_written() {
+        let mut buf = [0; 64];
+        let qid = Qid {
+            ty: QTDIR,
+            version: 3,
+            path: 42,
+        };
+        let len = Writer::new(&mut buf)
+            .u32(7)
+            .str(b"name")
+            .qid(qid)
+            .u64(u64::MAX)
+            .finish(TWALK, 9)
+            .unwrap();
+        assert_eq!(len, HEADER_SIZE + 4 + 6 + 13 + 8);
+
+        let (ty, tag, mut reader) = Reader::new(&buf).unwrap();
+        assert_eq!((ty, tag), (TWALK, 9));
+        assert_eq!(reader.u32(), Some(7));
+        assert_eq!(reader.str(), Some(&b"name"[..]));
+        assert_eq!(reader.qid(), Some(qid));
+        assert_eq!(reader.u64(), Some(u64::MAX));
+        // The size ends the message, whatever follows in the buffer.
+        assert_eq!(reader.u8(), None);
+    }
+
+    #[test]
+    fn what_does_not_fit_fails_the_message() {
+        let mut buf = [0; 12];
+        assert_eq!(Writer::new(&mut buf).u64(1).finish(TCLUNK, 0), None);
+        assert_eq!(Writer::new(&mut buf).u32(1).finish(TCLUNK, 0), Some(11));
+
+        // A size past the buffer, or short of a header, is no message.
+        buf[0] = 13;
+        assert!(Reader::new(&buf).is_none());
+        buf[0] = 6;
+        assert!(Reader::new(&buf).is_none());
+    }
+}
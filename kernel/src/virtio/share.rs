@@ -0,0 +1,107 @@
+use super::driver::{BUFFER_SIZE, QUEUE_SIZE, Queue, Transport};
+use super::p9::{self, Reader, Writer};
+use super::{DEVICE_9P, P9_F_MOUNT_TAG, SHARE_SLOT};
+use crate::fs::errors::{FsError, Result};
+use crate::memory::{address::DirectMap, alloc::palloc::PageAllocator};
+
+/// The queue requests go on.
+const REQUEST_QUEUE: u32 = 0;
+/// The first half of the queue's buffers hold a request, the second its
+/// reply.
+const HALF: u16 = QUEUE_SIZE / 2;
+/// The longest message either way.
+pub const MSIZE: usize = HALF as usize * BUFFER_SIZE;
+/// Longest mount tag kept.
+pub const TAG_MAX: usize = 32;
+
+/// The VMM's 9p device, which serves a host directory. Requests go one at a
+/// time and the device answers each as it is notified of it, so a request
+/// is a round trip through the VMM and nothing waits on interrupts.
+pub struct VirtioShare {
+    transport: Transport,
+    queue: Queue,
+    tag: [u8; TAG_MAX],
+    tag_len: usize,
+}
+
+// SAFETY: the registers and the queue are only reached through the share's
+// lock.
+unsafe impl Send for VirtioShare {}
+
+impl VirtioShare {
+    /// Bring up the 9p device if the VMM has one.
+    pub fn probe(palloc: &PageAllocator, dm: &impl DirectMap) -> Result<Option<Self>> {
+        let Some(mut transport) = Transport::find(SHARE_SLOT, DEVICE_9P, dm) else {
+            return Ok(None);
+        };
+        let Some(features) = transport.negotiate(P9_F_MOUNT_TAG) else {
+            return Ok(None);
+        };
+        let Some([queue]) = transport.setup_queues([REQUEST_QUEUE], palloc, dm)? else {
+            return Ok(None);
+        };
+        let mut tag = [0; TAG_MAX];
+        let mut tag_len = 0;
+        if features & P9_F_MOUNT_TAG != 0 {
+            let len = u16::from_le_bytes([transport.config_byte(0), transport.config_byte(1)]);
+            tag_len = usize::from(len).min(TAG_MAX);
+            for (i, byte) in tag[..tag_len].iter_mut().enumerate() {
+                *byte = transport.config_byte(2 + i);
+            }
+        }
+        transport.start();
+        Ok(Some(Self {
+            transport,
+            queue,
+            tag,
+            tag_len,
+        }))
+    }
+
+    /// The name the VMM gave the share, cut to `TAG_MAX` bytes.
+    pub fn tag(&self) -> &[u8] {
+        &self.tag[..self.tag_len]
+    }
+
+    /// Send the request of type `ty` that `fill` writes the fields of, and
+    /// take its reply apart past the header. Errors the server answers with
+    /// come back as theirs.
+    pub fn call(&mut self, ty: u8, fill: impl FnOnce(&mut Writer<'_>)) -> Result<Reader<'_>> {
+        let mut writer = Writer::new(self.queue.buffers(0, HALF));
+        fill(&mut writer);
+        let len = writer
+            .finish(ty, 0)
+            .ok_or(FsError::NameTooLong { max: MSIZE })?;
+        self.queue.offer_request(0, len, HALF, MSIZE);
+        self.transport.notify(REQUEST_QUEUE);
+        // The device is done with the chain by the time the notification
+        // returns; anything else is a broken device.
+        self.queue.take_used().ok_or(FsError::Io)?;
+        let (reply_ty, _, mut reply) =
+            Reader::new(self.queue.buffers(HALF, HALF)).ok_or(FsError::Io)?;
+        match reply_ty {
+            p9::RLERROR => Err(error(reply.u32().ok_or(FsError::Io)?)),
+            reply_ty if reply_ty == ty + 1 => Ok(reply),
+            _ => Err(FsError::Io),
+        }
+    }
+}
+
+// What the host's errno means to the guest's filesystem layer.
+fn error(errno: u32) -> FsError {
+    match errno {
+        p9::ENOENT => FsError::NotFound,
+        p9::EEXIST => FsError::AlreadyExists,
+        p9::ENOTDIR => FsError::NotDirectory,
+        p9::EISDIR => FsError::IsDirectory,
+        p9::ENOTEMPTY => FsError::NotEmpty,
+        p9::EPERM | p9::EACCES => FsError::PermissionDenied,
+        p9::EBUSY => FsError::Busy,
+        p9::EINVAL => FsError::InvalidArgument,
+        p9::ENOSPC => FsError::NoSpace,
+        p9::ENAMETOOLONG => FsError::NameTooLong { max: p9::NAME_MAX },
+        p9::EXDEV => FsError::CrossDevice,
+        p9::EROFS => FsError::ReadOnly,
+        _ => FsError::Io,
+    }
+}
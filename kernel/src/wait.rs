@@ -0,0 +1,74 @@
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::Kernel;
+use crate::memory::address::DirectMap;
+use crate::process;
+
+/// Processes sleeping until something else happens: data arriving, a lock
+/// being released, time passing.
+///
+/// Waiters check their condition and call [`WaitQueue::sleep`] with
+/// interrupts masked, as every syscall runs, so a [`WaitQueue::wake_all`]
+/// cannot slip in between the check and the sleep and be lost. A woken
+/// process has to check its condition again.
+pub struct WaitQueue {
+    waiters: spin::Mutex<Vec<usize>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Block the calling process until `wake_all` runs or the monotonic clock
+    /// reaches `deadline`. Like [`process::block_current`], this yields
+    /// instead when blocking is not possible, and also when there is no memory
+    /// to queue the caller.
+    pub fn sleep<DM: DirectMap>(&self, kernel: &Kernel<'_, DM>, deadline: Option<Duration>) {
+        let pid = process::current_pid(kernel);
+        if !self.add(kernel, pid) {
+            process::yield_now(kernel);
+            return;
+        }
+        process::block_current(kernel, deadline);
+        self.remove(pid);
+    }
+
+    /// Whether no process is sleeping here.
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+
+    /// Make every sleeping process ready again.
+    pub fn wake_all<DM: DirectMap>(&self, kernel: &Kernel<'_, DM>) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for pid in waiters {
+            process::wake(kernel, pid);
+        }
+    }
+
+    // Processes killed while asleep never remove themselves, so their
+    // entries are reclaimed here. Returns whether `pid` was queued.
+    fn add<DM: DirectMap>(&self, kernel: &Kernel<'_, DM>, pid: usize) -> bool {
+        let mut waiters = self.waiters.lock();
+        waiters.retain(|&waiter| process::has_pid(kernel, waiter));
+        if waiters.try_reserve(1).is_err() {
+            return false;
+        }
+        waiters.push(pid);
+        true
+    }
+
+    fn remove(&self, pid: usize) {
+        self.waiters.lock().retain(|&waiter| waiter != pid);
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,82 @@
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand};
+use hostel::failures::{DEFAULT_FAILURE_LOG, FailureLog, summarize};
+use hostel::vm::Result as VmResult;
+
+#[derive(Args)]
+pub struct Cmd {
+    /// JSONL failure log written by `hostel run --failure-log` and by failing
+    /// kernel test runs.
+    #[arg(long, global = true, default_value = DEFAULT_FAILURE_LOG)]
+    pub log: PathBuf,
+
+    #[command(subcommand)]
+    pub action: Action,
+}
+
+#[derive(Subcommand)]
+pub enum Action {
+    /// Print recorded failures, newest last.
+    Show {
+        /// Only show failures of this kernel build hash.
+        #[arg(long)]
+        build: Option<String>,
+
+        /// Only show the last N matching failures.
+        #[arg(short, long)]
+        limit: Option<usize>,
+
+        /// Include the captured console lines of each failure.
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Group failures by kind and message, most frequent first.
+    Summarize,
+}
+
+impl Cmd {
+    pub fn execute(&self) -> VmResult<()> {
+        let records = FailureLog::new(&self.log).load()?;
+
+        match &self.action {
+            Action::Show {
+                build,
+                limit,
+                verbose,
+            } => {
+                let matching: Vec<_> = records
+                    .iter()
+                    .filter(|record| build.as_ref().is_none_or(|b| &record.build_hash == b))
+                    .collect();
+                let skip = limit.map_or(0, |limit| matching.len().saturating_sub(limit));
+
+                for record in &matching[skip..] {
+                    println!(
+                        "{} {} {:<15} {}",
+                        record.timestamp, record.build_hash, record.kind, record.message
+                    );
+                    if *verbose {
+                        for line in &record.details {
+                            println!("    {line}");
+                        }
+                    }
+                }
+            }
+            Action::Summarize => {
+                for summary in summarize(&records) {
+                    println!(
+                        "{:>5}x {:<15} builds={} first={} last={}  {}",
+                        summary.occurrences,
+                        summary.kind,
+                        summary.builds.len(),
+                        summary.first_seen,
+                        summary.last_seen,
+                        summary.message
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1 +1,2 @@
+pub mod failures;
 pub mod run;
@@ -1,19 +1,209 @@
-use clap::Args;
-use hostel::vm::{Result as VmResult, Vm};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Args, ValueEnum};
+use hostel::failures::{FailureLog, FailureRecord, build_hash};
+use hostel::vm::{DumpFormat, ExitStatus, HugePages, Result as VmResult, Tap, Vm};
+use kernel::boot::RunFlags;
 
 #[derive(Args)]
 pub struct Cmd {
     #[arg(short, long)]
     pub filepath: String,
+
+    /// Newc cpio archive the guest kernel unpacks into its root filesystem
+    /// as it boots.
+    #[arg(long)]
+    pub initramfs: Option<PathBuf>,
+
+    /// Command line the guest kernel prints as it boots.
+    #[arg(long)]
+    pub cmdline: Option<String>,
+
+    /// User id reported to guest processes.
+    #[arg(long, default_value_t = 0)]
+    pub uid: u16,
+
+    /// Group id reported to guest processes.
+    #[arg(long, default_value_t = 0)]
+    pub gid: u16,
+
+    /// Guest memory in MiB; defaults to the whole direct map.
+    #[arg(long)]
+    pub memory_mib: Option<usize>,
+
+    /// What backs guest memory on the host: 4 KiB pages only, transparent
+    /// huge pages, or huge pages reserved from the host's hugetlbfs pool,
+    /// which must then hold all of --memory-mib.
+    #[arg(long, value_enum, default_value_t = HugePageBacking::Transparent)]
+    pub hugepages: HugePageBacking,
+
+    /// Virtual CPUs to give the guest.
+    #[arg(long, default_value_t = 1)]
+    pub cpus: usize,
+
+    /// Zero guest pages as they are freed so no data outlives its owner.
+    #[arg(long)]
+    pub scrub_on_free: bool,
+
+    /// Timer ticks (10 ms each) a guest process runs before another of the
+    /// same priority gets a turn; defaults to one.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    pub quantum_ticks: Option<u8>,
+
+    /// Send the guest an NMI this many seconds in, making it dump where it
+    /// was and what its scheduler was doing. For breaking into a guest that
+    /// has stopped making progress.
+    #[arg(long)]
+    pub nmi_after_secs: Option<u64>,
+
+    /// Give the guest a network card on this host tap interface. The guest
+    /// is 10.0.2.15/24 and expects a router at 10.0.2.2.
+    #[arg(long)]
+    pub tap: Option<String>,
+
+    /// Have the guest write through a virtio console rather than a byte
+    /// at a time through the serial port. Kernel messages and programs'
+    /// output both still end up on stdout, on ports of their own.
+    #[arg(long)]
+    pub virtio_console: bool,
+
+    /// Share this host directory with the guest, which finds it at /host.
+    /// Read-only unless --share-writable is given.
+    #[arg(long)]
+    pub share: Option<PathBuf>,
+
+    /// Let the guest change what is in the --share directory.
+    #[arg(long, requires = "share")]
+    pub share_writable: bool,
+
+    /// Have the guest report the memory it frees so the host can take it
+    /// back, keeping the VM's footprint close to what the guest is using.
+    #[arg(long)]
+    pub balloon: bool,
+
+    /// Stop the guest and fail if it is still running after this many
+    /// seconds.
+    #[arg(long)]
+    pub timeout_secs: Option<u64>,
+
+    /// Print what the guest's vCPUs exited for, and where, to stderr when
+    /// the run ends.
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Append a structured record to this JSONL log when the guest fails.
+    #[arg(long)]
+    pub failure_log: Option<PathBuf>,
+
+    /// Write guest memory and vCPU state to this file when the guest stops
+    /// unexpectedly or its kernel tests fail.
+    #[arg(long)]
+    pub crash_dump: Option<PathBuf>,
+
+    /// How to write --crash-dump: an ELF core gdb can read alongside the
+    /// kernel, or a raw memory image with the registers in <file>.vcpus.
+    #[arg(long, value_enum, default_value_t = CrashDumpFormat::Elf, requires = "crash_dump")]
+    pub crash_dump_format: CrashDumpFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum HugePageBacking {
+    Off,
+    Transparent,
+    Explicit,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum CrashDumpFormat {
+    Elf,
+    Raw,
 }
 
 impl Cmd {
-    pub fn execute(&self) -> VmResult<()> {
-        let mut vm = Vm::new()?;
+    /// Run the guest and return the exit status it powered off with.
+    pub fn execute(&self) -> VmResult<ExitStatus> {
         let data = std::fs::read(&self.filepath)?;
-        vm.load_elf(&data)?;
-        vm.run()?;
-        println!("guest finished execution");
-        Ok(())
+        let initramfs = self.initramfs.as_ref().map(std::fs::read).transpose()?;
+        let hugepages = match self.hugepages {
+            HugePageBacking::Off => HugePages::Off,
+            HugePageBacking::Transparent => HugePages::Transparent,
+            HugePageBacking::Explicit => HugePages::Explicit,
+        };
+        let mut builder = Vm::builder()
+            .hugepages(hugepages)
+            .vcpus(self.cpus)
+            .run_flags(
+                RunFlags::empty()
+                    .with_uid(self.uid)
+                    .with_gid(self.gid)
+                    .with_scrub_on_free(self.scrub_on_free)
+                    .with_quantum_ticks(self.quantum_ticks.unwrap_or(0)),
+            )
+            .kernel(&data);
+        if let Some(command_line) = &self.cmdline {
+            builder = builder.command_line(command_line);
+        }
+        if let Some(archive) = &initramfs {
+            builder = builder.initramfs(archive);
+        }
+        if let Some(mib) = self.memory_mib {
+            builder = builder.memory_mib(mib);
+        }
+        if self.virtio_console {
+            builder = builder.virtio_console(vec![Box::new(std::io::stdout())]);
+        }
+        if let Some(name) = &self.tap {
+            builder = builder.net_backend(Box::new(Tap::open(name)?));
+        }
+        if let Some(dir) = &self.share {
+            builder = builder.share_dir(dir, !self.share_writable);
+        }
+        if self.balloon {
+            builder = builder.balloon(true);
+        }
+        if let Some(path) = &self.crash_dump {
+            let format = match self.crash_dump_format {
+                CrashDumpFormat::Elf => DumpFormat::Elf,
+                CrashDumpFormat::Raw => DumpFormat::Raw,
+            };
+            builder = builder.crash_dump(path, format);
+        }
+        let mut vm = builder.build()?;
+        vm.set_forward_stdin(true);
+        if let Some(secs) = self.nmi_after_secs {
+            let injector = vm.nmi_injector();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_secs(secs));
+                injector.inject();
+            });
+        }
+        let result = match self.timeout_secs {
+            Some(secs) => vm.run_with_timeout(Duration::from_secs(secs)),
+            None => vm.run(),
+        };
+        if self.stats {
+            eprint!("{}", vm.stats());
+        }
+        let status = match result {
+            Ok(status) => status,
+            Err(err) => {
+                if let Some(path) = &self.failure_log {
+                    let transcript = vm.console_transcript();
+                    let record = FailureRecord::from_run(
+                        &build_hash(&data),
+                        &err,
+                        transcript.iter().map(String::as_str),
+                    );
+                    FailureLog::new(path).append(&record)?;
+                }
+                return Err(err);
+            }
+        };
+        match status {
+            ExitStatus::Exited(_) => println!("guest finished execution"),
+            ExitStatus::Killed(signal) => println!("guest killed by signal {signal}"),
+        }
+        Ok(status)
     }
 }
@@ -1,6 +1,7 @@
 mod cmd;
 
 use clap::{Parser, Subcommand};
+use hostel::vm::ExitStatus;
 
 #[derive(Parser)]
 #[command(name = "hostel")]
@@ -12,17 +13,28 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Run(cmd::run::Cmd),
+    /// Inspect the guest failure log.
+    Failures(cmd::failures::Cmd),
 }
 
 fn main() {
     let cli = Cli::parse();
 
-    match &cli.command {
-        Commands::Run(cmd) => {
-            if let Err(e) = cmd.execute() {
-                eprintln!("error: {}", e);
-                std::process::exit(1);
-            }
+    let result = match &cli.command {
+        Commands::Run(cmd) => cmd.execute(),
+        Commands::Failures(cmd) => cmd.execute().map(|()| ExitStatus::Exited(0)),
+    };
+
+    match result {
+        // The guest's own exit code, so scripts can tell how it ended: 128
+        // plus the signal, as a shell has it, for a killed first process.
+        Ok(status) => match status.exit_code() {
+            0 => {}
+            code => std::process::exit(code.into()),
+        },
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
         }
     }
 }
@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, ErrorKind, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::vm::{Error, Result};
+
+pub const DEFAULT_FAILURE_LOG: &str = "hostel-failures.jsonl";
+
+// Line prefixes the kernel uses when it reports a fatal condition on the console.
+const PANIC_MARKER: &str = "kernel panic:";
+const EXCEPTION_MARKER: &str = "kernel exception:";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    Panic,
+    Exception,
+    TestFailure,
+    UnexpectedExit,
+    Timeout,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Panic => "panic",
+            Self::Exception => "exception",
+            Self::TestFailure => "test-failure",
+            Self::UnexpectedExit => "unexpected-exit",
+            Self::Timeout => "timeout",
+        };
+        f.pad(name)
+    }
+}
+
+/// One failed guest run, as stored in the failure log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FailureRecord {
+    pub timestamp: u64,
+    pub build_hash: String,
+    pub kind: FailureKind,
+    pub message: String,
+    pub details: Vec<String>,
+}
+
+impl FailureRecord {
+    /// Classify a failed run from the error returned by `Vm::run` and the
+    /// guest console transcript captured up to that point.
+    pub fn from_run<'a>(
+        build_hash: &str,
+        error: &Error,
+        transcript: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        let lines: Vec<&str> = transcript.into_iter().collect();
+        let last_marked = |marker: &str| lines.iter().rposition(|line| line.starts_with(marker));
+
+        let (kind, details) = if let Some(start) = last_marked(EXCEPTION_MARKER) {
+            (FailureKind::Exception, &lines[start..])
+        } else if let Some(start) = last_marked(PANIC_MARKER) {
+            let kind = if matches!(error, Error::KernelTestsFailed) {
+                FailureKind::TestFailure
+            } else {
+                FailureKind::Panic
+            };
+            (kind, &lines[start..])
+        } else {
+            let kind = match error {
+                Error::KernelTestsFailed => FailureKind::TestFailure,
+                Error::Timeout(_) => FailureKind::Timeout,
+                _ => FailureKind::UnexpectedExit,
+            };
+            (kind, &lines[..0])
+        };
+
+        let message = if details.is_empty() {
+            error.to_string()
+        } else {
+            details
+                .iter()
+                .map(|line| strip_marker(line).trim())
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        Self {
+            timestamp: unix_time(),
+            build_hash: build_hash.to_string(),
+            kind,
+            message,
+            details: details.iter().map(|line| line.to_string()).collect(),
+        }
+    }
+}
+
+/// Aggregate of all records sharing the same kind and message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailureSummary {
+    pub kind: FailureKind,
+    pub message: String,
+    pub occurrences: usize,
+    pub builds: Vec<String>,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// Append-only JSONL store of guest failures.
+pub struct FailureLog {
+    path: PathBuf,
+}
+
+impl FailureLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, record: &FailureRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Read every record in the log. A missing log is treated as empty.
+    pub fn load(&self) -> Result<Vec<FailureRecord>> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line)?);
+        }
+        Ok(records)
+    }
+}
+
+/// Group records by (kind, message), most frequent first.
+pub fn summarize(records: &[FailureRecord]) -> Vec<FailureSummary> {
+    let mut groups: BTreeMap<(FailureKind, &str), FailureSummary> = BTreeMap::new();
+
+    for record in records {
+        let summary = groups
+            .entry((record.kind, record.message.as_str()))
+            .or_insert_with(|| FailureSummary {
+                kind: record.kind,
+                message: record.message.clone(),
+                occurrences: 0,
+                builds: Vec::new(),
+                first_seen: record.timestamp,
+                last_seen: record.timestamp,
+            });
+
+        summary.occurrences += 1;
+        summary.first_seen = summary.first_seen.min(record.timestamp);
+        summary.last_seen = summary.last_seen.max(record.timestamp);
+        if !summary.builds.contains(&record.build_hash) {
+            summary.builds.push(record.build_hash.clone());
+        }
+    }
+
+    let mut summaries: Vec<_> = groups.into_values().collect();
+    summaries.sort_by(|a, b| {
+        b.occurrences
+            .cmp(&a.occurrences)
+            .then(b.last_seen.cmp(&a.last_seen))
+    });
+    summaries
+}
+
+/// Stable identifier of a kernel image (64-bit FNV-1a over the ELF bytes).
+pub fn build_hash(elf: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in elf {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{hash:016x}")
+}
+
+fn strip_marker(line: &str) -> &str {
+    line.strip_prefix(EXCEPTION_MARKER)
+        .or_else(|| line.strip_prefix(PANIC_MARKER))
+        .unwrap_or(line)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log(name: &str) -> FailureLog {
+        let path = std::env::temp_dir().join(format!(
+            "hostel-{name}-{}-{}.jsonl",
+            std::process::id(),
+            unix_time()
+        ));
+        let _ = std::fs::remove_file(&path);
+        FailureLog::new(path)
+    }
+
+    #[test]
+    fn panic_in_test_run_is_classified_as_test_failure() {
+        let transcript = [
+            "kernel: boot (integration-tests)",
+            "kernel panic: panicked at kernel-tests/src/test_process.rs:22:5:",
+            "process did not reach completion point",
+        ];
+        let record = FailureRecord::from_run("abc", &Error::KernelTestsFailed, transcript);
+
+        assert_eq!(record.kind, FailureKind::TestFailure);
+        assert_eq!(
+            record.message,
+            "panicked at kernel-tests/src/test_process.rs:22:5: process did not reach completion point"
+        );
+        assert_eq!(record.details.len(), 2);
+    }
+
+    #[test]
+    fn exit_without_console_marker_uses_error_text() {
+        let error = Error::UnexpectedExit("Shutdown".to_string());
+        let record = FailureRecord::from_run("abc", &error, ["kernel: boot"]);
+
+        assert_eq!(record.kind, FailureKind::UnexpectedExit);
+        assert_eq!(record.message, "unexpected vCPU exit: Shutdown");
+        assert!(record.details.is_empty());
+    }
+
+    #[test]
+    fn guests_that_run_too_long_are_timeouts() {
+        let error = Error::Timeout(std::time::Duration::from_secs(5));
+        let record = FailureRecord::from_run("abc", &error, ["kernel: boot"]);
+
+        assert_eq!(record.kind, FailureKind::Timeout);
+        assert_eq!(record.message, "guest still running after 5s");
+    }
+
+    #[test]
+    fn log_round_trips_and_summarizes_across_builds() {
+        let log = temp_log("failures");
+        let error = Error::KernelTestsFailed;
+        for (build, transcript) in [
+            ("b1", ["kernel panic: boom"]),
+            ("b2", ["kernel panic: boom"]),
+            ("b2", ["kernel panic: other"]),
+        ] {
+            log.append(&FailureRecord::from_run(build, &error, transcript))
+                .unwrap();
+        }
+
+        let records = log.load().unwrap();
+        assert_eq!(records.len(), 3);
+
+        let summaries = summarize(&records);
+        assert_eq!(summaries[0].message, "boom");
+        assert_eq!(summaries[0].occurrences, 2);
+        assert_eq!(summaries[0].builds, ["b1", "b2"]);
+        assert_eq!(summaries[1].message, "other");
+
+        std::fs::remove_file(log.path()).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use kernel::boot::RunFlags;
+use kernel::memory::constants::{MAX_CPUS, PAGE_SIZE, PALLOC_FIRST_PAGE};
+
+use super::{CpuId, DEFAULT_MEM_SIZE, DumpFormat, Error, HugePages, NetBackend, Result, Vm};
+
+/// An edit of the CPUID table, as [`VmBuilder::cpuid`] takes it.
+pub(super) type EditCpuid<'a> = Box<dyn FnMut(&mut CpuId) + 'a>;
+
+/// Describes a [`Vm`] before it is created. Anything left unset gets a
+/// default: memory covering the whole direct map in transparent huge pages,
+/// one vCPU, no run flags or command line, no kernel or initramfs loaded,
+/// no network card, no virtio console, no host share, no balloon and the
+/// CPUID KVM supports.
+pub struct VmBuilder<'a> {
+    mem_size: usize,
+    hugepages: HugePages,
+    cpu_count: usize,
+    run_flags: RunFlags,
+    command_line: &'a str,
+    kernel: Option<&'a [u8]>,
+    initramfs: Option<&'a [u8]>,
+    net_backend: Option<Box<dyn NetBackend>>,
+    console_outputs: Option<Vec<Box<dyn Write + Send>>>,
+    share: Option<(PathBuf, bool)>,
+    balloon: bool,
+    crash_dump: Option<(PathBuf, DumpFormat)>,
+    dirty_logging: bool,
+    edit_cpuid: Option<EditCpuid<'a>>,
+}
+
+impl Default for VmBuilder<'_> {
+    fn default() -> Self {
+        Self {
+            mem_size: DEFAULT_MEM_SIZE,
+            hugepages: HugePages::default(),
+            cpu_count: 1,
+            run_flags: RunFlags::empty(),
+            command_line: "",
+            kernel: None,
+            initramfs: None,
+            net_backend: None,
+            console_outputs: None,
+            share: None,
+            balloon: false,
+            crash_dump: None,
+            dirty_logging: false,
+            edit_cpuid: None,
+        }
+    }
+}
+
+impl<'a> VmBuilder<'a> {
+    /// Guest memory in MiB. It must be a whole number of 2 MiB pages, leave
+    /// at least one page for the kernel's page allocator and fit in the
+    /// direct map below the device registers, so at most
+    /// [`DEFAULT_MEM_SIZE`]. The kernel learns the size from the memory map.
+    pub fn memory_mib(mut self, mib: usize) -> Self {
+        self.mem_size = mib.saturating_mul(1 << 20);
+        self
+    }
+
+    /// What backs guest memory on the host; see [`HugePages`].
+    pub fn hugepages(mut self, hugepages: HugePages) -> Self {
+        self.hugepages = hugepages;
+        self
+    }
+
+    /// vCPUs to create, from 1 to `MAX_CPUS`. All of them enter the kernel,
+    /// each on its own stack, and [`Vm::run`] runs each on its own thread.
+    pub fn vcpus(mut self, count: usize) -> Self {
+        self.cpu_count = count;
+        self
+    }
+
+    pub fn run_flags(mut self, run_flags: RunFlags) -> Self {
+        self.run_flags = run_flags;
+        self
+    }
+
+    /// Command line for the kernel; see [`Vm::set_command_line`].
+    pub fn command_line(mut self, command_line: &'a str) -> Self {
+        self.command_line = command_line;
+        self
+    }
+
+    /// Kernel ELF to load, checked to fit the kernel image's place in guest
+    /// memory.
+    pub fn kernel(mut self, elf: &'a [u8]) -> Self {
+        self.kernel = Some(elf);
+        self
+    }
+
+    /// Newc cpio archive for the kernel to unpack into its root filesystem;
+    /// see [`Vm::load_initramfs`].
+    pub fn initramfs(mut self, archive: &'a [u8]) -> Self {
+        self.initramfs = Some(archive);
+        self
+    }
+
+    /// Give the guest a virtio network card, its frames carried by
+    /// `backend`: a [`Tap`](super::Tap), or a socket to another program.
+    pub fn net_backend(mut self, backend: Box<dyn NetBackend>) -> Self {
+        self.net_backend = Some(backend);
+        self
+    }
+
+    /// Give the guest a virtio console, which it writes through instead of
+    /// the serial port, a write at a time rather than a byte. Port 0 carries
+    /// kernel messages, which go wherever the serial port's do; `outputs`
+    /// are the ports after it, and the kernel sends programs' console
+    /// output to the first of them. Input still comes through the serial
+    /// port.
+    pub fn virtio_console(mut self, outputs: Vec<Box<dyn Write + Send>>) -> Self {
+        self.console_outputs = Some(outputs);
+        self
+    }
+
+    /// Share the host directory at `root` with the guest, which mounts it
+    /// at `/host`. A read-only share refuses every change. The guest cannot
+    /// reach anything outside `root`, through `..` or symlinks.
+    pub fn share_dir(mut self, root: impl Into<PathBuf>, read_only: bool) -> Self {
+        self.share = Some((root.into(), read_only));
+        self
+    }
+
+    /// Give the guest a virtio balloon, which it reports the memory it
+    /// frees to so the host can take it back. Without one, host memory the
+    /// guest has touched stays committed until the VM is dropped; see
+    /// [`Vm::balloon_reclaimed`].
+    pub fn balloon(mut self, enabled: bool) -> Self {
+        self.balloon = enabled;
+        self
+    }
+
+    /// Dump the guest to `path` when a run ends unexpectedly or its kernel
+    /// tests fail; see [`Vm::set_crash_dump`].
+    pub fn crash_dump(mut self, path: impl Into<PathBuf>, format: DumpFormat) -> Self {
+        self.crash_dump = Some((path.into(), format));
+        self
+    }
+
+    /// Log the pages the guest writes from the start; see
+    /// [`Vm::set_dirty_logging`].
+    pub fn dirty_logging(mut self, enabled: bool) -> Self {
+        self.dirty_logging = enabled;
+        self
+    }
+
+    /// Edit the CPUID table every vCPU is given, to hide features from the
+    /// guest or force them on. It already reports hostel as the hypervisor,
+    /// and the VM goes by the edited table, e.g. mapping memory without
+    /// 1 GiB pages when they are hidden. Each vCPU's APIC ID is set after.
+    pub fn cpuid(mut self, edit: impl FnMut(&mut CpuId) + 'a) -> Self {
+        self.edit_cpuid = Some(Box::new(edit));
+        self
+    }
+
+    pub fn build(self) -> Result<Vm> {
+        if !(1..=MAX_CPUS).contains(&self.cpu_count) {
+            return Err(Error::CpuCount {
+                count: self.cpu_count,
+            });
+        }
+        let invalid = |reason| Error::MemorySize {
+            size: self.mem_size,
+            reason,
+        };
+        if !self.mem_size.is_multiple_of(PAGE_SIZE) {
+            return Err(invalid("not a multiple of the page size"));
+        }
+        if self.mem_size <= PALLOC_FIRST_PAGE.as_usize() {
+            return Err(invalid("no room past the kernel image"));
+        }
+        if self.mem_size > DEFAULT_MEM_SIZE {
+            return Err(invalid("overlaps the device registers atop the direct map"));
+        }
+
+        let mut vm = Vm::create(
+            self.mem_size,
+            self.cpu_count,
+            self.hugepages,
+            self.edit_cpuid,
+        )?;
+        vm.set_run_flags(self.run_flags)?;
+        if !self.command_line.is_empty() {
+            vm.set_command_line(self.command_line)?;
+        }
+        if let Some(backend) = self.net_backend {
+            vm.attach_net(backend)?;
+        }
+        if let Some(outputs) = self.console_outputs {
+            vm.attach_console(outputs)?;
+        }
+        if let Some((root, read_only)) = &self.share {
+            vm.attach_share(root, *read_only)?;
+        }
+        if self.balloon {
+            vm.attach_balloon()?;
+        }
+        vm.claim_vacant_slots()?;
+        if let Some((path, format)) = self.crash_dump {
+            vm.set_crash_dump(path, format);
+        }
+        if let Some(elf) = self.kernel {
+            vm.load_elf(elf)?;
+        }
+        if let Some(archive) = self.initramfs {
+            vm.load_initramfs(archive)?;
+        }
+        // After loading, so the log holds only what the guest writes.
+        if self.dirty_logging {
+            vm.set_dirty_logging(true)?;
+        }
+        Ok(vm)
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use super::{Error, Result};
+
+/// A device the guest reaches through a range of physical addresses it
+/// does not have memory at.
+pub trait DeviceMmio: Send {
+    /// Handle a guest read of `data.len()` bytes at `offset` into the
+    /// device's range.
+    fn mmio_read(&mut self, offset: u64, data: &mut [u8]);
+
+    /// Handle a guest write at `offset` into the device's range.
+    fn mmio_write(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+}
+
+/// A device the guest reaches through a range of I/O ports.
+pub trait DevicePio: Send {
+    /// Handle a guest `in` of `data.len()` bytes at `offset` into the
+    /// device's ports.
+    fn pio_read(&mut self, offset: u16, data: &mut [u8]) -> Result<()>;
+
+    /// Handle a guest `out` at `offset` into the device's ports.
+    fn pio_write(&mut self, offset: u16, data: &[u8]) -> Result<()>;
+}
+
+/// A device the guest reaches through a range of model-specific registers,
+/// which KVM is told to leave to the VMM.
+pub trait DeviceMsr: Send {
+    /// Handle a guest `rdmsr` of the MSR `offset` into the device's range.
+    fn msr_read(&mut self, offset: u32) -> Result<u64>;
+
+    /// Handle a guest `wrmsr` of `value` to the MSR `offset` into the
+    /// device's range.
+    fn msr_write(&mut self, offset: u32, value: u64) -> Result<()>;
+}
+
+/// The devices on one kind of guest exit, by the ranges they claimed.
+pub struct Bus<A, D: ?Sized> {
+    // By start: each range's end and device. No two ranges overlap.
+    devices: BTreeMap<A, (A, Arc<Mutex<D>>)>,
+}
+
+/// The devices on the guest's MMIO exits.
+pub type MmioBus = Bus<u64, dyn DeviceMmio>;
+
+/// The devices on the guest's port I/O exits.
+pub type PioBus = Bus<u16, dyn DevicePio>;
+
+/// The devices on the guest's MSR exits.
+pub type MsrBus = Bus<u32, dyn DeviceMsr>;
+
+impl<A, D: ?Sized> Default for Bus<A, D> {
+    fn default() -> Self {
+        Self {
+            devices: BTreeMap::new(),
+        }
+    }
+}
+
+impl<A: Copy + Ord + Into<u64>, D: ?Sized> Bus<A, D> {
+    /// Have `device` handle `range`, which no other device may have.
+    pub fn register(&mut self, range: Range<A>, device: Arc<Mutex<D>>) -> Result<()> {
+        let before = self.devices.range(..=range.start).next_back();
+        let after = self.devices.range(range.start..).next();
+        if range.is_empty()
+            || before.is_some_and(|(_, &(end, _))| end > range.start)
+            || after.is_some_and(|(&start, _)| start < range.end)
+        {
+            return Err(Error::DeviceRange {
+                start: range.start.into(),
+                end: range.end.into(),
+            });
+        }
+        self.devices.insert(range.start, (range.end, device));
+        Ok(())
+    }
+
+    /// Whether a device has `addr`.
+    pub fn claims(&self, addr: A) -> bool {
+        self.find(addr, 1).is_some()
+    }
+
+    /// The ranges devices have, lowest first.
+    pub fn ranges(&self) -> impl Iterator<Item = Range<A>> + '_ {
+        self.devices.iter().map(|(&start, &(end, _))| start..end)
+    }
+
+    // The device whose range holds all `len` bytes from `addr`, and the
+    // offset of `addr` into it.
+    fn find(&self, addr: A, len: usize) -> Option<(&Mutex<D>, u64)> {
+        let (&start, (end, device)) = self.devices.range(..=addr).next_back()?;
+        let offset = addr.into() - start.into();
+        let size = (*end).into() - start.into();
+        (offset.checked_add(len as u64)? <= size).then_some((&**device, offset))
+    }
+}
+
+impl MmioBus {
+    /// Pass a guest read on to the device whose range holds all of it,
+    /// returning whether there was one.
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        match self.find(addr, data.len()) {
+            Some((device, offset)) => {
+                device.lock().unwrap().mmio_read(offset, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pass a guest write on to the device whose range holds all of it,
+    /// returning whether there was one.
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<bool> {
+        match self.find(addr, data.len()) {
+            Some((device, offset)) => {
+                device.lock().unwrap().mmio_write(offset, data)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl PioBus {
+    /// Pass a guest `in` on to the device whose ports hold all of it,
+    /// returning whether there was one.
+    pub fn read(&self, port: u16, data: &mut [u8]) -> Result<bool> {
+        match self.find(port, data.len()) {
+            Some((device, offset)) => {
+                device.lock().unwrap().pio_read(offset as u16, data)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Pass a guest `out` on to the device whose ports hold all of it,
+    /// returning whether there was one.
+    pub fn write(&self, port: u16, data: &[u8]) -> Result<bool> {
+        match self.find(port, data.len()) {
+            Some((device, offset)) => {
+                device.lock().unwrap().pio_write(offset as u16, data)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl MsrBus {
+    /// Pass a guest `rdmsr` on to the device that has the MSR, returning
+    /// what it read or `None` if there was none.
+    pub fn read(&self, index: u32) -> Result<Option<u64>> {
+        match self.find(index, 1) {
+            Some((device, offset)) => device.lock().unwrap().msr_read(offset as u32).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Pass a guest `wrmsr` on to the device that has the MSR, returning
+    /// whether there was one.
+    pub fn write(&self, index: u32, value: u64) -> Result<bool> {
+        match self.find(index, 1) {
+            Some((device, offset)) => {
+                device.lock().unwrap().msr_write(offset as u32, value)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keeps what is written to it, and reads as the offset read at.
+    #[derive(Default)]
+    struct Probe {
+        written: Vec<(u64, Vec<u8>)>,
+    }
+
+    impl DeviceMmio for Probe {
+        fn mmio_read(&mut self, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn mmio_write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+            self.written.push((offset, data.to_vec()));
+            Ok(())
+        }
+    }
+
+    impl DevicePio for Probe {
+        fn pio_read(&mut self, offset: u16, data: &mut [u8]) -> Result<()> {
+            self.mmio_read(offset.into(), data);
+            Ok(())
+        }
+
+        fn pio_write(&mut self, offset: u16, data: &[u8]) -> Result<()> {
+            self.mmio_write(offset.into(), data)
+        }
+    }
+
+    #[test]
+    fn accesses_reach_the_device_at_their_offset() {
+        let probe = Arc::new(Mutex::new(Probe::default()));
+        let mut bus = MmioBus::default();
+        bus.register(0x1000..0x1100, probe.clone()).unwrap();
+        assert!(bus.claims(0x10ff) && !bus.claims(0x1100));
+
+        let mut data = [0; 4];
+        assert!(bus.read(0x1010, &mut data));
+        assert_eq!(data, [0x10; 4]);
+        assert!(bus.write(0x10fc, &[1, 2, 3, 4]).unwrap());
+        assert_eq!(probe.lock().unwrap().written, [(0xfc, vec![1, 2, 3, 4])]);
+
+        // Nothing answers outside the range, or across its end.
+        assert!(!bus.read(0xfff, &mut data));
+        assert!(!bus.read(0x10fe, &mut data));
+        assert!(!bus.write(0x1100, &[0]).unwrap());
+    }
+
+    #[test]
+    fn ports_reach_the_device_at_their_offset() {
+        let probe = Arc::new(Mutex::new(Probe::default()));
+        let mut bus = PioBus::default();
+        bus.register(0x3f8..0x400, probe.clone()).unwrap();
+
+        let mut data = [0; 1];
+        assert!(bus.read(0x3fd, &mut data).unwrap());
+        assert_eq!(data, [5]);
+        assert!(bus.write(0x3f8, b"x").unwrap());
+        assert_eq!(probe.lock().unwrap().written, [(0, b"x".to_vec())]);
+        assert!(!bus.write(0x400, b"x").unwrap());
+        assert!(!bus.read(0xffff, &mut data).unwrap());
+    }
+
+    impl DeviceMsr for Probe {
+        fn msr_read(&mut self, offset: u32) -> Result<u64> {
+            Ok(offset.into())
+        }
+
+        fn msr_write(&mut self, offset: u32, value: u64) -> Result<()> {
+            self.mmio_write(offset.into(), &value.to_le_bytes())
+        }
+    }
+
+    #[test]
+    fn msrs_reach_the_device_at_their_offset() {
+        let probe = Arc::new(Mutex::new(Probe::default()));
+        let mut bus = MsrBus::default();
+        bus.register(0x4b56_0000..0x4b56_0010, probe.clone())
+            .unwrap();
+        assert!(bus.ranges().eq(std::iter::once(0x4b56_0000..0x4b56_0010)));
+
+        assert_eq!(bus.read(0x4b56_0003).unwrap(), Some(3));
+        assert!(bus.write(0x4b56_000f, 7).unwrap());
+        assert_eq!(
+            probe.lock().unwrap().written,
+            [(0xf, 7u64.to_le_bytes().to_vec())]
+        );
+        assert_eq!(bus.read(0x4b56_0010).unwrap(), None);
+        assert!(!bus.write(0x10, 0).unwrap());
+    }
+
+    #[test]
+    fn ranges_may_not_overlap() {
+        let mut bus = MmioBus::default();
+        let probe = || Arc::new(Mutex::new(Probe::default()));
+        bus.register(0x1000..0x1100, probe()).unwrap();
+
+        for range in [
+            0x1000..0x1010,
+            0xf00..0x1001,
+            0x10ff..0x110f,
+            0x2000..0x2000,
+        ] {
+            assert!(matches!(
+                bus.register(range, probe()),
+                Err(Error::DeviceRange { .. })
+            ));
+        }
+        bus.register(0xf00..0x1000, probe()).unwrap();
+        bus.register(0x1100..0x1200, probe()).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use kernel::memory::constants::SMALL_PAGE_SIZE;
+use vm_memory::GuestAddress;
+
+use super::x64::GUEST_BASE;
+
+/// The 4 KiB pages of guest RAM the guest wrote to, as KVM logged them:
+/// bit `n` of the bitmap stands for the page at `n * 4 KiB`.
+///
+/// Only the guest's own writes are in it. What the VMM writes into guest
+/// memory itself, such as frames a virtio device receives, is not.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirtyBitmap {
+    words: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    pub(super) fn new(words: Vec<u64>) -> Self {
+        Self { words }
+    }
+
+    /// Whether the guest wrote to the page holding `addr`.
+    pub fn is_dirty(&self, addr: GuestAddress) -> bool {
+        let page = ((addr.0 - GUEST_BASE.0) / SMALL_PAGE_SIZE as u64) as usize;
+        self.words
+            .get(page / 64)
+            .is_some_and(|word| word & (1 << (page % 64)) != 0)
+    }
+
+    /// The start of every page the guest wrote to, lowest first.
+    pub fn pages(&self) -> impl Iterator<Item = GuestAddress> + '_ {
+        self.words.iter().enumerate().flat_map(|(index, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| {
+                    let page = (index * 64 + bit) as u64;
+                    GuestAddress(GUEST_BASE.0 + page * SMALL_PAGE_SIZE as u64)
+                })
+        })
+    }
+
+    /// How many pages the guest wrote to.
+    pub fn count(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// The bitmap as KVM returns it, 64 pages to a word.
+    pub fn as_words(&self) -> &[u64] {
+        &self.words
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits_stand_for_the_pages_at_their_index() {
+        let bitmap = DirtyBitmap::new(vec![0b101, 1 << 63]);
+        let page = SMALL_PAGE_SIZE as u64;
+
+        assert_eq!(bitmap.count(), 3);
+        assert_eq!(
+            bitmap.pages().collect::<Vec<_>>(),
+            [
+                GuestAddress(0),
+                GuestAddress(2 * page),
+                GuestAddress(127 * page)
+            ]
+        );
+        assert!(bitmap.is_dirty(GuestAddress(2 * page + 0x123)));
+        assert!(!bitmap.is_dirty(GuestAddress(page)));
+        // Past the end of guest RAM nothing is dirty.
+        assert!(!bitmap.is_dirty(GuestAddress(128 * page)));
+    }
+}
@@ -0,0 +1,338 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use goblin::elf::header::header64::SIZEOF_EHDR;
+use goblin::elf::header::{
+    EI_CLASS, EI_DATA, EI_VERSION, ELFCLASS64, ELFDATA2LSB, ELFMAG, EM_X86_64, ET_CORE, EV_CURRENT,
+};
+use goblin::elf::note::NT_PRSTATUS;
+use goblin::elf::program_header::program_header64::SIZEOF_PHDR;
+use goblin::elf::program_header::{PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE};
+use kernel::memory::address::{DirectMap, KernelDirectMap, PhysicalAddr};
+use kernel::memory::constants::{KERNEL_CODE_PHYS, KERNEL_CODE_SIZE, KERNEL_CODE_VIRT};
+use kvm_bindings::{kvm_regs, kvm_sregs};
+use kvm_ioctls::VcpuFd;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryBackend, GuestMemoryMmap, GuestMemoryRegion};
+
+use super::Result;
+
+// Guest memory is written a chunk at a time, and pages of zeroes are left
+// as holes in the file.
+const CHUNK_SIZE: usize = 2 << 20;
+const HOLE_SIZE: usize = 4096;
+
+// Bytes of an x86-64 `elf_prstatus`, and where its registers start.
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REGS: usize = 112;
+// The owner of the note describing every vCPU in full, control registers
+// included.
+const HOSTEL_NOTE: &[u8] = b"HOSTEL";
+const NT_HOSTEL_VCPUS: u32 = 1;
+
+/// What [`Vm::run`](super::Vm::run) writes the guest to when it ends
+/// abnormally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// An ELF core file: guest RAM at both its physical addresses and the
+    /// kernel's, and each vCPU's registers as a `NT_PRSTATUS` note, so gdb
+    /// can open it next to the kernel ELF.
+    Elf,
+    /// Guest RAM as it sits at its physical addresses, with the vCPUs'
+    /// registers as text in a file of the same name plus `.vcpus`.
+    Raw,
+}
+
+/// The file a raw dump of `path` keeps the vCPUs' registers in.
+pub fn vcpus_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".vcpus");
+    name.into()
+}
+
+// Each vCPU's registers, as the guest left them.
+struct VcpuState {
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+}
+
+/// Write the guest's memory and vCPUs to `path` in `format`.
+pub(super) fn write(
+    path: &Path,
+    format: DumpFormat,
+    mem: &GuestMemoryMmap<()>,
+    vcpus: &[VcpuFd],
+) -> Result<()> {
+    let states = vcpus
+        .iter()
+        .map(|vcpu| {
+            Ok(VcpuState {
+                regs: vcpu.get_regs()?,
+                sregs: vcpu.get_sregs()?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut file = File::create(path)?;
+    match format {
+        DumpFormat::Elf => write_elf(&mut file, mem, &states)?,
+        DumpFormat::Raw => {
+            for region in mem.iter() {
+                let start = region.start_addr();
+                write_memory(&mut file, mem, start, region.len(), start.0)?;
+            }
+            std::fs::write(vcpus_path(path), describe(&states))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_elf(file: &mut File, mem: &GuestMemoryMmap<()>, states: &[VcpuState]) -> Result<()> {
+    let mut notes = Vec::new();
+    for (index, state) in states.iter().enumerate() {
+        push_note(&mut notes, b"CORE", NT_PRSTATUS, &prstatus(index, state));
+    }
+    push_note(
+        &mut notes,
+        HOSTEL_NOTE,
+        NT_HOSTEL_VCPUS,
+        describe(states).as_bytes(),
+    );
+
+    // Every region at its place in the direct map, and the kernel image at
+    // its link address too; both segments share the file's bytes.
+    let code = KERNEL_CODE_PHYS.as_u64();
+    let holds_code =
+        |&(start, len): &(u64, u64)| start <= code && code + KERNEL_CODE_SIZE as u64 <= start + len;
+    let regions: Vec<_> = mem.iter().map(|r| (r.start_addr().0, r.len())).collect();
+    let phnum = 1 + regions.len() + regions.iter().filter(|r| holds_code(r)).count();
+    let notes_offset = (SIZEOF_EHDR + phnum * SIZEOF_PHDR) as u64;
+    let mut offset = (notes_offset + notes.len() as u64).next_multiple_of(HOLE_SIZE as u64);
+    let mut headers = vec![Segment::note(notes_offset, notes.len() as u64)];
+    let mut loads = Vec::new();
+    for &(start, len) in &regions {
+        let virt = KernelDirectMap
+            .p2v(PhysicalAddr::new(start as usize))
+            .as_u64();
+        headers.push(Segment::load(offset, virt, start, len));
+        if holds_code(&(start, len)) {
+            headers.push(Segment::load(
+                offset + (code - start),
+                KERNEL_CODE_VIRT.as_u64(),
+                code,
+                KERNEL_CODE_SIZE as u64,
+            ));
+        }
+        loads.push((start, len, offset));
+        offset += len;
+    }
+
+    let mut head = elf_header(phnum as u16);
+    for segment in &headers {
+        segment.write_to(&mut head);
+    }
+    head.extend_from_slice(&notes);
+    file.write_all(&head)?;
+    for (start, len, offset) in loads {
+        write_memory(file, mem, GuestAddress(start), len, offset)?;
+    }
+    Ok(())
+}
+
+// Copy `len` bytes of guest memory from `start` to the file at `offset`,
+// leaving pages of zeroes as holes.
+fn write_memory(
+    file: &mut File,
+    mem: &GuestMemoryMmap<()>,
+    start: GuestAddress,
+    len: u64,
+    offset: u64,
+) -> Result<()> {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut done = 0;
+    while done < len {
+        let size = (len - done).min(CHUNK_SIZE as u64) as usize;
+        mem.read_slice(&mut chunk[..size], GuestAddress(start.0 + done))?;
+        for (index, page) in chunk[..size].chunks(HOLE_SIZE).enumerate() {
+            if page.iter().any(|&byte| byte != 0) {
+                let at = offset + done + (index * HOLE_SIZE) as u64;
+                file.seek(SeekFrom::Start(at))?;
+                file.write_all(page)?;
+            }
+        }
+        done += size as u64;
+    }
+    let end = offset + len;
+    if file.metadata()?.len() < end {
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+fn elf_header(phnum: u16) -> Vec<u8> {
+    let mut header = vec![0; SIZEOF_EHDR];
+    header[..4].copy_from_slice(ELFMAG);
+    header[EI_CLASS] = ELFCLASS64;
+    header[EI_DATA] = ELFDATA2LSB;
+    header[EI_VERSION] = EV_CURRENT;
+    header[16..18].copy_from_slice(&ET_CORE.to_le_bytes());
+    header[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    header[20..24].copy_from_slice(&u32::from(EV_CURRENT).to_le_bytes());
+    // e_phoff, then e_ehsize, e_phentsize and e_phnum.
+    header[32..40].copy_from_slice(&(SIZEOF_EHDR as u64).to_le_bytes());
+    header[52..54].copy_from_slice(&(SIZEOF_EHDR as u16).to_le_bytes());
+    header[54..56].copy_from_slice(&(SIZEOF_PHDR as u16).to_le_bytes());
+    header[56..58].copy_from_slice(&phnum.to_le_bytes());
+    header
+}
+
+// A program header of the core file.
+struct Segment {
+    ty: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    size: u64,
+    align: u64,
+}
+
+impl Segment {
+    fn note(offset: u64, size: u64) -> Self {
+        Self {
+            ty: PT_NOTE,
+            flags: PF_R,
+            offset,
+            vaddr: 0,
+            paddr: 0,
+            size,
+            align: 4,
+        }
+    }
+
+    fn load(offset: u64, vaddr: u64, paddr: u64, size: u64) -> Self {
+        Self {
+            ty: PT_LOAD,
+            flags: PF_R | PF_W | PF_X,
+            offset,
+            vaddr,
+            paddr,
+            size,
+            align: HOLE_SIZE as u64,
+        }
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.ty.to_le_bytes());
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        for value in [
+            self.offset,
+            self.vaddr,
+            self.paddr,
+            self.size,
+            self.size,
+            self.align,
+        ] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+fn push_note(out: &mut Vec<u8>, name: &[u8], ty: u32, desc: &[u8]) {
+    let name_size = name.len() + 1;
+    out.extend_from_slice(&(name_size as u32).to_le_bytes());
+    out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    out.extend_from_slice(&ty.to_le_bytes());
+    out.extend_from_slice(name);
+    out.resize(out.len() + name_size.next_multiple_of(4) - name.len(), 0);
+    out.extend_from_slice(desc);
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+// vCPU `index`'s registers as Linux lays a thread's out in a core file, in
+// `user_regs_struct` order. Its pid is its index plus one.
+fn prstatus(index: usize, VcpuState { regs, sregs }: &VcpuState) -> Vec<u8> {
+    let mut status = vec![0; PRSTATUS_SIZE];
+    let pid = (index as u32 + 1).to_le_bytes();
+    status[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&pid);
+    // No system call was interrupted, so `orig_rax` is -1.
+    let user_regs = [
+        regs.r15,
+        regs.r14,
+        regs.r13,
+        regs.r12,
+        regs.rbp,
+        regs.rbx,
+        regs.r11,
+        regs.r10,
+        regs.r9,
+        regs.r8,
+        regs.rax,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        u64::MAX,
+        regs.rip,
+        sregs.cs.selector.into(),
+        regs.rflags,
+        regs.rsp,
+        sregs.ss.selector.into(),
+        sregs.fs.base,
+        sregs.gs.base,
+        sregs.ds.selector.into(),
+        sregs.es.selector.into(),
+        sregs.fs.selector.into(),
+        sregs.gs.selector.into(),
+    ];
+    for (i, value) in user_regs.iter().enumerate() {
+        let at = PRSTATUS_REGS + i * 8;
+        status[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    status
+}
+
+// Every vCPU's registers, for people.
+fn describe(states: &[VcpuState]) -> String {
+    let mut text = String::new();
+    for (index, VcpuState { regs, sregs }) in states.iter().enumerate() {
+        let _ = writeln!(text, "vcpu {index}:");
+        let _ = writeln!(
+            text,
+            "  rip={:#018x} rsp={:#018x} rflags={:#x}",
+            regs.rip, regs.rsp, regs.rflags
+        );
+        let _ = writeln!(
+            text,
+            "  rax={:#018x} rbx={:#018x} rcx={:#018x} rdx={:#018x}",
+            regs.rax, regs.rbx, regs.rcx, regs.rdx
+        );
+        let _ = writeln!(
+            text,
+            "  rsi={:#018x} rdi={:#018x} rbp={:#018x} r8={:#018x}",
+            regs.rsi, regs.rdi, regs.rbp, regs.r8
+        );
+        let _ = writeln!(
+            text,
+            "  r9={:#018x} r10={:#018x} r11={:#018x} r12={:#018x}",
+            regs.r9, regs.r10, regs.r11, regs.r12
+        );
+        let _ = writeln!(
+            text,
+            "  r13={:#018x} r14={:#018x} r15={:#018x}",
+            regs.r13, regs.r14, regs.r15
+        );
+        let _ = writeln!(
+            text,
+            "  cr0={:#x} cr2={:#x} cr3={:#x} cr4={:#x} efer={:#x}",
+            sregs.cr0, sregs.cr2, sregs.cr3, sregs.cr4, sregs.efer
+        );
+        let _ = writeln!(
+            text,
+            "  cs={:#x} ss={:#x} fs.base={:#x} gs.base={:#x}",
+            sregs.cs.selector, sregs.ss.selector, sregs.fs.base, sregs.gs.base
+        );
+    }
+    text
+}
@@ -1,5 +1,9 @@
+use kernel::memory::constants::MAX_CPUS;
 use thiserror::Error as ThisError;
-use vm_memory::{GuestMemoryError, mmap::FromRangesError};
+use vm_memory::{
+    GuestMemoryError,
+    mmap::{FromRangesError, MmapRegionError},
+};
 
 #[derive(ThisError, Debug)]
 pub enum Error {
@@ -15,14 +19,59 @@ pub enum Error {
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("invalid guest memory size {size:#x}: {reason}")]
+    MemorySize { size: usize, reason: &'static str },
+
+    #[error("cannot reserve {size:#x} bytes of host huge pages for guest memory: {source}")]
+    HugePages {
+        size: usize,
+        source: MmapRegionError,
+    },
+
+    #[error(
+        "initramfs of {size:#x} bytes does not fit the {room:#x} bytes of guest RAM the kernel can spare"
+    )]
+    Initramfs { size: usize, room: usize },
+
+    #[error("command line of {len} bytes is longer than the {max} the guest has room for")]
+    CommandLine { len: usize, max: usize },
+
+    #[error("invalid vCPU count {count}: must be 1 to {max}", max = MAX_CPUS)]
+    CpuCount { count: usize },
+
     #[error("elf parse error: {0}")]
     Parsing(#[from] goblin::error::Error),
 
+    #[error("device range {start:#x}..{end:#x} is empty or overlaps another device's")]
+    DeviceRange { start: u64, end: u64 },
+
+    #[error("virtio device error: {0}")]
+    Virtio(String),
+
     #[error("unexpected vCPU exit: {0}")]
     UnexpectedExit(String),
 
+    #[error("guest {access} of unhandled MSR {index:#x} at rip {rip:#x}")]
+    UnhandledMsr {
+        access: String,
+        index: u32,
+        rip: u64,
+    },
+
+    #[error("MSRs {start:#x}..{end:#x} do not fit KVM's MSR filter")]
+    MsrFilter { start: u32, end: u32 },
+
+    #[error("dirty page logging is not enabled")]
+    DirtyLogging,
+
+    #[error("guest still running after {0:?}")]
+    Timeout(std::time::Duration),
+
     #[error("kernel integration tests failed")]
     KernelTestsFailed,
+
+    #[error("failure log error: {0}")]
+    FailureLog(#[from] serde_json::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
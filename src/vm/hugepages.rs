@@ -0,0 +1,52 @@
+use vm_memory::mmap::{FromRangesError, MmapRegionBuilder};
+use vm_memory::{GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+
+use super::x64::GUEST_BASE;
+use super::{Error, Result};
+
+/// What backs guest RAM on the host. The kernel maps all of it in 2 MiB
+/// pages, and each one the host backs with a 2 MiB page of its own too
+/// takes one TLB entry rather than 512, and one step less on an EPT miss.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HugePages {
+    /// Only 4 KiB host pages.
+    Off,
+    /// Transparent huge pages, which the host hands out as the guest first
+    /// touches each 2 MiB and falls back from to 4 KiB pages when it has
+    /// none to spare. Hosts that disable them entirely, rather than leave
+    /// them to `madvise`, give the guest 4 KiB pages as with `Off`.
+    #[default]
+    Transparent,
+    /// hugetlbfs pages from the host's pool (`vm.nr_hugepages`), reserved
+    /// for all of guest memory as the VM is created, which fails if the
+    /// pool cannot cover it.
+    Explicit,
+}
+
+/// Map `size` bytes of guest RAM, backed as `hugepages` asks.
+pub(super) fn map_guest_memory(size: usize, hugepages: HugePages) -> Result<GuestMemoryMmap<()>> {
+    let region = match hugepages {
+        HugePages::Explicit => MmapRegionBuilder::new(size)
+            .with_mmap_prot(libc::PROT_READ | libc::PROT_WRITE)
+            .with_mmap_flags(
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
+            )
+            .with_hugetlbfs(true)
+            .build()
+            .map_err(|source| Error::HugePages { size, source })?,
+        HugePages::Off | HugePages::Transparent => {
+            MmapRegion::new(size).map_err(FromRangesError::from)?
+        }
+    };
+    if hugepages == HugePages::Transparent {
+        // Linux starts mappings this large on a 2 MiB boundary, so the
+        // host's huge pages line up with the guest's. Only a host without
+        // transparent huge pages refuses, and that leaves 4 KiB pages.
+        // SAFETY: the range is the mapping just made; the advice only
+        // changes what backs it.
+        unsafe { libc::madvise(region.as_ptr().cast(), size, libc::MADV_HUGEPAGE) };
+    }
+    let region =
+        GuestRegionMmap::new(region, GUEST_BASE).ok_or(FromRangesError::InvalidGuestRegion)?;
+    Ok(GuestMemoryMmap::from_regions(vec![region]).map_err(FromRangesError::from)?)
+}
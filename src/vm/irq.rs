@@ -0,0 +1,47 @@
+use kvm_ioctls::VmFd;
+use vmm_sys_util::eventfd::{EFD_NONBLOCK, EventFd};
+
+use super::Result;
+
+/// COM1's ISA line, which the kernel unmasks on its PIC.
+pub const COM1_GSI: u32 = 4;
+
+/// The network card's line, an ISA line nothing else uses.
+pub const VIRTIO_NET_GSI: u32 = 5;
+
+/// The virtio console's line, which the kernel, having nothing to read
+/// from it, leaves masked.
+pub const VIRTIO_CONSOLE_GSI: u32 = 6;
+
+/// The host share's line, which the kernel leaves masked: each request is
+/// answered before the guest's notification returns.
+pub const VIRTIO_SHARE_GSI: u32 = 7;
+
+/// The balloon's line, which the kernel leaves masked: each report is
+/// taken before the guest's notification returns.
+pub const VIRTIO_BALLOON_GSI: u32 = 9;
+
+/// An interrupt line into the in-kernel irqchip. Devices raise it by writing
+/// to an eventfd KVM watches, so any thread can do so without going through
+/// the VM. Each trigger is an edge, which is how the guest programs its PIC.
+pub struct IrqLine {
+    gsi: u32,
+    event: EventFd,
+}
+
+impl IrqLine {
+    pub(super) fn new(vm: &VmFd, gsi: u32) -> Result<Self> {
+        let event = EventFd::new(EFD_NONBLOCK)?;
+        vm.register_irqfd(&event, gsi)?;
+        Ok(Self { gsi, event })
+    }
+
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+
+    pub fn trigger(&self) -> Result<()> {
+        self.event.write(1)?;
+        Ok(())
+    }
+}
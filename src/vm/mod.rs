@@ -1,65 +1,211 @@
+mod builder;
+mod bus;
+mod dirty;
+mod dump;
 pub mod error;
+mod hugepages;
+mod irq;
+mod nmi;
 mod serial;
+mod stats;
+mod terminal;
+mod vcpu;
+mod virtio;
 mod x64;
 
+use self::builder::EditCpuid;
+pub use self::builder::VmBuilder;
+pub use self::bus::{DeviceMmio, DeviceMsr, DevicePio};
+use self::bus::{MmioBus, MsrBus, PioBus};
+pub use self::dirty::DirtyBitmap;
+pub use self::dump::{DumpFormat, vcpus_path};
 pub use self::error::{Error, Result};
+pub use self::hugepages::HugePages;
+use self::hugepages::map_guest_memory;
+pub use self::irq::{
+    COM1_GSI, IrqLine, VIRTIO_BALLOON_GSI, VIRTIO_CONSOLE_GSI, VIRTIO_NET_GSI, VIRTIO_SHARE_GSI,
+};
+pub use self::nmi::NmiInjector;
+pub use self::stats::VmStats;
+pub use self::virtio::{DEFAULT_MAC, NetBackend, Tap};
+pub use kernel::process::ExitStatus;
+pub use kvm_bindings::CpuId;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
 use kernel::{
-    boot::{KERNEL_TEST_EXIT_FAILURE, KERNEL_TEST_EXIT_PORT, KERNEL_TEST_EXIT_SUCCESS, RunFlags},
+    boot::{
+        BootInfo, E820_RAM, E820_RESERVED, Initramfs, MemoryMap, MemoryRegion, RunFlags,
+        StartupMailbox,
+    },
     memory::address::KernelDirectMap,
-    memory::constants::{KERNEL_CODE_SIZE, KERNEL_CODE_VIRT, MAX_PHYSICAL_ADDR, RUN_FLAGS_PHYS},
+    memory::constants::{
+        BOOT_INFO_PHYS, COMMAND_LINE_MAX, COMMAND_LINE_PHYS, KERNEL_CODE_PHYS, KERNEL_CODE_SIZE,
+        KERNEL_CODE_VIRT, PAGE_SIZE, PALLOC_FIRST_PAGE, STARTUP_MAILBOX_PHYS, VIRTIO_MMIO_PHYS,
+        VIRTIO_MMIO_SIZE,
+    },
+    virtio::{BALLOON_SLOT, CONSOLE_SLOT, NET_SLOT, SHARE_SLOT, SLOT_SIZE},
+};
+use kvm_bindings::{
+    KVM_MAX_CPUID_ENTRIES, KVM_MP_STATE_RUNNABLE, KVM_PIT_SPEAKER_DUMMY, kvm_mp_state,
+    kvm_pit_config,
 };
-use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
 use kvm_ioctls::{Kvm, VmFd};
-use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
-use x64::{GUEST_BASE, init_x64};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryBackend, GuestMemoryMmap, GuestMemoryRegion};
+use x64::{
+    MEMORY_SLOT, enable_kvmclock, enable_msr_exits, init_x64, load_kernel_segment, register_memory,
+    set_apic_id, set_hypervisor_vendor, set_msr_filter, supports_gigapages, supports_kvmclock,
+};
 
 // goblin is already a dependency of the workspace; we reuse it here to parse ELF
 use goblin::elf::Elf;
 use goblin::elf::program_header::PT_LOAD;
-use serial::SerialConsole16550;
+use serial::{COM1_PORTS, SerialConsole16550};
+use virtio::{
+    ConsoleMmio, VacantSlot, VirtioBalloon, VirtioConsole, VirtioMmio, VirtioNet, VirtioShare,
+};
 
-const MEM_SIZE: usize = MAX_PHYSICAL_ADDR + 1;
+/// Guest memory used by [`Vm::new`]: everything the kernel's direct map
+/// covers up to the virtio devices' registers at its top. Host pages are
+/// only committed once the guest touches them.
+pub const DEFAULT_MEM_SIZE: usize = VIRTIO_MMIO_PHYS.as_usize();
+
+/// The name the host share goes by in the guest.
+const SHARE_TAG: &str = "host";
 
 pub struct Vm {
     _kvm: Kvm,
-    _vm: VmFd,
+    vm: VmFd,
     vcpus: Vec<kvm_ioctls::VcpuFd>,
     boot_mem: GuestMemoryMmap<()>,
-    serial: SerialConsole16550,
-    run_flags: RunFlags,
+    serial: Arc<Mutex<SerialConsole16550>>,
+    mmio: MmioBus,
+    pio: PioBus,
+    msrs: MsrBus,
+    boot_info: BootInfo,
+    nmi: NmiInjector,
+    forward_stdin: bool,
+    crash_dump: Option<(PathBuf, DumpFormat)>,
+    dirty_logging: bool,
+    stats: VmStats,
+    // Also on the MMIO bus; kept for the thread that feeds it frames.
+    net: Option<Arc<Mutex<VirtioMmio<VirtioNet>>>>,
+    // Also on the MMIO bus; kept to read back what it reclaimed.
+    balloon: Option<Arc<Mutex<VirtioMmio<VirtioBalloon>>>>,
 }
 
 impl Vm {
     pub fn new() -> Result<Self> {
+        Self::builder().build()
+    }
+
+    /// Start describing a VM: its memory, vCPUs, run flags and kernel.
+    pub fn builder<'a>() -> VmBuilder<'a> {
+        VmBuilder::default()
+    }
+
+    // `VmBuilder::build` has checked the sizes.
+    fn create(
+        mem_size: usize,
+        cpu_count: usize,
+        hugepages: HugePages,
+        edit_cpuid: Option<EditCpuid<'_>>,
+    ) -> Result<Self> {
         let kvm = Kvm::new()?;
         let vm = kvm.create_vm()?;
-        let vcpu = vm.create_vcpu(0)?;
-        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
-        vcpu.set_cpuid2(&cpuid)?;
-        let vcpus = vec![vcpu];
+        // The in-kernel PICs, IOAPIC and local APICs, which devices reach
+        // through `IrqLine`s. The kernel's preemption timer is the vCPU's
+        // local APIC; the PIT is only there to calibrate the TSC against.
+        // The speaker flag also puts port 0x61, which gates and reads back
+        // channel 2, in KVM.
+        vm.create_irq_chip()?;
+        enable_msr_exits(&vm)?;
+        vm.create_pit2(kvm_pit_config {
+            flags: KVM_PIT_SPEAKER_DUMMY,
+            ..Default::default()
+        })?;
+        let mut cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+        set_hypervisor_vendor(&mut cpuid);
+        // Before anything is decided by what the guest CPU supports.
+        if let Some(mut edit) = edit_cpuid {
+            edit(&mut cpuid);
+        }
+        let gigapages = supports_gigapages(&cpuid);
+        let kvmclock = supports_kvmclock(&cpuid);
+        let mut vcpus = Vec::with_capacity(cpu_count);
+        for index in 0..cpu_count {
+            let vcpu = vm.create_vcpu(index as u64)?;
+            set_apic_id(&mut cpuid, index as u32);
+            vcpu.set_cpuid2(&cpuid)?;
+            if kvmclock {
+                enable_kvmclock(&vcpu, index)?;
+            }
+            // With the in-kernel local APIC every vCPU but the first would
+            // wait for an INIT/SIPI; they are set up in long mode instead.
+            if index != 0 {
+                vcpu.set_mp_state(kvm_mp_state {
+                    mp_state: KVM_MP_STATE_RUNNABLE,
+                })?;
+            }
+            vcpus.push(vcpu);
+        }
 
-        let boot_mem: GuestMemoryMmap<()> =
-            GuestMemoryMmap::from_ranges(&[(GUEST_BASE, MEM_SIZE)])?;
+        let boot_mem = map_guest_memory(mem_size, hugepages)?;
 
-        init_x64(&vm, &vcpus, &boot_mem, MEM_SIZE, &KernelDirectMap)?;
+        init_x64(
+            &vm,
+            &vcpus,
+            &boot_mem,
+            mem_size,
+            gigapages,
+            &KernelDirectMap,
+        )?;
+        let mut boot_info = BootInfo::new(cpu_count as u32);
+        boot_info.memory_map = memory_map(mem_size, 0);
+        let mailbox = StartupMailbox::new();
+        boot_mem.write_slice(
+            mailbox.as_bytes(),
+            GuestAddress(STARTUP_MAILBOX_PHYS.as_u64()),
+        )?;
 
+        let com1_irq = IrqLine::new(&vm, COM1_GSI)?;
+        let serial = Arc::new(Mutex::new(SerialConsole16550::new(Some(com1_irq))));
+        let mut pio = PioBus::default();
+        pio.register(COM1_PORTS, serial.clone())?;
         let mut vm = Self {
             _kvm: kvm,
-            _vm: vm,
+            vm,
             vcpus,
             boot_mem,
-            serial: SerialConsole16550::new(),
-            run_flags: RunFlags::empty(),
+            serial,
+            mmio: MmioBus::default(),
+            pio,
+            msrs: MsrBus::default(),
+            boot_info,
+            nmi: NmiInjector::default(),
+            forward_stdin: false,
+            crash_dump: None,
+            dirty_logging: false,
+            stats: VmStats::default(),
+            net: None,
+            balloon: None,
         };
-        vm.write_run_flags()?;
+        vm.write_boot_info()?;
         Ok(vm)
     }
 
     /// Load an executable ELF blob into the guest memory and adjust the entry
     /// point accordingly.  The loader expects that the guest memory has already
-    /// been registered with KVM (done in `Vm::new`).
+    /// been registered with KVM (done in `Vm::new`). Every loadable segment
+    /// must lie within the kernel image, at the physical address the page
+    /// tables map it to, and within the file.
     pub fn load_elf(&mut self, data: &[u8]) -> Result<()> {
         let elf = Elf::parse(data)?;
+        let malformed = |reason: String| Error::Parsing(goblin::error::Error::Malformed(reason));
 
         for ph in &elf.program_headers {
             if ph.p_type != PT_LOAD {
@@ -73,11 +219,27 @@ impl Vm {
             if ph.p_vaddr < KERNEL_CODE_VIRT.as_u64()
                 || ph.p_vaddr + memsz as u64 > KERNEL_CODE_VIRT.as_u64() + KERNEL_CODE_SIZE as u64
             {
-                return Err(Error::Parsing(goblin::error::Error::Malformed(format!(
+                return Err(malformed(format!(
                     "Program header with p_vaddr {:#x} and memsz {:#x} is out of bounds",
                     ph.p_vaddr, memsz
-                ))));
+                )));
+            }
+            let expected_paddr =
+                KERNEL_CODE_PHYS.as_u64() + (ph.p_vaddr - KERNEL_CODE_VIRT.as_u64());
+            if ph.p_paddr != expected_paddr {
+                return Err(malformed(format!(
+                    "Program header with p_vaddr {:#x} has p_paddr {:#x}, expected {:#x}",
+                    ph.p_vaddr, ph.p_paddr, expected_paddr
+                )));
             }
+            if filesz > memsz || file_offset.saturating_add(filesz) > data.len() {
+                return Err(malformed(format!(
+                    "Program header with p_vaddr {:#x} has filesz {:#x} past memsz {:#x} or the end of the file",
+                    ph.p_vaddr, filesz, memsz
+                )));
+            }
+
+            load_kernel_segment(&self.boot_mem, ph.p_vaddr, memsz as u64, ph.p_flags)?;
 
             // copy the initialized data from the file
             self.boot_mem.write_slice(
@@ -93,64 +255,319 @@ impl Vm {
             }
         }
 
-        // update the guest RIP to the ELF entry point
-        let mut regs = self.vcpus[0].get_regs()?;
-        regs.rip = elf.entry;
-        self.vcpus[0].set_regs(&regs)?;
+        // every vCPU starts at the ELF entry point
+        for vcpu in &self.vcpus {
+            let mut regs = vcpu.get_regs()?;
+            regs.rip = elf.entry;
+            vcpu.set_regs(&regs)?;
+        }
 
         Ok(())
     }
 
+    /// Load `archive`, a newc cpio archive, as the initramfs the kernel
+    /// unpacks into its root filesystem as it boots. It goes in the last
+    /// pages of guest RAM, which the memory map reserves until the kernel
+    /// has unpacked it, and must leave the kernel at least a page besides.
+    /// Loading another replaces it; an empty one leaves the guest without.
+    pub fn load_initramfs(&mut self, archive: &[u8]) -> Result<()> {
+        let mem_size = self.mem_size();
+        let reserved = archive.len().next_multiple_of(PAGE_SIZE);
+        let room = mem_size - PALLOC_FIRST_PAGE.as_usize() - PAGE_SIZE;
+        if reserved > room {
+            return Err(Error::Initramfs {
+                size: archive.len(),
+                room,
+            });
+        }
+        let base = (mem_size - reserved) as u64;
+        self.boot_mem.write_slice(archive, GuestAddress(base))?;
+        self.boot_info.initramfs = Initramfs {
+            base,
+            len: archive.len() as u64,
+        };
+        self.boot_info.memory_map = memory_map(mem_size, reserved);
+        self.write_boot_info()
+    }
+
     pub fn set_run_flags(&mut self, run_flags: RunFlags) -> Result<()> {
-        self.run_flags = run_flags;
-        self.write_run_flags()
+        self.boot_info.flags = run_flags;
+        self.write_boot_info()
+    }
+
+    /// Give the kernel `command_line`, of at most `COMMAND_LINE_MAX` bytes,
+    /// which it prints as it boots. Empty by default.
+    pub fn set_command_line(&mut self, command_line: &str) -> Result<()> {
+        let len = command_line.len();
+        if len > COMMAND_LINE_MAX {
+            return Err(Error::CommandLine {
+                len,
+                max: COMMAND_LINE_MAX,
+            });
+        }
+        self.boot_mem.write_slice(
+            command_line.as_bytes(),
+            GuestAddress(COMMAND_LINE_PHYS.as_u64()),
+        )?;
+        self.boot_info.set_command_line(COMMAND_LINE_PHYS, len);
+        self.write_boot_info()
     }
 
-    /// Run the single vCPU until it halts.
-    pub fn run(&mut self) -> Result<()> {
-        use kvm_ioctls::VcpuExit;
+    /// Have [`Vm::run`] feed host stdin to the guest's serial port, for
+    /// interactive guests. Off by default.
+    pub fn set_forward_stdin(&mut self, enabled: bool) {
+        self.forward_stdin = enabled;
+    }
 
-        self.write_run_flags()?;
-        let run_tests = self.run_flags.run_tests();
+    /// Have [`Vm::run`] write the guest's memory and vCPU state to `path`
+    /// when the guest stops unexpectedly, times out or its kernel tests
+    /// fail, for looking into what went wrong afterwards. Off by default.
+    pub fn set_crash_dump(&mut self, path: impl Into<PathBuf>, format: DumpFormat) {
+        self.crash_dump = Some((path.into(), format));
+    }
 
-        loop {
-            match self.vcpus[0].run()? {
-                VcpuExit::Hlt => {
-                    self.serial.flush()?;
-                    if run_tests {
-                        return Err(Error::UnexpectedExit(
-                            "guest halted before kernel tests reported PASS/FAIL".to_string(),
-                        ));
-                    }
-                    return Ok(());
-                }
-                VcpuExit::IoOut(port, data) => {
-                    if port == KERNEL_TEST_EXIT_PORT {
-                        self.serial.flush()?;
-                        return Self::handle_kernel_test_exit(run_tests, data);
+    /// Have KVM log the pages the guest writes, for
+    /// [`Vm::take_dirty_bitmap`]. Enabling it starts the log with no page
+    /// dirty. Off by default, as logging makes the guest's first write to
+    /// each page since the last take fault into KVM.
+    pub fn set_dirty_logging(&mut self, enabled: bool) -> Result<()> {
+        register_memory(&self.vm, &self.boot_mem, self.mem_size(), enabled)?;
+        self.dirty_logging = enabled;
+        Ok(())
+    }
+
+    /// The pages the guest has written since dirty logging was enabled or
+    /// this was last called, which starts the log afresh. For snapshots and
+    /// checks that only need to look at what changed. Fails unless
+    /// [`Vm::set_dirty_logging`] enabled logging.
+    pub fn take_dirty_bitmap(&self) -> Result<DirtyBitmap> {
+        if !self.dirty_logging {
+            return Err(Error::DirtyLogging);
+        }
+        let words = self.vm.get_dirty_log(MEMORY_SLOT, self.mem_size())?;
+        Ok(DirtyBitmap::new(words))
+    }
+
+    fn mem_size(&self) -> usize {
+        self.boot_mem
+            .iter()
+            .map(|region| region.len() as usize)
+            .sum()
+    }
+
+    // Give the guest a network card whose frames go through `backend`.
+    fn attach_net(&mut self, backend: Box<dyn NetBackend>) -> Result<()> {
+        let irq = IrqLine::new(&self.vm, VIRTIO_NET_GSI)?;
+        let device = VirtioNet::new(backend, DEFAULT_MAC);
+        let net = Arc::new(Mutex::new(VirtioMmio::new(
+            device,
+            self.boot_mem.clone(),
+            irq,
+        )));
+        self.register_mmio(virtio_slot(NET_SLOT), net.clone())?;
+        self.net = Some(net);
+        Ok(())
+    }
+
+    // Give the guest a virtio console with `outputs` as its ports after
+    // the first.
+    fn attach_console(&mut self, outputs: Vec<Box<dyn Write + Send>>) -> Result<()> {
+        let irq = IrqLine::new(&self.vm, VIRTIO_CONSOLE_GSI)?;
+        let device = VirtioConsole::new(outputs);
+        let transport = VirtioMmio::new(device, self.boot_mem.clone(), irq);
+        let console = ConsoleMmio::new(transport, self.serial.clone());
+        self.register_mmio(virtio_slot(CONSOLE_SLOT), Arc::new(Mutex::new(console)))
+    }
+
+    // Share the host directory at `root` with the guest, which mounts it at
+    // `/host`.
+    fn attach_share(&mut self, root: &Path, read_only: bool) -> Result<()> {
+        let irq = IrqLine::new(&self.vm, VIRTIO_SHARE_GSI)?;
+        let device = VirtioShare::new(root, SHARE_TAG, read_only)?;
+        let share = VirtioMmio::new(device, self.boot_mem.clone(), irq);
+        self.register_mmio(virtio_slot(SHARE_SLOT), Arc::new(Mutex::new(share)))
+    }
+
+    // Give the guest a balloon to report its free memory to.
+    fn attach_balloon(&mut self) -> Result<()> {
+        let irq = IrqLine::new(&self.vm, VIRTIO_BALLOON_GSI)?;
+        let balloon = Arc::new(Mutex::new(VirtioMmio::new(
+            VirtioBalloon::new(),
+            self.boot_mem.clone(),
+            irq,
+        )));
+        self.register_mmio(virtio_slot(BALLOON_SLOT), balloon.clone())?;
+        self.balloon = Some(balloon);
+        Ok(())
+    }
+
+    /// Bytes of guest memory the balloon has handed back to the host, over
+    /// every report the guest made; memory reported twice counts twice.
+    /// `None` without a balloon.
+    pub fn balloon_reclaimed(&self) -> Option<u64> {
+        let balloon = self.balloon.as_ref()?;
+        Some(balloon.lock().unwrap().device().reclaimed())
+    }
+
+    // Fill the virtio slots no device took, so the guest's probes of them
+    // find nothing rather than stopping the VM.
+    fn claim_vacant_slots(&mut self) -> Result<()> {
+        for slot in 0..VIRTIO_MMIO_SIZE / SLOT_SIZE {
+            let range = virtio_slot(slot);
+            if !self.mmio.claims(range.start) {
+                self.register_mmio(range, Arc::new(Mutex::new(VacantSlot)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Have `device` handle guest accesses to the physical addresses in
+    /// `range`, which must not be RAM or another device's.
+    pub fn register_mmio(
+        &mut self,
+        range: Range<u64>,
+        device: Arc<Mutex<dyn DeviceMmio>>,
+    ) -> Result<()> {
+        self.mmio.register(range, device)
+    }
+
+    /// Have `device` handle guest `in`s and `out`s on the ports in `range`,
+    /// which must not be another device's. The serial port has COM1's.
+    pub fn register_pio(
+        &mut self,
+        range: Range<u16>,
+        device: Arc<Mutex<dyn DevicePio>>,
+    ) -> Result<()> {
+        self.pio.register(range, device)
+    }
+
+    /// Have `device` handle guest `rdmsr`s and `wrmsr`s of the MSRs in
+    /// `range`, taking them from KVM, which must not be another device's.
+    /// KVM can hand over at most 16 ranges of up to 12288 MSRs. MSRs that
+    /// neither KVM nor a device has end the run with
+    /// [`Error::UnhandledMsr`].
+    pub fn register_msr(
+        &mut self,
+        range: Range<u32>,
+        device: Arc<Mutex<dyn DeviceMsr>>,
+    ) -> Result<()> {
+        self.msrs.register(range, device)?;
+        let ranges: Vec<_> = self.msrs.ranges().collect();
+        set_msr_filter(&self.vm, &ranges)
+    }
+
+    /// Connect a device to guest interrupt line `gsi`. GSIs 0 to 15 are the
+    /// ISA lines, which reach the guest through both its PIC and its IOAPIC;
+    /// 16 to 23 are IOAPIC only.
+    pub fn irq_line(&self, gsi: u32) -> Result<IrqLine> {
+        IrqLine::new(&self.vm, gsi)
+    }
+
+    /// A handle for breaking into the guest from another thread while
+    /// [`Vm::run`] is going.
+    pub fn nmi_injector(&self) -> NmiInjector {
+        self.nmi.clone()
+    }
+
+    /// Run the guest until it powers off, returning the exit status it
+    /// powered off with: that of its first process, for hostel's kernel. A
+    /// kernel test run that passes returns `ExitStatus::Exited(0)`. Each vCPU runs on
+    /// a thread of its own; the first to power off or fail ends the run for
+    /// all of them.
+    pub fn run(&mut self) -> Result<ExitStatus> {
+        self.run_until(None)
+    }
+
+    /// [`Vm::run`], but stop every vCPU and fail with [`Error::Timeout`] if
+    /// the guest is still going after `timeout`.
+    pub fn run_with_timeout(&mut self, timeout: Duration) -> Result<ExitStatus> {
+        self.run_until(Some(timeout))
+    }
+
+    fn run_until(&mut self, timeout: Option<Duration>) -> Result<ExitStatus> {
+        let result = self.run_vcpus(timeout);
+        if let (
+            Err(Error::UnexpectedExit(_) | Error::Timeout(_) | Error::KernelTestsFailed),
+            Some((path, format)),
+        ) = (&result, &self.crash_dump)
+        {
+            // The run's error is what matters; a dump that fails is only
+            // reported.
+            match dump::write(path, *format, &self.boot_mem, &self.vcpus) {
+                Ok(()) => eprintln!("vmm: guest dumped to {}", path.display()),
+                Err(err) => eprintln!("vmm: dumping the guest to {} failed: {err}", path.display()),
+            }
+        }
+        result
+    }
+
+    fn run_vcpus(&mut self, timeout: Option<Duration>) -> Result<ExitStatus> {
+        // The guest's realtime clock starts from the host's as the run does.
+        self.boot_info.wall_clock_nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        self.write_boot_info()?;
+
+        let shared = vcpu::Shared::new(
+            &self.serial,
+            &self.mmio,
+            &self.pio,
+            &self.msrs,
+            self.boot_info.flags.run_tests(),
+            self.vcpus.len(),
+        );
+        let nmi = &self.nmi;
+        let mut stats = vec![VmStats::default(); self.vcpus.len()];
+        let (exits, exited) = mpsc::channel();
+        let result = thread::scope(|scope| {
+            for (index, (vcpu, stats)) in self.vcpus.iter_mut().zip(&mut stats).enumerate() {
+                let (shared, exits) = (&shared, exits.clone());
+                scope.spawn(move || {
+                    let nmi = (index == 0).then_some(nmi);
+                    if let Some(result) = vcpu::run(index, vcpu, shared, nmi, stats).transpose() {
+                        // Only the first result is waited for.
+                        let _ = exits.send(result);
                     }
-                    if self.serial.handles_range(port, data.len()) {
-                        self.serial.io_out(port, data)?;
-                    } else {
-                        return Err(Error::UnexpectedExit(format!(
-                            "unhandled IoOut on port {port:#x} with {} byte(s)",
-                            data.len()
-                        )));
+                });
+            }
+            if self.forward_stdin {
+                let (shared, exits) = (&shared, exits.clone());
+                scope.spawn(move || {
+                    if let Err(err) = terminal::forward_stdin(shared) {
+                        let _ = exits.send(Err(err));
                     }
-                }
-                VcpuExit::IoIn(port, data) => {
-                    if self.serial.handles_range(port, data.len()) {
-                        self.serial.io_in(port, data);
-                    } else {
-                        return Err(Error::UnexpectedExit(format!(
-                            "unhandled IoIn on port {port:#x} with {} byte(s)",
-                            data.len()
-                        )));
+                });
+            }
+            if let Some(net) = &self.net {
+                let (shared, exits) = (&shared, exits.clone());
+                scope.spawn(move || {
+                    if let Err(err) = virtio::forward_input(net, || shared.stopping()) {
+                        let _ = exits.send(Err(err));
                     }
-                }
-                other => return Err(Error::UnexpectedExit(format!("{:?}", other))),
+                });
+            }
+            drop(exits);
+            let result = match timeout {
+                Some(timeout) => exited.recv_timeout(timeout).map_err(|err| match err {
+                    mpsc::RecvTimeoutError::Timeout => Error::Timeout(timeout),
+                    mpsc::RecvTimeoutError::Disconnected => no_result(),
+                }),
+                None => exited.recv().map_err(|_| no_result()),
             }
+            .flatten();
+            shared.stop();
+            result
+        });
+        for stats in &stats {
+            self.stats.merge(stats);
         }
+        result
+    }
+
+    /// What the guest's vCPUs exited for, and where, over every run so far.
+    /// Its `Display` is a summary for finding hot device emulation.
+    pub fn stats(&self) -> &VmStats {
+        &self.stats
     }
 
     /// Return a reference to the guest physical memory.  This is primarily used
@@ -159,42 +576,115 @@ impl Vm {
         &self.boot_mem
     }
 
-    fn write_run_flags(&mut self) -> Result<()> {
+    /// Recent guest console output, oldest line first. Used to attach panic
+    /// messages and exception dumps to failure reports.
+    pub fn console_transcript(&self) -> Vec<String> {
+        self.serial
+            .lock()
+            .unwrap()
+            .transcript()
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn write_boot_info(&mut self) -> Result<()> {
         self.boot_mem.write_slice(
-            &self.run_flags.bits().to_le_bytes(),
-            GuestAddress(RUN_FLAGS_PHYS.as_u64()),
+            self.boot_info.as_bytes(),
+            GuestAddress(BOOT_INFO_PHYS.as_u64()),
         )?;
         Ok(())
     }
+}
 
-    fn handle_kernel_test_exit(run_tests: bool, data: &[u8]) -> Result<()> {
-        if !run_tests {
-            return Err(Error::UnexpectedExit(
-                "kernel emitted test exit code without run_tests flag".to_string(),
-            ));
-        }
-        if data.len() != core::mem::size_of::<u32>() {
-            return Err(Error::UnexpectedExit(format!(
-                "kernel test exit code has invalid size: {}",
-                data.len()
-            )));
-        }
+// What a run ends with when nothing reported how it ended.
+fn no_result() -> Error {
+    Error::UnexpectedExit("every vCPU thread exited without a result".to_string())
+}
+
+// The physical addresses of virtio slot `slot`'s registers.
+fn virtio_slot(slot: usize) -> Range<u64> {
+    let start = VIRTIO_MMIO_PHYS.as_u64() + (slot * SLOT_SIZE) as u64;
+    start..start + SLOT_SIZE as u64
+}
 
-        let code = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-        match code {
-            KERNEL_TEST_EXIT_SUCCESS => Ok(()),
-            KERNEL_TEST_EXIT_FAILURE => Err(Error::KernelTestsFailed),
-            other => Err(Error::UnexpectedExit(format!(
-                "unknown kernel test exit code: {other:#x}"
-            ))),
+// Everything below the page allocator's first page holds page tables, the
+// kernel image and boot info, and the last `initramfs` bytes hold the
+// initramfs; the rest is RAM for the kernel to hand out.
+fn memory_map(mem_size: usize, initramfs: usize) -> MemoryMap {
+    let first_page = PALLOC_FIRST_PAGE.as_u64();
+    let initramfs_base = (mem_size - initramfs) as u64;
+    let mut map = MemoryMap::empty();
+    for region in [
+        MemoryRegion::new(0, first_page, E820_RESERVED),
+        MemoryRegion::new(first_page, initramfs_base - first_page, E820_RAM),
+        MemoryRegion::new(initramfs_base, initramfs as u64, E820_RESERVED),
+    ] {
+        if region.len == 0 {
+            continue;
         }
+        map.push(region)
+            .expect("memory map has room for the boot regions");
     }
+    map
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::vm::Vm;
-    use kernel::boot::RunFlags;
+    use crate::failures::{DEFAULT_FAILURE_LOG, FailureLog, FailureRecord, build_hash};
+    use crate::vm::serial::COM1_PORTS;
+    use crate::vm::virtio::testing::{Captured, TempDir};
+    use crate::vm::x64::supports_kvmclock;
+    use crate::vm::x64::{PTE_NX, PTE_PS, PTE_RW};
+    use crate::vm::{
+        DEFAULT_MAC, DEFAULT_MEM_SIZE, DeviceMsr, DumpFormat, Error, ExitStatus, HugePages, Vm,
+        vcpus_path,
+    };
+    use goblin::elf::Elf;
+    use goblin::elf::header::ET_CORE;
+    use goblin::elf::note::NT_PRSTATUS;
+    use goblin::elf::program_header::PT_LOAD;
+    use kernel::boot::{
+        BootInfo, E820_RAM, E820_RESERVED, Initramfs, MemoryMap, MemoryRegion, POWER_OFF_PORT,
+        PvClock, RunFlags,
+    };
+    use kernel::memory::constants::{
+        BOOT_INFO_PHYS, COMMAND_LINE_MAX, DIRECT_MAP_PD, DIRECT_MAP_PDPT, KERNEL_CODE_PHYS,
+        KERNEL_CODE_PT, KERNEL_CODE_VIRT, MAX_CPUS, PAGE_SIZE, PALLOC_FIRST_PAGE, PVCLOCK_PHYS,
+        SMALL_PAGE_SIZE, STARTUP_MAILBOX_PHYS,
+    };
+    use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+    use kvm_ioctls::Kvm;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime};
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryBackend};
+
+    const SMALL_GUEST_MEM_MIB: usize = 128;
+    // Far longer than any test guest runs, so a hung one fails its test
+    // rather than stalling the suite.
+    const TEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+    // Runs the kernel's integration tests, appending a record to the
+    // failure log at the crate root when they fail so `hostel failures`
+    // sees failures from test runs too.
+    fn run_kernel_tests(vm: &mut Vm, kernel: &[u8]) -> Result<ExitStatus, Error> {
+        let result = vm.run_with_timeout(TEST_TIMEOUT);
+        if let Err(err @ Error::KernelTestsFailed) = &result {
+            let transcript = vm.console_transcript();
+            let record = FailureRecord::from_run(
+                &build_hash(kernel),
+                err,
+                transcript.iter().map(String::as_str),
+            );
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(DEFAULT_FAILURE_LOG);
+            if let Err(log_err) = FailureLog::new(path).append(&record) {
+                eprintln!("could not record the failure: {log_err}");
+            }
+        }
+        result
+    }
 
     #[test]
     fn vm_loads_kernel_elf_from_build_script() {
@@ -204,7 +694,82 @@ mod tests {
 
         let mut vm = Vm::new().unwrap();
         vm.load_elf(&data).expect("load elf");
-        vm.run().expect("run guest");
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(0));
+    }
+
+    #[test]
+    fn vm_maps_kernel_code_read_only_and_data_no_execute() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+        let elf = Elf::parse(&data).expect("parse kernel elf");
+
+        let mut vm = Vm::new().unwrap();
+        vm.load_elf(&data).expect("load elf");
+
+        let pte = |vaddr: u64| -> u64 {
+            let index = (vaddr - KERNEL_CODE_VIRT.as_u64()) / SMALL_PAGE_SIZE as u64;
+            vm.guest_memory()
+                .read_obj(GuestAddress(KERNEL_CODE_PT.as_u64() + index * 8))
+                .unwrap()
+        };
+
+        let entry = pte(elf.entry);
+        assert_eq!(entry & (PTE_RW | PTE_NX), 0, "code must be RX");
+
+        let data_segment = elf
+            .program_headers
+            .iter()
+            .find(|ph| ph.p_type == PT_LOAD && ph.p_flags & 2 != 0)
+            .expect("kernel has a writable segment");
+        let entry = pte(data_segment.p_vaddr);
+        assert_eq!(
+            entry & (PTE_RW | PTE_NX),
+            PTE_RW | PTE_NX,
+            "data must be RW+NX"
+        );
+
+        // The first direct-map leaf is a 1 GiB PDPT entry when the CPU
+        // supports it and a 2 MiB PD entry otherwise.
+        let mem = vm.guest_memory();
+        let pdpte: u64 = mem
+            .read_obj(GuestAddress(DIRECT_MAP_PDPT.as_u64()))
+            .unwrap();
+        let leaf = if pdpte & PTE_PS != 0 {
+            pdpte
+        } else {
+            mem.read_obj(GuestAddress(DIRECT_MAP_PD.as_u64())).unwrap()
+        };
+        assert_ne!(leaf & PTE_NX, 0, "direct map must be NX");
+    }
+
+    #[test]
+    fn vm_goes_by_the_cpuid_the_builder_edits() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+
+        // A CPU without RDRAND or 1 GiB pages.
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .cpuid(|cpuid| {
+                for entry in cpuid.as_mut_slice() {
+                    match entry.function {
+                        1 => entry.ecx &= !(1 << 30),
+                        0x8000_0001 => entry.edx &= !(1 << 26),
+                        _ => {}
+                    }
+                }
+            })
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+
+        let pdpte: u64 = vm
+            .guest_memory()
+            .read_obj(GuestAddress(DIRECT_MAP_PDPT.as_u64()))
+            .unwrap();
+        assert_eq!(pdpte & PTE_PS, 0, "direct map must go through 2 MiB pages");
+        assert_eq!(pdpte & !0xfff, DIRECT_MAP_PD.as_u64());
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(0));
     }
 
     #[test]
@@ -212,10 +777,755 @@ mod tests {
         let path = env!("KERNEL_BIN");
         let data = std::fs::read(path).expect("read kernel elf");
 
+        let mut vm = Vm::builder()
+            .run_flags(RunFlags::empty().with_run_tests(true))
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        run_kernel_tests(&mut vm, &data).expect("kernel integration tests must pass");
+    }
+
+    #[test]
+    fn vm_runs_kernel_integration_tests_with_a_host_share() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+        let dir = TempDir::new("share-tests");
+        std::fs::write(dir.path().join("input.txt"), "hello from the host\n").unwrap();
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .share_dir(dir.path(), false)
+            .run_flags(RunFlags::empty().with_run_tests(true))
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        run_kernel_tests(&mut vm, &data)
+            .expect("kernel integration tests must pass with a host share");
+
+        assert!(
+            vm.console_transcript()
+                .iter()
+                .any(|line| line == "hostfs: host mounted at /host"),
+            "the guest must mount the share"
+        );
+        let written = std::fs::read_to_string(dir.path().join("out/result.txt")).unwrap();
+        assert_eq!(written, "hello from the guest");
+    }
+
+    #[test]
+    fn vm_dumps_a_guest_that_stops_unexpectedly_as_an_elf_core() {
+        let dir = TempDir::new("crash-dump");
+        let path = dir.path().join("core");
+        // With no kernel loaded the vCPU faults at once, has no IDT to take
+        // the fault with, and shuts down.
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .vcpus(2)
+            .crash_dump(&path, DumpFormat::Elf)
+            .build()
+            .expect("create vm");
+        assert!(matches!(vm.run(), Err(Error::UnexpectedExit(_))));
+
+        let data = std::fs::read(&path).expect("read crash dump");
+        let elf = Elf::parse(&data).expect("parse crash dump");
+        assert_eq!(elf.header.e_type, ET_CORE);
+        let threads = elf
+            .iter_note_headers(&data)
+            .unwrap()
+            .map(Result::unwrap)
+            .filter(|note| note.name == "CORE" && note.n_type == NT_PRSTATUS)
+            .count();
+        assert_eq!(threads, 2);
+
+        let ram = elf
+            .program_headers
+            .iter()
+            .find(|ph| ph.p_type == PT_LOAD && ph.p_paddr == 0)
+            .expect("guest RAM segment");
+        assert_eq!(ram.p_filesz as usize, SMALL_GUEST_MEM_MIB << 20);
+        let mut mailbox = [0; 16];
+        vm.guest_memory()
+            .read_slice(&mut mailbox, GuestAddress(STARTUP_MAILBOX_PHYS.as_u64()))
+            .unwrap();
+        let at = (ram.p_offset + STARTUP_MAILBOX_PHYS.as_u64()) as usize;
+        assert_eq!(data[at..at + mailbox.len()], mailbox);
+    }
+
+    #[test]
+    fn vm_dumps_failed_guests_raw_with_their_registers_beside() {
+        let dir = TempDir::new("crash-dump");
+        let path = dir.path().join("ram");
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .crash_dump(&path, DumpFormat::Raw)
+            .build()
+            .expect("create vm");
+        assert!(vm.run().is_err());
+
+        let size = std::fs::metadata(&path).expect("crash dump").len();
+        assert_eq!(size as usize, SMALL_GUEST_MEM_MIB << 20);
+        let vcpus = std::fs::read_to_string(vcpus_path(&path)).unwrap();
+        assert!(vcpus.starts_with("vcpu 0:\n  rip="), "{vcpus}");
+        assert!(vcpus.contains("cr3=0x0"), "{vcpus}");
+    }
+
+    // A VM whose every vCPU runs `code` in place of the kernel, and the
+    // virtual address it starts at.
+    fn vm_running(code: &[u8], vcpus: usize) -> (Vm, u64) {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+        let elf = Elf::parse(&data).expect("parse kernel elf");
+
+        let vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .vcpus(vcpus)
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        let entry = KERNEL_CODE_PHYS.as_u64() + (elf.entry - KERNEL_CODE_VIRT.as_u64());
+        vm.guest_memory()
+            .write_slice(code, GuestAddress(entry))
+            .unwrap();
+        (vm, elf.entry)
+    }
+
+    #[test]
+    fn vm_stops_a_guest_still_running_at_its_timeout() {
+        // Every vCPU spins at the entry point for good: jmp $.
+        let (mut vm, _) = vm_running(&[0xeb, 0xfe], 2);
+
+        let timeout = Duration::from_millis(200);
+        let started = Instant::now();
+        assert!(matches!(
+            vm.run_with_timeout(timeout),
+            Err(Error::Timeout(t)) if t == timeout
+        ));
+        assert!(started.elapsed() < TEST_TIMEOUT);
+    }
+
+    // Reads as its offset plus 0x1_0000_0000, and keeps what is written.
+    #[derive(Default)]
+    struct MsrProbe {
+        written: Vec<(u32, u64)>,
+    }
+
+    impl DeviceMsr for MsrProbe {
+        fn msr_read(&mut self, offset: u32) -> crate::vm::Result<u64> {
+            Ok((1 << 32) | offset as u64)
+        }
+
+        fn msr_write(&mut self, offset: u32, value: u64) -> crate::vm::Result<()> {
+            self.written.push((offset, value));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn vm_hands_msrs_to_the_device_that_took_them() {
+        // mov ecx, 0x0bad0005; rdmsr; wrmsr; out 0xf5, al
+        let code = [
+            0xb9, 0x05, 0x00, 0xad, 0x0b, 0x0f, 0x32, 0x0f, 0x30, 0xe6, 0xf5,
+        ];
+        let (mut vm, _) = vm_running(&code, 1);
+        let probe = Arc::new(Mutex::new(MsrProbe::default()));
+        vm.register_msr(0x0bad_0000..0x0bad_0010, probe.clone())
+            .unwrap();
+
+        // The guest powers off with the low byte it read.
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(5));
+        assert_eq!(probe.lock().unwrap().written, [(5, (1 << 32) | 5)]);
+        assert_eq!(vm.stats().exits["X86Rdmsr"], 1);
+    }
+
+    #[test]
+    fn vm_reports_the_status_the_guest_powered_off_with() {
+        // mov ax, 9; out 0xf5, ax: a wait status for a process SIGKILL ended.
+        let code = [0x66, 0xb8, 0x09, 0x00, 0x66, 0xe7, 0xf5];
+        let (mut vm, _) = vm_running(&code, 1);
+
+        let status = vm.run().expect("run guest");
+        assert_eq!(status, ExitStatus::Killed(9));
+        assert_eq!(status.exit_code(), 137);
+    }
+
+    #[test]
+    fn vm_names_the_msr_nothing_handles_and_where_it_was_read() {
+        // mov ecx, 0x0bad1000; rdmsr
+        let code = [0xb9, 0x00, 0x10, 0xad, 0x0b, 0x0f, 0x32];
+        let (mut vm, entry) = vm_running(&code, 1);
+        vm.register_msr(
+            0x0bad_0000..0x0bad_0010,
+            Arc::new(Mutex::new(MsrProbe::default())),
+        )
+        .unwrap();
+
+        let err = vm.run().unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::UnhandledMsr { access, index: 0x0bad_1000, rip }
+                    if access == "rdmsr" && *rip == entry + 5
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn vm_runs_kernel_integration_tests_in_small_guest() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .run_flags(RunFlags::empty().with_run_tests(true))
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        run_kernel_tests(&mut vm, &data)
+            .expect("kernel integration tests must pass in a small guest");
+    }
+
+    #[test]
+    fn vm_runs_kernel_integration_tests_with_page_scrubbing() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .run_flags(
+                RunFlags::empty()
+                    .with_run_tests(true)
+                    .with_scrub_on_free(true),
+            )
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        run_kernel_tests(&mut vm, &data)
+            .expect("kernel integration tests must pass with page scrubbing");
+    }
+
+    #[test]
+    fn vm_runs_kernel_integration_tests_with_long_time_slices() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .run_flags(
+                RunFlags::empty()
+                    .with_run_tests(true)
+                    .with_quantum_ticks(20),
+            )
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        run_kernel_tests(&mut vm, &data)
+            .expect("kernel integration tests must pass with long time slices");
+    }
+
+    #[test]
+    fn vm_survives_injected_nmis_and_reports_state() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+
         let mut vm = Vm::new().unwrap();
-        vm.set_run_flags(RunFlags::empty().with_run_tests(true))
-            .expect("write run flags");
         vm.load_elf(&data).expect("load elf");
-        vm.run().expect("kernel integration tests must pass");
+        let injector = vm.nmi_injector();
+        let mem = vm.guest_memory().clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let kicker = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                // The guest is done in a fraction of a second, so rather
+                // than wait a fixed time from the start, break in as soon as
+                // the boot vCPU has its IDT, then keep at it until the guest
+                // is gone.
+                let idt_loaded = GuestAddress(STARTUP_MAILBOX_PHYS.as_u64() + 4);
+                while mem.read_obj::<u32>(idt_loaded).unwrap() == 0 && !done.load(Ordering::Relaxed)
+                {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                while !done.load(Ordering::Relaxed) {
+                    injector.inject();
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+            })
+        };
+        let result = vm.run();
+        done.store(true, Ordering::Relaxed);
+        kicker.join().unwrap();
+
+        assert_eq!(result.expect("run guest"), ExitStatus::Exited(0));
+        assert!(
+            vm.console_transcript()
+                .iter()
+                .any(|line| line.starts_with("nmi: interrupted rip")),
+            "guest must report the state an NMI interrupted"
+        );
+    }
+
+    #[test]
+    fn vm_starts_every_vcpu_and_runs_on_the_first() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .vcpus(4)
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(0));
+
+        let online: u32 = vm
+            .guest_memory()
+            .read_obj(GuestAddress(STARTUP_MAILBOX_PHYS.as_u64()))
+            .unwrap();
+        assert_eq!(boot_info(&vm).cpu_count, 4);
+        assert_eq!(online, 4, "every vCPU must check in");
+
+        // Booting talks to the serial port, and ends on the power-off port.
+        let stats = vm.stats();
+        assert!(stats.ports[&COM1_PORTS.start] > 0, "{stats}");
+        assert_eq!(stats.ports[&POWER_OFF_PORT], 1, "{stats}");
+        assert!(stats.exits["IoOut"] >= stats.ports[&COM1_PORTS.start]);
+        assert!(!stats.guest_time.is_zero() && !stats.host_time.is_zero());
+    }
+
+    #[test]
+    fn vm_logs_the_pages_the_guest_writes() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .vcpus(2)
+            .kernel(&data)
+            .dirty_logging(true)
+            .build()
+            .expect("create vm");
+        // Loading the kernel was the VMM's doing, not the guest's.
+        assert_eq!(vm.take_dirty_bitmap().unwrap().count(), 0);
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(0));
+
+        let dirty = vm.take_dirty_bitmap().unwrap();
+        let mailbox = GuestAddress(STARTUP_MAILBOX_PHYS.as_u64());
+        assert!(dirty.is_dirty(mailbox), "vCPUs check in at the mailbox");
+        assert!(dirty.pages().any(|page| page.0 == mailbox.0 & !0xfff));
+        assert!(dirty.count() < SMALL_GUEST_MEM_MIB * 256);
+        // Taking the log starts it afresh, and the guest has stopped.
+        assert_eq!(vm.take_dirty_bitmap().unwrap().count(), 0);
+
+        vm.set_dirty_logging(false).unwrap();
+        assert!(matches!(vm.take_dirty_bitmap(), Err(Error::DirtyLogging)));
+    }
+
+    #[test]
+    fn vm_keeps_every_vcpus_kvmclock_current() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+        let kvm = Kvm::new().unwrap();
+        if !supports_kvmclock(&kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap()) {
+            return;
+        }
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .vcpus(2)
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(0));
+
+        for cpu in 0..2 {
+            let clock = PVCLOCK_PHYS.as_u64() + (cpu * size_of::<PvClock>()) as u64;
+            let mut bytes = [0u8; size_of::<PvClock>()];
+            vm.guest_memory()
+                .read_slice(&mut bytes, GuestAddress(clock))
+                .unwrap();
+            let version = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+            let mul = u32::from_le_bytes(bytes[24..28].try_into().unwrap());
+            assert!(version != 0 && version % 2 == 0, "vCPU {cpu}: {version}");
+            assert_ne!(mul, 0, "vCPU {cpu}");
+        }
+    }
+
+    // An ARP frame for IPv4 over Ethernet, from `sender` to `target`, each
+    // a link and a protocol address.
+    fn arp_frame(
+        dst: [u8; 6],
+        op: u8,
+        sender: ([u8; 6], [u8; 4]),
+        target: ([u8; 6], [u8; 4]),
+    ) -> Vec<u8> {
+        let mut frame = dst.to_vec();
+        frame.extend_from_slice(&sender.0);
+        frame.extend_from_slice(&[0x08, 0x06, 0, 1, 0x08, 0x00, 6, 4, 0, op]);
+        for (hardware, protocol) in [sender, target] {
+            frame.extend_from_slice(&hardware);
+            frame.extend_from_slice(&protocol);
+        }
+        frame
+    }
+
+    #[test]
+    fn vm_guest_answers_arp_over_virtio_net() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+        let (backend, peer) = UnixDatagram::pair().unwrap();
+        peer.set_nonblocking(true).unwrap();
+
+        // Ask who has the guest's address as its router, before it has
+        // booted, so the request is waiting when the driver comes up.
+        let router = ([0x02, 0, 0, 0, 0, 0x02], [10, 0, 2, 2]);
+        let guest = (DEFAULT_MAC, [10, 0, 2, 15]);
+        let request = arp_frame([0xff; 6], 1, router, ([0; 6], guest.1));
+        peer.send(&request).unwrap();
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .net_backend(Box::new(backend))
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(0));
+
+        let mut frames = Vec::new();
+        let mut buf = [0u8; 2048];
+        while let Ok(len) = peer.recv(&mut buf) {
+            frames.push(buf[..len].to_vec());
+        }
+        assert!(
+            frames.contains(&arp_frame([0xff; 6], 1, guest, ([0; 6], guest.1))),
+            "guest must announce its address"
+        );
+        assert!(
+            frames.contains(&arp_frame(router.0, 2, guest, router)),
+            "guest must answer its router's request"
+        );
+        assert!(
+            vm.console_transcript()
+                .iter()
+                .any(|line| line.starts_with("net: virtio-net")),
+            "guest must report its network card"
+        );
+    }
+
+    #[test]
+    fn vm_virtio_console_separates_kernel_log_from_program_output() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+        let output = Captured::default();
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .virtio_console(vec![Box::new(output.clone())])
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(0));
+
+        let output = String::from_utf8(output.contents()).unwrap();
+        assert!(
+            output.contains("task A: done via SYS_write"),
+            "programs' output must reach its port: {output:?}"
+        );
+        assert!(!output.contains("kernel: boot"));
+        assert!(
+            vm.console_transcript()
+                .iter()
+                .any(|line| line == "kernel: boot"),
+            "kernel messages must join the console transcript"
+        );
+        assert!(
+            !vm.console_transcript()
+                .iter()
+                .any(|line| line.contains("done via SYS_write"))
+        );
+    }
+
+    #[test]
+    fn vm_takes_back_the_memory_the_guest_frees_through_its_balloon() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .balloon(true)
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        assert_eq!(vm.balloon_reclaimed(), Some(0));
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(0));
+
+        assert!(
+            vm.console_transcript()
+                .iter()
+                .any(|line| line.starts_with("balloon:")),
+            "guest must find its balloon"
+        );
+        let reclaimed = vm.balloon_reclaimed().unwrap();
+        assert!(reclaimed > 0, "guest must report what it frees");
+        assert_eq!(reclaimed % PAGE_SIZE as u64, 0);
+
+        let vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .build()
+            .unwrap();
+        assert_eq!(vm.balloon_reclaimed(), None);
+    }
+
+    // The boot info as the VM last wrote it.
+    fn boot_info(vm: &Vm) -> BootInfo {
+        let mut bytes = [0u8; size_of::<BootInfo>()];
+        vm.guest_memory()
+            .read_slice(&mut bytes, GuestAddress(BOOT_INFO_PHYS.as_u64()))
+            .unwrap();
+        // SAFETY: the boot info is made of integers, which any bytes are.
+        unsafe { std::ptr::read_unaligned(bytes.as_ptr().cast()) }
+    }
+
+    #[test]
+    fn vm_tells_the_kernel_what_it_needs_in_one_boot_info() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+        let flags = RunFlags::empty().with_uid(1000).with_quantum_ticks(3);
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .vcpus(2)
+            .run_flags(flags)
+            .command_line("init=/sbin/init quiet")
+            .kernel(&data)
+            .build()
+            .expect("create vm");
+        let info = boot_info(&vm);
+        assert!(info.is_valid());
+        assert_eq!((info.cpu_count, info.flags), (2, flags));
+        assert!(info.initramfs.is_empty());
+        assert_eq!(
+            info.memory_map.ram().map(|region| region.end()).max(),
+            Some((SMALL_GUEST_MEM_MIB << 20) as u64)
+        );
+
+        let before = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(0));
+        let wall_clock = Duration::from_nanos(boot_info(&vm).wall_clock_nanos);
+        assert!(
+            wall_clock >= before.unwrap(),
+            "guest boots at the host's time"
+        );
+        assert!(
+            vm.console_transcript()
+                .iter()
+                .any(|line| line == "kernel: command line: init=/sbin/init quiet"),
+            "guest must find its command line"
+        );
+
+        let too_long = "x".repeat(COMMAND_LINE_MAX + 1);
+        assert!(matches!(
+            vm.set_command_line(&too_long),
+            Err(Error::CommandLine { .. })
+        ));
+    }
+
+    // A newc cpio archive of `members`, each a name, mode and contents.
+    fn newc_archive(members: &[(&str, u32, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for (name, mode, data) in members.iter().chain([&("TRAILER!!!", 0, &b""[..])]) {
+            archive.extend_from_slice(b"070701");
+            let fields = [1, *mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0];
+            for field in fields.into_iter().chain([name.len() as u32 + 1, 0]) {
+                archive.extend_from_slice(format!("{field:08x}").as_bytes());
+            }
+            archive.extend_from_slice(name.as_bytes());
+            archive.push(0);
+            archive.resize(archive.len().next_multiple_of(4), 0);
+            archive.extend_from_slice(data);
+            archive.resize(archive.len().next_multiple_of(4), 0);
+        }
+        archive
+    }
+
+    #[test]
+    fn vm_hands_the_guest_an_initramfs_it_unpacks() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+        let archive = newc_archive(&[
+            ("etc", 0o040755, b""),
+            ("etc/motd", 0o100644, b"hello from the initramfs\n"),
+            ("etc/localtime", 0o120777, b"/usr/share/zoneinfo/UTC"),
+        ]);
+
+        let mut vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .kernel(&data)
+            .initramfs(&archive)
+            .build()
+            .expect("create vm");
+
+        // The archive sits in the last page of RAM, which the memory map
+        // keeps from the kernel's page allocator.
+        let info = boot_info(&vm);
+        let base = (SMALL_GUEST_MEM_MIB << 20) as u64 - PAGE_SIZE as u64;
+        assert_eq!(
+            info.initramfs,
+            Initramfs {
+                base,
+                len: archive.len() as u64
+            }
+        );
+        let mut loaded = vec![0; archive.len()];
+        vm.guest_memory()
+            .read_slice(&mut loaded, GuestAddress(base))
+            .unwrap();
+        assert_eq!(loaded, archive);
+        let mut expected = MemoryMap::empty();
+        for region in [
+            MemoryRegion::new(0, PALLOC_FIRST_PAGE.as_u64(), E820_RESERVED),
+            MemoryRegion::new(
+                PALLOC_FIRST_PAGE.as_u64(),
+                base - PALLOC_FIRST_PAGE.as_u64(),
+                E820_RAM,
+            ),
+            MemoryRegion::new(base, PAGE_SIZE as u64, E820_RESERVED),
+        ] {
+            expected.push(region).unwrap();
+        }
+        assert_eq!(info.memory_map, expected);
+
+        assert_eq!(vm.run().expect("run guest"), ExitStatus::Exited(0));
+        assert!(
+            vm.console_transcript()
+                .iter()
+                .any(|line| line == "initramfs: unpacked 2 entries"),
+            "guest must unpack the directory and the file but not the symlink"
+        );
+
+        let too_large = vec![0; (SMALL_GUEST_MEM_MIB << 20) - PALLOC_FIRST_PAGE.as_usize()];
+        assert!(matches!(
+            vm.load_initramfs(&too_large),
+            Err(Error::Initramfs { .. })
+        ));
+    }
+
+    #[test]
+    fn vm_rejects_unusable_cpu_counts() {
+        for count in [0, MAX_CPUS + 1] {
+            assert!(matches!(
+                Vm::builder()
+                    .memory_mib(SMALL_GUEST_MEM_MIB)
+                    .vcpus(count)
+                    .build(),
+                Err(Error::CpuCount { .. })
+            ));
+        }
+    }
+
+    // Field `key` of what /proc/self/smaps says of the mapping starting at
+    // `addr`.
+    fn smaps_field(addr: usize, key: &str) -> Option<String> {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let start = format!("{addr:x}-");
+        smaps
+            .lines()
+            .skip_while(|line| !line.starts_with(&start))
+            .skip(1)
+            .map_while(|line| line.split_once(':').filter(|(name, _)| !name.contains(' ')))
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.trim().to_owned())
+    }
+
+    #[test]
+    fn vm_backs_guest_memory_with_the_huge_pages_it_asks_for() {
+        let host_base = |vm: &Vm| {
+            let mem = vm.guest_memory();
+            mem.get_host_address(GuestAddress(0)).unwrap() as usize
+        };
+        let thp = std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled")
+            .unwrap_or_default();
+
+        let vm = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .build()
+            .unwrap();
+        let base = host_base(&vm);
+        assert_eq!(
+            base % PAGE_SIZE,
+            0,
+            "host and guest huge pages must line up"
+        );
+        if thp.contains("[madvise]") {
+            assert_eq!(smaps_field(base, "THPeligible").as_deref(), Some("1"));
+            let vm = Vm::builder()
+                .memory_mib(SMALL_GUEST_MEM_MIB)
+                .hugepages(HugePages::Off)
+                .build()
+                .unwrap();
+            assert_eq!(
+                smaps_field(host_base(&vm), "THPeligible").as_deref(),
+                Some("0")
+            );
+        }
+
+        // hugetlbfs pages are reserved up front, so a pool short of them
+        // fails the build.
+        let meminfo = std::fs::read_to_string("/proc/meminfo").unwrap();
+        let pool_free: usize = meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix("HugePages_Free:"))
+            .map_or(0, |count| count.trim().parse().unwrap());
+        let explicit = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .hugepages(HugePages::Explicit)
+            .build();
+        if pool_free * PAGE_SIZE < SMALL_GUEST_MEM_MIB << 20 {
+            assert!(matches!(explicit, Err(Error::HugePages { .. })));
+        } else {
+            let base = host_base(&explicit.unwrap());
+            assert_eq!(
+                smaps_field(base, "KernelPageSize").as_deref(),
+                Some("2048 kB")
+            );
+        }
+    }
+
+    #[test]
+    fn vm_rejects_unusable_memory_sizes() {
+        for mib in [
+            SMALL_GUEST_MEM_MIB + 1,
+            PALLOC_FIRST_PAGE.as_usize() >> 20,
+            (DEFAULT_MEM_SIZE + PAGE_SIZE) >> 20,
+        ] {
+            assert!(matches!(
+                Vm::builder().memory_mib(mib).build(),
+                Err(Error::MemorySize { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn vm_rejects_kernel_segments_outside_the_image() {
+        let path = env!("KERNEL_BIN");
+        let data = std::fs::read(path).expect("read kernel elf");
+        let elf = Elf::parse(&data).expect("parse kernel elf");
+        let (index, _) = elf
+            .program_headers
+            .iter()
+            .enumerate()
+            .find(|(_, ph)| ph.p_type == PT_LOAD)
+            .expect("kernel has a loadable segment");
+        // p_paddr is the fourth field of an ELF64 program header.
+        let paddr_offset = elf.header.e_phoff as usize
+            + index * elf.header.e_phentsize as usize
+            + std::mem::offset_of!(goblin::elf64::program_header::ProgramHeader, p_paddr);
+
+        let mut moved = data.clone();
+        moved[paddr_offset..paddr_offset + 8].copy_from_slice(&0x1000u64.to_le_bytes());
+        let result = Vm::builder()
+            .memory_mib(SMALL_GUEST_MEM_MIB)
+            .kernel(&moved)
+            .build();
+        assert!(matches!(result, Err(Error::Parsing(_))));
     }
 }
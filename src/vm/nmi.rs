@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::vcpu::kick;
+
+/// Breaks into a running guest with an NMI, which the kernel answers by
+/// dumping where it was and what the scheduler was doing. Cheap to clone and
+/// usable from any thread.
+#[derive(Clone, Default)]
+pub struct NmiInjector {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    pending: AtomicBool,
+    // The thread running the boot vCPU, if any.
+    vcpu_thread: Mutex<Option<libc::pthread_t>>,
+}
+
+impl NmiInjector {
+    /// Deliver an NMI to the boot vCPU. Only inject once the kernel has booted:
+    /// before it has an IDT the NMI takes the guest down. If the vCPU is
+    /// not running the NMI goes in when it next is, and one that races with
+    /// the vCPU entering the guest waits for its next exit.
+    pub fn inject(&self) {
+        self.shared.pending.store(true, Ordering::SeqCst);
+        // Held across the kick so the thread cannot be gone by then.
+        if let Some(thread) = *self.shared.vcpu_thread.lock().unwrap() {
+            kick(thread);
+        }
+    }
+
+    /// Make the calling thread the one `inject` kicks.
+    pub(super) fn attach(&self) {
+        // SAFETY: always safe to call.
+        *self.shared.vcpu_thread.lock().unwrap() = Some(unsafe { libc::pthread_self() });
+    }
+
+    pub(super) fn detach(&self) {
+        *self.shared.vcpu_thread.lock().unwrap() = None;
+    }
+
+    /// Whether an NMI was requested since the last call.
+    pub(super) fn take_pending(&self) -> bool {
+        self.shared.pending.swap(false, Ordering::SeqCst)
+    }
+}
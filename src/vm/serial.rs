@@ -1,11 +1,28 @@
-use crate::vm::Result;
+use crate::vm::{DevicePio, IrqLine, Result};
+use std::collections::VecDeque;
 use std::io::Write as _;
+use std::ops::Range;
 
-const SERIAL_COM1_BASE: u16 = 0x3f8;
-const SERIAL_PORT_COUNT: u16 = 8;
+/// The ports of COM1, the UART's registers.
+pub const COM1_PORTS: Range<u16> = 0x3f8..0x400;
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+// Interrupt identification: bit 0 clear means one is pending.
+const IIR_NONE: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
 const LCR_DLAB: u8 = 1 << 7;
+// On a PC the UART's interrupt output only reaches the PIC with OUT2 set.
+const MCR_OUT2: u8 = 1 << 3;
+const LSR_DATA_READY: u8 = 1 << 0;
 const LSR_THR_EMPTY: u8 = 1 << 5;
 const LSR_TSR_EMPTY: u8 = 1 << 6;
+// Input the guest has yet to read. Far deeper than a real 16550's FIFO, so
+// pasted text survives a guest that only drains it between time slices;
+// past this, input is dropped as with a real UART overrun.
+const RX_FIFO_SIZE: usize = 4096;
+// Number of most recent guest console lines kept for failure reports.
+const TRANSCRIPT_LINES: usize = 256;
 
 pub struct SerialConsole16550 {
     dll: u8,
@@ -14,11 +31,21 @@ pub struct SerialConsole16550 {
     lcr: u8,
     mcr: u8,
     scr: u8,
+    // Transmission is instant, so this is set again by every THR write and
+    // only cleared when the guest reads it out of the IIR.
+    thr_empty_pending: bool,
+    rx_fifo: VecDeque<u8>,
+    irq: Option<IrqLine>,
+    // Whether the line was last left raised, so each interrupt is one edge.
+    irq_raised: bool,
     line_buffer: Vec<u8>,
+    transcript: VecDeque<String>,
 }
 
 impl SerialConsole16550 {
-    pub fn new() -> Self {
+    /// A UART that raises `irq`, if given, for the interrupts the guest
+    /// enables in the IER.
+    pub fn new(irq: Option<IrqLine>) -> Self {
         Self {
             dll: 0,
             dlm: 0,
@@ -26,30 +53,31 @@ impl SerialConsole16550 {
             lcr: 0,
             mcr: 0,
             scr: 0,
+            thr_empty_pending: false,
+            rx_fifo: VecDeque::new(),
+            irq,
+            irq_raised: false,
             line_buffer: Vec::new(),
+            transcript: VecDeque::with_capacity(TRANSCRIPT_LINES),
         }
     }
 
-    pub fn handles_range(&self, port: u16, size: usize) -> bool {
-        let Some(last) = port.checked_add(size.saturating_sub(1) as u16) else {
-            return false;
-        };
-        port <= SERIAL_COM1_BASE + SERIAL_PORT_COUNT - 1 && last >= SERIAL_COM1_BASE
+    /// Queue `bytes` for the guest to read, as if they came down the line.
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<()> {
+        let room = RX_FIFO_SIZE - self.rx_fifo.len();
+        self.rx_fifo.extend(&bytes[..bytes.len().min(room)]);
+        self.update_irq()
     }
 
-    pub fn io_out(&mut self, port: u16, data: &[u8]) -> Result<()> {
-        for (idx, &value) in data.iter().enumerate() {
-            self.write_reg(port.wrapping_add(idx as u16), value)?;
+    /// Pass on guest output that reached the console some other way, as if
+    /// it had been written to the port.
+    pub fn output(&mut self, bytes: &[u8]) -> Result<()> {
+        for &byte in bytes {
+            self.enqueue_tx(byte)?;
         }
         Ok(())
     }
 
-    pub fn io_in(&mut self, port: u16, data: &mut [u8]) {
-        for (idx, value) in data.iter_mut().enumerate() {
-            *value = self.read_reg(port.wrapping_add(idx as u16));
-        }
-    }
-
     pub fn flush(&mut self) -> Result<()> {
         if self.line_buffer.is_empty() {
             return Ok(());
@@ -58,24 +86,45 @@ impl SerialConsole16550 {
         let mut stdout = std::io::stdout().lock();
         stdout.write_all(&self.line_buffer)?;
         stdout.flush()?;
+        self.record_transcript();
         self.line_buffer.clear();
         Ok(())
     }
 
-    fn write_reg(&mut self, port: u16, value: u8) -> Result<()> {
-        let offset = port.wrapping_sub(SERIAL_COM1_BASE);
+    /// Most recent lines written by the guest, oldest first.
+    pub fn transcript(&self) -> impl Iterator<Item = &str> {
+        self.transcript.iter().map(String::as_str)
+    }
+
+    fn record_transcript(&mut self) {
+        let text = String::from_utf8_lossy(&self.line_buffer);
+        for line in text.lines() {
+            if self.transcript.len() == TRANSCRIPT_LINES {
+                self.transcript.pop_front();
+            }
+            self.transcript.push_back(line.to_string());
+        }
+    }
+
+    fn write_reg(&mut self, offset: u16, value: u8) -> Result<()> {
         match offset {
             0 => {
                 if self.lcr & LCR_DLAB != 0 {
                     self.dll = value;
                 } else {
                     self.enqueue_tx(value)?;
+                    self.thr_empty_pending = true;
                 }
             }
             1 => {
                 if self.lcr & LCR_DLAB != 0 {
                     self.dlm = value;
                 } else {
+                    // Enabling the THR empty interrupt raises it at once
+                    // when the THR is, as it always is here.
+                    if value & !self.ier & IER_THR_EMPTY != 0 {
+                        self.thr_empty_pending = true;
+                    }
                     self.ier = value;
                 }
             }
@@ -88,14 +137,13 @@ impl SerialConsole16550 {
         Ok(())
     }
 
-    fn read_reg(&self, port: u16) -> u8 {
-        let offset = port.wrapping_sub(SERIAL_COM1_BASE);
+    fn read_reg(&mut self, offset: u16) -> u8 {
         match offset {
             0 => {
                 if self.lcr & LCR_DLAB != 0 {
                     self.dll
                 } else {
-                    0
+                    self.rx_fifo.pop_front().unwrap_or(0)
                 }
             }
             1 => {
@@ -105,16 +153,54 @@ impl SerialConsole16550 {
                     self.ier
                 }
             }
-            2 => 0x01,
+            2 => {
+                let id = self.interrupt_id();
+                // Reading the IIR is what acknowledges a THR empty interrupt.
+                if id == IIR_THR_EMPTY {
+                    self.thr_empty_pending = false;
+                }
+                id
+            }
             3 => self.lcr,
             4 => self.mcr,
-            5 => LSR_THR_EMPTY | LSR_TSR_EMPTY,
+            5 => {
+                let data_ready = if self.rx_fifo.is_empty() {
+                    0
+                } else {
+                    LSR_DATA_READY
+                };
+                data_ready | LSR_THR_EMPTY | LSR_TSR_EMPTY
+            }
             6 => 0xB0,
             7 => self.scr,
             _ => 0xFF,
         }
     }
 
+    // The highest priority interrupt that is both pending and enabled.
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RX_AVAILABLE != 0 && !self.rx_fifo.is_empty() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_THR_EMPTY != 0 && self.thr_empty_pending {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NONE
+        }
+    }
+
+    // Raise the line when an interrupt becomes pending.
+    fn update_irq(&mut self) -> Result<()> {
+        let active = self.interrupt_id() != IIR_NONE && self.mcr & MCR_OUT2 != 0;
+        if active
+            && !self.irq_raised
+            && let Some(irq) = &self.irq
+        {
+            irq.trigger()?;
+        }
+        self.irq_raised = active;
+        Ok(())
+    }
+
     fn enqueue_tx(&mut self, value: u8) -> Result<()> {
         if value == b'\r' {
             return Ok(());
@@ -127,3 +213,83 @@ impl SerialConsole16550 {
         Ok(())
     }
 }
+
+impl DevicePio for SerialConsole16550 {
+    fn pio_read(&mut self, offset: u16, data: &mut [u8]) -> Result<()> {
+        for (idx, value) in data.iter_mut().enumerate() {
+            *value = self.read_reg(offset + idx as u16);
+        }
+        self.update_irq()
+    }
+
+    fn pio_write(&mut self, offset: u16, data: &[u8]) -> Result<()> {
+        for (idx, &value) in data.iter().enumerate() {
+            self.write_reg(offset + idx as u16, value)?;
+        }
+        self.update_irq()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RBR: u16 = 0;
+    const IER: u16 = 1;
+    const IIR: u16 = 2;
+    const MCR: u16 = 4;
+    const LSR: u16 = 5;
+
+    fn read(serial: &mut SerialConsole16550, offset: u16) -> u8 {
+        let mut data = [0];
+        serial.pio_read(offset, &mut data).unwrap();
+        data[0]
+    }
+
+    #[test]
+    fn thr_empty_interrupt_is_raised_on_enable_and_acknowledged_by_iir() {
+        let mut serial = SerialConsole16550::new(None);
+        assert_eq!(read(&mut serial, IIR), IIR_NONE);
+
+        serial.pio_write(MCR, &[MCR_OUT2]).unwrap();
+        serial.pio_write(IER, &[IER_THR_EMPTY]).unwrap();
+        assert!(serial.irq_raised);
+        assert_eq!(read(&mut serial, IIR), IIR_THR_EMPTY);
+        assert!(!serial.irq_raised);
+        assert_eq!(read(&mut serial, IIR), IIR_NONE);
+
+        // Sending \r stays out of the console but still empties the THR.
+        serial.pio_write(RBR, b"\r").unwrap();
+        assert_eq!(read(&mut serial, IIR), IIR_THR_EMPTY);
+
+        serial.pio_write(IER, &[0]).unwrap();
+        serial.pio_write(RBR, b"\r").unwrap();
+        assert_eq!(read(&mut serial, IIR), IIR_NONE);
+        assert!(!serial.irq_raised);
+    }
+
+    #[test]
+    fn received_bytes_are_read_in_order_and_interrupt_until_drained() {
+        let mut serial = SerialConsole16550::new(None);
+        serial.pio_write(MCR, &[MCR_OUT2]).unwrap();
+        serial
+            .pio_write(IER, &[IER_RX_AVAILABLE | IER_THR_EMPTY])
+            .unwrap();
+        assert_eq!(read(&mut serial, IIR), IIR_THR_EMPTY);
+        assert_eq!(read(&mut serial, LSR) & LSR_DATA_READY, 0);
+
+        serial.receive(b"hi").unwrap();
+        assert!(serial.irq_raised);
+        assert_eq!(read(&mut serial, IIR), IIR_RX_AVAILABLE);
+        let mut received = Vec::new();
+        while read(&mut serial, LSR) & LSR_DATA_READY != 0 {
+            received.push(read(&mut serial, RBR));
+        }
+        assert_eq!(received, b"hi");
+        assert_eq!(read(&mut serial, IIR), IIR_NONE);
+        assert!(!serial.irq_raised);
+
+        serial.receive(&vec![b'x'; RX_FIFO_SIZE + 1]).unwrap();
+        assert_eq!(serial.rx_fifo.len(), RX_FIFO_SIZE);
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use kvm_ioctls::VcpuExit;
+
+// How many of the busiest ports and MMIO addresses the summary lists.
+const SUMMARY_TOP: usize = 10;
+
+/// Why and where the guest's vCPUs left it, and for how long, summed over
+/// every vCPU and every run of a [`Vm`](super::Vm).
+#[derive(Clone, Debug, Default)]
+pub struct VmStats {
+    /// Exits by their `VcpuExit` variant, with `Interrupted` for KVM_RUN
+    /// returning early to stop the vCPU or inject an NMI.
+    pub exits: BTreeMap<&'static str, u64>,
+    /// Port I/O exits by port, ins and outs together.
+    pub ports: BTreeMap<u16, u64>,
+    /// MMIO exits by guest physical address, reads and writes together.
+    pub mmio: BTreeMap<u64, u64>,
+    /// Time spent in KVM_RUN.
+    pub guest_time: Duration,
+    /// Time spent between KVM_RUNs, handling exits.
+    pub host_time: Duration,
+}
+
+impl VmStats {
+    /// Count one exit of the guest.
+    pub(super) fn record(&mut self, exit: &VcpuExit<'_>) {
+        let reason = match exit {
+            VcpuExit::IoIn(port, _) | VcpuExit::IoOut(port, _) => {
+                *self.ports.entry(*port).or_default() += 1;
+                if matches!(exit, VcpuExit::IoIn(..)) {
+                    "IoIn"
+                } else {
+                    "IoOut"
+                }
+            }
+            VcpuExit::MmioRead(addr, _) | VcpuExit::MmioWrite(addr, _) => {
+                *self.mmio.entry(*addr).or_default() += 1;
+                if matches!(exit, VcpuExit::MmioRead(..)) {
+                    "MmioRead"
+                } else {
+                    "MmioWrite"
+                }
+            }
+            VcpuExit::X86Rdmsr(..) => "X86Rdmsr",
+            VcpuExit::X86Wrmsr(..) => "X86Wrmsr",
+            VcpuExit::Hlt => "Hlt",
+            VcpuExit::Shutdown => "Shutdown",
+            VcpuExit::FailEntry(..) => "FailEntry",
+            VcpuExit::InternalError => "InternalError",
+            VcpuExit::SystemEvent(..) => "SystemEvent",
+            _ => "Other",
+        };
+        self.count(reason);
+    }
+
+    /// Count one KVM_RUN that returned without the guest exiting.
+    pub(super) fn record_interrupted(&mut self) {
+        self.count("Interrupted");
+    }
+
+    fn count(&mut self, reason: &'static str) {
+        *self.exits.entry(reason).or_default() += 1;
+    }
+
+    /// Add `other`'s counts and times to these.
+    pub fn merge(&mut self, other: &VmStats) {
+        for (reason, count) in &other.exits {
+            *self.exits.entry(reason).or_default() += count;
+        }
+        for (port, count) in &other.ports {
+            *self.ports.entry(*port).or_default() += count;
+        }
+        for (addr, count) in &other.mmio {
+            *self.mmio.entry(*addr).or_default() += count;
+        }
+        self.guest_time += other.guest_time;
+        self.host_time += other.host_time;
+    }
+
+    /// Every exit, whatever its reason.
+    pub fn total_exits(&self) -> u64 {
+        self.exits.values().sum()
+    }
+}
+
+// The busiest of `counts`, most exits first.
+fn busiest<K: Copy + Ord>(counts: &BTreeMap<K, u64>) -> Vec<(K, u64)> {
+    let mut busiest: Vec<_> = counts.iter().map(|(&key, &count)| (key, count)).collect();
+    busiest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    busiest.truncate(SUMMARY_TOP);
+    busiest
+}
+
+/// The end-of-run summary: exit reasons, then the busiest ports and MMIO
+/// addresses, which are where device emulation is hot.
+impl fmt::Display for VmStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.guest_time + self.host_time;
+        let guest_share = if total.is_zero() {
+            0.0
+        } else {
+            100.0 * self.guest_time.as_secs_f64() / total.as_secs_f64()
+        };
+        writeln!(
+            f,
+            "{} exits; {:.3?} in the guest, {:.3?} in the host ({guest_share:.1}% guest)",
+            self.total_exits(),
+            self.guest_time,
+            self.host_time,
+        )?;
+        for (reason, count) in &self.exits {
+            writeln!(f, "  {reason:<14}{count:>10}")?;
+        }
+        if !self.ports.is_empty() {
+            writeln!(f, "busiest ports:")?;
+            for (port, count) in busiest(&self.ports) {
+                writeln!(f, "  {port:#06x}{count:>18}")?;
+            }
+        }
+        if !self.mmio.is_empty() {
+            writeln!(f, "busiest MMIO addresses:")?;
+            for (addr, count) in busiest(&self.mmio) {
+                writeln!(f, "  {addr:#012x}{count:>12}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exits_are_counted_by_reason_and_place() {
+        let mut stats = VmStats::default();
+        let mut data = [0u8; 4];
+        stats.record(&VcpuExit::IoOut(0x3f8, b"x"));
+        stats.record(&VcpuExit::IoOut(0x3f8, b"y"));
+        stats.record(&VcpuExit::IoIn(0x3fd, &mut data[..1]));
+        stats.record(&VcpuExit::MmioWrite(0xd000_0050, &[0; 4]));
+        stats.record(&VcpuExit::Hlt);
+        stats.record_interrupted();
+
+        assert_eq!(stats.total_exits(), 6);
+        assert_eq!(stats.exits["IoOut"], 2);
+        assert_eq!(stats.exits["Interrupted"], 1);
+        assert_eq!(stats.ports[&0x3f8], 2);
+        assert_eq!(stats.mmio[&0xd000_0050], 1);
+
+        let mut sum = stats.clone();
+        sum.guest_time = Duration::from_millis(30);
+        sum.merge(&stats);
+        assert_eq!(sum.total_exits(), 12);
+        assert_eq!(sum.ports[&0x3fd], 2);
+        assert_eq!(sum.guest_time, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn summary_lists_the_busiest_ports_first() {
+        let mut stats = VmStats::default();
+        for port in 0..20u16 {
+            for _ in 0..=port {
+                stats.record(&VcpuExit::IoOut(port, &[0]));
+            }
+        }
+        let summary = stats.to_string();
+        assert!(summary.starts_with("210 exits;"), "{summary}");
+
+        let ports: Vec<_> = summary
+            .lines()
+            .skip_while(|line| *line != "busiest ports:")
+            .skip(1)
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(ports.len(), SUMMARY_TOP);
+        assert_eq!(ports[0], "0x0013");
+        assert_eq!(ports[9], "0x000a");
+    }
+}
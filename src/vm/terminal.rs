@@ -0,0 +1,101 @@
+use std::io;
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+use super::Result;
+use super::vcpu::Shared;
+
+// How long a poll of stdin waits before checking whether the run is over.
+const POLL_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Feed host stdin to the guest's serial port until the run stops or stdin
+/// reaches end of file. A terminal is switched out of line editing and echo
+/// meanwhile, so the guest sees each key as it is typed and decides what to
+/// echo.
+pub(super) fn forward_stdin(shared: &Shared<'_>) -> Result<()> {
+    let fd = libc::STDIN_FILENO;
+    let _raw = RawMode::enable(fd)?;
+    let mut buf = [0u8; 256];
+    while !shared.stopping() {
+        if !wait_readable(fd, POLL_TIMEOUT)? {
+            continue;
+        }
+        // Readable, so this does not block.
+        // SAFETY: `buf` is valid for writes of its length.
+        let len = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        match len {
+            0 => return Ok(()),
+            len if len < 0 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err.into());
+                }
+            }
+            len => shared.receive_input(&buf[..len as usize])?,
+        }
+    }
+    Ok(())
+}
+
+/// Wait up to `timeout` for `fd` to become readable, or to hang up. Being
+/// interrupted counts as a timeout.
+pub(super) fn wait_readable(fd: RawFd, timeout: Duration) -> Result<bool> {
+    let mut poll = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `poll` is a valid array of one entry.
+    let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) };
+    if ready < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(err.into());
+    }
+    Ok(ready > 0)
+}
+
+// Puts a terminal in non-canonical, no-echo mode and restores its settings
+// when dropped. Signal keys still work, so ^C stops the VMM. Anything that
+// is not a terminal is left alone.
+struct RawMode {
+    fd: RawFd,
+    saved: Option<libc::termios>,
+}
+
+impl RawMode {
+    fn enable(fd: RawFd) -> Result<Self> {
+        // SAFETY: `isatty` only inspects the descriptor.
+        if unsafe { libc::isatty(fd) } == 0 {
+            return Ok(Self { fd, saved: None });
+        }
+        // SAFETY: `termios` is plain data that tcgetattr fills in.
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: `raw` is a valid termios.
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            fd,
+            saved: Some(saved),
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            // SAFETY: `saved` came from tcgetattr on the same descriptor.
+            unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, saved) };
+        }
+    }
+}
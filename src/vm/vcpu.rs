@@ -0,0 +1,320 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+
+use kernel::boot::{
+    KERNEL_TEST_EXIT_FAILURE, KERNEL_TEST_EXIT_PORT, KERNEL_TEST_EXIT_SUCCESS, POWER_OFF_PORT,
+};
+use kernel::process::ExitStatus;
+use kvm_ioctls::{VcpuExit, VcpuFd};
+
+use super::nmi::NmiInjector;
+use super::serial::SerialConsole16550;
+use super::stats::VmStats;
+use super::{Error, MmioBus, MsrBus, PioBus, Result};
+
+// Sent to a vCPU thread to knock it out of KVM_RUN. The handler does
+// nothing; the EINTR is the point.
+const KICK_SIGNAL: libc::c_int = libc::SIGUSR1;
+
+// How often `Shared::stop` repeats its kicks, covering any that landed just
+// before a thread entered KVM_RUN.
+const KICK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Give the kick signal its handler, so `kick` interrupts KVM_RUN rather
+/// than killing the process.
+pub(super) fn install_kick_handler() {
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| {
+        extern "C" fn ignore(_: libc::c_int) {}
+        // SAFETY: the handler is async-signal-safe, doing nothing at all.
+        // No SA_RESTART, so KVM_RUN fails with EINTR.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = ignore as *const () as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(KICK_SIGNAL, &action, std::ptr::null_mut());
+        }
+    });
+}
+
+/// Make `thread` leave KVM_RUN if it is in it. A kick that lands just before
+/// the thread enters KVM_RUN is lost.
+pub(super) fn kick(thread: libc::pthread_t) {
+    // SAFETY: callers only pass threads that are still running, and the
+    // signal has a handler.
+    unsafe { libc::pthread_kill(thread, KICK_SIGNAL) };
+}
+
+/// What the vCPU threads of one `Vm::run` share.
+pub(super) struct Shared<'a> {
+    serial: &'a Mutex<SerialConsole16550>,
+    mmio: &'a MmioBus,
+    pio: &'a PioBus,
+    msrs: &'a MsrBus,
+    // Ports the guest touched that no device has, each reported once.
+    unhandled_ports: Mutex<HashSet<u16>>,
+    run_tests: bool,
+    stop: AtomicBool,
+    // Each vCPU's thread while it runs the vCPU.
+    threads: Vec<Mutex<Option<libc::pthread_t>>>,
+}
+
+impl<'a> Shared<'a> {
+    pub(super) fn new(
+        serial: &'a Mutex<SerialConsole16550>,
+        mmio: &'a MmioBus,
+        pio: &'a PioBus,
+        msrs: &'a MsrBus,
+        run_tests: bool,
+        vcpus: usize,
+    ) -> Self {
+        install_kick_handler();
+        Self {
+            serial,
+            mmio,
+            pio,
+            msrs,
+            unhandled_ports: Mutex::new(HashSet::new()),
+            run_tests,
+            stop: AtomicBool::new(false),
+            threads: (0..vcpus).map(|_| Mutex::new(None)).collect(),
+        }
+    }
+
+    /// Whether [`Shared::stop`] has been called.
+    pub(super) fn stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    /// Hand `bytes` to the guest through its serial port.
+    pub(super) fn receive_input(&self, bytes: &[u8]) -> Result<()> {
+        self.serial.lock().unwrap().receive(bytes)
+    }
+
+    // Tell the user the first time the guest reaches `port` with no device
+    // on it. The guest goes on as if nothing were there.
+    fn report_unhandled(&self, access: &str, port: u16, len: usize) {
+        if self.unhandled_ports.lock().unwrap().insert(port) {
+            eprintln!("vmm: unhandled {access} on port {port:#x} with {len} byte(s)");
+        }
+    }
+
+    /// Make every vCPU thread return, and wait until they have.
+    pub(super) fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        loop {
+            let mut running = false;
+            for thread in &self.threads {
+                // Held across the kick so the thread cannot be gone by then.
+                if let Some(thread) = *thread.lock().unwrap() {
+                    kick(thread);
+                    running = true;
+                }
+            }
+            if !running {
+                return;
+            }
+            std::thread::sleep(KICK_INTERVAL);
+        }
+    }
+}
+
+/// Run vCPU `index` on the calling thread until the guest powers off or
+/// fails, or until [`Shared::stop`], which returns `Ok(None)`. The boot
+/// vCPU is the one NMIs are injected into. Its exits are counted in
+/// `stats`.
+pub(super) fn run(
+    index: usize,
+    vcpu: &mut VcpuFd,
+    shared: &Shared<'_>,
+    nmi: Option<&NmiInjector>,
+    stats: &mut VmStats,
+) -> Result<Option<ExitStatus>> {
+    // SAFETY: always safe to call.
+    *shared.threads[index].lock().unwrap() = Some(unsafe { libc::pthread_self() });
+    if let Some(nmi) = nmi {
+        nmi.attach();
+    }
+    let result = run_until_exit(vcpu, shared, nmi, stats);
+    if let Some(nmi) = nmi {
+        nmi.detach();
+    }
+    *shared.threads[index].lock().unwrap() = None;
+    result
+}
+
+fn run_until_exit(
+    vcpu: &mut VcpuFd,
+    shared: &Shared<'_>,
+    nmi: Option<&NmiInjector>,
+    stats: &mut VmStats,
+) -> Result<Option<ExitStatus>> {
+    let mut left = Instant::now();
+    loop {
+        if shared.stopping() {
+            return Ok(None);
+        }
+        if nmi.is_some_and(NmiInjector::take_pending) {
+            vcpu.nmi()?;
+        }
+        let entered = Instant::now();
+        stats.host_time += entered - left;
+        let exit = vcpu.run();
+        left = Instant::now();
+        stats.guest_time += left - entered;
+        let exit = match exit {
+            Ok(exit) => exit,
+            // Kicked, either to stop or to take an NMI; both are seen to above.
+            Err(err) if err.errno() == libc::EINTR => {
+                stats.record_interrupted();
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        stats.record(&exit);
+        match exit {
+            VcpuExit::IoOut(port, data) => {
+                // The ports that end the run belong to no device.
+                if port == KERNEL_TEST_EXIT_PORT {
+                    shared.serial.lock().unwrap().flush()?;
+                    return handle_kernel_test_exit(shared.run_tests, data)
+                        .map(|()| Some(ExitStatus::Exited(0)));
+                }
+                if port == POWER_OFF_PORT {
+                    shared.serial.lock().unwrap().flush()?;
+                    if shared.run_tests {
+                        return Err(Error::UnexpectedExit(
+                            "guest halted before kernel tests reported PASS/FAIL".to_string(),
+                        ));
+                    }
+                    return power_off_status(data).map(Some);
+                }
+                if !shared.pio.write(port, data)? {
+                    shared.report_unhandled("IoOut", port, data.len());
+                }
+            }
+            VcpuExit::IoIn(port, data) => {
+                // Ports nothing drives read as all ones, as on a PC.
+                if !shared.pio.read(port, data)? {
+                    data.fill(0xff);
+                    shared.report_unhandled("IoIn", port, data.len());
+                }
+            }
+            VcpuExit::MmioRead(addr, data) => {
+                if !shared.mmio.read(addr, data) {
+                    return Err(Error::UnexpectedExit(format!(
+                        "unhandled MmioRead at {addr:#x} with {} byte(s)",
+                        data.len()
+                    )));
+                }
+            }
+            VcpuExit::MmioWrite(addr, data) => {
+                if !shared.mmio.write(addr, data)? {
+                    return Err(Error::UnexpectedExit(format!(
+                        "unhandled MmioWrite at {addr:#x} with {} byte(s)",
+                        data.len()
+                    )));
+                }
+            }
+            // KVM only hands over the MSRs it does not know and those the
+            // devices took from it.
+            VcpuExit::X86Rdmsr(exit) => {
+                let index = exit.index;
+                match shared.msrs.read(index)? {
+                    Some(value) => *exit.data = value,
+                    None => return Err(unhandled_msr(vcpu, "rdmsr".to_string(), index)),
+                }
+            }
+            VcpuExit::X86Wrmsr(exit) => {
+                let (index, value) = (exit.index, exit.data);
+                if !shared.msrs.write(index, value)? {
+                    let access = format!("wrmsr of {value:#x}");
+                    return Err(unhandled_msr(vcpu, access, index));
+                }
+            }
+            other => return Err(Error::UnexpectedExit(format!("{:?}", other))),
+        }
+    }
+}
+
+// The guest reached an MSR nothing has, at the instruction that did it.
+fn unhandled_msr(vcpu: &VcpuFd, access: String, index: u32) -> Error {
+    match vcpu.get_regs() {
+        Ok(regs) => Error::UnhandledMsr {
+            access,
+            index,
+            rip: regs.rip,
+        },
+        Err(err) => err.into(),
+    }
+}
+
+fn handle_kernel_test_exit(run_tests: bool, data: &[u8]) -> Result<()> {
+    if !run_tests {
+        return Err(Error::UnexpectedExit(
+            "kernel emitted test exit code without run_tests flag".to_string(),
+        ));
+    }
+    if data.len() != core::mem::size_of::<u32>() {
+        return Err(Error::UnexpectedExit(format!(
+            "kernel test exit code has invalid size: {}",
+            data.len()
+        )));
+    }
+
+    let code = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    match code {
+        KERNEL_TEST_EXIT_SUCCESS => Ok(()),
+        KERNEL_TEST_EXIT_FAILURE => Err(Error::KernelTestsFailed),
+        other => Err(Error::UnexpectedExit(format!(
+            "unknown kernel test exit code: {other:#x}"
+        ))),
+    }
+}
+
+// The status a write to the power-off port carries: a wait status in a
+// word, or an exit code alone in a byte.
+fn power_off_status(data: &[u8]) -> Result<ExitStatus> {
+    match *data {
+        [code] => Ok(ExitStatus::Exited(code)),
+        [low, high] => {
+            let status = u16::from_le_bytes([low, high]);
+            ExitStatus::from_wait_status(status).ok_or_else(|| {
+                Error::UnexpectedExit(format!("power-off with no exit in status {status:#06x}"))
+            })
+        }
+        _ => Err(Error::UnexpectedExit(format!(
+            "power-off with a {}-byte status",
+            data.len()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_off_carries_an_exit_code_or_a_wait_status() {
+        assert_eq!(power_off_status(&[3]).unwrap(), ExitStatus::Exited(3));
+        assert_eq!(
+            power_off_status(&[0x00, 0x2a]).unwrap(),
+            ExitStatus::Exited(42)
+        );
+        assert_eq!(
+            power_off_status(&[0x09, 0x00]).unwrap(),
+            ExitStatus::Killed(9)
+        );
+        // A stopped process has not ended, so is no status to power off with.
+        assert!(matches!(
+            power_off_status(&[0x7f, 0x13]),
+            Err(Error::UnexpectedExit(_))
+        ));
+        assert!(matches!(
+            power_off_status(&[0, 0, 0, 0]),
+            Err(Error::UnexpectedExit(_))
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+use kernel::memory::constants::SMALL_PAGE_SIZE;
+use kernel::virtio::{BALLOON_F_REPORTING, BALLOON_REPORTING_QUEUE, DEVICE_BALLOON};
+use vm_memory::{GuestAddress, GuestMemoryBackend, GuestMemoryMmap};
+
+use super::{Queue, VirtioDevice};
+use crate::vm::Result;
+
+const QUEUE_SIZE: u16 = 64;
+const REPORTING_QUEUE: usize = BALLOON_REPORTING_QUEUE as usize;
+
+/// A virtio balloon that takes the guest's reports of free pages and drops
+/// the host memory behind them, which the guest finds zeroed when it next
+/// uses those pages. The host never asks for pages back, so the inflate
+/// and deflate queues stay idle.
+#[derive(Default)]
+pub struct VirtioBalloon {
+    reclaimed: u64,
+}
+
+impl VirtioBalloon {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes of guest memory dropped over every report, counting memory
+    /// reported again each time.
+    pub fn reclaimed(&self) -> u64 {
+        self.reclaimed
+    }
+
+    // Drop the whole host pages in `len` bytes at `addr`, returning how
+    // much that was. A range outside guest memory is an error.
+    fn discard(&mut self, mem: &GuestMemoryMmap<()>, addr: u64, len: u64) -> Result<u64> {
+        let page = SMALL_PAGE_SIZE as u64;
+        let start = addr.next_multiple_of(page);
+        let end = (addr + len) / page * page;
+        if end <= start {
+            return Ok(0);
+        }
+        let len = (end - start) as usize;
+        let host = mem
+            .get_slice(GuestAddress(start), len)?
+            .ptr_guard_mut()
+            .as_ptr();
+        // SAFETY: the slice is guest memory the VMM mapped, which the guest
+        // has given up; dropping it leaves the mapping in place, reading as
+        // zeroes.
+        if unsafe { libc::madvise(host.cast(), len, libc::MADV_DONTNEED) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(len as u64)
+    }
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn device_type(&self) -> u32 {
+        DEVICE_BALLOON
+    }
+
+    fn features(&self) -> u64 {
+        BALLOON_F_REPORTING
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE; 3]
+    }
+
+    // `num_pages`, the pages the host wants, and `actual`, those the guest
+    // has given, are both 0.
+    fn read_config(&self, _offset: usize, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn process(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        mem: &GuestMemoryMmap<()>,
+    ) -> Result<bool> {
+        if index != REPORTING_QUEUE {
+            return Ok(false);
+        }
+        let queue = &mut queues[REPORTING_QUEUE];
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            for desc in &chain.descriptors {
+                self.reclaimed += self.discard(mem, desc.addr, desc.len.into())?;
+            }
+            queue.add_used(mem, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_memory::{Address, Bytes};
+
+    use super::*;
+    use crate::vm::virtio::testing::TestDriver;
+
+    #[test]
+    fn reported_pages_are_dropped_and_read_back_as_zeroes() {
+        let mut driver = TestDriver::start(
+            VirtioBalloon::new(),
+            BALLOON_F_REPORTING,
+            &[0, REPORTING_QUEUE],
+        );
+        let page = SMALL_PAGE_SIZE as u64;
+        let block = driver.spare_memory();
+        let last = block.unchecked_add(3 * page);
+        let mem = driver.transport.mem.clone();
+        mem.write_obj(0xaaaa_u64, block).unwrap();
+        mem.write_obj(0xbbbb_u64, last).unwrap();
+
+        // Only whole pages are dropped, so the last, cut short, is kept.
+        driver.offer_memory(REPORTING_QUEUE, block, (3 * page + 8) as u32);
+        assert_eq!(driver.take_used(REPORTING_QUEUE).len(), 1);
+        assert_eq!(driver.transport.device().reclaimed(), 3 * page);
+        assert_eq!(mem.read_obj::<u64>(block).unwrap(), 0);
+        assert_eq!(mem.read_obj::<u64>(last).unwrap(), 0xbbbb);
+
+        // Only the reporting queue drops anything.
+        driver.offer_memory(0, last, page as u32);
+        assert!(driver.take_used(0).is_empty());
+        assert_eq!(mem.read_obj::<u64>(last).unwrap(), 0xbbbb);
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use kernel::virtio::{
+    CONSOLE_CONSOLE_PORT, CONSOLE_CONTROL_RX_QUEUE, CONSOLE_CONTROL_SIZE, CONSOLE_CONTROL_TX_QUEUE,
+    CONSOLE_DEVICE_ADD, CONSOLE_DEVICE_READY, CONSOLE_F_MULTIPORT, CONSOLE_PORT_LOG,
+    CONSOLE_PORT_OPEN, CONSOLE_PORT_READY, DEVICE_CONSOLE,
+};
+use vm_memory::GuestMemoryMmap;
+
+use super::{Queue, VirtioDevice, VirtioMmio};
+use crate::vm::serial::SerialConsole16550;
+use crate::vm::{DeviceMmio, Result};
+
+const QUEUE_SIZE: u16 = 64;
+
+/// A multiport virtio console that only carries output. Port 0 is the
+/// kernel's log, which the VMM passes on with the serial port's output;
+/// every other port has a writer of its own.
+pub struct VirtioConsole {
+    outputs: Vec<Box<dyn Write + Send>>,
+    queue_sizes: Vec<u16>,
+    // What reached port 0 since `take_log`.
+    log: Vec<u8>,
+    // Control messages waiting for the driver to post buffers.
+    control: VecDeque<[u8; CONSOLE_CONTROL_SIZE]>,
+}
+
+impl VirtioConsole {
+    /// A console with `outputs` as ports 1 onwards.
+    pub fn new(outputs: Vec<Box<dyn Write + Send>>) -> Self {
+        // Port 0's queues, the control queues, then two for every other
+        // port.
+        let queue_sizes = vec![QUEUE_SIZE; 4 + 2 * outputs.len()];
+        Self {
+            outputs,
+            queue_sizes,
+            log: Vec::new(),
+            control: VecDeque::new(),
+        }
+    }
+
+    /// Take what the guest has logged since the last call.
+    pub fn take_log(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.log)
+    }
+
+    fn port_count(&self) -> u32 {
+        1 + self.outputs.len() as u32
+    }
+
+    fn transmit(
+        &mut self,
+        port: u32,
+        queue: &mut Queue,
+        mem: &GuestMemoryMmap<()>,
+    ) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            let data = chain.read_all(mem)?;
+            match port.checked_sub(1) {
+                None => self.log.extend_from_slice(&data),
+                Some(index) => {
+                    let output = &mut self.outputs[index as usize];
+                    output.write_all(&data)?;
+                    output.flush()?;
+                }
+            }
+            queue.add_used(mem, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    // Answer the driver's control messages: with every port once it is
+    // ready, and with each port's state once that port is.
+    fn handle_control(&mut self, queue: &mut Queue, mem: &GuestMemoryMmap<()>) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            let data = chain.read_all(mem)?;
+            if let Some(message) = data.first_chunk::<CONSOLE_CONTROL_SIZE>() {
+                let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+                let event = u16::from_le_bytes([message[4], message[5]]);
+                let value = u16::from_le_bytes([message[6], message[7]]);
+                match event {
+                    CONSOLE_DEVICE_READY if value == 1 => {
+                        for port in 0..self.port_count() {
+                            self.queue_control(port, CONSOLE_DEVICE_ADD, 0);
+                        }
+                    }
+                    CONSOLE_PORT_READY if value == 1 && id < self.port_count() => {
+                        if id == CONSOLE_PORT_LOG {
+                            self.queue_control(id, CONSOLE_CONSOLE_PORT, 1);
+                        }
+                        self.queue_control(id, CONSOLE_PORT_OPEN, 1);
+                    }
+                    // The driver opening or closing its end, and failures
+                    // to add a port, change nothing here.
+                    _ => {}
+                }
+            }
+            queue.add_used(mem, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn queue_control(&mut self, id: u32, event: u16, value: u16) {
+        let mut message = [0; CONSOLE_CONTROL_SIZE];
+        message[0..4].copy_from_slice(&id.to_le_bytes());
+        message[4..6].copy_from_slice(&event.to_le_bytes());
+        message[6..8].copy_from_slice(&value.to_le_bytes());
+        self.control.push_back(message);
+    }
+
+    // Hand queued control messages to the driver, as far as its buffers go.
+    fn deliver_control(&mut self, queue: &mut Queue, mem: &GuestMemoryMmap<()>) -> Result<bool> {
+        let mut used = false;
+        while !self.control.is_empty() && queue.is_usable() {
+            let Some(chain) = queue.pop(mem)? else {
+                break;
+            };
+            let message = self.control.pop_front().unwrap();
+            let written = chain.write_all(mem, &message)?;
+            queue.add_used(mem, chain.head, written as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_type(&self) -> u32 {
+        DEVICE_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        CONSOLE_F_MULTIPORT
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        // `cols` and `rows`, which are left 0, `max_nr_ports`, and
+        // `emerg_wr`, which reads 0.
+        let mut config = [0; 12];
+        config[4..8].copy_from_slice(&self.port_count().to_le_bytes());
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn process(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        mem: &GuestMemoryMmap<()>,
+    ) -> Result<bool> {
+        const CONTROL_RX: usize = CONSOLE_CONTROL_RX_QUEUE as usize;
+        const CONTROL_TX: usize = CONSOLE_CONTROL_TX_QUEUE as usize;
+        match index {
+            CONTROL_TX => {
+                let handled = self.handle_control(&mut queues[CONTROL_TX], mem)?;
+                let delivered = self.deliver_control(&mut queues[CONTROL_RX], mem)?;
+                Ok(handled || delivered)
+            }
+            CONTROL_RX => self.deliver_control(&mut queues[CONTROL_RX], mem),
+            // Port 0's transmit queue, then every other port's.
+            1 => self.transmit(0, &mut queues[1], mem),
+            index if index % 2 == 1 => {
+                self.transmit((index as u32 - 3) / 2, &mut queues[index], mem)
+            }
+            // Nothing is ever received.
+            _ => Ok(false),
+        }
+    }
+}
+
+/// The console's registers as the guest reaches them, passing what port 0
+/// logs on with the serial port's output.
+pub struct ConsoleMmio {
+    transport: VirtioMmio<VirtioConsole>,
+    serial: Arc<Mutex<SerialConsole16550>>,
+}
+
+impl ConsoleMmio {
+    pub fn new(
+        transport: VirtioMmio<VirtioConsole>,
+        serial: Arc<Mutex<SerialConsole16550>>,
+    ) -> Self {
+        Self { transport, serial }
+    }
+}
+
+impl DeviceMmio for ConsoleMmio {
+    fn mmio_read(&mut self, offset: u64, data: &mut [u8]) {
+        self.transport.mmio_read(offset, data);
+    }
+
+    fn mmio_write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.transport.mmio_write(offset, data)?;
+        let log = self.transport.device_mut().take_log();
+        self.serial.lock().unwrap().output(&log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::virtio::{console_rx_queue, console_tx_queue};
+
+    use super::*;
+    use crate::vm::virtio::testing::{Captured, TestDriver};
+
+    const CONTROL_RX: usize = CONSOLE_CONTROL_RX_QUEUE as usize;
+    const CONTROL_TX: usize = CONSOLE_CONTROL_TX_QUEUE as usize;
+
+    fn control(id: u32, event: u16, value: u16) -> Vec<u8> {
+        let mut message = id.to_le_bytes().to_vec();
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message
+    }
+
+    fn two_port_console(output: &Captured) -> TestDriver<VirtioConsole> {
+        let console = VirtioConsole::new(vec![Box::new(output.clone())]);
+        let queues: Vec<usize> = (0..6).collect();
+        TestDriver::start(console, CONSOLE_F_MULTIPORT, &queues)
+    }
+
+    #[test]
+    fn ports_are_added_and_opened_as_the_driver_gets_ready() {
+        let mut driver = two_port_console(&Captured::default());
+        for _ in 0..4 {
+            driver.post(CONTROL_RX, CONSOLE_CONTROL_SIZE as u32);
+        }
+
+        driver.send(CONTROL_TX, &control(0, CONSOLE_DEVICE_READY, 1));
+        assert_eq!(
+            driver.take_used(CONTROL_RX),
+            [
+                control(0, CONSOLE_DEVICE_ADD, 0),
+                control(1, CONSOLE_DEVICE_ADD, 0)
+            ]
+        );
+
+        driver.send(CONTROL_TX, &control(0, CONSOLE_PORT_READY, 1));
+        assert_eq!(
+            driver.take_used(CONTROL_RX),
+            [
+                control(0, CONSOLE_CONSOLE_PORT, 1),
+                control(0, CONSOLE_PORT_OPEN, 1)
+            ]
+        );
+
+        // Messages wait for buffers to put them in.
+        driver.send(CONTROL_TX, &control(1, CONSOLE_PORT_READY, 1));
+        assert!(driver.take_used(CONTROL_RX).is_empty());
+        driver.post(CONTROL_RX, CONSOLE_CONTROL_SIZE as u32);
+        assert_eq!(
+            driver.take_used(CONTROL_RX),
+            [control(1, CONSOLE_PORT_OPEN, 1)]
+        );
+    }
+
+    #[test]
+    fn each_port_has_its_own_output() {
+        let output = Captured::default();
+        let mut driver = two_port_console(&output);
+
+        driver.send(console_tx_queue(1) as usize, b"program");
+        driver.send(console_tx_queue(0) as usize, b"kernel");
+        assert_eq!(output.contents(), b"program");
+        assert_eq!(driver.transport.device_mut().take_log(), b"kernel");
+
+        // Nothing comes in, so receive buffers stay with the device.
+        driver.post(console_rx_queue(1) as usize, 64);
+        assert!(driver.take_used(console_rx_queue(1) as usize).is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+mod balloon;
+mod console;
+mod net;
+mod queue;
+mod share;
+#[cfg(test)]
+pub(super) mod testing;
+
+pub use self::balloon::VirtioBalloon;
+pub use self::console::{ConsoleMmio, VirtioConsole};
+pub(super) use self::net::forward_input;
+pub use self::net::{DEFAULT_MAC, NetBackend, Tap, VirtioNet};
+pub use self::queue::Queue;
+pub use self::share::VirtioShare;
+
+use kernel::virtio::{
+    self, F_VERSION_1, INTERRUPT_USED_BUFFER, STATUS_DRIVER_OK, STATUS_FEATURES_OK, reg,
+};
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+use super::{DeviceMmio, IrqLine, Result};
+
+/// A virtio device model, put in front of the guest by [`VirtioMmio`].
+pub trait VirtioDevice: Send {
+    fn device_type(&self) -> u32;
+
+    /// Feature bits offered besides `VIRTIO_F_VERSION_1`, which every
+    /// device has.
+    fn features(&self) -> u64;
+
+    /// The largest size of each of the device's queues.
+    fn queue_sizes(&self) -> &[u16];
+
+    /// Read the device-specific configuration at `offset`. Bytes past its
+    /// end read as 0.
+    fn read_config(&self, offset: usize, data: &mut [u8]);
+
+    /// Handle the buffers the driver made available on queue `index`,
+    /// returning whether any went to the used ring.
+    fn process(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        mem: &GuestMemoryMmap<()>,
+    ) -> Result<bool>;
+}
+
+/// The virtio MMIO transport, version 2: the registers a driver finds a
+/// device and sets up its queues through.
+pub struct VirtioMmio<D> {
+    device: D,
+    mem: GuestMemoryMmap<()>,
+    irq: IrqLine,
+    queues: Vec<Queue>,
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    interrupt_status: u32,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D, mem: GuestMemoryMmap<()>, irq: IrqLine) -> Self {
+        let queues = device
+            .queue_sizes()
+            .iter()
+            .copied()
+            .map(Queue::new)
+            .collect();
+        Self {
+            device,
+            mem,
+            irq,
+            queues,
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            interrupt_status: 0,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Whether the driver has finished setting the device up.
+    pub fn driver_ok(&self) -> bool {
+        self.status & STATUS_DRIVER_OK != 0
+    }
+
+    /// Have the device handle queue `index` if the driver has it running,
+    /// interrupting the guest if buffers were used. Devices with input of
+    /// their own call this as it arrives.
+    pub fn process(&mut self, index: usize) -> Result<()> {
+        if !self.driver_ok() || !self.queues.get(index).is_some_and(Queue::is_usable) {
+            return Ok(());
+        }
+        if self.device.process(index, &mut self.queues, &self.mem)? {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+            self.irq.trigger()?;
+        }
+        Ok(())
+    }
+
+    fn offered_features(&self) -> u64 {
+        F_VERSION_1 | self.device.features()
+    }
+
+    fn set_status(&mut self, value: u32) {
+        if value == 0 {
+            for queue in &mut self.queues {
+                queue.reset();
+            }
+            self.status = 0;
+            self.driver_features = 0;
+            self.interrupt_status = 0;
+            return;
+        }
+        let mut value = value;
+        // Features the device does not have, or a driver that does not
+        // follow virtio 1.x, are refused by leaving FEATURES_OK clear.
+        let newly_ok = value & STATUS_FEATURES_OK != 0 && self.status & STATUS_FEATURES_OK == 0;
+        if newly_ok
+            && (self.driver_features & !self.offered_features() != 0
+                || self.driver_features & F_VERSION_1 == 0)
+        {
+            value &= !STATUS_FEATURES_OK;
+        }
+        self.status = value;
+    }
+
+    fn selected_queue(&self) -> Option<&Queue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    // Queue setup only counts until the driver is done with it.
+    fn with_queue(&mut self, f: impl FnOnce(&mut Queue)) {
+        if self.status & STATUS_DRIVER_OK != 0 {
+            return;
+        }
+        if let Some(queue) = self.queues.get_mut(self.queue_sel as usize) {
+            f(queue);
+        }
+    }
+}
+
+impl<D: VirtioDevice> DeviceMmio for VirtioMmio<D> {
+    fn mmio_read(&mut self, offset: u64, data: &mut [u8]) {
+        if offset >= reg::CONFIG {
+            self.device
+                .read_config((offset - reg::CONFIG) as usize, data);
+            return;
+        }
+        let value = match offset {
+            reg::MAGIC_VALUE => virtio::MAGIC,
+            reg::VERSION => virtio::VERSION,
+            reg::DEVICE_ID => self.device.device_type(),
+            reg::VENDOR_ID => virtio::VENDOR_ID,
+            reg::DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.offered_features() as u32,
+                1 => (self.offered_features() >> 32) as u32,
+                _ => 0,
+            },
+            reg::QUEUE_NUM_MAX => self
+                .selected_queue()
+                .map_or(0, |queue| queue.max_size.into()),
+            reg::QUEUE_READY => self.selected_queue().map_or(0, |queue| queue.ready.into()),
+            reg::INTERRUPT_STATUS => self.interrupt_status,
+            reg::STATUS => self.status,
+            reg::CONFIG_GENERATION => 0,
+            _ => 0,
+        };
+        let bytes = value.to_le_bytes();
+        let len = data.len().min(bytes.len());
+        data[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    // Writes to anything but 32-bit registers are ignored, as is the
+    // configuration, which no device lets the driver change.
+    fn mmio_write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let Ok(bytes) = <[u8; 4]>::try_from(data) else {
+            return Ok(());
+        };
+        let value = u32::from_le_bytes(bytes);
+        match offset {
+            reg::DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            reg::DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            reg::DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xffff_ffff) | value as u64,
+                1 => {
+                    self.driver_features =
+                        (self.driver_features & 0xffff_ffff) | ((value as u64) << 32)
+                }
+                _ => {}
+            },
+            reg::QUEUE_SEL => self.queue_sel = value,
+            reg::QUEUE_NUM => self.with_queue(|queue| queue.size = value as u16),
+            reg::QUEUE_READY => self.with_queue(|queue| queue.ready = value == 1),
+            reg::QUEUE_DESC_LOW => self.with_queue(|queue| set_low(&mut queue.desc, value)),
+            reg::QUEUE_DESC_HIGH => self.with_queue(|queue| set_high(&mut queue.desc, value)),
+            reg::QUEUE_DRIVER_LOW => self.with_queue(|queue| set_low(&mut queue.avail, value)),
+            reg::QUEUE_DRIVER_HIGH => self.with_queue(|queue| set_high(&mut queue.avail, value)),
+            reg::QUEUE_DEVICE_LOW => self.with_queue(|queue| set_low(&mut queue.used, value)),
+            reg::QUEUE_DEVICE_HIGH => self.with_queue(|queue| set_high(&mut queue.used, value)),
+            reg::QUEUE_NOTIFY => self.process(value as usize)?,
+            reg::INTERRUPT_ACK => self.interrupt_status &= !value,
+            reg::STATUS => self.set_status(value),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// A virtio slot without a device. It reads as 0, which no driver takes
+/// for a device's magic value, and ignores writes.
+pub struct VacantSlot;
+
+impl DeviceMmio for VacantSlot {
+    fn mmio_read(&mut self, _offset: u64, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn mmio_write(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn set_low(addr: &mut GuestAddress, value: u32) {
+    addr.0 = (addr.0 & !0xffff_ffff) | value as u64;
+}
+
+fn set_high(addr: &mut GuestAddress, value: u32) {
+    addr.0 = (addr.0 & 0xffff_ffff) | ((value as u64) << 32);
+}
@@ -0,0 +1,328 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read as _, Write as _};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use kernel::virtio::{DEVICE_NET, NET_F_MAC, NET_HDR_NUM_BUFFERS, NET_HDR_SIZE};
+use vm_memory::GuestMemoryMmap;
+
+use super::{Queue, VirtioDevice, VirtioMmio};
+use crate::vm::terminal::wait_readable;
+use crate::vm::{Error, Result};
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const QUEUE_SIZE: u16 = 256;
+// Frames are at most this long, which covers any MTU a tap is set to.
+const MAX_FRAME: usize = 65535;
+// How long the input thread waits on the backend before checking whether
+// the run is over, and before trying again when the guest has no room.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// TUNSETIFF and its flags, from linux/if_tun.h.
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+/// The MAC address the guest's network card has.
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// Where the guest's Ethernet frames go and come from.
+pub trait NetBackend: Send {
+    /// Hand a frame to the network.
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Copy the next frame from the network to `buf`, if one is waiting,
+    /// without blocking. A frame of length 0 means the network has gone
+    /// away.
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+
+    /// A descriptor that polls readable while `recv` has a frame.
+    fn as_raw_fd(&self) -> RawFd;
+}
+
+/// A frame per datagram, for a peer on the other end of a socket: another
+/// process, or a test.
+impl NetBackend for UnixDatagram {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        UnixDatagram::send(self, frame).map(drop)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        // SAFETY: `buf` is valid for writes of its length.
+        let len = unsafe {
+            libc::recv(
+                AsRawFd::as_raw_fd(self),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        nonblocking_result(len)
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        AsRawFd::as_raw_fd(self)
+    }
+}
+
+/// A host tap interface. The host configures its end, with an address on
+/// the guest's subnet or as part of a bridge.
+pub struct Tap {
+    file: File,
+}
+
+impl Tap {
+    /// Attach to tap interface `name`, creating it if it does not exist,
+    /// which takes CAP_NET_ADMIN.
+    pub fn open(name: &str) -> Result<Self> {
+        // SAFETY: `ifreq` is plain data.
+        let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+        if name.is_empty() || name.len() >= request.ifr_name.len() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid tap name {name:?}"),
+            )));
+        }
+        for (dst, &src) in request.ifr_name.iter_mut().zip(name.as_bytes()) {
+            *dst = src as libc::c_char;
+        }
+        request.ifr_ifru.ifru_flags = IFF_TAP | IFF_NO_PI;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")?;
+        // SAFETY: TUNSETIFF reads and writes an `ifreq`.
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut request) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self { file })
+    }
+}
+
+impl NetBackend for Tap {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self.file.write(frame) {
+            // A full queue drops the frame, as a busy wire would.
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result.map(drop),
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.file.read(buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+fn nonblocking_result(len: isize) -> io::Result<Option<usize>> {
+    if len >= 0 {
+        return Ok(Some(len as usize));
+    }
+    let err = io::Error::last_os_error();
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(None),
+        _ => Err(err),
+    }
+}
+
+/// A virtio network card without offloads: every frame comes with a
+/// zeroed header, and the guest computes its own checksums.
+pub struct VirtioNet {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    // A frame taken from the backend while the guest had no buffer for it.
+    held: Option<Vec<u8>>,
+    disconnected: bool,
+    buf: Vec<u8>,
+}
+
+impl VirtioNet {
+    pub fn new(backend: Box<dyn NetBackend>, mac: [u8; 6]) -> Self {
+        Self {
+            mac,
+            backend,
+            held: None,
+            disconnected: false,
+            buf: vec![0; NET_HDR_SIZE + MAX_FRAME],
+        }
+    }
+
+    /// Whether input is waiting on the guest to post receive buffers.
+    pub fn stalled(&self) -> bool {
+        self.held.is_some()
+    }
+
+    /// Whether the backend has gone away, leaving nothing more to receive.
+    pub fn disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    pub fn backend_fd(&self) -> RawFd {
+        self.backend.as_raw_fd()
+    }
+
+    fn transmit(&mut self, queue: &mut Queue, mem: &GuestMemoryMmap<()>) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            let data = chain.read_all(mem)?;
+            if let Some(frame) = data.get(NET_HDR_SIZE..) {
+                // Like a wire, the network loses what it cannot take.
+                let _ = self.backend.send(frame);
+            }
+            queue.add_used(mem, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn receive(&mut self, queue: &mut Queue, mem: &GuestMemoryMmap<()>) -> Result<bool> {
+        let mut used = false;
+        loop {
+            let frame = match self.held.take() {
+                Some(frame) => frame,
+                None if self.disconnected => return Ok(used),
+                None => match self.backend.recv(&mut self.buf[NET_HDR_SIZE..])? {
+                    Some(0) => {
+                        self.disconnected = true;
+                        return Ok(used);
+                    }
+                    Some(len) => self.buf[NET_HDR_SIZE..NET_HDR_SIZE + len].to_vec(),
+                    None => return Ok(used),
+                },
+            };
+            let Some(chain) = queue.pop(mem)? else {
+                self.held = Some(frame);
+                return Ok(used);
+            };
+            let mut packet = vec![0; NET_HDR_SIZE];
+            packet[NET_HDR_NUM_BUFFERS] = 1;
+            packet.extend_from_slice(&frame);
+            // Frames too long for the buffer are cut short, and the guest
+            // drops them as malformed.
+            let written = chain.write_all(mem, &packet)?;
+            queue.add_used(mem, chain.head, written as u32)?;
+            used = true;
+        }
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_type(&self) -> u32 {
+        DEVICE_NET
+    }
+
+    fn features(&self) -> u64 {
+        NET_F_MAC
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE, QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.mac.get(offset + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn process(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        mem: &GuestMemoryMmap<()>,
+    ) -> Result<bool> {
+        match index {
+            RX_QUEUE => self.receive(&mut queues[RX_QUEUE], mem),
+            TX_QUEUE => self.transmit(&mut queues[TX_QUEUE], mem),
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Move frames from the backend into the guest as they arrive, until
+/// `stopping` says the run is over.
+pub(in crate::vm) fn forward_input(
+    net: &Mutex<VirtioMmio<VirtioNet>>,
+    stopping: impl Fn() -> bool,
+) -> Result<()> {
+    let fd = net.lock().unwrap().device().backend_fd();
+    while !stopping() {
+        if !wait_readable(fd, POLL_INTERVAL)? {
+            continue;
+        }
+        let mut net = net.lock().unwrap();
+        net.process(RX_QUEUE)?;
+        if net.device().disconnected() {
+            return Ok(());
+        }
+        // The guest picks up the rest once it sets the card up or posts
+        // buffers, which kicks the queue; until then, do not spin on a
+        // readable backend.
+        if !net.driver_ok() || net.device().stalled() {
+            drop(net);
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::virtio::reg;
+
+    use super::*;
+    use crate::vm::virtio::testing::TestDriver;
+
+    fn running_device(backend: UnixDatagram) -> TestDriver<VirtioNet> {
+        let net = VirtioNet::new(Box::new(backend), DEFAULT_MAC);
+        TestDriver::start(net, NET_F_MAC, &[RX_QUEUE, TX_QUEUE])
+    }
+
+    #[test]
+    fn frames_cross_the_queues_without_their_headers() {
+        let (backend, peer) = UnixDatagram::pair().unwrap();
+        let mut driver = running_device(backend);
+
+        let mut packet = vec![0; NET_HDR_SIZE];
+        packet.extend_from_slice(b"ping");
+        driver.send(TX_QUEUE, &packet);
+        let mut buf = [0; 64];
+        let len = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        peer.send(b"pong").unwrap();
+        driver.post(RX_QUEUE, 64);
+        let mut expected = vec![0; NET_HDR_SIZE];
+        expected[NET_HDR_NUM_BUFFERS] = 1;
+        expected.extend_from_slice(b"pong");
+        assert_eq!(driver.take_used(RX_QUEUE), [expected]);
+        assert_eq!(driver.read_reg(reg::INTERRUPT_STATUS), 1);
+    }
+
+    #[test]
+    fn input_waits_for_a_receive_buffer() {
+        let (backend, peer) = UnixDatagram::pair().unwrap();
+        let mut driver = running_device(backend);
+
+        peer.send(b"early").unwrap();
+        driver.transport.process(RX_QUEUE).unwrap();
+        assert!(driver.transport.device().stalled());
+
+        driver.post(RX_QUEUE, 64);
+        assert!(!driver.transport.device().stalled());
+        let used = driver.take_used(RX_QUEUE);
+        assert_eq!(&used[0][NET_HDR_SIZE..], b"early");
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::atomic::{Ordering, fence};
+
+use kernel::virtio::{DESC_F_NEXT, DESC_F_WRITE, Descriptor, RING_ENTRIES, RING_IDX, UsedElem};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+
+use crate::vm::{Error, Result};
+
+/// The device's side of a split virtqueue: what the driver set up through
+/// the transport, and how far through the rings the device has got.
+pub struct Queue {
+    pub(super) max_size: u16,
+    pub(super) size: u16,
+    pub(super) ready: bool,
+    pub(super) desc: GuestAddress,
+    pub(super) avail: GuestAddress,
+    pub(super) used: GuestAddress,
+    next_avail: u16,
+    next_used: u16,
+}
+
+/// A buffer the driver made available: the descriptors of one chain, in
+/// order. The device reads the ones without `DESC_F_WRITE` and writes the
+/// rest.
+pub struct DescriptorChain {
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// Everything in the chain's readable descriptors, in order.
+    pub fn read_all(&self, mem: &GuestMemoryMmap<()>) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for desc in self.descriptors.iter().filter(|desc| !is_write(desc)) {
+            let start = data.len();
+            data.resize(start + desc.len as usize, 0);
+            mem.read_slice(&mut data[start..], GuestAddress(desc.addr))?;
+        }
+        Ok(data)
+    }
+
+    /// How much the chain's writable descriptors hold between them.
+    pub fn writable_len(&self) -> usize {
+        self.descriptors
+            .iter()
+            .filter(is_write)
+            .map(|desc| desc.len as usize)
+            .sum()
+    }
+
+    /// Write `data` across the chain's writable descriptors, returning how
+    /// much fit.
+    pub fn write_all(&self, mem: &GuestMemoryMmap<()>, mut data: &[u8]) -> Result<usize> {
+        let mut written = 0;
+        for desc in self.descriptors.iter().filter(is_write) {
+            if data.is_empty() {
+                break;
+            }
+            let len = data.len().min(desc.len as usize);
+            mem.write_slice(&data[..len], GuestAddress(desc.addr))?;
+            data = &data[len..];
+            written += len;
+        }
+        Ok(written)
+    }
+}
+
+fn is_write(desc: &&Descriptor) -> bool {
+    desc.flags & DESC_F_WRITE != 0
+}
+
+impl Queue {
+    pub fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc: GuestAddress(0),
+            avail: GuestAddress(0),
+            used: GuestAddress(0),
+            next_avail: 0,
+            next_used: 0,
+        }
+    }
+
+    /// Back to how the device came up, as when the driver resets it.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.max_size);
+    }
+
+    /// Whether the driver has set the queue up and may use it. Sizes that
+    /// are not a power of two are refused.
+    pub fn is_usable(&self) -> bool {
+        self.ready && self.size != 0 && self.size <= self.max_size && self.size.is_power_of_two()
+    }
+
+    /// The next buffer the driver has made available, if any.
+    pub fn pop(&mut self, mem: &GuestMemoryMmap<()>) -> Result<Option<DescriptorChain>> {
+        let avail_idx: u16 = mem.read_obj(self.avail.unchecked_add(RING_IDX))?;
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+        // Read the entry only after the index that covers it.
+        fence(Ordering::Acquire);
+        let slot = RING_ENTRIES + u64::from(self.next_avail % self.size) * 2;
+        let head: u16 = mem.read_obj(self.avail.unchecked_add(slot))?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        loop {
+            if index >= self.size || descriptors.len() == self.size as usize {
+                return Err(Error::Virtio(format!(
+                    "descriptor chain from {head} is malformed at {index}"
+                )));
+            }
+            let desc = self.descriptor(mem, index)?;
+            descriptors.push(desc);
+            if desc.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = desc.next;
+        }
+        Ok(Some(DescriptorChain { head, descriptors }))
+    }
+
+    /// Give the chain starting at `head` back to the driver, with `len`
+    /// bytes of it written.
+    pub fn add_used(&mut self, mem: &GuestMemoryMmap<()>, head: u16, len: u32) -> Result<()> {
+        let slot =
+            RING_ENTRIES + u64::from(self.next_used % self.size) * size_of::<UsedElem>() as u64;
+        mem.write_obj(u32::from(head), self.used.unchecked_add(slot))?;
+        mem.write_obj(len, self.used.unchecked_add(slot + 4))?;
+        self.next_used = self.next_used.wrapping_add(1);
+        // The entry must be visible before the index that covers it.
+        fence(Ordering::Release);
+        mem.write_obj(self.next_used, self.used.unchecked_add(RING_IDX))?;
+        Ok(())
+    }
+
+    fn descriptor(&self, mem: &GuestMemoryMmap<()>, index: u16) -> Result<Descriptor> {
+        let mut raw = [0u8; size_of::<Descriptor>()];
+        mem.read_slice(
+            &mut raw,
+            self.desc
+                .unchecked_add(u64::from(index) * size_of::<Descriptor>() as u64),
+        )?;
+        Ok(Descriptor {
+            addr: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+            len: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+            flags: u16::from_le_bytes(raw[12..14].try_into().unwrap()),
+            next: u16::from_le_bytes(raw[14..16].try_into().unwrap()),
+        })
+    }
+}
@@ -0,0 +1,633 @@
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, DirBuilder, File, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use kernel::virtio::p9::{self, Qid, Reader, Writer};
+use kernel::virtio::{DEVICE_9P, P9_F_MOUNT_TAG};
+use vm_memory::GuestMemoryMmap;
+
+use super::{Queue, VirtioDevice};
+use crate::vm::Result;
+
+const REQUEST_QUEUE: usize = 0;
+const QUEUE_SIZE: u16 = 64;
+/// What `Rstatfs` calls the filesystem, as Linux's v9fs does.
+const V9FS_MAGIC: u32 = 0x0102_1997;
+/// `Rgetattr` fields that are filled in: everything up to the block count.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+// A request's outcome: fields written to the reply, or the errno to answer
+// with instead.
+type Reply = std::result::Result<(), u32>;
+
+/// A virtio 9p device serving a host directory over the part of 9P2000.L
+/// in [`kernel::virtio::p9`]. Nothing outside the directory can be reached:
+/// walks stop at its root and do not follow symlinks out of it. A read-only
+/// share refuses every change with `EROFS` and shows its files without
+/// write permission.
+pub struct VirtioShare {
+    root: PathBuf,
+    tag: String,
+    read_only: bool,
+    fids: HashMap<u32, Fid>,
+}
+
+// What a fid stands for: a file or directory under the root, by its
+// canonical path, and the file once opened.
+struct Fid {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl VirtioShare {
+    /// Share the directory at `root` under the name `tag`.
+    pub fn new(root: impl AsRef<Path>, tag: &str, read_only: bool) -> io::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(Self {
+            root,
+            tag: tag.to_owned(),
+            read_only,
+            fids: HashMap::new(),
+        })
+    }
+
+    /// Answer the request in `request` in `buf`, returning the reply's
+    /// length.
+    fn handle(&mut self, request: &[u8], buf: &mut [u8]) -> usize {
+        let Some((ty, tag, mut fields)) = Reader::new(request) else {
+            return error_reply(buf, 0, p9::EINVAL);
+        };
+        let mut reply = Writer::new(buf);
+        let outcome = match ty {
+            p9::TVERSION => self.version(&mut fields, &mut reply),
+            p9::TATTACH => self.attach(&mut fields, &mut reply),
+            p9::TWALK => self.walk(&mut fields, &mut reply),
+            p9::TLOPEN => self.lopen(&mut fields, &mut reply),
+            p9::TLCREATE => self.lcreate(&mut fields, &mut reply),
+            p9::TREAD => self.read(&mut fields, &mut reply),
+            p9::TWRITE => self.write(&mut fields, &mut reply),
+            p9::TCLUNK => self.clunk(&mut fields),
+            p9::TGETATTR => self.getattr(&mut fields, &mut reply),
+            p9::TSETATTR => self.setattr(&mut fields),
+            p9::TMKDIR => self.mkdir(&mut fields, &mut reply),
+            p9::TRENAMEAT => self.renameat(&mut fields),
+            p9::TUNLINKAT => self.unlinkat(&mut fields),
+            p9::TSTATFS => self.statfs(&mut fields, &mut reply),
+            _ => Err(p9::ENOSYS),
+        };
+        match outcome.map(|()| reply.finish(ty + 1, tag)) {
+            Ok(Some(len)) => len,
+            // The driver left no room for the reply.
+            Ok(None) => error_reply(buf, tag, p9::EIO),
+            Err(errno) => error_reply(buf, tag, errno),
+        }
+    }
+
+    fn version(&mut self, fields: &mut Reader<'_>, reply: &mut Writer<'_>) -> Reply {
+        let msize = fields.u32().ok_or(p9::EINVAL)?;
+        let version = fields.str().ok_or(p9::EINVAL)?;
+        // A new session forgets every fid of the last.
+        self.fids.clear();
+        let version = if version == p9::VERSION {
+            p9::VERSION
+        } else {
+            b"unknown"
+        };
+        reply.u32(msize).str(version);
+        Ok(())
+    }
+
+    fn attach(&mut self, fields: &mut Reader<'_>, reply: &mut Writer<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let qid = qid(&fs::metadata(&self.root).map_err(errno)?);
+        self.fids.insert(
+            fid,
+            Fid {
+                path: self.root.clone(),
+                file: None,
+            },
+        );
+        reply.qid(qid);
+        Ok(())
+    }
+
+    // A walk that stops short answers with the qids of the names it got
+    // through, unless it failed at the first.
+    fn walk(&mut self, fields: &mut Reader<'_>, reply: &mut Writer<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let new_fid = fields.u32().ok_or(p9::EINVAL)?;
+        let count = fields.u16().ok_or(p9::EINVAL)?;
+        let mut path = self.fid(fid)?.path.clone();
+        let mut qids = Vec::new();
+        for _ in 0..count {
+            let name = fields.str().ok_or(p9::EINVAL)?;
+            match self.step(&path, name) {
+                Ok((next, qid)) => {
+                    path = next;
+                    qids.push(qid);
+                }
+                Err(errno) if qids.is_empty() => return Err(errno),
+                Err(_) => break,
+            }
+        }
+        if qids.len() == usize::from(count) {
+            self.fids.insert(new_fid, Fid { path, file: None });
+        }
+        reply.u16(qids.len() as u16);
+        for qid in qids {
+            reply.qid(qid);
+        }
+        Ok(())
+    }
+
+    // Where `name` leads from the directory at `dir`, kept inside the root.
+    fn step(&self, dir: &Path, name: &[u8]) -> std::result::Result<(PathBuf, Qid), u32> {
+        let next = match name {
+            b".." if dir == self.root => dir.to_owned(),
+            b".." => dir.parent().unwrap_or(dir).to_owned(),
+            name => self.child(dir, name)?.canonicalize().map_err(errno)?,
+        };
+        if !next.starts_with(&self.root) {
+            return Err(p9::EACCES);
+        }
+        let metadata = fs::metadata(&next).map_err(errno)?;
+        Ok((next, qid(&metadata)))
+    }
+
+    fn lopen(&mut self, fields: &mut Reader<'_>, reply: &mut Writer<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let flags = fields.u32().ok_or(p9::EINVAL)?;
+        let writes = flags & p9::O_ACCMODE != p9::O_RDONLY || flags & p9::O_TRUNC != 0;
+        if writes && self.read_only {
+            return Err(p9::EROFS);
+        }
+        let fid = self.fid_mut(fid)?;
+        let file = open_options(flags)
+            .truncate(flags & p9::O_TRUNC != 0)
+            .open(&fid.path)
+            .map_err(errno)?;
+        let qid = qid(&file.metadata().map_err(errno)?);
+        fid.file = Some(file);
+        reply.qid(qid).u32(0);
+        Ok(())
+    }
+
+    // Files are only ever created anew, so a name cannot be a symlink that
+    // leads out of the share.
+    fn lcreate(&mut self, fields: &mut Reader<'_>, reply: &mut Writer<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let name = fields.str().ok_or(p9::EINVAL)?;
+        let flags = fields.u32().ok_or(p9::EINVAL)?;
+        let mode = fields.u32().ok_or(p9::EINVAL)?;
+        if self.read_only {
+            return Err(p9::EROFS);
+        }
+        let path = self.child(&self.fid(fid)?.path, name)?;
+        let file = open_options(flags)
+            .create_new(true)
+            .mode(mode & 0o7777)
+            .open(&path)
+            .map_err(errno)?;
+        let qid = qid(&file.metadata().map_err(errno)?);
+        *self.fid_mut(fid)? = Fid {
+            path,
+            file: Some(file),
+        };
+        reply.qid(qid).u32(0);
+        Ok(())
+    }
+
+    fn read(&mut self, fields: &mut Reader<'_>, reply: &mut Writer<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let offset = fields.u64().ok_or(p9::EINVAL)?;
+        let count = fields.u32().ok_or(p9::EINVAL)? as usize;
+        let file = self.fid(fid)?.file.as_ref().ok_or(p9::EBADF)?;
+        // No more than the reply has room for.
+        let mut data = vec![0; count.min(reply.room().saturating_sub(4))];
+        let mut read = 0;
+        while read < data.len() {
+            match file.read_at(&mut data[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(len) => read += len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(errno(err)),
+            }
+        }
+        reply.u32(read as u32).bytes(&data[..read]);
+        Ok(())
+    }
+
+    fn write(&mut self, fields: &mut Reader<'_>, reply: &mut Writer<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let offset = fields.u64().ok_or(p9::EINVAL)?;
+        let count = fields.u32().ok_or(p9::EINVAL)?;
+        let data = fields.bytes(count as usize).ok_or(p9::EINVAL)?;
+        if self.read_only {
+            return Err(p9::EROFS);
+        }
+        let file = self.fid(fid)?.file.as_ref().ok_or(p9::EBADF)?;
+        file.write_all_at(data, offset).map_err(errno)?;
+        reply.u32(count);
+        Ok(())
+    }
+
+    fn clunk(&mut self, fields: &mut Reader<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        self.fids.remove(&fid).map(|_| ()).ok_or(p9::EBADF)
+    }
+
+    fn getattr(&mut self, fields: &mut Reader<'_>, reply: &mut Writer<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let metadata = fs::metadata(&self.fid(fid)?.path).map_err(errno)?;
+        let mut mode = metadata.mode();
+        if self.read_only {
+            mode &= !0o222;
+        }
+        reply
+            .u64(GETATTR_BASIC)
+            .qid(qid(&metadata))
+            .u32(mode)
+            .u32(metadata.uid())
+            .u32(metadata.gid())
+            .u64(metadata.nlink())
+            .u64(metadata.rdev())
+            .u64(metadata.size())
+            .u64(metadata.blksize())
+            .u64(metadata.blocks());
+        for (sec, nsec) in [
+            (metadata.atime(), metadata.atime_nsec()),
+            (metadata.mtime(), metadata.mtime_nsec()),
+            (metadata.ctime(), metadata.ctime_nsec()),
+        ] {
+            reply.u64(sec as u64).u64(nsec as u64);
+        }
+        // Birth time, generation and data version, which are not kept.
+        reply.u64(0).u64(0).u64(0).u64(0);
+        Ok(())
+    }
+
+    // Only the size changes; the other attributes are left as they are.
+    fn setattr(&mut self, fields: &mut Reader<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let valid = fields.u32().ok_or(p9::EINVAL)?;
+        // Mode, owner and group come ahead of the size.
+        fields.bytes(12).ok_or(p9::EINVAL)?;
+        let size = fields.u64().ok_or(p9::EINVAL)?;
+        if valid & p9::SETATTR_SIZE == 0 {
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(p9::EROFS);
+        }
+        let fid = self.fid(fid)?;
+        match &fid.file {
+            Some(file) => file.set_len(size),
+            None => OpenOptions::new()
+                .write(true)
+                .open(&fid.path)
+                .and_then(|file| file.set_len(size)),
+        }
+        .map_err(errno)
+    }
+
+    fn mkdir(&mut self, fields: &mut Reader<'_>, reply: &mut Writer<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let name = fields.str().ok_or(p9::EINVAL)?;
+        let mode = fields.u32().ok_or(p9::EINVAL)?;
+        if self.read_only {
+            return Err(p9::EROFS);
+        }
+        let path = self.child(&self.fid(fid)?.path, name)?;
+        DirBuilder::new()
+            .mode(mode & 0o7777)
+            .create(&path)
+            .map_err(errno)?;
+        reply.qid(qid(&fs::metadata(&path).map_err(errno)?));
+        Ok(())
+    }
+
+    fn renameat(&mut self, fields: &mut Reader<'_>) -> Reply {
+        let from_fid = fields.u32().ok_or(p9::EINVAL)?;
+        let from_name = fields.str().ok_or(p9::EINVAL)?;
+        let to_fid = fields.u32().ok_or(p9::EINVAL)?;
+        let to_name = fields.str().ok_or(p9::EINVAL)?;
+        if self.read_only {
+            return Err(p9::EROFS);
+        }
+        let from = self.child(&self.fid(from_fid)?.path, from_name)?;
+        let to = self.child(&self.fid(to_fid)?.path, to_name)?;
+        fs::rename(from, to).map_err(errno)
+    }
+
+    fn unlinkat(&mut self, fields: &mut Reader<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let name = fields.str().ok_or(p9::EINVAL)?;
+        let flags = fields.u32().ok_or(p9::EINVAL)?;
+        if self.read_only {
+            return Err(p9::EROFS);
+        }
+        let path = self.child(&self.fid(fid)?.path, name)?;
+        if flags & p9::AT_REMOVEDIR != 0 {
+            fs::remove_dir(path)
+        } else {
+            fs::remove_file(path)
+        }
+        .map_err(errno)
+    }
+
+    fn statfs(&mut self, fields: &mut Reader<'_>, reply: &mut Writer<'_>) -> Reply {
+        let fid = fields.u32().ok_or(p9::EINVAL)?;
+        let path = CString::new(self.fid(fid)?.path.as_os_str().as_bytes()).unwrap();
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        // SAFETY: `path` is NUL-terminated and `stats` is ours to fill.
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+            return Err(errno(io::Error::last_os_error()));
+        }
+        reply
+            .u32(V9FS_MAGIC)
+            .u32(stats.f_bsize as u32)
+            .u64(stats.f_blocks)
+            .u64(stats.f_bfree)
+            .u64(stats.f_bavail)
+            .u64(stats.f_files)
+            .u64(stats.f_ffree)
+            .u64(stats.f_fsid)
+            .u32(stats.f_namemax as u32);
+        Ok(())
+    }
+
+    fn fid(&self, fid: u32) -> std::result::Result<&Fid, u32> {
+        self.fids.get(&fid).ok_or(p9::EBADF)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> std::result::Result<&mut Fid, u32> {
+        self.fids.get_mut(&fid).ok_or(p9::EBADF)
+    }
+
+    // The entry `name` in `dir`, which must be a plain name rather than a
+    // path or a way out of the directory.
+    fn child(&self, dir: &Path, name: &[u8]) -> std::result::Result<PathBuf, u32> {
+        if matches!(name, b"" | b"." | b"..") || name.contains(&b'/') || name.contains(&0) {
+            return Err(p9::EINVAL);
+        }
+        Ok(dir.join(OsStr::from_bytes(name)))
+    }
+}
+
+impl VirtioDevice for VirtioShare {
+    fn device_type(&self) -> u32 {
+        DEVICE_9P
+    }
+
+    fn features(&self) -> u64 {
+        P9_F_MOUNT_TAG
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn process(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        mem: &GuestMemoryMmap<()>,
+    ) -> Result<bool> {
+        if index != REQUEST_QUEUE {
+            return Ok(false);
+        }
+        let queue = &mut queues[REQUEST_QUEUE];
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            let request = chain.read_all(mem)?;
+            let mut reply = vec![0; chain.writable_len()];
+            let len = self.handle(&request, &mut reply);
+            let written = chain.write_all(mem, &reply[..len])?;
+            queue.add_used(mem, chain.head, written as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+fn qid(metadata: &Metadata) -> Qid {
+    Qid {
+        ty: if metadata.is_dir() { p9::QTDIR } else { 0 },
+        version: 0,
+        path: metadata.ino(),
+    }
+}
+
+fn open_options(flags: u32) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    match flags & p9::O_ACCMODE {
+        p9::O_WRONLY => options.write(true),
+        p9::O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+    options
+}
+
+fn errno(err: io::Error) -> u32 {
+    err.raw_os_error().map_or(p9::EIO, |errno| errno as u32)
+}
+
+fn error_reply(buf: &mut [u8], tag: u16, errno: u32) -> usize {
+    Writer::new(buf)
+        .u32(errno)
+        .finish(p9::RLERROR, tag)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{PermissionsExt, symlink};
+
+    use super::*;
+    use crate::vm::DeviceMmio;
+    use crate::vm::virtio::testing::{TempDir, TestDriver};
+
+    const REPLY_LEN: u32 = 0x1000;
+
+    // Send the request `fill` writes the fields of, returning the reply's
+    // type and fields.
+    fn call(
+        driver: &mut TestDriver<VirtioShare>,
+        ty: u8,
+        fill: impl FnOnce(&mut Writer<'_>),
+    ) -> (u8, Vec<u8>) {
+        let mut request = [0; 0x1000];
+        let mut writer = Writer::new(&mut request);
+        fill(&mut writer);
+        let len = writer.finish(ty, 0).unwrap();
+        let reply = driver.call(REQUEST_QUEUE, &request[..len], REPLY_LEN);
+        let (reply_ty, _, _) = Reader::new(&reply).unwrap();
+        (reply_ty, reply[p9::HEADER_SIZE..].to_vec())
+    }
+
+    // The errno a request failed with.
+    fn fails(reply: (u8, Vec<u8>)) -> u32 {
+        assert_eq!(reply.0, p9::RLERROR);
+        u32::from_le_bytes(reply.1[..4].try_into().unwrap())
+    }
+
+    // A share of `dir` with its root attached as fid 0.
+    fn attached(dir: &TempDir, read_only: bool) -> TestDriver<VirtioShare> {
+        let share = VirtioShare::new(dir.path(), "host", read_only).unwrap();
+        let mut driver = TestDriver::start(share, P9_F_MOUNT_TAG, &[REQUEST_QUEUE]);
+        let (ty, _) = call(&mut driver, p9::TVERSION, |msg| {
+            msg.u32(REPLY_LEN).str(p9::VERSION);
+        });
+        assert_eq!(ty, p9::TVERSION + 1);
+        let (ty, _) = call(&mut driver, p9::TATTACH, |msg| {
+            msg.u32(0).u32(p9::NOFID).str(b"").str(b"").u32(0);
+        });
+        assert_eq!(ty, p9::TATTACH + 1);
+        driver
+    }
+
+    fn walk(driver: &mut TestDriver<VirtioShare>, fid: u32, names: &[&[u8]]) -> (u8, Vec<u8>) {
+        call(driver, p9::TWALK, |msg| {
+            msg.u32(0).u32(fid).u16(names.len() as u16);
+            for name in names {
+                msg.str(name);
+            }
+        })
+    }
+
+    #[test]
+    fn files_are_read_through_walks_from_the_root() {
+        let dir = TempDir::new("share-read");
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/input.txt"), b"from the host").unwrap();
+        let mut driver = attached(&dir, true);
+
+        let mut tag = [0; 6];
+        driver
+            .transport
+            .mmio_read(kernel::virtio::reg::CONFIG, &mut tag);
+        assert_eq!(&tag, b"\x04\x00host");
+
+        let (ty, fields) = walk(&mut driver, 1, &[b"sub", b"input.txt"]);
+        assert_eq!(ty, p9::TWALK + 1);
+        assert_eq!(fields[..2], 2u16.to_le_bytes());
+        let (ty, _) = call(&mut driver, p9::TLOPEN, |msg| {
+            msg.u32(1).u32(p9::O_RDONLY);
+        });
+        assert_eq!(ty, p9::TLOPEN + 1);
+        let (ty, fields) = call(&mut driver, p9::TREAD, |msg| {
+            msg.u32(1).u64(5).u32(100);
+        });
+        assert_eq!(ty, p9::TREAD + 1);
+        assert_eq!(fields[..4], 8u32.to_le_bytes());
+        assert_eq!(&fields[4..], b"the host");
+
+        assert_eq!(fails(walk(&mut driver, 2, &[b"missing"])), p9::ENOENT);
+        // A walk that gets partway makes no fid.
+        let (ty, fields) = walk(&mut driver, 2, &[b"sub", b"missing"]);
+        assert_eq!(ty, p9::TWALK + 1);
+        assert_eq!(fields[..2], 1u16.to_le_bytes());
+        let clunk = call(&mut driver, p9::TCLUNK, |msg| {
+            msg.u32(2);
+        });
+        assert_eq!(fails(clunk), p9::EBADF);
+    }
+
+    #[test]
+    fn walks_do_not_leave_the_shared_directory() {
+        let outside = TempDir::new("share-outside");
+        fs::write(outside.path().join("secret"), b"").unwrap();
+        let dir = TempDir::new("share-root");
+        symlink(outside.path(), dir.path().join("escape")).unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        symlink("..", dir.path().join("sub/up")).unwrap();
+        let mut driver = attached(&dir, true);
+
+        let root = fs::metadata(dir.path()).unwrap().ino();
+        let (_, fields) = walk(&mut driver, 1, &[b"..", b".."]);
+        assert_eq!(fields[2 + 13 + 5..2 + 13 + 13], root.to_le_bytes());
+        // Symlinks within the share are followed; those out of it are not.
+        let (_, fields) = walk(&mut driver, 2, &[b"sub", b"up"]);
+        assert_eq!(fields[2 + 13 + 5..2 + 13 + 13], root.to_le_bytes());
+        assert_eq!(fails(walk(&mut driver, 3, &[b"escape"])), p9::EACCES);
+        assert_eq!(fails(walk(&mut driver, 3, &[b"a/b"])), p9::EINVAL);
+    }
+
+    #[test]
+    fn read_only_shares_refuse_changes() {
+        let dir = TempDir::new("share-read-only");
+        fs::write(dir.path().join("file"), b"kept").unwrap();
+        fs::set_permissions(dir.path().join("file"), fs::Permissions::from_mode(0o644)).unwrap();
+        let mut driver = attached(&dir, true);
+
+        walk(&mut driver, 1, &[b"file"]);
+        let (_, fields) = call(&mut driver, p9::TGETATTR, |msg| {
+            msg.u32(1).u64(GETATTR_BASIC);
+        });
+        let mode = u32::from_le_bytes(fields[8 + 13..8 + 13 + 4].try_into().unwrap());
+        assert_eq!(mode & 0o777, 0o444);
+        let open = call(&mut driver, p9::TLOPEN, |msg| {
+            msg.u32(1).u32(p9::O_RDWR);
+        });
+        assert_eq!(fails(open), p9::EROFS);
+
+        let create = call(&mut driver, p9::TLCREATE, |msg| {
+            msg.u32(0).str(b"new").u32(p9::O_WRONLY).u32(0o644).u32(0);
+        });
+        assert_eq!(fails(create), p9::EROFS);
+        let unlink = call(&mut driver, p9::TUNLINKAT, |msg| {
+            msg.u32(0).str(b"file").u32(0);
+        });
+        assert_eq!(fails(unlink), p9::EROFS);
+        assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"kept");
+        assert!(!dir.path().join("new").exists());
+    }
+
+    #[test]
+    fn writable_shares_create_write_and_remove_files() {
+        let dir = TempDir::new("share-writable");
+        let mut driver = attached(&dir, false);
+
+        walk(&mut driver, 1, &[]);
+        let (ty, _) = call(&mut driver, p9::TLCREATE, |msg| {
+            msg.u32(1).str(b"out.txt").u32(p9::O_RDWR).u32(0o600).u32(0);
+        });
+        assert_eq!(ty, p9::TLCREATE + 1);
+        let (_, fields) = call(&mut driver, p9::TWRITE, |msg| {
+            msg.u32(1).u64(0).u32(5).bytes(b"hello");
+        });
+        assert_eq!(fields[..4], 5u32.to_le_bytes());
+        assert_eq!(fs::read(dir.path().join("out.txt")).unwrap(), b"hello");
+
+        // Created files are new: an existing name is refused.
+        walk(&mut driver, 2, &[]);
+        let create = call(&mut driver, p9::TLCREATE, |msg| {
+            msg.u32(2).str(b"out.txt").u32(p9::O_RDWR).u32(0o600).u32(0);
+        });
+        assert_eq!(fails(create), p9::EEXIST);
+
+        let (ty, _) = call(&mut driver, p9::TUNLINKAT, |msg| {
+            msg.u32(0).str(b"out.txt").u32(0);
+        });
+        assert_eq!(ty, p9::TUNLINKAT + 1);
+        assert!(!dir.path().join("out.txt").exists());
+    }
+}
@@ -0,0 +1,251 @@
+//! A stand-in for a guest driver, for testing device models: it sets a
+//! device up through its registers and moves buffers through its queues.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use kernel::virtio::{
+    DESC_F_NEXT, DESC_F_WRITE, RING_ENTRIES, RING_IDX, STATUS_ACKNOWLEDGE, STATUS_DRIVER,
+    STATUS_DRIVER_OK, STATUS_FEATURES_OK, reg,
+};
+use kvm_ioctls::{Kvm, VmFd};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::{VirtioDevice, VirtioMmio};
+use crate::vm::{DeviceMmio, IrqLine};
+
+const QUEUE_SIZE: u16 = 4;
+// Each queue's descriptors, rings and buffers, in an area of its own past
+// the first.
+const QUEUE_AREA: u64 = 0x10000;
+const AVAIL_OFFSET: u64 = 0x1000;
+const USED_OFFSET: u64 = 0x2000;
+const BUFFERS_OFFSET: u64 = 0x3000;
+const BUFFER_SIZE: u64 = 0x1000;
+const MAX_QUEUES: usize = 16;
+
+pub(in crate::vm) struct TestDriver<D> {
+    pub(in crate::vm) transport: VirtioMmio<D>,
+    // Keeps the device's interrupt line registered.
+    _vm: VmFd,
+    // Descriptors and chains offered on each queue so far, and used entries
+    // seen.
+    described: [u16; MAX_QUEUES],
+    offered: [u16; MAX_QUEUES],
+    seen: [u16; MAX_QUEUES],
+}
+
+impl<D: VirtioDevice> TestDriver<D> {
+    /// Take `device` through setup, agreeing to `features` and setting up
+    /// `queues`.
+    pub(in crate::vm) fn start(device: D, features: u64, queues: &[usize]) -> Self {
+        let vm = Kvm::new().unwrap().create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        let size = (MAX_QUEUES as u64 + 1) * QUEUE_AREA;
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), size as usize)]).unwrap();
+        // Any line will do; nothing listens to it.
+        let irq = IrqLine::new(&vm, 5).unwrap();
+        let mut driver = Self {
+            transport: VirtioMmio::new(device, mem, irq),
+            _vm: vm,
+            described: [0; MAX_QUEUES],
+            offered: [0; MAX_QUEUES],
+            seen: [0; MAX_QUEUES],
+        };
+
+        let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        driver.write_reg(reg::STATUS, status);
+        for (sel, half) in [(0, features as u32), (1, (features >> 32) as u32 | 1)] {
+            driver.write_reg(reg::DRIVER_FEATURES_SEL, sel);
+            driver.write_reg(reg::DRIVER_FEATURES, half);
+        }
+        driver.write_reg(reg::STATUS, status | STATUS_FEATURES_OK);
+        assert_ne!(driver.read_reg(reg::STATUS) & STATUS_FEATURES_OK, 0);
+        for &queue in queues {
+            let area = queue_area(queue);
+            driver.write_reg(reg::QUEUE_SEL, queue as u32);
+            driver.write_reg(reg::QUEUE_NUM, QUEUE_SIZE.into());
+            driver.write_reg(reg::QUEUE_DESC_LOW, area as u32);
+            driver.write_reg(reg::QUEUE_DRIVER_LOW, (area + AVAIL_OFFSET) as u32);
+            driver.write_reg(reg::QUEUE_DEVICE_LOW, (area + USED_OFFSET) as u32);
+            driver.write_reg(reg::QUEUE_READY, 1);
+        }
+        driver.write_reg(reg::STATUS, status | STATUS_FEATURES_OK | STATUS_DRIVER_OK);
+        driver
+    }
+
+    pub(in crate::vm) fn write_reg(&mut self, offset: u64, value: u32) {
+        self.transport
+            .mmio_write(offset, &value.to_le_bytes())
+            .unwrap();
+    }
+
+    pub(in crate::vm) fn read_reg(&mut self, offset: u64) -> u32 {
+        let mut data = [0; 4];
+        self.transport.mmio_read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    /// Offer `data` on `queue` for the device to read, and notify it.
+    pub(in crate::vm) fn send(&mut self, queue: usize, data: &[u8]) {
+        let id = self.describe(queue, data.len() as u32, 0);
+        let buffer = buffer_addr(queue, id);
+        self.transport.mem.write_slice(data, buffer).unwrap();
+        self.publish(queue, id);
+    }
+
+    /// Offer a buffer of `len` bytes on `queue` for the device to write
+    /// to, and notify it.
+    pub(in crate::vm) fn post(&mut self, queue: usize, len: u32) {
+        let id = self.describe(queue, len, DESC_F_WRITE);
+        self.publish(queue, id);
+    }
+
+    /// Offer `len` bytes at `addr`, memory outside the queue's buffers,
+    /// on `queue` for the device to write to, and notify it.
+    pub(in crate::vm) fn offer_memory(&mut self, queue: usize, addr: GuestAddress, len: u32) {
+        let id = self.describe(queue, len, DESC_F_WRITE);
+        let desc = queue_area(queue) + u64::from(id) * 16;
+        self.transport
+            .mem
+            .write_obj(addr.0, GuestAddress(desc))
+            .unwrap();
+        self.publish(queue, id);
+    }
+
+    /// 64 KiB of the driver's own that no queue uses, for devices handed
+    /// memory rather than buffers: the area of the last queue, which tests
+    /// leave alone.
+    pub(in crate::vm) fn spare_memory(&self) -> GuestAddress {
+        GuestAddress(queue_area(MAX_QUEUES - 1))
+    }
+
+    /// Offer `request` on `queue` chained to a buffer of `reply_len` bytes
+    /// for the device to answer in, notify it, and return what it wrote
+    /// there.
+    pub(in crate::vm) fn call(&mut self, queue: usize, request: &[u8], reply_len: u32) -> Vec<u8> {
+        let head = self.describe(queue, request.len() as u32, DESC_F_NEXT);
+        let reply = self.describe(queue, reply_len, DESC_F_WRITE);
+        let mem = &self.transport.mem;
+        let desc = queue_area(queue) + u64::from(head) * 16;
+        mem.write_obj(reply, GuestAddress(desc + 14)).unwrap();
+        mem.write_slice(request, buffer_addr(queue, head)).unwrap();
+        self.publish(queue, head);
+
+        let used = queue_area(queue) + USED_OFFSET;
+        let slot = used + RING_ENTRIES + u64::from(self.seen[queue] % QUEUE_SIZE) * 8;
+        let [id, len]: [u32; 2] = self.transport.mem.read_obj(GuestAddress(slot)).unwrap();
+        assert_eq!(id, u32::from(head), "the device must answer at once");
+        self.seen[queue] = self.seen[queue].wrapping_add(1);
+        let mut data = vec![0; len as usize];
+        self.transport
+            .mem
+            .read_slice(&mut data, buffer_addr(queue, reply))
+            .unwrap();
+        data
+    }
+
+    /// What the device wrote to the buffers it has used on `queue` since
+    /// the last call, in order.
+    pub(in crate::vm) fn take_used(&mut self, queue: usize) -> Vec<Vec<u8>> {
+        let mem = &self.transport.mem;
+        let used = queue_area(queue) + USED_OFFSET;
+        let idx: u16 = mem.read_obj(GuestAddress(used + RING_IDX)).unwrap();
+        let mut buffers = Vec::new();
+        while self.seen[queue] != idx {
+            let slot = used + RING_ENTRIES + u64::from(self.seen[queue] % QUEUE_SIZE) * 8;
+            let [id, len]: [u32; 2] = mem.read_obj(GuestAddress(slot)).unwrap();
+            let mut data = vec![0; len as usize];
+            mem.read_slice(&mut data, buffer_addr(queue, id as u16))
+                .unwrap();
+            buffers.push(data);
+            self.seen[queue] = self.seen[queue].wrapping_add(1);
+        }
+        buffers
+    }
+
+    // Describe the next buffer of `queue`, returning its descriptor.
+    fn describe(&mut self, queue: usize, len: u32, flags: u16) -> u16 {
+        let mem = &self.transport.mem;
+        let id = self.described[queue] % QUEUE_SIZE;
+        let desc = queue_area(queue) + u64::from(id) * 16;
+        mem.write_obj(buffer_addr(queue, id).0, GuestAddress(desc))
+            .unwrap();
+        mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        mem.write_obj(flags, GuestAddress(desc + 12)).unwrap();
+        self.described[queue] = self.described[queue].wrapping_add(1);
+        id
+    }
+
+    // Put the chain from descriptor `head` in the available ring of `queue`
+    // and notify the device.
+    fn publish(&mut self, queue: usize, head: u16) {
+        let mem = &self.transport.mem;
+        let avail = queue_area(queue) + AVAIL_OFFSET;
+        let slot = avail + RING_ENTRIES + u64::from(self.offered[queue] % QUEUE_SIZE) * 2;
+        mem.write_obj(head, GuestAddress(slot)).unwrap();
+        self.offered[queue] = self.offered[queue].wrapping_add(1);
+        mem.write_obj(self.offered[queue], GuestAddress(avail + RING_IDX))
+            .unwrap();
+        self.write_reg(reg::QUEUE_NOTIFY, queue as u32);
+    }
+}
+
+fn queue_area(queue: usize) -> u64 {
+    (queue as u64 + 1) * QUEUE_AREA
+}
+
+fn buffer_addr(queue: usize, id: u16) -> GuestAddress {
+    GuestAddress(queue_area(queue) + BUFFERS_OFFSET + u64::from(id) * BUFFER_SIZE)
+}
+
+/// A writer whose output tests can read back once the guest is done.
+#[derive(Clone, Default)]
+pub(in crate::vm) struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    pub(in crate::vm) fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A directory of its own under the system's temporary one, removed with
+/// everything in it when dropped.
+pub(in crate::vm) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(in crate::vm) fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "hostel-{name}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+        Self(path)
+    }
+
+    pub(in crate::vm) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,19 +1,52 @@
-use crate::vm::Result;
-use kernel::memory::address::DirectMap;
+use crate::vm::{Error, Result};
+use kernel::boot::{HYPERVISOR_VENDOR, MSR_KVM_SYSTEM_TIME_NEW, PVCLOCK_ENABLE, PvClock};
+use kernel::memory::address::{DirectMap, PhysicalAddr};
 use kernel::memory::constants::{
     DIRECT_MAP_PD, DIRECT_MAP_PD_COUNT, DIRECT_MAP_PDPT, DIRECT_MAP_PDPT_COUNT, DIRECT_MAP_PML4,
     DIRECT_MAP_PML4_ENTRIES_COUNT, DIRECT_MAP_PML4_OFFSET, KERNEL_CODE_PD, KERNEL_CODE_PDPD,
-    KERNEL_CODE_PHYS, KERNEL_CODE_VIRT, KERNEL_STACK, PAGE_SIZE, PAGE_TABLE_ENTRIES,
-    PAGE_TABLE_SIZE,
+    KERNEL_CODE_PHYS, KERNEL_CODE_PT, KERNEL_CODE_VIRT, PAGE_SIZE, PAGE_TABLE_ENTRIES,
+    PAGE_TABLE_SIZE, PVCLOCK_PHYS, SMALL_PAGE_SIZE, entry_stack_top,
 };
-use kvm_bindings::kvm_userspace_memory_region;
-use kvm_ioctls::VmFd;
+use kvm_bindings::{
+    CpuId, KVM_CAP_X86_USER_SPACE_MSR, KVM_MEM_LOG_DIRTY_PAGES, KVM_MSR_FILTER_DEFAULT_ALLOW,
+    KVM_MSR_FILTER_MAX_BITMAP_SIZE, KVM_MSR_FILTER_MAX_RANGES, KVM_MSR_FILTER_READ,
+    KVM_MSR_FILTER_WRITE, KVMIO, Msrs, kvm_enable_cap, kvm_msr_entry, kvm_msr_filter,
+    kvm_msr_filter_range, kvm_userspace_memory_region,
+};
+use kvm_ioctls::{Cap, MsrExitReason, VmFd};
+use std::ops::Range;
 use vm_memory::{Bytes, GuestAddress, GuestMemoryBackend, GuestMemoryMmap};
+use vmm_sys_util::ioctl::ioctl_with_ref;
 
 // Page-table / PTE flag bits
 const PTE_PRESENT: u64 = 0x1;
-const PTE_RW: u64 = 0x2;
-const PTE_PS: u64 = 0x80;
+pub(crate) const PTE_RW: u64 = 0x2;
+pub(crate) const PTE_PS: u64 = 0x80;
+pub(crate) const PTE_NX: u64 = 1 << 63;
+
+const GIGAPAGE_SIZE: u64 = 1 << 30;
+
+/// The KVM memory slot all of guest RAM is in.
+pub const MEMORY_SLOT: u32 = 0;
+
+// CPUID.80000001H:EDX bit 26 advertises 1 GiB pages.
+const CPUID_EXT_FEATURES: u32 = 0x8000_0001;
+const CPUID_EXT_EDX_PDPE1GB: u32 = 1 << 26;
+// Leaves that report the APIC ID of the CPU executing them: bits 31:24 of
+// EBX for leaf 1, EDX for the x2APIC topology leaves.
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_EXTENDED_TOPOLOGY: u32 = 0xB;
+const CPUID_V2_EXTENDED_TOPOLOGY: u32 = 0x1F;
+// Where hypervisors report their vendor, in EBX, ECX and EDX.
+const CPUID_HYPERVISOR_VENDOR: u32 = 0x4000_0000;
+// KVM's paravirtual features, EAX bit 3 being the kvmclock MSRs at
+// 0x4b564d00 and up.
+const CPUID_KVM_FEATURES: u32 = 0x4000_0001;
+const CPUID_KVM_EAX_CLOCKSOURCE2: u32 = 1 << 3;
+
+// ELF program header flags
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
 
 // Control-register / system constants
 const CR4_PAE: u64 = 1 << 5;
@@ -21,11 +54,13 @@ const CR4_OSFXSR: u64 = 1 << 9;
 const CR4_OSXMMEXCPT: u64 = 1 << 10;
 const EFER_LME: u64 = 1 << 8;
 const EFER_LMA: u64 = 1 << 10;
+const EFER_NXE: u64 = 1 << 11;
 const CR0_PE: u64 = 1 << 0;
 const CR0_MP: u64 = 1 << 1;
 const CR0_EM: u64 = 1 << 2;
 const CR0_TS: u64 = 1 << 3;
 const CR0_NE: u64 = 1 << 5;
+const CR0_WP: u64 = 1 << 16;
 const CR0_PG: u64 = 1 << 31;
 const RFLAGS_RESERVED: u64 = 2;
 
@@ -37,11 +72,156 @@ const SS_TYPE: u8 = 0x3;
 
 pub const GUEST_BASE: GuestAddress = GuestAddress(0);
 
+/// Whether the guest CPU described by `cpuid` can map 1 GiB pages.
+pub fn supports_gigapages(cpuid: &CpuId) -> bool {
+    cpuid
+        .as_slice()
+        .iter()
+        .any(|entry| entry.function == CPUID_EXT_FEATURES && entry.edx & CPUID_EXT_EDX_PDPE1GB != 0)
+}
+
+// Not wrapped by kvm-ioctls.
+vmm_sys_util::ioctl_iow_nr!(KVM_X86_SET_MSR_FILTER, KVMIO, 0xc6, kvm_msr_filter);
+
+/// Have KVM hand the VMM the guest's accesses to MSRs it does not know, and
+/// to MSRs [`set_msr_filter`] filters, rather than fault the guest. Hosts
+/// older than Linux 5.10 cannot, and go on faulting it.
+pub fn enable_msr_exits(vm: &VmFd) -> Result<()> {
+    if !vm.check_extension(Cap::X86UserSpaceMsr) {
+        return Ok(());
+    }
+    let mut cap = kvm_enable_cap {
+        cap: KVM_CAP_X86_USER_SPACE_MSR,
+        ..Default::default()
+    };
+    cap.args[0] = (MsrExitReason::Unknown | MsrExitReason::Filter)
+        .bits()
+        .into();
+    vm.enable_cap(&cap)?;
+    Ok(())
+}
+
+/// Have KVM leave the MSRs in `ranges` to the VMM, in place of whatever it
+/// filtered before. Every other MSR stays KVM's.
+pub fn set_msr_filter(vm: &VmFd, ranges: &[Range<u32>]) -> Result<()> {
+    if ranges.len() > KVM_MSR_FILTER_MAX_RANGES as usize {
+        let last = &ranges[ranges.len() - 1];
+        return Err(Error::MsrFilter {
+            start: last.start,
+            end: last.end,
+        });
+    }
+    // Clear bits deny KVM the MSR, so every access exits.
+    let mut bitmaps = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        let bytes = range.len().div_ceil(8);
+        if bytes > KVM_MSR_FILTER_MAX_BITMAP_SIZE as usize {
+            return Err(Error::MsrFilter {
+                start: range.start,
+                end: range.end,
+            });
+        }
+        bitmaps.push(vec![0u8; bytes]);
+    }
+    let mut filter = kvm_msr_filter {
+        flags: KVM_MSR_FILTER_DEFAULT_ALLOW,
+        ..Default::default()
+    };
+    for ((slot, range), bitmap) in filter.ranges.iter_mut().zip(ranges).zip(&mut bitmaps) {
+        *slot = kvm_msr_filter_range {
+            flags: KVM_MSR_FILTER_READ | KVM_MSR_FILTER_WRITE,
+            nmsrs: range.len() as u32,
+            base: range.start,
+            bitmap: bitmap.as_mut_ptr(),
+        };
+    }
+    // SAFETY: KVM copies the filter and its bitmaps, which outlive the call.
+    if unsafe { ioctl_with_ref(vm, KVM_X86_SET_MSR_FILTER(), &filter) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Whether KVM offers the guest described by `cpuid` a kvmclock.
+pub fn supports_kvmclock(cpuid: &CpuId) -> bool {
+    cpuid.as_slice().iter().any(|entry| {
+        entry.function == CPUID_KVM_FEATURES && entry.eax & CPUID_KVM_EAX_CLOCKSOURCE2 != 0
+    })
+}
+
+/// Have KVM keep vCPU `index`'s kvmclock in the boot info current, from its
+/// first entry into the guest on. The kernel finds it there and reads its
+/// clock from it rather than calibrating the TSC.
+pub fn enable_kvmclock(vcpu: &kvm_ioctls::VcpuFd, index: usize) -> Result<()> {
+    let clock = PVCLOCK_PHYS.as_u64() + (index * size_of::<PvClock>()) as u64;
+    let msrs = Msrs::from_entries(&[kvm_msr_entry {
+        index: MSR_KVM_SYSTEM_TIME_NEW,
+        data: clock | PVCLOCK_ENABLE,
+        ..Default::default()
+    }])
+    .expect("one MSR fits");
+    vcpu.set_msrs(&msrs)?;
+    Ok(())
+}
+
+/// Make `cpuid` report hostel rather than KVM as the hypervisor. The KVM
+/// leaves that follow stay as they are.
+pub fn set_hypervisor_vendor(cpuid: &mut CpuId) {
+    for entry in cpuid.as_mut_slice() {
+        if entry.function == CPUID_HYPERVISOR_VENDOR {
+            let word =
+                |i: usize| u32::from_le_bytes(HYPERVISOR_VENDOR[i..i + 4].try_into().unwrap());
+            (entry.ebx, entry.ecx, entry.edx) = (word(0), word(4), word(8));
+        }
+    }
+}
+
+/// Make `cpuid` report `apic_id` as the executing CPU's APIC ID. KVM hands
+/// out one table for every vCPU, and the local APIC of vCPU n has ID n.
+pub fn set_apic_id(cpuid: &mut CpuId, apic_id: u32) {
+    for entry in cpuid.as_mut_slice() {
+        match entry.function {
+            CPUID_FEATURES => entry.ebx = (entry.ebx & 0x00ff_ffff) | (apic_id << 24),
+            CPUID_EXTENDED_TOPOLOGY | CPUID_V2_EXTENDED_TOPOLOGY => entry.edx = apic_id,
+            _ => {}
+        }
+    }
+}
+
+/// Give KVM the guest's memory as slot 0, the only one, of `mem_size`
+/// bytes. With `log_dirty` KVM records each page the guest writes, for
+/// `VmFd::get_dirty_log`. Registering the slot again changes its flags and
+/// starts the log afresh.
+pub fn register_memory(
+    vm: &VmFd,
+    boot_mem: &GuestMemoryMmap<()>,
+    mem_size: usize,
+    log_dirty: bool,
+) -> Result<()> {
+    // SAFETY: the slot is `boot_mem`'s mapping, which the `Vm` holding `vm`
+    // keeps for as long as the VM exists.
+    unsafe {
+        vm.set_user_memory_region(kvm_userspace_memory_region {
+            slot: MEMORY_SLOT,
+            guest_phys_addr: GUEST_BASE.0,
+            memory_size: mem_size as u64,
+            userspace_addr: boot_mem.get_host_address(GUEST_BASE).unwrap() as u64,
+            flags: if log_dirty {
+                KVM_MEM_LOG_DIRTY_PAGES
+            } else {
+                0
+            },
+        })?;
+    }
+    Ok(())
+}
+
 pub fn init_x64(
     vm: &VmFd,
     vcpus: &[kvm_ioctls::VcpuFd],
     boot_mem: &GuestMemoryMmap<()>,
     mem_size: usize,
+    gigapages: bool,
     direct_map: &impl DirectMap,
 ) -> Result<()> {
     // map direct map region
@@ -53,18 +233,19 @@ pub fn init_x64(
         boot_mem.write_slice(&entry_val.to_le_bytes(), entry_addr)?;
     }
 
-    for i in 0..DIRECT_MAP_PDPT_COUNT * PAGE_TABLE_ENTRIES {
-        let pd_phys = DIRECT_MAP_PD.as_u64() + i as u64 * PAGE_TABLE_SIZE as u64;
-        let entry_val = pd_phys | PTE_PRESENT | PTE_RW;
-        let entry_addr = GuestAddress(DIRECT_MAP_PDPT.as_u64() + (i * 8) as u64);
-        boot_mem.write_slice(&entry_val.to_le_bytes(), entry_addr)?;
-    }
-
-    for i in 0..DIRECT_MAP_PD_COUNT * PAGE_TABLE_ENTRIES {
-        let phys = i as u64 * PAGE_SIZE as u64;
-        let entry_val = phys | PTE_PRESENT | PTE_RW | PTE_PS;
-        let entry_addr = GuestAddress(DIRECT_MAP_PD.as_u64() + (i * 8) as u64);
-        boot_mem.write_slice(&entry_val.to_le_bytes(), entry_addr)?;
+    // With 1 GiB pages the PDPT entries are the leaves and the PD area is
+    // never written, which saves about half a million entries per boot.
+    if gigapages {
+        let leaves = (0..(DIRECT_MAP_PDPT_COUNT * PAGE_TABLE_ENTRIES) as u64)
+            .map(|i| (i * GIGAPAGE_SIZE) | PTE_PRESENT | PTE_RW | PTE_PS | PTE_NX);
+        write_entries(boot_mem, DIRECT_MAP_PDPT, leaves)?;
+    } else {
+        let tables = (0..(DIRECT_MAP_PDPT_COUNT * PAGE_TABLE_ENTRIES) as u64)
+            .map(|i| (DIRECT_MAP_PD.as_u64() + i * PAGE_TABLE_SIZE as u64) | PTE_PRESENT | PTE_RW);
+        write_entries(boot_mem, DIRECT_MAP_PDPT, tables)?;
+        let leaves = (0..(DIRECT_MAP_PD_COUNT * PAGE_TABLE_ENTRIES) as u64)
+            .map(|i| (i * PAGE_SIZE as u64) | PTE_PRESENT | PTE_RW | PTE_PS | PTE_NX);
+        write_entries(boot_mem, DIRECT_MAP_PD, leaves)?;
     }
 
     // map kernel code region
@@ -82,38 +263,53 @@ pub fn init_x64(
         boot_mem.write_slice(&entry_val.to_le_bytes(), entry_addr)?;
     }
 
-    for i in 0..PAGE_TABLE_ENTRIES {
+    // the first 2mb hold the kernel image and go through a page table so that
+    // load_kernel_segment can set per-segment permissions; everything else is data
+    let kernel_pt_val = KERNEL_CODE_PT.as_u64() | PTE_PRESENT | PTE_RW;
+    boot_mem.write_slice(
+        &kernel_pt_val.to_le_bytes(),
+        GuestAddress(KERNEL_CODE_PD.as_u64()),
+    )?;
+
+    for i in 1..PAGE_TABLE_ENTRIES {
         let phys = KERNEL_CODE_PHYS.add(i * PAGE_SIZE).as_u64();
-        let entry_val = phys | PTE_PRESENT | PTE_RW | PTE_PS;
+        let entry_val = phys | PTE_PRESENT | PTE_RW | PTE_PS | PTE_NX;
         let entry_addr = GuestAddress(KERNEL_CODE_PD.as_u64() + (i * 8) as u64);
         boot_mem.write_slice(&entry_val.to_le_bytes(), entry_addr)?;
     }
 
-    // Register the guest memory region with KVM.
-    unsafe {
-        vm.set_user_memory_region(kvm_userspace_memory_region {
-            slot: 0,
-            guest_phys_addr: GUEST_BASE.0,
-            memory_size: mem_size as u64,
-            userspace_addr: boot_mem.get_host_address(GUEST_BASE).unwrap() as u64,
-            flags: 0,
-        })?;
+    for i in 0..PAGE_TABLE_ENTRIES {
+        let phys = KERNEL_CODE_PHYS.add(i * SMALL_PAGE_SIZE).as_u64();
+        let entry_val = phys | PTE_PRESENT | PTE_RW | PTE_NX;
+        let entry_addr = GuestAddress(KERNEL_CODE_PT.as_u64() + (i * 8) as u64);
+        boot_mem.write_slice(&entry_val.to_le_bytes(), entry_addr)?;
     }
 
+    register_memory(vm, boot_mem, mem_size, false)?;
+
     // General purpose registers:
     // - RIP: instruction pointer where the guest will start executing
     // - RSP: stack pointer inside guest memory
     // - RFLAGS: set the reserved bit required by x86
-    let mut regs = vcpus[0].get_regs()?;
-    // _start is entered without a CALL frame; keep SysV ABI expectation
-    // (RSP % 16 == 8 on function entry) so local variables that require
-    // 16-byte alignment remain aligned after prologue.
-    regs.rsp = KERNEL_STACK.to_virtual(direct_map).as_u64() - 8;
-    regs.rflags = RFLAGS_RESERVED; // required reserved bit
-    vcpus[0].set_regs(&regs)?;
-
-    // Special registers (control & segment registers) for entering long mode.
-    let mut sregs = vcpus[0].get_sregs()?;
+    // - RDI: the vCPU's index, _start's first argument
+    for (index, vcpu) in vcpus.iter().enumerate() {
+        let mut regs = vcpu.get_regs()?;
+        // _start is entered without a CALL frame; keep SysV ABI expectation
+        // (RSP % 16 == 8 on function entry) so local variables that require
+        // 16-byte alignment remain aligned after prologue.
+        regs.rsp = entry_stack_top(index).to_virtual(direct_map).as_u64() - 8;
+        regs.rflags = RFLAGS_RESERVED; // required reserved bit
+        regs.rdi = index as u64;
+        vcpu.set_regs(&regs)?;
+        init_long_mode(vcpu)?;
+    }
+
+    Ok(())
+}
+
+// Special registers (control & segment registers) for entering long mode.
+fn init_long_mode(vcpu: &kvm_ioctls::VcpuFd) -> Result<()> {
+    let mut sregs = vcpu.get_sregs()?;
     sregs.cr3 = DIRECT_MAP_PML4.as_u64(); // CR3 = physical address of the PML4 (page-table root)
 
     // CR4.PAE must be set to enable physical-address-extension paging required
@@ -121,7 +317,8 @@ pub fn init_x64(
     sregs.cr4 |= CR4_PAE | CR4_OSFXSR | CR4_OSXMMEXCPT;
 
     // EFER.LME enables Long Mode; EFER.LMA indicates Long Mode Active.
-    sregs.efer = EFER_LME | EFER_LMA;
+    // EFER.NXE makes the CPU honour the no-execute bit in page table entries.
+    sregs.efer = EFER_LME | EFER_LMA | EFER_NXE;
 
     // Code segment descriptor: set as a 64-bit code segment.
     sregs.cs.l = 1; // L bit = 1 => 64-bit code segment
@@ -138,7 +335,8 @@ pub fn init_x64(
     sregs.ss.present = 1;
     sregs.ss.selector = SS_SELECTOR;
 
-    // KVM allows zero-sized GDT/IDT here because we supply selectors directly.
+    // KVM allows zero-sized GDT/IDT here because we supply selectors directly;
+    // the kernel loads its own tables, at the same selectors, during boot.
     sregs.gdt.limit = 0;
     sregs.idt.limit = 0;
 
@@ -146,10 +344,53 @@ pub fn init_x64(
     // error) so x87 exceptions behave as expected.
     sregs.cr0 |= CR0_PG | CR0_PE | CR0_MP; // paging + protected mode + monitor coprocessor
     sregs.cr0 |= CR0_NE; // numeric error
+    sregs.cr0 |= CR0_WP; // ring 0 honours read-only pages too
     sregs.cr0 &= !CR0_EM; // enable x87/SSE instructions
     sregs.cr0 &= !CR0_TS; // allow immediate FPU/SSE use
 
-    vcpus[0].set_sregs(&sregs)?;
+    vcpu.set_sregs(&sregs)?;
+
+    Ok(())
+}
+
+/// Write consecutive page-table entries starting at `table` in one copy.
+fn write_entries(
+    boot_mem: &GuestMemoryMmap<()>,
+    table: PhysicalAddr,
+    entries: impl Iterator<Item = u64>,
+) -> Result<()> {
+    let bytes: Vec<u8> = entries.flat_map(u64::to_le_bytes).collect();
+    boot_mem.write_slice(&bytes, GuestAddress(table.as_u64()))?;
+    Ok(())
+}
+
+/// Set the kernel image pages covering an ELF segment to the segment's
+/// permissions. Executable segments are never writable, and everything else is
+/// no-execute.
+pub fn load_kernel_segment(
+    boot_mem: &GuestMemoryMmap<()>,
+    vaddr: u64,
+    memsz: u64,
+    flags: u32,
+) -> Result<()> {
+    let page_size = SMALL_PAGE_SIZE as u64;
+    let start = (vaddr - KERNEL_CODE_VIRT.as_u64()) / page_size;
+    let end = (vaddr + memsz - KERNEL_CODE_VIRT.as_u64()).div_ceil(page_size);
+
+    let access = if flags & PF_X != 0 {
+        0
+    } else if flags & PF_W != 0 {
+        PTE_RW | PTE_NX
+    } else {
+        PTE_NX
+    };
+
+    for i in start..end {
+        let phys = KERNEL_CODE_PHYS.as_u64() + i * page_size;
+        let entry_val = phys | PTE_PRESENT | access;
+        let entry_addr = GuestAddress(KERNEL_CODE_PT.as_u64() + i * 8);
+        boot_mem.write_slice(&entry_val.to_le_bytes(), entry_addr)?;
+    }
 
     Ok(())
 }